@@ -25,9 +25,16 @@
 #include <android-base/logging.h>
 #include <android-base/properties.h>
 #include <android-base/strings.h>
+#include <aidl/android/security/fsverity/IKeystoreFsVerity.h>
+#include <android/binder_manager.h>
 #include <log/log.h>
 #include <mini_keyctl_utils.h>
 
+using aidl::android::security::fsverity::FsVerityCertificate;
+using aidl::android::security::fsverity::IKeystoreFsVerity;
+
+static const char* kKeystoreFsVerityService = "android.security.fsverity";
+
 bool LoadKeyToKeyring(key_serial_t keyring_id, const char* desc, const char* data, size_t size) {
     key_serial_t key = add_key("asymmetric", desc, data, size, keyring_id);
     if (key < 0) {
@@ -80,6 +87,30 @@ void LoadKeyFromVerifiedPartitions(key_serial_t keyring_id) {
     LoadKeyFromDirectory(keyring_id, "fsv_product_", "/product/etc/security/fsverity");
 }
 
+void LoadKeysFromKeystore(key_serial_t keyring_id) {
+    ndk::SpAIBinder binder(AServiceManager_getService(kKeystoreFsVerityService));
+    auto service = IKeystoreFsVerity::fromBinder(binder);
+    if (!service) {
+        LOG(ERROR) << "Failed to get service " << kKeystoreFsVerityService;
+        return;
+    }
+    std::vector<FsVerityCertificate> certificates;
+    auto status = service->getCertificates(&certificates);
+    if (!status.isOk()) {
+        LOG(ERROR) << "Failed to get certificates from keystore: " << status.getDescription();
+        return;
+    }
+    for (const auto& certificate : certificates) {
+        std::string keyname = "fsv_keystore_" + certificate.name;
+        LOG(INFO) << "LoadKeysFromKeystore keyname=" << keyname;
+        if (!LoadKeyToKeyring(keyring_id, keyname.c_str(),
+                              reinterpret_cast<const char*>(certificate.certificate.data()),
+                              certificate.certificate.size())) {
+            LOG(ERROR) << "Failed to load key " << keyname << " from keystore";
+        }
+    }
+}
+
 int main(int argc, const char** argv) {
     if (argc < 2) {
         LOG(ERROR) << "Not enough arguments";
@@ -102,6 +133,9 @@ int main(int argc, const char** argv) {
             return -1;
         }
         LoadKeyFromStdin(keyring_id, argv[2]);
+    } else if (command == "--load-keystore-keys") {
+        // Loads the certificates provisioned through keystore's IKeystoreFsVerity.
+        LoadKeysFromKeystore(keyring_id);
     } else if (command == "--lock") {
         // Requires files backed by fs-verity to be verified with a key in .fs-verity
         // keyring.
@@ -16,7 +16,7 @@
 //! to the API surface that Keystore 2.0 requires to perform permission checks against
 //! the SEPolicy. Notably, it provides wrappers for:
 //!  * getcon
-//!  * selinux_check_access
+//!  * selinux_check_access, with an optional cache of granted accesses
 //!  * selabel_lookup for the keystore2_key backend.
 //! And it provides an owning wrapper around context strings `Context`.
 
@@ -27,14 +27,17 @@ pub use selinux::pid_t;
 use selinux::SELABEL_CTX_ANDROID_KEYSTORE2_KEY;
 use selinux::SELINUX_CB_LOG;
 use selinux_bindgen as selinux;
+use std::collections::HashMap;
 use std::ffi::{CStr, CString};
 use std::fmt;
 use std::io;
 use std::marker::{Send, Sync};
+use std::mem::size_of;
 pub use std::ops::Deref;
 use std::os::raw::c_char;
 use std::ptr;
 use std::sync;
+use std::time::{Duration, Instant};
 
 static SELINUX_LOG_INIT: sync::Once = sync::Once::new();
 
@@ -321,6 +324,212 @@ pub fn check_access(source: &CStr, target: &CStr, tclass: &str, perm: &str) -> R
     }
 }
 
+/// Returns true iff the loaded policy defines the permission `perm` of the class `tclass`.
+pub fn is_permission_defined(tclass: &str, perm: &str) -> bool {
+    init_logger_once();
+
+    let (c_tclass, c_perm) = match (CString::new(tclass), CString::new(perm)) {
+        (Ok(c_tclass), Ok(c_perm)) => (c_tclass, c_perm),
+        _ => return false,
+    };
+
+    let _lock = LIB_SELINUX_LOCK.lock().unwrap();
+
+    // Safety: The argument is a valid C string that outlives the call.
+    let class = unsafe { selinux::string_to_security_class(c_tclass.as_ptr()) };
+    // Safety: The argument is a valid C string that outlives the call.
+    class != 0 && unsafe { selinux::string_to_av_perm(class, c_perm.as_ptr()) } != 0
+}
+
+/// Safe wrapper around security_compute_av. Computes the policy decision for the requested
+/// access without enforcing or auditing it. Unlike `check_access` this reports denials even if
+/// the source domain or the device is permissive, which allows evaluating the policy in an
+/// audit-only mode.
+///
+/// ## Return
+///  * Ok(true) iff the policy allows the requested access.
+///  * Ok(false) iff the policy denies the requested access.
+///  * Err(anyhow!(Error::sys(...))) if the class or permission is not known to the policy.
+///  * Err(anyhow!(ioError::last_os_error())) if any other error occurred while computing the
+///            access decision.
+pub fn compute_access(source: &CStr, target: &CStr, tclass: &str, perm: &str) -> Result<bool> {
+    init_logger_once();
+
+    let c_tclass = CString::new(tclass).with_context(|| {
+        format!("compute_access: Failed to convert tclass \"{}\" to CString.", tclass)
+    })?;
+    let c_perm = CString::new(perm).with_context(|| {
+        format!("compute_access: Failed to convert perm \"{}\" to CString.", perm)
+    })?;
+
+    let _lock = LIB_SELINUX_LOCK.lock().unwrap();
+
+    // Safety: The argument is a valid C string that outlives the call.
+    let class = unsafe { selinux::string_to_security_class(c_tclass.as_ptr()) };
+    if class == 0 {
+        return Err(anyhow!(Error::sys(format!("compute_access: Unknown class \"{}\".", tclass))));
+    }
+    // Safety: The argument is a valid C string that outlives the call.
+    let requested = unsafe { selinux::string_to_av_perm(class, c_perm.as_ptr()) };
+    if requested == 0 {
+        return Err(anyhow!(Error::sys(format!(
+            "compute_access: Unknown permission \"{}\" in class \"{}\".",
+            perm, tclass
+        ))));
+    }
+
+    // Safety: av_decision is a plain C struct for which all zeroes is a valid value.
+    let mut avd: selinux::av_decision = unsafe { std::mem::zeroed() };
+    // Safety: The contexts are valid C strings that outlive the call, and avd is a valid
+    // av_decision that is only written to by security_compute_av.
+    match unsafe {
+        selinux::security_compute_av(source.as_ptr(), target.as_ptr(), class, requested, &mut avd)
+    } {
+        0 => Ok(avd.allowed & requested == requested),
+        _ => Err(anyhow!(io::Error::last_os_error())).with_context(|| {
+            format!(
+                concat!(
+                    "compute_access: Failed with sctx: {:?} tctx: {:?}",
+                    " with target class: \"{}\" perm: \"{}\""
+                ),
+                source, target, tclass, perm
+            )
+        }),
+    }
+}
+
+/// Time after which a cached access decision is evaluated again.
+const ACCESS_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Maximal number of cached access decisions. The cache is flushed when it is full.
+const ACCESS_CACHE_CAPACITY: usize = 1024;
+
+/// Counters of the access decision cache used by `check_access_cached`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccessCacheStats {
+    /// Number of access checks that were answered from the cache.
+    pub hits: u64,
+    /// Number of access checks that were passed on to `check_access`.
+    pub misses: u64,
+    /// Number of times the cache was flushed.
+    pub flushes: u64,
+}
+
+#[derive(Default)]
+struct AccessCache {
+    entries: HashMap<(CString, CString, String, String), Instant>,
+    status_open: bool,
+    stats: AccessCacheStats,
+}
+
+impl AccessCache {
+    fn flush(&mut self) -> usize {
+        let flushed = self.entries.len();
+        self.entries.clear();
+        self.stats.flushes += 1;
+        flushed
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.entries
+            .keys()
+            .map(|(source, target, tclass, perm)| {
+                size_of::<((CString, CString, String, String), Instant)>()
+                    + source.as_bytes_with_nul().len()
+                    + target.as_bytes_with_nul().len()
+                    + tclass.len()
+                    + perm.len()
+            })
+            .sum()
+    }
+
+    /// Flushes the cache if the policy was reloaded or the enforcing mode changed since the
+    /// last call. Returns false if this cannot be determined, in which case the cache must
+    /// not be used.
+    fn sync_with_policy(&mut self) -> bool {
+        init_logger_once();
+        let _lock = LIB_SELINUX_LOCK.lock().unwrap();
+        if !self.status_open {
+            // Safety: selinux_status_open has no preconditions. The status page remains
+            // mapped for the lifetime of the process.
+            if unsafe { selinux::selinux_status_open(1) } < 0 {
+                return false;
+            }
+            self.status_open = true;
+        }
+        // Safety: The status page was opened above.
+        match unsafe { selinux::selinux_status_updated() } {
+            0 => true,
+            1 => {
+                self.flush();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn lookup(&mut self, key: &(CString, CString, String, String), now: Instant) -> bool {
+        match self.entries.get(key) {
+            Some(cached_at) if now.duration_since(*cached_at) < ACCESS_CACHE_TTL => {
+                self.stats.hits += 1;
+                true
+            }
+            _ => {
+                self.stats.misses += 1;
+                false
+            }
+        }
+    }
+
+    fn insert(&mut self, key: (CString, CString, String, String), now: Instant) {
+        if self.entries.len() >= ACCESS_CACHE_CAPACITY {
+            self.flush();
+        }
+        self.entries.insert(key, now);
+    }
+}
+
+lazy_static! {
+    static ref ACCESS_CACHE: sync::Mutex<AccessCache> = Default::default();
+}
+
+/// Like `check_access`, but granted accesses are cached in the style of the kernel's AVC.
+/// Cached decisions expire after `ACCESS_CACHE_TTL` and are flushed whenever the SELinux
+/// status page reports a policy reload or a change of the enforcing mode. Denials are never
+/// cached, so that every denial is audited. Note that in permissive mode, accesses that the
+/// policy denies are granted and cached, so that they are audited only once per TTL.
+pub fn check_access_cached(source: &CStr, target: &CStr, tclass: &str, perm: &str) -> Result<()> {
+    let key = (source.to_owned(), target.to_owned(), tclass.to_string(), perm.to_string());
+    {
+        let mut cache = ACCESS_CACHE.lock().unwrap();
+        if !cache.sync_with_policy() {
+            drop(cache);
+            return check_access(source, target, tclass, perm);
+        }
+        if cache.lookup(&key, Instant::now()) {
+            return Ok(());
+        }
+    }
+    check_access(source, target, tclass, perm)?;
+    ACCESS_CACHE.lock().unwrap().insert(key, Instant::now());
+    Ok(())
+}
+
+/// Drops all cached access decisions. This is for callers that learn about policy changes
+/// by other means than the SELinux status page. Returns the number of dropped decisions.
+pub fn flush_access_cache() -> usize {
+    ACCESS_CACHE.lock().unwrap().flush()
+}
+
+/// Returns an estimate of the memory held by the access decision cache in bytes.
+pub fn access_cache_size_bytes() -> usize {
+    ACCESS_CACHE.lock().unwrap().size_bytes()
+}
+
+/// Returns the counters of the access decision cache.
+pub fn access_cache_stats() -> AccessCacheStats {
+    ACCESS_CACHE.lock().unwrap().stats
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -372,6 +581,49 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_compute_access() -> Result<()> {
+        let tctx = Context::new("u:object_r:keystore:s0").unwrap();
+        let system_server = Context::new("u:r:system_server:s0").unwrap();
+        let shell = Context::new("u:r:shell:s0").unwrap();
+        assert!(compute_access(&system_server, &tctx, "keystore2_key", "use")?);
+        assert!(!compute_access(&shell, &tctx, "keystore2_key", "grant")?);
+        assert!(compute_access(&shell, &tctx, "keystore2_key", "no_such_perm").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_access_cache() {
+        let mut cache = AccessCache::default();
+        let key = (
+            CString::new("u:r:system_server:s0").unwrap(),
+            CString::new("u:object_r:keystore:s0").unwrap(),
+            "keystore2_key".to_string(),
+            "use".to_string(),
+        );
+        let now = Instant::now();
+        assert!(!cache.lookup(&key, now));
+        cache.insert(key.clone(), now);
+        assert!(cache.lookup(&key, now + ACCESS_CACHE_TTL / 2));
+        assert!(!cache.lookup(&key, now + ACCESS_CACHE_TTL));
+        cache.flush();
+        assert!(!cache.lookup(&key, now));
+        assert_eq!(cache.stats, AccessCacheStats { hits: 1, misses: 3, flushes: 1 });
+    }
+
+    #[test]
+    fn test_check_access_cached() -> Result<()> {
+        let tctx = Context::new("u:object_r:keystore:s0").unwrap();
+        let sctx = Context::new("u:r:system_server:s0").unwrap();
+        check_access_cached(&sctx, &tctx, "keystore2_key", "use")?;
+        let before = access_cache_stats();
+        check_access_cached(&sctx, &tctx, "keystore2_key", "use")?;
+        assert!(access_cache_stats().hits > before.hits);
+        flush_access_cache();
+        assert!(access_cache_stats().flushes > before.flushes);
+        Ok(())
+    }
+
     mod perm {
         use super::super::*;
         use super::*;
@@ -0,0 +1,332 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module defines the `AccessControl` trait, the backend that the permission checks of
+//! `permission` are evaluated against. By default this is the SELinux policy of the device,
+//! see `SelinuxAccessControl`. Builds without a full SELinux policy, i.e., the unit tests and
+//! the embedded mode, can install a different backend with `set_access_control`, e.g., a
+//! static policy table, see `PolicyTable`, or a backend that allows everything, see
+//! `AllowAll`. Production builds cannot replace the SELinux backend.
+
+use anyhow::{Context, Result};
+use keystore2_selinux as selinux;
+use lazy_static::lazy_static;
+use selinux::Backend;
+#[cfg(any(test, feature = "embedded"))]
+use std::collections::{HashMap, HashSet};
+use std::ffi::CStr;
+use std::sync::{Arc, RwLock};
+
+/// The backend of all access control decisions made by Keystore.
+pub trait AccessControl: Send + Sync {
+    /// Returns the context of Keystore. It is the target context of all checks of the
+    /// `keystore2` class and of the checks on keys in `Domain::APP`.
+    fn keystore_context(&self) -> Result<selinux::Context>;
+
+    /// Returns the target context of the keys in the given `Domain::SELINUX` namespace, or
+    /// None if the namespace has no context.
+    fn key_context(&self, namespace: i64) -> Result<Option<selinux::Context>>;
+
+    /// Checks if `source` has the permission `perm` of the class `tclass` on `target`.
+    /// Denials must be reported as `selinux::Error::PermissionDenied`. Like
+    /// `selinux::check_access`, the check may be subject to permissive mode and auditing.
+    fn check_access(&self, source: &CStr, target: &CStr, tclass: &str, perm: &str) -> Result<()>;
+
+    /// Returns the decision of the policy on the same query as `check_access`, but without
+    /// enforcing or auditing it.
+    fn compute_access(
+        &self,
+        source: &CStr,
+        target: &CStr,
+        tclass: &str,
+        perm: &str,
+    ) -> Result<bool>;
+
+    /// Returns an estimate of the memory held by the `key_context` lookup tables in bytes.
+    fn key_contexts_size_bytes(&self) -> usize {
+        0
+    }
+
+    /// Returns true iff the policy defines the permission `perm` of the class `tclass`. Checks
+    /// of undefined permissions fail with an error rather than a denial.
+    fn is_permission_defined(&self, _tclass: &str, _perm: &str) -> bool {
+        true
+    }
+}
+
+/// The keystore2_key_contexts files that libselinux loads into the keystore2_key backend.
+const KEY_CONTEXTS_FILES: &[&str] = &[
+    "/system/etc/selinux/plat_keystore2_key_contexts",
+    "/system_ext/etc/selinux/system_ext_keystore2_key_contexts",
+    "/product/etc/selinux/product_keystore2_key_contexts",
+    "/vendor/etc/selinux/vendor_keystore2_key_contexts",
+    "/odm/etc/selinux/odm_keystore2_key_contexts",
+];
+
+/// The default backend, which queries the SELinux policy of the device.
+pub struct SelinuxAccessControl {
+    key_backend: selinux::KeystoreKeyBackend,
+    key_contexts_size_bytes: usize,
+}
+
+impl SelinuxAccessControl {
+    /// Creates a new instance. This opens the keystore2_key context backend.
+    pub fn new() -> Result<Self> {
+        let key_backend = selinux::KeystoreKeyBackend::new()
+            .context("In SelinuxAccessControl::new: Failed to open keystore2_key backend.")?;
+        // The backend holds the parsed specifications of all files for the lifetime of the
+        // process. Their size is estimated by the size of the files.
+        let key_contexts_size_bytes = KEY_CONTEXTS_FILES
+            .iter()
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .map(|metadata| metadata.len() as usize)
+            .sum();
+        Ok(Self { key_backend, key_contexts_size_bytes })
+    }
+}
+
+impl AccessControl for SelinuxAccessControl {
+    fn keystore_context(&self) -> Result<selinux::Context> {
+        selinux::getcon().context("In SelinuxAccessControl::keystore_context: getcon failed.")
+    }
+
+    fn key_context(&self, namespace: i64) -> Result<Option<selinux::Context>> {
+        match self.key_backend.lookup(&namespace.to_string()) {
+            Ok(context) => Ok(Some(context)),
+            Err(e)
+                if matches!(
+                    e.root_cause().downcast_ref::<std::io::Error>(),
+                    Some(io_error) if io_error.raw_os_error() == Some(libc::ENOENT)
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn check_access(&self, source: &CStr, target: &CStr, tclass: &str, perm: &str) -> Result<()> {
+        selinux::check_access_cached(source, target, tclass, perm)
+    }
+
+    fn compute_access(
+        &self,
+        source: &CStr,
+        target: &CStr,
+        tclass: &str,
+        perm: &str,
+    ) -> Result<bool> {
+        selinux::compute_access(source, target, tclass, perm)
+    }
+
+    fn key_contexts_size_bytes(&self) -> usize {
+        self.key_contexts_size_bytes
+    }
+
+    fn is_permission_defined(&self, tclass: &str, perm: &str) -> bool {
+        selinux::is_permission_defined(tclass, perm)
+    }
+}
+
+/// A backend that evaluates the checks against a static table of rules instead of an SELinux
+/// policy. Everything that the table does not allow is denied, and `Domain::SELINUX`
+/// namespaces without a context in the table cannot be used.
+#[cfg(any(test, feature = "embedded"))]
+#[derive(Debug, Default)]
+pub struct PolicyTable {
+    keystore_context: String,
+    key_contexts: HashMap<i64, String>,
+    rules: HashSet<(String, String, String, String)>,
+}
+
+#[cfg(any(test, feature = "embedded"))]
+impl PolicyTable {
+    /// Creates an empty table in which Keystore has the context `keystore_context`.
+    pub fn new(keystore_context: &str) -> Self {
+        Self { keystore_context: keystore_context.to_string(), ..Default::default() }
+    }
+
+    /// Assigns `context` to the `Domain::SELINUX` namespace `namespace`.
+    pub fn key_context(mut self, namespace: i64, context: &str) -> Self {
+        self.key_contexts.insert(namespace, context.to_string());
+        self
+    }
+
+    /// Allows `source` the permissions `perms` of the class `tclass` on `target`.
+    pub fn allow(mut self, source: &str, target: &str, tclass: &str, perms: &[&str]) -> Self {
+        for perm in perms {
+            self.rules.insert((
+                source.to_string(),
+                target.to_string(),
+                tclass.to_string(),
+                perm.to_string(),
+            ));
+        }
+        self
+    }
+}
+
+#[cfg(any(test, feature = "embedded"))]
+impl AccessControl for PolicyTable {
+    fn keystore_context(&self) -> Result<selinux::Context> {
+        selinux::Context::new(&self.keystore_context).context("In PolicyTable::keystore_context.")
+    }
+
+    fn key_context(&self, namespace: i64) -> Result<Option<selinux::Context>> {
+        self.key_contexts
+            .get(&namespace)
+            .map(|context| selinux::Context::new(context))
+            .transpose()
+            .context("In PolicyTable::key_context.")
+    }
+
+    fn check_access(&self, source: &CStr, target: &CStr, tclass: &str, perm: &str) -> Result<()> {
+        if self.compute_access(source, target, tclass, perm)? {
+            Ok(())
+        } else {
+            Err(selinux::Error::perm())
+                .context(format!("In PolicyTable::check_access: {} {}", tclass, perm))
+        }
+    }
+
+    fn compute_access(
+        &self,
+        source: &CStr,
+        target: &CStr,
+        tclass: &str,
+        perm: &str,
+    ) -> Result<bool> {
+        let rule = (
+            source.to_str().context("In PolicyTable::compute_access: Bad source.")?.to_string(),
+            target.to_str().context("In PolicyTable::compute_access: Bad target.")?.to_string(),
+            tclass.to_string(),
+            perm.to_string(),
+        );
+        Ok(self.rules.contains(&rule))
+    }
+}
+
+/// A backend that allows every check. Keystore and the keys in every `Domain::SELINUX`
+/// namespace have the context `keystore_context`. Only the checks that do not depend on the
+/// backend, e.g., the isolation of `Domain::APP` by uid, still apply.
+#[cfg(any(test, feature = "embedded"))]
+#[derive(Debug)]
+pub struct AllowAll {
+    /// The context of Keystore and of all keys.
+    pub keystore_context: String,
+}
+
+#[cfg(any(test, feature = "embedded"))]
+impl AccessControl for AllowAll {
+    fn keystore_context(&self) -> Result<selinux::Context> {
+        selinux::Context::new(&self.keystore_context).context("In AllowAll::keystore_context.")
+    }
+
+    fn key_context(&self, _namespace: i64) -> Result<Option<selinux::Context>> {
+        self.keystore_context().map(Some)
+    }
+
+    fn check_access(
+        &self,
+        _source: &CStr,
+        _target: &CStr,
+        _tclass: &str,
+        _perm: &str,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn compute_access(
+        &self,
+        _source: &CStr,
+        _target: &CStr,
+        _tclass: &str,
+        _perm: &str,
+    ) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+lazy_static! {
+    /// The installed backend. None until it is first used or set.
+    static ref ACCESS_CONTROL: RwLock<Option<Arc<dyn AccessControl>>> = RwLock::new(None);
+}
+
+/// Installs the given backend. It replaces the current backend for all subsequent checks.
+#[cfg(any(test, feature = "embedded"))]
+pub(crate) fn set_access_control(access_control: Arc<dyn AccessControl>) {
+    *ACCESS_CONTROL.write().unwrap() = Some(access_control);
+}
+
+/// Returns an estimate of the memory held by the SELinux contexts of the installed backend in
+/// bytes.
+pub fn contexts_size_bytes() -> usize {
+    get().key_contexts_size_bytes()
+}
+
+/// Returns the installed backend. If none was installed, `SelinuxAccessControl` is installed.
+pub fn get() -> Arc<dyn AccessControl> {
+    if let Some(access_control) = ACCESS_CONTROL.read().unwrap().as_ref() {
+        return access_control.clone();
+    }
+    let mut access_control = ACCESS_CONTROL.write().unwrap();
+    access_control
+        .get_or_insert_with(|| {
+            // Panicking here is allowed because keystore cannot function without this backend
+            // and it would happen early and indicate a gross misconfiguration of the device.
+            Arc::new(SelinuxAccessControl::new().unwrap())
+        })
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    fn cstr(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    #[test]
+    fn policy_table() -> Result<()> {
+        let table = PolicyTable::new("u:r:keystore:s0")
+            .key_context(102, "u:object_r:wifi_key:s0")
+            .allow("u:r:wifi:s0", "u:object_r:wifi_key:s0", "keystore2_key", &["use", "get_info"]);
+        assert_eq!(table.keystore_context()?.to_str()?, "u:r:keystore:s0");
+        assert_eq!(table.key_context(102)?.unwrap().to_str()?, "u:object_r:wifi_key:s0");
+        assert!(table.key_context(103)?.is_none());
+
+        let (wifi, key) = (cstr("u:r:wifi:s0"), cstr("u:object_r:wifi_key:s0"));
+        assert!(table.compute_access(&wifi, &key, "keystore2_key", "use")?);
+        table.check_access(&wifi, &key, "keystore2_key", "get_info")?;
+        assert!(!table.compute_access(&wifi, &key, "keystore2_key", "delete")?);
+        let e = table.check_access(&cstr("u:r:vpn:s0"), &key, "keystore2_key", "use").unwrap_err();
+        assert_eq!(
+            Some(&selinux::Error::PermissionDenied),
+            e.root_cause().downcast_ref::<selinux::Error>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn allow_all() -> Result<()> {
+        let allow_all = AllowAll { keystore_context: "u:r:keystore:s0".to_string() };
+        assert_eq!(allow_all.key_context(5)?.unwrap().to_str()?, "u:r:keystore:s0");
+        let (source, target) = (cstr("u:r:untrusted_app:s0"), cstr("u:r:keystore:s0"));
+        allow_all.check_access(&source, &target, "keystore2", "reset")?;
+        assert!(allow_all.compute_access(&source, &target, "keystore2_key", "delete")?);
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements access groups. An access group lets a suite of apps share the keys
+//! in the Domain::APP namespace of one of them, the owner, similar to keychain access groups.
+//! Each member is given a mask of key permissions that it holds on the owner's keys. Groups are
+//! declared by app id in a privileged configuration file on the system partition and apply
+//! within each Android user.
+//!
+//! A member addresses the keys of the group by passing the owner's uid as namespace along with
+//! Domain::APP. Any other namespace resolves to the caller's own uid as before, so that callers
+//! unaware of access groups are not affected.
+//!
+//! The configuration file holds one line per member of the form
+//! `<owner app id> <member app id> <permission>[,<permission>...]`, where the permissions are
+//! given by their SELinux names, e.g., `10123 10124 use,get_info`. Empty lines and lines
+//! starting with `#` are ignored.
+
+use crate::config_file;
+use crate::permission::{KeyPerm, KeyPermSet};
+use crate::utils::AID_USER_OFFSET;
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+
+/// Location of the access group configuration.
+const ACCESS_GROUPS_CONFIG: &str = "/system/etc/security/keystore2_access_groups.conf";
+
+lazy_static! {
+    /// The access groups of this device, loaded once on first use.
+    static ref ACCESS_GROUPS: AccessGroups = AccessGroups::load(ACCESS_GROUPS_CONFIG);
+}
+
+/// A set of access groups.
+#[derive(Debug, Default)]
+pub struct AccessGroups {
+    /// Permission masks indexed by the owner's app id and the member's app id.
+    groups: HashMap<u32, HashMap<u32, KeyPermSet>>,
+}
+
+impl AccessGroups {
+    /// Parses the access group configuration. See the module documentation for the format.
+    pub fn parse(config: &str) -> Result<Self> {
+        let mut groups: HashMap<u32, HashMap<u32, KeyPermSet>> = HashMap::new();
+        for (n, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (owner, member, perms) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [owner, member, perms] => (owner, member, perms),
+                _ => return Err(anyhow!("Expected three fields in line {}.", n + 1)),
+            };
+            let owner: u32 =
+                owner.parse().with_context(|| format!("Bad owner app id in line {}.", n + 1))?;
+            let member: u32 =
+                member.parse().with_context(|| format!("Bad member app id in line {}.", n + 1))?;
+            if owner >= AID_USER_OFFSET || member >= AID_USER_OFFSET || owner == member {
+                return Err(anyhow!("Bad app ids in line {}.", n + 1));
+            }
+            let mut mask = KeyPermSet::from(0);
+            for name in perms.split(',') {
+                let perm = Self::parse_perm(name)
+                    .ok_or_else(|| anyhow!("Unknown permission {:?} in line {}.", name, n + 1))?;
+                mask = KeyPermSet(mask.0 | KeyPermSet::from(perm).0);
+            }
+            groups.entry(owner).or_default().insert(member, mask);
+        }
+        Ok(Self { groups })
+    }
+
+    fn parse_perm(name: &str) -> Option<KeyPerm> {
+        KeyPermSet(!0).into_iter().find(|p| p.to_selinux() == name && *p != KeyPerm::none())
+    }
+
+    /// A malformed configuration grants nothing, because access groups only extend access.
+    fn load(path: &str) -> Self {
+        config_file::load(path, Self::parse).unwrap_or_default()
+    }
+
+    /// Returns the permissions that `caller_uid` holds on the Domain::APP namespace `namespace`
+    /// by means of access group membership. The set is empty if the caller is not a member of
+    /// a group owned by the namespace or if both belong to different Android users.
+    pub fn member_permissions(&self, caller_uid: u32, namespace: i64) -> KeyPermSet {
+        if namespace < 0
+            || namespace / AID_USER_OFFSET as i64 != (caller_uid / AID_USER_OFFSET) as i64
+        {
+            return KeyPermSet::from(0);
+        }
+        let owner = (namespace % AID_USER_OFFSET as i64) as u32;
+        self.groups
+            .get(&owner)
+            .and_then(|members| members.get(&(caller_uid % AID_USER_OFFSET)))
+            .copied()
+            .unwrap_or_else(|| KeyPermSet::from(0))
+    }
+
+    /// Resolves the namespace of a Domain::APP key descriptor supplied by `caller_uid`. This is
+    /// `requested` if the caller is a member of the access group owned by `requested`, and the
+    /// caller's uid otherwise.
+    pub fn resolve_app_namespace(&self, caller_uid: u32, requested: i64) -> i64 {
+        if requested != caller_uid as i64 && self.member_permissions(caller_uid, requested).0 != 0 {
+            requested
+        } else {
+            caller_uid as i64
+        }
+    }
+}
+
+/// Returns the permissions that `caller_uid` holds on the Domain::APP namespace `namespace` by
+/// means of access group membership.
+pub fn member_permissions(caller_uid: u32, namespace: i64) -> KeyPermSet {
+    ACCESS_GROUPS.member_permissions(caller_uid, namespace)
+}
+
+/// Resolves the namespace of a Domain::APP key descriptor supplied by `caller_uid`.
+pub fn resolve_app_namespace(caller_uid: u32, requested: i64) -> i64 {
+    ACCESS_GROUPS.resolve_app_namespace(caller_uid, requested)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_perm_set;
+
+    const CONFIG: &str = "
+        # Suite of two apps sharing the keys of app 10100.
+        10100 10101 use,get_info
+        10100 10102 use,get_info,delete,rebind
+    ";
+
+    #[test]
+    fn test_member_permissions() -> Result<()> {
+        let groups = AccessGroups::parse(CONFIG)?;
+        assert_eq!(
+            groups.member_permissions(10101, 10100),
+            key_perm_set![KeyPerm::use_(), KeyPerm::get_info()]
+        );
+        // Groups apply within each user, but not across users.
+        assert_eq!(
+            groups.member_permissions(1010102, 1010100),
+            key_perm_set![
+                KeyPerm::use_(),
+                KeyPerm::get_info(),
+                KeyPerm::delete(),
+                KeyPerm::rebind()
+            ]
+        );
+        assert_eq!(groups.member_permissions(1010101, 10100), KeyPermSet::from(0));
+        // Membership is not symmetric.
+        assert_eq!(groups.member_permissions(10100, 10101), KeyPermSet::from(0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_app_namespace() -> Result<()> {
+        let groups = AccessGroups::parse(CONFIG)?;
+        assert_eq!(groups.resolve_app_namespace(10101, 10100), 10100);
+        assert_eq!(groups.resolve_app_namespace(10101, -1), 10101);
+        assert_eq!(groups.resolve_app_namespace(10103, 10100), 10103);
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_config() {
+        assert!(AccessGroups::parse("10100 10101").is_err());
+        assert!(AccessGroups::parse("10100 10101 use,fly").is_err());
+        assert!(AccessGroups::parse("10100 10100 use").is_err());
+    }
+}
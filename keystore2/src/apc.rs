@@ -21,6 +21,7 @@ use std::{
     sync::{mpsc::Sender, Arc, Mutex},
 };
 
+use crate::permission::is_permission_denied;
 use crate::utils::{compat_2_response_code, ui_opts_2_compat, watchdog as wd};
 use android_security_apc::aidl::android::security::apc::{
     IConfirmationCallback::IConfirmationCallback,
@@ -33,9 +34,26 @@ use android_security_apc::binder::{
 };
 use anyhow::{Context, Result};
 use keystore2_apc_compat::ApcHal;
-use keystore2_selinux as selinux;
 use std::time::{Duration, Instant};
 
+/// Upper bound for the size of a message that can be confirmed by a confirmation prompt. This is
+/// the limit of the ConfirmationUI HAL. The enforcement module refuses to finish operations
+/// with larger input, so tokens for larger messages are not forwarded.
+pub const MAX_CONFIRMATION_MESSAGE_SIZE: usize = 0x1800;
+
+/// A confirmation token produced by a successful confirmation prompt. It is bound to the data
+/// that was confirmed and to the app that presented the prompt. The enforcement module only
+/// forwards it to KeyMint when finishing an operation of the same app over the same data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfirmationToken {
+    /// The uid of the app that presented the prompt.
+    pub uid: u32,
+    /// The data that was confirmed, i.e., the message the confirmation token authorizes.
+    pub data_confirmed: Vec<u8>,
+    /// The confirmation token.
+    pub token: Vec<u8>,
+}
+
 /// This is the main APC error type, it wraps binder exceptions and the
 /// APC ResponseCode.
 #[derive(Debug, thiserror::Error, PartialEq)]
@@ -87,7 +105,8 @@ impl Error {
 /// All error conditions get logged by this function.
 ///
 /// `Error::Rc(x)` variants get mapped onto a service specific error code of `x`.
-/// `selinux::Error::perm()` is mapped on `ResponseCode::PERMISSION_DENIED`.
+/// Permission denials, see `permission::is_permission_denied`, are mapped on
+/// `ResponseCode::PERMISSION_DENIED`.
 ///
 /// All non `Error` error conditions get mapped onto ResponseCode::SYSTEM_ERROR`.
 ///
@@ -105,10 +124,8 @@ where
             let rc = match root_cause.downcast_ref::<Error>() {
                 Some(Error::Rc(rcode)) => rcode.0,
                 Some(Error::Binder(_, _)) => ResponseCode::SYSTEM_ERROR.0,
-                None => match root_cause.downcast_ref::<selinux::Error>() {
-                    Some(selinux::Error::PermissionDenied) => ResponseCode::PERMISSION_DENIED.0,
-                    _ => ResponseCode::SYSTEM_ERROR.0,
-                },
+                None if is_permission_denied(&e) => ResponseCode::PERMISSION_DENIED.0,
+                None => ResponseCode::SYSTEM_ERROR.0,
             };
             Err(BinderStatus::new_service_specific_error(rc, None))
         },
@@ -182,11 +199,11 @@ struct ApcSessionState {
 struct ApcState {
     session: Option<ApcSessionState>,
     rate_limiting: HashMap<u32, RateInfo>,
-    confirmation_token_sender: Sender<Vec<u8>>,
+    confirmation_token_sender: Sender<ConfirmationToken>,
 }
 
 impl ApcState {
-    fn new(confirmation_token_sender: Sender<Vec<u8>>) -> Self {
+    fn new(confirmation_token_sender: Sender<ConfirmationToken>) -> Self {
         Self { session: None, rate_limiting: Default::default(), confirmation_token_sender }
     }
 }
@@ -201,7 +218,7 @@ impl Interface for ApcManager {}
 impl ApcManager {
     /// Create a new instance of the Android Protected Confirmation service.
     pub fn new_native_binder(
-        confirmation_token_sender: Sender<Vec<u8>>,
+        confirmation_token_sender: Sender<ConfirmationToken>,
     ) -> Result<Strong<dyn IProtectedConfirmation>> {
         Ok(BnProtectedConfirmation::new_binder(
             Self { state: Arc::new(Mutex::new(ApcState::new(confirmation_token_sender))) },
@@ -232,8 +249,25 @@ impl ApcManager {
                 // Reset counter.
                 state.rate_limiting.remove(&uid);
                 // Send confirmation token to the enforcement module.
-                if let Err(e) = state.confirmation_token_sender.send(confirmation_token.to_vec()) {
-                    log::error!("Got confirmation token, but receiver would not have it. {:?}", e);
+                match data_confirmed {
+                    Some(data_confirmed)
+                        if data_confirmed.len() <= MAX_CONFIRMATION_MESSAGE_SIZE =>
+                    {
+                        let confirmation_token = ConfirmationToken {
+                            uid,
+                            data_confirmed: data_confirmed.to_vec(),
+                            token: confirmation_token.to_vec(),
+                        };
+                        if let Err(e) = state.confirmation_token_sender.send(confirmation_token) {
+                            log::error!(
+                                "Got confirmation token, but receiver would not have it. {:?}",
+                                e
+                            );
+                        }
+                    }
+                    _ => log::error!(
+                        "Got confirmation token without confirmed data within the size limit."
+                    ),
                 }
             }
             // If cancelled by the user or if aborted by the client.
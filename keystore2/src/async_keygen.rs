@@ -0,0 +1,349 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the IKeystoreAsyncKeyGeneration AIDL interface. It is an opt-in
+//! variant of `IKeystoreSecurityLevel::generateKey` for key generations that are known to be
+//! slow, e.g., RSA-4096 in StrongBox. The request is validated on the binder thread, but the
+//! KeyMint call and storing the new key happen on a worker thread. The caller receives a ticket
+//! right away and learns the outcome through a callback or by polling.
+
+use crate::audit_log::{log_device_id_attestation, log_key_generated};
+use crate::caller_identity::CallerIdentity;
+use crate::error::{get_error_code, map_or_log_err, Error, RetryAfter};
+use crate::globals::TASK_EXECUTOR;
+use crate::metrics_store::log_key_creation_event_stats;
+use crate::security_level::KeystoreSecurityLevel;
+use crate::task_executor::Priority;
+use crate::utils::{is_device_id_attestation_tag, watchdog as wd};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyParameter::KeyParameter, SecurityLevel::SecurityLevel,
+};
+use android_security_keygen::aidl::android::security::keygen::{
+    IKeyGenerationCallback::IKeyGenerationCallback,
+    IKeystoreAsyncKeyGeneration::{BnKeystoreAsyncKeyGeneration, IKeystoreAsyncKeyGeneration},
+};
+use android_security_keygen::binder::{BinderFeatures, Interface, Result as BinderResult, Strong};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    KeyDescriptor::KeyDescriptor, KeyMetadata::KeyMetadata, ResponseCode::ResponseCode,
+};
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Maximal number of key generations that a single uid may have in flight.
+const MAX_PENDING_PER_UID: usize = 8;
+
+/// Maximal number of completed key generations of a single uid whose outcome is retained for
+/// polling. If more complete, the outcomes of the oldest are dropped.
+const MAX_RETAINED_PER_UID: usize = 16;
+
+lazy_static! {
+    /// The security levels that asynchronous key generations can be dispatched to. They are
+    /// registered by `KeystoreSecurityLevel::new_native_binder`.
+    static ref SECURITY_LEVELS: RwLock<HashMap<SecurityLevel, Arc<KeystoreSecurityLevel>>> =
+        Default::default();
+    /// The tickets of all asynchronous key generations that were not yet retrieved.
+    static ref TICKETS: Mutex<TicketTable<Result<KeyMetadata>>> = Default::default();
+}
+
+/// Makes the given security level available to asynchronous key generation. A security level
+/// that is registered again, e.g., after a late HAL came up, replaces the previous instance.
+pub fn register_security_level(
+    security_level: SecurityLevel,
+    instance: Arc<KeystoreSecurityLevel>,
+) {
+    SECURITY_LEVELS.write().unwrap().insert(security_level, instance);
+}
+
+/// Returns the registered instance of the given security level.
+pub fn get_security_level(security_level: SecurityLevel) -> Result<Arc<KeystoreSecurityLevel>> {
+    SECURITY_LEVELS
+        .read()
+        .unwrap()
+        .get(&security_level)
+        .cloned()
+        .ok_or(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+        .with_context(|| {
+            format!("In get_security_level: Security level {:?} is not available.", security_level)
+        })
+}
+
+/// Like `get_security_level`, but for the creation of new keys on the security level that the
+/// client asked for. Outside the embedded mode, the SOFTWARE security level only serves keys
+/// that fell back to software, see `software_fallback`, so clients cannot create keys on it.
+pub fn get_security_level_for_new_keys(
+    security_level: SecurityLevel,
+) -> Result<Arc<KeystoreSecurityLevel>> {
+    if security_level == SecurityLevel::SOFTWARE && !cfg!(feature = "embedded") {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context("In get_security_level_for_new_keys: Security level SOFTWARE is internal.");
+    }
+    get_security_level(security_level).context("In get_security_level_for_new_keys.")
+}
+
+enum TicketState<T> {
+    Pending,
+    Done(T),
+}
+
+struct Ticket<T> {
+    owner: u32,
+    state: TicketState<T>,
+}
+
+/// Bookkeeping of the tickets of asynchronous key generations. Tickets are never reused while
+/// keystore is running.
+struct TicketTable<T> {
+    next_ticket: i64,
+    tickets: BTreeMap<i64, Ticket<T>>,
+}
+
+impl<T> Default for TicketTable<T> {
+    fn default() -> Self {
+        Self { next_ticket: 1, tickets: Default::default() }
+    }
+}
+
+impl<T> TicketTable<T> {
+    /// Issues a new pending ticket for the given uid, or returns None if the uid already has
+    /// `MAX_PENDING_PER_UID` tickets pending.
+    fn issue(&mut self, owner: u32) -> Option<i64> {
+        let pending = self
+            .tickets
+            .values()
+            .filter(|t| t.owner == owner && matches!(t.state, TicketState::Pending))
+            .count();
+        if pending >= MAX_PENDING_PER_UID {
+            return None;
+        }
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.tickets.insert(ticket, Ticket { owner, state: TicketState::Pending });
+        Some(ticket)
+    }
+
+    /// Records the outcome of the given ticket and drops the oldest outcomes of its owner
+    /// beyond `MAX_RETAINED_PER_UID`.
+    fn complete(&mut self, ticket: i64, outcome: T) {
+        let owner = match self.tickets.get_mut(&ticket) {
+            Some(t) => {
+                t.state = TicketState::Done(outcome);
+                t.owner
+            }
+            None => return,
+        };
+        let done: Vec<i64> = self
+            .tickets
+            .iter()
+            .filter(|(_, t)| t.owner == owner && matches!(t.state, TicketState::Done(_)))
+            .map(|(ticket, _)| *ticket)
+            .collect();
+        for ticket in done.iter().take(done.len().saturating_sub(MAX_RETAINED_PER_UID)) {
+            self.tickets.remove(ticket);
+        }
+    }
+
+    /// Forgets the given ticket regardless of its state.
+    fn forget(&mut self, ticket: i64) {
+        self.tickets.remove(&ticket);
+    }
+
+    /// Returns Some(None) if the ticket is pending and Some(Some(outcome)) if it completed,
+    /// in which case the ticket is forgotten. Returns None if the ticket is unknown or owned by
+    /// another uid.
+    fn poll(&mut self, ticket: i64, caller: u32) -> Option<Option<T>> {
+        match self.tickets.get(&ticket) {
+            Some(t) if t.owner == caller => {}
+            _ => return None,
+        }
+        if let Some(TicketState::Pending) = self.tickets.get(&ticket).map(|t| &t.state) {
+            return Some(None);
+        }
+        match self.tickets.remove(&ticket) {
+            Some(Ticket { state: TicketState::Done(outcome), .. }) => Some(Some(outcome)),
+            _ => None,
+        }
+    }
+}
+
+/// This struct is defined to implement the aforementioned AIDL interface.
+pub struct AsyncKeyGeneration;
+
+impl AsyncKeyGeneration {
+    /// Creates a new instance of the asynchronous key generation service wrapped in a
+    /// BnKeystoreAsyncKeyGeneration proxy object. It also enables
+    /// `BinderFeatures::set_requesting_sid` on the new interface, because the permission checks
+    /// of generateKey require it.
+    pub fn new_native_binder() -> Result<Strong<dyn IKeystoreAsyncKeyGeneration>> {
+        Ok(BnKeystoreAsyncKeyGeneration::new_binder(
+            Self,
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        ))
+    }
+
+    fn generate_key_async(
+        security_level: SecurityLevel,
+        key: &KeyDescriptor,
+        attestation_key: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+        flags: i32,
+        entropy: &[u8],
+        callback: Option<&Strong<dyn IKeyGenerationCallback>>,
+    ) -> Result<i64> {
+        let sec_level =
+            get_security_level_for_new_keys(security_level).context("In generate_key_async.")?;
+        let caller = CallerIdentity::current();
+        // All checks that depend on the calling context happen here, on the binder thread.
+        let request = match sec_level.prepare_generate_key(
+            key,
+            attestation_key,
+            params,
+            flags,
+            entropy,
+            &caller,
+        ) {
+            Ok(request) => request,
+            Err(e) => {
+                log_key_generated(key, &caller, false);
+                return Err(e).context("In generate_key_async.");
+            }
+        };
+
+        let ticket = TICKETS
+            .lock()
+            .unwrap()
+            .issue(caller.uid())
+            .ok_or(Error::Rc(ResponseCode::BACKEND_BUSY))
+            .context(RetryAfter(sec_level.generate_retry_after()))
+            .context("In generate_key_async: Too many key generations in flight.")?;
+
+        let params = params.to_vec();
+        let callback = callback.cloned();
+        let retry_after = sec_level.generate_retry_after();
+        let spawned = TASK_EXECUTOR.spawn("keystore2_async_keygen", Priority::Normal, move |_| {
+            let key = request.key().clone();
+            let result = sec_level.complete_generate_key(request);
+            log_key_creation_event_stats(security_level, &params, &result);
+            log_key_generated(&key, &caller, result.is_ok());
+            if params.iter().any(|kp| is_device_id_attestation_tag(kp.tag)) {
+                log_device_id_attestation(&key, &caller, result.is_ok());
+            }
+            if let Some(callback) = callback {
+                let (metadata, error_code) = match &result {
+                    Ok(metadata) => (Some(metadata), 0),
+                    Err(e) => (None, get_error_code(e)),
+                };
+                if let Err(e) = callback.onKeyGenerated(ticket, metadata, error_code) {
+                    log::warn!("Failed to deliver outcome of ticket {}: {:?}", ticket, e);
+                }
+            }
+            TICKETS.lock().unwrap().complete(ticket, result);
+        });
+        if let Err(e) = spawned {
+            TICKETS.lock().unwrap().forget(ticket);
+            // The task queue is full.
+            return Err(Error::Rc(ResponseCode::BACKEND_BUSY))
+                .context(RetryAfter(retry_after))
+                .context(format!(
+                    "In generate_key_async: Failed to queue the generation: {:?}",
+                    e
+                ));
+        }
+        Ok(ticket)
+    }
+
+    fn poll_key_generation(ticket: i64) -> Result<Option<KeyMetadata>> {
+        let caller = CallerIdentity::current();
+        let outcome = TICKETS
+            .lock()
+            .unwrap()
+            .poll(ticket, caller.uid())
+            .ok_or(Error::Rc(ResponseCode::KEY_NOT_FOUND))
+            .context("In poll_key_generation: Unknown ticket.")?;
+        match outcome {
+            None => Ok(None),
+            Some(result) => result.map(Some).context("In poll_key_generation."),
+        }
+    }
+}
+
+impl Interface for AsyncKeyGeneration {}
+
+impl IKeystoreAsyncKeyGeneration for AsyncKeyGeneration {
+    fn generateKeyAsync(
+        &self,
+        security_level: SecurityLevel,
+        key: &KeyDescriptor,
+        attestation_key: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+        flags: i32,
+        entropy: &[u8],
+        callback: Option<&Strong<dyn IKeyGenerationCallback>>,
+    ) -> BinderResult<i64> {
+        let _wp = wd::watch_millis("IKeystoreAsyncKeyGeneration::generateKeyAsync", 500);
+        map_or_log_err(
+            Self::generate_key_async(
+                security_level,
+                key,
+                attestation_key,
+                params,
+                flags,
+                entropy,
+                callback,
+            ),
+            Ok,
+        )
+    }
+
+    fn pollKeyGeneration(&self, ticket: i64) -> BinderResult<Option<KeyMetadata>> {
+        let _wp = wd::watch_millis("IKeystoreAsyncKeyGeneration::pollKeyGeneration", 500);
+        map_or_log_err(Self::poll_key_generation(ticket), Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticket_table_bookkeeping() {
+        let mut table: TicketTable<u8> = Default::default();
+        let tickets: Vec<i64> =
+            (0..MAX_PENDING_PER_UID).map(|_| table.issue(10001).unwrap()).collect();
+        assert_eq!(table.issue(10001), None);
+        let other = table.issue(10002).unwrap();
+
+        assert_eq!(table.poll(tickets[0], 10001), Some(None));
+        assert_eq!(table.poll(tickets[0], 10002), None);
+        table.complete(tickets[0], 7);
+        assert_eq!(table.poll(tickets[0], 10001), Some(Some(7)));
+        assert_eq!(table.poll(tickets[0], 10001), None);
+
+        // A completed ticket frees a pending slot, and tickets are never reused.
+        let next = table.issue(10001).unwrap();
+        assert!(next > other);
+
+        for (i, ticket) in tickets[1..].iter().chain(std::iter::once(&next)).enumerate() {
+            table.complete(*ticket, i as u8);
+        }
+        for _ in 0..MAX_RETAINED_PER_UID {
+            let ticket = table.issue(10001).unwrap();
+            table.complete(ticket, 0);
+        }
+        // Only the newest outcomes are retained.
+        assert_eq!(table.poll(tickets[1], 10001), None);
+        assert_eq!(table.poll(other, 10002), Some(None));
+    }
+}
@@ -93,6 +93,8 @@ struct AsyncTaskState {
     hi_prio_req: VecDeque<Box<dyn FnOnce(&mut Shelf) + Send>>,
     lo_prio_req: VecDeque<Box<dyn FnOnce(&mut Shelf) + Send>>,
     idle_fns: Vec<Arc<dyn Fn(&mut Shelf) + Send + Sync>>,
+    /// Set by `AsyncTask::shutdown` to make the worker exit as soon as the queues are empty.
+    shutdown_requested: bool,
     /// The store allows tasks to store state across invocations. It is passed to each invocation
     /// of each task. Tasks need to cooperate on the ids they use for storing state.
     shelf: Option<Shelf>,
@@ -126,6 +128,7 @@ impl AsyncTask {
                     hi_prio_req: VecDeque::new(),
                     lo_prio_req: VecDeque::new(),
                     idle_fns: Vec::new(),
+                    shutdown_requested: false,
                     shelf: None,
                 }),
             )),
@@ -164,6 +167,26 @@ impl AsyncTask {
         state.idle_fns.push(Arc::new(f));
     }
 
+    /// Runs all queued jobs to completion, stops the worker thread, and drops the shelf, so
+    /// that the next job starts from a clean state. Idle callbacks stay registered.
+    /// This must not be called from a job of this task, because it waits for the worker thread.
+    pub fn shutdown(&self) {
+        let (ref condvar, ref state) = *self.state;
+        let thread = {
+            let mut state = state.lock().unwrap();
+            state.shutdown_requested = true;
+            state.thread.take()
+        };
+        condvar.notify_all();
+        if let Some(t) = thread {
+            t.join().expect("AsyncTask panicked.");
+        }
+        let mut state = state.lock().unwrap();
+        state.shutdown_requested = false;
+        state.state = State::Exiting;
+        state.shelf = None;
+    }
+
     fn queue<F>(&self, f: F, hi_prio: bool)
     where
         F: for<'r> FnOnce(&'r mut Shelf) + Send + 'static,
@@ -213,7 +236,9 @@ impl AsyncTask {
                         // Wait for either a queued job to arrive or a timeout.
                         let (mut state, timeout) = condvar
                             .wait_timeout_while(state, timeout_period, |state| {
-                                state.hi_prio_req.is_empty() && state.lo_prio_req.is_empty()
+                                state.hi_prio_req.is_empty()
+                                    && state.lo_prio_req.is_empty()
+                                    && !state.shutdown_requested
                             })
                             .unwrap();
                         match (
@@ -225,7 +250,7 @@ impl AsyncTask {
                             (None, false, _) => {
                                 state.lo_prio_req.pop_front().map(|f| Action::QueuedFn(f))
                             }
-                            (None, true, true) => {
+                            (None, true, timed_out) if timed_out || state.shutdown_requested => {
                                 // When the worker exits it puts the shelf back into the shared
                                 // state for the next worker to use. So state is preserved not
                                 // only across invocations but also across worker thread shut down.
@@ -529,4 +554,25 @@ mod tests {
         });
         done_receiver.recv().unwrap();
     }
+
+    #[test]
+    fn test_async_task_shutdown() {
+        let at = AsyncTask::new(Duration::from_secs(30));
+        let (sender, receiver) = channel();
+        at.queue_lo(move |shelf| {
+            shelf.put(7u32);
+            sender.send(()).unwrap();
+        });
+        // Shutdown runs the queued job to completion even though the worker would otherwise
+        // linger for the whole timeout.
+        at.shutdown();
+        assert_eq!(receiver.try_recv(), Ok(()));
+
+        // The task can be used again, and the shelf was cleared.
+        let (sender, receiver) = channel();
+        at.queue_hi(move |shelf| {
+            sender.send(shelf.get_downcast_ref::<u32>().copied()).unwrap();
+        });
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(1)), Ok(None));
+    }
 }
@@ -0,0 +1,188 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module abstracts the retrieval of the device identifiers that KeyMint includes in
+//! attestation certificates. Callers may request that Keystore supplies an identifier by
+//! passing the corresponding attestation ID tag with an empty value. The value is then taken
+//! from the installed `AttestationIdProvider`. The default provider reads the system
+//! properties that the framework uses for attestation. Devices that derive identifiers
+//! differently, e.g., an IMEI derived from an eSIM, can install their own provider with
+//! `set_provider`.
+
+use crate::error::Error;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    ErrorCode::ErrorCode, KeyParameter::KeyParameter, KeyParameterValue::KeyParameterValue,
+    Tag::Tag,
+};
+use anyhow::{Context, Result};
+use keystore2_system_property::{PropertyWatcher, PropertyWatcherError};
+use lazy_static::lazy_static;
+use std::sync::RwLock;
+
+/// Supplies the device identifiers used for ID attestation.
+pub trait AttestationIdProvider: Send + Sync {
+    /// Returns the value of the identifier denoted by the attestation ID tag `tag`, or None if
+    /// the provider does not know the identifier.
+    fn get_id(&self, tag: Tag) -> Result<Option<Vec<u8>>>;
+}
+
+/// The default provider. It reads the `*_for_attestation` system properties, falling back to
+/// the regular product properties, and the serial number. The radio identifiers are owned by
+/// telephony and are not available to Keystore, so they must be passed in by the caller.
+pub struct SystemPropertyIdProvider;
+
+impl SystemPropertyIdProvider {
+    fn properties(tag: Tag) -> &'static [&'static str] {
+        match tag {
+            Tag::ATTESTATION_ID_BRAND => &["ro.product.brand_for_attestation", "ro.product.brand"],
+            Tag::ATTESTATION_ID_DEVICE => {
+                &["ro.product.device_for_attestation", "ro.product.device"]
+            }
+            Tag::ATTESTATION_ID_PRODUCT => &["ro.product.name_for_attestation", "ro.product.name"],
+            Tag::ATTESTATION_ID_MANUFACTURER => {
+                &["ro.product.manufacturer_for_attestation", "ro.product.manufacturer"]
+            }
+            Tag::ATTESTATION_ID_MODEL => &["ro.product.model_for_attestation", "ro.product.model"],
+            Tag::ATTESTATION_ID_SERIAL => &["ro.serialno"],
+            _ => &[],
+        }
+    }
+
+    fn read_property(name: &str) -> Result<Option<String>> {
+        let mut watcher = PropertyWatcher::new(name)
+            .context("In SystemPropertyIdProvider::read_property: Failed to create watcher.")?;
+        match watcher.read(|_n, v| Ok(v.to_string())) {
+            Ok(value) if !value.is_empty() => Ok(Some(value)),
+            Ok(_) | Err(PropertyWatcherError::SystemPropertyAbsent) => Ok(None),
+            Err(e) => Err(e).context(format!(
+                "In SystemPropertyIdProvider::read_property: Failed to read {}.",
+                name
+            )),
+        }
+    }
+}
+
+impl AttestationIdProvider for SystemPropertyIdProvider {
+    fn get_id(&self, tag: Tag) -> Result<Option<Vec<u8>>> {
+        for name in Self::properties(tag) {
+            if let Some(value) = Self::read_property(name).context("In get_id.")? {
+                return Ok(Some(value.into_bytes()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+lazy_static! {
+    static ref PROVIDER: RwLock<Box<dyn AttestationIdProvider>> =
+        RwLock::new(Box::new(SystemPropertyIdProvider));
+}
+
+/// Replaces the attestation ID provider used by `fill_attestation_ids`.
+pub fn set_provider(provider: Box<dyn AttestationIdProvider>) {
+    *PROVIDER.write().unwrap() = provider;
+}
+
+fn is_attestation_id_tag(tag: Tag) -> bool {
+    matches!(
+        tag,
+        Tag::ATTESTATION_ID_BRAND
+            | Tag::ATTESTATION_ID_DEVICE
+            | Tag::ATTESTATION_ID_PRODUCT
+            | Tag::ATTESTATION_ID_SERIAL
+            | Tag::ATTESTATION_ID_IMEI
+            | Tag::ATTESTATION_ID_MEID
+            | Tag::ATTESTATION_ID_MANUFACTURER
+            | Tag::ATTESTATION_ID_MODEL
+    )
+}
+
+/// Replaces the values of the attestation ID parameters in `params` that were passed empty
+/// with the identifiers supplied by `provider`. Fails with `ErrorCode::CANNOT_ATTEST_IDS` if
+/// the provider does not know a requested identifier.
+pub fn fill_attestation_ids_with(
+    provider: &dyn AttestationIdProvider,
+    params: &mut [KeyParameter],
+) -> Result<()> {
+    for param in params.iter_mut().filter(|kp| is_attestation_id_tag(kp.tag)) {
+        if !matches!(&param.value, KeyParameterValue::Blob(value) if value.is_empty()) {
+            continue;
+        }
+        let id = provider
+            .get_id(param.tag)
+            .context("In fill_attestation_ids_with: Failed to get attestation ID.")?
+            .ok_or(Error::Km(ErrorCode::CANNOT_ATTEST_IDS))
+            .with_context(|| {
+                format!("In fill_attestation_ids_with: No value known for {:?}.", param.tag)
+            })?;
+        param.value = KeyParameterValue::Blob(id);
+    }
+    Ok(())
+}
+
+/// Like `fill_attestation_ids_with` using the installed provider.
+pub fn fill_attestation_ids(params: &mut [KeyParameter]) -> Result<()> {
+    fill_attestation_ids_with(&**PROVIDER.read().unwrap(), params)
+        .context("In fill_attestation_ids.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct FakeProvider(HashMap<Tag, Vec<u8>>);
+
+    impl AttestationIdProvider for FakeProvider {
+        fn get_id(&self, tag: Tag) -> Result<Option<Vec<u8>>> {
+            Ok(self.0.get(&tag).cloned())
+        }
+    }
+
+    fn blob_param(tag: Tag, value: &[u8]) -> KeyParameter {
+        KeyParameter { tag, value: KeyParameterValue::Blob(value.to_vec()) }
+    }
+
+    #[test]
+    fn fills_empty_ids_only() {
+        let provider = FakeProvider(
+            [(Tag::ATTESTATION_ID_IMEI, b"490154203237518".to_vec())].iter().cloned().collect(),
+        );
+        let mut params = vec![
+            blob_param(Tag::ATTESTATION_ID_IMEI, b""),
+            blob_param(Tag::ATTESTATION_ID_BRAND, b"caller brand"),
+            blob_param(Tag::ATTESTATION_CHALLENGE, b""),
+        ];
+        fill_attestation_ids_with(&provider, &mut params).unwrap();
+        assert_eq!(
+            params,
+            vec![
+                blob_param(Tag::ATTESTATION_ID_IMEI, b"490154203237518"),
+                blob_param(Tag::ATTESTATION_ID_BRAND, b"caller brand"),
+                blob_param(Tag::ATTESTATION_CHALLENGE, b""),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_id_cannot_be_attested() {
+        let provider = FakeProvider(HashMap::new());
+        let mut params = vec![blob_param(Tag::ATTESTATION_ID_SERIAL, b"")];
+        let e = fill_attestation_ids_with(&provider, &mut params).unwrap_err();
+        assert_eq!(
+            e.root_cause().downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::CANNOT_ATTEST_IDS))
+        );
+    }
+}
@@ -20,7 +20,7 @@ use crate::database::{KeyIdGuard, KeystoreDB};
 use crate::error::{Error, ErrorCode};
 use crate::permission::KeyPerm;
 use crate::remote_provisioning::RemProvState;
-use crate::utils::check_key_permission;
+use crate::utils::{check_client_context, check_key_permission};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     AttestationKey::AttestationKey, Certificate::Certificate, KeyParameter::KeyParameter, Tag::Tag,
 };
@@ -112,6 +112,8 @@ fn load_attest_key_blob_and_cert(
                     |k, av| check_key_permission(KeyPerm::use_(), k, &av),
                 )
                 .context("In load_attest_key_blob_and_cert: Failed to load key.")?;
+            check_client_context(key_entry.metadata())
+                .context("In load_attest_key_blob_and_cert: Checking client context.")?;
 
             let (blob, blob_metadata) =
                 key_entry.take_key_blob_info().ok_or_else(Error::sys).context(concat!(
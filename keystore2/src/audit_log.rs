@@ -13,19 +13,49 @@
 // limitations under the License.
 
 //! This module implements functions to log audit events to binary security log buffer for NIAP
-//! compliance.
+//! compliance. Events are only recorded while security logging is enabled by device policy.
 
-use crate::globals::LOGS_HANDLER;
+use crate::caller_identity::CallerIdentity;
+use crate::globals::{DB_PATH, LOGS_HANDLER};
+use crate::permission::PermissionDenied;
 use android_system_keystore2::aidl::android::system::keystore2::{
     Domain::Domain, KeyDescriptor::KeyDescriptor,
 };
-use libc::uid_t;
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
 use log_event_list::{LogContext, LogIdSecurity};
+use std::fs;
+use std::io::ErrorKind;
+use std::sync::RwLock;
 
 const TAG_KEY_GENERATED: u32 = 210024;
 const TAG_KEY_IMPORTED: u32 = 210025;
 const TAG_KEY_DESTROYED: u32 = 210026;
 const TAG_KEY_INTEGRITY_VIOLATION: u32 = 210032;
+// Declared in event.logtags.
+const TAG_KEY_MATERIAL_EXPORTED: u32 = 210044;
+const TAG_KEY_ESCROWED: u32 = 210045;
+const TAG_KEY_ESCROW_RECORD_RETRIEVED: u32 = 210046;
+const TAG_KEYSTORE_RESET: u32 = 210047;
+const TAG_USER_KEYS_RESET: u32 = 210048;
+const TAG_NAMESPACE_CLEARED: u32 = 210049;
+const TAG_DEVICE_OWNER_KEY_GRANTED: u32 = 210050;
+const TAG_DEVICE_ID_ATTESTATION: u32 = 210051;
+const TAG_FS_VERITY_CERT_PROVISIONED: u32 = 210052;
+const TAG_FS_VERITY_CERT_REMOVED: u32 = 210053;
+const TAG_DATABASE_QUARANTINED: u32 = 210054;
+const TAG_NAMESPACE_FROZEN: u32 = 210055;
+const TAG_PERMISSION_DENIED: u32 = 210056;
+
+/// The file in the database directory holding the uid of the device owner app, whose grants
+/// are logged, on a single line. Without it, grants are not logged. It is written by
+/// `set_device_owner_uid` on behalf of the device policy manager.
+const DEVICE_OWNER_FILE_NAME: &str = "device_owner_uid";
+
+lazy_static! {
+    /// The uid of the device owner app, mirrored from `DEVICE_OWNER_FILE_NAME`.
+    static ref DEVICE_OWNER_UID: RwLock<Option<u32>> = Default::default();
+}
 
 const FLAG_NAMESPACE: i64 = 0x80000000;
 
@@ -42,18 +72,185 @@ fn key_owner(domain: Domain, nspace: i64, uid: i32) -> i32 {
 }
 
 /// Logs key generation event to NIAP audit log.
-pub fn log_key_generated(key: &KeyDescriptor, calling_app: uid_t, success: bool) {
-    log_key_event(TAG_KEY_GENERATED, key, calling_app, success);
+pub fn log_key_generated(key: &KeyDescriptor, caller: &CallerIdentity, success: bool) {
+    log_key_event(TAG_KEY_GENERATED, key, caller, success);
 }
 
 /// Logs key import event to NIAP audit log.
-pub fn log_key_imported(key: &KeyDescriptor, calling_app: uid_t, success: bool) {
-    log_key_event(TAG_KEY_IMPORTED, key, calling_app, success);
+pub fn log_key_imported(key: &KeyDescriptor, caller: &CallerIdentity, success: bool) {
+    log_key_event(TAG_KEY_IMPORTED, key, caller, success);
 }
 
 /// Logs key deletion event to NIAP audit log.
-pub fn log_key_deleted(key: &KeyDescriptor, calling_app: uid_t, success: bool) {
-    log_key_event(TAG_KEY_DESTROYED, key, calling_app, success);
+pub fn log_key_deleted(key: &KeyDescriptor, caller: &CallerIdentity, success: bool) {
+    log_key_event(TAG_KEY_DESTROYED, key, caller, success);
+}
+
+/// Logs the export of key material through the debug only path to the audit log.
+pub fn log_key_material_exported(key: &KeyDescriptor, caller: &CallerIdentity, success: bool) {
+    log_key_event(TAG_KEY_MATERIAL_EXPORTED, key, caller, success);
+}
+
+/// Logs the escrow of a key at creation to the audit log.
+pub fn log_key_escrowed(key: &KeyDescriptor, caller: &CallerIdentity, success: bool) {
+    log_key_event(TAG_KEY_ESCROWED, key, caller, success);
+}
+
+/// Logs the retrieval of the escrow record of a key to the audit log.
+pub fn log_key_escrow_record_retrieved(
+    key: &KeyDescriptor,
+    caller: &CallerIdentity,
+    success: bool,
+) {
+    log_key_event(TAG_KEY_ESCROW_RECORD_RETRIEVED, key, caller, success);
+}
+
+/// Logs the deletion of all keys from all security levels to the audit log.
+pub fn log_keystore_reset(caller: &CallerIdentity, success: bool) {
+    with_log_context(TAG_KEYSTORE_RESET, |ctx| {
+        ctx.append_i32(if success { 1 } else { 0 }).append_i32(caller.uid() as i32)
+    })
+}
+
+/// Logs the deletion of all keys of an Android user to the audit log.
+pub fn log_user_keys_reset(user_id: u32, caller: &CallerIdentity, success: bool) {
+    with_log_context(TAG_USER_KEYS_RESET, |ctx| {
+        ctx.append_i32(if success { 1 } else { 0 })
+            .append_i32(user_id as i32)
+            .append_i32(caller.uid() as i32)
+    })
+}
+
+/// Logs the deletion of all keys of a namespace to the audit log.
+pub fn log_namespace_cleared(domain: Domain, nspace: i64, caller: &CallerIdentity, success: bool) {
+    with_log_context(TAG_NAMESPACE_CLEARED, |ctx| {
+        ctx.append_i32(if success { 1 } else { 0 })
+            .append_i32(key_owner(domain, nspace, nspace as i32))
+            .append_i32(caller.uid() as i32)
+    })
+}
+
+/// Logs the freezing or unfreezing of a namespace to the audit log.
+pub fn log_namespace_frozen(
+    domain: Domain,
+    nspace: i64,
+    frozen: bool,
+    caller: &CallerIdentity,
+    success: bool,
+) {
+    with_log_context(TAG_NAMESPACE_FROZEN, |ctx| {
+        ctx.append_i32(if success { 1 } else { 0 })
+            .append_i32(if frozen { 1 } else { 0 })
+            .append_i32(key_owner(domain, nspace, nspace as i32))
+            .append_i32(caller.uid() as i32)
+    })
+}
+
+/// Logs a grant to the audit log if the granting caller is the device owner.
+pub fn log_key_granted(
+    key: &KeyDescriptor,
+    grantee_uid: u32,
+    caller: &CallerIdentity,
+    success: bool,
+) {
+    if *DEVICE_OWNER_UID.read().unwrap() != Some(caller.uid()) {
+        return;
+    }
+    with_log_context(TAG_DEVICE_OWNER_KEY_GRANTED, |ctx| {
+        ctx.append_i32(if success { 1 } else { 0 })
+            .append_str(key.alias.as_ref().map_or("none", String::as_str))
+            .append_i32(key_owner(key.domain, key.nspace, caller.uid() as i32))
+            .append_i32(grantee_uid as i32)
+    })
+}
+
+/// Logs the generation of a key whose attestation includes device identifiers to the audit log.
+pub fn log_device_id_attestation(key: &KeyDescriptor, caller: &CallerIdentity, success: bool) {
+    log_key_event(TAG_DEVICE_ID_ATTESTATION, key, caller, success);
+}
+
+/// Logs the provisioning of a certificate for the fs-verity keyring to the audit log.
+pub fn log_fs_verity_cert_provisioned(name: &str, caller: &CallerIdentity, success: bool) {
+    with_log_context(TAG_FS_VERITY_CERT_PROVISIONED, |ctx| {
+        ctx.append_i32(if success { 1 } else { 0 }).append_str(name).append_i32(caller.uid() as i32)
+    })
+}
+
+/// Logs the removal of a certificate for the fs-verity keyring to the audit log.
+pub fn log_fs_verity_cert_removed(name: &str, caller: &CallerIdentity, success: bool) {
+    with_log_context(TAG_FS_VERITY_CERT_REMOVED, |ctx| {
+        ctx.append_i32(if success { 1 } else { 0 }).append_str(name).append_i32(caller.uid() as i32)
+    })
+}
+
+/// Logs that the persistent database was found corrupt and replaced by an empty one, i.e.,
+/// that all keys were lost.
+pub fn log_database_quarantined(problems: usize) {
+    with_log_context(TAG_DATABASE_QUARANTINED, |ctx| ctx.append_i32(problems as i32))
+}
+
+/// Logs a permission denial reported to the caller to the audit log. The denied permission is
+/// taken from the `PermissionDenied` root cause of the error; denials from other sources, see
+/// `permission::is_permission_denied`, are logged with an empty class and permission.
+pub fn log_permission_denied(e: &anyhow::Error, caller: &CallerIdentity) {
+    let (class, perm) = e
+        .root_cause()
+        .downcast_ref::<PermissionDenied>()
+        .map_or(("", ""), |denied| (denied.class, denied.perm));
+    with_log_context(TAG_PERMISSION_DENIED, |ctx| {
+        ctx.append_str(class).append_str(perm).append_i32(caller.uid() as i32)
+    })
+}
+
+/// Loads the uid of the device owner app stored by `set_device_owner_uid`. Must be called on
+/// startup, after `DB_PATH` was set, see `Maintenance::new_native_binder`. Errors are logged, and grants are not logged then.
+pub fn load_device_owner_uid_on_startup() {
+    let path = DB_PATH.read().expect("Could not get DB_PATH.").join(DEVICE_OWNER_FILE_NAME);
+    let uid = match fs::read_to_string(&path) {
+        Ok(content) => parse_device_owner_uid(&content),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context("Failed to read device owner uid."),
+    };
+    match uid {
+        Ok(uid) => *DEVICE_OWNER_UID.write().unwrap() = uid,
+        Err(e) => log::error!("In load_device_owner_uid_on_startup: {:?}", e),
+    }
+}
+
+/// Sets or, if `uid` is None, clears the uid of the device owner app whose grants are logged.
+/// The uid is stored in the database directory, so that it survives restarts of Keystore, and
+/// takes effect immediately.
+pub fn set_device_owner_uid(uid: Option<u32>) -> Result<()> {
+    let path = DB_PATH.read().expect("Could not get DB_PATH.").join(DEVICE_OWNER_FILE_NAME);
+    // Held while writing, so that the last writer's uid is both on disk and in memory.
+    let mut device_owner_uid = DEVICE_OWNER_UID.write().unwrap();
+    match uid {
+        Some(uid) => fs::write(&path, format!("{}\n", uid))
+            .context("In set_device_owner_uid: Failed to write device owner uid."),
+        None => match fs::remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).context("In set_device_owner_uid: Failed to remove device owner uid.")
+            }
+            _ => Ok(()),
+        },
+    }?;
+    *device_owner_uid = uid;
+    Ok(())
+}
+
+/// Parses the content of `DEVICE_OWNER_FILE_NAME`. Empty lines and lines starting with `#` are
+/// ignored.
+fn parse_device_owner_uid(config: &str) -> Result<Option<u32>> {
+    let mut lines =
+        config.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#'));
+    let uid = match lines.next() {
+        Some(uid) => uid.parse::<u32>().context("Bad device owner uid.")?,
+        None => return Ok(None),
+    };
+    if lines.next().is_some() {
+        return Err(anyhow!("Expected a single device owner uid."));
+    }
+    Ok(Some(uid))
 }
 
 /// Logs key integrity violation to NIAP audit log.
@@ -64,13 +261,20 @@ pub fn log_key_integrity_violation(key: &KeyDescriptor) {
     })
 }
 
-fn log_key_event(tag: u32, key: &KeyDescriptor, calling_app: uid_t, success: bool) {
-    with_log_context(tag, |ctx| {
-        let owner = key_owner(key.domain, key.nspace, calling_app as i32);
-        ctx.append_i32(if success { 1 } else { 0 })
+/// Logs a key event attributed to the caller. The package of the caller is resolved on the
+/// logging thread, because it may require a call into the package manager.
+fn log_key_event(tag: u32, key: &KeyDescriptor, caller: &CallerIdentity, success: bool) {
+    if let Some(ctx) = LogContext::new(LogIdSecurity, tag) {
+        let owner = key_owner(key.domain, key.nspace, caller.uid() as i32);
+        let ctx = ctx
+            .append_i32(if success { 1 } else { 0 })
             .append_str(key.alias.as_ref().map_or("none", String::as_str))
-            .append_i32(owner)
-    })
+            .append_i32(owner);
+        let caller = caller.clone();
+        LOGS_HANDLER.queue_lo(move |_| {
+            ctx.append_str(caller.package_name().as_deref().unwrap_or("none")).write();
+        });
+    }
 }
 
 fn with_log_context<F>(tag: u32, f: F)
@@ -84,3 +288,17 @@ where
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_device_owner_uid() {
+        assert_eq!(parse_device_owner_uid("").unwrap(), None);
+        assert_eq!(parse_device_owner_uid("# No device owner.\n").unwrap(), None);
+        assert_eq!(parse_device_owner_uid("# Device owner\n 10123 \n\n").unwrap(), Some(10123));
+        assert!(parse_device_owner_uid("owner").is_err());
+        assert!(parse_device_owner_uid("10123\n10124").is_err());
+    }
+}
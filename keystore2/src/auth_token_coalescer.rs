@@ -0,0 +1,72 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the periodic coalescing of the per boot auth token table. Heavy
+//! biometric use leaves many tokens behind that were superseded by more recent tokens of the
+//! same user and authenticator. A compaction pass runs right after Keystore started, and
+//! further passes run periodically. The token counts before and after each pass are logged
+//! to metrics.
+
+use crate::globals::{ASYNC_TASK, DB, TASK_EXECUTOR};
+use crate::metrics_store::log_auth_token_coalescing_stats;
+use crate::task_executor::Priority;
+use keystore2_system_property::PropertyWatcher;
+use std::time::Duration;
+
+/// System property holding the interval between coalescing passes in seconds. Zero disables
+/// the periodic passes.
+const COALESCE_INTERVAL_PROPERTY: &str = "keystore.auth_token_coalesce_interval_seconds";
+
+/// Default interval between coalescing passes.
+const DEFAULT_COALESCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+fn coalesce_interval() -> Option<Duration> {
+    let seconds = PropertyWatcher::new(COALESCE_INTERVAL_PROPERTY)
+        .ok()
+        .and_then(|mut w| w.read(|_n, v| v.parse::<u64>().map_err(std::convert::Into::into)).ok());
+    match seconds {
+        Some(0) => None,
+        Some(seconds) => Some(Duration::from_secs(seconds)),
+        None => Some(DEFAULT_COALESCE_INTERVAL),
+    }
+}
+
+fn coalesce(at_startup: bool) {
+    ASYNC_TASK.queue_lo(move |_| {
+        let (before, after) = DB.with(|db| db.borrow().coalesce_auth_tokens());
+        if before != after {
+            log::info!("Coalesced auth tokens from {} to {}.", before, after);
+        }
+        log_auth_token_coalescing_stats(before, after, at_startup);
+    });
+}
+
+/// Runs the compaction pass and starts the periodic coalescing if it is not disabled by the
+/// system property `keystore.auth_token_coalesce_interval_seconds`.
+pub fn start() {
+    coalesce(true);
+    let interval = match coalesce_interval() {
+        Some(interval) => interval,
+        None => return,
+    };
+    let spawned = TASK_EXECUTOR.spawn_periodic(
+        "keystore2_auth_token_coalescer",
+        Priority::Background,
+        interval,
+        || coalesce(false),
+    );
+    if let Err(e) = spawned {
+        log::error!("Failed to start the auth token coalescer: {:?}", e);
+    }
+}
@@ -14,9 +14,13 @@
 
 //! This module implements IKeystoreAuthorization AIDL interface.
 
+use crate::blob_verification;
+use crate::database::{KeyEntryLoadBits, KeyType};
 use crate::error::Error as KeystoreError;
 use crate::globals::{ENFORCEMENTS, SUPER_KEY, DB, LEGACY_MIGRATOR};
-use crate::permission::KeystorePerm;
+use crate::perboot_recovery;
+use crate::permission::{is_permission_denied, KeystorePerm};
+use crate::storage_key;
 use crate::super_key::UserState;
 use crate::utils::{check_keystore_permission, watchdog as wd};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
@@ -25,15 +29,15 @@ use android_hardware_security_keymint::aidl::android::hardware::security::keymin
 use android_security_authorization::binder::{BinderFeatures,ExceptionCode, Interface, Result as BinderResult,
      Strong, Status as BinderStatus};
 use android_security_authorization::aidl::android::security::authorization::{
+    IAuthTokenRecoveryListener::IAuthTokenRecoveryListener,
     IKeystoreAuthorization::BnKeystoreAuthorization, IKeystoreAuthorization::IKeystoreAuthorization,
     LockScreenEvent::LockScreenEvent, AuthorizationTokens::AuthorizationTokens,
     ResponseCode::ResponseCode,
 };
 use android_system_keystore2::aidl::android::system::keystore2::{
-    ResponseCode::ResponseCode as KsResponseCode };
+    Domain::Domain, KeyDescriptor::KeyDescriptor, ResponseCode::ResponseCode as KsResponseCode };
 use anyhow::{Context, Result};
 use keystore2_crypto::Password;
-use keystore2_selinux as selinux;
 
 /// This is the Authorization error type, it wraps binder exceptions and the
 /// Authorization ResponseCode
@@ -58,7 +62,8 @@ pub enum Error {
 /// which are then converted to the corresponding response codes of android.security.authorization
 /// AIDL interface specification.
 ///
-/// `selinux::Error::perm()` is mapped on `ResponseCode::PERMISSION_DENIED`.
+/// Permission denials, see `permission::is_permission_denied`, are mapped on
+/// `ResponseCode::PERMISSION_DENIED`.
 ///
 /// All non `Error` error conditions get mapped onto ResponseCode::SYSTEM_ERROR`.
 ///
@@ -93,10 +98,8 @@ where
             let rc = match root_cause.downcast_ref::<Error>() {
                 Some(Error::Rc(rcode)) => rcode.0,
                 Some(Error::Binder(_, _)) => ResponseCode::SYSTEM_ERROR.0,
-                None => match root_cause.downcast_ref::<selinux::Error>() {
-                    Some(selinux::Error::PermissionDenied) => ResponseCode::PERMISSION_DENIED.0,
-                    _ => ResponseCode::SYSTEM_ERROR.0,
-                },
+                None if is_permission_denied(&e) => ResponseCode::PERMISSION_DENIED.0,
+                None => ResponseCode::SYSTEM_ERROR.0,
             };
             Err(BinderStatus::new_service_specific_error(rc, None))
         },
@@ -125,6 +128,17 @@ impl AuthorizationManager {
         Ok(())
     }
 
+    fn set_auth_token_recovery_listener(
+        &self,
+        listener: Option<&Strong<dyn IAuthTokenRecoveryListener>>,
+    ) -> Result<()> {
+        // Permission check. Must return on error. Do not touch the '?'.
+        check_keystore_permission(KeystorePerm::add_auth())
+            .context("In set_auth_token_recovery_listener.")?;
+        perboot_recovery::set_listener(listener);
+        Ok(())
+    }
+
     fn on_lock_screen_event(
         &self,
         lock_screen_event: LockScreenEvent,
@@ -173,6 +187,7 @@ impl AuthorizationManager {
                         "In on_lock_screen_event. Trying to unlock when LSKF is uninitialized."
                     );
                 }
+                blob_verification::schedule(user_id as u32);
 
                 Ok(())
             }
@@ -184,6 +199,7 @@ impl AuthorizationManager {
                     SUPER_KEY.try_unlock_user_with_biometric(&mut db.borrow_mut(), user_id as u32)
                 })
                 .context("In on_lock_screen_event: try_unlock_user_with_biometric failed")?;
+                blob_verification::schedule(user_id as u32);
                 Ok(())
             }
             (LockScreenEvent::LOCK, None) => {
@@ -197,6 +213,8 @@ impl AuthorizationManager {
                         unlocking_sids.unwrap_or(&[]),
                     );
                 });
+                // Vold converts the storage keys again when the user is unlocked.
+                storage_key::clear_cache();
                 Ok(())
             }
             _ => {
@@ -228,6 +246,30 @@ impl AuthorizationManager {
             ENFORCEMENTS.get_auth_tokens(challenge, secure_user_id, auth_token_max_age_millis)?;
         Ok(AuthorizationTokens { authToken: auth_token, timestampToken: ts_token })
     }
+
+    fn approve_key_use(&self, key_id: i64, uid: i32) -> Result<i64> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::approve_key_use())
+            .context("In approve_key_use.")?;
+        let key = KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, alias: None, blob: None };
+        // The approve_key_use permission covers all keys, so there is no key permission check.
+        let (_, key_entry) = DB
+            .with(|db| {
+                db.borrow_mut().load_key_entry(
+                    &key,
+                    KeyType::Client,
+                    KeyEntryLoadBits::NONE,
+                    uid as u32,
+                    |_, _| Ok(()),
+                )
+            })
+            .context("In approve_key_use: Failed to load key.")?;
+        if key_entry.metadata().user_mediated() != Some(&true) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In approve_key_use: The key is not user mediated.");
+        }
+        ENFORCEMENTS.approve_key_use(key_id, uid as u32).context("In approve_key_use.")
+    }
 }
 
 impl Interface for AuthorizationManager {}
@@ -238,6 +280,14 @@ impl IKeystoreAuthorization for AuthorizationManager {
         map_or_log_err(self.add_auth_token(auth_token), Ok)
     }
 
+    fn setAuthTokenRecoveryListener(
+        &self,
+        listener: Option<&Strong<dyn IAuthTokenRecoveryListener>>,
+    ) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreAuthorization::setAuthTokenRecoveryListener", 500);
+        map_or_log_err(self.set_auth_token_recovery_listener(listener), Ok)
+    }
+
     fn onLockScreenEvent(
         &self,
         lock_screen_event: LockScreenEvent,
@@ -276,4 +326,9 @@ impl IKeystoreAuthorization for AuthorizationManager {
             Ok,
         )
     }
+
+    fn approveKeyUse(&self, key_id: i64, uid: i32) -> BinderResult<i64> {
+        let _wp = wd::watch_millis("IKeystoreAuthorization::approveKeyUse", 500);
+        map_or_log_err(self.approve_key_use(key_id, uid), Ok)
+    }
 }
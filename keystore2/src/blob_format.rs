@@ -0,0 +1,115 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module is the registry of the formats that Keystore uses to wrap key material with
+//! its own super keys. The format covers the layout of the encrypted blob and the set and
+//! encoding of the blob metadata entries that are needed to decrypt it. Keystore can read
+//! every format listed here, but only ever writes `SuperEncryptionFormat::CURRENT`.
+//!
+//! The format of a blob is recorded in its metadata. Blobs without a format version were
+//! written before formats were versioned. Blobs with a version that is not known to this
+//! release were written by a newer release, e.g., before a downgrade. These are refused
+//! instead of being decrypted with the wrong assumptions, and, most importantly, they are
+//! never rewritten, so that the entry remains intact for the release that wrote it.
+
+use crate::database::{BlobMetaData, BlobMetaEntry};
+use crate::error::{Error, ResponseCode};
+use anyhow::{Context, Result};
+
+/// The known formats of super encrypted blobs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SuperEncryptionFormat {
+    /// Blobs written before the format was versioned. AES-GCM encrypted blobs carry an Iv and
+    /// AeadTag entry, ECDH encrypted blobs additionally a PublicKey and Salt entry, and
+    /// password encrypted super keys a Salt, Iv, and AeadTag entry.
+    Unversioned,
+    /// Same layout as `Unversioned`, but the version is recorded in the FormatVersion entry.
+    V1,
+}
+
+impl SuperEncryptionFormat {
+    /// The format written by this release.
+    pub const CURRENT: Self = Self::V1;
+
+    /// All formats this release can read, by version number.
+    const REGISTRY: &'static [(i32, Self)] = &[(1, Self::V1)];
+
+    /// Returns the version number recorded in the metadata of blobs written in this format.
+    pub fn version(&self) -> Option<i32> {
+        Self::REGISTRY.iter().find(|(_, f)| f == self).map(|(v, _)| *v)
+    }
+
+    /// Determines the format of a blob from its metadata. Fails with `SYSTEM_ERROR` if the blob
+    /// was written in a format unknown to this release.
+    pub fn from_metadata(metadata: &BlobMetaData) -> Result<Self> {
+        match metadata.format_version() {
+            None => Ok(Self::Unversioned),
+            Some(version) => Self::REGISTRY
+                .iter()
+                .find(|(v, _)| v == version)
+                .map(|(_, f)| *f)
+                .ok_or(Error::Rc(ResponseCode::SYSTEM_ERROR))
+                .with_context(|| {
+                    format!(
+                        concat!(
+                            "In SuperEncryptionFormat::from_metadata: Unknown format version {}. ",
+                            "The blob was written by a newer release."
+                        ),
+                        version
+                    )
+                }),
+        }
+    }
+
+    /// Records the current format in the metadata of a newly encrypted blob.
+    pub fn add_current_to_metadata(metadata: &mut BlobMetaData) {
+        if let Some(version) = Self::CURRENT.version() {
+            metadata.add(BlobMetaEntry::FormatVersion(version));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_current_format_round_trip() -> Result<()> {
+        let mut metadata = BlobMetaData::new();
+        SuperEncryptionFormat::add_current_to_metadata(&mut metadata);
+        assert_eq!(
+            SuperEncryptionFormat::from_metadata(&metadata)?,
+            SuperEncryptionFormat::CURRENT
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unversioned_and_unknown_formats() -> Result<()> {
+        let mut metadata = BlobMetaData::new();
+        assert_eq!(
+            SuperEncryptionFormat::from_metadata(&metadata)?,
+            SuperEncryptionFormat::Unversioned
+        );
+        metadata.add(BlobMetaEntry::FormatVersion(i32::MAX));
+        assert_eq!(
+            Some(&Error::Rc(ResponseCode::SYSTEM_ERROR)),
+            SuperEncryptionFormat::from_metadata(&metadata)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the optional verification of super encrypted key blobs after a user
+//! unlocked the device. It decrypts a random sample of the user's super encrypted blobs with
+//! the super keys that just became available and reports blobs that no longer decrypt. The
+//! decrypted key material is dropped right away and never reaches KeyMint. Without this, silent
+//! corruption of a blob is only discovered when the app tries to use the key.
+
+use crate::database::{BlobMetaData, KeystoreDB};
+use crate::error::{Error, ResponseCode};
+use crate::globals::{ASYNC_TASK, DB, SUPER_KEY};
+use crate::metrics_store::log_blob_verification_stats;
+use anyhow::{Context, Result};
+use keystore2_system_property::PropertyWatcher;
+
+/// System property holding the number of blobs that are verified after each unlock. If absent
+/// or zero, blobs are not verified.
+const SAMPLE_SIZE_PROPERTY: &str = "keystore.blob_verification_sample_size";
+
+/// The outcome of one verification.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct VerificationSummary {
+    /// Number of blobs that decrypted.
+    pub verified: usize,
+    /// Ids of the key entries whose blob did not decrypt.
+    pub failed: Vec<i64>,
+    /// Number of blobs that could not be checked, because their super key is not in memory.
+    pub skipped: usize,
+}
+
+/// Verifies that the given blobs decrypt with the super keys currently in memory.
+pub fn verify_blobs(blobs: &[(i64, Vec<u8>, BlobMetaData)]) -> VerificationSummary {
+    let mut summary: VerificationSummary = Default::default();
+    for (key_id, blob, blob_metadata) in blobs {
+        match SUPER_KEY.unwrap_key_if_required(blob_metadata, blob) {
+            Ok(_) => summary.verified += 1,
+            Err(e) => match e.root_cause().downcast_ref::<Error>() {
+                // E.g., the screen lock bound super key of a key that requires an unlocked
+                // device is not available after an unlock with a biometric.
+                Some(Error::Rc(ResponseCode::LOCKED)) => summary.skipped += 1,
+                _ => {
+                    log::error!(
+                        "In verify_blobs: Blob of key {} does not decrypt: {:?}",
+                        key_id,
+                        e
+                    );
+                    summary.failed.push(*key_id);
+                }
+            },
+        }
+    }
+    summary
+}
+
+/// Verifies a sample of up to `sample_size` super encrypted blobs of the given user.
+pub fn verify_user(
+    db: &mut KeystoreDB,
+    user_id: u32,
+    sample_size: usize,
+) -> Result<VerificationSummary> {
+    let blobs = db
+        .sample_super_encrypted_blobs(user_id, sample_size)
+        .context("In verify_user: Failed to sample blobs.")?;
+    Ok(verify_blobs(&blobs))
+}
+
+fn sample_size() -> usize {
+    PropertyWatcher::new(SAMPLE_SIZE_PROPERTY)
+        .ok()
+        .and_then(|mut w| w.read(|_n, v| v.parse::<usize>().map_err(std::convert::Into::into)).ok())
+        .unwrap_or(0)
+}
+
+/// Schedules the verification of the given user's blobs on the low priority queue of the async
+/// task, if it is enabled by the system property `keystore.blob_verification_sample_size`.
+/// Must be called after the user's super keys were unlocked.
+pub fn schedule(user_id: u32) {
+    let sample_size = sample_size();
+    if sample_size == 0 {
+        return;
+    }
+    ASYNC_TASK.queue_lo(move |_| {
+        match DB.with(|db| verify_user(&mut db.borrow_mut(), user_id, sample_size)) {
+            Ok(summary) => {
+                if !summary.failed.is_empty() {
+                    log::error!(
+                        "Blob verification of user {}: {} of {} blobs failed to decrypt.",
+                        user_id,
+                        summary.failed.len(),
+                        summary.verified + summary.failed.len()
+                    );
+                }
+                if summary.verified + summary.failed.len() > 0 {
+                    log_blob_verification_stats(summary.verified, summary.failed.len());
+                }
+            }
+            Err(e) => log::error!("Blob verification of user {} failed: {:?}", user_id, e),
+        }
+    });
+}
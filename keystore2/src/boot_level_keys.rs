@@ -15,6 +15,7 @@
 //! Offer keys based on the "boot level" for superencryption.
 
 use crate::{
+    capability_matrix::{self, Feature, Support},
     database::{KeyType, KeystoreDB},
     key_parameter::KeyParameterValue,
     raw_device::KeyMintDevice,
@@ -31,13 +32,16 @@ use std::{collections::VecDeque, convert::TryFrom};
 fn get_preferred_km_instance_for_level_zero_key() -> Result<KeyMintDevice> {
     let tee = KeyMintDevice::get(SecurityLevel::TRUSTED_ENVIRONMENT)
         .context("In get_preferred_km_instance_for_level_zero_key: Get TEE instance failed.")?;
-    if tee.version() >= KeyMintDevice::KEY_MASTER_V4_1 {
+    if capability_matrix::support(tee.version(), Feature::EarlyBootOnly) == Support::PassThrough {
         Ok(tee)
     } else {
         match KeyMintDevice::get_or_none(SecurityLevel::STRONGBOX).context(
             "In get_preferred_km_instance_for_level_zero_key: Get Strongbox instance failed.",
         )? {
-            Some(strongbox) if strongbox.version() >= KeyMintDevice::KEY_MASTER_V4_1 => {
+            Some(strongbox)
+                if capability_matrix::support(strongbox.version(), Feature::EarlyBootOnly)
+                    == Support::PassThrough =>
+            {
                 Ok(strongbox)
             }
             _ => Ok(tee),
@@ -62,7 +66,8 @@ pub fn get_level_zero_key(db: &mut KeystoreDB) -> Result<ZVec> {
         KeyParameterValue::NoAuthRequired.into(),
     ];
 
-    let has_early_boot_only = km_dev.version() >= KeyMintDevice::KEY_MASTER_V4_1;
+    let has_early_boot_only = capability_matrix::support(km_dev.version(), Feature::EarlyBootOnly)
+        == Support::PassThrough;
 
     if has_early_boot_only {
         params.push(KeyParameterValue::EarlyBootOnly.into());
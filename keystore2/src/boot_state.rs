@@ -0,0 +1,214 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module detects changes of the verified boot state between boots, e.g., when the device
+//! was re-locked or received a security patch. Attestation certificates record the boot state
+//! of the device at the time they were issued, and relying parties reject certificate chains
+//! whose RootOfTrust or patch levels no longer match the running system.
+//!
+//! On startup, the boot state is compared with the one saved in `BOOT_STATE_FILE_NAME` at the
+//! previous boot. If it changed, the remotely provisioned attestation keys are retired, see
+//! `KeystoreDB::mark_attestation_keys_stale`, so that the next attestation request gets a
+//! fresh key. The attestations of existing client keys cannot be renewed by Keystore, so the
+//! registered `IBootStateListener`s are informed instead. A change is reported to listeners
+//! that register later during the same boot as well.
+
+use crate::globals::{DB, DB_PATH};
+use android_security_maintenance::aidl::android::security::maintenance::IBootStateListener::IBootStateListener;
+use android_security_maintenance::binder::{Interface, Strong};
+use anyhow::{Context, Result};
+use keystore2_system_property::{PropertyWatcher, PropertyWatcherError};
+use lazy_static::lazy_static;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The file in the database directory holding the boot state of the previous boot.
+const BOOT_STATE_FILE_NAME: &str = "boot_state";
+
+/// The system properties that make up the boot state.
+const BOOT_STATE_PROPERTIES: &[&str] = &[
+    "ro.boot.verifiedbootstate",
+    "ro.boot.vbmeta.device_state",
+    "ro.boot.vbmeta.digest",
+    "ro.build.version.security_patch",
+    "ro.vendor.build.security_patch",
+];
+
+#[derive(Default)]
+struct ListenerState {
+    listeners: Vec<Strong<dyn IBootStateListener>>,
+    // The properties that changed since the previous boot, if any.
+    changed: Vec<String>,
+}
+
+lazy_static! {
+    static ref LISTENER_STATE: Mutex<ListenerState> = Default::default();
+}
+
+/// The values of `BOOT_STATE_PROPERTIES`. Absent properties have an empty value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BootState(Vec<(String, String)>);
+
+impl BootState {
+    fn read_property(name: &str) -> Result<String> {
+        let mut watcher = PropertyWatcher::new(name)
+            .context("In BootState::read_property: Failed to create watcher.")?;
+        match watcher.read(|_n, v| Ok(v.to_string())) {
+            Ok(value) => Ok(value),
+            Err(PropertyWatcherError::SystemPropertyAbsent) => Ok(String::new()),
+            Err(e) => {
+                Err(e).context(format!("In BootState::read_property: Failed to read {}.", name))
+            }
+        }
+    }
+
+    fn current() -> Result<Self> {
+        BOOT_STATE_PROPERTIES
+            .iter()
+            .map(|name| Ok((name.to_string(), Self::read_property(name)?)))
+            .collect::<Result<Vec<_>>>()
+            .map(Self)
+            .context("In BootState::current.")
+    }
+
+    /// Parses the file format written by `serialize`, i.e., one `name=value` line per property.
+    fn parse(s: &str) -> Self {
+        Self(
+            s.lines()
+                .filter_map(|line| line.split_once('='))
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    fn serialize(&self) -> String {
+        self.0.iter().map(|(name, value)| format!("{}={}\n", name, value)).collect()
+    }
+
+    /// Returns the names of the properties of `self` whose value differs in `previous`.
+    /// Properties that `previous` does not know were added after it was saved, so they do not
+    /// count as changed.
+    fn changed_since(&self, previous: &Self) -> Vec<String> {
+        self.0
+            .iter()
+            .filter(|(name, value)| {
+                previous
+                    .0
+                    .iter()
+                    .any(|(prev_name, prev_value)| prev_name == name && prev_value != value)
+            })
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
+
+fn load(path: &Path) -> Result<Option<BootState>> {
+    match fs::read_to_string(path) {
+        Ok(s) => Ok(Some(BootState::parse(&s))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context("In load: Failed to read boot state file."),
+    }
+}
+
+fn notify(listener: &Strong<dyn IBootStateListener>, changed: &[String]) {
+    if let Err(e) = listener.onBootStateChanged(changed) {
+        log::warn!("In notify: Failed to notify listener: {:?}", e);
+    }
+}
+
+/// Compares the boot state with the one of the previous boot and handles a change as
+/// described in the module documentation. Must be called on startup, after `DB_PATH` was set.
+pub fn check_on_startup() {
+    if let Err(e) = check_and_save() {
+        log::error!("In check_on_startup: {:?}", e);
+    }
+}
+
+fn check_and_save() -> Result<()> {
+    let mut path = DB_PATH.read().expect("Could not get DB_PATH.").clone();
+    path.push(BOOT_STATE_FILE_NAME);
+    let current = BootState::current().context("In check_and_save.")?;
+    let previous = load(&path).context("In check_and_save.")?;
+
+    // Without a previous state, e.g., on the first boot, there is nothing to compare with.
+    if let Some(previous) = previous {
+        if previous == current {
+            return Ok(());
+        }
+        let changed = current.changed_since(&previous);
+        if !changed.is_empty() {
+            log::info!("The boot state changed since the previous boot: {:?}", changed);
+            let count = DB
+                .with(|db| db.borrow_mut().mark_attestation_keys_stale())
+                .context("In check_and_save: Failed to retire attestation keys.")?;
+            log::info!("In check_and_save: Retired {} attestation keys.", count);
+            let mut state = LISTENER_STATE.lock().unwrap();
+            state.listeners.iter().for_each(|l| notify(l, &changed));
+            state.changed = changed;
+        }
+    }
+    // The state is saved only after the keys were retired, so that a failure is retried on
+    // the next start.
+    fs::write(&path, current.serialize()).context("In check_and_save: Failed to save state.")
+}
+
+/// Registers a boot state listener. If the boot state changed since the previous boot, the
+/// listener is informed right away. Registering the same listener twice has no effect.
+pub fn add_listener(listener: &Strong<dyn IBootStateListener>) {
+    let mut state = LISTENER_STATE.lock().unwrap();
+    if state.listeners.iter().any(|l| l.as_binder() == listener.as_binder()) {
+        return;
+    }
+    if !state.changed.is_empty() {
+        notify(listener, &state.changed);
+    }
+    state.listeners.push(listener.clone());
+}
+
+/// Unregisters a boot state listener.
+pub fn remove_listener(listener: &Strong<dyn IBootStateListener>) {
+    LISTENER_STATE.lock().unwrap().listeners.retain(|l| l.as_binder() != listener.as_binder());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(values: &[(&str, &str)]) -> BootState {
+        BootState(values.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect())
+    }
+
+    #[test]
+    fn test_serialize_and_parse() {
+        let s = state(&[("ro.boot.verifiedbootstate", "green"), ("ro.boot.vbmeta.digest", "")]);
+        assert_eq!(BootState::parse(&s.serialize()), s);
+    }
+
+    #[test]
+    fn test_changed_since() {
+        let previous = state(&[
+            ("ro.boot.verifiedbootstate", "orange"),
+            ("ro.build.version.security_patch", "2026-09-05"),
+        ]);
+        let current = state(&[
+            ("ro.boot.verifiedbootstate", "green"),
+            ("ro.build.version.security_patch", "2026-09-05"),
+            ("ro.vendor.build.security_patch", "2026-10-01"),
+        ]);
+        assert_eq!(current.changed_since(&previous), vec!["ro.boot.verifiedbootstate"]);
+        assert!(current.changed_since(&current).is_empty());
+    }
+}
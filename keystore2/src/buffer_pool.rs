@@ -0,0 +1,248 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements pools of reusable buffers for the largest transient allocations of
+//! parcel-heavy call paths that do not end up in a response. When many apps start at once, each of them generates and uses keys,
+//! and allocating and freeing these buffers on every call shows up as allocator churn in heap
+//! profiles. A `PooledVec` goes back to its pool when it is dropped, and the next caller gets it
+//! cleared but with its capacity.
+//!
+//! A pool retains at most `MAX_RETAINED_BUFFERS` buffers, and buffers that grew beyond
+//! `MAX_RETAINED_BYTES` are freed instead of retained, so that a single large request does not
+//! pin its memory. The retained memory is accounted and trimmed under memory pressure, see
+//! `cache_accounting`. The reuse counters of each pool are reported to metrics.
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::KeyParameter::KeyParameter;
+use lazy_static::lazy_static;
+use std::fmt;
+use std::io::Write;
+use std::mem::size_of;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// The maximal number of buffers a pool retains.
+const MAX_RETAINED_BUFFERS: usize = 16;
+
+/// Buffers with a larger capacity in bytes are freed instead of retained.
+const MAX_RETAINED_BYTES: usize = 64 * 1024;
+
+/// The buffer pools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolKind {
+    /// The operation input recorded on update and finish for trusted confirmation.
+    UpdatePayloads,
+    /// The key parameters of key generation requests.
+    KeyParameters,
+}
+
+/// The counters of a buffer pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    /// The pool.
+    pub kind: PoolKind,
+    /// The number of buffers handed out that were taken from the pool.
+    pub reused: u64,
+    /// The number of buffers handed out that had to be allocated.
+    pub allocated: u64,
+    /// The number of buffers that were freed instead of retained, because they were too large
+    /// or the pool was full.
+    pub discarded: u64,
+    /// The memory currently retained by the pool in bytes.
+    pub retained_bytes: usize,
+}
+
+/// A pool of vectors of `T`.
+pub struct BufferPool<T> {
+    kind: PoolKind,
+    buffers: Mutex<Vec<Vec<T>>>,
+    reused: AtomicU64,
+    allocated: AtomicU64,
+    discarded: AtomicU64,
+}
+
+impl<T> BufferPool<T> {
+    fn new(kind: PoolKind) -> Self {
+        Self {
+            kind,
+            buffers: Mutex::new(Vec::new()),
+            reused: AtomicU64::new(0),
+            allocated: AtomicU64::new(0),
+            discarded: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns an empty buffer with a capacity of at least `min_capacity`, reusing a retained
+    /// buffer if there is one.
+    pub fn take(&'static self, min_capacity: usize) -> PooledVec<T> {
+        let retained = self.buffers.lock().unwrap().pop();
+        let mut vec = match retained {
+            Some(vec) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                vec
+            }
+            None => {
+                self.allocated.fetch_add(1, Ordering::Relaxed);
+                Vec::new()
+            }
+        };
+        vec.reserve(min_capacity);
+        PooledVec { vec, pool: self }
+    }
+
+    fn give_back(&self, mut vec: Vec<T>) {
+        // Buffers that were never used hold no memory worth retaining.
+        if vec.capacity() == 0 {
+            return;
+        }
+        vec.clear();
+        if vec.capacity() * size_of::<T>() <= MAX_RETAINED_BYTES {
+            let mut buffers = self.buffers.lock().unwrap();
+            if buffers.len() < MAX_RETAINED_BUFFERS {
+                buffers.push(vec);
+                return;
+            }
+        }
+        self.discarded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn retained_bytes(&self) -> usize {
+        self.buffers.lock().unwrap().iter().map(|vec| vec.capacity() * size_of::<T>()).sum()
+    }
+
+    /// Frees all retained buffers and returns their number.
+    fn clear(&self) -> usize {
+        self.buffers.lock().unwrap().drain(..).count()
+    }
+
+    fn stats(&self) -> PoolStats {
+        PoolStats {
+            kind: self.kind,
+            reused: self.reused.load(Ordering::Relaxed),
+            allocated: self.allocated.load(Ordering::Relaxed),
+            discarded: self.discarded.load(Ordering::Relaxed),
+            retained_bytes: self.retained_bytes(),
+        }
+    }
+}
+
+/// A vector taken from a `BufferPool`. It dereferences to the vector and goes back to the pool
+/// when it is dropped.
+pub struct PooledVec<T> {
+    vec: Vec<T>,
+    pool: &'static BufferPool<T>,
+}
+
+impl<T: Clone> PooledVec<T> {
+    /// Returns a copy of this vector in a buffer taken from the same pool.
+    pub fn clone_pooled(&self) -> Self {
+        let mut copy = self.pool.take(self.vec.len());
+        copy.extend_from_slice(&self.vec);
+        copy
+    }
+}
+
+impl<T> Deref for PooledVec<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.vec
+    }
+}
+
+impl<T> DerefMut for PooledVec<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.vec
+    }
+}
+
+impl<T> Drop for PooledVec<T> {
+    fn drop(&mut self) {
+        self.pool.give_back(std::mem::take(&mut self.vec));
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for PooledVec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.vec.fmt(f)
+    }
+}
+
+lazy_static! {
+    /// See `PoolKind::UpdatePayloads`.
+    pub static ref UPDATE_PAYLOAD_BUFFERS: BufferPool<u8> =
+        BufferPool::new(PoolKind::UpdatePayloads);
+    /// See `PoolKind::KeyParameters`.
+    pub static ref KEY_PARAMETER_BUFFERS: BufferPool<KeyParameter> =
+        BufferPool::new(PoolKind::KeyParameters);
+}
+
+/// Returns the counters of all pools.
+pub fn stats() -> Vec<PoolStats> {
+    vec![UPDATE_PAYLOAD_BUFFERS.stats(), KEY_PARAMETER_BUFFERS.stats()]
+}
+
+/// Returns the memory retained by all pools in bytes.
+pub fn size_bytes() -> usize {
+    stats().iter().map(|s| s.retained_bytes).sum()
+}
+
+/// Frees the buffers retained by all pools and returns their number.
+pub fn trim() -> usize {
+    UPDATE_PAYLOAD_BUFFERS.clear() + KEY_PARAMETER_BUFFERS.clear()
+}
+
+/// Writes the counters of all pools to `f`.
+pub fn dump(f: &mut dyn Write) -> std::io::Result<()> {
+    writeln!(f, "Buffer pools:")?;
+    for s in stats() {
+        writeln!(
+            f,
+            "  {:?}: {} reused, {} allocated, {} discarded, {} bytes retained",
+            s.kind, s.reused, s.allocated, s.discarded, s.retained_bytes
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    lazy_static! {
+        static ref TEST_BUFFERS: BufferPool<u8> = BufferPool::new(PoolKind::UpdatePayloads);
+    }
+
+    #[test]
+    fn test_buffer_reuse() {
+        let mut buffer = TEST_BUFFERS.take(100);
+        buffer.extend_from_slice(b"payload");
+        let capacity = buffer.capacity();
+        drop(buffer);
+
+        let buffer = TEST_BUFFERS.take(10);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.capacity(), capacity);
+        drop(buffer);
+
+        // Buffers beyond the size limit are not retained.
+        let mut buffer = TEST_BUFFERS.take(MAX_RETAINED_BYTES + 1);
+        buffer.push(0);
+        drop(buffer);
+
+        let stats = TEST_BUFFERS.stats();
+        assert_eq!((stats.reused, stats.allocated, stats.discarded), (2, 1, 1));
+        assert_eq!(TEST_BUFFERS.clear(), 0);
+    }
+}
@@ -0,0 +1,431 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the IKeystoreBulkImport AIDL interface. It imports a batch of keys,
+//! e.g., the certificates and keys of an enterprise provisioning run, with a single binder call.
+//! The key material is read from a shared memory region supplied by the caller. The permission
+//! checks and the templates are handled once per batch on the binder thread. The keys are
+//! imported by a background task, which stores them in chunks with one database transaction
+//! per chunk, and the outcome is delivered through a callback. Each entry is validated and
+//! imported on its own, so that a bad entry only fails itself.
+
+use crate::async_keygen::get_security_level_for_new_keys;
+use crate::audit_log::log_key_imported;
+use crate::caller_identity::CallerIdentity;
+use crate::error::{get_error_code, map_or_log_err, Error};
+use crate::globals::TASK_EXECUTOR;
+use crate::metrics_store::log_key_creation_event_stats;
+use crate::security_level::{ImportTemplate, KeystoreSecurityLevel};
+use crate::task_executor::{Priority, TaskContext};
+use crate::utils::watchdog as wd;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use android_security_bulkimport::aidl::android::security::bulkimport::{
+    BulkImportResult::BulkImportResult,
+    BulkImportTemplate::BulkImportTemplate,
+    IBulkImportCallback::IBulkImportCallback,
+    IKeystoreBulkImport::{BnKeystoreBulkImport, IKeystoreBulkImport},
+};
+use android_security_bulkimport::binder::{
+    parcel::ParcelFileDescriptor, BinderFeatures, Interface, Result as BinderResult, Strong,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    KeyDescriptor::KeyDescriptor, ResponseCode::ResponseCode,
+};
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex};
+
+/// Maximal number of entries in a batch.
+const MAX_ENTRIES: u32 = 1024;
+
+/// Maximal size of a batch in bytes.
+const MAX_BATCH_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Number of entries that are stored with one database transaction.
+const CHUNK_SIZE: usize = 32;
+
+lazy_static! {
+    /// The uids that have a batch in flight. Each uid may have one batch in flight, so that a
+    /// single caller cannot occupy all background workers.
+    static ref BATCHES_IN_FLIGHT: Mutex<HashSet<u32>> = Default::default();
+}
+
+/// Reads `buf.len()` bytes at `offset` of the batch. A batch that ends early is reported as
+/// `ResponseCode::INVALID_ARGUMENT`, other read errors are passed through.
+fn read_exact<F>(read_at: &F, buf: &mut [u8], offset: u64) -> Result<()>
+where
+    F: Fn(&mut [u8], u64) -> std::io::Result<()>,
+{
+    read_at(buf, offset).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => {
+            anyhow!(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context("Batch is truncated.")
+        }
+        _ => anyhow!(e),
+    })
+}
+
+fn read_u32<F>(read_at: &F, offset: &mut u64) -> Result<u32>
+where
+    F: Fn(&mut [u8], u64) -> std::io::Result<()>,
+{
+    let mut buf = [0u8; 4];
+    read_exact(read_at, &mut buf, *offset)?;
+    *offset += buf.len() as u64;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Splits the batch into the payloads of its entries. `read_at` reads from the shared memory
+/// region by offset, so that the file offset, which is shared with the caller, is left alone.
+fn read_batch<F>(read_at: F) -> Result<Vec<Vec<u8>>>
+where
+    F: Fn(&mut [u8], u64) -> std::io::Result<()>,
+{
+    let mut offset = 0;
+    let count = read_u32(&read_at, &mut offset).context("In read_batch: Reading entry count.")?;
+    if count > MAX_ENTRIES {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(format!(
+            "In read_batch: The batch has {} entries, at most {} are supported.",
+            count, MAX_ENTRIES
+        ));
+    }
+    let mut payloads = Vec::with_capacity(count as usize);
+    for i in 0..count {
+        let len = read_u32(&read_at, &mut offset)
+            .with_context(|| format!("In read_batch: Reading length of entry {}.", i))?;
+        if offset + len as u64 > MAX_BATCH_SIZE {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(format!(
+                "In read_batch: The batch exceeds {} bytes at entry {}.",
+                MAX_BATCH_SIZE, i
+            ));
+        }
+        let mut payload = vec![0; len as usize];
+        read_exact(&read_at, &mut payload, offset)
+            .with_context(|| format!("In read_batch: Reading entry {}.", i))?;
+        offset += len as u64;
+        payloads.push(payload);
+    }
+    Ok(payloads)
+}
+
+/// A validated entry of a batch.
+#[derive(Debug, PartialEq)]
+struct BatchEntry<'a> {
+    template: usize,
+    alias: &'a str,
+    key_data: &'a [u8],
+}
+
+/// Parses and validates the payload of a batch entry against the number of templates.
+fn parse_entry(payload: &[u8], template_count: usize) -> Result<BatchEntry> {
+    if payload.len() < 4 {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context("In parse_entry: Entry is too short.");
+    }
+    let template = u16::from_le_bytes([payload[0], payload[1]]) as usize;
+    let alias_len = u16::from_le_bytes([payload[2], payload[3]]) as usize;
+    let rest = &payload[4..];
+    if template >= template_count {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(format!("In parse_entry: Unknown template {}.", template));
+    }
+    if alias_len == 0 || alias_len > rest.len() {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(format!("In parse_entry: Invalid alias length {}.", alias_len));
+    }
+    let alias = std::str::from_utf8(&rest[..alias_len])
+        .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+        .context("In parse_entry: Alias is not valid UTF-8.")?;
+    Ok(BatchEntry { template, alias, key_data: &rest[alias_len..] })
+}
+
+/// Returns the outcome of an entry that failed with `e`.
+fn failed_entry(alias: &str, e: &anyhow::Error) -> BulkImportResult {
+    BulkImportResult { alias: alias.to_string(), errorCode: get_error_code(e), metadata: None }
+}
+
+/// A batch that passed the checks on the binder thread and is imported by a background task.
+struct Batch {
+    sec_level: Arc<KeystoreSecurityLevel>,
+    security_level: SecurityLevel,
+    target: KeyDescriptor,
+    templates: Vec<BulkImportTemplate>,
+    prepared: Vec<Result<ImportTemplate>>,
+    payloads: Vec<Vec<u8>>,
+    caller: CallerIdentity,
+}
+
+impl Batch {
+    /// Imports the entries of the batch chunk by chunk. If the task is cancelled, the entries
+    /// that were not imported yet fail with `ResponseCode::BACKEND_BUSY`.
+    fn import(&self, ctx: &TaskContext) -> Vec<BulkImportResult> {
+        let mut results = Vec::with_capacity(self.payloads.len());
+        for (chunk_index, chunk) in self.payloads.chunks(CHUNK_SIZE).enumerate() {
+            if ctx.is_cancelled() {
+                let e = anyhow!(Error::Rc(ResponseCode::BACKEND_BUSY))
+                    .context("In Batch::import: Keystore is shutting down.");
+                results.extend(chunk.iter().map(|_| failed_entry("", &e)));
+                continue;
+            }
+            results.extend(self.import_chunk(chunk_index * CHUNK_SIZE, chunk));
+        }
+        results
+    }
+
+    fn import_chunk(&self, first: usize, chunk: &[Vec<u8>]) -> Vec<BulkImportResult> {
+        let mut results: Vec<Option<BulkImportResult>> = chunk.iter().map(|_| None).collect();
+        let mut entries = Vec::with_capacity(chunk.len());
+        let mut positions = Vec::with_capacity(chunk.len());
+        for (i, payload) in chunk.iter().enumerate() {
+            let entry = match parse_entry(payload, self.templates.len()) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    log::warn!("In import_chunk: Rejecting entry {}: {:?}", first + i, e);
+                    results[i] = Some(failed_entry("", &e));
+                    continue;
+                }
+            };
+            match &self.prepared[entry.template] {
+                Ok(template) => {
+                    entries.push((entry.alias, template, entry.key_data));
+                    positions.push((i, entry.template));
+                }
+                Err(e) => {
+                    self.log_outcome(
+                        first + i,
+                        entry.alias,
+                        entry.template,
+                        &self.prepared[entry.template],
+                    );
+                    results[i] = Some(failed_entry(entry.alias, e));
+                }
+            }
+        }
+
+        match self.sec_level.import_batch(&self.target, &entries, &self.caller) {
+            Ok(imported) => {
+                for ((i, template), ((alias, _, _), result)) in
+                    positions.into_iter().zip(entries.iter().zip(imported))
+                {
+                    self.log_outcome(first + i, alias, template, &result);
+                    results[i] = Some(match result {
+                        Ok(metadata) => BulkImportResult {
+                            alias: alias.to_string(),
+                            errorCode: 0,
+                            metadata: Some(metadata),
+                        },
+                        Err(e) => failed_entry(alias, &e),
+                    });
+                }
+            }
+            Err(e) => {
+                let failure: Result<()> = Err(e).context("In import_chunk.");
+                for ((i, template), (alias, _, _)) in positions.into_iter().zip(&entries) {
+                    self.log_outcome(first + i, alias, template, &failure);
+                    results[i] = failure.as_ref().err().map(|e| failed_entry(alias, e));
+                }
+            }
+        }
+        results
+            .into_iter()
+            .map(|result| result.unwrap_or_else(|| failed_entry("", &anyhow!(Error::sys()))))
+            .collect()
+    }
+
+    fn log_outcome<U>(&self, index: usize, alias: &str, template: usize, result: &Result<U>) {
+        log_key_creation_event_stats(self.security_level, &self.templates[template].params, result);
+        log_key_imported(&self.key(alias), &self.caller, result.is_ok());
+        if let Err(e) = result {
+            log::error!("In import_chunk: Importing entry {} failed: {:?}", index, e);
+        }
+    }
+
+    fn key(&self, alias: &str) -> KeyDescriptor {
+        KeyDescriptor { alias: Some(alias.to_string()), ..self.target.clone() }
+    }
+}
+
+/// Implementation of the IKeystoreBulkImport AIDL interface.
+pub struct BulkImport;
+
+impl BulkImport {
+    /// Creates a new instance of the bulk import service wrapped in a BnKeystoreBulkImport
+    /// proxy object. It also enables `BinderFeatures::set_requesting_sid` on the new interface,
+    /// because the permission checks of importKey require it.
+    pub fn new_native_binder() -> Result<Strong<dyn IKeystoreBulkImport>> {
+        Ok(BnKeystoreBulkImport::new_binder(
+            Self,
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        ))
+    }
+
+    fn import_keys(
+        security_level: SecurityLevel,
+        target: &KeyDescriptor,
+        templates: &[BulkImportTemplate],
+        batch: &ParcelFileDescriptor,
+        callback: &Strong<dyn IBulkImportCallback>,
+    ) -> Result<()> {
+        let sec_level =
+            get_security_level_for_new_keys(security_level).context("In import_keys.")?;
+        let caller = CallerIdentity::current();
+        // All checks that depend on the calling context happen here, on the binder thread.
+        let target =
+            sec_level.resolve_bulk_import_target(target, &caller).context("In import_keys.")?;
+        let prepared = templates
+            .iter()
+            .map(|template| {
+                sec_level.prepare_import_template(
+                    &target,
+                    &template.params,
+                    template.flags,
+                    &caller,
+                )
+            })
+            .collect();
+        let file: &File = batch.as_ref();
+        let payloads =
+            read_batch(|buf, offset| file.read_exact_at(buf, offset)).context("In import_keys.")?;
+
+        let uid = caller.uid();
+        if !BATCHES_IN_FLIGHT.lock().unwrap().insert(uid) {
+            return Err(Error::Rc(ResponseCode::BACKEND_BUSY))
+                .context("In import_keys: The caller has another batch in flight.");
+        }
+        let batch = Batch {
+            sec_level,
+            security_level,
+            target,
+            templates: templates.to_vec(),
+            prepared,
+            payloads,
+            caller,
+        };
+        let callback = callback.clone();
+        let spawned =
+            TASK_EXECUTOR.spawn("keystore2_bulk_import", Priority::Background, move |ctx| {
+                let results = batch.import(ctx);
+                BATCHES_IN_FLIGHT.lock().unwrap().remove(&uid);
+                if let Err(e) = callback.onBatchImported(&results) {
+                    log::warn!(
+                        "In import_keys: Failed to deliver the outcome of the batch: {:?}",
+                        e
+                    );
+                }
+            });
+        if let Err(e) = spawned {
+            BATCHES_IN_FLIGHT.lock().unwrap().remove(&uid);
+            // The task queue is full.
+            return Err(Error::Rc(ResponseCode::BACKEND_BUSY))
+                .context(format!("In import_keys: Failed to queue the batch: {:?}", e));
+        }
+        Ok(())
+    }
+}
+
+impl Interface for BulkImport {}
+
+impl IKeystoreBulkImport for BulkImport {
+    fn importKeys(
+        &self,
+        security_level: SecurityLevel,
+        target: &KeyDescriptor,
+        templates: &[BulkImportTemplate],
+        batch: &ParcelFileDescriptor,
+        callback: &Strong<dyn IBulkImportCallback>,
+    ) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreBulkImport::importKeys", 500);
+        map_or_log_err(Self::import_keys(security_level, target, templates, batch, callback), Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(template: u16, alias: &[u8], key_data: &[u8]) -> Vec<u8> {
+        let mut payload = template.to_le_bytes().to_vec();
+        payload.extend_from_slice(&(alias.len() as u16).to_le_bytes());
+        payload.extend_from_slice(alias);
+        payload.extend_from_slice(key_data);
+        payload
+    }
+
+    fn batch(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut batch = (entries.len() as u32).to_le_bytes().to_vec();
+        for entry in entries {
+            batch.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+            batch.extend_from_slice(entry);
+        }
+        batch
+    }
+
+    fn read_from(batch: &[u8]) -> impl Fn(&mut [u8], u64) -> std::io::Result<()> + '_ {
+        move |buf, offset| {
+            let start = offset as usize;
+            let src = batch
+                .get(start..start + buf.len())
+                .ok_or_else(|| std::io::Error::from(ErrorKind::UnexpectedEof))?;
+            buf.copy_from_slice(src);
+            Ok(())
+        }
+    }
+
+    fn is_invalid_argument(e: &anyhow::Error) -> bool {
+        get_error_code(e) == ResponseCode::INVALID_ARGUMENT.0
+    }
+
+    #[test]
+    fn read_and_parse_batch() -> Result<()> {
+        let entries = vec![entry(0, b"first", b"key1"), entry(1, b"second", b"")];
+        let data = batch(&entries);
+        let payloads = read_batch(read_from(&data))?;
+        assert_eq!(payloads, entries);
+
+        assert_eq!(
+            parse_entry(&payloads[0], 2)?,
+            BatchEntry { template: 0, alias: "first", key_data: b"key1" }
+        );
+        assert_eq!(
+            parse_entry(&payloads[1], 2)?,
+            BatchEntry { template: 1, alias: "second", key_data: b"" }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed_batch() {
+        let data = batch(&[entry(0, b"alias", b"key")]);
+        assert!(is_invalid_argument(&read_batch(read_from(&data[..data.len() - 1])).unwrap_err()));
+        assert!(is_invalid_argument(&read_batch(read_from(&data[..2])).unwrap_err()));
+        let too_many = (MAX_ENTRIES + 1).to_le_bytes();
+        assert!(is_invalid_argument(&read_batch(read_from(&too_many)).unwrap_err()));
+        let too_large = [1u32.to_le_bytes(), (MAX_BATCH_SIZE as u32).to_le_bytes()].concat();
+        assert!(is_invalid_argument(&read_batch(read_from(&too_large)).unwrap_err()));
+    }
+
+    #[test]
+    fn reject_malformed_entry() {
+        assert!(is_invalid_argument(&parse_entry(&[0, 0, 1], 1).unwrap_err()));
+        assert!(is_invalid_argument(&parse_entry(&entry(1, b"alias", b"key"), 1).unwrap_err()));
+        assert!(is_invalid_argument(&parse_entry(&entry(0, b"", b"key"), 1).unwrap_err()));
+        assert!(is_invalid_argument(&parse_entry(&entry(0, &[0xff], b"key"), 1).unwrap_err()));
+        let mut truncated = entry(0, b"alias", b"");
+        truncated.truncate(6);
+        assert!(is_invalid_argument(&parse_entry(&truncated, 1).unwrap_err()));
+    }
+}
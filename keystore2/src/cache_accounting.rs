@@ -0,0 +1,287 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module accounts the memory held by Keystore's in-memory caches and keeps it bounded.
+//! Caches are trimmed when the system signals memory pressure through
+//! `IKeystoreMaintenance::onTrimMemory`, and when their combined size exceeds the ceiling set
+//! by the system property `keystore.cache_memory_limit_kb`, which is checked periodically.
+//! Sizes are estimates of the heap memory held by the cached entries. The eviction counts of
+//! each cache are reported to metrics. Attestation key chains are not cached by Keystore
+//! itself; they are read from the database and accounted with the SQLite page caches.
+
+use crate::access_control;
+use crate::buffer_pool;
+use crate::caller_identity;
+use crate::database::KeystoreDB;
+use crate::globals::{ASYNC_TASK, DB, TASK_EXECUTOR};
+use crate::storage_key;
+use crate::task_executor::Priority;
+use keystore2_selinux as selinux;
+use keystore2_system_property::PropertyWatcher;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// System property holding the ceiling for the combined size of all caches in KiB.
+const CACHE_MEMORY_LIMIT_PROPERTY: &str = "keystore.cache_memory_limit_kb";
+
+/// Default ceiling for the combined size of all caches in bytes.
+const DEFAULT_CACHE_MEMORY_LIMIT: usize = 1024 * 1024;
+
+/// Interval between checks of the ceiling.
+const CEILING_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Levels of `ComponentCallbacks2.onTrimMemory` that call for trimming all caches.
+const TRIM_MEMORY_RUNNING_CRITICAL: i32 = 15;
+const TRIM_MEMORY_COMPLETE: i32 = 80;
+
+/// The memory pressure under which caches are trimmed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MemoryPressure {
+    /// Caches that are cheap to fill again are trimmed.
+    Moderate,
+    /// All caches are trimmed.
+    Critical,
+}
+
+impl MemoryPressure {
+    /// Maps a level of `ComponentCallbacks2.onTrimMemory` to a memory pressure.
+    pub fn from_trim_level(level: i32) -> Self {
+        if level == TRIM_MEMORY_RUNNING_CRITICAL || level >= TRIM_MEMORY_COMPLETE {
+            Self::Critical
+        } else {
+            Self::Moderate
+        }
+    }
+}
+
+/// The accounted caches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheKind {
+    /// The per-boot auth token table. Trimming drops superseded tokens only.
+    AuthTokens,
+    /// The package names and target SDK versions of callers.
+    PackageNames,
+    /// The SELinux access decisions of permission checks.
+    AccessDecisions,
+    /// The active grants of recent grantees.
+    Grants,
+    /// The ephemeral keys converted from storage keys.
+    EphemeralStorageKeys,
+    /// The buffers retained for reuse, see `buffer_pool`.
+    BufferPools,
+    /// The statement and page caches of SQLite. Evictions count released pages.
+    Sqlite,
+    /// The SELinux contexts of keystore2_key namespaces. They are held for the lifetime of
+    /// the process and cannot be trimmed.
+    SelinuxContexts,
+}
+
+struct AccountedCache {
+    kind: CacheKind,
+    /// The least pressure under which the cache is trimmed.
+    trim_at: MemoryPressure,
+    size_bytes: fn() -> usize,
+    /// Evicts entries and returns the number of evicted entries.
+    trim: fn() -> usize,
+    evictions: AtomicU64,
+}
+
+static CACHES: [AccountedCache; 8] = [
+    AccountedCache {
+        kind: CacheKind::AuthTokens,
+        trim_at: MemoryPressure::Moderate,
+        size_bytes: KeystoreDB::auth_tokens_size_bytes,
+        trim: trim_auth_tokens,
+        evictions: AtomicU64::new(0),
+    },
+    AccountedCache {
+        kind: CacheKind::PackageNames,
+        trim_at: MemoryPressure::Moderate,
+        size_bytes: caller_identity::package_name_cache_size_bytes,
+        trim: caller_identity::clear_package_name_cache,
+        evictions: AtomicU64::new(0),
+    },
+    AccountedCache {
+        kind: CacheKind::AccessDecisions,
+        trim_at: MemoryPressure::Moderate,
+        size_bytes: selinux::access_cache_size_bytes,
+        trim: selinux::flush_access_cache,
+        evictions: AtomicU64::new(0),
+    },
+    AccountedCache {
+        kind: CacheKind::Grants,
+        trim_at: MemoryPressure::Critical,
+        size_bytes: KeystoreDB::grant_cache_size_bytes,
+        trim: KeystoreDB::invalidate_grant_caches,
+        evictions: AtomicU64::new(0),
+    },
+    AccountedCache {
+        kind: CacheKind::EphemeralStorageKeys,
+        trim_at: MemoryPressure::Critical,
+        size_bytes: storage_key::cache_size_bytes,
+        trim: storage_key::clear_cache,
+        evictions: AtomicU64::new(0),
+    },
+    AccountedCache {
+        kind: CacheKind::BufferPools,
+        trim_at: MemoryPressure::Moderate,
+        size_bytes: buffer_pool::size_bytes,
+        trim: buffer_pool::trim,
+        evictions: AtomicU64::new(0),
+    },
+    AccountedCache {
+        kind: CacheKind::Sqlite,
+        trim_at: MemoryPressure::Critical,
+        size_bytes: KeystoreDB::sqlite_memory_size_bytes,
+        trim: trim_sqlite,
+        evictions: AtomicU64::new(0),
+    },
+    AccountedCache {
+        kind: CacheKind::SelinuxContexts,
+        trim_at: MemoryPressure::Critical,
+        size_bytes: access_control::contexts_size_bytes,
+        trim: not_trimmable,
+        evictions: AtomicU64::new(0),
+    },
+];
+
+/// The page size of the Keystore database, by which released SQLite memory is counted.
+const SQLITE_PAGE_SIZE: usize = 4096;
+
+fn trim_auth_tokens() -> usize {
+    let (before, after) = DB.with(|db| db.borrow().coalesce_auth_tokens());
+    before - after
+}
+
+fn trim_sqlite() -> usize {
+    KeystoreDB::release_sqlite_memory() / SQLITE_PAGE_SIZE
+}
+
+fn not_trimmable() -> usize {
+    0
+}
+
+/// The size and eviction count of a cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheUsage {
+    /// The cache.
+    pub kind: CacheKind,
+    /// The estimated size of the cache in bytes.
+    pub size_bytes: usize,
+    /// The number of entries evicted under memory pressure since Keystore started.
+    pub evictions: u64,
+}
+
+/// Returns the usage of all accounted caches.
+pub fn usage() -> Vec<CacheUsage> {
+    CACHES
+        .iter()
+        .map(|cache| CacheUsage {
+            kind: cache.kind,
+            size_bytes: (cache.size_bytes)(),
+            evictions: cache.evictions.load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+fn total_size_bytes() -> usize {
+    CACHES.iter().map(|cache| (cache.size_bytes)()).sum()
+}
+
+fn memory_limit() -> usize {
+    PropertyWatcher::new(CACHE_MEMORY_LIMIT_PROPERTY)
+        .ok()
+        .and_then(|mut w| w.read(|_n, v| v.parse::<usize>().map_err(std::convert::Into::into)).ok())
+        .map_or(DEFAULT_CACHE_MEMORY_LIMIT, |kib| kib.saturating_mul(1024))
+}
+
+/// Trims all caches that are subject to trimming under the given memory pressure. Returns the
+/// number of evicted entries.
+pub fn trim(pressure: MemoryPressure) -> usize {
+    let evicted: usize = CACHES
+        .iter()
+        .filter(|cache| cache.trim_at <= pressure)
+        .map(|cache| {
+            let evicted = (cache.trim)();
+            cache.evictions.fetch_add(evicted as u64, Ordering::Relaxed);
+            evicted
+        })
+        .sum();
+    log::info!("Trimmed caches under {:?} memory pressure: {} entries.", pressure, evicted);
+    evicted
+}
+
+fn enforce_ceiling() {
+    let limit = memory_limit();
+    for pressure in &[MemoryPressure::Moderate, MemoryPressure::Critical] {
+        let total = total_size_bytes();
+        if total <= limit {
+            return;
+        }
+        log::warn!(
+            concat!(
+                "Caches hold {} bytes, exceeding the ceiling of {} bytes. ",
+                "Trimming under {:?} pressure."
+            ),
+            total,
+            limit,
+            pressure
+        );
+        trim(*pressure);
+    }
+}
+
+/// Starts the periodic check of the ceiling for the combined size of all caches.
+pub fn start() {
+    let spawned = TASK_EXECUTOR.spawn_periodic(
+        "keystore2_cache_accounting",
+        Priority::Background,
+        CEILING_CHECK_INTERVAL,
+        || ASYNC_TASK.queue_lo(|_| enforce_ceiling()),
+    );
+    if let Err(e) = spawned {
+        log::error!("Failed to start the cache ceiling check: {:?}", e);
+    }
+}
+
+/// Writes the size and eviction count of every cache to `f`.
+pub fn dump(f: &mut dyn Write) -> std::io::Result<()> {
+    writeln!(f, "Cache memory (ceiling {} bytes):", memory_limit())?;
+    for cache in usage() {
+        writeln!(
+            f,
+            "  {:?}: {} bytes, {} evictions",
+            cache.kind, cache.size_bytes, cache.evictions
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pressure_from_trim_level() {
+        // TRIM_MEMORY_RUNNING_MODERATE, TRIM_MEMORY_RUNNING_LOW, TRIM_MEMORY_UI_HIDDEN,
+        // TRIM_MEMORY_BACKGROUND, and TRIM_MEMORY_MODERATE.
+        for level in &[5, 10, 20, 40, 60] {
+            assert_eq!(MemoryPressure::from_trim_level(*level), MemoryPressure::Moderate);
+        }
+        for level in &[TRIM_MEMORY_RUNNING_CRITICAL, TRIM_MEMORY_COMPLETE] {
+            assert_eq!(MemoryPressure::from_trim_level(*level), MemoryPressure::Critical);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements `CallerIdentity`, the identity of the client of a binder call. It is
+//! resolved once at the beginning of a call and handed down to all layers that need to know who
+//! the caller is, so that they don't have to query the thread state or derive the Android user
+//! on their own. It also attributes the caller to a package, which is used for audit logging.
+
+use crate::error::{map_binder_status, map_binder_status_code};
+use crate::utils::uid_to_android_user;
+use anyhow::{anyhow, Context, Result};
+use binder::{Strong, ThreadState};
+use lazy_static::lazy_static;
+use packagemanager_aidl::aidl::android::content::pm::IPackageManagerNative::IPackageManagerNative;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Name of the native package manager service.
+const PACKAGE_MANAGER_SERVICE_NAME: &str = "package_native";
+
+/// Maximal number of uids whose package name is cached.
+const PACKAGE_NAME_CACHE_SIZE: usize = 256;
+
+/// After the package manager could not be reached, e.g., early during boot, no further
+/// attempts are made for this long.
+const PACKAGE_MANAGER_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    static ref PACKAGE_NAMES: Mutex<PackageNameCache> = Default::default();
+}
+
+#[derive(Default)]
+struct PackageNameCache {
+    names: HashMap<u32, Option<Arc<str>>>,
+    target_sdks: HashMap<u32, Option<i32>>,
+    unavailable_since: Option<Instant>,
+}
+
+/// The identity of the client of a binder call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallerIdentity {
+    uid: u32,
+    pid: i32,
+    sid: Option<CString>,
+    user_id: u32,
+}
+
+impl CallerIdentity {
+    /// Resolves the identity of the client of the binder call that is served by the current
+    /// thread. Must be called on a binder thread.
+    pub fn current() -> Self {
+        let sid = ThreadState::with_calling_sid(|sid| sid.map(CStr::to_owned));
+        Self::new(ThreadState::get_calling_uid(), ThreadState::get_calling_pid(), sid)
+    }
+
+    /// Creates the identity of a caller that is not the client of the current binder call,
+    /// e.g., keystore itself acting on behalf of a uid.
+    pub fn new(uid: u32, pid: i32, sid: Option<CString>) -> Self {
+        Self { uid, pid, sid, user_id: uid_to_android_user(uid) }
+    }
+
+    /// The uid of the caller.
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    /// The pid of the caller.
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    /// The SELinux context of the caller, if the binder interface requested it.
+    pub fn sid(&self) -> Option<&CStr> {
+        self.sid.as_deref()
+    }
+
+    /// The Android user of the caller.
+    pub fn user_id(&self) -> u32 {
+        self.user_id
+    }
+
+    /// The name that the package manager reports for the uid of the caller. This is the package
+    /// name, or the shared user id if the uid is shared by several packages. Returns None if the
+    /// package manager does not know the uid or cannot be reached.
+    ///
+    /// The names are cached, but a cache miss results in a binder call into the package
+    /// manager, so this should not be called on the critical path of a request.
+    pub fn package_name(&self) -> Option<Arc<str>> {
+        self.try_package_name().ok().flatten()
+    }
+
+    /// Like `package_name`, but returns an error if the package manager cannot be reached.
+    fn try_package_name(&self) -> Result<Option<Arc<str>>> {
+        if let Some(name) = PACKAGE_NAMES.lock().unwrap().lookup(self.uid) {
+            return name.context("In try_package_name.");
+        }
+        match query_package_name(self.uid) {
+            Ok(name) => {
+                let name: Option<Arc<str>> = name.map(Into::into);
+                PACKAGE_NAMES.lock().unwrap().insert(self.uid, name.clone());
+                Ok(name)
+            }
+            Err(e) => {
+                log::warn!("Failed to resolve the package of uid {}: {:?}", self.uid, e);
+                PACKAGE_NAMES.lock().unwrap().unavailable_since = Some(Instant::now());
+                Err(e).context("In try_package_name.")
+            }
+        }
+    }
+
+    /// The target SDK version of the caller's package. Returns None if the package manager does
+    /// not know the uid or the uid is shared by several packages. Returns an error if the
+    /// package manager cannot be reached, so that callers can fail closed. Errors are not
+    /// cached.
+    ///
+    /// Like `package_name`, a cache miss results in binder calls into the package manager.
+    pub fn target_sdk_version(&self) -> Result<Option<i32>> {
+        if let Some(version) = PACKAGE_NAMES.lock().unwrap().target_sdks.get(&self.uid) {
+            return Ok(*version);
+        }
+        let package_name = match self.try_package_name().context("In target_sdk_version.")? {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        // The package manager reports "<shared user id>:<uid>" for shared uids.
+        let version = if package_name.contains(':') {
+            None
+        } else {
+            Some(query_target_sdk_version(&package_name).context("In target_sdk_version.")?)
+        };
+        PACKAGE_NAMES.lock().unwrap().target_sdks.insert(self.uid, version);
+        Ok(version)
+    }
+}
+
+impl PackageNameCache {
+    /// Returns Some if the name is cached or the package manager is known to be unavailable.
+    fn lookup(&mut self, uid: u32) -> Option<Result<Option<Arc<str>>>> {
+        if let Some(name) = self.names.get(&uid) {
+            return Some(Ok(name.clone()));
+        }
+        match self.unavailable_since {
+            Some(since) if since.elapsed() < PACKAGE_MANAGER_RETRY_INTERVAL => {
+                Some(Err(anyhow!("The package manager is unavailable.")))
+            }
+            _ => {
+                self.unavailable_since = None;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, uid: u32, name: Option<Arc<str>>) {
+        if self.names.len() >= PACKAGE_NAME_CACHE_SIZE {
+            self.names.clear();
+            self.target_sdks.clear();
+        }
+        self.names.insert(uid, name);
+    }
+}
+
+/// Drops the cached package name of the given uid. This must be called when the packages of a
+/// uid change, i.e., when an app is uninstalled and its uid may be reused.
+pub fn forget_package_name(uid: u32) {
+    let mut cache = PACKAGE_NAMES.lock().unwrap();
+    cache.names.remove(&uid);
+    cache.target_sdks.remove(&uid);
+}
+
+/// Returns an estimate of the memory held by the package name cache in bytes.
+pub fn package_name_cache_size_bytes() -> usize {
+    let cache = PACKAGE_NAMES.lock().unwrap();
+    let names: usize = cache
+        .names
+        .values()
+        .map(|name| {
+            size_of::<(u32, Option<Arc<str>>)>() + name.as_ref().map_or(0, |name| name.len())
+        })
+        .sum();
+    names + cache.target_sdks.len() * size_of::<(u32, Option<i32>)>()
+}
+
+/// Drops all cached package names and target SDK versions. Returns the number of dropped
+/// package names.
+pub fn clear_package_name_cache() -> usize {
+    let mut cache = PACKAGE_NAMES.lock().unwrap();
+    let cleared = cache.names.len();
+    cache.names.clear();
+    cache.target_sdks.clear();
+    cleared
+}
+
+fn query_package_name(uid: u32) -> Result<Option<String>> {
+    let package_manager: Strong<dyn IPackageManagerNative> =
+        map_binder_status_code(binder::get_interface(PACKAGE_MANAGER_SERVICE_NAME))
+            .context("In query_package_name: Failed to get the package manager.")?;
+    let names = map_binder_status(package_manager.getNamesForUids(&[uid as i32]))
+        .context("In query_package_name: getNamesForUids failed.")?;
+    Ok(names.into_iter().next().filter(|name| !name.is_empty()))
+}
+
+fn query_target_sdk_version(package_name: &str) -> Result<i32> {
+    let package_manager: Strong<dyn IPackageManagerNative> =
+        map_binder_status_code(binder::get_interface(PACKAGE_MANAGER_SERVICE_NAME))
+            .context("In query_target_sdk_version: Failed to get the package manager.")?;
+    map_binder_status(package_manager.getTargetSdkVersionForPackage(package_name))
+        .context("In query_target_sdk_version: getTargetSdkVersionForPackage failed.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn package_name_cache() {
+        let mut cache: PackageNameCache = Default::default();
+        assert!(cache.lookup(10001).is_none());
+        cache.insert(10001, Some("com.example".into()));
+        cache.insert(10002, None);
+        assert_eq!(cache.lookup(10001).unwrap().unwrap(), Some("com.example".into()));
+        assert_eq!(cache.lookup(10002).unwrap().unwrap(), None);
+
+        // While the package manager is unavailable, uncached uids fail.
+        cache.unavailable_since = Some(Instant::now());
+        assert!(cache.lookup(10003).unwrap().is_err());
+        if let Some(since) = Instant::now().checked_sub(PACKAGE_MANAGER_RETRY_INTERVAL) {
+            cache.unavailable_since = Some(since);
+            assert!(cache.lookup(10003).is_none());
+            assert_eq!(cache.unavailable_since, None);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module decides, per KeyMint instance, how Keystore 2.0 handles features that not all
+//! HAL versions support. Each feature is either passed through to the HAL, emulated by
+//! Keystore, or rejected. The versions of all connected instances are recorded, so that the
+//! resulting matrix can be dumped for integrators.
+//!
+//! HAL versions follow the convention established in `globals::connect_keymint`: Keymaster
+//! devices behind the legacy wrapper report 10 * <major> + <minor>, e.g., 41 for Keymaster 4.1,
+//! and KeyMint devices report 100 * <AIDL version>.
+
+use crate::raw_device::KeyMintDevice;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::Mutex;
+
+/// A feature whose availability depends on the HAL version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// The EARLY_BOOT_ONLY tag. Emulated with MAX_USES_PER_BOOT(1) for internal keys.
+    EarlyBootOnly,
+    /// IKeyMintDevice::convertStorageKeyToEphemeral. The legacy wrapper implements it for
+    /// Keymaster 4.0 and 4.1 devices by exporting the key in raw format.
+    StorageKeys,
+    /// Ending the operations of a previous instance of Keystore. KeyMint devices do so when
+    /// Keystore dies. Keystore emulates it for Keymaster devices by aborting the operations it
+    /// recorded through the legacy wrapper.
+    OrphanedOperationCleanup,
+}
+
+/// How a feature is handled on a given HAL version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Support {
+    /// The HAL implements the feature.
+    PassThrough,
+    /// Keystore emulates the feature, possibly with weaker guarantees.
+    Emulate,
+    /// Requests that need the feature are rejected by Keystore.
+    Reject,
+}
+
+/// Each feature is passed through from the given version on and handled as given below it.
+const MATRIX: &[(Feature, i32, Support)] = &[
+    (Feature::EarlyBootOnly, KeyMintDevice::KEY_MASTER_V4_1, Support::Emulate),
+    (Feature::StorageKeys, KeyMintDevice::KEY_MASTER_V4_0, Support::Reject),
+    (Feature::OrphanedOperationCleanup, KeyMintDevice::KEY_MINT_V1, Support::Emulate),
+];
+
+lazy_static! {
+    /// The HAL versions of all KeyMint instances that were connected.
+    static ref INSTANCES: Mutex<BTreeMap<i32, i32>> = Default::default();
+}
+
+/// Returns how `feature` is handled on a HAL of the given version.
+pub fn support(version: i32, feature: Feature) -> Support {
+    MATRIX
+        .iter()
+        .find(|(f, _, _)| *f == feature)
+        .map(
+            |(_, min_version, below)| {
+                if version >= *min_version {
+                    Support::PassThrough
+                } else {
+                    *below
+                }
+            },
+        )
+        .unwrap_or(Support::PassThrough)
+}
+
+/// Records the HAL version of the KeyMint instance of the given security level. This is called
+/// whenever Keystore connects to an instance.
+pub fn record_instance(security_level: SecurityLevel, version: i32) {
+    INSTANCES.lock().unwrap().insert(security_level.0, version);
+}
+
+/// Writes the matrix for all recorded instances to `f`.
+pub fn dump(f: &mut dyn Write) -> std::io::Result<()> {
+    writeln!(f, "KeyMint capability matrix:")?;
+    let instances = INSTANCES.lock().unwrap().clone();
+    for (security_level, version) in instances {
+        writeln!(f, "  {:?} (HAL version {}):", SecurityLevel(security_level), version)?;
+        for (feature, _, _) in MATRIX {
+            writeln!(f, "    {:?}: {:?}", feature, support(version, *feature))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_support() {
+        const KEY_MASTER_V3_0: i32 = 30;
+        assert_eq!(
+            support(KeyMintDevice::KEY_MASTER_V4_0, Feature::EarlyBootOnly),
+            Support::Emulate
+        );
+        assert_eq!(support(KEY_MASTER_V3_0, Feature::StorageKeys), Support::Reject);
+        for version in &[KeyMintDevice::KEY_MASTER_V4_0, KeyMintDevice::KEY_MASTER_V4_1] {
+            assert_eq!(support(*version, Feature::StorageKeys), Support::PassThrough);
+            assert_eq!(support(*version, Feature::OrphanedOperationCleanup), Support::Emulate);
+        }
+        assert_eq!(
+            support(KeyMintDevice::KEY_MASTER_V4_1, Feature::EarlyBootOnly),
+            Support::PassThrough
+        );
+        for feature in
+            &[Feature::EarlyBootOnly, Feature::StorageKeys, Feature::OrphanedOperationCleanup]
+        {
+            assert_eq!(support(KeyMintDevice::KEY_MINT_V1, *feature), Support::PassThrough);
+        }
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements a counting limit on the number of concurrent requests that
+//! Keystore issues to a KeyMint instance. Most TEE implementations can serve several
+//! sessions at once, while StrongBox implementations typically serve one, and requests
+//! beyond the capacity of the instance only queue up in the HAL where they count against
+//! the watchdog of the calling binder thread.
+//!
+//! Requests that would queue up behind too many others are rejected instead, with an estimate
+//! of when a slot will be available, so that clients can back off. So are requests that waited
+//! for a slot for too long, so that a stuck instance cannot hold binder threads indefinitely.
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use keystore2_system_property::PropertyWatcher;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Default number of concurrent key generation requests for TEE KeyMint instances.
+const DEFAULT_TEE_MAX_CONCURRENT_GENERATE: usize = 4;
+
+/// Default number of key generation requests that may wait for a slot.
+const DEFAULT_MAX_QUEUED_GENERATE: usize = 8;
+
+/// How long a key generation request may wait for a slot.
+const MAX_WAIT_GENERATE: Duration = Duration::from_secs(5);
+
+/// Bounds of the retry hint given to rejected requests.
+const MIN_RETRY_AFTER: Duration = Duration::from_millis(100);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct State {
+    in_flight: usize,
+    queued: usize,
+    /// Exponentially weighted moving average of the time a slot is held.
+    mean_hold: Duration,
+}
+
+/// Limits the number of threads that may hold a `ConcurrencyGuard` at any given time.
+pub struct ConcurrencyLimit {
+    max: usize,
+    max_queued: usize,
+    max_wait: Option<Duration>,
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+/// Held while a request is in flight. The slot is released on drop.
+pub struct ConcurrencyGuard<'a> {
+    limit: &'a ConcurrencyLimit,
+    acquired: Instant,
+}
+
+impl ConcurrencyLimit {
+    /// Creates a new limit that admits `max` concurrent holders. A `max` of 0 is treated as 1.
+    /// Any number of threads may wait for a slot for as long as it takes.
+    pub fn new(max: usize) -> Self {
+        Self {
+            max: max.max(1),
+            max_queued: usize::MAX,
+            max_wait: None,
+            state: Mutex::new(Default::default()),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Limits the number of threads that may wait for a slot in `acquire_or_reject`.
+    pub fn with_max_queued(self, max_queued: usize) -> Self {
+        Self { max_queued, ..self }
+    }
+
+    /// Limits the time a thread may wait for a slot in `acquire_or_reject`.
+    pub fn with_max_wait(self, max_wait: Duration) -> Self {
+        Self { max_wait: Some(max_wait), ..self }
+    }
+
+    /// Creates the limit for concurrent key generation requests on the KeyMint instance of
+    /// the given security level. The limit can be configured with the system property
+    /// `keystore.keymint.<tee|strongbox>.max_concurrent_generate`. Otherwise, TEE instances
+    /// admit `DEFAULT_TEE_MAX_CONCURRENT_GENERATE` requests and all others one. Likewise, the
+    /// number of waiting requests is configured with `<...>.max_queued_generate` and defaults to
+    /// `DEFAULT_MAX_QUEUED_GENERATE`. Requests wait at most `MAX_WAIT_GENERATE` for a slot.
+    pub fn for_generate_key(security_level: SecurityLevel) -> Self {
+        let (prefix, default) = match security_level {
+            SecurityLevel::TRUSTED_ENVIRONMENT => {
+                ("keystore.keymint.tee", DEFAULT_TEE_MAX_CONCURRENT_GENERATE)
+            }
+            SecurityLevel::STRONGBOX => ("keystore.keymint.strongbox", 1),
+            _ => return Self::new(1).with_max_wait(MAX_WAIT_GENERATE),
+        };
+        let read = |property: String| {
+            PropertyWatcher::new(&property).ok().and_then(|mut w| {
+                w.read(|_n, v| v.parse::<usize>().map_err(std::convert::Into::into)).ok()
+            })
+        };
+        let max = read(format!("{}.max_concurrent_generate", prefix)).unwrap_or(default);
+        let max_queued =
+            read(format!("{}.max_queued_generate", prefix)).unwrap_or(DEFAULT_MAX_QUEUED_GENERATE);
+        Self::new(max).with_max_queued(max_queued).with_max_wait(MAX_WAIT_GENERATE)
+    }
+
+    /// Returns the maximal number of concurrent holders.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Blocks until a slot is available and returns a guard that holds it.
+    pub fn acquire(&self) -> ConcurrencyGuard {
+        self.acquire_bounded(usize::MAX, None).expect("Unbounded acquire cannot be rejected.")
+    }
+
+    /// Like `acquire`, but if all slots are taken and the configured number of threads is
+    /// waiting already, it returns immediately with an estimate of when to retry. Likewise, if
+    /// no slot became available within the configured time, it gives up with an estimate.
+    pub fn acquire_or_reject(&self) -> Result<ConcurrencyGuard, Duration> {
+        self.acquire_bounded(self.max_queued, self.max_wait)
+    }
+
+    /// Estimates how long it takes until a new request would get a slot, based on the number of
+    /// waiting requests and the mean time a slot is held.
+    pub fn retry_after(&self) -> Duration {
+        let state = self.state.lock().unwrap();
+        Self::estimate_retry_after(&state, self.max)
+    }
+
+    fn estimate_retry_after(state: &State, max: usize) -> Duration {
+        let rounds = (state.queued / max + 1) as u32;
+        (state.mean_hold * rounds).clamp(MIN_RETRY_AFTER, MAX_RETRY_AFTER)
+    }
+
+    fn acquire_bounded(
+        &self,
+        max_queued: usize,
+        max_wait: Option<Duration>,
+    ) -> Result<ConcurrencyGuard, Duration> {
+        let mut state = self.state.lock().unwrap();
+        if state.in_flight >= self.max {
+            if state.queued >= max_queued {
+                return Err(Self::estimate_retry_after(&state, self.max));
+            }
+            state.queued += 1;
+            state = match max_wait {
+                Some(max_wait) => {
+                    self.cond
+                        .wait_timeout_while(state, max_wait, |s| s.in_flight >= self.max)
+                        .unwrap()
+                        .0
+                }
+                None => self.cond.wait_while(state, |s| s.in_flight >= self.max).unwrap(),
+            };
+            state.queued -= 1;
+            if state.in_flight >= self.max {
+                return Err(Self::estimate_retry_after(&state, self.max));
+            }
+        }
+        state.in_flight += 1;
+        Ok(ConcurrencyGuard { limit: self, acquired: Instant::now() })
+    }
+}
+
+impl Drop for ConcurrencyGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.limit.state.lock().unwrap();
+        state.in_flight -= 1;
+        state.mean_hold = (state.mean_hold * 7 + self.acquired.elapsed()) / 8;
+        self.limit.cond.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_limit_is_respected() {
+        let limit = Arc::new(ConcurrencyLimit::new(2));
+        let current = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let (limit, current, peak) = (limit.clone(), current.clone(), peak.clone());
+                thread::spawn(move || {
+                    let _guard = limit.acquire();
+                    let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(20));
+                    current.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(peak.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn test_reject_when_queue_is_full() {
+        let limit = Arc::new(ConcurrencyLimit::new(1).with_max_queued(1));
+        let guard = limit.acquire_or_reject().unwrap();
+
+        let waiter = {
+            let limit = limit.clone();
+            thread::spawn(move || drop(limit.acquire_or_reject().unwrap()))
+        };
+        while limit.state.lock().unwrap().queued == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(limit.acquire_or_reject().err(), Some(MIN_RETRY_AFTER));
+
+        drop(guard);
+        waiter.join().unwrap();
+        assert!(limit.acquire_or_reject().is_ok());
+    }
+
+    #[test]
+    fn test_reject_after_max_wait() {
+        let limit = ConcurrencyLimit::new(1).with_max_wait(Duration::from_millis(10));
+        let guard = limit.acquire_or_reject().unwrap();
+        assert_eq!(limit.acquire_or_reject().err(), Some(MIN_RETRY_AFTER));
+        assert_eq!(limit.state.lock().unwrap().queued, 0);
+        drop(guard);
+        assert!(limit.acquire_or_reject().is_ok());
+    }
+
+    #[test]
+    fn test_retry_after_estimate() {
+        let mut state = State { in_flight: 2, queued: 0, mean_hold: Duration::from_secs(1) };
+        assert_eq!(ConcurrencyLimit::estimate_retry_after(&state, 2), Duration::from_secs(1));
+        state.queued = 4;
+        assert_eq!(ConcurrencyLimit::estimate_retry_after(&state, 2), Duration::from_secs(3));
+        state.queued = 100;
+        assert_eq!(ConcurrencyLimit::estimate_retry_after(&state, 2), MAX_RETRY_AFTER);
+        state.mean_hold = Duration::ZERO;
+        assert_eq!(ConcurrencyLimit::estimate_retry_after(&state, 2), MIN_RETRY_AFTER);
+    }
+
+    #[test]
+    fn test_zero_is_treated_as_one() {
+        let limit = ConcurrencyLimit::new(0);
+        assert_eq!(limit.max(), 1);
+        drop(limit.acquire());
+        drop(limit.acquire());
+    }
+}
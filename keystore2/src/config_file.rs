@@ -0,0 +1,81 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the loading of the line based configuration files of Keystore,
+//! e.g., the storage quotas or the key policy. A missing file means that the device does not
+//! use the feature. A file that is present but cannot be read or parsed is an error, because
+//! silently ignoring it would drop restrictions that the device maker asked for. Callers
+//! refuse the affected requests in this case instead of falling back to the defaults.
+
+use anyhow::{anyhow, Context, Result};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// Reads the configuration file at `path` and parses it with `parse`. Returns the default
+/// configuration if the file does not exist. Errors are logged, so that a malformed file
+/// shows up in the log of the boot that picked it up.
+pub fn load<T: Default>(path: &str, parse: impl FnOnce(&str) -> Result<T>) -> Result<T> {
+    let result = match std::fs::read_to_string(path) {
+        Ok(config) => parse(&config).with_context(|| format!("Malformed {}.", path)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Default::default()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}.", path)),
+    };
+    if let Err(e) = &result {
+        log::error!("In config_file::load: {:?}", e);
+    }
+    result
+}
+
+/// Parses an inclusive range of the form `<first>[-<last>]`.
+pub fn parse_range<T: FromStr + PartialOrd>(range: &str) -> Result<RangeInclusive<T>> {
+    let (first, last) = range.split_once('-').unwrap_or((range, range));
+    let first: T = first.parse().map_err(|_| anyhow!("Bad range start \"{}\".", first))?;
+    let last: T = last.parse().map_err(|_| anyhow!("Bad range end \"{}\".", last))?;
+    if first > last {
+        return Err(anyhow!("Empty range \"{}\".", range));
+    }
+    Ok(first..=last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keystore2_test_utils::TempDir;
+
+    fn parse_count(config: &str) -> Result<usize> {
+        config.trim().parse().context("Bad count.")
+    }
+
+    #[test]
+    fn test_parse_range() -> Result<()> {
+        assert_eq!(parse_range::<i64>("7")?, 7..=7);
+        assert_eq!(parse_range::<u32>("10000-19999")?, 10000..=19999);
+        assert!(parse_range::<i64>("9-1").is_err());
+        assert!(parse_range::<u32>("a-1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load() -> Result<()> {
+        let temp_dir = TempDir::new("config_file_test")?;
+        let path = temp_dir.path().join("test.conf");
+        let path = path.to_str().unwrap();
+        assert_eq!(load(path, parse_count)?, 0);
+        std::fs::write(path, "3\n")?;
+        assert_eq!(load(path, parse_count)?, 3);
+        std::fs::write(path, "three\n")?;
+        assert!(load(path, parse_count).is_err());
+        Ok(())
+    }
+}
@@ -26,6 +26,7 @@
 #include <openssl/evp.h>
 #include <openssl/hkdf.h>
 #include <openssl/rand.h>
+#include <openssl/sha.h>
 #include <openssl/x509.h>
 
 #include <vector>
@@ -319,3 +320,39 @@ int extractSubjectFromCertificate(const uint8_t* cert_buf, size_t cert_len, uint
     uint8_t* tmp = subject_buf;
     return i2d_X509_NAME(subject, &tmp);
 }
+
+bool certificateFingerprints(const uint8_t* cert_buf, size_t cert_len, uint8_t* cert_digest,
+                             uint8_t* spki_digest) {
+    if (!cert_buf || !cert_digest || !spki_digest) {
+        ALOGE("certificateFingerprints: received null pointer");
+        return false;
+    }
+
+    const uint8_t* p = cert_buf;
+    bssl::UniquePtr<X509> cert(d2i_X509(nullptr /* Allocate X509 struct */, &p, cert_len));
+    if (!cert) {
+        ALOGE("certificateFingerprints: failed to parse certificate");
+        return false;
+    }
+
+    uint8_t* spki = nullptr;
+    int spki_len = i2d_X509_PUBKEY(X509_get_X509_PUBKEY(cert.get()), &spki);
+    if (spki_len <= 0) {
+        ALOGE("certificateFingerprints: failed to encode subject public key info");
+        return false;
+    }
+    bssl::UniquePtr<uint8_t> spki_owner(spki);
+
+    SHA256(cert_buf, cert_len, cert_digest);
+    SHA256(spki, spki_len, spki_digest);
+    return true;
+}
+
+bool sha256Digest(const uint8_t* data, size_t data_len, uint8_t* digest) {
+    if (!data || !digest) {
+        ALOGE("sha256Digest: received null pointer");
+        return false;
+    }
+    SHA256(data, data_len, digest);
+    return true;
+}
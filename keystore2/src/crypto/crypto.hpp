@@ -87,4 +87,20 @@ extern "C" {
 int extractSubjectFromCertificate(const uint8_t* cert_buf, size_t cert_len,
                                   uint8_t* subject_buf, size_t subject_buf_len);
 
+// Parse a DER-encoded X.509 certificate contained in cert_buf, with length
+// cert_len, and write the SHA-256 digest of the certificate to cert_digest and
+// the SHA-256 digest of its DER-encoded SubjectPublicKeyInfo to spki_digest.
+// Both output buffers must hold SHA256_DIGEST_LENGTH bytes.
+//
+// Returns false if the certificate could not be parsed. The reason will be
+// logged.
+bool certificateFingerprints(const uint8_t* cert_buf, size_t cert_len,
+                             uint8_t* cert_digest, uint8_t* spki_digest);
+
+// Write the SHA-256 digest of the data_len bytes in data to digest, which must
+// hold SHA256_DIGEST_LENGTH bytes.
+//
+// Returns false if a null pointer was passed.
+bool sha256Digest(const uint8_t* data, size_t data_len, uint8_t* digest);
+
 #endif  //  __CRYPTO_H__
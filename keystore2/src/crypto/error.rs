@@ -93,4 +93,12 @@ pub enum Error {
     /// This is returned if the C implementation of extractSubjectFromCertificate failed.
     #[error("Failed to extract certificate subject.")]
     ExtractSubjectFailed,
+
+    /// This is returned if the C implementation of certificateFingerprints failed.
+    #[error("Failed to compute certificate fingerprints.")]
+    CertificateFingerprintsFailed,
+
+    /// This is returned if the C implementation of sha256Digest failed.
+    #[error("Failed to compute SHA-256 digest.")]
+    Sha256Failed,
 }
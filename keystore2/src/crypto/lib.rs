@@ -19,10 +19,11 @@ mod error;
 mod zvec;
 pub use error::Error;
 use keystore2_crypto_bindgen::{
-    extractSubjectFromCertificate, generateKeyFromPassword, randomBytes, AES_gcm_decrypt,
-    AES_gcm_encrypt, ECDHComputeKey, ECKEYGenerateKey, ECKEYMarshalPrivateKey,
-    ECKEYParsePrivateKey, ECPOINTOct2Point, ECPOINTPoint2Oct, EC_KEY_free, EC_KEY_get0_public_key,
-    EC_POINT_free, HKDFExpand, HKDFExtract, EC_KEY, EC_MAX_BYTES, EC_POINT, EVP_MAX_MD_SIZE,
+    certificateFingerprints, extractSubjectFromCertificate, generateKeyFromPassword, randomBytes,
+    sha256Digest, AES_gcm_decrypt, AES_gcm_encrypt, ECDHComputeKey, ECKEYGenerateKey,
+    ECKEYMarshalPrivateKey, ECKEYParsePrivateKey, ECPOINTOct2Point, ECPOINTPoint2Oct, EC_KEY_free,
+    EC_KEY_get0_public_key, EC_POINT_free, HKDFExpand, HKDFExtract, EC_KEY, EC_MAX_BYTES, EC_POINT,
+    EVP_MAX_MD_SIZE,
 };
 use std::convert::TryFrom;
 use std::convert::TryInto;
@@ -40,6 +41,9 @@ pub const AES_128_KEY_LENGTH: usize = 16;
 /// Length of the expected salt for key from password generation.
 pub const SALT_LENGTH: usize = 16;
 
+/// Length of a SHA-256 digest in bytes.
+pub const SHA256_DIGEST_LENGTH: usize = 32;
+
 /// Older versions of keystore produced IVs with four extra
 /// ignored zero bytes at the end; recognise and trim those.
 pub const LEGACY_IV_LENGTH: usize = 16;
@@ -61,6 +65,16 @@ pub fn generate_salt() -> Result<Vec<u8>, Error> {
     generate_random_data(SALT_LENGTH)
 }
 
+/// Fill the given buffer with random bytes.
+pub fn fill_random(buf: &mut [u8]) -> Result<(), Error> {
+    // Safety: randomBytes writes exactly buf.len() bytes.
+    if unsafe { randomBytes(buf.as_mut_ptr(), buf.len()) } {
+        Ok(())
+    } else {
+        Err(Error::RandomNumberGenerationFailed)
+    }
+}
+
 /// Generate random data of the given size.
 pub fn generate_random_data(size: usize) -> Result<Vec<u8>, Error> {
     // Safety: data has the same length as the requested number of random bytes.
@@ -457,6 +471,45 @@ pub fn parse_subject_from_certificate(cert_buf: &[u8]) -> Result<Vec<u8>, Error>
     Ok(retval)
 }
 
+/// Uses BoringSSL to compute the SHA-256 digest of a DER-encoded X.509 certificate and the
+/// SHA-256 digest of its DER-encoded SubjectPublicKeyInfo, in this order.
+pub fn certificate_fingerprints(cert_buf: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let mut cert_digest = vec![0; SHA256_DIGEST_LENGTH];
+    let mut spki_digest = vec![0; SHA256_DIGEST_LENGTH];
+
+    // Safety: certificateFingerprints reads at most cert_buf.len() bytes from cert_buf and
+    // writes SHA256_DIGEST_LENGTH bytes to each of cert_digest and spki_digest.
+    let success = unsafe {
+        certificateFingerprints(
+            cert_buf.as_ptr(),
+            cert_buf.len(),
+            cert_digest.as_mut_ptr(),
+            spki_digest.as_mut_ptr(),
+        )
+    };
+
+    if success {
+        Ok((cert_digest, spki_digest))
+    } else {
+        Err(Error::CertificateFingerprintsFailed)
+    }
+}
+
+/// Uses BoringSSL to compute the SHA-256 digest of `data`.
+pub fn sha256(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut digest = vec![0; SHA256_DIGEST_LENGTH];
+
+    // Safety: sha256Digest reads at most data.len() bytes from data and writes
+    // SHA256_DIGEST_LENGTH bytes to digest.
+    let success = unsafe { sha256Digest(data.as_ptr(), data.len(), digest.as_mut_ptr()) };
+
+    if success {
+        Ok(digest)
+    } else {
+        Err(Error::Sha256Failed)
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -531,6 +584,19 @@ mod tests {
         assert_ne!(key, vec![0; 16]);
     }
 
+    #[test]
+    fn test_sha256() {
+        let digest = sha256(b"abc").unwrap();
+        assert_eq!(
+            digest,
+            vec![
+                0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+                0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+                0xf2, 0x00, 0x15, 0xad
+            ]
+        );
+    }
+
     #[test]
     fn test_hkdf() {
         let result = hkdf_extract(&[0; 16], &[0; 16]);
@@ -0,0 +1,262 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module is the single source of random bytes for Keystore 2.0, e.g., for super keys,
+//! salts, ids, and the entropy that is fed to KeyMint. It wraps the BoringSSL random number
+//! generator and adds health tests:
+//!  * A startup test draws a number of blocks and fails if any of them repeats.
+//!  * In approved mode, every block of output is compared with the previous block, in the
+//!    style of the FIPS 140-2 continuous random number generator test.
+//!
+//! Once a health test failed, all further requests fail until Keystore restarts.
+//! Tests can substitute a deterministic source with `with_deterministic_source`.
+
+use anyhow::{Context, Result};
+use keystore2_crypto::{ZVec, AES_256_KEY_LENGTH, SALT_LENGTH};
+use keystore2_system_property::PropertyWatcher;
+use lazy_static::lazy_static;
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::sync::Mutex;
+
+/// If this property is "true", the continuous test runs on all output.
+const APPROVED_MODE_PROPERTY: &str = "ro.keystore.approved_mode";
+
+/// Size of the blocks that the health tests compare.
+const BLOCK_SIZE: usize = 16;
+
+/// Number of blocks that are drawn by the startup test.
+const STARTUP_TEST_BLOCKS: usize = 64;
+
+/// Error codes specific to the csprng module.
+#[derive(thiserror::Error, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// A health test of the random number generator failed.
+    #[error("Random number generator health test failed.")]
+    HealthTestFailed,
+}
+
+#[derive(Default)]
+struct State {
+    startup_tested: bool,
+    failed: bool,
+    continuous_test: ContinuousTest,
+}
+
+lazy_static! {
+    static ref STATE: Mutex<State> = Default::default();
+    static ref APPROVED_MODE: bool = PropertyWatcher::new(APPROVED_MODE_PROPERTY)
+        .ok()
+        .and_then(|mut w| w.read(|_n, v| Ok(v == "true")).ok())
+        .unwrap_or(false);
+}
+
+/// Compares each block of output with the previous one. The previous block is kept in a
+/// ZVec, because it may be part of key material.
+#[derive(Default)]
+struct ContinuousTest {
+    last_block: Option<ZVec>,
+}
+
+impl ContinuousTest {
+    /// Checks `data`, which must be a multiple of BLOCK_SIZE long.
+    fn check(&mut self, data: &[u8]) -> Result<()> {
+        for block in data.chunks(BLOCK_SIZE) {
+            if self.last_block.as_deref() == Some(block) {
+                return Err(Error::HealthTestFailed)
+                    .context("In ContinuousTest::check: Repeated block.");
+            }
+            self.last_block = Some(block.try_into().context("In ContinuousTest::check.")?);
+        }
+        Ok(())
+    }
+}
+
+/// Draws STARTUP_TEST_BLOCKS blocks from `source` and fails if any of them repeats.
+fn startup_test(source: impl Fn(&mut [u8]) -> Result<()>) -> Result<()> {
+    let mut data = ZVec::new(STARTUP_TEST_BLOCKS * BLOCK_SIZE).context("In startup_test.")?;
+    source(&mut data).context("In startup_test: Failed to draw random data.")?;
+    let distinct: HashSet<&[u8]> = data.chunks(BLOCK_SIZE).collect();
+    if distinct.len() != STARTUP_TEST_BLOCKS {
+        return Err(Error::HealthTestFailed).context("In startup_test: Repeated block.");
+    }
+    Ok(())
+}
+
+fn system_source(buf: &mut [u8]) -> Result<()> {
+    keystore2_crypto::fill_random(buf).context("In system_source.")
+}
+
+/// Runs the startup test unless it ran already. It runs on first use anyway, but calling this
+/// early during start-up surfaces a broken random number generator right away.
+pub fn self_test() -> Result<()> {
+    let mut state = STATE.lock().unwrap();
+    ensure_startup_tested(&mut state).context("In self_test.")
+}
+
+fn ensure_startup_tested(state: &mut State) -> Result<()> {
+    if state.failed {
+        return Err(Error::HealthTestFailed)
+            .context("In ensure_startup_tested: A previous health test failed.");
+    }
+    if !state.startup_tested {
+        if let Err(e) = startup_test(system_source) {
+            log::error!("Random number generator startup test failed: {:?}", e);
+            state.failed = true;
+            return Err(e).context("In ensure_startup_tested.");
+        }
+        state.startup_tested = true;
+    }
+    Ok(())
+}
+
+/// Fills `buf` with random bytes.
+pub fn fill(buf: &mut [u8]) -> Result<()> {
+    if deterministic_fill(buf) {
+        return Ok(());
+    }
+
+    let mut state = STATE.lock().unwrap();
+    ensure_startup_tested(&mut state).context("In fill.")?;
+
+    if !*APPROVED_MODE {
+        return system_source(buf).context("In fill.");
+    }
+
+    // In approved mode we draw whole blocks, so that every byte of output is covered by
+    // the continuous test.
+    let blocks = (buf.len() + BLOCK_SIZE - 1) / BLOCK_SIZE;
+    let mut data = ZVec::new(blocks * BLOCK_SIZE).context("In fill.")?;
+    system_source(&mut data).context("In fill.")?;
+    if let Err(e) = state.continuous_test.check(&data) {
+        log::error!("Random number generator continuous test failed: {:?}", e);
+        state.failed = true;
+        return Err(e).context("In fill.");
+    }
+    buf.copy_from_slice(&data[..buf.len()]);
+    Ok(())
+}
+
+/// Returns `size` random bytes.
+pub fn random_data(size: usize) -> Result<Vec<u8>> {
+    let mut data = vec![0; size];
+    fill(&mut data).context("In random_data.")?;
+    Ok(data)
+}
+
+/// Returns a random 64 bit integer, e.g., for use as an id.
+pub fn random_i64() -> Result<i64> {
+    let mut data = [0u8; 8];
+    fill(&mut data).context("In random_i64.")?;
+    Ok(i64::from_ne_bytes(data))
+}
+
+/// Generates a new AES-256 key.
+pub fn generate_aes256_key() -> Result<ZVec> {
+    let mut key = ZVec::new(AES_256_KEY_LENGTH).context("In generate_aes256_key.")?;
+    fill(&mut key).context("In generate_aes256_key.")?;
+    Ok(key)
+}
+
+/// Generates a salt for password based key derivation.
+pub fn generate_salt() -> Result<Vec<u8>> {
+    random_data(SALT_LENGTH).context("In generate_salt.")
+}
+
+#[cfg(not(test))]
+fn deterministic_fill(_buf: &mut [u8]) -> bool {
+    false
+}
+
+#[cfg(test)]
+fn deterministic_fill(buf: &mut [u8]) -> bool {
+    test_source::fill(buf)
+}
+
+/// Runs `f` with a deterministic source of random bytes on the current thread. The same seed
+/// yields the same bytes.
+#[cfg(test)]
+pub fn with_deterministic_source<T>(seed: u64, f: impl FnOnce() -> T) -> T {
+    test_source::SOURCE.with(|s| *s.borrow_mut() = Some(seed));
+    let result = f();
+    test_source::SOURCE.with(|s| *s.borrow_mut() = None);
+    result
+}
+
+#[cfg(test)]
+mod test_source {
+    use std::cell::RefCell;
+
+    thread_local! {
+        pub static SOURCE: RefCell<Option<u64>> = RefCell::new(None);
+    }
+
+    /// Fills `buf` from the deterministic source if one is installed on this thread and
+    /// returns true. This uses splitmix64, which is good enough for tests.
+    pub fn fill(buf: &mut [u8]) -> bool {
+        SOURCE.with(|s| {
+            let mut s = s.borrow_mut();
+            let state = match s.as_mut() {
+                Some(state) => state,
+                None => return false,
+            };
+            for chunk in buf.chunks_mut(8) {
+                *state = state.wrapping_add(0x9e3779b97f4a7c15);
+                let mut z = *state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+                z ^= z >> 31;
+                chunk.copy_from_slice(&z.to_ne_bytes()[..chunk.len()]);
+            }
+            true
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_continuous_test_detects_repeated_block() -> Result<()> {
+        let mut test: ContinuousTest = Default::default();
+        test.check(&[1u8; BLOCK_SIZE])?;
+        test.check(&[2u8; BLOCK_SIZE])?;
+        let e = test.check(&[2u8; BLOCK_SIZE]).unwrap_err();
+        assert_eq!(e.root_cause().downcast_ref::<Error>(), Some(&Error::HealthTestFailed));
+        Ok(())
+    }
+
+    #[test]
+    fn test_startup_test() -> Result<()> {
+        startup_test(system_source)?;
+        let stuck = |buf: &mut [u8]| {
+            buf.iter_mut().for_each(|b| *b = 0);
+            Ok(())
+        };
+        assert!(startup_test(stuck).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_deterministic_source() -> Result<()> {
+        let a = with_deterministic_source(42, || random_data(20))?;
+        let b = with_deterministic_source(42, || random_data(20))?;
+        let c = with_deterministic_source(43, || random_data(20))?;
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_ne!(random_data(20)?, a);
+        Ok(())
+    }
+}
@@ -41,14 +41,24 @@
 //! from the database module these functions take permission check
 //! callbacks.
 
+mod cert_store;
+#[cfg(any(test, feature = "db_fixtures"))]
+pub mod fixtures;
+mod grant_cache;
+pub mod io_stats;
 mod perboot;
+pub mod schema;
 pub(crate) mod utils;
 mod versioning;
 
+use crate::access_group;
 use crate::impl_metadata; // This is in db_utils.rs
 use crate::key_parameter::{KeyParameter, Tag};
+use crate::key_policy;
 use crate::metrics_store::log_rkp_error_stats;
 use crate::permission::KeyPermSet;
+use crate::quota;
+use crate::tenants;
 use crate::utils::{get_current_time_in_milliseconds, watchdog as wd, AID_USER_OFFSET};
 use crate::{
     error::{Error as KsError, ErrorCode, ResponseCode},
@@ -56,6 +66,7 @@ use crate::{
 };
 use crate::{gc::Gc, super_key::USER_SUPER_KEY};
 use anyhow::{anyhow, Context, Result};
+use schema::SchemaDump;
 use std::{convert::TryFrom, convert::TryInto, ops::Deref, time::SystemTimeError};
 use utils as db_utils;
 use utils::SqlField;
@@ -67,6 +78,7 @@ use android_hardware_security_keymint::aidl::android::hardware::security::keymin
 use android_system_keystore2::aidl::android::system::keystore2::{
     Domain::Domain, KeyDescriptor::KeyDescriptor,
 };
+use android_security_keyparameters::aidl::android::security::keyparameters::KeystoreKeyFlag::KeystoreKeyFlag;
 use android_security_remoteprovisioning::aidl::android::security::remoteprovisioning::{
     AttestationPoolStatus::AttestationPoolStatus,
 };
@@ -76,11 +88,11 @@ use android_security_metrics::aidl::android::security::metrics::{
     RkpError::RkpError as MetricsRkpError,
 };
 
-use keystore2_crypto::ZVec;
+#[cfg(not(test))]
+use crate::csprng::random_i64 as random;
+use keystore2_crypto::{certificate_fingerprints, ZVec};
 use lazy_static::lazy_static;
 use log::error;
-#[cfg(not(test))]
-use rand::prelude::random;
 use rusqlite::{
     params,
     types::FromSql,
@@ -92,8 +104,9 @@ use rusqlite::{
 
 use std::{
     collections::{HashMap, HashSet},
+    ops::RangeInclusive,
     path::Path,
-    sync::{Arc, Condvar, Mutex},
+    sync::{Arc, Condvar, Mutex, RwLock},
     time::{Duration, SystemTime},
 };
 
@@ -119,6 +132,39 @@ impl_metadata!(
         AttestationRawPubKey(Vec<u8>) with accessor attestation_raw_pub_key,
         /// SEC1 public key for ECDH encryption
         Sec1PublicKey(Vec<u8>) with accessor sec1_public_key,
+        /// If set on a user super key, the user removed their LSKF and the super key, as well as
+        /// all keys bound to it, are deleted at this time unless a new LSKF is set before.
+        LskfRemovalDeadline(DateTime) with accessor lskf_removal_deadline,
+        /// Set by the expired key sweeper to the time at which it found that the usage
+        /// expiration date of the key had passed.
+        Expired(DateTime) with accessor expired,
+        /// If true, the owner asked at creation time for the key to be deleted once the
+        /// expired key sweeper finds it expired.
+        DeleteOnExpiry(bool) with accessor delete_on_expiry,
+        /// If set, the key may only be used by callers whose SELinux context matches this
+        /// pattern. See `permission::check_client_context`.
+        ClientContextPattern(String) with accessor client_context_pattern,
+        /// The key material of the key encrypted to the escrow public key, if the key was
+        /// designated for escrow at creation. See `escrow::EscrowRecord`.
+        EscrowRecord(Vec<u8>) with accessor escrow_record,
+        /// Provenance: how the key came into Keystore.
+        Origin(KeyOrigin) with accessor origin,
+        /// Provenance: the uid of the caller that generated or imported the key.
+        CreatorUid(i64) with accessor creator_uid,
+        /// Provenance: the version number of the KeyMint HAL that created the key.
+        KmVersion(i32) with accessor km_version,
+        /// Provenance: the super encryption format version that protected the key blob at
+        /// creation, if it was super encrypted. See `blob_format::SuperEncryptionFormat`.
+        SuperKeyVersion(i32) with accessor super_key_version,
+        /// Set on an assigned remotely provisioned attestation key at the time the boot state
+        /// of the device was found changed. See `boot_state`.
+        AttestationStale(DateTime) with accessor attestation_stale,
+        /// Date of the last successful use of the key entry to begin an operation. It is
+        /// updated at most once per `security_level::LAST_USE_GRANULARITY`.
+        LastUsed(DateTime) with accessor last_used,
+        /// If true, every operation with the key must be approved by the user beforehand. See
+        /// `enforcements::USER_MEDIATED_FLAG`.
+        UserMediated(bool) with accessor user_mediated,
         //  --- ADD NEW META DATA FIELDS HERE ---
         // For backwards compatibility add new entries only to
         // end of this list and above this comment.
@@ -168,6 +214,25 @@ impl KeyMetaData {
         }
         Ok(())
     }
+
+    /// Deletes the entries of the key entry `key_id` for which `filter` returns true and
+    /// returns their number.
+    fn remove_from_db<F>(key_id: i64, tx: &Transaction, filter: F) -> Result<usize>
+    where
+        F: Fn(&KeyMetaEntry) -> bool,
+    {
+        let metadata = Self::load_from_db(key_id, tx).context("In KeyMetaData::remove_from_db.")?;
+        let mut removed = 0;
+        for (tag, _) in metadata.data.iter().filter(|(_, entry)| filter(entry)) {
+            removed += tx
+                .execute(
+                    "DELETE FROM persistent.keymetadata WHERE keyentryid = ? AND tag = ?;",
+                    params![key_id, tag],
+                )
+                .context("In KeyMetaData::remove_from_db: Failed to delete entry.")?;
+        }
+        Ok(removed)
+    }
 }
 
 impl_metadata!(
@@ -194,6 +259,9 @@ impl_metadata!(
         /// If the key is encrypted with a MaxBootLevel key, this is the boot level
         /// of that key
         MaxBootLevel(i32) with accessor max_boot_level,
+        /// If the blob is super encrypted, this is the version of the super encryption format.
+        /// See `blob_format::SuperEncryptionFormat`.
+        FormatVersion(i32) with accessor format_version,
         //  --- ADD NEW META DATA FIELDS HERE ---
         // For backwards compatibility add new entries only to
         // end of this list and above this comment.
@@ -279,8 +347,209 @@ impl FromSql for KeyType {
     }
 }
 
+/// How a key came into Keystore. Recorded in `KeyMetaEntry::Origin` when the key is stored.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum KeyOrigin {
+    /// The key was generated by KeyMint.
+    Generated,
+    /// The key was imported, possibly wrapped.
+    Imported,
+    /// The key was migrated from the legacy Keystore database.
+    Migrated,
+}
+
+impl ToSql for KeyOrigin {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
+        Ok(ToSqlOutput::Owned(Value::Integer(match self {
+            KeyOrigin::Generated => 0,
+            KeyOrigin::Imported => 1,
+            KeyOrigin::Migrated => 2,
+        })))
+    }
+}
+
+impl FromSql for KeyOrigin {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        match i64::column_result(value)? {
+            0 => Ok(KeyOrigin::Generated),
+            1 => Ok(KeyOrigin::Imported),
+            2 => Ok(KeyOrigin::Migrated),
+            v => Err(FromSqlError::OutOfRange(v)),
+        }
+    }
+}
+
+/// The digests under which a key can be found by `KeystoreDB::find_keys_by_fingerprint`.
+/// Both are SHA-256 digests computed from the key's certificate.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum KeyFingerprintKind {
+    /// The digest of the DER-encoded certificate.
+    Certificate,
+    /// The digest of the DER-encoded SubjectPublicKeyInfo of the certificate.
+    SubjectPublicKeyInfo,
+}
+
+impl ToSql for KeyFingerprintKind {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
+        Ok(ToSqlOutput::Owned(Value::Integer(match self {
+            KeyFingerprintKind::Certificate => 0,
+            KeyFingerprintKind::SubjectPublicKeyInfo => 1,
+        })))
+    }
+}
+
+/// A key found by `KeystoreDB::sweep_expired_keys`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpiredKey {
+    /// The descriptor under which the key was bound when it was found expired.
+    pub key: KeyDescriptor,
+    /// True if the key was deleted, because its owner opted in to deletion on expiry.
+    pub deleted: bool,
+}
+
+/// A grant found by `KeystoreDB::list_all_grants`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrantRecord {
+    /// The grant id, i.e., the namespace of the grant's `Domain::GRANT` descriptor.
+    pub grant_id: i64,
+    /// The uid of the grantee.
+    pub grantee: u32,
+    /// The descriptor under which the granted key is bound.
+    pub key: KeyDescriptor,
+    /// The permissions granted.
+    pub access_vector: KeyPermSet,
+}
+
+/// The kind of change recorded in the key change journal, see `KeystoreDB::list_key_changes`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum KeyChangeKind {
+    /// An alias was bound to a key where there was none before.
+    Created,
+    /// The key bound to an alias was replaced, or its certificates were updated.
+    Updated,
+    /// The key bound to an alias was deleted.
+    Deleted,
+}
+
+impl ToSql for KeyChangeKind {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
+        Ok(ToSqlOutput::Owned(Value::Integer(match self {
+            KeyChangeKind::Created => 0,
+            KeyChangeKind::Updated => 1,
+            KeyChangeKind::Deleted => 2,
+        })))
+    }
+}
+
+impl FromSql for KeyChangeKind {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        match i64::column_result(value)? {
+            0 => Ok(KeyChangeKind::Created),
+            1 => Ok(KeyChangeKind::Updated),
+            2 => Ok(KeyChangeKind::Deleted),
+            v => Err(FromSqlError::OutOfRange(v)),
+        }
+    }
+}
+
+/// An entry of the key change journal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyChangeRecord {
+    /// The sequence number of the change. Sequence numbers increase monotonically across all
+    /// namespaces.
+    pub sequence: i64,
+    /// The alias that the change applies to.
+    pub alias: String,
+    /// What happened to the key bound to `alias`.
+    pub kind: KeyChangeKind,
+}
+
+/// The result of `KeystoreDB::list_key_changes`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyChanges {
+    /// The changes in the queried namespace in the order they happened.
+    pub changes: Vec<KeyChangeRecord>,
+    /// The sequence number to pass to the next query in order to receive subsequent changes.
+    pub next_sequence: i64,
+    /// True if changes following the queried sequence number may have been dropped from the
+    /// journal. The caller must fall back to listing the namespace.
+    pub truncated: bool,
+}
+
+/// How thoroughly `KeystoreDB::check_integrity` checks the structure of the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityCheck {
+    /// `PRAGMA quick_check`, which runs in linear time, but does not verify that the indices
+    /// match their tables. Used on startup, where the check delays the boot.
+    Quick,
+    /// `PRAGMA integrity_check`, which also verifies the indices.
+    Full,
+}
+
+/// The result of `KeystoreDB::check_integrity`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// The problems reported by the structural check. Empty if the database is intact.
+    pub problems: Vec<String>,
+    /// The number of rows that referred to missing key or blob entries and were deleted.
+    pub orphans_removed: usize,
+}
+
+/// The result of `KeystoreDB::rebuild_derived_state`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RebuildReport {
+    /// The number of keys whose certificate fingerprints were indexed again.
+    pub fingerprinted_keys: usize,
+    /// The number of certificates of the certificate store whose reference count was wrong.
+    pub corrected_refcounts: usize,
+    /// The number of certificates that were deleted, because no certificate chain refers to
+    /// them.
+    pub released_certificates: usize,
+}
+
+/// Determines what happens to the grants of a key when its alias is rebound to a new key.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum GrantRebindPolicy {
+    /// The grants of the replaced key are left alone. They refer to the unreferenced key until
+    /// it is collected along with its grants, and grantees fail to load it in the meantime.
+    Keep,
+    /// The grants of the replaced key are deleted. Grantees lose access and must be granted
+    /// access to the new key explicitly.
+    Invalidate,
+    /// The grants of the replaced key are moved to the new key. Existing grant descriptors
+    /// remain valid, keep their access vectors, and refer to the new key.
+    Transfer,
+}
+
+impl GrantRebindPolicy {
+    /// Key flag that selects `GrantRebindPolicy::Transfer`.
+    pub const TRANSFER_GRANTS_ON_REBIND_FLAG: i32 = KeystoreKeyFlag::TRANSFER_GRANTS_ON_REBIND.0;
+
+    /// Key flag that selects `GrantRebindPolicy::Invalidate`.
+    pub const INVALIDATE_GRANTS_ON_REBIND_FLAG: i32 =
+        KeystoreKeyFlag::INVALIDATE_GRANTS_ON_REBIND.0;
+
+    /// Derives the policy from the flags passed at key creation. The grants are kept unless
+    /// the caller explicitly asked for them to be transferred or invalidated. Asking for both
+    /// is an error.
+    pub fn from_key_flags(flags: Option<i32>) -> Result<Self> {
+        let flags = flags.unwrap_or(0);
+        match (
+            flags & Self::TRANSFER_GRANTS_ON_REBIND_FLAG != 0,
+            flags & Self::INVALIDATE_GRANTS_ON_REBIND_FLAG != 0,
+        ) {
+            (false, false) => Ok(Self::Keep),
+            (true, false) => Ok(Self::Transfer),
+            (false, true) => Ok(Self::Invalidate),
+            (true, true) => Err(KsError::Km(ErrorCode::INVALID_ARGUMENT))
+                .context("In from_key_flags: Grants cannot be both transferred and invalidated."),
+        }
+    }
+}
+
 /// Uuid representation that can be stored in the database.
-/// Right now it can only be initialized from SecurityLevel.
+/// It is initialized from SecurityLevel, or from raw bytes for key sources that are not
+/// KeyMint instances, see `external_keys::provider_uuid`.
 /// Once KeyMint provides a UUID type a corresponding From impl shall be added.
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Uuid([u8; 16]);
@@ -299,6 +568,12 @@ impl From<SecurityLevel> for Uuid {
     }
 }
 
+impl From<[u8; 16]> for Uuid {
+    fn from(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+}
+
 impl ToSql for Uuid {
     fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
         self.0.to_sql()
@@ -458,6 +733,9 @@ enum KeyLifeCycle {
     Live,
     /// An unreferenced key is scheduled for garbage collection.
     Unreferenced,
+    /// A quarantined key belongs to a frozen namespace that was cleared. It is kept as evidence
+    /// but is not visible to clients. It becomes unreferenced when the namespace is unfrozen.
+    Quarantined,
 }
 
 impl ToSql for KeyLifeCycle {
@@ -466,6 +744,7 @@ impl ToSql for KeyLifeCycle {
             Self::Existing => Ok(ToSqlOutput::Owned(Value::Integer(0))),
             Self::Live => Ok(ToSqlOutput::Owned(Value::Integer(1))),
             Self::Unreferenced => Ok(ToSqlOutput::Owned(Value::Integer(2))),
+            Self::Quarantined => Ok(ToSqlOutput::Owned(Value::Integer(3))),
         }
     }
 }
@@ -476,6 +755,7 @@ impl FromSql for KeyLifeCycle {
             0 => Ok(KeyLifeCycle::Existing),
             1 => Ok(KeyLifeCycle::Live),
             2 => Ok(KeyLifeCycle::Unreferenced),
+            3 => Ok(KeyLifeCycle::Quarantined),
             v => Err(FromSqlError::OutOfRange(v)),
         }
     }
@@ -511,6 +791,8 @@ impl KeyEntryLoadBits {
 
 lazy_static! {
     static ref KEY_ID_LOCK: KeyIdLockDb = KeyIdLockDb::new();
+    /// Every transaction holds this lock for reading, see `KeystoreDB::block_transactions`.
+    static ref TRANSACTION_GATE: RwLock<()> = RwLock::new(());
 }
 
 struct KeyIdLockDb {
@@ -595,6 +877,23 @@ impl CertificateInfo {
     }
 }
 
+/// A client key that is stored together with other keys by `KeystoreDB::store_new_keys`. The
+/// fields correspond to the arguments of `KeystoreDB::store_new_key`.
+pub struct NewKey<'a> {
+    /// The descriptor with the alias, domain and namespace of the key.
+    pub key: &'a KeyDescriptor,
+    /// The key parameters of the key.
+    pub params: &'a [KeyParameter],
+    /// The key blob and its metadata.
+    pub blob_info: (&'a [u8], &'a BlobMetaData),
+    /// The certificate and certificate chain of the key.
+    pub cert_info: &'a CertificateInfo,
+    /// The metadata of the key.
+    pub metadata: &'a KeyMetaData,
+    /// What happens to the grants of a key that was bound to the alias before.
+    pub grant_policy: GrantRebindPolicy,
+}
+
 /// This type represents a certificate chain with a private key corresponding to the leaf
 /// certificate. TODO(jbires): This will be used in a follow-on CL, for now it's used in the tests.
 pub struct CertificateChain {
@@ -737,6 +1036,17 @@ pub struct KeystoreDB {
     conn: Connection,
     gc: Option<Arc<Gc>>,
     perboot: Arc<perboot::PerbootDB>,
+    grant_cache: Arc<grant_cache::GrantCache>,
+    gc_cursor: GcScanCursor,
+}
+
+/// Tracks where the garbage collector's scans for superseded blobs continue. Each scan resumes
+/// after the last blob it found, so that cleaning up a large deletion in batches does not
+/// rescan the same rows over and over. A scan starts over once it reaches the end of the table.
+#[derive(Debug, Default, Clone, Copy)]
+struct GcScanCursor {
+    key_blobs: i64,
+    other_blobs: i64,
 }
 
 /// Database representation of the monotonic time retrieved from the system call clock_gettime with
@@ -826,13 +1136,31 @@ impl AuthTokenEntry {
 /// connection alive in order to keep the in memory per boot database alive.
 pub struct PerBootDbKeepAlive(Connection);
 
+/// Like `PerBootDbKeepAlive`, but keeps the shared in-memory persistent database of the
+/// embedded mode alive, see `KeystoreDB::new_in_memory`.
+#[cfg(feature = "embedded")]
+pub struct InMemoryDbKeepAlive(Connection);
+
 impl KeystoreDB {
     const UNASSIGNED_KEY_ID: i64 = -1i64;
-    const CURRENT_DB_VERSION: u32 = 1;
-    const UPGRADERS: &'static [fn(&Transaction) -> Result<u32>] = &[Self::from_0_to_1];
+    /// The number of entries retained in the key change journal. Older entries are dropped.
+    const MAX_KEY_JOURNAL_ENTRIES: i64 = 10000;
+    const CURRENT_DB_VERSION: u32 = 3;
+    const UPGRADERS: &'static [fn(&Transaction) -> Result<u32>] =
+        &[Self::from_0_to_1, Self::from_1_to_2, Self::from_2_to_3];
 
     /// Name of the file that holds the cross-boot persistent database.
     pub const PERSISTENT_DB_FILENAME: &'static str = &"persistent.sqlite";
+    /// The first delay before a statement that failed because the database was busy is retried.
+    const BUSY_RETRY_INITIAL_DELAY: Duration = Duration::from_micros(500);
+    /// The delay between retries doubles up to this limit.
+    const BUSY_RETRY_MAX_DELAY: Duration = Duration::from_millis(32);
+    /// The garbage collector cleans up at most this many unreferenced key entries or
+    /// superseded blobs per transaction, so that it does not hold the database lock for long
+    /// after a large deletion, e.g., of a namespace or user.
+    const GC_CLEANUP_BATCH: usize = 100;
+    /// The maximal number of problems reported by `check_integrity`.
+    const MAX_INTEGRITY_PROBLEMS: u32 = 10;
 
     /// This will create a new database connection connecting the two
     /// files persistent.sqlite and perboot.sqlite in the given directory.
@@ -843,14 +1171,53 @@ impl KeystoreDB {
         let _wp = wd::watch_millis("KeystoreDB::new", 500);
 
         let persistent_path = Self::make_persistent_path(&db_root)?;
-        let conn = Self::make_connection(&persistent_path)?;
+        Self::open(&persistent_path, gc)
+    }
+
+    /// Like `new`, but the persistent database is a shared in-memory database with the given
+    /// name instead of a file. All connections to the same name see the same database, which
+    /// is destroyed when its last connection is closed, see `keep_in_memory_db_alive`.
+    /// Used by the embedded mode, see `embedded`.
+    #[cfg(feature = "embedded")]
+    pub fn new_in_memory(name: &str, gc: Option<Arc<Gc>>) -> Result<Self> {
+        let _wp = wd::watch_millis("KeystoreDB::new_in_memory", 500);
+
+        Self::open(&Self::make_in_memory_path(name), gc)
+    }
+
+    /// Opens a connection to the shared in-memory database with the given name, see
+    /// `new_in_memory`, and returns it wrapped so that it keeps the database alive.
+    #[cfg(feature = "embedded")]
+    pub fn keep_in_memory_db_alive(name: &str) -> Result<InMemoryDbKeepAlive> {
+        Self::make_connection(&Self::make_in_memory_path(name))
+            .map(InMemoryDbKeepAlive)
+            .context("In keep_in_memory_db_alive.")
+    }
+
+    #[cfg(feature = "embedded")]
+    fn make_in_memory_path(name: &str) -> String {
+        format!("file:{}?mode=memory&cache=shared", name)
+    }
+
+    fn open(persistent_path: &str, gc: Option<Arc<Gc>>) -> Result<Self> {
+        let conn = Self::make_connection(persistent_path)?;
 
-        let mut db = Self { conn, gc, perboot: perboot::PERBOOT_DB.clone() };
+        let grant_cache = grant_cache::for_path(Path::new(persistent_path));
+        let mut db = Self {
+            conn,
+            gc,
+            perboot: perboot::PERBOOT_DB.clone(),
+            grant_cache,
+            gc_cursor: Default::default(),
+        };
         db.with_transaction(TransactionBehavior::Immediate, |tx| {
             versioning::upgrade_database(tx, Self::CURRENT_DB_VERSION, Self::UPGRADERS)
                 .context("In KeystoreDB::new: trying to upgrade database.")?;
             Self::init_tables(tx).context("Trying to initialize tables.").no_gc()
         })?;
+        for drift in db.dump_schema().context("In KeystoreDB::new.")?.drift() {
+            log::warn!("In KeystoreDB::new: Schema drift: {}", drift);
+        }
         Ok(db)
     }
 
@@ -874,114 +1241,81 @@ impl KeystoreDB {
         Ok(1)
     }
 
-    fn init_tables(tx: &Transaction) -> Result<()> {
-        tx.execute(
-            "CREATE TABLE IF NOT EXISTS persistent.keyentry (
-                     id INTEGER UNIQUE,
-                     key_type INTEGER,
-                     domain INTEGER,
-                     namespace INTEGER,
-                     alias BLOB,
-                     state INTEGER,
-                     km_uuid BLOB);",
-            NO_PARAMS,
-        )
-        .context("Failed to initialize \"keyentry\" table.")?;
-
-        tx.execute(
-            "CREATE INDEX IF NOT EXISTS persistent.keyentry_id_index
-            ON keyentry(id);",
-            NO_PARAMS,
-        )
-        .context("Failed to create index keyentry_id_index.")?;
-
-        tx.execute(
-            "CREATE INDEX IF NOT EXISTS persistent.keyentry_domain_namespace_index
-            ON keyentry(domain, namespace, alias);",
-            NO_PARAMS,
-        )
-        .context("Failed to create index keyentry_domain_namespace_index.")?;
-
-        tx.execute(
-            "CREATE TABLE IF NOT EXISTS persistent.blobentry (
-                    id INTEGER PRIMARY KEY,
-                    subcomponent_type INTEGER,
-                    keyentryid INTEGER,
-                    blob BLOB);",
-            NO_PARAMS,
-        )
-        .context("Failed to initialize \"blobentry\" table.")?;
-
-        tx.execute(
-            "CREATE INDEX IF NOT EXISTS persistent.blobentry_keyentryid_index
-            ON blobentry(keyentryid);",
-            NO_PARAMS,
-        )
-        .context("Failed to create index blobentry_keyentryid_index.")?;
-
-        tx.execute(
-            "CREATE TABLE IF NOT EXISTS persistent.blobmetadata (
-                     id INTEGER PRIMARY KEY,
-                     blobentryid INTEGER,
-                     tag INTEGER,
-                     data ANY,
-                     UNIQUE (blobentryid, tag));",
-            NO_PARAMS,
-        )
-        .context("Failed to initialize \"blobmetadata\" table.")?;
-
-        tx.execute(
-            "CREATE INDEX IF NOT EXISTS persistent.blobmetadata_blobentryid_index
-            ON blobmetadata(blobentryid);",
-            NO_PARAMS,
-        )
-        .context("Failed to create index blobmetadata_blobentryid_index.")?;
+    /// Indexes the certificates of all existing keys by fingerprint.
+    fn from_1_to_2(tx: &Transaction) -> Result<u32> {
+        schema::create_schema(tx).context("In from_1_to_2: Failed to create tables.")?;
+        Self::index_all_fingerprints(tx).context("In from_1_to_2.")?;
+        Ok(2)
+    }
 
-        tx.execute(
-            "CREATE TABLE IF NOT EXISTS persistent.keyparameter (
-                     keyentryid INTEGER,
-                     tag INTEGER,
-                     data ANY,
-                     security_level INTEGER);",
-            NO_PARAMS,
-        )
-        .context("Failed to initialize \"keyparameter\" table.")?;
+    /// Replaces the fingerprint index with the fingerprints of the current certificates of all
+    /// keys. Returns the number of keys with a certificate.
+    fn index_all_fingerprints(tx: &Transaction) -> Result<usize> {
+        tx.execute("DELETE FROM persistent.keyfingerprint;", NO_PARAMS)
+            .context("In index_all_fingerprints: Failed to delete fingerprints.")?;
+        let mut stmt = tx
+            .prepare(
+                "SELECT keyentryid, blob FROM persistent.blobentry
+                 WHERE id IN (
+                     SELECT MAX(id) FROM persistent.blobentry
+                     WHERE subcomponent_type = ?
+                     GROUP BY keyentryid
+                 );",
+            )
+            .context("In index_all_fingerprints: Failed to prepare statement.")?;
+        let certs = stmt
+            .query_map(params![SubComponentType::CERT], |row| Ok((row.get(0)?, row.get(1)?)))
+            .context("In index_all_fingerprints: Failed to query certificates.")?
+            .collect::<rusqlite::Result<Vec<(i64, Vec<u8>)>>>()
+            .context("In index_all_fingerprints: Failed to read certificates.")?;
+        for (key_id, cert) in &certs {
+            Self::store_fingerprints(tx, *key_id, Some(cert))
+                .context("In index_all_fingerprints.")?;
+        }
+        Ok(certs.len())
+    }
 
-        tx.execute(
-            "CREATE INDEX IF NOT EXISTS persistent.keyparameter_keyentryid_index
-            ON keyparameter(keyentryid);",
-            NO_PARAMS,
-        )
-        .context("Failed to create index keyparameter_keyentryid_index.")?;
+    /// Moves the certificates of all existing certificate chains into the deduplicated
+    /// certificate store.
+    fn from_2_to_3(tx: &Transaction) -> Result<u32> {
+        schema::create_schema(tx).context("In from_2_to_3: Failed to create tables.")?;
+        let mut stmt = tx
+            .prepare("SELECT id, blob FROM persistent.blobentry WHERE subcomponent_type = ?;")
+            .context("In from_2_to_3: Failed to prepare statement.")?;
+        let chains = stmt
+            .query_map(params![SubComponentType::CERT_CHAIN], |row| Ok((row.get(0)?, row.get(1)?)))
+            .context("In from_2_to_3: Failed to query certificate chains.")?
+            .collect::<rusqlite::Result<Vec<(i64, Vec<u8>)>>>()
+            .context("In from_2_to_3: Failed to read certificate chains.")?;
+        // The chain blobs are kept, so that a rollback to version 2 still finds them.
+        for (blob_id, chain) in chains {
+            cert_store::store_chain(tx, blob_id, &chain).context("In from_2_to_3.")?;
+        }
+        Ok(3)
+    }
 
-        tx.execute(
-            "CREATE TABLE IF NOT EXISTS persistent.keymetadata (
-                     keyentryid INTEGER,
-                     tag INTEGER,
-                     data ANY,
-                     UNIQUE (keyentryid, tag));",
-            NO_PARAMS,
-        )
-        .context("Failed to initialize \"keymetadata\" table.")?;
+    fn init_tables(tx: &Transaction) -> Result<()> {
+        schema::create_schema(tx)
+    }
 
-        tx.execute(
-            "CREATE INDEX IF NOT EXISTS persistent.keymetadata_keyentryid_index
-            ON keymetadata(keyentryid);",
-            NO_PARAMS,
-        )
-        .context("Failed to create index keymetadata_keyentryid_index.")?;
+    /// Reads from the key entry table of the persistent database to check that the database
+    /// can be used. Fails if the database file became unreadable or the table is missing.
+    pub fn probe(&mut self) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::probe", 500);
 
-        tx.execute(
-            "CREATE TABLE IF NOT EXISTS persistent.grant (
-                    id INTEGER UNIQUE,
-                    grantee INTEGER,
-                    keyentryid INTEGER,
-                    access_vector INTEGER);",
-            NO_PARAMS,
-        )
-        .context("Failed to initialize \"grant\" table.")?;
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            tx.query_row("SELECT COUNT(*) FROM persistent.keyentry LIMIT 1;", NO_PARAMS, |_| Ok(()))
+                .context("Failed to read the key entry table.")
+                .no_gc()
+        })
+        .context("In probe.")
+    }
 
-        Ok(())
+    /// Returns a description of the schema of the persistent database as found on disk.
+    /// Use `SchemaDump::drift` to compare it with the schema expected by this code.
+    pub fn dump_schema(&mut self) -> Result<SchemaDump> {
+        self.with_transaction(TransactionBehavior::Deferred, |tx| SchemaDump::load(tx).no_gc())
+            .context("In dump_schema.")
     }
 
     fn make_persistent_path(db_root: &Path) -> Result<String> {
@@ -996,24 +1330,159 @@ impl KeystoreDB {
         Ok(persistent_path_str)
     }
 
+    /// Moves the persistent database in `db_root` aside, so that the next connection creates a
+    /// fresh one. The database file and its write-ahead log are renamed with the suffix
+    /// `.corrupt`, replacing the snapshot of an earlier recovery. No connection to the
+    /// database may be open.
+    pub fn quarantine_persistent_db(db_root: &Path) -> Result<()> {
+        let path = db_root.join(Self::PERSISTENT_DB_FILENAME);
+        for suffix in &["", "-wal", "-shm"] {
+            let mut from = path.clone().into_os_string();
+            from.push(suffix);
+            let mut to = from.clone();
+            to.push(".corrupt");
+            match std::fs::rename(&from, &to) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).context(format!(
+                        "In quarantine_persistent_db: Failed to move {:?}.",
+                        from
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if `e` was caused by SQLite finding the database file corrupt or not a
+    /// database at all.
+    pub fn is_corruption_error(e: &anyhow::Error) -> bool {
+        matches!(
+            e.root_cause().downcast_ref::<rusqlite::ffi::Error>(),
+            Some(rusqlite::ffi::Error { code: rusqlite::ErrorCode::DatabaseCorrupt, .. })
+                | Some(rusqlite::ffi::Error { code: rusqlite::ErrorCode::NotADatabase, .. })
+        )
+    }
+
+    /// Checks the structural integrity of the persistent database as selected by `check`.
+    /// If the database is intact, rows that refer to missing key entries or blob entries are
+    /// deleted. Orphaned blob entries are left to the garbage collector.
+    pub fn check_integrity(&mut self, check: IntegrityCheck) -> Result<IntegrityReport> {
+        let _wp = wd::watch_millis("KeystoreDB::check_integrity", 5000);
+
+        let pragma = match check {
+            IntegrityCheck::Quick => "quick_check",
+            IntegrityCheck::Full => "integrity_check",
+        };
+        let problems = self
+            .conn
+            .prepare(&format!("PRAGMA persistent.{}({});", pragma, Self::MAX_INTEGRITY_PROBLEMS))
+            .context("In check_integrity: Failed to prepare integrity check.")?
+            .query_map(NO_PARAMS, |row| row.get::<_, String>(0))
+            .context("In check_integrity: Failed to run integrity check.")?
+            .filter(|problem| !matches!(problem.as_deref(), Ok("ok")))
+            .collect::<rusqlite::Result<Vec<String>>>()
+            .context("In check_integrity: Failed to read integrity check result.")?;
+        if !problems.is_empty() {
+            return Ok(IntegrityReport { problems, orphans_removed: 0 });
+        }
+
+        let orphans_removed = self
+            .with_transaction(TransactionBehavior::Immediate, |tx| {
+                let mut removed = 0;
+                for table in &["keyparameter", "keymetadata", "keyfingerprint", "grant"] {
+                    removed += tx
+                        .execute(
+                            &format!(
+                                "DELETE FROM persistent.{} WHERE keyentryid NOT IN
+                                     (SELECT id FROM persistent.keyentry);",
+                                table
+                            ),
+                            NO_PARAMS,
+                        )
+                        .with_context(|| format!("Trying to delete orphans from {}.", table))?;
+                }
+                grant_cache::note_grant_write();
+                removed += tx
+                    .execute(
+                        "DELETE FROM persistent.blobmetadata WHERE blobentryid NOT IN
+                             (SELECT id FROM persistent.blobentry);",
+                        NO_PARAMS,
+                    )
+                    .context("Trying to delete orphans from blobmetadata.")?;
+                Ok(removed).no_gc()
+            })
+            .context("In check_integrity.")?;
+        Ok(IntegrityReport { problems, orphans_removed })
+    }
+
+    /// Rebuilds the state that is derived from the key entries and their blobs, so that a
+    /// partially corrupted database can be recovered without wiping Keystore: missing tables
+    /// and indices are created, the fingerprint index is rebuilt from the certificates, the
+    /// reference counts of the certificate store are recomputed from the certificate chains,
+    /// and cached grants are dropped. Finally, all indices are rebuilt and the query planner
+    /// statistics are refreshed. Run `check_integrity` first, so that rows referring to
+    /// missing key entries are gone.
+    pub fn rebuild_derived_state(&mut self) -> Result<RebuildReport> {
+        let _wp = wd::watch_millis("KeystoreDB::rebuild_derived_state", 5000);
+
+        let report = self
+            .with_transaction(TransactionBehavior::Immediate, |tx| {
+                schema::create_schema(tx).context("Trying to create missing tables.")?;
+                let fingerprinted_keys = Self::index_all_fingerprints(tx)?;
+                let (corrected_refcounts, released_certificates) =
+                    cert_store::recount_references(tx)?;
+                grant_cache::note_grant_write();
+                Ok(RebuildReport { fingerprinted_keys, corrected_refcounts, released_certificates })
+                    .no_gc()
+            })
+            .context("In rebuild_derived_state.")?;
+        Self::retry_on_busy(|| {
+            self.conn
+                .execute_batch("REINDEX; ANALYZE persistent;")
+                .context("Failed to rebuild indices.")
+        })
+        .context("In rebuild_derived_state.")?;
+        Ok(report)
+    }
+
+    /// Rebuilds all indices and recreates the certificate fingerprint index, which is derived
+    /// from the certificates of the keys. This repairs the problems that `check_integrity`
+    /// reports for them without touching the key entries. The fingerprint index stays empty
+    /// until `rebuild_derived_state` runs.
+    pub fn rebuild_indices(&mut self) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::rebuild_indices", 5000);
+
+        Self::retry_on_busy(|| {
+            self.conn.execute_batch("REINDEX;").context("Failed to rebuild indices.")
+        })
+        .context("In rebuild_indices.")?;
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            tx.execute("DROP TABLE IF EXISTS persistent.keyfingerprint;", NO_PARAMS)
+                .context("Trying to drop the fingerprint index.")?;
+            schema::create_schema(tx).context("Trying to recreate the fingerprint index.")?;
+            Ok(()).no_gc()
+        })
+        .context("In rebuild_indices.")
+    }
+
     fn make_connection(persistent_file: &str) -> Result<Connection> {
         let conn =
             Connection::open_in_memory().context("Failed to initialize SQLite connection.")?;
 
-        loop {
-            if let Err(e) = conn
-                .execute("ATTACH DATABASE ? as persistent;", params![persistent_file])
+        Self::retry_on_busy(|| {
+            conn.execute("ATTACH DATABASE ? as persistent;", params![persistent_file])
                 .context("Failed to attach database persistent.")
-            {
-                if Self::is_locked_error(&e) {
-                    std::thread::sleep(std::time::Duration::from_micros(500));
-                    continue;
-                } else {
-                    return Err(e);
-                }
-            }
-            break;
-        }
+        })?;
+
+        // With write-ahead logging, readers do not block the writer and the writer does not
+        // block readers, so concurrent binder threads rarely find the database busy. The mode
+        // is stored in the database file. In-memory databases keep their own journal mode.
+        Self::retry_on_busy(|| {
+            conn.query_row("PRAGMA persistent.journal_mode = WAL;", NO_PARAMS, |_| Ok(()))
+                .context("Failed to enable write-ahead logging for persistent db.")
+        })?;
 
         // Drop the cache size from default (2M) to 0.5M
         conn.execute("PRAGMA persistent.cache_size = -500;", params![])
@@ -1063,6 +1532,27 @@ impl KeystoreDB {
         )
     }
 
+    /// Makes sure that the persistent database file holds all committed transactions, e.g.,
+    /// before Keystore shuts down. Committed transactions are durable already. But if the
+    /// persistent database uses write-ahead logging, the log is checkpointed into the database
+    /// file and truncated, so that the next instance does not have to replay it.
+    pub fn flush(&mut self) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::flush", 500);
+        self.conn
+            .query_row("PRAGMA persistent.wal_checkpoint(TRUNCATE);", NO_PARAMS, |_| Ok(()))
+            .context("In flush: Failed to checkpoint the persistent database.")
+    }
+
+    /// Waits for the transactions in progress on all connections and blocks all later
+    /// transactions for the rest of the lifetime of the process. This is used right before
+    /// Keystore exits, so that it does not exit while a transaction is in progress, and
+    /// requests that reach the database afterwards wait until the process exits instead of
+    /// changing the database after the final flush.
+    pub fn block_transactions() {
+        let _wp = wd::watch_millis("KeystoreDB::block_transactions", 500);
+        std::mem::forget(TRANSACTION_GATE.write().unwrap());
+    }
+
     /// Fetches a storage statisitics atom for a given storage type. For storage
     /// types that map to a table, information about the table's storage is
     /// returned. Requests for storage types that are not DB tables return None.
@@ -1121,92 +1611,128 @@ impl KeystoreDB {
     }
 
     /// This function is intended to be used by the garbage collector.
-    /// It deletes the blobs given by `blob_ids_to_delete`. It then tries to find up to `max_blobs`
-    /// superseded key blobs that might need special handling by the garbage collector.
-    /// If no further superseded blobs can be found it deletes all other superseded blobs that don't
-    /// need special handling and returns None.
+    /// It deletes the blobs given by `blob_ids_to_delete` and a batch of unreferenced key
+    /// entries. It then tries to find up to `max_blobs` superseded key blobs that might need
+    /// special handling by the garbage collector.
+    /// If no further superseded blobs can be found it deletes a batch of other superseded blobs
+    /// that don't need special handling and returns an empty vector.
+    /// The returned flag is true if a batch limit was hit, i.e., if there is more to clean up
+    /// even if no key blobs were returned.
     pub fn handle_next_superseded_blobs(
         &mut self,
         blob_ids_to_delete: &[i64],
         max_blobs: usize,
-    ) -> Result<Vec<(i64, Vec<u8>, BlobMetaData)>> {
+    ) -> Result<(Vec<(i64, Vec<u8>, BlobMetaData)>, bool)> {
         let _wp = wd::watch_millis("KeystoreDB::handle_next_superseded_blob", 500);
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            // Delete the given blobs.
-            for blob_id in blob_ids_to_delete {
-                tx.execute(
-                    "DELETE FROM persistent.blobmetadata WHERE blobentryid = ?;",
-                    params![blob_id],
-                )
-                .context("Trying to delete blob metadata.")?;
-                tx.execute("DELETE FROM persistent.blobentry WHERE id = ?;", params![blob_id])
-                    .context("Trying to blob.")?;
-            }
+        let cursor = self.gc_cursor;
+        let (result, more_pending, cursor) = self
+            .with_transaction(TransactionBehavior::Immediate, |tx| {
+                // Delete the given blobs.
+                for blob_id in blob_ids_to_delete {
+                    tx.execute(
+                        "DELETE FROM persistent.blobmetadata WHERE blobentryid = ?;",
+                        params![blob_id],
+                    )
+                    .context("Trying to delete blob metadata.")?;
+                    tx.execute("DELETE FROM persistent.blobentry WHERE id = ?;", params![blob_id])
+                        .context("Trying to blob.")?;
+                }
 
-            Self::cleanup_unreferenced(tx).context("Trying to cleanup unreferenced.")?;
+                let cleaned = Self::cleanup_unreferenced(tx, Self::GC_CLEANUP_BATCH)
+                    .context("Trying to cleanup unreferenced.")?;
+                let more_pending = cleaned == Self::GC_CLEANUP_BATCH;
+                let mut cursor = cursor;
 
-            // Find up to max_blobx more superseded key blobs, load their metadata and return it.
-            let result: Vec<(i64, Vec<u8>)> = {
-                let mut stmt = tx
-                    .prepare(
-                        "SELECT id, blob FROM persistent.blobentry
-                        WHERE subcomponent_type = ?
-                        AND (
-                            id NOT IN (
-                                SELECT MAX(id) FROM persistent.blobentry
-                                WHERE subcomponent_type = ?
-                                GROUP BY keyentryid, subcomponent_type
-                            )
-                        OR keyentryid NOT IN (SELECT id FROM persistent.keyentry)
-                    ) LIMIT ?;",
-                    )
-                    .context("Trying to prepare query for superseded blobs.")?;
+                // Find up to max_blobx more superseded key blobs, load their metadata and
+                // return it.
+                let result = Self::find_superseded_blobs(
+                    tx,
+                    true,
+                    &mut cursor.key_blobs,
+                    max_blobs,
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .context("Trying to query superseded blobs.")?;
 
-                let rows = stmt
-                    .query_map(
-                        params![
-                            SubComponentType::KEY_BLOB,
-                            SubComponentType::KEY_BLOB,
-                            max_blobs as i64,
-                        ],
-                        |row| Ok((row.get(0)?, row.get(1)?)),
-                    )
-                    .context("Trying to query superseded blob.")?;
+                let result = result
+                    .into_iter()
+                    .map(|(blob_id, blob)| {
+                        Ok((blob_id, blob, BlobMetaData::load_from_db(blob_id, tx)?))
+                    })
+                    .collect::<Result<Vec<(i64, Vec<u8>, BlobMetaData)>>>()
+                    .context("Trying to load blob metadata.")?;
+                if !result.is_empty() {
+                    return Ok((result, more_pending, cursor)).no_gc();
+                }
 
-                rows.collect::<Result<Vec<(i64, Vec<u8>)>, rusqlite::Error>>()
-                    .context("Trying to extract superseded blobs.")?
-            };
+                // We did not find any superseded key blob, so let's remove a batch of other
+                // superseded blobs.
+                let superseded = Self::find_superseded_blobs(
+                    tx,
+                    false,
+                    &mut cursor.other_blobs,
+                    Self::GC_CLEANUP_BATCH,
+                    |row| Ok((row.get(0)?, ())),
+                )
+                .context("Trying to query other superseded blobs.")?;
+                for (blob_id, _) in &superseded {
+                    cert_store::release_chain(tx, *blob_id)
+                        .context("Trying to release certificates.")?;
+                    tx.execute("DELETE FROM persistent.blobentry WHERE id = ?;", params![blob_id])
+                        .context("Trying to purge superseded blob.")?;
+                }
+                let purged = superseded.len();
 
-            let result = result
-                .into_iter()
-                .map(|(blob_id, blob)| {
-                    Ok((blob_id, blob, BlobMetaData::load_from_db(blob_id, tx)?))
-                })
-                .collect::<Result<Vec<(i64, Vec<u8>, BlobMetaData)>>>()
-                .context("Trying to load blob metadata.")?;
-            if !result.is_empty() {
-                return Ok(result).no_gc();
-            }
+                Ok((vec![], more_pending || purged == Self::GC_CLEANUP_BATCH, cursor)).no_gc()
+            })
+            .context("In handle_next_superseded_blobs.")?;
+        self.gc_cursor = cursor;
+        Ok((result, more_pending))
+    }
 
-            // We did not find any superseded key blob, so let's remove other superseded blob in
-            // one transaction.
-            tx.execute(
-                "DELETE FROM persistent.blobentry
-                 WHERE NOT subcomponent_type = ?
+    /// Finds up to `limit` superseded blobs after `cursor`, either key blobs or other blobs as
+    /// selected by `key_blobs`. A blob is superseded if its key entry has a newer blob of the
+    /// same type or if its key entry no longer exists. Both conditions are looked up through
+    /// indices, so the cost of a scan is proportional to the rows it passes. The cursor is
+    /// advanced past the last blob found, or reset if the scan reached the end of the table.
+    fn find_superseded_blobs<T, F>(
+        tx: &Transaction,
+        key_blobs: bool,
+        cursor: &mut i64,
+        limit: usize,
+        row_extractor: F,
+    ) -> Result<Vec<(i64, T)>>
+    where
+        F: FnMut(&rusqlite::Row) -> rusqlite::Result<(i64, T)>,
+    {
+        let blobs = tx
+            .prepare(&format!(
+                "SELECT id, {} FROM persistent.blobentry AS b
+                 WHERE {} subcomponent_type = ? AND id > ?
                  AND (
-                     id NOT IN (
-                        SELECT MAX(id) FROM persistent.blobentry
-                        WHERE NOT subcomponent_type = ?
-                        GROUP BY keyentryid, subcomponent_type
-                     ) OR keyentryid NOT IN (SELECT id FROM persistent.keyentry)
-                 );",
-                params![SubComponentType::KEY_BLOB, SubComponentType::KEY_BLOB],
-            )
-            .context("Trying to purge superseded blobs.")?;
-
-            Ok(vec![]).no_gc()
-        })
-        .context("In handle_next_superseded_blobs.")
+                     EXISTS (
+                         SELECT 1 FROM persistent.blobentry AS newer
+                         WHERE newer.keyentryid = b.keyentryid
+                         AND newer.subcomponent_type = b.subcomponent_type
+                         AND newer.id > b.id
+                     )
+                     OR NOT EXISTS (SELECT 1 FROM persistent.keyentry WHERE id = b.keyentryid)
+                 )
+                 ORDER BY id LIMIT ?;",
+                if key_blobs { "blob" } else { "NULL" },
+                if key_blobs { "" } else { "NOT" }
+            ))
+            .context("In find_superseded_blobs: Failed to prepare statement.")?
+            .query_map(params![SubComponentType::KEY_BLOB, *cursor, limit as i64], row_extractor)
+            .context("In find_superseded_blobs: Failed to query blobs.")?
+            .collect::<rusqlite::Result<Vec<(i64, T)>>>()
+            .context("In find_superseded_blobs: Failed to read blobs.")?;
+        *cursor = match blobs.last() {
+            Some((blob_id, _)) if blobs.len() == limit => *blob_id,
+            None if limit == 0 => *cursor,
+            _ => 0,
+        };
+        Ok(blobs)
     }
 
     /// This maintenance function should be called only once before the database is used for the
@@ -1337,30 +1863,110 @@ impl KeystoreDB {
         .context("In load_super_key.")
     }
 
-    /// Atomically loads a key entry and associated metadata or creates it using the
-    /// callback create_new_key callback. The callback is called during a database
-    /// transaction. This means that implementers should be mindful about using
-    /// blocking operations such as IPC or grabbing mutexes.
-    pub fn get_or_create_key_with<F>(
+    /// Sets or, if `deadline` is None, clears the LSKF removal deadline of the given user's
+    /// super key. Returns false if the user has no super key.
+    pub fn set_lskf_removal_deadline(
         &mut self,
-        domain: Domain,
-        namespace: i64,
-        alias: &str,
-        km_uuid: Uuid,
-        create_new_key: F,
-    ) -> Result<(KeyIdGuard, KeyEntry)>
-    where
-        F: Fn() -> Result<(Vec<u8>, BlobMetaData)>,
-    {
-        let _wp = wd::watch_millis("KeystoreDB::get_or_create_key_with", 500);
+        user_id: u32,
+        deadline: Option<DateTime>,
+    ) -> Result<bool> {
+        let _wp = wd::watch_millis("KeystoreDB::set_lskf_removal_deadline", 500);
 
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            let id = {
-                let mut stmt = tx
-                    .prepare(
-                        "SELECT id FROM persistent.keyentry
-                    WHERE
-                    key_type = ?
+            let key_descriptor = KeyDescriptor {
+                domain: Domain::APP,
+                nspace: user_id as i64,
+                alias: Some(USER_SUPER_KEY.alias.into()),
+                blob: None,
+            };
+            let key_id = match Self::load_key_entry_id(&tx, &key_descriptor, KeyType::Super) {
+                Ok(key_id) => key_id,
+                Err(e) => match e.root_cause().downcast_ref::<KsError>() {
+                    Some(KsError::Rc(ResponseCode::KEY_NOT_FOUND)) => return Ok(false).no_gc(),
+                    _ => return Err(e),
+                },
+            };
+            match deadline {
+                Some(deadline) => {
+                    let mut metadata = KeyMetaData::new();
+                    metadata.add(KeyMetaEntry::LskfRemovalDeadline(deadline));
+                    metadata.store_in_db(key_id, tx)
+                }
+                None => KeyMetaData::remove_from_db(key_id, tx, |entry| {
+                    matches!(entry, KeyMetaEntry::LskfRemovalDeadline(_))
+                })
+                .map(|_| ())
+                .context("Failed to delete LSKF removal deadline."),
+            }
+            .map(|_| true)
+            .no_gc()
+        })
+        .context("In set_lskf_removal_deadline.")
+    }
+
+    /// Returns the users whose super key has an LSKF removal deadline, see
+    /// `set_lskf_removal_deadline`.
+    pub fn list_users_with_lskf_removal_deadline(&mut self) -> Result<Vec<u32>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_users_with_lskf_removal_deadline", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT namespace FROM persistent.keyentry
+                     WHERE key_type = ?
+                     AND domain = ?
+                     AND alias = ?
+                     AND state = ?
+                     AND EXISTS (
+                        SELECT 1 FROM persistent.keymetadata
+                        WHERE keyentryid = keyentry.id AND tag = ?);",
+                )
+                .context("Failed to prepare.")?;
+            let mut rows = stmt
+                .query(params![
+                    KeyType::Super,
+                    Domain::APP.0 as u32,
+                    USER_SUPER_KEY.alias,
+                    KeyLifeCycle::Live,
+                    KeyMetaData::LskfRemovalDeadline
+                ])
+                .context("Failed to query.")?;
+            let mut user_ids = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                let user_id: i64 = row.get(0).context("Trying to extract user id.")?;
+                user_ids.push(user_id as u32);
+                Ok(())
+            })
+            .context("Failed to extract rows.")?;
+            Ok(user_ids).no_gc()
+        })
+        .context("In list_users_with_lskf_removal_deadline.")
+    }
+
+    /// Atomically loads a key entry and associated metadata or creates it using the
+    /// callback create_new_key callback. The callback is called during a database
+    /// transaction. This means that implementers should be mindful about using
+    /// blocking operations such as IPC or grabbing mutexes.
+    pub fn get_or_create_key_with<F>(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        alias: &str,
+        km_uuid: Uuid,
+        create_new_key: F,
+    ) -> Result<(KeyIdGuard, KeyEntry)>
+    where
+        F: Fn() -> Result<(Vec<u8>, BlobMetaData)>,
+    {
+        let _wp = wd::watch_millis("KeystoreDB::get_or_create_key_with", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let id = {
+                let mut stmt = tx
+                    .prepare(
+                        "SELECT id FROM persistent.keyentry
+                    WHERE
+                    key_type = ?
                     AND domain = ?
                     AND namespace = ?
                     AND alias = ?
@@ -1432,35 +2038,52 @@ impl KeystoreDB {
         .context("In get_or_create_key_with.")
     }
 
+    /// Calls `f` until it does not fail with DatabaseBusy or DatabaseLocked. The delay between
+    /// attempts starts at `BUSY_RETRY_INITIAL_DELAY` and doubles up to `BUSY_RETRY_MAX_DELAY`.
+    /// A random jitter of up to the delay keeps competing threads from retrying in lock step.
+    fn retry_on_busy<T, F>(mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        let mut delay = Self::BUSY_RETRY_INITIAL_DELAY;
+        loop {
+            match f() {
+                Err(e) if Self::is_locked_error(&e) => {
+                    let jitter = rand::random::<u64>() % delay.as_micros() as u64;
+                    std::thread::sleep(delay + Duration::from_micros(jitter));
+                    delay = std::cmp::min(delay * 2, Self::BUSY_RETRY_MAX_DELAY);
+                }
+                result => return result,
+            }
+        }
+    }
+
     /// Creates a transaction with the given behavior and executes f with the new transaction.
-    /// The transaction is committed only if f returns Ok and retried if DatabaseBusy
-    /// or DatabaseLocked is encountered.
+    /// The transaction is committed only if f returns Ok and retried with backoff if
+    /// DatabaseBusy or DatabaseLocked is encountered, see `retry_on_busy`. All transactions of
+    /// the database go through this function. If f wrote to the grant table, the grant cache
+    /// is invalidated once the transaction has completed.
     fn with_transaction<T, F>(&mut self, behavior: TransactionBehavior, f: F) -> Result<T>
     where
         F: Fn(&Transaction) -> Result<(bool, T)>,
     {
-        loop {
-            match self
-                .conn
-                .transaction_with_behavior(behavior)
+        let _gate = TRANSACTION_GATE.read().unwrap();
+        let conn = &mut self.conn;
+        let result = Self::retry_on_busy(|| {
+            conn.transaction_with_behavior(behavior)
                 .context("In with_transaction.")
                 .and_then(|tx| f(&tx).map(|result| (result, tx)))
                 .and_then(|(result, tx)| {
                     tx.commit().context("In with_transaction: Failed to commit transaction.")?;
                     Ok(result)
-                }) {
-                Ok(result) => break Ok(result),
-                Err(e) => {
-                    if Self::is_locked_error(&e) {
-                        std::thread::sleep(std::time::Duration::from_micros(500));
-                        continue;
-                    } else {
-                        return Err(e).context("In with_transaction.");
-                    }
-                }
-            }
+                })
+        })
+        .context("In with_transaction.");
+        if grant_cache::take_grant_write() {
+            self.grant_cache.invalidate();
         }
-        .map(|(need_gc, result)| {
+        self.account_io();
+        result.map(|(need_gc, result)| {
             if need_gc {
                 if let Some(ref gc) = self.gc {
                     gc.notify_gc();
@@ -1470,6 +2093,32 @@ impl KeystoreDB {
         })
     }
 
+    /// Attributes the pages that this connection read and wrote since the last call to the
+    /// current subsystem of this thread as one transaction.
+    fn account_io(&self) {
+        let (mut pages_read, mut pages_written, mut high_water) = (0, 0, 0);
+        // Safety: The handle stays valid for the lifetime of the connection and
+        // sqlite3_db_status only reads and resets the counters of the connection.
+        unsafe {
+            let handle = self.conn.handle();
+            rusqlite::ffi::sqlite3_db_status(
+                handle,
+                rusqlite::ffi::SQLITE_DBSTATUS_CACHE_MISS,
+                &mut pages_read,
+                &mut high_water,
+                1,
+            );
+            rusqlite::ffi::sqlite3_db_status(
+                handle,
+                rusqlite::ffi::SQLITE_DBSTATUS_CACHE_WRITE,
+                &mut pages_written,
+                &mut high_water,
+                1,
+            );
+        }
+        io_stats::record(pages_read.max(0) as u64, pages_written.max(0) as u64);
+    }
+
     fn is_locked_error(e: &anyhow::Error) -> bool {
         matches!(
             e.root_cause().downcast_ref::<rusqlite::ffi::Error>(),
@@ -1592,7 +2241,12 @@ impl KeystoreDB {
         let _wp = wd::watch_millis("KeystoreDB::set_blob", 500);
 
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            Self::set_blob_internal(&tx, key_id.0, sc_type, blob, blob_metadata).need_gc()
+            Self::set_blob_internal(&tx, key_id.0, sc_type, blob, blob_metadata)?;
+            // Key blob upgrades are not observable by clients and are not journaled.
+            if sc_type != SubComponentType::KEY_BLOB {
+                Self::journal_key_change(tx, key_id.0, KeyChangeKind::Updated)?;
+            }
+            Ok(()).need_gc()
         })
         .context("In set_blob.")
     }
@@ -1617,6 +2271,35 @@ impl KeystoreDB {
         .context("In set_deleted_blob.")
     }
 
+    /// Replaces the fingerprints of the given key with those of `cert`. Certificates that
+    /// cannot be parsed are not indexed, so the key cannot be found by fingerprint.
+    fn store_fingerprints(tx: &Transaction, key_id: i64, cert: Option<&[u8]>) -> Result<()> {
+        tx.execute("DELETE FROM persistent.keyfingerprint WHERE keyentryid = ?;", params![key_id])
+            .context("In store_fingerprints: Failed to delete fingerprints.")?;
+        let (cert_digest, spki_digest) = match cert.map(certificate_fingerprints) {
+            Some(Ok(digests)) => digests,
+            Some(Err(e)) => {
+                log::warn!("In store_fingerprints: Not indexing key {}: {:?}", key_id, e);
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        tx.execute(
+            "INSERT INTO persistent.keyfingerprint (keyentryid, kind, digest)
+             VALUES (?, ?, ?), (?, ?, ?);",
+            params![
+                key_id,
+                KeyFingerprintKind::Certificate,
+                cert_digest,
+                key_id,
+                KeyFingerprintKind::SubjectPublicKeyInfo,
+                spki_digest
+            ],
+        )
+        .context("In store_fingerprints: Failed to insert fingerprints.")?;
+        Ok(())
+    }
+
     fn set_blob_internal(
         tx: &Transaction,
         key_id: i64,
@@ -1632,24 +2315,51 @@ impl KeystoreDB {
                     params![sc_type, key_id, blob],
                 )
                 .context("In set_blob_internal: Failed to insert blob.")?;
+                let blob_id: i64 = tx
+                    .query_row("SELECT MAX(id) FROM persistent.blobentry;", NO_PARAMS, |row| {
+                        row.get(0)
+                    })
+                    .context("In set_blob_internal: Failed to get new blob id.")?;
+                if sc_type == SubComponentType::CERT_CHAIN {
+                    // The blob keeps the chain as well, see `cert_store`.
+                    cert_store::store_chain(tx, blob_id, blob)
+                        .context("In set_blob_internal: Trying to store certificate chain.")?;
+                }
                 if let Some(blob_metadata) = blob_metadata {
-                    let blob_id = tx
-                        .query_row("SELECT MAX(id) FROM persistent.blobentry;", NO_PARAMS, |row| {
-                            row.get(0)
-                        })
-                        .context("In set_blob_internal: Failed to get new blob id.")?;
                     blob_metadata
                         .store_in_db(blob_id, tx)
                         .context("In set_blob_internal: Trying to store blob metadata.")?;
                 }
+                if sc_type == SubComponentType::CERT {
+                    Self::store_fingerprints(tx, key_id, Some(blob))
+                        .context("In set_blob_internal.")?;
+                }
             }
             (None, SubComponentType::CERT) | (None, SubComponentType::CERT_CHAIN) => {
+                if sc_type == SubComponentType::CERT_CHAIN {
+                    let blob_ids = tx
+                        .prepare(
+                            "SELECT id FROM persistent.blobentry
+                             WHERE subcomponent_type = ? AND keyentryid = ?;",
+                        )
+                        .context("In set_blob_internal: Failed to prepare statement.")?
+                        .query_map(params![sc_type, key_id], |row| row.get(0))
+                        .context("In set_blob_internal: Failed to query blobs.")?
+                        .collect::<rusqlite::Result<Vec<i64>>>()
+                        .context("In set_blob_internal: Failed to read blobs.")?;
+                    for blob_id in blob_ids {
+                        cert_store::release_chain(tx, blob_id).context("In set_blob_internal.")?;
+                    }
+                }
                 tx.execute(
                     "DELETE FROM persistent.blobentry
                     WHERE subcomponent_type = ? AND keyentryid = ?;",
                     params![sc_type, key_id],
                 )
                 .context("In set_blob_internal: Failed to delete blob.")?;
+                if sc_type == SubComponentType::CERT {
+                    Self::store_fingerprints(tx, key_id, None).context("In set_blob_internal.")?;
+                }
             }
             (None, _) => {
                 return Err(KsError::sys())
@@ -1695,15 +2405,61 @@ impl KeystoreDB {
         Ok(())
     }
 
-    /// Insert a set of key entry specific metadata into the database.
-    #[cfg(test)]
-    fn insert_key_metadata(&mut self, key_id: &KeyIdGuard, metadata: &KeyMetaData) -> Result<()> {
+    /// Attaches the given metadata to the key entry, replacing entries of the same kind. This
+    /// is how subsystems record their own per key state. A new kind of metadata is added as a
+    /// variant of `KeyMetaEntry` and needs no schema migration.
+    pub fn insert_key_metadata(
+        &mut self,
+        key_id: &KeyIdGuard,
+        metadata: &KeyMetaData,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::insert_key_metadata", 500);
+
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
             metadata.store_in_db(key_id.0, &tx).no_gc()
         })
         .context("In insert_key_metadata.")
     }
 
+    /// Removes the metadata entries of the key entry for which `filter` returns true, e.g.,
+    /// `|entry| matches!(entry, KeyMetaEntry::Expired(_))`, and returns their number.
+    pub fn remove_key_metadata<F>(&mut self, key_id: &KeyIdGuard, filter: F) -> Result<usize>
+    where
+        F: Fn(&KeyMetaEntry) -> bool,
+    {
+        let _wp = wd::watch_millis("KeystoreDB::remove_key_metadata", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            KeyMetaData::remove_from_db(key_id.0, tx, &filter).no_gc()
+        })
+        .context("In remove_key_metadata.")
+    }
+
+    /// Records `used_at` as the date of the last use of the key entry, see
+    /// `KeyMetaEntry::LastUsed`. Unlike `insert_key_metadata`, this does not take the key id
+    /// guard, because it is called after an operation began with the key. Nothing is written
+    /// if the key entry is no longer live by then.
+    pub fn note_key_used(&mut self, key_id: i64, used_at: DateTime) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::note_key_used", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let mut metadata = KeyMetaData::new();
+            metadata.add(KeyMetaEntry::LastUsed(used_at));
+            let live = tx
+                .query_row(
+                    "SELECT count(*) FROM persistent.keyentry WHERE id = ? AND state = ?;",
+                    params![key_id, KeyLifeCycle::Live],
+                    |row| row.get::<_, i64>(0),
+                )
+                .context("Failed to query key entry state.")?;
+            if live != 0 {
+                metadata.store_in_db(key_id, tx)?;
+            }
+            Ok(()).no_gc()
+        })
+        .context("In note_key_used.")
+    }
+
     /// Stores a signed certificate chain signed by a remote provisioning server, keyed
     /// on the public key.
     pub fn store_signed_attestation_certificate_chain(
@@ -1952,6 +2708,45 @@ impl KeystoreDB {
         .context("In delete_all_attestation_keys: ")
     }
 
+    /// Retires the remotely provisioned attestation keys after the boot state of the device
+    /// changed, because their certificates were issued for the old state. Signed keys that are
+    /// not assigned yet are sent to the garbage collector right away. Assigned keys are marked
+    /// with `KeyMetaEntry::AttestationStale` and replaced on their next use, see
+    /// `retrieve_attestation_key_and_cert_chain`. Unsigned keys are kept, because the server
+    /// certifies them for the state reported at signing time. Returns the number of affected
+    /// keys.
+    pub fn mark_attestation_keys_stale(&mut self) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::mark_attestation_keys_stale", 500);
+
+        let now = DateTime::now().context("In mark_attestation_keys_stale: Failed to get time.")?;
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT id, domain IS NOT NULL FROM persistent.keyentry
+                     WHERE alias IS NOT NULL AND key_type = ? AND state = ?;",
+                )
+                .context("Failed to prepare statement")?;
+            let keys = stmt
+                .query_map(params![KeyType::Attestation, KeyLifeCycle::Live], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<(i64, bool)>>>()
+                .context("Failed to execute statement")?;
+            let mut retired = 0;
+            for (key_id, assigned) in keys.iter() {
+                if *assigned {
+                    let mut metadata = KeyMetaData::new();
+                    metadata.add(KeyMetaEntry::AttestationStale(now));
+                    metadata.store_in_db(*key_id, &tx).context("Trying to mark key stale.")?;
+                } else if Self::mark_unreferenced(&tx, *key_id)? {
+                    retired += 1;
+                }
+            }
+            Ok(keys.len()).do_gc(retired != 0)
+        })
+        .context("In mark_attestation_keys_stale: ")
+    }
+
     /// Counts the number of keys that will expire by the provided epoch date and the number of
     /// keys not currently assigned to a domain.
     pub fn get_attestation_pool_status(
@@ -2021,7 +2816,9 @@ impl KeystoreDB {
 
     /// Fetches the private key and corresponding certificate chain assigned to a
     /// domain/namespace pair. Will either return nothing if the domain/namespace is
-    /// not assigned, or one CertificateChain.
+    /// not assigned, or one CertificateChain. An assigned key that was marked stale, see
+    /// `mark_attestation_keys_stale`, is retired and nothing is returned, so that the caller
+    /// assigns a fresh key.
     pub fn retrieve_attestation_key_and_cert_chain(
         &mut self,
         domain: Domain,
@@ -2038,8 +2835,36 @@ impl KeystoreDB {
             }
         }
         self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let stale_key_id: Option<i64> = tx
+                .query_row(
+                    "SELECT id FROM persistent.keyentry
+                     WHERE key_type = ?
+                           AND domain = ?
+                           AND namespace = ?
+                           AND state = ?
+                           AND km_uuid = ?
+                           AND EXISTS (
+                               SELECT 1 FROM persistent.keymetadata
+                               WHERE keyentryid = keyentry.id AND tag = ?
+                           );",
+                    params![
+                        KeyType::Attestation,
+                        domain.0 as u32,
+                        namespace,
+                        KeyLifeCycle::Live,
+                        km_uuid,
+                        KeyMetaData::AttestationStale
+                    ],
+                    |row| row.get(0),
+                )
+                .optional()
+                .context("Trying to find a stale key.")?;
+            if let Some(key_id) = stale_key_id {
+                let retired = Self::mark_unreferenced(&tx, key_id)?;
+                return Ok(None).do_gc(retired);
+            }
             let mut stmt = tx.prepare(
-                "SELECT subcomponent_type, blob
+                "SELECT subcomponent_type, blob, id
              FROM persistent.blobentry
              WHERE keyentryid IN
                 (SELECT id
@@ -2059,9 +2884,9 @@ impl KeystoreDB {
                         KeyLifeCycle::Live,
                         km_uuid
                     ],
-                    |row| Ok((row.get(0)?, row.get(1)?)),
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
                 )?
-                .collect::<rusqlite::Result<Vec<(SubComponentType, Vec<u8>)>>>()
+                .collect::<rusqlite::Result<Vec<(SubComponentType, Vec<u8>, i64)>>>()
                 .context("query failed.")?;
             if rows.is_empty() {
                 return Ok(None).no_gc();
@@ -2084,7 +2909,9 @@ impl KeystoreDB {
                         km_blob = row.1;
                     }
                     SubComponentType::CERT_CHAIN => {
-                        cert_chain_blob = row.1;
+                        cert_chain_blob = cert_store::load_chain(tx, row.2)
+                            .context("Trying to load certificate chain.")?
+                            .unwrap_or(row.1);
                     }
                     SubComponentType::CERT => {
                         batch_cert_blob = row.1;
@@ -2114,6 +2941,7 @@ impl KeystoreDB {
         domain: &Domain,
         namespace: &i64,
         key_type: KeyType,
+        grant_policy: GrantRebindPolicy,
     ) -> Result<bool> {
         match *domain {
             Domain::APP | Domain::SELINUX => {}
@@ -2124,6 +2952,32 @@ impl KeystoreDB {
                 ));
             }
         }
+        // The grants of the key that is about to be replaced are dealt with in the same
+        // transaction, so that a grantee never observes a grant to an unreferenced key.
+        if grant_policy != GrantRebindPolicy::Keep {
+            grant_cache::note_grant_write();
+        }
+        match grant_policy {
+            GrantRebindPolicy::Keep => 0,
+            GrantRebindPolicy::Invalidate => tx
+                .execute(
+                    "DELETE FROM persistent.grant
+                     WHERE keyentryid IN (
+                        SELECT id FROM persistent.keyentry
+                        WHERE alias = ? AND domain = ? AND namespace = ? AND key_type = ?);",
+                    params![alias, domain.0 as u32, namespace, key_type],
+                )
+                .context("In rebind_alias: Failed to invalidate grants.")?,
+            GrantRebindPolicy::Transfer => tx
+                .execute(
+                    "UPDATE persistent.grant SET keyentryid = ?
+                     WHERE keyentryid IN (
+                        SELECT id FROM persistent.keyentry
+                        WHERE alias = ? AND domain = ? AND namespace = ? AND key_type = ?);",
+                    params![newid.0, alias, domain.0 as u32, namespace, key_type],
+                )
+                .context("In rebind_alias: Failed to transfer grants.")?,
+        };
         let updated = tx
             .execute(
                 "UPDATE persistent.keyentry
@@ -2154,6 +3008,12 @@ impl KeystoreDB {
                 result
             ));
         }
+        Self::journal_key_change(
+            tx,
+            newid.0,
+            if updated != 0 { KeyChangeKind::Updated } else { KeyChangeKind::Created },
+        )
+        .context("In rebind_alias.")?;
         Ok(updated != 0)
     }
 
@@ -2169,7 +3029,10 @@ impl KeystoreDB {
         let _wp = wd::watch_millis("KeystoreDB::migrate_key_namespace", 500);
 
         let destination = match destination.domain {
-            Domain::APP => KeyDescriptor { nspace: caller_uid as i64, ..(*destination).clone() },
+            Domain::APP => KeyDescriptor {
+                nspace: access_group::resolve_app_namespace(caller_uid, destination.nspace),
+                ..(*destination).clone()
+            },
             Domain::SELINUX => (*destination).clone(),
             domain => {
                 return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
@@ -2204,6 +3067,7 @@ impl KeystoreDB {
                     .context("Target already exists.");
             }
 
+            Self::journal_key_change(tx, key_id_guard.id(), KeyChangeKind::Deleted)?;
             let updated = tx
                 .execute(
                     "UPDATE persistent.keyentry
@@ -2217,6 +3081,7 @@ impl KeystoreDB {
                 return Err(KsError::sys())
                     .context(format!("Update succeeded, but {} rows were updated.", updated));
             }
+            Self::journal_key_change(tx, key_id_guard.id(), KeyChangeKind::Created)?;
             Ok(()).no_gc()
         })
         .context("In migrate_key_namespace:")
@@ -2224,7 +3089,8 @@ impl KeystoreDB {
 
     /// Store a new key in a single transaction.
     /// The function creates a new key entry, populates the blob, key parameter, and metadata
-    /// fields, and rebinds the given alias to the new key.
+    /// fields, and rebinds the given alias to the new key. If the alias was bound to another key,
+    /// the grants of that key are transferred or invalidated according to `grant_policy`.
     /// The boolean returned is a hint for the garbage collector. If true, a key was replaced,
     /// is now unreferenced and needs to be collected.
     #[allow(clippy::clippy::too_many_arguments)]
@@ -2237,53 +3103,200 @@ impl KeystoreDB {
         cert_info: &CertificateInfo,
         metadata: &KeyMetaData,
         km_uuid: &Uuid,
+        grant_policy: GrantRebindPolicy,
     ) -> Result<KeyIdGuard> {
         let _wp = wd::watch_millis("KeystoreDB::store_new_key", 500);
 
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let (key_id, need_gc) = Self::store_new_key_internal(
+                tx,
+                key,
+                key_type,
+                params,
+                blob_info,
+                cert_info,
+                metadata,
+                km_uuid,
+                grant_policy,
+            )?;
+            Ok(key_id).do_gc(need_gc)
+        })
+        .context("In store_new_key.")
+    }
+
+    /// Stores the client keys `keys` like `store_new_key` would, but in a single transaction.
+    /// Every key is stored within a savepoint, so that a key that cannot be stored, e.g.,
+    /// because it exceeds the quota, fails on its own without affecting the other keys.
+    pub fn store_new_keys(
+        &mut self,
+        keys: &[NewKey],
+        km_uuid: &Uuid,
+    ) -> Result<Vec<Result<KeyIdGuard>>> {
+        let _wp = wd::watch_millis("KeystoreDB::store_new_keys", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let mut need_gc = false;
+            let mut results = Vec::with_capacity(keys.len());
+            for new_key in keys {
+                tx.execute("SAVEPOINT new_key;", NO_PARAMS).context("Trying to set savepoint.")?;
+                let result = Self::store_new_key_internal(
+                    tx,
+                    new_key.key,
+                    KeyType::Client,
+                    new_key.params,
+                    &new_key.blob_info,
+                    new_key.cert_info,
+                    new_key.metadata,
+                    km_uuid,
+                    new_key.grant_policy,
+                );
+                let end_savepoint = match result {
+                    Ok(_) => "RELEASE new_key;",
+                    Err(_) => "ROLLBACK TO new_key; RELEASE new_key;",
+                };
+                tx.execute_batch(end_savepoint).context("Trying to end savepoint.")?;
+                results.push(result.map(|(key_id, gc)| {
+                    need_gc |= gc;
+                    key_id
+                }));
+            }
+            Ok(results).do_gc(need_gc)
+        })
+        .context("In store_new_keys.")
+    }
+
+    #[allow(clippy::clippy::too_many_arguments)]
+    fn store_new_key_internal(
+        tx: &Transaction,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        params: &[KeyParameter],
+        blob_info: &(&[u8], &BlobMetaData),
+        cert_info: &CertificateInfo,
+        metadata: &KeyMetaData,
+        km_uuid: &Uuid,
+        grant_policy: GrantRebindPolicy,
+    ) -> Result<(KeyIdGuard, bool)> {
         let (alias, domain, namespace) = match key {
             KeyDescriptor { alias: Some(alias), domain: Domain::APP, nspace, blob: None }
             | KeyDescriptor { alias: Some(alias), domain: Domain::SELINUX, nspace, blob: None } => {
                 (alias, key.domain, nspace)
             }
             _ => {
-                return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
-                    .context("In store_new_key: Need alias and domain must be APP or SELINUX.")
+                return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT)).context(
+                    "In store_new_key_internal: Need alias and domain must be APP or SELINUX.",
+                )
             }
         };
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            let key_id = Self::create_key_entry_internal(tx, &domain, namespace, key_type, km_uuid)
-                .context("Trying to create new key entry.")?;
-            let (blob, blob_metadata) = *blob_info;
+        let (blob, blob_metadata) = *blob_info;
+        let new_bytes = blob.len()
+            + cert_info.cert.as_ref().map_or(0, Vec::len)
+            + cert_info.cert_chain.as_ref().map_or(0, Vec::len);
+        if key_type == KeyType::Client {
+            let limits = quota::limits(domain, *namespace).context("Trying to store a new key.")?;
+            Self::check_quota_internal(tx, key, new_bytes, &limits)
+                .context("Trying to store a new key.")?;
+            Self::check_tenant_quota_internal(tx, key).context("Trying to store a new key.")?;
+        }
+        let key_id = Self::create_key_entry_internal(tx, &domain, namespace, key_type, km_uuid)
+            .context("Trying to create new key entry.")?;
+        Self::set_blob_internal(
+            tx,
+            key_id.id(),
+            SubComponentType::KEY_BLOB,
+            Some(blob),
+            Some(&blob_metadata),
+        )
+        .context("Trying to insert the key blob.")?;
+        if let Some(cert) = &cert_info.cert {
+            Self::set_blob_internal(tx, key_id.id(), SubComponentType::CERT, Some(&cert), None)
+                .context("Trying to insert the certificate.")?;
+        }
+        if let Some(cert_chain) = &cert_info.cert_chain {
             Self::set_blob_internal(
                 tx,
                 key_id.id(),
-                SubComponentType::KEY_BLOB,
-                Some(blob),
-                Some(&blob_metadata),
+                SubComponentType::CERT_CHAIN,
+                Some(&cert_chain),
+                None,
             )
-            .context("Trying to insert the key blob.")?;
-            if let Some(cert) = &cert_info.cert {
-                Self::set_blob_internal(tx, key_id.id(), SubComponentType::CERT, Some(&cert), None)
-                    .context("Trying to insert the certificate.")?;
-            }
-            if let Some(cert_chain) = &cert_info.cert_chain {
-                Self::set_blob_internal(
-                    tx,
-                    key_id.id(),
-                    SubComponentType::CERT_CHAIN,
-                    Some(&cert_chain),
-                    None,
-                )
-                .context("Trying to insert the certificate chain.")?;
-            }
-            Self::insert_keyparameter_internal(tx, &key_id, params)
-                .context("Trying to insert key parameters.")?;
-            metadata.store_in_db(key_id.id(), tx).context("Trying to insert key metadata.")?;
-            let need_gc = Self::rebind_alias(tx, &key_id, &alias, &domain, namespace, key_type)
+            .context("Trying to insert the certificate chain.")?;
+        }
+        Self::insert_keyparameter_internal(tx, &key_id, params)
+            .context("Trying to insert key parameters.")?;
+        metadata.store_in_db(key_id.id(), tx).context("Trying to insert key metadata.")?;
+        let need_gc =
+            Self::rebind_alias(tx, &key_id, &alias, &domain, namespace, key_type, grant_policy)
                 .context("Trying to rebind alias.")?;
-            Ok(key_id).do_gc(need_gc)
-        })
-        .context("In store_new_key.")
+        Ok((key_id, need_gc))
+    }
+
+    /// Checks that adding a client key with `new_bytes` bytes of blobs under the alias of
+    /// `key` does not exceed `limits`, see `quota`. The key that is currently bound to the alias,
+    /// if any, is replaced, so it does not count against the limits.
+    fn check_quota_internal(
+        tx: &Transaction,
+        key: &KeyDescriptor,
+        new_bytes: usize,
+        limits: &quota::Limits,
+    ) -> Result<()> {
+        if limits.is_unlimited() {
+            return Ok(());
+        }
+        let (keys, bytes): (i64, i64) = tx
+            .query_row(
+                // Only the current blob of each subcomponent counts, superseded blobs are
+                // awaiting garbage collection. A certificate chain blob that was cleared in
+                // favor of the certificate store counts with the certificates it links.
+                "SELECT COUNT(*), COALESCE(SUM((
+                         SELECT SUM(CASE WHEN LENGTH(b.blob) > 0 THEN LENGTH(b.blob) ELSE (
+                             SELECT COALESCE(SUM(LENGTH(c.data)), 0)
+                             FROM persistent.certchain cc
+                             JOIN persistent.certificate c ON c.id = cc.certificateid
+                             WHERE cc.blobentryid = b.id
+                         ) END)
+                         FROM persistent.blobentry b
+                         WHERE b.keyentryid = k.id
+                         AND b.id = (
+                             SELECT MAX(id) FROM persistent.blobentry
+                             WHERE keyentryid = k.id AND subcomponent_type = b.subcomponent_type
+                         )
+                     )), 0)
+                     FROM persistent.keyentry k
+                     WHERE k.domain = ?
+                     AND k.namespace = ?
+                     AND k.alias IS NOT ?
+                     AND k.state = ?
+                     AND k.key_type = ?;",
+                params![
+                    key.domain.0 as u32,
+                    key.nspace,
+                    key.alias,
+                    KeyLifeCycle::Live,
+                    KeyType::Client
+                ],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .context("In check_quota_internal: Failed to query namespace usage.")?;
+        limits
+            .check(key.domain, key.nspace, keys as usize + 1, bytes as usize + new_bytes)
+            .context("In check_quota_internal.")
+    }
+
+    /// Checks that adding a client key under the alias of `key` does not exceed the key quota
+    /// of the tenant owning its namespace, if any, see `tenants`. The key that is currently
+    /// bound to the alias, if any, is replaced, so it does not count against the quota.
+    fn check_tenant_quota_internal(tx: &Transaction, key: &KeyDescriptor) -> Result<()> {
+        let tenant = match tenants::tenant_of_namespace(key.domain, key.nspace)
+            .context("In check_tenant_quota_internal.")?
+        {
+            Some(tenant) => tenant,
+            None => return Ok(()),
+        };
+        let count =
+            Self::count_keys_in_namespaces_internal(tx, key.domain, &tenant.namespaces, Some(key))
+                .context("In check_tenant_quota_internal.")?;
+        tenant.check_quota(count).context("In check_tenant_quota_internal.")
     }
 
     /// Store a new certificate
@@ -2310,6 +3323,14 @@ impl KeystoreDB {
             }
         };
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            if key_type == KeyType::Client {
+                let limits = quota::limits(domain, *namespace)
+                    .context("Trying to store a new certificate.")?;
+                Self::check_quota_internal(tx, key, cert.len(), &limits)
+                    .context("Trying to store a new certificate.")?;
+                Self::check_tenant_quota_internal(tx, key)
+                    .context("Trying to store a new certificate.")?;
+            }
             let key_id = Self::create_key_entry_internal(tx, &domain, namespace, key_type, km_uuid)
                 .context("Trying to create new key entry.")?;
 
@@ -2329,8 +3350,16 @@ impl KeystoreDB {
 
             metadata.store_in_db(key_id.id(), tx).context("Trying to insert key metadata.")?;
 
-            let need_gc = Self::rebind_alias(tx, &key_id, &alias, &domain, namespace, key_type)
-                .context("Trying to rebind alias.")?;
+            let need_gc = Self::rebind_alias(
+                tx,
+                &key_id,
+                &alias,
+                &domain,
+                namespace,
+                key_type,
+                GrantRebindPolicy::Keep,
+            )
+            .context("Trying to rebind alias.")?;
             Ok(key_id).do_gc(need_gc)
         })
         .context("In store_new_certificate.")
@@ -2367,6 +3396,38 @@ impl KeystoreDB {
         .context("In load_key_entry_id.")
     }
 
+    /// Loads all grants of the given grantee that refer to live keys, mapped from grant id
+    /// to key id and access vector.
+    fn load_grants_of_grantee(
+        tx: &Transaction,
+        grantee: u32,
+    ) -> Result<grant_cache::GranteeGrants> {
+        let mut stmt = tx
+            .prepare(
+                "SELECT g.id, g.keyentryid, g.access_vector
+                    FROM persistent.grant AS g
+                    INNER JOIN persistent.keyentry AS k ON g.keyentryid = k.id
+                    WHERE g.grantee = ? AND k.state = ?;",
+            )
+            .context("In load_grants_of_grantee: prepare statement failed.")?;
+        let mut rows = stmt
+            .query(params![grantee as i64, KeyLifeCycle::Live])
+            .context("In load_grants_of_grantee: query failed.")?;
+        let mut grants = grant_cache::GranteeGrants::new();
+        db_utils::with_rows_extract_all(&mut rows, |row| {
+            grants.insert(
+                row.get(0).context("Failed to unpack grant id.")?,
+                (
+                    row.get(1).context("Failed to unpack key_id.")?,
+                    row.get(2).context("Failed to unpack access_vector.")?,
+                ),
+            );
+            Ok(())
+        })
+        .context("In load_grants_of_grantee.")?;
+        Ok(grants)
+    }
+
     /// This helper function completes the access tuple of a key, which is required
     /// to perform access control. The strategy depends on the `domain` field in the
     /// key descriptor.
@@ -2375,7 +3436,8 @@ impl KeystoreDB {
     /// * Domain::APP: Like Domain::SELINUX, but the tuple is completed by `caller_uid`
     ///       which serves as the namespace.
     /// * Domain::GRANT: The grant table is queried for the `key_id` and the
-    ///       `access_vector`.
+    ///       `access_vector`. If a `grant_cache` is given, the active grants of the
+    ///       caller are served from and loaded into the cache instead.
     /// * Domain::KEY_ID: The keyentry table is queried for the owning `domain` and
     ///       `namespace`.
     /// In each case the information returned is sufficient to perform the access
@@ -2385,6 +3447,7 @@ impl KeystoreDB {
         key: &KeyDescriptor,
         key_type: KeyType,
         caller_uid: u32,
+        grant_cache: Option<&grant_cache::GrantCache>,
     ) -> Result<(i64, KeyDescriptor, Option<KeyPermSet>)> {
         match key.domain {
             // Domain App or SELinux. In this case we load the key_id from
@@ -2392,21 +3455,43 @@ impl KeystoreDB {
             // We already have the full access tuple to perform access control.
             // The only distinction is that we use the caller_uid instead
             // of the caller supplied namespace if the domain field is
-            // Domain::APP.
+            // Domain::APP, unless the caller addresses the keys of an access group.
             Domain::APP | Domain::SELINUX => {
                 let mut access_key = key.clone();
                 if access_key.domain == Domain::APP {
-                    access_key.nspace = caller_uid as i64;
+                    access_key.nspace = access_group::resolve_app_namespace(caller_uid, key.nspace);
                 }
                 let key_id = Self::load_key_entry_id(&tx, &access_key, key_type)
                     .with_context(|| format!("With key.domain = {:?}.", access_key.domain))?;
 
-                Ok((key_id, access_key, None))
+                // A namespace grant covers all keys of the namespace.
+                let access_vector = if access_key.domain == Domain::SELINUX {
+                    Self::load_namespace_grant(tx, caller_uid, access_key.nspace)
+                        .context("Domain::SELINUX.")?
+                } else {
+                    None
+                };
+
+                Ok((key_id, access_key, access_vector))
             }
 
             // Domain::GRANT. In this case we load the key_id and the access_vector
             // from the grant table.
             Domain::GRANT => {
+                if let Some(grant_cache) = grant_cache {
+                    let grants = grant_cache
+                        .get_or_load(caller_uid, || Self::load_grants_of_grantee(tx, caller_uid))
+                        .context("Domain::GRANT.")?;
+                    let (key_id, access_vector) = grants
+                        .get(&key.nspace)
+                        .copied()
+                        .ok_or(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+                        .context("Domain::GRANT.")?;
+                    let access_vector =
+                        Self::restrict_grant(tx, key_id, caller_uid, access_vector.into())
+                            .context("Domain::GRANT.")?;
+                    return Ok((key_id, key.clone(), Some(access_vector)));
+                }
                 let mut stmt = tx
                     .prepare(
                         "SELECT keyentryid, access_vector FROM persistent.grant
@@ -2427,7 +3512,10 @@ impl KeystoreDB {
                         ))
                     })
                     .context("Domain::GRANT.")?;
-                Ok((key_id, key.clone(), Some(access_vector.into())))
+                let access_vector =
+                    Self::restrict_grant(tx, key_id, caller_uid, access_vector.into())
+                        .context("Domain::GRANT.")?;
+                Ok((key_id, key.clone(), Some(access_vector)))
             }
 
             // Domain::KEY_ID. In this case we load the domain and namespace from the
@@ -2473,7 +3561,16 @@ impl KeystoreDB {
                             )
                             .optional()
                             .context("Domain::KEY_ID: query grant failed.")?;
-                        access_vector.map(|p| p.into())
+                        let namespace_grant = if domain == Domain::SELINUX {
+                            Self::load_namespace_grant(tx, caller_uid, namespace)
+                                .context("Domain::KEY_ID.")?
+                        } else {
+                            None
+                        };
+                        match (namespace_grant, access_vector) {
+                            (Some(n), Some(k)) => Some(KeyPermSet(n.0 | k)),
+                            (n, k) => n.or_else(|| k.map(|p| p.into())),
+                        }
                     } else {
                         None
                     };
@@ -2489,12 +3586,58 @@ impl KeystoreDB {
         }
     }
 
-    fn load_blob_components(
-        key_id: i64,
-        load_bits: KeyEntryLoadBits,
+    /// Applies the device key policy to the `access_vector` of a grant of the key `key_id` to
+    /// `grantee`, see `key_policy::restrict_grant`.
+    fn restrict_grant(
         tx: &Transaction,
-    ) -> Result<(bool, Option<(Vec<u8>, BlobMetaData)>, Option<Vec<u8>>, Option<Vec<u8>>)> {
-        let mut stmt = tx
+        key_id: i64,
+        grantee: u32,
+        access_vector: KeyPermSet,
+    ) -> Result<KeyPermSet> {
+        if !key_policy::is_active() {
+            return Ok(access_vector);
+        }
+        let owner = tx
+            .query_row(
+                "SELECT domain, namespace, alias FROM persistent.keyentry WHERE id = ?;",
+                params![key_id],
+                |row| {
+                    Ok(KeyDescriptor {
+                        domain: Domain(row.get(0)?),
+                        nspace: row.get(1)?,
+                        alias: row.get(2)?,
+                        blob: None,
+                    })
+                },
+            )
+            .context("In restrict_grant: Failed to load the owner of the key.")?;
+        Ok(key_policy::restrict_grant(grantee, access_vector, &owner))
+    }
+
+    /// Returns the access vector of the namespace grant of `namespace` to `grantee`, if any.
+    fn load_namespace_grant(
+        tx: &Transaction,
+        grantee: u32,
+        namespace: i64,
+    ) -> Result<Option<KeyPermSet>> {
+        let access_vector: Option<i32> = tx
+            .query_row(
+                "SELECT access_vector FROM persistent.namespacegrant
+                    WHERE grantee = ? AND namespace = ?;",
+                params![grantee as i64, namespace],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("In load_namespace_grant: Failed to query namespace grant.")?;
+        Ok(access_vector.map(|p| p.into()))
+    }
+
+    fn load_blob_components(
+        key_id: i64,
+        load_bits: KeyEntryLoadBits,
+        tx: &Transaction,
+    ) -> Result<(bool, Option<(Vec<u8>, BlobMetaData)>, Option<Vec<u8>>, Option<Vec<u8>>)> {
+        let mut stmt = tx
             .prepare(
                 "SELECT MAX(id), subcomponent_type, blob FROM persistent.blobentry
                     WHERE keyentryid = ? GROUP BY subcomponent_type;",
@@ -2506,7 +3649,7 @@ impl KeystoreDB {
 
         let mut key_blob: Option<(i64, Vec<u8>)> = None;
         let mut cert_blob: Option<Vec<u8>> = None;
-        let mut cert_chain_blob: Option<Vec<u8>> = None;
+        let mut cert_chain_blob: Option<(i64, Vec<u8>)> = None;
         let mut has_km_blob: bool = false;
         db_utils::with_rows_extract_all(&mut rows, |row| {
             let sub_type: SubComponentType =
@@ -2524,8 +3667,10 @@ impl KeystoreDB {
                         Some(row.get(2).context("Failed to extract public certificate blob.")?);
                 }
                 (SubComponentType::CERT_CHAIN, true, _) => {
-                    cert_chain_blob =
-                        Some(row.get(2).context("Failed to extract certificate chain blob.")?);
+                    cert_chain_blob = Some((
+                        row.get(0).context("Failed to extract certificate chain blob id.")?,
+                        row.get(2).context("Failed to extract certificate chain blob.")?,
+                    ));
                 }
                 (SubComponentType::CERT, _, _)
                 | (SubComponentType::CERT_CHAIN, _, _)
@@ -2544,6 +3689,14 @@ impl KeystoreDB {
             )))
         })?;
 
+        let cert_chain_blob = cert_chain_blob
+            .map(|(blob_id, blob)| -> Result<Vec<u8>> {
+                Ok(cert_store::load_chain(tx, blob_id)
+                    .context("In load_blob_components: Trying to load certificate chain.")?
+                    .unwrap_or(blob))
+            })
+            .transpose()?;
+
         Ok((has_km_blob, blob_info, cert_blob, cert_chain_blob))
     }
 
@@ -2628,25 +3781,10 @@ impl KeystoreDB {
     ) -> Result<(KeyIdGuard, KeyEntry)> {
         let _wp = wd::watch_millis("KeystoreDB::load_key_entry", 500);
 
-        loop {
-            match self.load_key_entry_internal(
-                key,
-                key_type,
-                load_bits,
-                caller_uid,
-                &check_permission,
-            ) {
-                Ok(result) => break Ok(result),
-                Err(e) => {
-                    if Self::is_locked_error(&e) {
-                        std::thread::sleep(std::time::Duration::from_micros(500));
-                        continue;
-                    } else {
-                        return Err(e).context("In load_key_entry.");
-                    }
-                }
-            }
-        }
+        Self::retry_on_busy(|| {
+            self.load_key_entry_internal(key, key_type, load_bits, caller_uid, &check_permission)
+        })
+        .context("In load_key_entry.")
     }
 
     fn load_key_entry_internal(
@@ -2672,7 +3810,7 @@ impl KeystoreDB {
 
         // Load the key_id and complete the access control tuple.
         let (key_id, access_key_descriptor, access_vector) =
-            Self::load_access_tuple(&tx, key, key_type, caller_uid)
+            Self::load_access_tuple(&tx, key, key_type, caller_uid, Some(&self.grant_cache))
                 .context("In load_key_entry.")?;
 
         // Perform access control. It is vital that we return here if the permission is denied.
@@ -2716,6 +3854,7 @@ impl KeystoreDB {
                         },
                         key_type,
                         caller_uid,
+                        None,
                     )
                     .context("In load_key_entry. (deferred key lock)")?;
                     (key_id_guard, tx)
@@ -2729,11 +3868,36 @@ impl KeystoreDB {
             .context("In load_key_entry.")?;
 
         tx.commit().context("In load_key_entry: Failed to commit transaction.")?;
+        self.account_io();
 
         Ok((key_id_guard, key_entry))
     }
 
+    /// Appends an entry of the given kind for the key `key_id` to the key change journal.
+    /// Only live client keys bound to an alias are journaled, all other keys are ignored.
+    fn journal_key_change(tx: &Transaction, key_id: i64, kind: KeyChangeKind) -> Result<()> {
+        tx.execute(
+            "INSERT INTO persistent.keyjournal (domain, namespace, alias, event)
+             SELECT domain, namespace, alias, ? FROM persistent.keyentry
+             WHERE id = ? AND key_type = ? AND state = ? AND alias IS NOT NULL;",
+            params![kind, key_id, KeyType::Client, KeyLifeCycle::Live],
+        )
+        .context("In journal_key_change: Failed to insert journal entry.")?;
+        Self::trim_key_journal(tx).context("In journal_key_change.")
+    }
+
+    fn trim_key_journal(tx: &Transaction) -> Result<()> {
+        tx.execute(
+            "DELETE FROM persistent.keyjournal
+             WHERE seq <= (SELECT MAX(seq) FROM persistent.keyjournal) - ?;",
+            params![Self::MAX_KEY_JOURNAL_ENTRIES],
+        )
+        .context("In trim_key_journal: Failed to delete old journal entries.")?;
+        Ok(())
+    }
+
     fn mark_unreferenced(tx: &Transaction, key_id: i64) -> Result<bool> {
+        Self::journal_key_change(tx, key_id, KeyChangeKind::Deleted)?;
         let updated = tx
             .execute("DELETE FROM persistent.keyentry WHERE id = ?;", params![key_id])
             .context("Trying to delete keyentry.")?;
@@ -2741,8 +3905,11 @@ impl KeystoreDB {
             .context("Trying to delete keymetadata.")?;
         tx.execute("DELETE FROM persistent.keyparameter WHERE keyentryid = ?;", params![key_id])
             .context("Trying to delete keyparameters.")?;
+        tx.execute("DELETE FROM persistent.keyfingerprint WHERE keyentryid = ?;", params![key_id])
+            .context("Trying to delete key fingerprints.")?;
         tx.execute("DELETE FROM persistent.grant WHERE keyentryid = ?;", params![key_id])
             .context("Trying to delete grants.")?;
+        grant_cache::note_grant_write();
         Ok(updated != 0)
     }
 
@@ -2759,7 +3926,7 @@ impl KeystoreDB {
 
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
             let (key_id, access_key_descriptor, access_vector) =
-                Self::load_access_tuple(tx, key, key_type, caller_uid)
+                Self::load_access_tuple(tx, key, key_type, caller_uid, None)
                     .context("Trying to get access tuple.")?;
 
             // Perform access control. It is vital that we return here if the permission is denied.
@@ -2783,9 +3950,14 @@ impl KeystoreDB {
         .context("In get_key_km_uuid.")
     }
 
-    /// Delete all artifacts belonging to the namespace given by the domain-namespace tuple.
-    /// This leaves all of the blob entries orphaned for subsequent garbage collection.
-    pub fn unbind_keys_for_namespace(&mut self, domain: Domain, namespace: i64) -> Result<()> {
+    /// Unbinds all keys of the namespace given by the domain-namespace tuple and revokes their
+    /// grants. The key entries are marked unreferenced, so that the garbage collector deletes
+    /// them along with their blobs, metadata, and parameters in batches. All of this happens in
+    /// a single transaction, so that a crash never leaves the namespace half cleared. The keys of
+    /// a frozen namespace, see `namespace_freeze`, are quarantined instead. They are kept as
+    /// evidence and unbound when the namespace is unfrozen, so that an app that reuses the uid
+    /// never sees them. Returns the number of unbound or quarantined keys.
+    pub fn unbind_keys_for_namespace(&mut self, domain: Domain, namespace: i64) -> Result<usize> {
         let _wp = wd::watch_millis("KeystoreDB::unbind_keys_for_namespace", 500);
 
         if !(domain == Domain::APP || domain == Domain::SELINUX) {
@@ -2793,24 +3965,10 @@ impl KeystoreDB {
                 .context("In unbind_keys_for_namespace.");
         }
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            tx.execute(
-                "DELETE FROM persistent.keymetadata
-                WHERE keyentryid IN (
-                    SELECT id FROM persistent.keyentry
-                    WHERE domain = ? AND namespace = ? AND key_type = ?
-                );",
-                params![domain.0, namespace, KeyType::Client],
-            )
-            .context("Trying to delete keymetadata.")?;
-            tx.execute(
-                "DELETE FROM persistent.keyparameter
-                WHERE keyentryid IN (
-                    SELECT id FROM persistent.keyentry
-                    WHERE domain = ? AND namespace = ? AND key_type = ?
-                );",
-                params![domain.0, namespace, KeyType::Client],
-            )
-            .context("Trying to delete keyparameters.")?;
+            // Checked in the transaction, so that the namespace cannot be frozen while it is
+            // cleared.
+            let frozen = Self::is_frozen(tx, domain, namespace)
+                .context("Trying to check whether the namespace is frozen.")?;
             tx.execute(
                 "DELETE FROM persistent.grant
                 WHERE keyentryid IN (
@@ -2820,73 +3978,253 @@ impl KeystoreDB {
                 params![domain.0, namespace, KeyType::Client],
             )
             .context("Trying to delete grants.")?;
+            if domain == Domain::APP {
+                // The namespace of an app is its uid. Revoke the grants the app received, so
+                // that an app installed later under the same uid does not inherit them.
+                tx.execute("DELETE FROM persistent.grant WHERE grantee = ?;", params![namespace])
+                    .context("Trying to delete grants to the app.")?;
+                tx.execute(
+                    "DELETE FROM persistent.namespacegrant WHERE grantee = ?;",
+                    params![namespace],
+                )
+                .context("Trying to delete namespace grants to the app.")?;
+            } else {
+                // Grants of the entire namespace must not cover keys created after the clear.
+                tx.execute(
+                    "DELETE FROM persistent.namespacegrant WHERE namespace = ?;",
+                    params![namespace],
+                )
+                .context("Trying to delete namespace grants.")?;
+            }
+            grant_cache::note_grant_write();
             tx.execute(
-                "DELETE FROM persistent.keyentry
-                 WHERE domain = ? AND namespace = ? AND key_type = ?;",
-                params![domain.0, namespace, KeyType::Client],
+                "INSERT INTO persistent.keyjournal (domain, namespace, alias, event)
+                 SELECT domain, namespace, alias, ? FROM persistent.keyentry
+                 WHERE domain = ? AND namespace = ? AND key_type = ? AND state = ?
+                 AND alias IS NOT NULL ORDER BY id;",
+                params![
+                    KeyChangeKind::Deleted,
+                    domain.0,
+                    namespace,
+                    KeyType::Client,
+                    KeyLifeCycle::Live
+                ],
             )
-            .context("Trying to delete keyentry.")?;
-            Ok(()).need_gc()
+            .context("Trying to journal deleted keys.")?;
+            Self::trim_key_journal(tx).context("Trying to trim key journal.")?;
+            if frozen {
+                let quarantined = tx
+                    .execute(
+                        "UPDATE persistent.keyentry SET state = ?
+                         WHERE domain = ? AND namespace = ? AND key_type = ? AND state = ?;",
+                        params![
+                            KeyLifeCycle::Quarantined,
+                            domain.0,
+                            namespace,
+                            KeyType::Client,
+                            KeyLifeCycle::Live
+                        ],
+                    )
+                    .context("Trying to quarantine keyentry.")?;
+                return Ok(quarantined).no_gc();
+            }
+            let unbound = tx
+                .execute(
+                    "UPDATE persistent.keyentry
+                     SET alias = NULL, domain = NULL, namespace = NULL, state = ?
+                     WHERE domain = ? AND namespace = ? AND key_type = ?;",
+                    params![KeyLifeCycle::Unreferenced, domain.0, namespace, KeyType::Client],
+                )
+                .context("Trying to unbind keyentry.")?;
+            Ok(unbound).do_gc(unbound != 0)
         })
         .context("In unbind_keys_for_namespace")
     }
 
-    fn cleanup_unreferenced(tx: &Transaction) -> Result<()> {
-        let _wp = wd::watch_millis("KeystoreDB::cleanup_unreferenced", 500);
-        {
-            tx.execute(
-                "DELETE FROM persistent.keymetadata
-            WHERE keyentryid IN (
-                SELECT id FROM persistent.keyentry
-                WHERE state = ?
-            );",
-                params![KeyLifeCycle::Unreferenced],
-            )
-            .context("Trying to delete keymetadata.")?;
-            tx.execute(
-                "DELETE FROM persistent.keyparameter
-            WHERE keyentryid IN (
-                SELECT id FROM persistent.keyentry
-                WHERE state = ?
-            );",
-                params![KeyLifeCycle::Unreferenced],
-            )
-            .context("Trying to delete keyparameters.")?;
-            tx.execute(
-                "DELETE FROM persistent.grant
-            WHERE keyentryid IN (
-                SELECT id FROM persistent.keyentry
-                WHERE state = ?
-            );",
-                params![KeyLifeCycle::Unreferenced],
+    fn is_frozen(tx: &Transaction, domain: Domain, namespace: i64) -> Result<bool> {
+        Ok(tx
+            .query_row(
+                "SELECT 1 FROM persistent.frozennamespace WHERE domain = ? AND namespace = ?;",
+                params![domain.0 as u32, namespace],
+                |_| Ok(()),
             )
+            .optional()
+            .context("In is_frozen.")?
+            .is_some())
+    }
+
+    /// Quarantines the given live key, see `KeyLifeCycle::Quarantined`, and revokes its grants.
+    /// Returns true if the key was quarantined.
+    fn quarantine_key(tx: &Transaction, key_id: i64) -> Result<bool> {
+        Self::journal_key_change(tx, key_id, KeyChangeKind::Deleted)?;
+        tx.execute("DELETE FROM persistent.grant WHERE keyentryid = ?;", params![key_id])
             .context("Trying to delete grants.")?;
-            tx.execute(
-                "DELETE FROM persistent.keyentry
-                WHERE state = ?;",
-                params![KeyLifeCycle::Unreferenced],
+        grant_cache::note_grant_write();
+        let updated = tx
+            .execute(
+                "UPDATE persistent.keyentry SET state = ? WHERE id = ? AND state = ?;",
+                params![KeyLifeCycle::Quarantined, key_id, KeyLifeCycle::Live],
             )
-            .context("Trying to delete keyentry.")?;
-            Result::<()>::Ok(())
+            .context("Trying to quarantine keyentry.")?;
+        Ok(updated != 0)
+    }
+
+    /// Deletes up to `limit` unreferenced key entries along with their metadata, parameters,
+    /// fingerprints, and grants. Their blobs are left to the garbage collector. Returns the
+    /// number of deleted key entries.
+    fn cleanup_unreferenced(tx: &Transaction, limit: usize) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::cleanup_unreferenced", 500);
+        {
+            let key_ids = tx
+                .prepare("SELECT id FROM persistent.keyentry WHERE state = ? LIMIT ?;")
+                .context("Trying to prepare query for unreferenced keys.")?
+                .query_map(params![KeyLifeCycle::Unreferenced, limit as i64], |row| row.get(0))
+                .context("Trying to query unreferenced keys.")?
+                .collect::<rusqlite::Result<Vec<i64>>>()
+                .context("Trying to extract unreferenced keys.")?;
+            for key_id in key_ids.iter() {
+                tx.execute(
+                    "DELETE FROM persistent.keymetadata WHERE keyentryid = ?;",
+                    params![key_id],
+                )
+                .context("Trying to delete keymetadata.")?;
+                tx.execute(
+                    "DELETE FROM persistent.keyparameter WHERE keyentryid = ?;",
+                    params![key_id],
+                )
+                .context("Trying to delete keyparameters.")?;
+                tx.execute(
+                    "DELETE FROM persistent.keyfingerprint WHERE keyentryid = ?;",
+                    params![key_id],
+                )
+                .context("Trying to delete key fingerprints.")?;
+                tx.execute("DELETE FROM persistent.grant WHERE keyentryid = ?;", params![key_id])
+                    .context("Trying to delete grants.")?;
+                tx.execute("DELETE FROM persistent.keyentry WHERE id = ?;", params![key_id])
+                    .context("Trying to delete keyentry.")?;
+            }
+            if !key_ids.is_empty() {
+                grant_cache::note_grant_write();
+            }
+            Result::<usize>::Ok(key_ids.len())
         }
         .context("In cleanup_unreferenced")
     }
 
+    /// Returns up to `limit` randomly chosen key blobs of live app keys of the given user that
+    /// are super encrypted, along with the id of their key entry and their blob metadata.
+    pub fn sample_super_encrypted_blobs(
+        &mut self,
+        user_id: u32,
+        limit: usize,
+    ) -> Result<Vec<(i64, Vec<u8>, BlobMetaData)>> {
+        let _wp = wd::watch_millis("KeystoreDB::sample_super_encrypted_blobs", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(&format!(
+                    "SELECT keyentry.id, blobentry.id, blobentry.blob
+                     FROM persistent.keyentry
+                     INNER JOIN persistent.blobentry ON blobentry.keyentryid = keyentry.id
+                     WHERE keyentry.key_type = ?
+                         AND keyentry.domain = ?
+                         AND cast ( (keyentry.namespace/{aid_user_offset}) as int) = ?
+                         AND keyentry.state = ?
+                         AND blobentry.id = (
+                             SELECT MAX(id) FROM persistent.blobentry AS newest
+                             WHERE newest.keyentryid = keyentry.id
+                                 AND newest.subcomponent_type = ?
+                         )
+                         AND EXISTS (
+                             SELECT 1 FROM persistent.blobmetadata
+                             WHERE blobmetadata.blobentryid = blobentry.id
+                                 AND blobmetadata.tag = ?
+                         )
+                     ORDER BY RANDOM() LIMIT ?;",
+                    aid_user_offset = AID_USER_OFFSET
+                ))
+                .context("In sample_super_encrypted_blobs: Failed to prepare statement.")?;
+            let rows = stmt
+                .query_map(
+                    params![
+                        KeyType::Client,
+                        Domain::APP.0 as u32,
+                        user_id,
+                        KeyLifeCycle::Live,
+                        SubComponentType::KEY_BLOB,
+                        BlobMetaData::EncryptedBy,
+                        limit as i64
+                    ],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )
+                .context("In sample_super_encrypted_blobs: Query failed.")?
+                .collect::<rusqlite::Result<Vec<(i64, i64, Vec<u8>)>>>()
+                .context("In sample_super_encrypted_blobs: Failed to read rows.")?;
+            rows.into_iter()
+                .map(|(key_id, blob_id, blob)| {
+                    Ok((key_id, blob, BlobMetaData::load_from_db(blob_id, tx)?))
+                })
+                .collect::<Result<Vec<_>>>()
+                .no_gc()
+        })
+        .context("In sample_super_encrypted_blobs.")
+    }
+
+    /// Returns the live client keys whose certificate has the given fingerprint.
+    pub fn find_keys_by_fingerprint(
+        &mut self,
+        kind: KeyFingerprintKind,
+        digest: &[u8],
+    ) -> Result<Vec<KeyDescriptor>> {
+        let _wp = wd::watch_millis("KeystoreDB::find_keys_by_fingerprint", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT keyentry.domain, keyentry.namespace, keyentry.alias
+                     FROM persistent.keyfingerprint
+                     INNER JOIN persistent.keyentry
+                         ON keyentry.id = keyfingerprint.keyentryid
+                     WHERE keyfingerprint.kind = ?
+                         AND keyfingerprint.digest = ?
+                         AND keyentry.key_type = ?
+                         AND keyentry.state = ?
+                     ORDER BY keyentry.id;",
+                )
+                .context("In find_keys_by_fingerprint: Failed to prepare statement.")?;
+            let keys = stmt
+                .query_map(params![kind, digest, KeyType::Client, KeyLifeCycle::Live], |row| {
+                    Ok(KeyDescriptor {
+                        domain: Domain(row.get(0)?),
+                        nspace: row.get(1)?,
+                        alias: row.get(2)?,
+                        blob: None,
+                    })
+                })
+                .context("In find_keys_by_fingerprint: Query failed.")?
+                .collect::<rusqlite::Result<Vec<KeyDescriptor>>>()
+                .context("In find_keys_by_fingerprint: Failed to read rows.")?;
+            Ok(keys).no_gc()
+        })
+        .context("In find_keys_by_fingerprint.")
+    }
+
     /// Delete the keys created on behalf of the user, denoted by the user id.
     /// Delete all the keys unless 'keep_non_super_encrypted_keys' set to true.
-    /// Returned boolean is to hint the garbage collector to delete the unbound keys.
-    /// The caller of this function should notify the gc if the returned value is true.
+    /// The keys are deleted in a single transaction, which also hands their blobs to the
+    /// garbage collector. Keys of frozen app namespaces are quarantined instead, like in
+    /// `unbind_keys_for_namespace`. Returns the number of deleted or quarantined keys.
     pub fn unbind_keys_for_user(
         &mut self,
         user_id: u32,
         keep_non_super_encrypted_keys: bool,
-    ) -> Result<()> {
+    ) -> Result<usize> {
         let _wp = wd::watch_millis("KeystoreDB::unbind_keys_for_user", 500);
 
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
             let mut stmt = tx
                 .prepare(&format!(
-                    "SELECT id from persistent.keyentry
+                    "SELECT id, domain, namespace from persistent.keyentry
                      WHERE (
                          key_type = ?
                          AND domain = ?
@@ -2920,16 +4258,20 @@ impl KeystoreDB {
                 ])
                 .context("In unbind_keys_for_user. Failed to query the keys created by apps.")?;
 
-            let mut key_ids: Vec<i64> = Vec::new();
+            let mut keys: Vec<(i64, Option<i32>, Option<i64>)> = Vec::new();
             db_utils::with_rows_extract_all(&mut rows, |row| {
-                key_ids
-                    .push(row.get(0).context("Failed to read key id of a key created by an app.")?);
+                keys.push((
+                    row.get(0).context("Failed to read key id of a key created by an app.")?,
+                    row.get(1).context("Failed to read domain of a key created by an app.")?,
+                    row.get(2).context("Failed to read namespace of a key created by an app.")?,
+                ));
                 Ok(())
             })
             .context("In unbind_keys_for_user.")?;
 
-            let mut notify_gc = false;
-            for key_id in key_ids {
+            let mut unbound = 0;
+            let mut quarantined = 0;
+            for (key_id, domain, namespace) in keys {
                 if keep_non_super_encrypted_keys {
                     // Load metadata and filter out non-super-encrypted keys.
                     if let (_, Some((_, blob_metadata)), _, _) =
@@ -2941,11 +4283,21 @@ impl KeystoreDB {
                         }
                     }
                 }
-                notify_gc = Self::mark_unreferenced(&tx, key_id)
-                    .context("In unbind_keys_for_user.")?
-                    || notify_gc;
+                if let (Some(domain), Some(namespace)) = (domain, namespace) {
+                    if Self::is_frozen(tx, Domain(domain), namespace)
+                        .context("In unbind_keys_for_user.")?
+                    {
+                        if Self::quarantine_key(tx, key_id).context("In unbind_keys_for_user.")? {
+                            quarantined += 1;
+                        }
+                        continue;
+                    }
+                }
+                if Self::mark_unreferenced(&tx, key_id).context("In unbind_keys_for_user.")? {
+                    unbound += 1;
+                }
             }
-            Ok(()).do_gc(notify_gc)
+            Ok(unbound + quarantined).do_gc(unbound != 0)
         })
         .context("In unbind_keys_for_user.")
     }
@@ -2964,61 +4316,545 @@ impl KeystoreDB {
         let parameters = Self::load_key_parameters(key_id, &tx)
             .context("In load_key_components: Trying to load key parameters.")?;
 
-        let km_uuid = Self::get_key_km_uuid(&tx, key_id)
-            .context("In load_key_components: Trying to get KM uuid.")?;
+        let km_uuid = Self::get_key_km_uuid(&tx, key_id)
+            .context("In load_key_components: Trying to get KM uuid.")?;
+
+        Ok(KeyEntry {
+            id: key_id,
+            key_blob_info,
+            cert: cert_blob,
+            cert_chain: cert_chain_blob,
+            km_uuid,
+            parameters,
+            metadata,
+            pure_cert: !has_km_blob,
+        })
+    }
+
+    /// Returns a list of KeyDescriptors in the selected domain/namespace.
+    /// The key descriptors will have the domain, nspace, and alias field set.
+    /// The list is ordered by alias in binary collation order, i.e., by the bytes of the UTF-8
+    /// encoding, which does not depend on the SQLite version or the locale. Aliases are
+    /// unique within a namespace, so the order is total and a listing can be resumed
+    /// after the last alias that was returned, see `list_past_alias`.
+    /// Domain must be APP or SELINUX, the caller must make sure of that.
+    pub fn list(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        key_type: KeyType,
+    ) -> Result<Vec<KeyDescriptor>> {
+        let _wp = wd::watch_millis("KeystoreDB::list", 500);
+        self.list_past_alias(domain, namespace, key_type, None, None).context("In list.")
+    }
+
+    /// Like `list`, but returns only the keys whose alias sorts after `start_past_alias`, and
+    /// at most `limit` of them. Passing the last alias of one batch as `start_past_alias` of
+    /// the next pages through a namespace. The query is served by `keyentry_list_index`, so
+    /// the cost of a batch does not depend on how many keys precede it.
+    /// Domain must be APP or SELINUX, the caller must make sure of that.
+    pub fn list_past_alias(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        key_type: KeyType,
+        start_past_alias: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<KeyDescriptor>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_past_alias", 500);
+
+        // A negative limit means no limit to SQLite.
+        let limit = limit.map_or(-1, |limit| limit as i64);
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let query = format!(
+                "SELECT alias FROM persistent.keyentry
+                 WHERE domain = ?
+                 AND namespace = ?
+                 AND alias IS NOT NULL
+                 AND state = ?
+                 AND key_type = ?
+                 {}
+                 ORDER BY alias COLLATE BINARY ASC
+                 LIMIT ?;",
+                if start_past_alias.is_some() { "AND alias > ? COLLATE BINARY" } else { "" }
+            );
+            let mut stmt = tx.prepare(&query).context("In list_past_alias: Failed to prepare.")?;
+
+            let domain_id = domain.0 as u32;
+            let mut rows = match start_past_alias {
+                Some(start) => stmt.query(params![
+                    domain_id,
+                    namespace,
+                    KeyLifeCycle::Live,
+                    key_type,
+                    start,
+                    limit
+                ]),
+                None => {
+                    stmt.query(params![domain_id, namespace, KeyLifeCycle::Live, key_type, limit])
+                }
+            }
+            .context("In list_past_alias: Failed to query.")?;
+
+            let mut descriptors: Vec<KeyDescriptor> = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                descriptors.push(KeyDescriptor {
+                    domain,
+                    nspace: namespace,
+                    alias: Some(row.get(0).context("Trying to extract alias.")?),
+                    blob: None,
+                });
+                Ok(())
+            })
+            .context("In list_past_alias: Failed to extract rows.")?;
+            Ok(descriptors).no_gc()
+        })
+    }
+
+    /// Returns the number of keys that `list` would return for the selected domain/namespace.
+    /// Domain must be APP or SELINUX, the caller must make sure of that.
+    pub fn count_keys(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        key_type: KeyType,
+    ) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::count_keys", 500);
+
+        let count: i64 = self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            tx.query_row(
+                "SELECT COUNT(alias) FROM persistent.keyentry
+                 WHERE domain = ?
+                 AND namespace = ?
+                 AND alias IS NOT NULL
+                 AND state = ?
+                 AND key_type = ?;",
+                params![domain.0 as u32, namespace, KeyLifeCycle::Live, key_type],
+                |row| row.get(0),
+            )
+            .context("In count_keys: Failed to count keys.")
+            .no_gc()
+        })?;
+        Ok(count as usize)
+    }
+
+    /// Lists the live client keys with an alias that apps of the given user own, ordered by
+    /// namespace and alias. These are the keys that `unbind_keys_for_user` deletes.
+    pub fn list_keys_for_user(&mut self, user_id: u32) -> Result<Vec<KeyDescriptor>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_keys_for_user", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(&format!(
+                    "SELECT namespace, alias FROM persistent.keyentry
+                     WHERE key_type = ?
+                     AND domain = ?
+                     AND cast ( (namespace/{aid_user_offset}) as int) = ?
+                     AND alias IS NOT NULL
+                     AND state = ?
+                     ORDER BY namespace ASC, alias COLLATE BINARY ASC;",
+                    aid_user_offset = AID_USER_OFFSET
+                ))
+                .context("In list_keys_for_user: Failed to prepare.")?;
+            let mut rows = stmt
+                .query(params![KeyType::Client, Domain::APP.0 as u32, user_id, KeyLifeCycle::Live])
+                .context("In list_keys_for_user: Failed to query.")?;
+            let mut descriptors = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                descriptors.push(KeyDescriptor {
+                    domain: Domain::APP,
+                    nspace: row.get(0).context("Trying to extract namespace.")?,
+                    alias: Some(row.get(1).context("Trying to extract alias.")?),
+                    blob: None,
+                });
+                Ok(())
+            })
+            .context("In list_keys_for_user: Failed to extract rows.")?;
+            Ok(descriptors).no_gc()
+        })
+    }
+
+    /// Lists all live client keys with an alias that are backed by a KeyMint blob, ordered by
+    /// domain, namespace, and alias.
+    pub fn list_keys_with_km_blob(&mut self) -> Result<Vec<KeyDescriptor>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_keys_with_km_blob", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT k.domain, k.namespace, k.alias FROM persistent.keyentry AS k
+                     WHERE k.key_type = ?
+                     AND k.alias IS NOT NULL
+                     AND k.state = ?
+                     AND EXISTS (SELECT 1 FROM persistent.blobentry AS b
+                                 WHERE b.keyentryid = k.id AND b.subcomponent_type = ?)
+                     ORDER BY k.domain ASC, k.namespace ASC, k.alias COLLATE BINARY ASC;",
+                )
+                .context("In list_keys_with_km_blob: Failed to prepare.")?;
+            let mut rows = stmt
+                .query(params![KeyType::Client, KeyLifeCycle::Live, SubComponentType::KEY_BLOB])
+                .context("In list_keys_with_km_blob: Failed to query.")?;
+            let mut descriptors = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                descriptors.push(KeyDescriptor {
+                    domain: Domain(row.get(0).context("Trying to extract domain.")?),
+                    nspace: row.get(1).context("Trying to extract namespace.")?,
+                    alias: Some(row.get(2).context("Trying to extract alias.")?),
+                    blob: None,
+                });
+                Ok(())
+            })
+            .context("In list_keys_with_km_blob: Failed to extract rows.")?;
+            Ok(descriptors).no_gc()
+        })
+    }
+
+    /// Counts the live client keys of `domain` whose namespace lies in `namespaces`. If `exclude`
+    /// is given, the key with the same namespace and alias is not counted, so that rebinding an
+    /// alias does not count twice.
+    pub fn count_keys_in_namespaces(
+        &mut self,
+        domain: Domain,
+        namespaces: RangeInclusive<i64>,
+        exclude: Option<&KeyDescriptor>,
+    ) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::count_keys_in_namespaces", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            Self::count_keys_in_namespaces_internal(tx, domain, &namespaces, exclude).no_gc()
+        })
+    }
+
+    fn count_keys_in_namespaces_internal(
+        tx: &Transaction,
+        domain: Domain,
+        namespaces: &RangeInclusive<i64>,
+        exclude: Option<&KeyDescriptor>,
+    ) -> Result<usize> {
+        let (exclude_namespace, exclude_alias) =
+            exclude.map_or((None, None), |k| (Some(k.nspace), k.alias.clone()));
+        tx.query_row(
+            "SELECT COUNT(*) FROM persistent.keyentry
+                 WHERE domain = ?
+                 AND namespace BETWEEN ? AND ?
+                 AND state = ?
+                 AND key_type = ?
+                 AND NOT (namespace IS ? AND alias IS ?);",
+            params![
+                domain.0 as u32,
+                namespaces.start(),
+                namespaces.end(),
+                KeyLifeCycle::Live,
+                KeyType::Client,
+                exclude_namespace,
+                exclude_alias
+            ],
+            |row| row.get::<_, i64>(0),
+        )
+        .context("In count_keys_in_namespaces_internal: Failed to count keys.")
+        .map(|count| count as usize)
+    }
+
+    /// Returns up to `max_changes` entries of the key change journal for the given namespace
+    /// with a sequence number greater than `since`. Pass 0 to read the journal from the
+    /// beginning and `KeyChanges::next_sequence` of the previous result to continue reading.
+    /// Only the most recent `MAX_KEY_JOURNAL_ENTRIES` changes across all namespaces are
+    /// retained, `KeyChanges::truncated` tells the caller if it has fallen behind.
+    pub fn list_key_changes(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        since: i64,
+        max_changes: usize,
+    ) -> Result<KeyChanges> {
+        let _wp = wd::watch_millis("KeystoreDB::list_key_changes", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let (oldest, latest): (Option<i64>, Option<i64>) = tx
+                .query_row(
+                    "SELECT MIN(seq), MAX(seq) FROM persistent.keyjournal;",
+                    NO_PARAMS,
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .context("Failed to query journal bounds.")?;
+            let mut stmt = tx
+                .prepare(
+                    "SELECT seq, alias, event FROM persistent.keyjournal
+                     WHERE domain = ? AND namespace = ? AND seq > ?
+                     ORDER BY seq LIMIT ?;",
+                )
+                .context("Failed to prepare.")?;
+            let mut rows = stmt
+                .query(params![domain.0, namespace, since, max_changes as i64])
+                .context("Failed to query.")?;
+            let mut changes = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                changes.push(KeyChangeRecord {
+                    sequence: row.get(0).context("Trying to extract sequence number.")?,
+                    alias: row.get(1).context("Trying to extract alias.")?,
+                    kind: row.get(2).context("Trying to extract event.")?,
+                });
+                Ok(())
+            })
+            .context("Failed to extract rows.")?;
+            // If the result was cut short, the caller must continue after the last change
+            // returned. Otherwise, there is nothing to read for this namespace up to `latest`.
+            let next_sequence = match changes.last() {
+                Some(last) if changes.len() == max_changes => last.sequence,
+                _ => latest.unwrap_or(0),
+            };
+            // Changes were lost if entries following `since` were trimmed, or if the journal
+            // has been reset, e.g., because the database was recreated.
+            let truncated = match (oldest, latest) {
+                (Some(oldest), Some(latest)) => oldest > since + 1 || latest < since,
+                _ => since > 0,
+            };
+            Ok(KeyChanges { changes, next_sequence, truncated }).no_gc()
+        })
+        .context("In list_key_changes.")
+    }
+
+    /// Finds all live client keys whose usage expiration date lies before `now` and that were not
+    /// found expired before. Keys whose owner opted in to deletion on expiry are unbound, all
+    /// others are marked expired in their metadata, so that they are reported only once.
+    pub fn sweep_expired_keys(&mut self, now: DateTime) -> Result<Vec<ExpiredKey>> {
+        let _wp = wd::watch_millis("KeystoreDB::sweep_expired_keys", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT id, domain, namespace, alias FROM persistent.keyentry
+                     WHERE state = ?
+                     AND key_type = ?
+                     AND alias IS NOT NULL
+                     AND EXISTS (
+                        SELECT 1 FROM persistent.keyparameter
+                        WHERE keyentryid = keyentry.id AND tag = ? AND data < ?)
+                     AND NOT EXISTS (
+                        SELECT 1 FROM persistent.keymetadata
+                        WHERE keyentryid = keyentry.id AND tag = ?);",
+                )
+                .context("Failed to prepare.")?;
+            let mut rows = stmt
+                .query(params![
+                    KeyLifeCycle::Live,
+                    KeyType::Client,
+                    Tag::USAGE_EXPIRE_DATETIME.0,
+                    now.to_millis_epoch(),
+                    KeyMetaData::Expired,
+                ])
+                .context("Failed to query.")?;
+
+            let mut candidates: Vec<(i64, KeyDescriptor)> = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                let domain: u32 = row.get(1).context("Trying to extract domain.")?;
+                candidates.push((
+                    row.get(0).context("Trying to extract key id.")?,
+                    KeyDescriptor {
+                        domain: Domain(domain as i32),
+                        nspace: row.get(2).context("Trying to extract namespace.")?,
+                        alias: Some(row.get(3).context("Trying to extract alias.")?),
+                        blob: None,
+                    },
+                ));
+                Ok(())
+            })
+            .context("Failed to extract rows.")?;
+
+            let mut need_gc = false;
+            let mut expired_keys = Vec::new();
+            for (key_id, key) in candidates {
+                let metadata =
+                    KeyMetaData::load_from_db(key_id, tx).context("Trying to load metadata.")?;
+                let deleted = metadata.delete_on_expiry().copied().unwrap_or(false);
+                if deleted {
+                    need_gc |= Self::mark_unreferenced(tx, key_id)
+                        .context("Trying to mark the key unreferenced.")?;
+                } else {
+                    let mut metadata = KeyMetaData::new();
+                    metadata.add(KeyMetaEntry::Expired(now));
+                    metadata.store_in_db(key_id, tx).context("Trying to mark the key expired.")?;
+                }
+                expired_keys.push(ExpiredKey { key, deleted });
+            }
+            Ok(expired_keys).do_gc(need_gc)
+        })
+        .context("In sweep_expired_keys.")
+    }
+
+    /// Freezes the given namespace, see `namespace_freeze`. Returns false if it was frozen
+    /// already.
+    pub fn freeze_namespace(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        frozen_at: DateTime,
+    ) -> Result<bool> {
+        let _wp = wd::watch_millis("KeystoreDB::freeze_namespace", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let inserted = tx
+                .execute(
+                    "INSERT OR IGNORE INTO persistent.frozennamespace (domain, namespace, frozen_at)
+                        VALUES (?, ?, ?);",
+                    params![domain.0 as u32, namespace, frozen_at.to_millis_epoch()],
+                )
+                .context("Failed to insert frozen namespace.")?;
+            Ok(inserted == 1).no_gc()
+        })
+        .context("In freeze_namespace.")
+    }
+
+    /// Unfreezes the given namespace. Keys that were quarantined while the namespace was frozen
+    /// are unbound in the same transaction, so that they never become visible again. Returns
+    /// false if it was not frozen.
+    pub fn unfreeze_namespace(&mut self, domain: Domain, namespace: i64) -> Result<bool> {
+        let _wp = wd::watch_millis("KeystoreDB::unfreeze_namespace", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let unbound = tx
+                .execute(
+                    "UPDATE persistent.keyentry
+                     SET alias = NULL, domain = NULL, namespace = NULL, state = ?
+                     WHERE domain = ? AND namespace = ? AND state = ?;",
+                    params![
+                        KeyLifeCycle::Unreferenced,
+                        domain.0,
+                        namespace,
+                        KeyLifeCycle::Quarantined
+                    ],
+                )
+                .context("Failed to unbind quarantined keys.")?;
+            let deleted = tx
+                .execute(
+                    "DELETE FROM persistent.frozennamespace WHERE domain = ? AND namespace = ?;",
+                    params![domain.0 as u32, namespace],
+                )
+                .context("Failed to delete frozen namespace.")?;
+            Ok(deleted == 1).do_gc(unbound != 0)
+        })
+        .context("In unfreeze_namespace.")
+    }
+
+    /// Returns all frozen namespaces.
+    pub fn list_frozen_namespaces(&mut self) -> Result<Vec<(Domain, i64)>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_frozen_namespaces", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare("SELECT domain, namespace FROM persistent.frozennamespace;")
+                .context("Failed to prepare.")?;
+            let mut rows = stmt.query(NO_PARAMS).context("Failed to query.")?;
+            let mut namespaces = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                let domain: u32 = row.get(0).context("Trying to extract domain.")?;
+                namespaces.push((
+                    Domain(domain as i32),
+                    row.get(1).context("Trying to extract namespace.")?,
+                ));
+                Ok(())
+            })
+            .context("Failed to extract rows.")?;
+            Ok(namespaces).no_gc()
+        })
+        .context("In list_frozen_namespaces.")
+    }
+
+    /// Stores the given fs-verity certificate under the given name, replacing any certificate
+    /// of the same name. Fails with `INVALID_ARGUMENT` if this would exceed `max_certificates`.
+    pub fn put_fs_verity_certificate(
+        &mut self,
+        name: &str,
+        certificate: &[u8],
+        max_certificates: usize,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::put_fs_verity_certificate", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let others: i64 = tx
+                .query_row(
+                    "SELECT COUNT(*) FROM persistent.fsveritycert WHERE name != ?;",
+                    params![name],
+                    |row| row.get(0),
+                )
+                .context("Failed to count certificates.")?;
+            if others as usize >= max_certificates {
+                return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context("Too many certificates.");
+            }
+            tx.execute(
+                "INSERT OR REPLACE INTO persistent.fsveritycert (name, certificate) VALUES (?, ?);",
+                params![name, certificate],
+            )
+            .context("Failed to insert certificate.")?;
+            Ok(()).no_gc()
+        })
+        .context("In put_fs_verity_certificate.")
+    }
+
+    /// Returns all fs-verity certificates ordered by name.
+    pub fn list_fs_verity_certificates(&mut self) -> Result<Vec<(String, Vec<u8>)>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_fs_verity_certificates", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare("SELECT name, certificate FROM persistent.fsveritycert ORDER BY name;")
+                .context("Failed to prepare.")?;
+            let mut rows = stmt.query(NO_PARAMS).context("Failed to query.")?;
+            let mut certificates = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                certificates.push((
+                    row.get(0).context("Trying to extract name.")?,
+                    row.get(1).context("Trying to extract certificate.")?,
+                ));
+                Ok(())
+            })
+            .context("Failed to extract rows.")?;
+            Ok(certificates).no_gc()
+        })
+        .context("In list_fs_verity_certificates.")
+    }
+
+    /// Deletes the fs-verity certificate with the given name. Returns false if there was none.
+    pub fn delete_fs_verity_certificate(&mut self, name: &str) -> Result<bool> {
+        let _wp = wd::watch_millis("KeystoreDB::delete_fs_verity_certificate", 500);
 
-        Ok(KeyEntry {
-            id: key_id,
-            key_blob_info,
-            cert: cert_blob,
-            cert_chain: cert_chain_blob,
-            km_uuid,
-            parameters,
-            metadata,
-            pure_cert: !has_km_blob,
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let deleted = tx
+                .execute("DELETE FROM persistent.fsveritycert WHERE name = ?;", params![name])
+                .context("Failed to delete certificate.")?;
+            Ok(deleted == 1).no_gc()
         })
+        .context("In delete_fs_verity_certificate.")
     }
 
-    /// Returns a list of KeyDescriptors in the selected domain/namespace.
-    /// The key descriptors will have the domain, nspace, and alias field set.
-    /// Domain must be APP or SELINUX, the caller must make sure of that.
-    pub fn list(
+    /// Returns the ids of all keys of the given namespace.
+    pub fn list_key_ids_of_namespace(
         &mut self,
         domain: Domain,
         namespace: i64,
-        key_type: KeyType,
-    ) -> Result<Vec<KeyDescriptor>> {
-        let _wp = wd::watch_millis("KeystoreDB::list", 500);
+    ) -> Result<Vec<i64>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_key_ids_of_namespace", 500);
 
         self.with_transaction(TransactionBehavior::Deferred, |tx| {
             let mut stmt = tx
                 .prepare(
-                    "SELECT alias FROM persistent.keyentry
-                     WHERE domain = ?
-                     AND namespace = ?
-                     AND alias IS NOT NULL
-                     AND state = ?
-                     AND key_type = ?;",
+                    "SELECT id FROM persistent.keyentry
+                     WHERE domain = ? AND namespace = ? AND key_type = ?;",
                 )
-                .context("In list: Failed to prepare.")?;
-
+                .context("Failed to prepare.")?;
             let mut rows = stmt
-                .query(params![domain.0 as u32, namespace, KeyLifeCycle::Live, key_type])
-                .context("In list: Failed to query.")?;
-
-            let mut descriptors: Vec<KeyDescriptor> = Vec::new();
+                .query(params![domain.0, namespace, KeyType::Client])
+                .context("Failed to query.")?;
+            let mut key_ids = Vec::new();
             db_utils::with_rows_extract_all(&mut rows, |row| {
-                descriptors.push(KeyDescriptor {
-                    domain,
-                    nspace: namespace,
-                    alias: Some(row.get(0).context("Trying to extract alias.")?),
-                    blob: None,
-                });
+                key_ids.push(row.get(0).context("Trying to extract key id.")?);
                 Ok(())
             })
-            .context("In list: Failed to extract rows.")?;
-            Ok(descriptors).no_gc()
+            .context("Failed to extract rows.")?;
+            Ok(key_ids).no_gc()
         })
+        .context("In list_key_ids_of_namespace.")
     }
 
     /// Adds a grant to the grant table.
@@ -3048,7 +4884,7 @@ impl KeystoreDB {
             // But even if we load the access tuple by grant here, the permission
             // check denies the attempt to create a grant by grant descriptor.
             let (key_id, access_key_descriptor, _) =
-                Self::load_access_tuple(&tx, key, KeyType::Client, caller_uid)
+                Self::load_access_tuple(&tx, key, KeyType::Client, caller_uid, None)
                     .context("In grant")?;
 
             // Perform access control. It is vital that we return here if the permission
@@ -3059,6 +4895,8 @@ impl KeystoreDB {
             check_permission(&access_key_descriptor, &access_vector)
                 .context("In grant: check_permission failed.")?;
 
+            grant_cache::note_grant_write();
+
             let grant_id = if let Some(grant_id) = tx
                 .query_row(
                     "SELECT id FROM persistent.grant
@@ -3094,13 +4932,18 @@ impl KeystoreDB {
     }
 
     /// This function checks permissions like `grant` and `load_key_entry`
-    /// before removing a grant from the grant table.
+    /// before revoking a grant. If `access_vector` is given, only these permissions are
+    /// revoked and the grant is removed once its access vector becomes empty. Otherwise,
+    /// the grant is removed from the grant table entirely.
+    /// The `check_permission` callback is called with the permissions that are about to be
+    /// revoked.
     pub fn ungrant(
         &mut self,
         key: &KeyDescriptor,
         caller_uid: u32,
         grantee_uid: u32,
-        check_permission: impl Fn(&KeyDescriptor) -> Result<()>,
+        access_vector: Option<KeyPermSet>,
+        check_permission: impl Fn(&KeyDescriptor, &KeyPermSet) -> Result<()>,
     ) -> Result<()> {
         let _wp = wd::watch_millis("KeystoreDB::ungrant", 500);
 
@@ -3108,31 +4951,295 @@ impl KeystoreDB {
             // Load the key_id and complete the access control tuple.
             // We ignore the access vector here because grants cannot be granted.
             let (key_id, access_key_descriptor, _) =
-                Self::load_access_tuple(&tx, key, KeyType::Client, caller_uid)
+                Self::load_access_tuple(&tx, key, KeyType::Client, caller_uid, None)
                     .context("In ungrant.")?;
 
+            let existing: Option<(i64, i32)> = tx
+                .query_row(
+                    "SELECT id, access_vector FROM persistent.grant
+                    WHERE keyentryid = ? AND grantee = ?;",
+                    params![key_id, grantee_uid],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()
+                .context("In ungrant: Failed to get optional existing grant.")?;
+            let granted = existing.map_or(KeyPermSet(0), |(_, av)| KeyPermSet::from(av));
+            let revoked = access_vector.unwrap_or(granted);
+
             // Perform access control. We must return here if the permission
             // was denied. So do not touch the '?' at the end of this line.
-            check_permission(&access_key_descriptor)
-                .context("In grant: check_permission failed.")?;
+            check_permission(&access_key_descriptor, &revoked)
+                .context("In ungrant: check_permission failed.")?;
+
+            let (grant_id, remaining) = match existing {
+                Some((grant_id, _)) => (grant_id, KeyPermSet(granted.0 & !revoked.0)),
+                None => return Ok(()).no_gc(),
+            };
+
+            grant_cache::note_grant_write();
+            if remaining.0 == 0 {
+                tx.execute("DELETE FROM persistent.grant WHERE id = ?;", params![grant_id])
+                    .context("Failed to delete grant.")?;
+            } else {
+                tx.execute(
+                    "UPDATE persistent.grant SET access_vector = ? WHERE id = ?;",
+                    params![i32::from(remaining), grant_id],
+                )
+                .context("Failed to update grant.")?;
+            }
+
+            Ok(()).no_gc()
+        })
+    }
+
+    /// Grants the permissions in `access_vector` on all keys of the `Domain::SELINUX`
+    /// namespace `namespace` to `grantee_uid`, replacing any previous namespace grant.
+    /// Unlike per-key grants, namespace grants also cover keys that are created after the
+    /// grant, and they are not returned as grant descriptors. The grantee addresses the keys
+    /// by `Domain::SELINUX` descriptors instead.
+    /// The `check_permission` callback is called with the namespace as key descriptor without
+    /// alias, and it must check the grant permission like for per-key grants.
+    pub fn grant_namespace(
+        &mut self,
+        namespace: i64,
+        grantee_uid: u32,
+        access_vector: KeyPermSet,
+        check_permission: impl Fn(&KeyDescriptor, &KeyPermSet) -> Result<()>,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::grant_namespace", 500);
+
+        let key =
+            KeyDescriptor { domain: Domain::SELINUX, nspace: namespace, alias: None, blob: None };
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            // Perform access control. It is vital that we return here if the permission
+            // was denied. So do not touch that '?' at the end of the line.
+            check_permission(&key, &access_vector)
+                .context("In grant_namespace: check_permission failed.")?;
 
             tx.execute(
-                "DELETE FROM persistent.grant
-                WHERE keyentryid = ? AND grantee = ?;",
-                params![key_id, grantee_uid],
+                "INSERT OR REPLACE INTO persistent.namespacegrant
+                    (grantee, namespace, access_vector) VALUES (?, ?, ?);",
+                params![grantee_uid, namespace, i32::from(access_vector)],
+            )
+            .context("In grant_namespace: Failed to insert namespace grant.")?;
+            Ok(()).no_gc()
+        })
+    }
+
+    /// Revokes the namespace grant of `namespace` to `grantee_uid`, see `grant_namespace`.
+    /// The `check_permission` callback is called with the namespace as key descriptor without
+    /// alias and with the permissions that are about to be revoked.
+    pub fn ungrant_namespace(
+        &mut self,
+        namespace: i64,
+        grantee_uid: u32,
+        check_permission: impl Fn(&KeyDescriptor, &KeyPermSet) -> Result<()>,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::ungrant_namespace", 500);
+
+        let key =
+            KeyDescriptor { domain: Domain::SELINUX, nspace: namespace, alias: None, blob: None };
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let granted = Self::load_namespace_grant(tx, grantee_uid, namespace)
+                .context("In ungrant_namespace.")?;
+
+            // Perform access control. We must return here if the permission
+            // was denied. So do not touch the '?' at the end of this line.
+            check_permission(&key, &granted.unwrap_or(KeyPermSet(0)))
+                .context("In ungrant_namespace: check_permission failed.")?;
+
+            tx.execute(
+                "DELETE FROM persistent.namespacegrant WHERE grantee = ? AND namespace = ?;",
+                params![grantee_uid, namespace],
             )
-            .context("Failed to delete grant.")?;
+            .context("In ungrant_namespace: Failed to delete namespace grant.")?;
+            Ok(()).no_gc()
+        })
+    }
+
+    /// Lists all grants of live client keys. This is used to reconcile the grants with the
+    /// current SELinux policy and does not perform access control.
+    pub fn list_all_grants(&mut self) -> Result<Vec<GrantRecord>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_all_grants", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT g.id, g.grantee, g.access_vector, k.domain, k.namespace, k.alias
+                        FROM persistent.grant AS g
+                        INNER JOIN persistent.keyentry AS k ON g.keyentryid = k.id
+                        WHERE k.state = ? AND k.key_type = ?;",
+                )
+                .context("In list_all_grants: Failed to prepare.")?;
+            let mut rows = stmt
+                .query(params![KeyLifeCycle::Live, KeyType::Client])
+                .context("In list_all_grants: Failed to query.")?;
+            let mut grants = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                let access_vector: i32 = row.get(2).context("Failed to unpack access_vector.")?;
+                grants.push(GrantRecord {
+                    grant_id: row.get(0).context("Failed to unpack grant id.")?,
+                    grantee: row.get(1).context("Failed to unpack grantee.")?,
+                    access_vector: access_vector.into(),
+                    key: KeyDescriptor {
+                        domain: Domain(row.get(3).context("Failed to unpack domain.")?),
+                        nspace: row.get(4).context("Failed to unpack namespace.")?,
+                        alias: row.get(5).context("Failed to unpack alias.")?,
+                        blob: None,
+                    },
+                });
+                Ok(())
+            })
+            .context("In list_all_grants: Failed to extract rows.")?;
+            Ok(grants).no_gc()
+        })
+    }
+
+    /// Lists the grants of live client keys held by `grantee` as grant descriptors, i.e., key
+    /// descriptors with `Domain::GRANT` and the grant id as namespace, along with the access
+    /// vector of each grant. Access control is left to the caller.
+    pub fn list_grants_of_grantee(
+        &mut self,
+        grantee: u32,
+    ) -> Result<Vec<(KeyDescriptor, KeyPermSet)>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_grants_of_grantee", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT g.id, g.access_vector
+                        FROM persistent.grant AS g
+                        INNER JOIN persistent.keyentry AS k ON g.keyentryid = k.id
+                        WHERE g.grantee = ? AND k.state = ? AND k.key_type = ?
+                        ORDER BY g.id ASC;",
+                )
+                .context("In list_grants_of_grantee: Failed to prepare.")?;
+            let mut rows = stmt
+                .query(params![grantee as i64, KeyLifeCycle::Live, KeyType::Client])
+                .context("In list_grants_of_grantee: Failed to query.")?;
+            let mut grants = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                let access_vector: i32 = row.get(1).context("Failed to unpack access_vector.")?;
+                grants.push((
+                    KeyDescriptor {
+                        domain: Domain::GRANT,
+                        nspace: row.get(0).context("Failed to unpack grant id.")?,
+                        alias: None,
+                        blob: None,
+                    },
+                    access_vector.into(),
+                ));
+                Ok(())
+            })
+            .context("In list_grants_of_grantee: Failed to extract rows.")?;
+            Ok(grants).no_gc()
+        })
+    }
+
+    /// Lists the outstanding grants of the key indicated by `key`. The key is looked up like
+    /// in `grant` and the `check_permission` callback is called with the key descriptor under
+    /// which the key is bound, before any grant is disclosed.
+    pub fn list_grants_of_key(
+        &mut self,
+        key: &KeyDescriptor,
+        caller_uid: u32,
+        check_permission: impl Fn(&KeyDescriptor) -> Result<()>,
+    ) -> Result<Vec<GrantRecord>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_grants_of_key", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let (key_id, access_key_descriptor, _) =
+                Self::load_access_tuple(&tx, key, KeyType::Client, caller_uid, None)
+                    .context("In list_grants_of_key.")?;
+
+            check_permission(&access_key_descriptor)
+                .context("In list_grants_of_key: check_permission failed.")?;
+
+            let mut stmt = tx
+                .prepare(
+                    "SELECT id, grantee, access_vector FROM persistent.grant
+                        WHERE keyentryid = ?
+                        ORDER BY id ASC;",
+                )
+                .context("In list_grants_of_key: Failed to prepare.")?;
+            let mut rows =
+                stmt.query(params![key_id]).context("In list_grants_of_key: Failed to query.")?;
+            let mut grants = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                let access_vector: i32 = row.get(2).context("Failed to unpack access_vector.")?;
+                grants.push(GrantRecord {
+                    grant_id: row.get(0).context("Failed to unpack grant id.")?,
+                    grantee: row.get(1).context("Failed to unpack grantee.")?,
+                    key: access_key_descriptor.clone(),
+                    access_vector: access_vector.into(),
+                });
+                Ok(())
+            })
+            .context("In list_grants_of_key: Failed to extract rows.")?;
+            Ok(grants).no_gc()
+        })
+    }
+
+    /// Binds the key indicated by `key` to callers whose SELinux context matches `pattern`, see
+    /// `permission::check_client_context`, or removes the binding if `pattern` is None. The key
+    /// is looked up like in `grant` and the `check_permission` callback is called with the key
+    /// descriptor under which the key is bound, before the binding is changed.
+    pub fn set_client_context_pattern(
+        &mut self,
+        key: &KeyDescriptor,
+        caller_uid: u32,
+        pattern: Option<&str>,
+        check_permission: impl Fn(&KeyDescriptor) -> Result<()>,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::set_client_context_pattern", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let (key_id, access_key_descriptor, _) =
+                Self::load_access_tuple(&tx, key, KeyType::Client, caller_uid, None)
+                    .context("In set_client_context_pattern.")?;
+
+            check_permission(&access_key_descriptor)
+                .context("In set_client_context_pattern: check_permission failed.")?;
 
+            match pattern {
+                Some(pattern) => {
+                    let mut metadata = KeyMetaData::new();
+                    metadata.add(KeyMetaEntry::ClientContextPattern(pattern.to_string()));
+                    metadata.store_in_db(key_id, tx)
+                }
+                None => KeyMetaData::remove_from_db(key_id, tx, |entry| {
+                    matches!(entry, KeyMetaEntry::ClientContextPattern(_))
+                })
+                .map(|_| ()),
+            }
+            .context("In set_client_context_pattern: Failed to update the key metadata.")?;
             Ok(()).no_gc()
         })
     }
 
+    /// Deletes the grants with the given grant ids without access control. Returns the number
+    /// of grants deleted.
+    pub fn revoke_grants(&mut self, grant_ids: &[i64]) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::revoke_grants", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            grant_cache::note_grant_write();
+            let mut revoked = 0;
+            for grant_id in grant_ids {
+                revoked += tx
+                    .execute("DELETE FROM persistent.grant WHERE id = ?;", params![grant_id])
+                    .context("In revoke_grants: Failed to delete grant.")?;
+            }
+            Ok(revoked).no_gc()
+        })
+    }
+
     // Generates a random id and passes it to the given function, which will
     // try to insert it into a database.  If that insertion fails, retry;
     // otherwise return the id.
     fn insert_with_retry(inserter: impl Fn(i64) -> rusqlite::Result<usize>) -> Result<i64> {
         loop {
-            let newid: i64 = match random() {
+            let newid: i64 = match random().context("In insert_with_retry: Failed to get id.")? {
                 Self::UNASSIGNED_KEY_ID => continue, // UNASSIGNED_KEY_ID cannot be assigned.
                 i => i,
             };
@@ -3161,6 +5268,45 @@ impl KeystoreDB {
         ))
     }
 
+    /// Drops auth tokens that are superseded by more recently received tokens or that cannot
+    /// satisfy any key. Returns the number of auth tokens before and after coalescing.
+    pub fn coalesce_auth_tokens(&self) -> (usize, usize) {
+        self.perboot.coalesce_auth_tokens()
+    }
+
+    /// Returns an estimate of the memory held by the per-boot auth token table in bytes.
+    /// The table is shared by all database connections.
+    pub fn auth_tokens_size_bytes() -> usize {
+        perboot::PERBOOT_DB.auth_tokens_size_bytes()
+    }
+
+    /// Returns an estimate of the memory held by the grant caches of all databases in bytes.
+    pub fn grant_cache_size_bytes() -> usize {
+        grant_cache::size_bytes()
+    }
+
+    /// Drops the grant caches of all databases. They are filled again on demand. Returns the
+    /// number of grantees whose grants were dropped.
+    pub fn invalidate_grant_caches() -> usize {
+        grant_cache::invalidate_all()
+    }
+
+    /// Returns the heap memory held by SQLite in bytes. It is dominated by the statement and
+    /// page caches of all database connections, which also hold the attestation key chains
+    /// read during attestation.
+    pub fn sqlite_memory_size_bytes() -> usize {
+        // Safety: sqlite3_memory_used only reads a global counter.
+        unsafe { rusqlite::ffi::sqlite3_memory_used() }.max(0) as usize
+    }
+
+    /// Releases unused pages from the page caches of all database connections. They are read
+    /// again on demand. Returns the number of released bytes.
+    pub fn release_sqlite_memory() -> usize {
+        // Safety: sqlite3_release_memory is thread safe and only frees memory that no
+        // connection is using.
+        unsafe { rusqlite::ffi::sqlite3_release_memory(i32::MAX) }.max(0) as usize
+    }
+
     /// Find the newest auth token matching the given predicate.
     pub fn find_auth_token_entry<F>(&self, p: F) -> Option<(AuthTokenEntry, MonotonicRawTime)>
     where
@@ -3243,7 +5389,13 @@ mod tests {
     fn new_test_db() -> Result<KeystoreDB> {
         let conn = KeystoreDB::make_connection("file::memory:")?;
 
-        let mut db = KeystoreDB { conn, gc: None, perboot: Arc::new(perboot::PerbootDB::new()) };
+        let mut db = KeystoreDB {
+            conn,
+            gc: None,
+            perboot: Arc::new(perboot::PerbootDB::new()),
+            grant_cache: Default::default(),
+            gc_cursor: Default::default(),
+        };
         db.with_transaction(TransactionBehavior::Immediate, |tx| {
             KeystoreDB::init_tables(tx).context("Failed to initialize tables.").no_gc()
         })?;
@@ -3270,7 +5422,16 @@ mod tests {
         namespace: i64,
     ) -> Result<bool> {
         db.with_transaction(TransactionBehavior::Immediate, |tx| {
-            KeystoreDB::rebind_alias(tx, newid, alias, &domain, &namespace, KeyType::Client).no_gc()
+            KeystoreDB::rebind_alias(
+                tx,
+                newid,
+                alias,
+                &domain,
+                &namespace,
+                KeyType::Client,
+                GrantRebindPolicy::Keep,
+            )
+            .no_gc()
         })
         .context("In rebind_alias.")
     }
@@ -3302,9 +5463,9 @@ mod tests {
     // Ensure that we're using the "injected" random function, not the real one.
     #[test]
     fn test_mocked_random() {
-        let rand1 = random();
-        let rand2 = random();
-        let rand3 = random();
+        let rand1 = random().unwrap();
+        let rand2 = random().unwrap();
+        let rand3 = random().unwrap();
         if rand1 == rand2 {
             assert_eq!(rand2 + 1, rand3);
         } else {
@@ -3322,13 +5483,20 @@ mod tests {
             .prepare("SELECT name from persistent.sqlite_master WHERE type='table' ORDER BY name;")?
             .query_map(params![], |row| row.get(0))?
             .collect::<rusqlite::Result<Vec<String>>>()?;
-        assert_eq!(tables.len(), 6);
+        assert_eq!(tables.len(), 13);
         assert_eq!(tables[0], "blobentry");
         assert_eq!(tables[1], "blobmetadata");
-        assert_eq!(tables[2], "grant");
-        assert_eq!(tables[3], "keyentry");
-        assert_eq!(tables[4], "keymetadata");
-        assert_eq!(tables[5], "keyparameter");
+        assert_eq!(tables[2], "certchain");
+        assert_eq!(tables[3], "certificate");
+        assert_eq!(tables[4], "frozennamespace");
+        assert_eq!(tables[5], "fsveritycert");
+        assert_eq!(tables[6], "grant");
+        assert_eq!(tables[7], "keyentry");
+        assert_eq!(tables[8], "keyfingerprint");
+        assert_eq!(tables[9], "keyjournal");
+        assert_eq!(tables[10], "keymetadata");
+        assert_eq!(tables[11], "keyparameter");
+        assert_eq!(tables[12], "namespacegrant");
         Ok(())
     }
 
@@ -3384,6 +5552,41 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_coalesce_auth_tokens() -> Result<()> {
+        let db = new_test_db()?;
+        let make_token = |user_id, authenticator_id, authenticator_type| HardwareAuthToken {
+            challenge: 0,
+            userId: user_id,
+            authenticatorId: authenticator_id,
+            authenticatorType: kmhw_authenticator_type(authenticator_type),
+            timestamp: Timestamp { milliSeconds: 500 },
+            mac: String::from("mac").into_bytes(),
+        };
+        let insert = |token: HardwareAuthToken| {
+            db.perboot.insert_auth_token_entry(AuthTokenEntry::new(token, MonotonicRawTime::now()));
+            // Make sure that the next token is received strictly later.
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        };
+        let password = kmhw_authenticator_type::PASSWORD.0;
+        let fingerprint = kmhw_authenticator_type::FINGERPRINT.0;
+
+        // Superseded by the newer password token carrying the same user id.
+        insert(make_token(300, 0, password));
+        // Cannot satisfy any key.
+        insert(make_token(300, 301, kmhw_authenticator_type::NONE.0));
+        // Older than the password token, but not superseded, because of the other type.
+        insert(make_token(300, 302, fingerprint));
+        insert(make_token(300, 301, password));
+        // Not superseded, because it was received after the token that covers it.
+        insert(make_token(400, 401, password | fingerprint));
+        insert(make_token(400, 0, password));
+
+        assert_eq!(db.coalesce_auth_tokens(), (6, 4));
+        assert_eq!(db.coalesce_auth_tokens(), (4, 4));
+        Ok(())
+    }
+
     // utility function for test_auth_token_table_invariant()
     fn get_auth_tokens(db: &KeystoreDB) -> Vec<AuthTokenEntry> {
         db.perboot.get_all_auth_token_entries()
@@ -3523,10 +5726,217 @@ mod tests {
             20,
             &KEYSTORE_UUID,
         )?;
-        status = db.get_attestation_pool_status(0 /* expiration */, &KEYSTORE_UUID)?;
-        assert_eq!(status.attested, 4);
-        assert_eq!(status.unassigned, 1);
-        assert_eq!(status.total, 4);
+        status = db.get_attestation_pool_status(0 /* expiration */, &KEYSTORE_UUID)?;
+        assert_eq!(status.attested, 4);
+        assert_eq!(status.unassigned, 1);
+        assert_eq!(status.total, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_integrity_removes_orphans() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        assert_eq!(db.check_integrity(IntegrityCheck::Quick)?, IntegrityReport::default());
+
+        db.conn.execute("DELETE FROM persistent.keyentry WHERE id = ?;", params![key_id.id()])?;
+        let report = db.check_integrity(IntegrityCheck::Quick)?;
+        assert!(report.problems.is_empty());
+        assert!(report.orphans_removed > 0);
+        let count: i64 = db.conn.query_row(
+            "SELECT COUNT(*) FROM persistent.keyparameter;",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        assert_eq!(count, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild_derived_state() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        let intermediate: &[u8] = &[0x30, 0x03, 0xca, 0xca, 0xca];
+        let leaf1: &[u8] = &[0x30, 0x02, 0x01, 0x01];
+        let leaf2: &[u8] = &[0x30, 0x02, 0x02, 0x02];
+        let chain1 = [leaf1, intermediate].concat();
+        let chain2 = [leaf2, intermediate].concat();
+        db.set_blob(&KEY_ID_LOCK.get(3001), SubComponentType::CERT_CHAIN, Some(&chain1), None)?;
+        db.set_blob(&KEY_ID_LOCK.get(3002), SubComponentType::CERT_CHAIN, Some(&chain2), None)?;
+        let count = |db: &mut KeystoreDB, table: &str| -> Result<i64> {
+            Ok(db.conn.query_row(
+                &format!("SELECT COUNT(*) FROM persistent.{};", table),
+                NO_PARAMS,
+                |row| row.get(0),
+            )?)
+        };
+        assert_eq!(count(&mut db, "certificate")?, 3);
+
+        // Damage the derived state: a stale fingerprint of a certificate the key does not
+        // have, wrong reference counts, an unreferenced certificate, and a missing index.
+        db.conn.execute(
+            "INSERT INTO persistent.keyfingerprint (keyentryid, kind, digest) VALUES (?, ?, ?);",
+            params![key_id.id(), KeyFingerprintKind::Certificate, vec![1u8; 32]],
+        )?;
+        db.conn.execute("UPDATE persistent.certificate SET refcount = 5;", NO_PARAMS)?;
+        db.conn.execute(
+            "INSERT INTO persistent.certificate (digest, data, refcount) VALUES (?, ?, 1);",
+            params![vec![0u8; 32], vec![0x30u8, 0x00]],
+        )?;
+        db.conn.execute("DROP INDEX persistent.certchain_certificateid_index;", NO_PARAMS)?;
+
+        let report = db.rebuild_derived_state()?;
+        assert_eq!(
+            report,
+            RebuildReport {
+                fingerprinted_keys: 1,
+                corrected_refcounts: 4,
+                released_certificates: 1
+            }
+        );
+        // The test certificate cannot be parsed, so the key has no fingerprints.
+        assert_eq!(count(&mut db, "keyfingerprint")?, 0);
+        assert_eq!(count(&mut db, "certificate")?, 3);
+        assert!(db.dump_schema()?.drift().is_empty());
+
+        // Rebuilding intact state changes nothing.
+        let report = db.rebuild_derived_state()?;
+        assert_eq!((report.corrected_refcounts, report.released_certificates), (0, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild_indices() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        db.conn.execute(
+            "INSERT INTO persistent.keyfingerprint (keyentryid, kind, digest) VALUES (?, ?, ?);",
+            params![key_id.id(), KeyFingerprintKind::Certificate, vec![1u8; 32]],
+        )?;
+
+        // Make an index inconsistent with its table by changing its definition behind the back
+        // of SQLite.
+        let schema_version: i64 =
+            db.conn.query_row("PRAGMA persistent.schema_version;", NO_PARAMS, |row| row.get(0))?;
+        db.conn.execute_batch(&format!(
+            "PRAGMA persistent.writable_schema = ON;
+             UPDATE persistent.sqlite_master
+                 SET sql = 'CREATE INDEX keyfingerprint_digest_index ON keyfingerprint(kind)'
+                 WHERE name = 'keyfingerprint_digest_index';
+             PRAGMA persistent.schema_version = {};
+             PRAGMA persistent.writable_schema = OFF;",
+            schema_version + 1
+        ))?;
+        assert!(!db.check_integrity(IntegrityCheck::Full)?.problems.is_empty());
+
+        db.rebuild_indices()?;
+        assert!(db.check_integrity(IntegrityCheck::Full)?.problems.is_empty());
+        assert!(db.dump_schema()?.drift().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_quarantine_corrupt_database() -> Result<()> {
+        let temp_dir = TempDir::new("test_quarantine_corrupt_database_")?;
+        let path = temp_dir.path().join(KeystoreDB::PERSISTENT_DB_FILENAME);
+        std::fs::write(&path, vec![0xaa; 4096])?;
+
+        let e = KeystoreDB::new(temp_dir.path(), None).err().expect("Opened a corrupt database.");
+        assert!(KeystoreDB::is_corruption_error(&e));
+
+        KeystoreDB::quarantine_persistent_db(temp_dir.path())?;
+        assert!(temp_dir.path().join("persistent.sqlite.corrupt").exists());
+        let mut db = KeystoreDB::new(temp_dir.path(), None)?;
+        assert!(db.check_integrity(IntegrityCheck::Full)?.problems.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_next_superseded_blobs_in_batches() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_count = KeystoreDB::GC_CLEANUP_BATCH + 50;
+        for i in 0..key_count {
+            make_test_key_entry(&mut db, Domain::APP, 1, &format!("key{}", i), None)?;
+        }
+        assert_eq!(db.unbind_keys_for_namespace(Domain::APP, 1)?, key_count);
+
+        let mut invalidated = 0;
+        let mut blob_ids = vec![];
+        loop {
+            let (blobs, more_pending) = db.handle_next_superseded_blobs(&blob_ids, 20)?;
+            assert!(blobs.len() <= 20);
+            if blobs.is_empty() && !more_pending {
+                break;
+            }
+            invalidated += blobs.len();
+            blob_ids = blobs.into_iter().map(|(blob_id, _, _)| blob_id).collect();
+        }
+        assert_eq!(invalidated, key_count);
+
+        for table in &["keyentry", "keyparameter", "keymetadata", "blobentry"] {
+            let count: i64 = db.conn.query_row(
+                &format!("SELECT COUNT(*) FROM persistent.{};", table),
+                NO_PARAMS,
+                |row| row.get(0),
+            )?;
+            assert_eq!(count, 0, "{} is not empty", table);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe() -> Result<()> {
+        let mut db = new_test_db()?;
+        db.probe()?;
+        db.conn.execute("DROP TABLE persistent.keyentry;", NO_PARAMS)?;
+        assert!(db.probe().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_superseded_blob_scan_resumes_and_starts_over() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_count = KeystoreDB::GC_CLEANUP_BATCH + 50;
+        let mut key_ids = vec![];
+        for i in 0..key_count {
+            let key_id = make_test_key_entry(&mut db, Domain::APP, 1, &format!("key{}", i), None)?;
+            key_ids.push(key_id.id());
+        }
+        for key_id in &key_ids {
+            db.set_blob(
+                &KEY_ID_LOCK.get(*key_id),
+                SubComponentType::CERT,
+                Some(TEST_CERT_BLOB),
+                None,
+            )?;
+        }
+        let count_blobs = |db: &mut KeystoreDB| -> Result<usize> {
+            Ok(db.conn.query_row(
+                "SELECT COUNT(*) FROM persistent.blobentry WHERE NOT subcomponent_type = ?;",
+                params![SubComponentType::KEY_BLOB],
+                |row| row.get::<_, i64>(0),
+            )? as usize)
+        };
+
+        // Each key has a certificate chain and two certificates, one of them superseded. The
+        // first batch stops in the middle of the table.
+        assert_eq!(db.handle_next_superseded_blobs(&[], 0)?, (vec![], true));
+        assert_eq!(count_blobs(&mut db)?, 3 * key_count - KeystoreDB::GC_CLEANUP_BATCH);
+
+        // A blob before the cursor is superseded while the scan is under way. The scan
+        // continues after the cursor and removes the remaining superseded certificates.
+        db.set_blob(
+            &KEY_ID_LOCK.get(key_ids[0]),
+            SubComponentType::CERT_CHAIN,
+            Some(TEST_CERT_CHAIN_BLOB),
+            None,
+        )?;
+        assert_eq!(db.handle_next_superseded_blobs(&[], 0)?, (vec![], false));
+        assert_eq!(count_blobs(&mut db)?, 2 * key_count + 1);
+
+        // The next scan starts over and finds the superseded certificate chain.
+        assert_eq!(db.handle_next_superseded_blobs(&[], 0)?, (vec![], false));
+        assert_eq!(count_blobs(&mut db)?, 2 * key_count);
         Ok(())
     }
 
@@ -3633,8 +6043,8 @@ mod tests {
             (Some(Domain::APP), Some(42), None, Some(KEYSTORE_UUID))
         );
 
-        // Test that the first call to rebind_alias sets the alias.
-        rebind_alias(&mut db, &KEY_ID_LOCK.get(entries[0].id), "foo", Domain::APP, 42)?;
+        // Test that the first call to rebind_alias sets the alias. Nothing needs to be collected.
+        assert!(!rebind_alias(&mut db, &KEY_ID_LOCK.get(entries[0].id), "foo", Domain::APP, 42)?);
         let entries = get_keyentry(&db)?;
         assert_eq!(entries.len(), 2);
         assert_eq!(
@@ -3646,11 +6056,14 @@ mod tests {
             (Some(Domain::APP), Some(42), None, Some(KEYSTORE_UUID))
         );
 
-        // Test that the second call to rebind_alias also empties the old one.
-        rebind_alias(&mut db, &KEY_ID_LOCK.get(entries[1].id), "foo", Domain::APP, 42)?;
+        // Test that the second call to rebind_alias also empties the old one and marks it for
+        // garbage collection.
+        assert!(rebind_alias(&mut db, &KEY_ID_LOCK.get(entries[1].id), "foo", Domain::APP, 42)?);
         let entries = get_keyentry(&db)?;
         assert_eq!(entries.len(), 2);
         assert_eq!(extractor(&entries[0]), (None, None, None, Some(KEYSTORE_UUID)));
+        assert_eq!(entries[0].state, KeyLifeCycle::Unreferenced);
+        assert_eq!(entries[1].state, KeyLifeCycle::Live);
         assert_eq!(
             extractor(&entries[1]),
             (Some(Domain::APP), Some(42), Some("foo"), Some(KEYSTORE_UUID))
@@ -3687,6 +6100,179 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_sample_super_encrypted_blobs() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 10001, "key", None)?.id();
+        make_test_key_entry(&mut db, Domain::APP, AID_USER_OFFSET as i64 + 10001, "key", None)?;
+        make_bootlevel_key_entry(&mut db, Domain::APP, 10002, "bootlevel", false)?;
+
+        let sample = db.sample_super_encrypted_blobs(0, 5)?;
+        assert_eq!(sample.len(), 1);
+        let (sampled_key_id, blob, blob_metadata) = &sample[0];
+        assert_eq!(*sampled_key_id, key_id);
+        assert_eq!(blob, TEST_KEY_BLOB);
+        assert_eq!(blob_metadata.encrypted_by(), Some(&EncryptedBy::Password));
+        assert!(db.sample_super_encrypted_blobs(0, 0)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_keys_by_fingerprint() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_guard = make_test_key_entry(&mut db, Domain::APP, 1, "key", None)?;
+        let key_id = key_guard.id();
+        // The test certificate cannot be parsed, so the key is not indexed.
+        assert!(db.find_keys_by_fingerprint(KeyFingerprintKind::Certificate, &[1; 32])?.is_empty());
+        db.conn.execute(
+            "INSERT INTO persistent.keyfingerprint (keyentryid, kind, digest)
+             VALUES (?, ?, ?), (?, ?, ?);",
+            params![
+                key_id,
+                KeyFingerprintKind::Certificate,
+                vec![1u8; 32],
+                key_id,
+                KeyFingerprintKind::SubjectPublicKeyInfo,
+                vec![2u8; 32]
+            ],
+        )?;
+
+        let expected = vec![KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some("key".to_string()),
+            blob: None,
+        }];
+        assert_eq!(
+            db.find_keys_by_fingerprint(KeyFingerprintKind::Certificate, &[1; 32])?,
+            expected
+        );
+        assert_eq!(
+            db.find_keys_by_fingerprint(KeyFingerprintKind::SubjectPublicKeyInfo, &[2; 32])?,
+            expected
+        );
+        assert!(db
+            .find_keys_by_fingerprint(KeyFingerprintKind::SubjectPublicKeyInfo, &[1; 32])?
+            .is_empty());
+
+        // Replacing the certificate drops the stale fingerprints.
+        db.set_blob(&key_guard, SubComponentType::CERT, Some(b"other cert"), None)?;
+        assert!(db.find_keys_by_fingerprint(KeyFingerprintKind::Certificate, &[1; 32])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_and_revoke_grants() -> Result<()> {
+        let mut db = new_test_db()?;
+        db.conn.execute(
+            "INSERT INTO persistent.keyentry (id, key_type, domain, namespace, alias, state, km_uuid)
+                VALUES (1, 0, 0, 15, 'key', 1, ?), (2, 0, 2, 7, 'yek', 1, ?);",
+            params![KEYSTORE_UUID, KEYSTORE_UUID],
+        )?;
+        let app_key = KeyDescriptor {
+            domain: super::Domain::APP,
+            nspace: 15,
+            alias: Some("key".to_string()),
+            blob: None,
+        };
+        let selinux_key = KeyDescriptor {
+            domain: super::Domain::SELINUX,
+            nspace: 7,
+            alias: Some("yek".to_string()),
+            blob: None,
+        };
+        let app_grant =
+            db.grant(&app_key, 15, 12, key_perm_set![KeyPerm::use_()], |_, _| Ok(()))?;
+        let selinux_grant =
+            db.grant(&selinux_key, 15, 13, key_perm_set![KeyPerm::get_info()], |_, _| Ok(()))?;
+
+        let mut grants = db.list_all_grants()?;
+        grants.sort_by_key(|g| g.grantee);
+        assert_eq!(
+            grants,
+            vec![
+                GrantRecord {
+                    grant_id: app_grant.nspace,
+                    grantee: 12,
+                    key: app_key.clone(),
+                    access_vector: key_perm_set![KeyPerm::use_()],
+                },
+                GrantRecord {
+                    grant_id: selinux_grant.nspace,
+                    grantee: 13,
+                    key: selinux_key,
+                    access_vector: key_perm_set![KeyPerm::get_info()],
+                },
+            ]
+        );
+
+        assert_eq!(
+            db.list_grants_of_grantee(12)?,
+            vec![(app_grant.clone(), key_perm_set![KeyPerm::use_()])]
+        );
+        assert_eq!(db.list_grants_of_grantee(14)?, vec![]);
+
+        assert_eq!(
+            db.list_grants_of_key(&app_key, 15, |_| Ok(()))?,
+            vec![GrantRecord {
+                grant_id: app_grant.nspace,
+                grantee: 12,
+                key: app_key.clone(),
+                access_vector: key_perm_set![KeyPerm::use_()],
+            }]
+        );
+        assert!(db.list_grants_of_key(&app_key, 15, |_| Err(anyhow!(KsError::perm()))).is_err());
+
+        assert_eq!(db.revoke_grants(&[app_grant.nspace, 4711])?, 1);
+        assert_eq!(db.list_grants_of_grantee(12)?, vec![]);
+        let grants = db.list_all_grants()?;
+        assert_eq!(grants.len(), 1);
+        assert_eq!(grants[0].grant_id, selinux_grant.nspace);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_client_context_pattern() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?.0;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let load_pattern = |db: &mut KeystoreDB| {
+            db.with_transaction(TransactionBehavior::Deferred, |tx| {
+                KeyMetaData::load_from_db(key_id, tx).no_gc()
+            })
+            .map(|metadata| metadata.client_context_pattern().cloned())
+        };
+
+        assert!(db
+            .set_client_context_pattern(&key, 1, Some("u:r:vold:s0"), |_| Err(anyhow!(
+                KsError::perm()
+            )))
+            .is_err());
+        assert_eq!(load_pattern(&mut db)?, None);
+
+        db.set_client_context_pattern(&key, 1, Some("u:r:vold:s0"), |k| {
+            assert_eq!(k.domain, Domain::APP);
+            assert_eq!(k.nspace, 1);
+            Ok(())
+        })?;
+        assert_eq!(load_pattern(&mut db)?, Some("u:r:vold:s0".to_string()));
+
+        db.set_client_context_pattern(&key, 1, Some("u:r:*:s0"), |_| Ok(()))?;
+        assert_eq!(load_pattern(&mut db)?, Some("u:r:*:s0".to_string()));
+
+        db.set_client_context_pattern(&key, 1, None, |_| Ok(()))?;
+        assert_eq!(load_pattern(&mut db)?, None);
+
+        // Other uids do not find the key.
+        assert!(db.set_client_context_pattern(&key, 2, None, |_| Ok(())).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_grant_ungrant() -> Result<()> {
         const CALLER_UID: u32 = 15;
@@ -3833,8 +6419,8 @@ mod tests {
         println!("app_key {:?}", app_key);
         println!("selinux_key {:?}", selinux_key);
 
-        db.ungrant(&app_key, CALLER_UID, GRANTEE_UID, |_| Ok(()))?;
-        db.ungrant(&selinux_key, CALLER_UID, GRANTEE_UID, |_| Ok(()))?;
+        db.ungrant(&app_key, CALLER_UID, GRANTEE_UID, None, |_, _| Ok(()))?;
+        db.ungrant(&selinux_key, CALLER_UID, GRANTEE_UID, None, |_, _| Ok(()))?;
 
         Ok(())
     }
@@ -3887,6 +6473,56 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_cert_chain_deduplication() -> Result<()> {
+        let mut db = new_test_db()?;
+        let leaf1: &[u8] = &[0x30, 0x02, 0x01, 0x01];
+        let leaf2: &[u8] = &[0x30, 0x02, 0x02, 0x02];
+        let intermediate: &[u8] = &[0x30, 0x03, 0xca, 0xca, 0xca];
+        let chain1 = [leaf1, intermediate].concat();
+        let chain2 = [leaf2, intermediate].concat();
+        let key_id1 = KEY_ID_LOCK.get(3001);
+        let key_id2 = KEY_ID_LOCK.get(3002);
+        db.set_blob(&key_id1, SubComponentType::CERT_CHAIN, Some(&chain1), None)?;
+        db.set_blob(&key_id2, SubComponentType::CERT_CHAIN, Some(&chain2), None)?;
+
+        let count_certs = |db: &mut KeystoreDB| -> Result<i64> {
+            Ok(db.conn.query_row(
+                "SELECT COUNT(id) FROM persistent.certificate;",
+                NO_PARAMS,
+                |row| row.get(0),
+            )?)
+        };
+        let load_chain = |db: &mut KeystoreDB, key_id: i64| -> Result<Option<Vec<u8>>> {
+            db.with_transaction(TransactionBehavior::Deferred, |tx| {
+                let (_, _, _, chain) =
+                    KeystoreDB::load_blob_components(key_id, KeyEntryLoadBits::PUBLIC, tx)?;
+                Ok(chain).no_gc()
+            })
+        };
+
+        // The intermediate certificate is stored only once and the chains are reassembled.
+        assert_eq!(count_certs(&mut db)?, 3);
+        let stored_blobs: Vec<Vec<u8>> = db
+            .conn
+            .prepare("SELECT blob FROM persistent.blobentry;")?
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        // The chain blobs are kept for builds that predate the certificate store.
+        assert_eq!(stored_blobs, vec![chain1.clone(), chain2.clone()]);
+        assert_eq!(load_chain(&mut db, 3001)?, Some(chain1));
+        assert_eq!(load_chain(&mut db, 3002)?, Some(chain2.clone()));
+
+        // Removing a chain releases only the certificates no other chain refers to.
+        db.set_blob(&key_id1, SubComponentType::CERT_CHAIN, None, None)?;
+        assert_eq!(count_certs(&mut db)?, 2);
+        assert_eq!(load_chain(&mut db, 3001)?, None);
+        assert_eq!(load_chain(&mut db, 3002)?, Some(chain2));
+        db.set_blob(&key_id2, SubComponentType::CERT_CHAIN, None, None)?;
+        assert_eq!(count_certs(&mut db)?, 0);
+        Ok(())
+    }
+
     static TEST_ALIAS: &str = "my super duper key";
 
     #[test]
@@ -4214,7 +6850,277 @@ mod tests {
             .root_cause()
             .downcast_ref::<KsError>()
         );
-
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_grant_cache_follows_grant_table_writes() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let load_access_vector = |db: &mut KeystoreDB, granted_key: &KeyDescriptor| {
+            let access_vector = std::cell::Cell::new(None);
+            db.load_key_entry(granted_key, KeyType::Client, KeyEntryLoadBits::NONE, 2, |_, av| {
+                access_vector.set(av);
+                Ok(())
+            })
+            .map(|_| access_vector.get().unwrap())
+        };
+
+        let granted_key = db.grant(&key, 1, 2, key_perm_set![KeyPerm::use_()], |_, _| Ok(()))?;
+        assert_eq!(load_access_vector(&mut db, &granted_key)?, key_perm_set![KeyPerm::use_()]);
+
+        // Updating the grant must not be masked by the cached grants of the grantee.
+        let regranted_key =
+            db.grant(&key, 1, 2, key_perm_set![KeyPerm::use_(), KeyPerm::get_info()], |_, _| {
+                Ok(())
+            })?;
+        assert_eq!(granted_key, regranted_key);
+        assert_eq!(
+            load_access_vector(&mut db, &granted_key)?,
+            key_perm_set![KeyPerm::use_(), KeyPerm::get_info()]
+        );
+
+        // Revoking part of the access vector keeps the remaining permissions.
+        db.ungrant(&key, 1, 2, Some(key_perm_set![KeyPerm::get_info()]), |_, av| {
+            assert_eq!(*av, key_perm_set![KeyPerm::get_info()]);
+            Ok(())
+        })?;
+        assert_eq!(load_access_vector(&mut db, &granted_key)?, key_perm_set![KeyPerm::use_()]);
+
+        // Revoking the last permission removes the grant.
+        db.ungrant(&key, 1, 2, Some(key_perm_set![KeyPerm::use_()]), |_, _| Ok(()))?;
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+            load_access_vector(&mut db, &granted_key)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_namespace_grants() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::SELINUX, 102, TEST_ALIAS, None)?;
+        let key = KeyDescriptor {
+            domain: Domain::SELINUX,
+            nspace: 102,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let load_access_vector = |db: &mut KeystoreDB, caller_uid| {
+            let access_vector = std::cell::Cell::new(None);
+            db.load_key_entry(&key, KeyType::Client, KeyEntryLoadBits::NONE, caller_uid, |_, av| {
+                access_vector.set(av);
+                Ok(())
+            })
+            .map(|_| access_vector.get())
+        };
+        assert_eq!(load_access_vector(&mut db, 2)?, None);
+
+        db.grant_namespace(102, 2, key_perm_set![KeyPerm::use_()], |k, _| {
+            assert_eq!((k.domain, k.nspace, k.alias.as_ref()), (Domain::SELINUX, 102, None));
+            Ok(())
+        })?;
+        assert_eq!(load_access_vector(&mut db, 2)?, Some(key_perm_set![KeyPerm::use_()]));
+        // The grant is limited to its grantee and its namespace.
+        assert_eq!(load_access_vector(&mut db, 3)?, None);
+        make_test_key_entry(&mut db, Domain::SELINUX, 103, TEST_ALIAS, None)?;
+        let other_key = KeyDescriptor { nspace: 103, ..key.clone() };
+        db.load_key_entry(&other_key, KeyType::Client, KeyEntryLoadBits::NONE, 2, |_, av| {
+            assert_eq!(av, None);
+            Ok(())
+        })?;
+
+        // Granting again replaces the access vector.
+        db.grant_namespace(102, 2, key_perm_set![KeyPerm::get_info()], |_, _| Ok(()))?;
+        assert_eq!(load_access_vector(&mut db, 2)?, Some(key_perm_set![KeyPerm::get_info()]));
+
+        // A denied permission check leaves the grant untouched.
+        assert!(db.ungrant_namespace(102, 2, |_, _| Err(anyhow!("denied"))).is_err());
+        assert_eq!(load_access_vector(&mut db, 2)?, Some(key_perm_set![KeyPerm::get_info()]));
+        db.ungrant_namespace(102, 2, |_, av| {
+            assert_eq!(*av, key_perm_set![KeyPerm::get_info()]);
+            Ok(())
+        })?;
+        assert_eq!(load_access_vector(&mut db, 2)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unbind_keys_for_namespace_revokes_namespace_grants() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::SELINUX, 102, TEST_ALIAS, None)?;
+        db.grant_namespace(102, 2, key_perm_set![KeyPerm::use_()], |_, _| Ok(()))?;
+        assert_eq!(db.unbind_keys_for_namespace(Domain::SELINUX, 102)?, 1);
+
+        // A key created in the namespace after the clear is not covered by the old grant.
+        make_test_key_entry(&mut db, Domain::SELINUX, 102, TEST_ALIAS, None)?;
+        let key = KeyDescriptor {
+            domain: Domain::SELINUX,
+            nspace: 102,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        db.load_key_entry(&key, KeyType::Client, KeyEntryLoadBits::NONE, 2, |_, av| {
+            assert_eq!(av, None);
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_unbind_keys_for_namespace_revokes_grants_to_app() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        make_test_key_entry(&mut db, Domain::SELINUX, 102, TEST_ALIAS, None)?;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let granted_key = db.grant(&key, 1, 2, key_perm_set![KeyPerm::use_()], |_, _| Ok(()))?;
+        db.grant_namespace(102, 2, key_perm_set![KeyPerm::use_()], |_, _| Ok(()))?;
+
+        // Uninstalling the grantee revokes its grants, but leaves the granted keys alone.
+        assert_eq!(db.unbind_keys_for_namespace(Domain::APP, 2)?, 0);
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+            db.load_key_entry(&granted_key, KeyType::Client, KeyEntryLoadBits::NONE, 2, |_, _| {
+                Ok(())
+            })
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>()
+        );
+        let selinux_key = KeyDescriptor { domain: Domain::SELINUX, nspace: 102, ..key.clone() };
+        db.load_key_entry(&selinux_key, KeyType::Client, KeyEntryLoadBits::NONE, 2, |_, av| {
+            assert_eq!(av, None);
+            Ok(())
+        })?;
+        db.load_key_entry(&key, KeyType::Client, KeyEntryLoadBits::NONE, 1, |_, _| Ok(()))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_grants_on_rebind() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        let granted_key = db.grant(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 0,
+                alias: Some(TEST_ALIAS.to_string()),
+                blob: None,
+            },
+            1,
+            2,
+            key_perm_set![KeyPerm::use_()],
+            |_k, _av| Ok(()),
+        )?;
+
+        fn rebind(db: &mut KeystoreDB, grant_policy: GrantRebindPolicy) -> Result<i64> {
+            let key_id = db.create_key_entry(&Domain::APP, &1, KeyType::Client, &KEYSTORE_UUID)?;
+            db.with_transaction(TransactionBehavior::Immediate, |tx| {
+                KeystoreDB::rebind_alias(
+                    tx,
+                    &key_id,
+                    TEST_ALIAS,
+                    &Domain::APP,
+                    &1,
+                    KeyType::Client,
+                    grant_policy,
+                )
+                .no_gc()
+            })?;
+            Ok(key_id.id())
+        }
+
+        // A transferred grant refers to the new key.
+        let new_key_id = rebind(&mut db, GrantRebindPolicy::Transfer)?;
+        let (_, key_entry) = db.load_key_entry(
+            &granted_key,
+            KeyType::Client,
+            KeyEntryLoadBits::NONE,
+            2,
+            |_k, _av| Ok(()),
+        )?;
+        assert_eq!(key_entry.id(), new_key_id);
+
+        // An invalidated grant is gone.
+        rebind(&mut db, GrantRebindPolicy::Invalidate)?;
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+            db.load_key_entry(
+                &granted_key,
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                2,
+                |_k, _av| Ok(()),
+            )
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>()
+        );
+        let grant_count = |db: &mut KeystoreDB| -> Result<i64> {
+            Ok(db
+                .conn
+                .query_row("SELECT COUNT(*) FROM persistent.grant;", NO_PARAMS, |row| row.get(0))?)
+        };
+        assert_eq!(grant_count(&mut db)?, 0);
+
+        // A kept grant stays with the replaced key, which the grantee cannot load.
+        let granted_key = db.grant(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 0,
+                alias: Some(TEST_ALIAS.to_string()),
+                blob: None,
+            },
+            1,
+            2,
+            key_perm_set![KeyPerm::use_()],
+            |_k, _av| Ok(()),
+        )?;
+        rebind(&mut db, GrantRebindPolicy::Keep)?;
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+            db.load_key_entry(
+                &granted_key,
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                2,
+                |_k, _av| Ok(()),
+            )
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>()
+        );
+        assert_eq!(grant_count(&mut db)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_grant_rebind_policy_from_key_flags() -> Result<()> {
+        let transfer = GrantRebindPolicy::TRANSFER_GRANTS_ON_REBIND_FLAG;
+        let invalidate = GrantRebindPolicy::INVALIDATE_GRANTS_ON_REBIND_FLAG;
+        assert_eq!(GrantRebindPolicy::from_key_flags(None)?, GrantRebindPolicy::Keep);
+        assert_eq!(GrantRebindPolicy::from_key_flags(Some(0))?, GrantRebindPolicy::Keep);
+        assert_eq!(GrantRebindPolicy::from_key_flags(Some(transfer))?, GrantRebindPolicy::Transfer);
+        assert_eq!(
+            GrantRebindPolicy::from_key_flags(Some(invalidate))?,
+            GrantRebindPolicy::Invalidate
+        );
+        assert!(GrantRebindPolicy::from_key_flags(Some(transfer | invalidate)).is_err());
         Ok(())
     }
 
@@ -4623,6 +7529,101 @@ mod tests {
         );
     }
 
+    /// Turns a database of the current version into one of the given older version by undoing
+    /// the upgraders in reverse order.
+    fn revert_to_version(db: &mut KeystoreDB, version: u32) -> Result<()> {
+        db.with_transaction(TransactionBehavior::Immediate, |tx| {
+            if version < 3 {
+                // Store the deduplicated certificate chains verbatim again.
+                let blob_ids = tx
+                    .prepare("SELECT DISTINCT blobentryid FROM persistent.certchain;")?
+                    .query_map(NO_PARAMS, |row| row.get(0))?
+                    .collect::<rusqlite::Result<Vec<i64>>>()?;
+                for blob_id in blob_ids {
+                    let chain = cert_store::load_chain(tx, blob_id)?.unwrap();
+                    tx.execute(
+                        "UPDATE persistent.blobentry SET blob = ? WHERE id = ?;",
+                        params![chain, blob_id],
+                    )?;
+                }
+                tx.execute_batch(
+                    "DROP TABLE persistent.certchain; DROP TABLE persistent.certificate;",
+                )?;
+            }
+            if version < 2 {
+                tx.execute("DROP TABLE persistent.keyfingerprint;", NO_PARAMS)?;
+            }
+            versioning::create_or_get_version(tx, KeystoreDB::CURRENT_DB_VERSION)?;
+            versioning::update_version(tx, version)?;
+            Ok(()).no_gc()
+        })
+    }
+
+    #[test]
+    fn test_upgrade_from_each_version() -> Result<()> {
+        let leaf1: &[u8] = &[0x30, 0x02, 0x01, 0x01];
+        let leaf2: &[u8] = &[0x30, 0x02, 0x02, 0x02];
+        let intermediate: &[u8] = &[0x30, 0x03, 0xca, 0xca, 0xca];
+        let chain1 = [leaf1, intermediate].concat();
+        let chain2 = [leaf2, intermediate].concat();
+        let load_chain = |db: &mut KeystoreDB, key_id: i64| -> Result<Option<Vec<u8>>> {
+            db.with_transaction(TransactionBehavior::Deferred, |tx| {
+                let (_, _, _, chain) =
+                    KeystoreDB::load_blob_components(key_id, KeyEntryLoadBits::PUBLIC, tx)?;
+                Ok(chain).no_gc()
+            })
+        };
+
+        for version in 0..KeystoreDB::CURRENT_DB_VERSION {
+            let mut db = new_test_db()?;
+            let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?.0;
+            db.set_blob(&KEY_ID_LOCK.get(3001), SubComponentType::CERT_CHAIN, Some(&chain1), None)?;
+            db.set_blob(&KEY_ID_LOCK.get(3002), SubComponentType::CERT_CHAIN, Some(&chain2), None)?;
+            revert_to_version(&mut db, version)?;
+
+            db.with_transaction(TransactionBehavior::Immediate, |tx| {
+                versioning::upgrade_database(
+                    tx,
+                    KeystoreDB::CURRENT_DB_VERSION,
+                    KeystoreDB::UPGRADERS,
+                )
+                .no_gc()
+            })
+            .with_context(|| format!("Failed to upgrade from version {}.", version))?;
+
+            assert_eq!(db.dump_schema()?.drift(), Vec::<String>::new(), "version {}", version);
+            let (_, key_entry) = db.load_key_entry(
+                &KeyDescriptor {
+                    domain: Domain::APP,
+                    nspace: 1,
+                    alias: Some(TEST_ALIAS.to_string()),
+                    blob: None,
+                },
+                KeyType::Client,
+                KeyEntryLoadBits::BOTH,
+                1,
+                |_, _| Ok(()),
+            )?;
+            assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+            assert_eq!(load_chain(&mut db, 3001)?, Some(chain1.clone()));
+            assert_eq!(load_chain(&mut db, 3002)?, Some(chain2.clone()));
+            let certificates: i64 = db.conn.query_row(
+                "SELECT COUNT(*) FROM persistent.certificate;",
+                NO_PARAMS,
+                |row| row.get(0),
+            )?;
+            assert_eq!(certificates, 3, "version {}", version);
+            let chain_blob: Vec<u8> = db.conn.query_row(
+                "SELECT blob FROM persistent.blobentry
+                 WHERE keyentryid = 3001 AND subcomponent_type = ?;",
+                params![SubComponentType::CERT_CHAIN],
+                |row| row.get(0),
+            )?;
+            assert_eq!(chain_blob, chain1, "version {}", version);
+        }
+        Ok(())
+    }
+
     static KEY_LOCK_TEST_ALIAS: &str = "my super duper locked key";
 
     #[test]
@@ -4842,6 +7843,49 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn list_binary_collation_order() -> Result<()> {
+        let mut db = new_test_db()?;
+        for alias in &["b", "\u{e4}", "B", "a", "A", "a0", "_"] {
+            make_test_key_entry(&mut db, Domain::APP, 1, alias, None)?;
+        }
+        let aliases: Vec<String> =
+            db.list(Domain::APP, 1, KeyType::Client)?.into_iter().filter_map(|d| d.alias).collect();
+        assert_eq!(aliases, vec!["A", "B", "_", "a", "a0", "b", "\u{e4}"]);
+        Ok(())
+    }
+
+    #[test]
+    fn list_past_alias_in_batches() -> Result<()> {
+        let mut db = new_test_db()?;
+        for alias in &["b", "\u{e4}", "B", "a", "A", "a0", "_"] {
+            make_test_key_entry(&mut db, Domain::APP, 1, alias, None)?;
+        }
+        make_test_key_entry(&mut db, Domain::APP, 2, "a1", None)?;
+        let mut aliases = Vec::new();
+        let mut start_past_alias: Option<String> = None;
+        loop {
+            let batch = db.list_past_alias(
+                Domain::APP,
+                1,
+                KeyType::Client,
+                start_past_alias.as_deref(),
+                Some(3),
+            )?;
+            assert!(batch.len() <= 3);
+            match batch.last() {
+                Some(last) => start_past_alias = last.alias.clone(),
+                None => break,
+            }
+            aliases.extend(batch.into_iter().filter_map(|d| d.alias));
+        }
+        assert_eq!(aliases, vec!["A", "B", "_", "a", "a0", "b", "\u{e4}"]);
+        assert_eq!(db.count_keys(Domain::APP, 1, KeyType::Client)?, 7);
+        assert_eq!(db.count_keys(Domain::APP, 2, KeyType::Client)?, 1);
+        assert_eq!(db.count_keys(Domain::APP, 3, KeyType::Client)?, 0);
+        Ok(())
+    }
+
     #[test]
     fn list() -> Result<()> {
         let temp_dir = TempDir::new("list_test")?;
@@ -4986,6 +8030,130 @@ mod tests {
         batch_cert: Vec<u8>,
     }
 
+    #[test]
+    fn test_mark_attestation_keys_stale() -> Result<()> {
+        let mut db = new_test_db()?;
+        let expiration_date: i64 =
+            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64 + 10000;
+        let namespace: i64 = 30;
+        load_attestation_key_pool(&mut db, expiration_date, namespace, 0x01)?;
+        // A signed key that is not assigned.
+        let raw_public_key: Vec<u8> = vec![0x0b * 0x02, 0x0c * 0x02];
+        db.create_attestation_key_entry(&[0x02, 0x04], &raw_public_key, &[0x0a], &KEYSTORE_UUID)?;
+        db.store_signed_attestation_certificate_chain(
+            &raw_public_key,
+            &[0x1a],
+            &[0x06],
+            expiration_date,
+            &KEYSTORE_UUID,
+        )?;
+        // An unsigned key.
+        db.create_attestation_key_entry(&[0x03, 0x06], &[0x21, 0x24], &[0x0f], &KEYSTORE_UUID)?;
+
+        assert_eq!(db.mark_attestation_keys_stale()?, 2);
+        let status = db.get_attestation_pool_status(0, &KEYSTORE_UUID)?;
+        assert_eq!(status.unassigned, 0);
+        assert_eq!(status.total, 2);
+
+        // The stale key is retired on its next use.
+        assert!(db
+            .retrieve_attestation_key_and_cert_chain(Domain::APP, namespace, &KEYSTORE_UUID)?
+            .is_none());
+        let status = db.get_attestation_pool_status(0, &KEYSTORE_UUID)?;
+        assert_eq!(status.attested, 0);
+        assert_eq!(status.total, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_quota() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key1_id = make_test_key_entry(&mut db, Domain::APP, 10001, "key1", None)?.0;
+        make_test_key_entry(&mut db, Domain::APP, 10001, "key2", None)?;
+        // Superseded blobs awaiting garbage collection do not count against the quota.
+        db.set_blob(
+            &KEY_ID_LOCK.get(key1_id),
+            SubComponentType::CERT_CHAIN,
+            Some(TEST_CERT_CHAIN_BLOB),
+            None,
+        )?;
+        let key_bytes = TEST_KEY_BLOB.len() + TEST_CERT_BLOB.len() + TEST_CERT_CHAIN_BLOB.len();
+        let key = |alias: &str| KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 10001,
+            alias: Some(alias.to_string()),
+            blob: None,
+        };
+        let check = |db: &mut KeystoreDB, key: &KeyDescriptor, limits: quota::Limits| {
+            db.with_transaction(TransactionBehavior::Deferred, |tx| {
+                KeystoreDB::check_quota_internal(tx, key, 10, &limits).no_gc()
+            })
+        };
+
+        let max_keys = quota::Limits { max_keys: Some(2), ..Default::default() };
+        // Replacing a key does not count against the quota.
+        check(&mut db, &key("key1"), max_keys)?;
+        let e = check(&mut db, &key("key3"), max_keys).unwrap_err();
+        assert!(e.root_cause().is::<quota::QuotaExceeded>());
+
+        let max_bytes = quota::Limits { max_bytes: Some(2 * key_bytes + 10), ..Default::default() };
+        check(&mut db, &key("key3"), max_bytes)?;
+        let max_bytes = quota::Limits { max_bytes: Some(2 * key_bytes + 9), ..Default::default() };
+        assert!(check(&mut db, &key("key3"), max_bytes).is_err());
+
+        // Other namespaces are not affected.
+        let other = KeyDescriptor { nspace: 10002, ..key("key3") };
+        check(&mut db, &other, quota::Limits { max_keys: Some(1), ..Default::default() })?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_new_keys() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key = |domain: Domain, alias: &str| KeyDescriptor {
+            domain,
+            nspace: 10001,
+            alias: Some(alias.to_string()),
+            blob: None,
+        };
+        let keys = [key(Domain::APP, "key1"), key(Domain::BLOB, "key2"), key(Domain::APP, "key3")];
+        let params = make_test_params(None);
+        let blob_metadata = BlobMetaData::new();
+        let cert_info = CertificateInfo::new(Some(TEST_CERT_BLOB.to_vec()), None);
+        let metadata = KeyMetaData::new();
+        let new_keys: Vec<NewKey> = keys
+            .iter()
+            .map(|key| NewKey {
+                key,
+                params: &params,
+                blob_info: (TEST_KEY_BLOB, &blob_metadata),
+                cert_info: &cert_info,
+                metadata: &metadata,
+                grant_policy: GrantRebindPolicy::Keep,
+            })
+            .collect();
+
+        // The key with the invalid domain fails on its own.
+        let results = db.store_new_keys(&new_keys, &KEYSTORE_UUID)?;
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok());
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::INVALID_ARGUMENT)),
+            results[1].as_ref().unwrap_err().root_cause().downcast_ref::<KsError>()
+        );
+        assert!(results[2].is_ok());
+        drop(results);
+
+        let mut aliases: Vec<String> = db
+            .list(Domain::APP, 10001, KeyType::Client)?
+            .into_iter()
+            .filter_map(|key| key.alias)
+            .collect();
+        aliases.sort();
+        assert_eq!(aliases, vec!["key1".to_string(), "key3".to_string()]);
+        Ok(())
+    }
+
     fn load_attestation_key_pool(
         db: &mut KeystoreDB,
         expiration_date: i64,
@@ -5416,11 +8584,11 @@ mod tests {
         })
     }
 
-    pub fn random() -> i64 {
+    pub fn random() -> Result<i64> {
         RANDOM_COUNTER.with(|counter| {
             let result = *counter.borrow() / 2;
             *counter.borrow_mut() += 1;
-            result
+            Ok(result)
         })
     }
 
@@ -5444,11 +8612,11 @@ mod tests {
     #[test]
     fn test_unbind_keys_for_user() -> Result<()> {
         let mut db = new_test_db()?;
-        db.unbind_keys_for_user(1, false)?;
+        assert_eq!(db.unbind_keys_for_user(1, false)?, 0);
 
         make_test_key_entry(&mut db, Domain::APP, 210000, TEST_ALIAS, None)?;
         make_test_key_entry(&mut db, Domain::APP, 110000, TEST_ALIAS, None)?;
-        db.unbind_keys_for_user(2, false)?;
+        assert_eq!(db.unbind_keys_for_user(2, false)?, 1);
 
         assert_eq!(1, db.list(Domain::APP, 110000, KeyType::Client)?.len());
         assert_eq!(0, db.list(Domain::APP, 210000, KeyType::Client)?.len());
@@ -5459,6 +8627,125 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_unbind_super_encrypted_keys_for_user() -> Result<()> {
+        // This is what `IKeystoreMaintenance::resetUserSuperKeys` does to the database.
+        let mut db = new_test_db()?;
+        let pw: keystore2_crypto::Password = (&b"xyzabc"[..]).into();
+        let super_key = keystore2_crypto::generate_aes256_key()?;
+        let (encrypted_super_key, metadata) =
+            SuperKeyManager::encrypt_with_password(&super_key, &pw)?;
+        for user_id in 1..=2 {
+            db.store_super_key(
+                user_id,
+                &USER_SUPER_KEY,
+                &encrypted_super_key,
+                &metadata,
+                &KeyMetaData::new(),
+            )?;
+        }
+
+        // The test key entries are super encrypted.
+        make_test_key_entry(&mut db, Domain::APP, 110000, "encrypted", None)?;
+        make_test_key_entry(&mut db, Domain::APP, 210000, "encrypted", None)?;
+        let plain = make_test_key_entry(&mut db, Domain::APP, 110000, "plain", None)?;
+        db.set_blob(&plain, SubComponentType::KEY_BLOB, Some(TEST_KEY_BLOB), None)?;
+
+        // The super key and the super encrypted key of the user are deleted.
+        assert_eq!(db.unbind_keys_for_user(1, true)?, 2);
+        assert!(!db.key_exists(Domain::APP, 1, &USER_SUPER_KEY.alias, KeyType::Super)?);
+        let aliases: Vec<Option<String>> = db
+            .list(Domain::APP, 110000, KeyType::Client)?
+            .into_iter()
+            .map(|key| key.alias)
+            .collect();
+        assert_eq!(aliases, vec![Some("plain".to_string())]);
+
+        // Other users are not affected.
+        assert!(db.key_exists(Domain::APP, 2, &USER_SUPER_KEY.alias, KeyType::Super)?);
+        assert_eq!(1, db.list(Domain::APP, 210000, KeyType::Client)?.len());
+
+        // Resetting again finds nothing to delete.
+        assert_eq!(db.unbind_keys_for_user(1, true)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_keys_for_user_and_with_km_blob() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::APP, 210000, TEST_ALIAS, None)?;
+        make_test_key_entry(&mut db, Domain::APP, 110000, TEST_ALIAS, None)?;
+        make_test_key_entry(&mut db, Domain::SELINUX, 1, TEST_ALIAS, None)?;
+
+        let descriptor = |domain, nspace| KeyDescriptor {
+            domain,
+            nspace,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        assert_eq!(db.list_keys_for_user(1)?, vec![descriptor(Domain::APP, 110000)]);
+        assert_eq!(
+            db.list_keys_with_km_blob()?,
+            vec![
+                descriptor(Domain::APP, 110000),
+                descriptor(Domain::APP, 210000),
+                descriptor(Domain::SELINUX, 1),
+            ]
+        );
+
+        // Listing changes nothing.
+        db.unbind_keys_for_user(2, false)?;
+        assert_eq!(db.list_keys_for_user(2)?, vec![]);
+        assert_eq!(db.list_keys_for_user(1)?, vec![descriptor(Domain::APP, 110000)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_key_changes() -> Result<()> {
+        let mut db = new_test_db()?;
+        let change = |sequence, kind| KeyChangeRecord { sequence, alias: "key".to_string(), kind };
+
+        make_test_key_entry(&mut db, Domain::APP, 1, "key", None)?;
+        make_test_key_entry(&mut db, Domain::APP, 2, "key", None)?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, "key", None)?;
+        db.set_blob(&key_id, SubComponentType::CERT, Some(TEST_CERT_BLOB), None)?;
+        // Key blob upgrades are not journaled.
+        db.set_blob(&key_id, SubComponentType::KEY_BLOB, Some(TEST_KEY_BLOB), None)?;
+        db.unbind_keys_for_namespace(Domain::APP, 1)?;
+
+        let changes = db.list_key_changes(Domain::APP, 1, 0, 100)?;
+        assert_eq!(
+            changes.changes,
+            vec![
+                change(1, KeyChangeKind::Created),
+                change(3, KeyChangeKind::Updated),
+                change(4, KeyChangeKind::Updated),
+                change(5, KeyChangeKind::Deleted),
+            ]
+        );
+        assert_eq!(changes.next_sequence, 5);
+        assert!(!changes.truncated);
+
+        let changes = db.list_key_changes(Domain::APP, 1, 0, 2)?;
+        assert_eq!(changes.changes.len(), 2);
+        assert_eq!(changes.next_sequence, 3);
+        let changes = db.list_key_changes(Domain::APP, 1, 3, 2)?;
+        assert_eq!(changes.changes.len(), 2);
+        assert_eq!(changes.next_sequence, 5);
+
+        assert_eq!(
+            db.list_key_changes(Domain::APP, 2, 0, 100)?.changes,
+            vec![change(2, KeyChangeKind::Created)]
+        );
+        assert_eq!(
+            db.list_key_changes(Domain::APP, 1, 5, 100)?,
+            KeyChanges { changes: vec![], next_sequence: 5, truncated: false }
+        );
+        // A sequence number from the future indicates that the journal was reset.
+        assert!(db.list_key_changes(Domain::APP, 1, 7, 100)?.truncated);
+        Ok(())
+    }
+
     #[test]
     fn test_store_super_key() -> Result<()> {
         let mut db = new_test_db()?;
@@ -5495,6 +8782,230 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_lskf_removal_deadline() -> Result<()> {
+        let mut db = new_test_db()?;
+        let deadline = DateTime::from_millis_epoch(1000);
+
+        // Without a super key there is nothing to mark.
+        assert!(!db.set_lskf_removal_deadline(1, Some(deadline))?);
+
+        db.store_super_key(1, &USER_SUPER_KEY, b"blob", &BlobMetaData::new(), &KeyMetaData::new())?;
+        db.store_super_key(2, &USER_SUPER_KEY, b"blob", &BlobMetaData::new(), &KeyMetaData::new())?;
+        assert!(db.set_lskf_removal_deadline(1, Some(deadline))?);
+        let (_, key_entry) = db.load_super_key(&USER_SUPER_KEY, 1)?.unwrap();
+        assert_eq!(key_entry.metadata().lskf_removal_deadline(), Some(&deadline));
+        assert_eq!(db.list_users_with_lskf_removal_deadline()?, vec![1]);
+
+        assert!(db.set_lskf_removal_deadline(1, None)?);
+        let (_, key_entry) = db.load_super_key(&USER_SUPER_KEY, 1)?.unwrap();
+        assert_eq!(key_entry.metadata().lskf_removal_deadline(), None);
+        assert_eq!(db.list_users_with_lskf_removal_deadline()?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_and_remove_key_metadata() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        // The key id is locked by `key_id`, so the metadata is read directly.
+        let load_metadata = |db: &mut KeystoreDB| {
+            db.with_transaction(TransactionBehavior::Deferred, |tx| {
+                KeyMetaData::load_from_db(key_id.id(), tx).no_gc()
+            })
+        };
+
+        let mut metadata = KeyMetaData::new();
+        metadata.add(KeyMetaEntry::Expired(DateTime::from_millis_epoch(1000)));
+        metadata.add(KeyMetaEntry::CreatorUid(1));
+        db.insert_key_metadata(&key_id, &metadata)?;
+        let metadata = load_metadata(&mut db)?;
+        assert_eq!(metadata.expired(), Some(&DateTime::from_millis_epoch(1000)));
+        assert_eq!(metadata.creator_uid(), Some(&1));
+        assert_eq!(metadata.creation_date(), Some(&DateTime::from_millis_epoch(123456789)));
+
+        assert_eq!(
+            db.remove_key_metadata(&key_id, |entry| matches!(entry, KeyMetaEntry::Expired(_)))?,
+            1
+        );
+        let metadata = load_metadata(&mut db)?;
+        assert_eq!(metadata.expired(), None);
+        assert_eq!(metadata.creator_uid(), Some(&1));
+        assert_eq!(db.remove_key_metadata(&key_id, |_| false)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_freeze_namespace() -> Result<()> {
+        let mut db = new_test_db()?;
+        let now = DateTime::from_millis_epoch(1000);
+        assert!(db.freeze_namespace(Domain::APP, 10001, now)?);
+        assert!(!db.freeze_namespace(Domain::APP, 10001, now)?);
+        assert!(db.freeze_namespace(Domain::SELINUX, 10001, now)?);
+
+        let mut frozen = db.list_frozen_namespaces()?;
+        frozen.sort();
+        assert_eq!(frozen, vec![(Domain::APP, 10001), (Domain::SELINUX, 10001)]);
+
+        assert!(db.unfreeze_namespace(Domain::APP, 10001)?);
+        assert!(!db.unfreeze_namespace(Domain::APP, 10001)?);
+        assert_eq!(db.list_frozen_namespaces()?, vec![(Domain::SELINUX, 10001)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_clear_frozen_namespace_quarantines_keys() -> Result<()> {
+        let mut db = new_test_db()?;
+        let now = DateTime::from_millis_epoch(1000);
+        assert!(db.freeze_namespace(Domain::SELINUX, 10001, now)?);
+        let key_id = make_test_key_entry(&mut db, Domain::SELINUX, 10001, TEST_ALIAS, None)?.0;
+
+        // The clear succeeds, but the key is only hidden.
+        assert_eq!(db.unbind_keys_for_namespace(Domain::SELINUX, 10001)?, 1);
+        assert_eq!(db.list(Domain::SELINUX, 10001, KeyType::Client)?, vec![]);
+        assert_eq!(db.list_key_ids_of_namespace(Domain::SELINUX, 10001)?, vec![key_id]);
+
+        // Unfreezing unbinds the quarantined key before the namespace becomes usable again.
+        assert!(db.unfreeze_namespace(Domain::SELINUX, 10001)?);
+        assert_eq!(db.list_key_ids_of_namespace(Domain::SELINUX, 10001)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unbind_keys_for_user_quarantines_frozen_namespaces() -> Result<()> {
+        let mut db = new_test_db()?;
+        let frozen_uid = 2 * AID_USER_OFFSET + 10001;
+        let other_uid = 2 * AID_USER_OFFSET + 10002;
+        let frozen_key =
+            make_test_key_entry(&mut db, Domain::APP, frozen_uid as i64, TEST_ALIAS, None)?.0;
+        make_test_key_entry(&mut db, Domain::APP, other_uid as i64, TEST_ALIAS, None)?;
+        assert!(db.freeze_namespace(Domain::APP, frozen_uid as i64, DateTime::now()?)?);
+
+        assert_eq!(db.unbind_keys_for_user(2, false)?, 2);
+        assert_eq!(db.list(Domain::APP, frozen_uid as i64, KeyType::Client)?, vec![]);
+        assert_eq!(db.list_key_ids_of_namespace(Domain::APP, frozen_uid as i64)?, vec![frozen_key]);
+        assert_eq!(db.list_key_ids_of_namespace(Domain::APP, other_uid as i64)?, vec![]);
+
+        assert!(db.unfreeze_namespace(Domain::APP, frozen_uid as i64)?);
+        assert_eq!(db.list_key_ids_of_namespace(Domain::APP, frozen_uid as i64)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fs_verity_certificates() -> Result<()> {
+        let mut db = new_test_db()?;
+        db.put_fs_verity_certificate("fsv_ods", b"cert1", 2)?;
+        db.put_fs_verity_certificate("other", b"cert2", 2)?;
+        let e = db.put_fs_verity_certificate("one_too_many", b"cert3", 2).unwrap_err();
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::INVALID_ARGUMENT)),
+            e.root_cause().downcast_ref()
+        );
+        // Replacing a certificate does not count against the limit.
+        db.put_fs_verity_certificate("fsv_ods", b"cert3", 2)?;
+        assert_eq!(
+            db.list_fs_verity_certificates()?,
+            vec![
+                ("fsv_ods".to_string(), b"cert3".to_vec()),
+                ("other".to_string(), b"cert2".to_vec())
+            ]
+        );
+
+        assert!(db.delete_fs_verity_certificate("fsv_ods")?);
+        assert!(!db.delete_fs_verity_certificate("fsv_ods")?);
+        assert_eq!(
+            db.list_fs_verity_certificates()?,
+            vec![("other".to_string(), b"cert2".to_vec())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_note_key_used() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?.0;
+        db.note_key_used(key_id, DateTime::from_millis_epoch(1000))?;
+        db.note_key_used(key_id, DateTime::from_millis_epoch(2000))?;
+        let (_, entry) = db.load_key_entry(
+            &KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, alias: None, blob: None },
+            KeyType::Client,
+            KeyEntryLoadBits::NONE,
+            1,
+            |_, _| Ok(()),
+        )?;
+        assert_eq!(entry.metadata().last_used(), Some(&DateTime::from_millis_epoch(2000)));
+        assert_eq!(entry.metadata().creation_date(), Some(&DateTime::from_millis_epoch(123456789)));
+
+        // No metadata is recorded for a key entry that is gone.
+        db.unbind_key(
+            &KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, alias: None, blob: None },
+            KeyType::Client,
+            1,
+            |_, _| Ok(()),
+        )?;
+        db.note_key_used(key_id, DateTime::from_millis_epoch(3000))?;
+        let metadata = db.with_transaction(TransactionBehavior::Deferred, |tx| {
+            KeyMetaData::load_from_db(key_id, tx).no_gc()
+        })?;
+        assert_eq!(metadata.last_used(), Some(&DateTime::from_millis_epoch(2000)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sweep_expired_keys() -> Result<()> {
+        let mut db = new_test_db()?;
+        // The test vector sets a usage expiration date in 1970.
+        make_test_key_entry(&mut db, Domain::APP, 1, "kept", None)?;
+        let deleted_id = make_test_key_entry(&mut db, Domain::APP, 1, "deleted", None)?.id();
+        db.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let mut metadata = KeyMetaData::new();
+            metadata.add(KeyMetaEntry::DeleteOnExpiry(true));
+            metadata.store_in_db(deleted_id, tx).no_gc()
+        })?;
+
+        let mut expired_keys = db.sweep_expired_keys(DateTime::now()?)?;
+        expired_keys.sort_by_key(|e| e.key.alias.clone());
+        assert_eq!(
+            expired_keys.iter().map(|e| (e.key.alias.as_deref(), e.deleted)).collect::<Vec<_>>(),
+            vec![(Some("deleted"), true), (Some("kept"), false)]
+        );
+
+        // Keys are reported only once, and the deleted key is gone.
+        assert!(db.sweep_expired_keys(DateTime::now()?)?.is_empty());
+        let keys = db.list(Domain::APP, 1, KeyType::Client)?;
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].alias.as_deref(), Some("kept"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_provenance_round_trip() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, "key", None)?.id();
+        db.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let mut metadata = KeyMetaData::new();
+            metadata.add(KeyMetaEntry::Origin(KeyOrigin::Imported));
+            metadata.add(KeyMetaEntry::CreatorUid(10001));
+            metadata.add(KeyMetaEntry::KmVersion(100));
+            metadata.add(KeyMetaEntry::SuperKeyVersion(1));
+            metadata.store_in_db(key_id, tx).no_gc()
+        })?;
+
+        let (_, key_entry) = db.load_key_entry(
+            &KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, alias: None, blob: None },
+            KeyType::Client,
+            KeyEntryLoadBits::NONE,
+            1,
+            |_, _| Ok(()),
+        )?;
+        let metadata = key_entry.metadata();
+        assert_eq!(metadata.origin(), Some(&KeyOrigin::Imported));
+        assert_eq!(metadata.creator_uid(), Some(&10001));
+        assert_eq!(metadata.km_version(), Some(&100));
+        assert_eq!(metadata.super_key_version(), Some(&1));
+        Ok(())
+    }
+
     fn get_valid_statsd_storage_types() -> Vec<MetricsStorage> {
         vec![
             MetricsStorage::KEY_ENTRY,
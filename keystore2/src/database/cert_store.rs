@@ -0,0 +1,250 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements content-addressed storage of the certificates that make up
+//! certificate chains. Intermediate CA certificates are shared by many keys, so each distinct
+//! certificate is stored once in the `certificate` table, keyed by its SHA-256 digest, and
+//! the `certchain` table links each certificate chain blob entry to its certificates in order.
+//!
+//! The chain blob itself is kept verbatim in the `blobentry` table for now, so that a build
+//! that predates the certificate store, i.e., one with database version 2, still reads complete
+//! chains after a rollback. A later database version may clear the blobs once such rollbacks
+//! are no longer supported. Readers must check `load_chain` before falling back to the blob,
+//! because chains that cannot be split into DER elements are not linked.
+//!
+//! Each certificate carries the number of links referring to it. Whoever deletes a chain blob
+//! entry calls `release_chain` first, which drops the links of that entry and deletes the
+//! certificates that are no longer referenced. This only touches the rows of the entry, so
+//! deleting a chain never scans the store. `collect_garbage` is a full scan for recovery.
+
+use anyhow::{anyhow, Context, Result};
+use keystore2_crypto::sha256;
+use rusqlite::{params, Transaction, NO_PARAMS};
+
+/// Returns the length of the DER element at the beginning of `data` including its header,
+/// or None if `data` does not start with a well formed definite length DER element.
+fn der_element_len(data: &[u8]) -> Option<usize> {
+    let first_len_byte = *data.get(1)?;
+    let (header_len, content_len) = if first_len_byte & 0x80 == 0 {
+        (2, first_len_byte as usize)
+    } else {
+        let len_bytes = (first_len_byte & 0x7f) as usize;
+        // Zero is the indefinite length form, which is not allowed in DER.
+        if len_bytes == 0 || len_bytes > std::mem::size_of::<u32>() {
+            return None;
+        }
+        let content_len =
+            data.get(2..2 + len_bytes)?.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        (2 + len_bytes, content_len)
+    };
+    let len = header_len.checked_add(content_len)?;
+    if len > data.len() {
+        None
+    } else {
+        Some(len)
+    }
+}
+
+/// Splits a certificate chain, i.e., a concatenation of DER encoded certificates, into its
+/// certificates. Returns None if `chain` is empty or if any element is not a DER SEQUENCE.
+pub fn split_chain(chain: &[u8]) -> Option<Vec<&[u8]>> {
+    const DER_SEQUENCE: u8 = 0x30;
+    let mut certs = Vec::new();
+    let mut rest = chain;
+    while !rest.is_empty() {
+        if rest[0] != DER_SEQUENCE {
+            return None;
+        }
+        let len = der_element_len(rest)?;
+        certs.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+    if certs.is_empty() {
+        None
+    } else {
+        Some(certs)
+    }
+}
+
+/// Stores the certificates of `chain` and links them to the blob entry `blob_id`.
+/// Returns false if `chain` could not be split into certificates, in which case nothing
+/// was stored and the caller must keep the chain in the blob entry.
+pub fn store_chain(tx: &Transaction, blob_id: i64, chain: &[u8]) -> Result<bool> {
+    let certs = match split_chain(chain) {
+        Some(certs) => certs,
+        None => return Ok(false),
+    };
+    for (position, cert) in certs.into_iter().enumerate() {
+        let digest = sha256(cert).context("In store_chain: Failed to compute digest.")?;
+        tx.execute(
+            "INSERT OR IGNORE INTO persistent.certificate (digest, data, refcount)
+             VALUES (?, ?, 0);",
+            params![digest, cert],
+        )
+        .context("In store_chain: Failed to insert certificate.")?;
+        let cert_id: i64 = tx
+            .query_row(
+                "SELECT id FROM persistent.certificate WHERE digest = ?;",
+                params![digest],
+                |row| row.get(0),
+            )
+            .context("In store_chain: Failed to look up certificate.")?;
+        tx.execute(
+            "UPDATE persistent.certificate SET refcount = refcount + 1 WHERE id = ?;",
+            params![cert_id],
+        )
+        .context("In store_chain: Failed to reference certificate.")?;
+        tx.execute(
+            "INSERT INTO persistent.certchain (blobentryid, position, certificateid)
+             VALUES (?, ?, ?);",
+            params![blob_id, position as i64, cert_id],
+        )
+        .context("In store_chain: Failed to link certificate.")?;
+    }
+    Ok(true)
+}
+
+/// Reassembles the certificate chain linked to the blob entry `blob_id`.
+/// Returns None if the blob entry has no linked certificates.
+pub fn load_chain(tx: &Transaction, blob_id: i64) -> Result<Option<Vec<u8>>> {
+    let mut stmt = tx
+        .prepare(
+            "SELECT certchain.certificateid, certificate.data FROM persistent.certchain
+             LEFT JOIN persistent.certificate ON certificate.id = certchain.certificateid
+             WHERE certchain.blobentryid = ?
+             ORDER BY certchain.position;",
+        )
+        .context("In load_chain: Failed to prepare statement.")?;
+    let certs = stmt
+        .query_map(params![blob_id], |row| Ok((row.get(0)?, row.get(1)?)))
+        .context("In load_chain: Failed to query certificates.")?
+        .collect::<rusqlite::Result<Vec<(i64, Option<Vec<u8>>)>>>()
+        .context("In load_chain: Failed to read certificates.")?;
+    if certs.is_empty() {
+        return Ok(None);
+    }
+    let mut chain = Vec::new();
+    for (cert_id, cert) in certs {
+        match cert {
+            Some(cert) => chain.extend_from_slice(&cert),
+            None => {
+                return Err(anyhow!(
+                    "In load_chain: Blob entry {} links missing certificate {}.",
+                    blob_id,
+                    cert_id
+                ))
+            }
+        }
+    }
+    Ok(Some(chain))
+}
+
+/// Releases the certificates linked to the blob entry `blob_id`, which is about to be deleted,
+/// and deletes the certificates that are no longer referenced. Returns the number of deleted
+/// certificates.
+pub fn release_chain(tx: &Transaction, blob_id: i64) -> Result<usize> {
+    let cert_ids = tx
+        .prepare("SELECT certificateid FROM persistent.certchain WHERE blobentryid = ?;")
+        .context("In release_chain: Failed to prepare statement.")?
+        .query_map(params![blob_id], |row| row.get(0))
+        .context("In release_chain: Failed to query links.")?
+        .collect::<rusqlite::Result<Vec<i64>>>()
+        .context("In release_chain: Failed to read links.")?;
+    if cert_ids.is_empty() {
+        return Ok(0);
+    }
+    tx.execute("DELETE FROM persistent.certchain WHERE blobentryid = ?;", params![blob_id])
+        .context("In release_chain: Failed to delete links.")?;
+    let mut deleted = 0;
+    // A chain may link the same certificate more than once, so each link is released.
+    for cert_id in cert_ids {
+        tx.execute(
+            "UPDATE persistent.certificate SET refcount = refcount - 1 WHERE id = ?;",
+            params![cert_id],
+        )
+        .context("In release_chain: Failed to release certificate.")?;
+        deleted += tx
+            .execute(
+                "DELETE FROM persistent.certificate WHERE id = ? AND refcount <= 0;",
+                params![cert_id],
+            )
+            .context("In release_chain: Failed to delete certificate.")?;
+    }
+    Ok(deleted)
+}
+
+/// Releases the links of all blob entries that no longer exist and deletes all
+/// certificates that are no longer referenced. Returns the number of deleted certificates.
+/// This scans the entire store, so it is only used to recover a damaged store, see
+/// `recount_references`. Regular deletions use `release_chain`.
+pub fn collect_garbage(tx: &Transaction) -> Result<usize> {
+    tx.execute(
+        "UPDATE persistent.certificate SET refcount = refcount - (
+             SELECT COUNT(*) FROM persistent.certchain
+             WHERE certchain.certificateid = certificate.id
+             AND certchain.blobentryid NOT IN (SELECT id FROM persistent.blobentry)
+         )
+         WHERE id IN (
+             SELECT certificateid FROM persistent.certchain
+             WHERE blobentryid NOT IN (SELECT id FROM persistent.blobentry)
+         );",
+        NO_PARAMS,
+    )
+    .context("In collect_garbage: Failed to release certificates.")?;
+    tx.execute(
+        "DELETE FROM persistent.certchain
+         WHERE blobentryid NOT IN (SELECT id FROM persistent.blobentry);",
+        NO_PARAMS,
+    )
+    .context("In collect_garbage: Failed to delete links.")?;
+    tx.execute("DELETE FROM persistent.certificate WHERE refcount <= 0;", NO_PARAMS)
+        .context("In collect_garbage: Failed to delete certificates.")
+}
+
+/// Recomputes the reference count of every certificate from the links that refer to it and
+/// deletes the certificates that are no longer referenced. Returns the number of corrected
+/// reference counts and the number of deleted certificates.
+pub fn recount_references(tx: &Transaction) -> Result<(usize, usize)> {
+    let corrected = tx
+        .execute(
+            "UPDATE persistent.certificate SET refcount = (
+                 SELECT COUNT(*) FROM persistent.certchain
+                 WHERE certchain.certificateid = certificate.id
+             )
+             WHERE refcount != (
+                 SELECT COUNT(*) FROM persistent.certchain
+                 WHERE certchain.certificateid = certificate.id
+             );",
+            NO_PARAMS,
+        )
+        .context("In recount_references: Failed to recount references.")?;
+    let deleted = collect_garbage(tx).context("In recount_references.")?;
+    Ok((corrected, deleted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_chain_test() {
+        let chain = [0x30, 0x01, 0xaa, 0x30, 0x81, 0x02, 0xbb, 0xcc];
+        assert_eq!(split_chain(&chain), Some(vec![&chain[..3], &chain[3..]]));
+        // Empty chains, truncated elements, and other elements are not split.
+        assert_eq!(split_chain(&[]), None);
+        assert_eq!(split_chain(&chain[..7]), None);
+        assert_eq!(split_chain(b"my test cert_chain"), None);
+        assert_eq!(split_chain(&[0x30, 0x80, 0x00, 0x00]), None);
+    }
+}
@@ -0,0 +1,428 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module populates a database with realistic content for tests and benchmarks.
+//! `populate` fills a database according to a `FixtureSpec` with app and SELinux namespaces
+//! holding a mix of asymmetric and symmetric keys, grants to other apps, auth tokens, and the
+//! leftovers that accumulate on long-lived devices, i.e., entries of interrupted key creations
+//! and superseded key blobs awaiting garbage collection.
+//!
+//! All content is derived from `FixtureSpec::seed`, so that the same spec yields the same
+//! aliases, parameters, and blobs. Key and grant ids are drawn by the database and are only
+//! deterministic where the database uses a deterministic id source, as in unit tests.
+//! The module is compiled for tests and with the `db_fixtures` feature.
+
+use super::{
+    grant_cache, BlobMetaData, BlobMetaEntry, DateTime, DoGc, GrantRebindPolicy, KeyLifeCycle,
+    KeyMetaData, KeyMetaEntry, KeyType, KeystoreDB, SubComponentType, TransactionBehavior,
+    KEYSTORE_UUID,
+};
+use crate::key_parameter::{KeyParameter, KeyParameterValue};
+use crate::key_perm_set;
+use crate::permission::{KeyPerm, KeyPermSet};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, BlockMode::BlockMode, Digest::Digest, EcCurve::EcCurve,
+    HardwareAuthToken::HardwareAuthToken, HardwareAuthenticatorType::HardwareAuthenticatorType,
+    KeyPurpose::KeyPurpose, PaddingMode::PaddingMode, SecurityLevel::SecurityLevel,
+};
+use android_hardware_security_secureclock::aidl::android::hardware::security::secureclock::Timestamp::Timestamp;
+use android_system_keystore2::aidl::android::system::keystore2::Domain::Domain;
+use anyhow::{Context, Result};
+use rusqlite::params;
+
+/// The first app uid used for generated app namespaces.
+const FIRST_APP_UID: i64 = 10000;
+/// The first namespace used for generated SELinux namespaces.
+const FIRST_SELINUX_NAMESPACE: i64 = 100;
+
+/// Describes the content of a generated database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixtureSpec {
+    /// Seed of all generated content.
+    pub seed: u64,
+    /// Number of namespaces. Every eighth namespace is a SELinux namespace, all others
+    /// are app namespaces.
+    pub namespaces: u32,
+    /// Number of keys in each namespace.
+    pub keys_per_namespace: u32,
+    /// Maximal number of grants of each app key. The actual number is drawn per key.
+    pub max_grants_per_key: u32,
+    /// Number of auth tokens inserted into the per-boot database.
+    pub auth_tokens: u32,
+    /// Number of key entries left over from interrupted key creations.
+    pub leftover_entries: u32,
+    /// Number of superseded key blobs that still await garbage collection.
+    pub superseded_blobs: u32,
+}
+
+impl Default for FixtureSpec {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            namespaces: 50,
+            keys_per_namespace: 10,
+            max_grants_per_key: 2,
+            auth_tokens: 20,
+            leftover_entries: 10,
+            superseded_blobs: 20,
+        }
+    }
+}
+
+/// Counts of the generated content.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FixtureSummary {
+    /// Number of live keys.
+    pub keys: usize,
+    /// Number of grants.
+    pub grants: usize,
+    /// Number of auth tokens.
+    pub auth_tokens: usize,
+    /// Number of key entries left over from interrupted key creations.
+    pub leftover_entries: usize,
+    /// Number of superseded key blobs.
+    pub superseded_blobs: usize,
+}
+
+/// A small deterministic pseudo random number generator (SplitMix64). The fixtures must not
+/// depend on the algorithm behind the `rand` crate's seedable generators, which may change
+/// between versions.
+struct FixtureRng(u64);
+
+impl FixtureRng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a value in `0..bound`. `bound` must not be 0.
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next_u64() as u8).collect()
+    }
+}
+
+/// The kinds of keys found in typical databases, with the parameters and blob sizes that
+/// KeyMint produces for them.
+#[derive(Clone, Copy)]
+enum KeyProfile {
+    EcSigning,
+    RsaSigning,
+    AesGcm,
+    HmacSha256,
+}
+
+impl KeyProfile {
+    fn pick(rng: &mut FixtureRng) -> Self {
+        match rng.below(10) {
+            0..=3 => Self::EcSigning,
+            4..=5 => Self::RsaSigning,
+            6..=8 => Self::AesGcm,
+            _ => Self::HmacSha256,
+        }
+    }
+
+    fn is_asymmetric(self) -> bool {
+        matches!(self, Self::EcSigning | Self::RsaSigning)
+    }
+
+    fn key_blob_len(self) -> usize {
+        match self {
+            Self::EcSigning => 350,
+            Self::RsaSigning => 1450,
+            Self::AesGcm | Self::HmacSha256 => 180,
+        }
+    }
+
+    fn parameters(self, user_id: i32, creation_ms: i64) -> Vec<KeyParameter> {
+        let tee = |v| KeyParameter::new(v, SecurityLevel::TRUSTED_ENVIRONMENT);
+        let mut params = match self {
+            Self::EcSigning => vec![
+                tee(KeyParameterValue::Algorithm(Algorithm::EC)),
+                tee(KeyParameterValue::EcCurve(EcCurve::P_256)),
+                tee(KeyParameterValue::KeySize(256)),
+                tee(KeyParameterValue::KeyPurpose(KeyPurpose::SIGN)),
+                tee(KeyParameterValue::KeyPurpose(KeyPurpose::VERIFY)),
+                tee(KeyParameterValue::Digest(Digest::SHA_2_256)),
+            ],
+            Self::RsaSigning => vec![
+                tee(KeyParameterValue::Algorithm(Algorithm::RSA)),
+                tee(KeyParameterValue::KeySize(2048)),
+                tee(KeyParameterValue::KeyPurpose(KeyPurpose::SIGN)),
+                tee(KeyParameterValue::KeyPurpose(KeyPurpose::VERIFY)),
+                tee(KeyParameterValue::Digest(Digest::SHA_2_256)),
+                tee(KeyParameterValue::PaddingMode(PaddingMode::RSA_PKCS1_1_5_SIGN)),
+            ],
+            Self::AesGcm => vec![
+                tee(KeyParameterValue::Algorithm(Algorithm::AES)),
+                tee(KeyParameterValue::KeySize(256)),
+                tee(KeyParameterValue::KeyPurpose(KeyPurpose::ENCRYPT)),
+                tee(KeyParameterValue::KeyPurpose(KeyPurpose::DECRYPT)),
+                tee(KeyParameterValue::BlockMode(BlockMode::GCM)),
+                tee(KeyParameterValue::PaddingMode(PaddingMode::NONE)),
+                tee(KeyParameterValue::MinMacLength(128)),
+            ],
+            Self::HmacSha256 => vec![
+                tee(KeyParameterValue::Algorithm(Algorithm::HMAC)),
+                tee(KeyParameterValue::KeySize(256)),
+                tee(KeyParameterValue::KeyPurpose(KeyPurpose::SIGN)),
+                tee(KeyParameterValue::Digest(Digest::SHA_2_256)),
+                tee(KeyParameterValue::MinMacLength(256)),
+            ],
+        };
+        params.push(tee(KeyParameterValue::NoAuthRequired));
+        params.push(tee(KeyParameterValue::CreationDateTime(creation_ms)));
+        params.push(KeyParameter::new(KeyParameterValue::UserID(user_id), SecurityLevel::SOFTWARE));
+        params
+    }
+}
+
+/// Populates `db` according to `spec` and returns the counts of the generated content.
+/// The persistent content is written in a single transaction.
+pub fn populate(db: &mut KeystoreDB, spec: &FixtureSpec) -> Result<FixtureSummary> {
+    let summary = db
+        .with_transaction(TransactionBehavior::Immediate, |tx| {
+            // Start over from the seed if the transaction is retried.
+            let mut rng = FixtureRng(spec.seed);
+            let mut summary = FixtureSummary::default();
+            let mut key_ids = Vec::new();
+
+            for n in 0..spec.namespaces {
+                let (domain, namespace) = if n % 8 == 7 {
+                    (Domain::SELINUX, FIRST_SELINUX_NAMESPACE + n as i64)
+                } else {
+                    (Domain::APP, FIRST_APP_UID + n as i64)
+                };
+                for k in 0..spec.keys_per_namespace {
+                    let profile = KeyProfile::pick(&mut rng);
+                    let alias = format!("fixture_key_{}_{}", n, k);
+                    let creation_ms = 1_600_000_000_000 + rng.below(100_000_000_000) as i64;
+                    let key_id = KeystoreDB::create_key_entry_internal(
+                        tx,
+                        &domain,
+                        &namespace,
+                        KeyType::Client,
+                        &KEYSTORE_UUID,
+                    )
+                    .context("In populate: Failed to create key entry.")?;
+
+                    let mut blob_metadata = BlobMetaData::new();
+                    blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+                    KeystoreDB::set_blob_internal(
+                        tx,
+                        key_id.id(),
+                        SubComponentType::KEY_BLOB,
+                        Some(&rng.bytes(profile.key_blob_len())),
+                        Some(&blob_metadata),
+                    )
+                    .context("In populate: Failed to store key blob.")?;
+                    if profile.is_asymmetric() {
+                        let cert_len = 600 + rng.below(200) as usize;
+                        KeystoreDB::set_blob_internal(
+                            tx,
+                            key_id.id(),
+                            SubComponentType::CERT,
+                            Some(&rng.bytes(cert_len)),
+                            None,
+                        )
+                        .context("In populate: Failed to store certificate.")?;
+                        let cert_chain_len = 1200 + rng.below(800) as usize;
+                        KeystoreDB::set_blob_internal(
+                            tx,
+                            key_id.id(),
+                            SubComponentType::CERT_CHAIN,
+                            Some(&rng.bytes(cert_chain_len)),
+                            None,
+                        )
+                        .context("In populate: Failed to store certificate chain.")?;
+                    }
+                    let user_id = (namespace / crate::utils::AID_USER_OFFSET as i64) as i32;
+                    KeystoreDB::insert_keyparameter_internal(
+                        tx,
+                        &key_id,
+                        &profile.parameters(user_id, creation_ms),
+                    )
+                    .context("In populate: Failed to store key parameters.")?;
+                    let mut metadata = KeyMetaData::new();
+                    metadata
+                        .add(KeyMetaEntry::CreationDate(DateTime::from_millis_epoch(creation_ms)));
+                    metadata.store_in_db(key_id.id(), tx).context("In populate.")?;
+                    KeystoreDB::rebind_alias(
+                        tx,
+                        &key_id,
+                        &alias,
+                        &domain,
+                        &namespace,
+                        KeyType::Client,
+                        GrantRebindPolicy::Keep,
+                    )
+                    .context("In populate: Failed to bind alias.")?;
+                    summary.keys += 1;
+
+                    if domain == Domain::APP && spec.max_grants_per_key != 0 {
+                        let grants = rng.below(spec.max_grants_per_key as u64 + 1);
+                        for g in 0..grants {
+                            let grantee = FIRST_APP_UID
+                                + ((n as i64 + 1 + g as i64) % spec.namespaces.max(1) as i64);
+                            let access_vector = if rng.below(2) == 0 {
+                                KeyPermSet::from(KeyPerm::use_())
+                            } else {
+                                key_perm_set![KeyPerm::use_(), KeyPerm::get_info()]
+                            };
+                            KeystoreDB::insert_with_retry(|id| {
+                                tx.execute(
+                                    "INSERT INTO persistent.grant
+                                        (id, grantee, keyentryid, access_vector)
+                                        VALUES (?, ?, ?, ?);",
+                                    params![id, grantee, key_id.id(), i32::from(access_vector)],
+                                )
+                            })
+                            .context("In populate: Failed to insert grant.")?;
+                            grant_cache::note_grant_write();
+                            summary.grants += 1;
+                        }
+                    }
+                    key_ids.push(key_id.id());
+                }
+            }
+
+            if !key_ids.is_empty() {
+                for _ in 0..spec.superseded_blobs {
+                    let key_id = key_ids[rng.below(key_ids.len() as u64) as usize];
+                    let mut blob_metadata = BlobMetaData::new();
+                    blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+                    // Storing a new key blob supersedes the current one.
+                    KeystoreDB::set_blob_internal(
+                        tx,
+                        key_id,
+                        SubComponentType::KEY_BLOB,
+                        Some(&rng.bytes(350)),
+                        Some(&blob_metadata),
+                    )
+                    .context("In populate: Failed to supersede key blob.")?;
+                    summary.superseded_blobs += 1;
+                }
+            }
+            for l in 0..spec.leftover_entries {
+                let namespace = FIRST_APP_UID + l as i64;
+                let key_id = KeystoreDB::create_key_entry_internal(
+                    tx,
+                    &Domain::APP,
+                    &namespace,
+                    KeyType::Client,
+                    &KEYSTORE_UUID,
+                )
+                .context("In populate: Failed to create leftover entry.")?;
+                KeystoreDB::set_blob_internal(
+                    tx,
+                    key_id.id(),
+                    SubComponentType::KEY_BLOB,
+                    Some(&rng.bytes(350)),
+                    None,
+                )
+                .context("In populate: Failed to store leftover key blob.")?;
+                summary.leftover_entries += 1;
+            }
+            Ok(summary).no_gc()
+        })
+        .context("In populate.")?;
+
+    let mut rng = FixtureRng(spec.seed ^ 0x6175_7468);
+    for t in 0..spec.auth_tokens {
+        db.insert_auth_token(&HardwareAuthToken {
+            challenge: if rng.below(4) == 0 { rng.next_u64() as i64 } else { 0 },
+            userId: rng.next_u64() as i64,
+            authenticatorId: if t % 2 == 0 { 0 } else { rng.next_u64() as i64 },
+            authenticatorType: if t % 2 == 0 {
+                HardwareAuthenticatorType::PASSWORD
+            } else {
+                HardwareAuthenticatorType::FINGERPRINT
+            },
+            timestamp: Timestamp { milliSeconds: t as i64 * 1000 },
+            mac: rng.bytes(32),
+        });
+    }
+    Ok(FixtureSummary { auth_tokens: spec.auth_tokens as usize, ..summary })
+}
+
+/// Returns the number of live keys in `db`.
+pub fn count_live_keys(db: &mut KeystoreDB) -> Result<usize> {
+    db.with_transaction(TransactionBehavior::Deferred, |tx| {
+        tx.query_row(
+            "SELECT COUNT(id) FROM persistent.keyentry WHERE state = ?;",
+            params![KeyLifeCycle::Live],
+            |row| row.get::<_, i64>(0),
+        )
+        .map(|count| count as usize)
+        .context("In count_live_keys.")
+        .no_gc()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keystore2_test_utils::TempDir;
+
+    fn dump_aliases_and_blobs(db: &mut KeystoreDB) -> Result<Vec<(String, i64, Vec<u8>)>> {
+        db.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx.prepare(
+                "SELECT k.alias, k.namespace, b.blob FROM persistent.keyentry AS k
+                    INNER JOIN persistent.blobentry AS b ON b.keyentryid = k.id
+                    WHERE k.state = ? ORDER BY k.alias, b.id;",
+            )?;
+            let rows = stmt
+                .query_map(params![KeyLifeCycle::Live], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(rows).no_gc()
+        })
+    }
+
+    #[test]
+    fn test_populate_is_deterministic() -> Result<()> {
+        // Auth tokens are kept out of this test, because they would end up in the shared
+        // per-boot database of databases opened with `KeystoreDB::new`.
+        let spec = FixtureSpec {
+            namespaces: 9,
+            keys_per_namespace: 3,
+            auth_tokens: 0,
+            ..Default::default()
+        };
+        let temp_dir1 = TempDir::new("fixtures_test_1")?;
+        let temp_dir2 = TempDir::new("fixtures_test_2")?;
+        let mut db1 = KeystoreDB::new(temp_dir1.path(), None)?;
+        let mut db2 = KeystoreDB::new(temp_dir2.path(), None)?;
+
+        let summary = populate(&mut db1, &spec)?;
+        assert_eq!(populate(&mut db2, &spec)?, summary);
+        assert_eq!(summary.keys, 27);
+        assert_eq!(count_live_keys(&mut db1)?, 27);
+        assert_eq!(db1.cleanup_leftovers()?, spec.leftover_entries as usize);
+        assert_eq!(dump_aliases_and_blobs(&mut db1)?, dump_aliases_and_blobs(&mut db2)?);
+
+        let temp_dir3 = TempDir::new("fixtures_test_3")?;
+        let mut db3 = KeystoreDB::new(temp_dir3.path(), None)?;
+        populate(&mut db3, &FixtureSpec { seed: 1, ..spec })?;
+        assert_ne!(dump_aliases_and_blobs(&mut db1)?, dump_aliases_and_blobs(&mut db3)?);
+        Ok(())
+    }
+}
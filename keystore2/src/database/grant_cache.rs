@@ -0,0 +1,181 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements an in-memory cache of the active grants of recent grantees.
+//! Every use of a key by grant resolves the grant id through the grant table. The cache
+//! loads all live grants of a grantee at once and serves subsequent lookups of that
+//! grantee from memory until the grant table is written.
+//!
+//! Writers of the grant table call `note_grant_write` from within their transaction.
+//! `KeystoreDB::with_transaction` invalidates the cache after the transaction completed.
+//! A cache fill that raced with such a write is discarded by means of a generation counter.
+
+use lazy_static::lazy_static;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The maximal number of grantees whose grants are cached at any time.
+const MAX_CACHED_GRANTEES: usize = 64;
+
+/// The grants of one grantee mapped from grant id to (key id, access vector).
+pub type GranteeGrants = HashMap<i64, (i64, i32)>;
+
+thread_local! {
+    static GRANT_TABLE_WRITTEN: Cell<bool> = Cell::new(false);
+}
+
+lazy_static! {
+    /// The grant caches of all persistent databases opened by this process, keyed by the
+    /// path of the database file. All connections to the same database share one cache.
+    static ref GRANT_CACHES: Mutex<HashMap<PathBuf, Arc<GrantCache>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Records that the current transaction modified the grant table.
+pub fn note_grant_write() {
+    GRANT_TABLE_WRITTEN.with(|w| w.set(true));
+}
+
+/// Returns true and resets the flag if a grant table write was recorded on this thread
+/// since the last call.
+pub fn take_grant_write() -> bool {
+    GRANT_TABLE_WRITTEN.with(|w| w.replace(false))
+}
+
+/// Returns the grant cache shared by all connections to the database at `path`.
+pub fn for_path(path: &Path) -> Arc<GrantCache> {
+    GRANT_CACHES.lock().unwrap().entry(path.to_path_buf()).or_default().clone()
+}
+
+#[derive(Default)]
+struct GrantCacheState {
+    generation: u64,
+    grantees: HashMap<u32, Arc<GranteeGrants>>,
+}
+
+/// Caches the active grants of recent grantees.
+#[derive(Default)]
+pub struct GrantCache {
+    state: Mutex<GrantCacheState>,
+}
+
+impl GrantCache {
+    /// Returns the cached grants of `grantee` if present. Otherwise, `load` is called to
+    /// read all active grants of the grantee from the database. The result is cached
+    /// unless the cache was invalidated while `load` was running.
+    pub fn get_or_load<F, E>(&self, grantee: u32, load: F) -> Result<Arc<GranteeGrants>, E>
+    where
+        F: FnOnce() -> Result<GranteeGrants, E>,
+    {
+        let generation = {
+            let state = self.state.lock().unwrap();
+            if let Some(grants) = state.grantees.get(&grantee) {
+                return Ok(grants.clone());
+            }
+            state.generation
+        };
+        let grants = Arc::new(load()?);
+        let mut state = self.state.lock().unwrap();
+        if state.generation == generation {
+            if state.grantees.len() >= MAX_CACHED_GRANTEES {
+                if let Some(evict) = state.grantees.keys().next().copied() {
+                    state.grantees.remove(&evict);
+                }
+            }
+            state.grantees.insert(grantee, grants.clone());
+        }
+        Ok(grants)
+    }
+
+    /// Drops all cached grants.
+    pub fn invalidate(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.generation = state.generation.wrapping_add(1);
+        state.grantees.clear();
+    }
+
+    /// Returns the number of cached grantees and an estimate of the memory held by their
+    /// grants in bytes.
+    fn usage(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        let bytes = state
+            .grantees
+            .values()
+            .map(|grants| {
+                size_of::<(u32, Arc<GranteeGrants>)>()
+                    + grants.len() * size_of::<(i64, (i64, i32))>()
+            })
+            .sum();
+        (state.grantees.len(), bytes)
+    }
+}
+
+fn all_caches() -> Vec<Arc<GrantCache>> {
+    GRANT_CACHES.lock().unwrap().values().cloned().collect()
+}
+
+/// Returns an estimate of the memory held by all grant caches in bytes.
+pub fn size_bytes() -> usize {
+    all_caches().iter().map(|cache| cache.usage().1).sum()
+}
+
+/// Drops the cached grants of all databases. Returns the number of grantees whose grants were
+/// dropped.
+pub fn invalidate_all() -> usize {
+    all_caches()
+        .iter()
+        .map(|cache| {
+            let (grantees, _) = cache.usage();
+            cache.invalidate();
+            grantees
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fill_racing_invalidation_is_discarded() {
+        let cache = GrantCache::default();
+        let grants: Result<_, ()> = cache.get_or_load(10, || {
+            cache.invalidate();
+            Ok(vec![(1, (2, 3))].into_iter().collect())
+        });
+        assert_eq!(grants.unwrap().get(&1), Some(&(2, 3)));
+
+        // The first fill was discarded, so this one has to load again.
+        let grants: Result<_, ()> = cache.get_or_load(10, || Ok(GranteeGrants::new()));
+        assert!(grants.unwrap().is_empty());
+        let grants: Result<_, ()> = cache.get_or_load(10, || panic!("Should be cached."));
+        assert!(grants.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_usage() {
+        let cache = GrantCache::default();
+        assert_eq!(cache.usage(), (0, 0));
+        let _: Result<_, ()> =
+            cache.get_or_load(10, || Ok(vec![(1, (2, 3)), (4, (5, 6))].into_iter().collect()));
+        let (grantees, bytes) = cache.usage();
+        assert_eq!(grantees, 1);
+        assert!(bytes >= 2 * size_of::<(i64, (i64, i32))>());
+        cache.invalidate();
+        assert_eq!(cache.usage(), (0, 0));
+    }
+}
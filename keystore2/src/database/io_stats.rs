@@ -0,0 +1,157 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module accounts database transactions and the database pages they read from and
+//! wrote to storage per originating subsystem. Each thread has a current subsystem, which
+//! defaults to `Subsystem::ServiceCall`. Background workers enter their subsystem with
+//! `enter` before they touch the database. The cumulative counts are reported by the dump
+//! of the maintenance service.
+
+use std::cell::Cell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The subsystems that database transactions are attributed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    /// Requests served on behalf of Keystore clients.
+    ServiceCall = 0,
+    /// The garbage collector.
+    Gc = 1,
+    /// Migration of legacy Keystore blobs.
+    Migration = 2,
+    /// Remote key provisioning.
+    Rkp = 3,
+}
+
+impl Subsystem {
+    /// All subsystems in the order of their discriminants.
+    pub const ALL: [Subsystem; 4] =
+        [Subsystem::ServiceCall, Subsystem::Gc, Subsystem::Migration, Subsystem::Rkp];
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Subsystem::ServiceCall => "service_call",
+            Subsystem::Gc => "gc",
+            Subsystem::Migration => "migration",
+            Subsystem::Rkp => "rkp",
+        })
+    }
+}
+
+/// Cumulative database activity of one subsystem.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SubsystemIo {
+    /// Number of transactions.
+    pub transactions: u64,
+    /// Number of database pages read into the page cache.
+    pub pages_read: u64,
+    /// Number of database pages written to storage.
+    pub pages_written: u64,
+}
+
+struct Counters {
+    transactions: AtomicU64,
+    pages_read: AtomicU64,
+    pages_written: AtomicU64,
+}
+
+static COUNTERS: [Counters; 4] =
+    [Counters::new(), Counters::new(), Counters::new(), Counters::new()];
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            transactions: AtomicU64::new(0),
+            pages_read: AtomicU64::new(0),
+            pages_written: AtomicU64::new(0),
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT_SUBSYSTEM: Cell<Subsystem> = Cell::new(Subsystem::ServiceCall);
+}
+
+/// Sets the subsystem of the current thread while in scope and restores the previous one
+/// on drop.
+pub struct SubsystemScope {
+    previous: Subsystem,
+    not_send: PhantomData<*mut ()>, // SubsystemScope must not be Send.
+}
+
+impl Drop for SubsystemScope {
+    fn drop(&mut self) {
+        CURRENT_SUBSYSTEM.with(|c| c.set(self.previous));
+    }
+}
+
+/// Attributes all database transactions of the current thread to `subsystem` until the
+/// returned scope is dropped.
+pub fn enter(subsystem: Subsystem) -> SubsystemScope {
+    let previous = CURRENT_SUBSYSTEM.with(|c| c.replace(subsystem));
+    SubsystemScope { previous, not_send: PhantomData }
+}
+
+/// Returns the subsystem that database transactions of this thread are attributed to.
+pub fn current() -> Subsystem {
+    CURRENT_SUBSYSTEM.with(|c| c.get())
+}
+
+/// Accounts one transaction with the given page counts to the current subsystem.
+pub(super) fn record(pages_read: u64, pages_written: u64) {
+    let counters = &COUNTERS[current() as usize];
+    counters.transactions.fetch_add(1, Ordering::Relaxed);
+    counters.pages_read.fetch_add(pages_read, Ordering::Relaxed);
+    counters.pages_written.fetch_add(pages_written, Ordering::Relaxed);
+}
+
+/// Returns the cumulative database activity of all subsystems since Keystore started.
+pub fn snapshot() -> Vec<(Subsystem, SubsystemIo)> {
+    Subsystem::ALL
+        .iter()
+        .map(|s| {
+            let counters = &COUNTERS[*s as usize];
+            (
+                *s,
+                SubsystemIo {
+                    transactions: counters.transactions.load(Ordering::Relaxed),
+                    pages_read: counters.pages_read.load(Ordering::Relaxed),
+                    pages_written: counters.pages_written.load(Ordering::Relaxed),
+                },
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subsystem_scopes() {
+        assert_eq!(current(), Subsystem::ServiceCall);
+        let gc = enter(Subsystem::Gc);
+        {
+            let _rkp = enter(Subsystem::Rkp);
+            assert_eq!(current(), Subsystem::Rkp);
+        }
+        assert_eq!(current(), Subsystem::Gc);
+        drop(gc);
+        assert_eq!(current(), Subsystem::ServiceCall);
+    }
+}
@@ -14,6 +14,11 @@
 
 //! This module implements a per-boot, shared, in-memory storage of auth tokens
 //! and last-time-on-body for the main Keystore 2.0 database module.
+//!
+//! Auth tokens are never written to storage. Each upsert only replaces an entry of the
+//! in-memory table, so heavy biometric use causes no flash writes and there is nothing to
+//! batch. This is also the crash-safety story: if Keystore dies, the table dies with it, no
+//! partially written state can survive, and `perboot_recovery` asks for fresh tokens.
 
 use super::{AuthTokenEntry, MonotonicRawTime};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
@@ -62,13 +67,34 @@ impl PartialEq<AuthTokenEntryWrap> for AuthTokenEntryWrap {
 
 impl Eq for AuthTokenEntryWrap {}
 
+/// Secure user ids of zero are never bound to keys.
+fn secure_ids(tok: &HardwareAuthToken) -> impl Iterator<Item = i64> {
+    std::iter::once(tok.userId).chain(std::iter::once(tok.authenticatorId)).filter(|sid| *sid != 0)
+}
+
+fn can_satisfy_any(tok: &HardwareAuthToken) -> bool {
+    tok.authenticatorType != HardwareAuthenticatorType::NONE && secure_ids(tok).next().is_some()
+}
+
+/// True if `newer` satisfies all keys that `older` satisfies. A token minted for the
+/// challenge of a pending operation is only superseded by a token for the same challenge.
+fn dominates(newer: &HardwareAuthToken, older: &HardwareAuthToken) -> bool {
+    let types_covered =
+        (older.authenticatorType.0 as i32) & !(newer.authenticatorType.0 as i32) == 0;
+    let challenge_covered = older.challenge == 0 || older.challenge == newer.challenge;
+    types_covered
+        && challenge_covered
+        && secure_ids(older).all(|sid| sid == newer.userId || sid == newer.authenticatorId)
+}
+
 /// Per-boot state structure. Currently only used to track auth tokens and
 /// last-off-body.
 #[derive(Default)]
 pub struct PerbootDB {
     // We can use a .unwrap() discipline on this lock, because only panicking
-    // while holding a .write() lock will poison it. The only write usage is
-    // an insert call which inserts a pre-constructed pair.
+    // while holding a .write() lock will poison it. The write usages are
+    // an insert call which inserts a pre-constructed pair, and coalescing,
+    // which only filters the existing entries.
     auth_tokens: RwLock<HashSet<AuthTokenEntryWrap>>,
     // Ordering::Relaxed is appropriate for accessing this atomic, since it
     // does not currently need to be synchronized with anything else.
@@ -110,10 +136,41 @@ impl PerbootDB {
     pub fn set_last_off_body(&self, last_off_body: MonotonicRawTime) {
         self.last_off_body.store(last_off_body.0, Ordering::Relaxed)
     }
+    /// Removes auth tokens that can no longer make a difference to an authorization decision:
+    /// tokens that cannot satisfy any key, and tokens for which a more recently received token
+    /// satisfies every key the older token would satisfy. Returns the number of tracked auth
+    /// tokens before and after coalescing.
+    pub fn coalesce_auth_tokens(&self) -> (usize, usize) {
+        let mut auth_tokens = self.auth_tokens.write().unwrap();
+        let before = auth_tokens.len();
+        let mut entries: Vec<AuthTokenEntryWrap> = auth_tokens.drain().collect();
+        // Newest first, so that every kept token was received no earlier than the ones after it.
+        entries.sort_by(|a, b| b.0.time_received.cmp(&a.0.time_received));
+        let mut kept: Vec<AuthTokenEntryWrap> = Vec::with_capacity(entries.len());
+        for entry in entries {
+            if !can_satisfy_any(&entry.0.auth_token)
+                || kept.iter().any(|k| dominates(&k.0.auth_token, &entry.0.auth_token))
+            {
+                continue;
+            }
+            kept.push(entry);
+        }
+        auth_tokens.extend(kept);
+        (before, auth_tokens.len())
+    }
     /// Return how many auth tokens are currently tracked.
     pub fn auth_tokens_len(&self) -> usize {
         self.auth_tokens.read().unwrap().len()
     }
+    /// Return an estimate of the memory held by the tracked auth tokens in bytes.
+    pub fn auth_tokens_size_bytes(&self) -> usize {
+        self.auth_tokens
+            .read()
+            .unwrap()
+            .iter()
+            .map(|x| std::mem::size_of::<AuthTokenEntryWrap>() + x.0.auth_token.mac.len())
+            .sum()
+    }
     #[cfg(test)]
     /// For testing, return all auth tokens currently tracked.
     pub fn get_all_auth_token_entries(&self) -> Vec<AuthTokenEntry> {
@@ -0,0 +1,465 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module declares the tables and indices of the persistent Keystore 2.0 database.
+//! The same declarations are used to create the schema and to introspect it at runtime,
+//! so that the code's expectations and the on-disk schema cannot silently drift apart.
+//!
+//! Tables and indices must only be added here. Changes to existing tables require a
+//! database upgrade in `KeystoreDB::UPGRADERS` in addition to changing the declaration.
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Transaction, NO_PARAMS};
+use std::fmt;
+
+/// A column of a table in the persistent database.
+pub struct Column {
+    /// Name of the column.
+    pub name: &'static str,
+    /// Declared SQLite type of the column.
+    pub sql_type: &'static str,
+    /// Column constraints, e.g., "UNIQUE" or "PRIMARY KEY". May be empty.
+    pub constraints: &'static str,
+}
+
+/// A table in the persistent database.
+pub struct Table {
+    /// Name of the table.
+    pub name: &'static str,
+    /// Columns in declaration order.
+    pub columns: &'static [Column],
+    /// Table constraints, e.g., "UNIQUE (keyentryid, tag)".
+    pub constraints: &'static [&'static str],
+}
+
+/// An index in the persistent database.
+pub struct Index {
+    /// Name of the index.
+    pub name: &'static str,
+    /// Name of the indexed table.
+    pub table: &'static str,
+    /// Indexed columns in order, optionally followed by a collation, e.g.,
+    /// "alias COLLATE BINARY".
+    pub columns: &'static [&'static str],
+}
+
+macro_rules! columns {
+    ($($name:ident $sql_type:ident $($constraint:literal)?),* $(,)?) => {
+        &[$(Column {
+            name: stringify!($name),
+            sql_type: stringify!($sql_type),
+            constraints: concat!("", $($constraint)?),
+        }),*]
+    };
+}
+
+/// All tables of the persistent database.
+pub const TABLES: &[Table] = &[
+    Table {
+        name: "keyentry",
+        columns: columns![
+            id INTEGER "UNIQUE",
+            key_type INTEGER,
+            domain INTEGER,
+            namespace INTEGER,
+            alias BLOB,
+            state INTEGER,
+            km_uuid BLOB,
+        ],
+        constraints: &[],
+    },
+    Table {
+        name: "blobentry",
+        columns: columns![
+            id INTEGER "PRIMARY KEY",
+            subcomponent_type INTEGER,
+            keyentryid INTEGER,
+            blob BLOB,
+        ],
+        constraints: &[],
+    },
+    Table {
+        name: "blobmetadata",
+        columns: columns![id INTEGER "PRIMARY KEY", blobentryid INTEGER, tag INTEGER, data ANY],
+        constraints: &["UNIQUE (blobentryid, tag)"],
+    },
+    Table {
+        name: "keyparameter",
+        columns: columns![keyentryid INTEGER, tag INTEGER, data ANY, security_level INTEGER],
+        constraints: &[],
+    },
+    Table {
+        name: "keymetadata",
+        columns: columns![keyentryid INTEGER, tag INTEGER, data ANY],
+        constraints: &["UNIQUE (keyentryid, tag)"],
+    },
+    Table {
+        name: "grant",
+        columns: columns![
+            id INTEGER "UNIQUE",
+            grantee INTEGER,
+            keyentryid INTEGER,
+            access_vector INTEGER,
+        ],
+        constraints: &[],
+    },
+    // Grants of all keys of a `Domain::SELINUX` namespace, see `KeystoreDB::grant_namespace`.
+    Table {
+        name: "namespacegrant",
+        columns: columns![grantee INTEGER, namespace INTEGER, access_vector INTEGER],
+        constraints: &["UNIQUE (grantee, namespace)"],
+    },
+    Table {
+        name: "keyfingerprint",
+        columns: columns![keyentryid INTEGER, kind INTEGER, digest BLOB],
+        constraints: &[],
+    },
+    // Journal of changes to client key bindings, see `KeystoreDB::list_key_changes`. The
+    // sequence number is the rowid. Only the oldest entries are ever deleted, so rowids
+    // remain monotonically increasing without AUTOINCREMENT.
+    Table {
+        name: "keyjournal",
+        columns: columns![
+            seq INTEGER "PRIMARY KEY",
+            domain INTEGER,
+            namespace INTEGER,
+            alias BLOB,
+            event INTEGER,
+        ],
+        constraints: &[],
+    },
+    // Content-addressed certificates shared by certificate chains, see `cert_store`.
+    Table {
+        name: "certificate",
+        columns: columns![
+            id INTEGER "PRIMARY KEY",
+            digest BLOB "UNIQUE",
+            data BLOB,
+            refcount INTEGER,
+        ],
+        constraints: &[],
+    },
+    Table {
+        name: "certchain",
+        columns: columns![blobentryid INTEGER, position INTEGER, certificateid INTEGER],
+        constraints: &["UNIQUE (blobentryid, position)"],
+    },
+    // Namespaces whose keys must not be used, see `namespace_freeze`.
+    Table {
+        name: "frozennamespace",
+        columns: columns![domain INTEGER, namespace INTEGER, frozen_at INTEGER],
+        constraints: &["UNIQUE (domain, namespace)"],
+    },
+    // Certificates for the .fs-verity keyring, see `fs_verity`.
+    Table {
+        name: "fsveritycert",
+        columns: columns![name TEXT "UNIQUE", certificate BLOB],
+        constraints: &[],
+    },
+];
+
+/// All explicitly created indices of the persistent database.
+pub const INDICES: &[Index] = &[
+    Index { name: "keyentry_id_index", table: "keyentry", columns: &["id"] },
+    Index {
+        name: "keyentry_domain_namespace_index",
+        table: "keyentry",
+        columns: &["domain", "namespace", "alias"],
+    },
+    Index { name: "blobentry_keyentryid_index", table: "blobentry", columns: &["keyentryid"] },
+    Index {
+        name: "blobmetadata_blobentryid_index",
+        table: "blobmetadata",
+        columns: &["blobentryid"],
+    },
+    Index {
+        name: "keyparameter_keyentryid_index",
+        table: "keyparameter",
+        columns: &["keyentryid"],
+    },
+    Index { name: "keymetadata_keyentryid_index", table: "keymetadata", columns: &["keyentryid"] },
+    Index { name: "grant_grantee_id_index", table: "grant", columns: &["grantee", "id"] },
+    Index { name: "grant_keyentryid_index", table: "grant", columns: &["keyentryid"] },
+    Index { name: "keyfingerprint_digest_index", table: "keyfingerprint", columns: &["digest"] },
+    Index {
+        name: "keyfingerprint_keyentryid_index",
+        table: "keyfingerprint",
+        columns: &["keyentryid"],
+    },
+    // Serves `KeystoreDB::list`, which returns aliases in binary collation order. The collation
+    // is spelled out, so that the index can be used for the ORDER BY clause regardless of
+    // the default collation.
+    Index {
+        name: "keyentry_list_index",
+        table: "keyentry",
+        columns: &["domain", "namespace", "key_type", "state", "alias COLLATE BINARY"],
+    },
+    Index {
+        name: "keyjournal_domain_namespace_index",
+        table: "keyjournal",
+        columns: &["domain", "namespace", "seq"],
+    },
+    Index {
+        name: "certchain_certificateid_index",
+        table: "certchain",
+        columns: &["certificateid"],
+    },
+];
+
+impl Table {
+    /// Returns the statement that creates this table in the persistent database
+    /// if it does not exist.
+    pub fn create_statement(&self) -> String {
+        let definitions: Vec<String> = self
+            .columns
+            .iter()
+            .map(|c| {
+                if c.constraints.is_empty() {
+                    format!("{} {}", c.name, c.sql_type)
+                } else {
+                    format!("{} {} {}", c.name, c.sql_type, c.constraints)
+                }
+            })
+            .chain(self.constraints.iter().map(|c| c.to_string()))
+            .collect();
+        format!("CREATE TABLE IF NOT EXISTS persistent.{} ({});", self.name, definitions.join(", "))
+    }
+}
+
+impl Index {
+    /// Returns the statement that creates this index in the persistent database
+    /// if it does not exist.
+    pub fn create_statement(&self) -> String {
+        format!(
+            "CREATE INDEX IF NOT EXISTS persistent.{} ON {}({});",
+            self.name,
+            self.table,
+            self.columns.join(", ")
+        )
+    }
+}
+
+/// Creates all declared tables and indices that do not exist yet.
+pub fn create_schema(tx: &Transaction) -> Result<()> {
+    for table in TABLES {
+        tx.execute(&table.create_statement(), NO_PARAMS)
+            .with_context(|| format!("Failed to initialize \"{}\" table.", table.name))?;
+    }
+    for index in INDICES {
+        tx.execute(&index.create_statement(), NO_PARAMS)
+            .with_context(|| format!("Failed to create index {}.", index.name))?;
+    }
+    Ok(())
+}
+
+/// Introspected description of a table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableInfo {
+    /// Name of the table.
+    pub name: String,
+    /// (name, declared type) of each column in declaration order.
+    pub columns: Vec<(String, String)>,
+}
+
+/// Introspected description of an index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexInfo {
+    /// Name of the index.
+    pub name: String,
+    /// Name of the indexed table.
+    pub table: String,
+    /// Indexed columns in order.
+    pub columns: Vec<String>,
+}
+
+/// A description of a database schema, either as declared in this module or as found
+/// on disk. Tables and indices are sorted by name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchemaDump {
+    /// All tables.
+    pub tables: Vec<TableInfo>,
+    /// All explicitly created indices. Automatic indices created by SQLite are omitted.
+    pub indices: Vec<IndexInfo>,
+}
+
+impl SchemaDump {
+    /// Returns the schema as declared by `TABLES` and `INDICES`.
+    pub fn declared() -> Self {
+        let mut tables: Vec<TableInfo> = TABLES
+            .iter()
+            .map(|t| TableInfo {
+                name: t.name.to_string(),
+                columns: t
+                    .columns
+                    .iter()
+                    .map(|c| (c.name.to_string(), c.sql_type.to_string()))
+                    .collect(),
+            })
+            .collect();
+        tables.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut indices: Vec<IndexInfo> = INDICES
+            .iter()
+            .map(|i| IndexInfo {
+                name: i.name.to_string(),
+                table: i.table.to_string(),
+                // pragma_index_info reports column names without collation.
+                columns: i
+                    .columns
+                    .iter()
+                    .map(|c| c.split_whitespace().next().unwrap_or(c).to_string())
+                    .collect(),
+            })
+            .collect();
+        indices.sort_by(|a, b| a.name.cmp(&b.name));
+        Self { tables, indices }
+    }
+
+    /// Reads the schema of the persistent database.
+    pub fn load(tx: &Transaction) -> Result<Self> {
+        let table_names = tx
+            .prepare(
+                "SELECT name FROM persistent.sqlite_master
+                 WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name;",
+            )
+            .context("In SchemaDump::load: Failed to prepare table query.")?
+            .query_map(NO_PARAMS, |row| row.get(0))
+            .context("In SchemaDump::load: Failed to query tables.")?
+            .collect::<rusqlite::Result<Vec<String>>>()
+            .context("In SchemaDump::load: Failed to read table names.")?;
+
+        let mut tables = Vec::new();
+        for name in table_names {
+            let columns = tx
+                .prepare("SELECT name, type FROM pragma_table_info(?, 'persistent') ORDER BY cid;")
+                .context("In SchemaDump::load: Failed to prepare column query.")?
+                .query_map(params![name], |row| Ok((row.get(0)?, row.get(1)?)))
+                .context("In SchemaDump::load: Failed to query columns.")?
+                .collect::<rusqlite::Result<Vec<(String, String)>>>()
+                .context("In SchemaDump::load: Failed to read columns.")?;
+            tables.push(TableInfo { name, columns });
+        }
+
+        let index_names = tx
+            .prepare(
+                "SELECT name, tbl_name FROM persistent.sqlite_master
+                 WHERE type = 'index' AND sql IS NOT NULL ORDER BY name;",
+            )
+            .context("In SchemaDump::load: Failed to prepare index query.")?
+            .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))
+            .context("In SchemaDump::load: Failed to query indices.")?
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()
+            .context("In SchemaDump::load: Failed to read index names.")?;
+
+        let mut indices = Vec::new();
+        for (name, table) in index_names {
+            let columns = tx
+                .prepare("SELECT name FROM pragma_index_info(?, 'persistent') ORDER BY seqno;")
+                .context("In SchemaDump::load: Failed to prepare index column query.")?
+                .query_map(params![name], |row| row.get(0))
+                .context("In SchemaDump::load: Failed to query index columns.")?
+                .collect::<rusqlite::Result<Vec<String>>>()
+                .context("In SchemaDump::load: Failed to read index columns.")?;
+            indices.push(IndexInfo { name, table, columns });
+        }
+
+        Ok(Self { tables, indices })
+    }
+
+    /// Compares this schema, as found on disk, to the declared schema and returns a
+    /// human readable description of every difference. Tables that are not declared in
+    /// this module, e.g., the version table, are ignored.
+    pub fn drift(&self) -> Vec<String> {
+        let declared = Self::declared();
+        let mut result = Vec::new();
+        for expected in &declared.tables {
+            match self.tables.iter().find(|t| t.name == expected.name) {
+                None => result.push(format!("Missing table {}.", expected.name)),
+                Some(actual) if actual.columns != expected.columns => result.push(format!(
+                    "Table {} has columns {:?} but expected {:?}.",
+                    expected.name, actual.columns, expected.columns
+                )),
+                Some(_) => {}
+            }
+        }
+        for expected in &declared.indices {
+            match self.indices.iter().find(|i| i.name == expected.name) {
+                None => result.push(format!("Missing index {}.", expected.name)),
+                Some(actual) if actual != expected => result.push(format!(
+                    "Index {} is on {}({}) but expected {}({}).",
+                    expected.name,
+                    actual.table,
+                    actual.columns.join(", "),
+                    expected.table,
+                    expected.columns.join(", ")
+                )),
+                Some(_) => {}
+            }
+        }
+        result
+    }
+}
+
+impl fmt::Display for SchemaDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for table in &self.tables {
+            writeln!(f, "TABLE {}", table.name)?;
+            for (name, sql_type) in &table.columns {
+                writeln!(f, "    {} {}", name, sql_type)?;
+            }
+        }
+        for index in &self.indices {
+            writeln!(f, "INDEX {} ON {}({})", index.name, index.table, index.columns.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::{Connection, TransactionBehavior};
+
+    fn new_test_connection() -> Result<Connection> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute("ATTACH DATABASE 'file::memory:' as persistent;", NO_PARAMS)?;
+        Ok(conn)
+    }
+
+    #[test]
+    fn test_created_schema_matches_declaration() -> Result<()> {
+        let mut conn = new_test_connection()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        create_schema(&tx)?;
+        let dump = SchemaDump::load(&tx)?;
+        assert_eq!(dump, SchemaDump::declared());
+        assert!(dump.drift().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_drift_is_reported() -> Result<()> {
+        let mut conn = new_test_connection()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        create_schema(&tx)?;
+        tx.execute("DROP INDEX persistent.keymetadata_keyentryid_index;", NO_PARAMS)?;
+        tx.execute("ALTER TABLE persistent.grant ADD COLUMN extra INTEGER;", NO_PARAMS)?;
+        tx.execute("CREATE TABLE persistent.version (id INTEGER PRIMARY KEY);", NO_PARAMS)?;
+
+        let drift = SchemaDump::load(&tx)?.drift();
+        assert_eq!(drift.len(), 2);
+        assert!(drift[0].starts_with("Table grant has columns"));
+        assert_eq!(drift[1], "Missing index keymetadata_keyentryid_index.");
+        Ok(())
+    }
+}
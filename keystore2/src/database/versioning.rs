@@ -83,10 +83,28 @@ where
     }
     let mut db_version = create_or_get_version(tx, current_version)
         .context("In upgrade_database: Failed to get database version.")?;
+    if db_version > current_version {
+        // The database was written by a newer build, e.g., before a rollback. Migrations only
+        // go forward, so the database is used as is and keeps its version.
+        log::warn!(
+            "In upgrade_database: Database version {} is newer than {}.",
+            db_version,
+            current_version
+        );
+    }
     while db_version < current_version {
-        db_version = upgraders[db_version as usize](tx).with_context(|| {
+        let new_version = upgraders[db_version as usize](tx).with_context(|| {
             format!("In upgrade_database: Trying to upgrade from db version {}.", db_version)
         })?;
+        // An upgrader that does not move forward would be run again and again.
+        if new_version <= db_version {
+            return Err(anyhow!(
+                "In upgrade_database: Upgrader for db version {} returned version {}.",
+                db_version,
+                new_version
+            ));
+        }
+        db_version = new_version;
     }
     update_version(tx, db_version).context("In upgrade_database.")
 }
@@ -185,6 +203,23 @@ mod test {
         }
     }
 
+    #[test]
+    fn upgrade_database_rejects_stalled_upgrader() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute("ATTACH DATABASE 'file::memory:' as persistent;", NO_PARAMS).unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate).unwrap();
+        create_or_get_version(&tx, 0).unwrap();
+        update_version(&tx, 1).unwrap();
+
+        let upgraders: [fn(&Transaction) -> Result<u32>; 2] = [|_| Ok(1), |_| Ok(1)];
+        assert!(upgrade_database(&tx, 2, &upgraders).is_err());
+
+        // A database of a newer version is left alone.
+        let no_upgraders: [fn(&Transaction) -> Result<u32>; 0] = [];
+        upgrade_database(&tx, 0, &no_upgraders).unwrap();
+        assert_eq!(create_or_get_version(&tx, 0).unwrap(), 1);
+    }
+
     #[test]
     fn create_or_get_version_new_database() {
         let mut conn = Connection::open_in_memory().unwrap();
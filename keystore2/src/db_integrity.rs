@@ -0,0 +1,73 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the integrity self-check of the persistent database on startup.
+//! Without it, a corrupt persistent.sqlite makes Keystore panic on the first database access,
+//! and init restarts it into the same panic over and over again, which leaves the device
+//! without Keystore.
+//!
+//! The check runs `KeystoreDB::check_integrity` with `PRAGMA quick_check`, which only takes
+//! linear time, so that it does not hold up the boot for long. If SQLite finds the database corrupt, or
+//! cannot open it at all, the database is moved aside to `persistent.sqlite.corrupt` for later
+//! analysis, and Keystore starts over with an empty database. All keys are lost in that case,
+//! which is logged to the audit log and reported in metrics. Rows that refer to missing key
+//! entries are deleted and reported in metrics as well.
+
+use crate::audit_log::log_database_quarantined;
+use crate::database::{IntegrityCheck, KeystoreDB};
+use crate::globals::DB_PATH;
+use crate::metrics_store::log_database_integrity_stats;
+use std::path::Path;
+
+/// Returns the problems that make the database in `db_root` unusable, or an empty vector if it
+/// can be used. Failures that do not indicate corruption, e.g., I/O errors, are logged but do
+/// not count as problems, because moving the database aside would not fix them.
+fn find_problems(db_root: &Path) -> Vec<String> {
+    let result =
+        KeystoreDB::new(db_root, None).and_then(|mut db| db.check_integrity(IntegrityCheck::Quick));
+    match result {
+        Ok(report) => {
+            if report.orphans_removed != 0 {
+                log::warn!("In find_problems: Removed {} orphaned rows.", report.orphans_removed);
+                if report.problems.is_empty() {
+                    log_database_integrity_stats(false, report.orphans_removed);
+                }
+            }
+            report.problems
+        }
+        Err(e) if KeystoreDB::is_corruption_error(&e) => vec![format!("{:?}", e)],
+        Err(e) => {
+            log::error!("In find_problems: Failed to check the database: {:?}", e);
+            vec![]
+        }
+    }
+}
+
+/// Checks the integrity of the persistent database in `DB_PATH` and recovers from corruption
+/// as described in the module documentation. Must be called on startup, after `DB_PATH` was
+/// set and before any database connection is opened.
+pub fn check_on_startup() {
+    let db_root = DB_PATH.read().expect("Could not get DB_PATH.").clone();
+    let problems = find_problems(&db_root);
+    if problems.is_empty() {
+        return;
+    }
+    log::error!("The persistent database is corrupt and will be replaced: {:?}", problems);
+    if let Err(e) = KeystoreDB::quarantine_persistent_db(&db_root) {
+        log::error!("In check_on_startup: Failed to quarantine the database: {:?}", e);
+        return;
+    }
+    log_database_integrity_stats(true, 0);
+    log_database_quarantined(problems.len());
+}
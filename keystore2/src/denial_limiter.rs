@@ -0,0 +1,268 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the rate limiting of permission denials. A caller that keeps
+//! requesting keys it has no permission for makes Keystore check the SELinux policy and log
+//! the denial over and over again. Each uid gets a token bucket, and every denial takes a
+//! token. Once the bucket of a uid is empty, requests for a (key, permission) tuple that was
+//! denied to the uid within `WINDOW` are denied right away, without checking the policy and
+//! without logging. The bucket refills with one token per `REFILL_INTERVAL`, so a repeated
+//! request is checked for real at least that often, and a permission that was granted in
+//! the meantime takes effect after at most one interval.
+
+use crate::error::{get_error_code, ResponseCode};
+use crate::permission::KeyPerm;
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The number of denials a uid may accumulate before repeated denials are short-circuited.
+const BURST: u32 = 10;
+
+/// The time it takes to get one token back.
+const REFILL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a denial is remembered.
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// The number of (key, permission) tuples remembered per uid.
+const MAX_TUPLES_PER_UID: usize = 64;
+
+lazy_static! {
+    static ref DENIAL_LIMITER: DenialLimiter = Default::default();
+}
+
+static SUPPRESSED_DENIALS: AtomicU64 = AtomicU64::new(0);
+
+/// Reason for denying a request without checking the policy, because the same request was
+/// denied to the caller repeatedly. It is reported as `ResponseCode::PERMISSION_DENIED`, but
+/// it is not logged.
+#[derive(Debug, thiserror::Error, PartialEq)]
+#[error("Suppressed the repeated denial of {perm} to caller {caller_uid}.")]
+pub struct DenialSuppressed {
+    /// The denied permission.
+    pub perm: &'static str,
+    /// The calling uid.
+    pub caller_uid: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Tuple {
+    domain: i32,
+    nspace: i64,
+    alias: Option<String>,
+    perm: &'static str,
+}
+
+impl Tuple {
+    /// Returns None for keys that are not identified by their descriptor, i.e., key blobs.
+    fn new(perm: KeyPerm, key: &KeyDescriptor) -> Option<Self> {
+        if key.domain == Domain::BLOB {
+            return None;
+        }
+        Some(Self {
+            domain: key.domain.0,
+            nspace: key.nspace,
+            alias: key.alias.clone(),
+            perm: perm.to_selinux(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: u32,
+    refilled: Instant,
+    // The time of the last denial of each tuple.
+    denials: HashMap<Tuple, Instant>,
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Self {
+        Self { tokens: BURST, refilled: now, denials: Default::default() }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let refills = (now.saturating_duration_since(self.refilled).as_millis()
+            / REFILL_INTERVAL.as_millis()) as u32;
+        self.tokens = BURST.min(self.tokens.saturating_add(refills));
+        if self.tokens == BURST {
+            self.refilled = now;
+        } else {
+            self.refilled += REFILL_INTERVAL * refills;
+        }
+    }
+}
+
+/// What the limiter knows about a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// The caller was not denied anything recently.
+    Untracked,
+    /// The caller was denied requests recently, but this one is checked for real.
+    Tracked,
+    /// The request is denied without checking the policy.
+    Suppressed,
+}
+
+#[derive(Debug, Default)]
+struct DenialLimiter {
+    buckets: Mutex<HashMap<u32, Bucket>>,
+}
+
+impl DenialLimiter {
+    fn state(&self, caller_uid: u32, tuple: &Tuple, now: Instant) -> State {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = match buckets.get_mut(&caller_uid) {
+            Some(bucket) => bucket,
+            None => return State::Untracked,
+        };
+        bucket.refill(now);
+        let denied = bucket.denials.get(tuple);
+        if bucket.tokens == 0
+            && denied.map_or(false, |t| now.saturating_duration_since(*t) < WINDOW)
+        {
+            State::Suppressed
+        } else {
+            State::Tracked
+        }
+    }
+
+    fn note_denial(&self, caller_uid: u32, tuple: Tuple, now: Instant) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(caller_uid).or_insert_with(|| Bucket::new(now));
+        bucket.refill(now);
+        bucket.tokens = bucket.tokens.saturating_sub(1);
+        bucket.denials.retain(|_, t| now.saturating_duration_since(*t) < WINDOW);
+        if bucket.denials.len() < MAX_TUPLES_PER_UID || bucket.denials.contains_key(&tuple) {
+            bucket.denials.insert(tuple, now);
+        }
+        // Forget the uids that were not denied anything recently.
+        buckets.retain(|_, bucket| {
+            bucket.refill(now);
+            bucket.tokens < BURST
+        });
+    }
+
+    fn note_allowed(&self, caller_uid: u32, tuple: &Tuple) {
+        if let Some(bucket) = self.buckets.lock().unwrap().get_mut(&caller_uid) {
+            bucket.denials.remove(tuple);
+        }
+    }
+}
+
+/// Runs the permission check `check` of `perm` on `key` for `caller_uid`, unless the caller
+/// was denied the same request repeatedly. See the module documentation.
+pub fn check<F>(caller_uid: u32, perm: KeyPerm, key: &KeyDescriptor, check: F) -> Result<()>
+where
+    F: FnOnce() -> Result<()>,
+{
+    let tuple = match Tuple::new(perm, key) {
+        Some(tuple) => tuple,
+        None => return check(),
+    };
+    let state = DENIAL_LIMITER.state(caller_uid, &tuple, Instant::now());
+    if state == State::Suppressed {
+        SUPPRESSED_DENIALS.fetch_add(1, Ordering::Relaxed);
+        return Err(anyhow!(DenialSuppressed { perm: perm.to_selinux(), caller_uid }));
+    }
+    let result = check();
+    // The lock is taken a second time only for callers that were denied something recently.
+    match &result {
+        Ok(()) if state == State::Tracked => DENIAL_LIMITER.note_allowed(caller_uid, &tuple),
+        Ok(()) => {}
+        Err(e) if get_error_code(e) == ResponseCode::PERMISSION_DENIED.0 => {
+            DENIAL_LIMITER.note_denial(caller_uid, tuple, Instant::now())
+        }
+        Err(_) => {}
+    }
+    result
+}
+
+/// Writes the number of suppressed denials to `f`. Used by `IKeystoreMaintenance::dump`.
+pub fn dump(f: &mut dyn Write) -> std::io::Result<()> {
+    writeln!(f, "Suppressed permission denials: {}", SUPPRESSED_DENIALS.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tuple(alias: &str) -> Tuple {
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 10001,
+            alias: Some(alias.to_string()),
+            blob: None,
+        };
+        Tuple::new(KeyPerm::use_(), &key).unwrap()
+    }
+
+    #[test]
+    fn test_suppress_after_burst() {
+        let limiter = DenialLimiter::default();
+        let start = Instant::now();
+        for _ in 0..BURST {
+            assert_ne!(limiter.state(10001, &tuple("key"), start), State::Suppressed);
+            limiter.note_denial(10001, tuple("key"), start);
+        }
+        assert_eq!(limiter.state(10001, &tuple("key"), start), State::Suppressed);
+        // Other keys and other callers are checked as usual.
+        assert_eq!(limiter.state(10001, &tuple("other"), start), State::Tracked);
+        assert_eq!(limiter.state(10002, &tuple("key"), start), State::Untracked);
+        // A refilled token lets one request through.
+        assert_ne!(limiter.state(10001, &tuple("key"), start + REFILL_INTERVAL), State::Suppressed);
+        limiter.note_denial(10001, tuple("key"), start + REFILL_INTERVAL);
+        assert_eq!(limiter.state(10001, &tuple("key"), start + REFILL_INTERVAL), State::Suppressed);
+    }
+
+    #[test]
+    fn test_allowed_request_is_forgotten() {
+        let limiter = DenialLimiter::default();
+        let start = Instant::now();
+        for _ in 0..BURST {
+            limiter.note_denial(10001, tuple("key"), start);
+        }
+        limiter.note_allowed(10001, &tuple("key"));
+        assert_ne!(limiter.state(10001, &tuple("key"), start), State::Suppressed);
+    }
+
+    #[test]
+    fn test_denials_expire() {
+        let limiter = DenialLimiter::default();
+        let start = Instant::now();
+        for _ in 0..BURST {
+            limiter.note_denial(10001, tuple("key"), start);
+        }
+        // Keep the bucket empty with denials of another key.
+        for _ in 0..BURST {
+            limiter.note_denial(10001, tuple("other"), start + WINDOW);
+        }
+        assert_eq!(limiter.state(10001, &tuple("other"), start + WINDOW), State::Suppressed);
+        assert_ne!(limiter.state(10001, &tuple("key"), start + WINDOW), State::Suppressed);
+    }
+
+    #[test]
+    fn test_suppressed_denial_is_permission_denial() {
+        let e = anyhow!(DenialSuppressed { perm: "use", caller_uid: 10001 }).context("In test.");
+        assert!(crate::permission::is_permission_denied(&e));
+    }
+}
@@ -14,11 +14,12 @@
 
 //! Implement ECDH-based encryption.
 
+use crate::csprng::generate_salt;
 use anyhow::{Context, Result};
 use keystore2_crypto::{
     aes_gcm_decrypt, aes_gcm_encrypt, ec_key_generate_key, ec_key_get0_public_key,
     ec_key_marshal_private_key, ec_key_parse_private_key, ec_point_oct_to_point,
-    ec_point_point_to_oct, ecdh_compute_key, generate_salt, hkdf_expand, hkdf_extract, ECKey, ZVec,
+    ec_point_point_to_oct, ecdh_compute_key, hkdf_expand, hkdf_extract, ECKey, ZVec,
     AES_256_KEY_LENGTH,
 };
 
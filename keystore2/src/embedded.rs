@@ -0,0 +1,187 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the embedded mode of Keystore, which is built with the feature
+//! "embedded", see `libkeystore2_embedded`. It is meant for protected VM guests, e.g., in
+//! Microdroid, that need the key management semantics of Keystore without the services of a
+//! full Android system. The embedded mode uses the same database, enforcement, and operation
+//! code as the system Keystore. It differs in the following:
+//!
+//!  * The only security level is SOFTWARE, which is served by the software implementation of
+//!    KeyMint in process. Remote provisioning is not available.
+//!  * The persistent database lives in a directory of the guest's choosing or in memory.
+//!  * Access control does not consult an SELinux policy, see `EmbeddedAccessControl`. Only
+//!    the `Domain::SELINUX` namespaces that the guest names in `init` can be used.
+//!
+//! The guest calls `init` once and exposes the returned service, e.g., over RPC binder.
+
+use crate::access_control::{self, AccessControl};
+use crate::database::{InMemoryDbKeepAlive, KeystoreDB};
+use crate::globals::{DB_PATH, IN_MEMORY_DB};
+use crate::id_rotation::IdRotationState;
+use crate::service::KeystoreService;
+use android_system_keystore2::aidl::android::system::keystore2::IKeystoreService::IKeystoreService;
+use anyhow::{Context, Result};
+use binder::Strong;
+use keystore2_selinux as selinux;
+use lazy_static::lazy_static;
+use std::collections::HashSet;
+use std::ffi::{CStr, CString};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// The name of the shared in-memory database, see `Storage::InMemory`.
+const IN_MEMORY_DB_NAME: &str = "keystore2_embedded";
+
+/// The context that stands in for the SELinux context of all callers.
+const CALLER_CONTEXT: &str = "u:r:keystore_embedded_client:s0";
+
+/// The context of Keystore and of the keys in `Domain::APP`.
+const KEYSTORE_CONTEXT: &str = "u:r:keystore_embedded:s0";
+
+/// The context of the shared `Domain::SELINUX` namespaces, see `EmbeddedAccessControl`.
+const SHARED_NAMESPACE_CONTEXT: &str = "u:object_r:keystore_embedded_key:s0";
+
+lazy_static! {
+    static ref CALLER_CONTEXT_CSTR: CString = CString::new(CALLER_CONTEXT).unwrap();
+    /// Keeps the in-memory database alive while no thread has a connection to it.
+    static ref KEEP_ALIVE: Mutex<Option<InMemoryDbKeepAlive>> = Mutex::new(None);
+}
+
+/// Where the embedded mode keeps its persistent database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Storage {
+    /// The database is kept in a file in the given directory. Keys survive restarts of the
+    /// guest if the directory does.
+    Directory(PathBuf),
+    /// The database is kept in memory. All keys are lost when the process ends.
+    InMemory,
+}
+
+/// Returns the context that stands in for the SELinux context of callers, which have none in
+/// embedded mode.
+pub fn caller_context() -> &'static CStr {
+    &CALLER_CONTEXT_CSTR
+}
+
+/// The access control backend of the embedded mode. A guest is a single trust domain without
+/// an SELinux policy. All permissions of the `keystore2` class, which guard the administration
+/// of Keystore, are denied. The permissions of the `keystore2_key` class are granted on the
+/// keys in `Domain::APP`, which remain isolated by uid, because that check does not depend on
+/// the backend. In `Domain::SELINUX`, they are granted only on the namespaces that the guest
+/// shares among its callers. Other namespaces have no context and cannot be used.
+pub struct EmbeddedAccessControl {
+    shared_namespaces: HashSet<i64>,
+}
+
+impl EmbeddedAccessControl {
+    /// Creates a backend that grants access to the given `Domain::SELINUX` namespaces.
+    pub fn new(shared_namespaces: &[i64]) -> Self {
+        Self { shared_namespaces: shared_namespaces.iter().copied().collect() }
+    }
+}
+
+impl AccessControl for EmbeddedAccessControl {
+    fn keystore_context(&self) -> Result<selinux::Context> {
+        selinux::Context::new(KEYSTORE_CONTEXT)
+            .context("In EmbeddedAccessControl::keystore_context.")
+    }
+
+    fn key_context(&self, namespace: i64) -> Result<Option<selinux::Context>> {
+        if !self.shared_namespaces.contains(&namespace) {
+            return Ok(None);
+        }
+        selinux::Context::new(SHARED_NAMESPACE_CONTEXT)
+            .map(Some)
+            .context("In EmbeddedAccessControl::key_context.")
+    }
+
+    fn check_access(&self, source: &CStr, target: &CStr, tclass: &str, perm: &str) -> Result<()> {
+        if self.compute_access(source, target, tclass, perm)? {
+            Ok(())
+        } else {
+            Err(selinux::Error::perm())
+                .context(format!("In EmbeddedAccessControl::check_access: {} {}", tclass, perm))
+        }
+    }
+
+    fn compute_access(
+        &self,
+        _source: &CStr,
+        target: &CStr,
+        tclass: &str,
+        _perm: &str,
+    ) -> Result<bool> {
+        let target = target.to_str().unwrap_or("");
+        Ok(tclass == "keystore2_key"
+            && (target == KEYSTORE_CONTEXT || target == SHARED_NAMESPACE_CONTEXT))
+    }
+}
+
+/// Initializes the embedded mode and returns the Keystore service. All callers may use the
+/// keys in the `Domain::SELINUX` namespaces listed in `shared_namespaces`. It must be called
+/// once, before any other Keystore function is used.
+pub fn init(storage: Storage, shared_namespaces: &[i64]) -> Result<Strong<dyn IKeystoreService>> {
+    access_control::set_access_control(Arc::new(EmbeddedAccessControl::new(shared_namespaces)));
+    let id_rotation_path = match storage {
+        Storage::Directory(path) => {
+            *DB_PATH.write().expect("Could not lock DB_PATH.") = path.clone();
+            path
+        }
+        Storage::InMemory => {
+            *KEEP_ALIVE.lock().unwrap() = Some(
+                KeystoreDB::keep_in_memory_db_alive(IN_MEMORY_DB_NAME)
+                    .context("In init: Failed to create in-memory database.")?,
+            );
+            *IN_MEMORY_DB.write().unwrap() = Some(IN_MEMORY_DB_NAME.to_string());
+            // There is no place for the id rotation timestamp, so requests for unique ids
+            // fail.
+            DB_PATH.read().expect("Could not get DB_PATH.").clone()
+        }
+    };
+    KeystoreService::new_embedded_binder(IdRotationState::new(&id_rotation_path))
+        .context("In init: Failed to create the Keystore service.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedded_access_control() -> Result<()> {
+        let access_control = EmbeddedAccessControl::new(&[102]);
+        let target = access_control.keystore_context()?;
+        assert!(access_control
+            .check_access(caller_context(), &target, "keystore2_key", "use")
+            .is_ok());
+        assert!(access_control
+            .check_access(caller_context(), &target, "keystore2", "list")
+            .is_err());
+        assert!(!access_control.compute_access(caller_context(), &target, "keystore2", "list")?);
+
+        let shared = access_control.key_context(102)?.expect("Shared namespace has no context.");
+        assert!(access_control
+            .check_access(caller_context(), &shared, "keystore2_key", "get_info")
+            .is_ok());
+        assert_eq!(access_control.key_context(103)?, None);
+        let other = selinux::Context::new("u:object_r:vold_key:s0")?;
+        assert!(!access_control.compute_access(
+            caller_context(),
+            &other,
+            "keystore2_key",
+            "use"
+        )?);
+        Ok(())
+    }
+}
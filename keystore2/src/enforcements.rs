@@ -14,9 +14,12 @@
 
 //! This is the Keystore 2.0 Enforcements module.
 // TODO: more description to follow.
+use crate::apc::{ConfirmationToken, MAX_CONFIRMATION_MESSAGE_SIZE};
+use crate::buffer_pool::{PooledVec, UPDATE_PAYLOAD_BUFFERS};
 use crate::error::{map_binder_status, Error, ErrorCode};
 use crate::globals::{get_timestamp_service, ASYNC_TASK, DB, ENFORCEMENTS};
 use crate::key_parameter::{KeyParameter, KeyParameterValue};
+use crate::metrics_store::{log_clock_skew_tolerance_stats, log_key_use_throttled_stats};
 use crate::{authorization::Error as AuthzError, super_key::SuperEncryptionType};
 use crate::{
     database::{AuthTokenEntry, MonotonicRawTime},
@@ -25,27 +28,101 @@ use crate::{
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     Algorithm::Algorithm, ErrorCode::ErrorCode as Ec, HardwareAuthToken::HardwareAuthToken,
     HardwareAuthenticatorType::HardwareAuthenticatorType,
-    KeyParameter::KeyParameter as KmKeyParameter, KeyPurpose::KeyPurpose, Tag::Tag,
+    KeyParameter::KeyParameter as KmKeyParameter,
+    KeyParameterValue::KeyParameterValue as KmKeyParameterValue, KeyPurpose::KeyPurpose, Tag::Tag,
 };
 use android_hardware_security_secureclock::aidl::android::hardware::security::secureclock::{
     ISecureClock::ISecureClock, TimeStampToken::TimeStampToken,
 };
 use android_security_authorization::aidl::android::security::authorization::ResponseCode::ResponseCode as AuthzResponseCode;
+use android_security_keyparameters::aidl::android::security::keyparameters::{
+    KeystoreKeyFlag::KeystoreKeyFlag, KeystoreTag::KeystoreTag,
+};
 use android_system_keystore2::aidl::android::system::keystore2::{
     Domain::Domain, IKeystoreSecurityLevel::KEY_FLAG_AUTH_BOUND_WITHOUT_CRYPTOGRAPHIC_LSKF_BINDING,
     OperationChallenge::OperationChallenge,
 };
 use android_system_keystore2::binder::Strong;
 use anyhow::{Context, Result};
+use keystore2_system_property::PropertyWatcher;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     sync::{
         mpsc::{channel, Receiver, Sender, TryRecvError},
         Arc, Mutex, Weak,
     },
-    time::SystemTime,
+    time::{Duration, Instant, SystemTime},
 };
 
+/// System property holding the tolerance in milliseconds that is granted when enforcing the
+/// ACTIVE_DATETIME, ORIGINATION_EXPIRE_DATETIME, and USAGE_EXPIRE_DATETIME of a key. It avoids
+/// spurious failures while the clock is still being corrected, e.g., right after boot. If absent
+/// or zero, the validity period is enforced exactly. Larger values than
+/// MAX_CLOCK_SKEW_TOLERANCE_MS are capped.
+const CLOCK_SKEW_TOLERANCE_PROPERTY: &str = "keystore.validity_clock_skew_tolerance_ms";
+
+/// Upper bound for the clock skew tolerance in milliseconds. A misconfigured property must not
+/// effectively disable the validity period of keys.
+const MAX_CLOCK_SKEW_TOLERANCE_MS: i64 = 5 * 60 * 1000;
+
+/// Parses the value of CLOCK_SKEW_TOLERANCE_PROPERTY and caps it to the supported range.
+fn parse_clock_skew_tolerance(value: &str) -> Option<i64> {
+    value.parse::<i64>().ok().map(|ms| ms.clamp(0, MAX_CLOCK_SKEW_TOLERANCE_MS))
+}
+
+/// Key flag that makes a key user mediated when passed to generateKey or importKey. Every
+/// operation with a user mediated key must be approved by the user right before it begins, see
+/// `Enforcements::approve_key_use`.
+pub const USER_MEDIATED_FLAG: i32 = KeystoreKeyFlag::USER_MEDIATED.0;
+
+/// Operation parameter that carries the token returned by `Enforcements::approve_key_use` to
+/// createOperation. It is removed before the operation parameters are passed to KeyMint.
+pub const KEY_USE_APPROVAL_TAG: Tag = Tag(KeystoreTag::KEY_USE_APPROVAL.0);
+
+/// How long the approval of the use of a user mediated key remains valid.
+const KEY_USE_APPROVAL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Returns the approval token among the given operation parameters, see
+/// `KEY_USE_APPROVAL_TAG`.
+pub fn key_use_approval_token(op_params: &[KmKeyParameter]) -> Option<i64> {
+    op_params.iter().find_map(|p| match (p.tag, &p.value) {
+        (KEY_USE_APPROVAL_TAG, KmKeyParameterValue::LongInteger(token)) => Some(*token),
+        _ => None,
+    })
+}
+
+/// The approval of a single use of a user mediated key by the app `uid`.
+struct KeyUseApproval {
+    key_id: i64,
+    uid: u32,
+    approved_at: Instant,
+}
+
+/// Outcome of comparing the current time with one bound of the validity period of a key.
+#[derive(Debug, PartialEq, Eq)]
+enum ValidityCheck {
+    /// The current time is within the bound.
+    Satisfied,
+    /// The current time is outside the bound by the given number of milliseconds, but within
+    /// the tolerance.
+    WithinTolerance(i64),
+    /// The current time is outside the bound and the tolerance.
+    Violated,
+}
+
+/// Checks the current time `now` against `bound`, all in milliseconds since the epoch. A start
+/// bound is satisfied from the bound on, an end bound until the bound inclusively.
+fn check_validity_bound(now: i64, bound: i64, is_start: bool, tolerance: i64) -> ValidityCheck {
+    let skew = if is_start { bound.saturating_sub(now) } else { now.saturating_sub(bound) };
+    if skew <= 0 {
+        ValidityCheck::Satisfied
+    } else if skew <= tolerance {
+        ValidityCheck::WithinTolerance(skew)
+    } else {
+        ValidityCheck::Violated
+    }
+}
+
 #[derive(Debug)]
 enum AuthRequestState {
     /// An outstanding per operation authorization request.
@@ -140,6 +217,74 @@ enum DeferredAuthState {
     Token(HardwareAuthToken, Option<TimeStampToken>),
 }
 
+/// The number of confirmation tokens that are kept until the operation they were issued for
+/// finishes. Prompts are presented one at a time, so this only bounds the tokens of
+/// operations that never finish.
+const MAX_PENDING_CONFIRMATION_TOKENS: usize = 8;
+
+/// The confirmation tokens delivered by the APC service that were not yet used by the
+/// operation they were issued for.
+#[derive(Debug, Default)]
+struct ConfirmationTokens {
+    receiver: Option<Receiver<ConfirmationToken>>,
+    pending: VecDeque<ConfirmationToken>,
+}
+
+type ConfirmationTokenReceiver = Arc<Mutex<ConfirmationTokens>>;
+
+/// Reasons for refusing to finish an operation with a key that requires trusted confirmation.
+/// Both are reported to the client as `ErrorCode::NO_USER_CONFIRMATION`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ConfirmationError {
+    /// No confirmation prompt succeeded since the last operation that required confirmation.
+    #[error("No confirmation token was received.")]
+    NoConfirmation,
+    /// A confirmation prompt succeeded, but for a different app or a different message.
+    #[error("The confirmation token does not match the operation.")]
+    WrongConfirmation,
+}
+
+impl ConfirmationTokens {
+    /// Moves the tokens that the APC service delivered since the last call to the pending
+    /// tokens, dropping the oldest pending tokens beyond MAX_PENDING_CONFIRMATION_TOKENS.
+    fn receive(&mut self) {
+        if let Some(receiver) = &self.receiver {
+            loop {
+                match receiver.try_recv() {
+                    Ok(t) => self.pending.push_back(t),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        log::error!(concat!(
+                            "We got disconnected from the APC service, ",
+                            "this should never happen."
+                        ));
+                        break;
+                    }
+                }
+            }
+        }
+        while self.pending.len() > MAX_PENDING_CONFIRMATION_TOKENS {
+            self.pending.pop_front();
+        }
+    }
+
+    /// Takes the most recent pending token that was issued to the app `uid` for exactly
+    /// `message`. The message is None if it exceeded the size limit of confirmation prompts.
+    /// Tokens issued for other operations stay pending until those operations finish.
+    fn take(&mut self, uid: u32, message: Option<&[u8]>) -> Result<Vec<u8>, ConfirmationError> {
+        self.receive();
+        if !self.pending.iter().any(|t| t.uid == uid) {
+            return Err(ConfirmationError::NoConfirmation);
+        }
+        let index = self
+            .pending
+            .iter()
+            .rposition(|t| t.uid == uid && Some(t.data_confirmed.as_slice()) == message)
+            .ok_or(ConfirmationError::WrongConfirmation)?;
+        Ok(self.pending.remove(index).expect("The index was just found.").token)
+    }
+}
+
 /// Auth info hold all of the authorization related information of an operation. It is stored
 /// in and owned by the operation. It is constructed by authorize_create and stays with the
 /// operation until it completes.
@@ -148,7 +293,10 @@ pub struct AuthInfo {
     state: DeferredAuthState,
     /// An optional key id required to update the usage count if the key usage is limited.
     key_usage_limited: Option<i64>,
-    confirmation_token_receiver: Option<Arc<Mutex<Option<Receiver<Vec<u8>>>>>>,
+    confirmation_token_receiver: Option<ConfirmationTokenReceiver>,
+    /// The input of the operation if the key requires trusted confirmation. It is None if the
+    /// input exceeded MAX_CONFIRMATION_MESSAGE_SIZE.
+    confirmation_message: Option<PooledVec<u8>>,
 }
 
 struct TokenReceiverMap {
@@ -283,32 +431,43 @@ impl AuthInfo {
         self.get_auth_tokens()
     }
 
+    /// This function records the input of the operation if the key requires trusted
+    /// confirmation. It is called on update and finish.
+    pub fn record_input(&mut self, input: &[u8]) {
+        if self.confirmation_token_receiver.is_none() {
+            return;
+        }
+        if let Some(message) = &mut self.confirmation_message {
+            if message.len() + input.len() > MAX_CONFIRMATION_MESSAGE_SIZE {
+                self.confirmation_message = None;
+            } else {
+                message.extend_from_slice(input);
+            }
+        }
+    }
+
     /// This function is the authorization hook called before operation finish.
     /// It returns the auth tokens required by the operation to commence finish.
-    /// The third token is a confirmation token.
+    /// The third token is a confirmation token. If the key requires trusted confirmation, the
+    /// confirmation token must have been issued to the operation's owner `uid` for exactly the
+    /// input of the operation. Otherwise, this fails with `ErrorCode::NO_USER_CONFIRMATION`
+    /// and the `ConfirmationError` in its context tells why.
     pub fn before_finish(
         &mut self,
+        uid: u32,
     ) -> Result<(Option<HardwareAuthToken>, Option<TimeStampToken>, Option<Vec<u8>>)> {
         let mut confirmation_token: Option<Vec<u8>> = None;
         if let Some(ref confirmation_token_receiver) = self.confirmation_token_receiver {
-            let locked_receiver = confirmation_token_receiver.lock().unwrap();
-            if let Some(ref receiver) = *locked_receiver {
-                loop {
-                    match receiver.try_recv() {
-                        // As long as we get tokens we loop and discard all but the most
-                        // recent one.
-                        Ok(t) => confirmation_token = Some(t),
-                        Err(TryRecvError::Empty) => break,
-                        Err(TryRecvError::Disconnected) => {
-                            log::error!(concat!(
-                                "We got disconnected from the APC service, ",
-                                "this should never happen."
-                            ));
-                            break;
-                        }
-                    }
-                }
-            }
+            confirmation_token = Some(
+                confirmation_token_receiver
+                    .lock()
+                    .unwrap()
+                    .take(uid, self.confirmation_message.as_ref().map(|m| m.as_slice()))
+                    .map_err(|e| {
+                        anyhow::Error::new(Error::Km(ErrorCode::NO_USER_CONFIRMATION)).context(e)
+                    })
+                    .context("In before_finish.")?,
+            );
         }
         self.get_auth_tokens().map(|(hat, tst)| (hat, tst, confirmation_token))
     }
@@ -379,19 +538,116 @@ pub struct Enforcements {
     /// is cleaned up in regular intervals.
     op_auth_map: TokenReceiverMap,
     /// The enforcement module will try to get a confirmation token from this channel whenever
-    /// an operation that requires confirmation finishes.
-    confirmation_token_receiver: Arc<Mutex<Option<Receiver<Vec<u8>>>>>,
+    /// an operation that requires confirmation finishes. Tokens for other operations are kept
+    /// until those operations finish.
+    confirmation_token_receiver: ConfirmationTokenReceiver,
+    /// This field maps the ids of throttled keys to the earliest time of their next use.
+    next_key_use: Mutex<HashMap<i64, Instant>>,
+    /// Outstanding approvals of uses of user mediated keys, indexed by their token.
+    key_use_approvals: Mutex<HashMap<i64, KeyUseApproval>>,
 }
 
 impl Enforcements {
+    /// Records a use of the given key at `now` unless the key was last used less than `interval`
+    /// before. Returns false if the use was refused. Entries of keys that may be used again are
+    /// pruned on the way.
+    fn record_key_use(&self, key_id: i64, interval: Duration, now: Instant) -> bool {
+        let mut next_key_use = self.next_key_use.lock().unwrap();
+        next_key_use.retain(|_, next| *next > now);
+        if next_key_use.contains_key(&key_id) {
+            return false;
+        }
+        next_key_use.insert(key_id, now + interval);
+        true
+    }
+
+    /// Records a use of a key that carries `Tag::MIN_SECONDS_BETWEEN_OPS`, see `record_key_use`.
+    /// Called only once the creation of the operation is otherwise authorized, so that refused
+    /// requests do not count as uses.
+    fn throttle_key_use(
+        &self,
+        key_id: i64,
+        min_use_interval: Option<Duration>,
+        purpose: KeyPurpose,
+    ) -> Result<()> {
+        match min_use_interval {
+            Some(interval) if !self.record_key_use(key_id, interval, Instant::now()) => {
+                log_key_use_throttled_stats(purpose);
+                Err(Error::Km(Ec::KEY_RATE_LIMIT_EXCEEDED))
+                    .context("In throttle_key_use: key was used too recently.")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Records that the user approved a single use of the user mediated key `key_id` by the app
+    /// `uid`, e.g., in a system dialog, and returns the token that the app must pass to
+    /// createOperation, see `KEY_USE_APPROVAL_TAG`. The approval expires after
+    /// `KEY_USE_APPROVAL_TIMEOUT`.
+    pub fn approve_key_use(&self, key_id: i64, uid: u32) -> Result<i64> {
+        let token = loop {
+            let token = crate::csprng::random_i64().context("In approve_key_use.")?;
+            if token != 0 {
+                break token;
+            }
+        };
+        self.add_key_use_approval(token, key_id, uid, Instant::now());
+        Ok(token)
+    }
+
+    fn add_key_use_approval(&self, token: i64, key_id: i64, uid: u32, now: Instant) {
+        let mut approvals = self.key_use_approvals.lock().unwrap();
+        approvals
+            .retain(|_, a| now.saturating_duration_since(a.approved_at) < KEY_USE_APPROVAL_TIMEOUT);
+        approvals.insert(token, KeyUseApproval { key_id, uid, approved_at: now });
+    }
+
+    /// Consumes the approval `token` of a use of the user mediated key `key_id` by the app
+    /// `uid`. Fails with `ErrorCode::KEY_USER_NOT_AUTHENTICATED` if there is no such approval
+    /// or if it expired. A token is consumed even if it was presented for another key or app.
+    pub fn consume_key_use_approval(
+        &self,
+        key_id: i64,
+        uid: u32,
+        token: Option<i64>,
+    ) -> Result<()> {
+        self.consume_key_use_approval_at(key_id, uid, token, Instant::now())
+    }
+
+    fn consume_key_use_approval_at(
+        &self,
+        key_id: i64,
+        uid: u32,
+        token: Option<i64>,
+        now: Instant,
+    ) -> Result<()> {
+        let approval =
+            token.and_then(|token| self.key_use_approvals.lock().unwrap().remove(&token));
+        match approval {
+            Some(a)
+                if a.key_id == key_id
+                    && a.uid == uid
+                    && now.saturating_duration_since(a.approved_at) < KEY_USE_APPROVAL_TIMEOUT =>
+            {
+                Ok(())
+            }
+            Some(_) => Err(Error::Km(Ec::KEY_USER_NOT_AUTHENTICATED)).context(
+                "In consume_key_use_approval: The approval expired or does not match the key.",
+            ),
+            None => Err(Error::Km(Ec::KEY_USER_NOT_AUTHENTICATED))
+                .context("In consume_key_use_approval: The use of the key was not approved."),
+        }
+    }
+
     /// Install the confirmation token receiver. The enforcement module will try to get a
     /// confirmation token from this channel whenever an operation that requires confirmation
     /// finishes.
     pub fn install_confirmation_token_receiver(
         &self,
-        confirmation_token_receiver: Receiver<Vec<u8>>,
+        confirmation_token_receiver: Receiver<ConfirmationToken>,
     ) {
-        *self.confirmation_token_receiver.lock().unwrap() = Some(confirmation_token_receiver);
+        self.confirmation_token_receiver.lock().unwrap().receiver =
+            Some(confirmation_token_receiver);
     }
 
     /// Checks if a create call is authorized, given key parameters and operation parameters.
@@ -421,6 +677,7 @@ impl Enforcements {
                         state: DeferredAuthState::NoAuthRequired,
                         key_usage_limited: None,
                         confirmation_token_receiver: None,
+                        confirmation_message: None,
                     },
                 ))
             }
@@ -481,8 +738,9 @@ impl Enforcements {
         let mut allow_while_on_body = false;
         let mut unlocked_device_required = false;
         let mut key_usage_limited: Option<i64> = None;
-        let mut confirmation_token_receiver: Option<Arc<Mutex<Option<Receiver<Vec<u8>>>>>> = None;
+        let mut confirmation_token_receiver: Option<ConfirmationTokenReceiver> = None;
         let mut max_boot_level: Option<i32> = None;
+        let mut min_use_interval: Option<Duration> = None;
 
         // iterate through key parameters, recording information we need for authorization
         // enforcements later, or enforcing authorizations in place, where applicable
@@ -507,14 +765,14 @@ impl Enforcements {
                     caller_nonce_allowed = true;
                 }
                 KeyParameterValue::ActiveDateTime(a) => {
-                    if !Enforcements::is_given_time_passed(*a, true) {
+                    if !Enforcements::is_within_validity_bound(*a, true) {
                         return Err(Error::Km(Ec::KEY_NOT_YET_VALID))
                             .context("In authorize_create: key is not yet active.");
                     }
                 }
                 KeyParameterValue::OriginationExpireDateTime(o) => {
                     if (purpose == KeyPurpose::ENCRYPT || purpose == KeyPurpose::SIGN)
-                        && Enforcements::is_given_time_passed(*o, false)
+                        && !Enforcements::is_within_validity_bound(*o, false)
                     {
                         return Err(Error::Km(Ec::KEY_EXPIRED))
                             .context("In authorize_create: key is expired.");
@@ -522,7 +780,7 @@ impl Enforcements {
                 }
                 KeyParameterValue::UsageExpireDateTime(u) => {
                     if (purpose == KeyPurpose::DECRYPT || purpose == KeyPurpose::VERIFY)
-                        && Enforcements::is_given_time_passed(*u, false)
+                        && !Enforcements::is_within_validity_bound(*u, false)
                     {
                         return Err(Error::Km(Ec::KEY_EXPIRED))
                             .context("In authorize_create: key is expired.");
@@ -552,6 +810,12 @@ impl Enforcements {
                 KeyParameterValue::MaxBootLevel(level) => {
                     max_boot_level = Some(*level);
                 }
+                KeyParameterValue::MinSecondsBetweenOps(s) if *s > 0 => {
+                    // Keys that device policy considers high value carry a minimum interval
+                    // between uses, which is enforced here as well as by KeyMint to blunt the
+                    // abuse of such keys as an oracle by a compromised app.
+                    min_use_interval = Some(Duration::from_secs(*s as u64));
+                }
                 // NOTE: as per offline discussion, sanitizing key parameters and rejecting
                 // create operation if any non-allowed tags are present, is not done in
                 // authorize_create (unlike in legacy keystore where AuthorizeBegin is rejected if
@@ -612,13 +876,19 @@ impl Enforcements {
             }
         }
 
+        let confirmation_message =
+            confirmation_token_receiver.as_ref().map(|_| UPDATE_PAYLOAD_BUFFERS.take(0));
+
         if !unlocked_device_required && no_auth_required {
+            self.throttle_key_use(key_id, min_use_interval, purpose)
+                .context("In authorize_create.")?;
             return Ok((
                 None,
                 AuthInfo {
                     state: DeferredAuthState::NoAuthRequired,
                     key_usage_limited,
                     confirmation_token_receiver,
+                    confirmation_message,
                 },
             ));
         }
@@ -675,6 +945,8 @@ impl Enforcements {
             _ => None,
         };
 
+        self.throttle_key_use(key_id, min_use_interval, purpose).context("In authorize_create.")?;
+
         Ok(match (hat, requires_timestamp, per_op_bound) {
             // Per-op-bound and Some(hat) can only happen if we are both per-op bound and unlocked
             // device required. In addition, this KM instance needs a timestamp token.
@@ -695,7 +967,15 @@ impl Enforcements {
             (None, _, false) => (None, DeferredAuthState::NoAuthRequired),
         })
         .map(|(hat, state)| {
-            (hat, AuthInfo { state, key_usage_limited, confirmation_token_receiver })
+            (
+                hat,
+                AuthInfo {
+                    state,
+                    key_usage_limited,
+                    confirmation_token_receiver,
+                    confirmation_message,
+                },
+            )
         })
     }
 
@@ -706,20 +986,31 @@ impl Enforcements {
         DB.with(|db| db.borrow().find_auth_token_entry(p))
     }
 
-    /// Checks if the time now since epoch is greater than (or equal, if is_given_time_inclusive is
-    /// set) the given time (in milliseconds)
-    fn is_given_time_passed(given_time: i64, is_given_time_inclusive: bool) -> bool {
-        let duration_since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
-
-        let time_since_epoch = match duration_since_epoch {
-            Ok(duration) => duration.as_millis(),
-            Err(_) => return false,
-        };
+    /// Returns the clock skew tolerance for validity periods in milliseconds.
+    fn clock_skew_tolerance() -> i64 {
+        PropertyWatcher::new(CLOCK_SKEW_TOLERANCE_PROPERTY)
+            .ok()
+            .and_then(|mut w| w.read(|_n, v| Ok(parse_clock_skew_tolerance(v))).ok().flatten())
+            .unwrap_or(0)
+    }
 
-        if is_given_time_inclusive {
-            time_since_epoch >= (given_time as u128)
-        } else {
-            time_since_epoch > (given_time as u128)
+    /// Checks the current time against a start bound, i.e., ACTIVE_DATETIME, or an end bound,
+    /// i.e., an expiration date, of the validity period of a key, granting the configured clock
+    /// skew tolerance. Uses within the tolerance are reported as metric.
+    fn is_within_validity_bound(bound: i64, is_start: bool) -> bool {
+        // A clock before the epoch is treated like the epoch itself.
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_millis() as i64);
+
+        match check_validity_bound(now, bound, is_start, Self::clock_skew_tolerance()) {
+            ValidityCheck::Satisfied => true,
+            ValidityCheck::WithinTolerance(skew) => {
+                log::info!("Allowing key use {} ms outside of its validity period.", skew);
+                log_clock_skew_tolerance_stats(!is_start, skew);
+                true
+            }
+            ValidityCheck::Violated => false,
         }
     }
 
@@ -853,3 +1144,195 @@ impl Enforcements {
 }
 
 // TODO: Add tests to enforcement module (b/175578618).
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_validity_bound_test() {
+        // Start bounds are satisfied from the bound on.
+        assert_eq!(check_validity_bound(1000, 1000, true, 0), ValidityCheck::Satisfied);
+        assert_eq!(check_validity_bound(999, 1000, true, 0), ValidityCheck::Violated);
+        assert_eq!(check_validity_bound(900, 1000, true, 100), ValidityCheck::WithinTolerance(100));
+        assert_eq!(check_validity_bound(899, 1000, true, 100), ValidityCheck::Violated);
+        // End bounds are satisfied until the bound inclusively.
+        assert_eq!(check_validity_bound(1000, 1000, false, 0), ValidityCheck::Satisfied);
+        assert_eq!(check_validity_bound(1001, 1000, false, 0), ValidityCheck::Violated);
+        assert_eq!(
+            check_validity_bound(1050, 1000, false, 100),
+            ValidityCheck::WithinTolerance(50)
+        );
+        assert_eq!(check_validity_bound(1101, 1000, false, 100), ValidityCheck::Violated);
+    }
+
+    #[test]
+    fn clock_skew_tolerance_is_capped() {
+        assert_eq!(parse_clock_skew_tolerance("1500"), Some(1500));
+        assert_eq!(parse_clock_skew_tolerance("-1"), Some(0));
+        assert_eq!(parse_clock_skew_tolerance("86400000"), Some(MAX_CLOCK_SKEW_TOLERANCE_MS));
+        assert_eq!(parse_clock_skew_tolerance("soon"), None);
+    }
+
+    #[test]
+    fn key_use_is_throttled_per_key() {
+        let enforcements = Enforcements::default();
+        let now = Instant::now();
+        let interval = Duration::from_secs(5);
+        assert!(enforcements.record_key_use(1, interval, now));
+        assert!(!enforcements.record_key_use(1, interval, now + Duration::from_secs(4)));
+        // Other keys and their intervals are independent.
+        assert!(enforcements.record_key_use(2, Duration::from_secs(1), now));
+        assert!(enforcements.record_key_use(
+            2,
+            Duration::from_secs(1),
+            now + Duration::from_secs(1)
+        ));
+        assert!(enforcements.record_key_use(1, interval, now + interval));
+    }
+
+    #[test]
+    fn key_use_approvals_are_single_use() {
+        let enforcements = Enforcements::default();
+        let now = Instant::now();
+        enforcements.add_key_use_approval(7, 1, 10001, now);
+        assert!(enforcements.consume_key_use_approval_at(1, 10001, Some(7), now).is_ok());
+        assert!(enforcements.consume_key_use_approval_at(1, 10001, Some(7), now).is_err());
+        assert!(enforcements.consume_key_use_approval_at(1, 10001, None, now).is_err());
+    }
+
+    #[test]
+    fn key_use_approvals_are_bound_to_key_uid_and_time() {
+        let enforcements = Enforcements::default();
+        let now = Instant::now();
+        enforcements.add_key_use_approval(7, 1, 10001, now);
+        // A mismatching attempt consumes the approval.
+        assert!(enforcements.consume_key_use_approval_at(2, 10001, Some(7), now).is_err());
+        assert!(enforcements.consume_key_use_approval_at(1, 10001, Some(7), now).is_err());
+
+        enforcements.add_key_use_approval(8, 1, 10001, now);
+        assert!(enforcements.consume_key_use_approval_at(1, 10002, Some(8), now).is_err());
+
+        enforcements.add_key_use_approval(9, 1, 10001, now);
+        let later = now + KEY_USE_APPROVAL_TIMEOUT;
+        assert!(enforcements.consume_key_use_approval_at(1, 10001, Some(9), later).is_err());
+    }
+
+    #[test]
+    fn key_use_approval_token_is_found_in_op_params() {
+        let token_param = KmKeyParameter {
+            tag: KEY_USE_APPROVAL_TAG,
+            value: KmKeyParameterValue::LongInteger(42),
+        };
+        assert_eq!(key_use_approval_token(&[]), None);
+        assert_eq!(key_use_approval_token(&[token_param]), Some(42));
+    }
+
+    /// Creates the auth info of an operation with a key that requires trusted confirmation,
+    /// and the sender, over which the tests deliver confirmation tokens like the APC service.
+    fn confirmation_auth_info() -> (Sender<ConfirmationToken>, AuthInfo) {
+        let (sender, receiver) = channel();
+        let tokens = ConfirmationTokens { receiver: Some(receiver), ..Default::default() };
+        (sender, auth_info_with_tokens(&Arc::new(Mutex::new(tokens))))
+    }
+
+    fn auth_info_with_tokens(tokens: &ConfirmationTokenReceiver) -> AuthInfo {
+        AuthInfo {
+            state: DeferredAuthState::NoAuthRequired,
+            key_usage_limited: None,
+            confirmation_token_receiver: Some(tokens.clone()),
+            confirmation_message: Some(UPDATE_PAYLOAD_BUFFERS.take(0)),
+        }
+    }
+
+    fn confirm(sender: &Sender<ConfirmationToken>, uid: u32, data_confirmed: &[u8]) {
+        sender
+            .send(ConfirmationToken {
+                uid,
+                data_confirmed: data_confirmed.to_vec(),
+                token: [&uid.to_be_bytes(), data_confirmed].concat(),
+            })
+            .unwrap();
+    }
+
+    fn confirmation_error(auth_info: &mut AuthInfo, uid: u32) -> Option<ConfirmationError> {
+        let e = auth_info.before_finish(uid).unwrap_err();
+        assert_eq!(
+            e.root_cause().downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::NO_USER_CONFIRMATION))
+        );
+        e.downcast_ref::<ConfirmationError>().copied()
+    }
+
+    #[test]
+    fn before_finish_binds_confirmation_token() -> Result<()> {
+        let (sender, mut auth_info) = confirmation_auth_info();
+        confirm(&sender, 10001, b"message");
+        confirm(&sender, 10002, b"message");
+        confirm(&sender, 10001, b"other message");
+        auth_info.record_input(b"mess");
+        auth_info.record_input(b"age");
+
+        let (_, _, token) = auth_info.before_finish(10001)?;
+        assert_eq!(token, Some([&10001u32.to_be_bytes(), &b"message"[..]].concat()));
+        Ok(())
+    }
+
+    #[test]
+    fn before_finish_keeps_tokens_of_other_operations() -> Result<()> {
+        let (sender, receiver) = channel();
+        let tokens: ConfirmationTokenReceiver = Arc::new(Mutex::new(ConfirmationTokens {
+            receiver: Some(receiver),
+            ..Default::default()
+        }));
+        let mut first = auth_info_with_tokens(&tokens);
+        let mut second = auth_info_with_tokens(&tokens);
+        first.record_input(b"first message");
+        second.record_input(b"second message");
+        confirm(&sender, 10001, b"first message");
+        confirm(&sender, 10002, b"second message");
+
+        let (_, _, token) = first.before_finish(10001)?;
+        assert_eq!(token, Some([&10001u32.to_be_bytes(), &b"first message"[..]].concat()));
+        let (_, _, token) = second.before_finish(10002)?;
+        assert_eq!(token, Some([&10002u32.to_be_bytes(), &b"second message"[..]].concat()));
+
+        // A token is used only once.
+        let mut third = auth_info_with_tokens(&tokens);
+        third.record_input(b"first message");
+        assert_eq!(confirmation_error(&mut third, 10001), Some(ConfirmationError::NoConfirmation));
+        Ok(())
+    }
+
+    #[test]
+    fn before_finish_distinguishes_confirmation_errors() {
+        let (sender, mut auth_info) = confirmation_auth_info();
+        auth_info.record_input(b"message");
+        assert_eq!(
+            confirmation_error(&mut auth_info, 10001),
+            Some(ConfirmationError::NoConfirmation)
+        );
+
+        confirm(&sender, 10001, b"other message");
+        assert_eq!(
+            confirmation_error(&mut auth_info, 10001),
+            Some(ConfirmationError::WrongConfirmation)
+        );
+
+        confirm(&sender, 10002, b"message");
+        assert_eq!(
+            confirmation_error(&mut auth_info, 10001),
+            Some(ConfirmationError::WrongConfirmation)
+        );
+
+        // Messages beyond the size limit of confirmation prompts cannot have been confirmed.
+        let (sender, mut auth_info) = confirmation_auth_info();
+        let message = vec![0; MAX_CONFIRMATION_MESSAGE_SIZE + 1];
+        auth_info.record_input(&message);
+        confirm(&sender, 10001, &message);
+        assert_eq!(
+            confirmation_error(&mut auth_info, 10001),
+            Some(ConfirmationError::WrongConfirmation)
+        );
+    }
+}
@@ -43,7 +43,7 @@ pub fn register_feeder() {
 }
 
 fn get_entropy(size: usize) -> Result<Vec<u8>> {
-    keystore2_crypto::generate_random_data(size).context("Retrieving entropy for KeyMint device")
+    crate::csprng::random_data(size).context("Retrieving entropy for KeyMint device")
 }
 
 /// Feed entropy to all known KeyMint devices.
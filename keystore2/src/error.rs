@@ -29,14 +29,34 @@
 //!
 //! Keystore functions should use `anyhow::Result` to return error conditions, and
 //! context should be added every time an error is forwarded.
-
+//!
+//! Transient errors caused by congestion may carry a `RetryAfter` context, which is reported
+//! to the client in the typed error details.
+//!
+//! The details of every reported error are also recorded by `error_details`, from where
+//! clients can retrieve them in typed form. The message of the service specific error carries
+//! the id of the recorded details as `error_id=<id>`.
+
+use crate::audit_log;
+use crate::caller_identity::CallerIdentity;
+use crate::denial_limiter::DenialSuppressed;
+use crate::error_details;
+use crate::key_policy::KeyPolicyDenied;
+use crate::namespace_freeze::NamespaceFrozen;
+use crate::permission::{is_permission_denied, PermissionDenied, UnknownNamespace};
+use crate::quota::QuotaExceeded;
+use crate::tenants::TenantError;
+use crate::trace;
 pub use android_hardware_security_keymint::aidl::android::hardware::security::keymint::ErrorCode::ErrorCode;
+pub use android_security_errors::aidl::android::security::errors::KeystoreErrorCode::KeystoreErrorCode;
 pub use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
 use android_system_keystore2::binder::{
     ExceptionCode, Result as BinderResult, Status as BinderStatus, StatusCode,
 };
 use keystore2_selinux as selinux;
 use std::cmp::PartialEq;
+use std::ffi::CString;
+use std::time::Duration;
 
 /// This is the main Keystore error type. It wraps the Keystore `ResponseCode` generated
 /// from AIDL in the `Rc` variant and Keymint `ErrorCode` in the Km variant.
@@ -60,6 +80,13 @@ pub enum Error {
     Rp(ErrorCode),
 }
 
+/// Attached as context to transient errors, typically `ResponseCode::BACKEND_BUSY`, that are
+/// caused by congestion. It tells the client how long to back off before retrying. The hint is
+/// reported as `ErrorDetails::retryAfterMillis`, see `error_details`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("Retry after {0:?}.")]
+pub struct RetryAfter(pub Duration);
+
 impl Error {
     /// Short hand for `Error::Rc(ResponseCode::SYSTEM_ERROR)`
     pub fn sys() -> Self {
@@ -138,11 +165,16 @@ pub fn map_binder_status_code<T>(r: Result<T, StatusCode>) -> Result<T, Error> {
 /// into service specific exceptions.
 ///
 /// All error conditions get logged by this function, except for KEY_NOT_FOUND error.
+/// Permission denials are recorded in the audit log as well, see `audit_log`.
 ///
 /// All `Error::Rc(x)` and `Error::Km(x)` variants get mapped onto a service specific error
 /// code of x. This is possible because KeyMint `ErrorCode` errors are always negative and
 /// `ResponseCode` codes are always positive.
 /// `selinux::Error::PermissionDenied` is mapped on `ResponseCode::PERMISSION_DENIED`.
+/// `PermissionDenied`, `UnknownNamespace`, `TenantError`, and `KeyPolicyDenied` are mapped on
+/// `ResponseCode::PERMISSION_DENIED` as well.
+/// `QuotaExceeded` is mapped on `KeystoreErrorCode::QUOTA_EXCEEDED`.
+/// `NamespaceFrozen` is mapped on `KeystoreErrorCode::NAMESPACE_FROZEN`.
 ///
 /// All non `Error` error conditions and the Error::Binder variant get mapped onto
 /// ResponseCode::SYSTEM_ERROR`.
@@ -171,12 +203,19 @@ where
     map_err_with(
         result,
         |e| {
-            // Make the key not found errors silent.
+            // Make the key not found errors and suppressed permission denials silent.
             if !matches!(
                 e.root_cause().downcast_ref::<Error>(),
                 Some(Error::Rc(ResponseCode::KEY_NOT_FOUND))
-            ) {
-                log::error!("{:?}", e);
+            ) && !e.root_cause().is::<DenialSuppressed>()
+            {
+                match trace::current() {
+                    Some(trace_id) => log::error!("trace: {} {:?}", trace_id, e),
+                    None => log::error!("{:?}", e),
+                }
+                if is_permission_denied(&e) {
+                    audit_log::log_permission_denied(&e, &CallerIdentity::current());
+                }
             }
             e
         },
@@ -199,8 +238,10 @@ where
     result.map_or_else(
         |e| {
             let e = map_err(e);
+            let error_id = error_details::record(&e);
             let rc = get_error_code(&e);
-            Err(BinderStatus::new_service_specific_error(rc, None))
+            let message = CString::new(format!("error_id={}", error_id)).ok();
+            Err(BinderStatus::new_service_specific_error(rc, message.as_deref()))
         },
         handle_ok,
     )
@@ -221,11 +262,35 @@ pub fn get_error_code(e: &anyhow::Error) -> i32 {
         }
         None => match root_cause.downcast_ref::<selinux::Error>() {
             Some(selinux::Error::PermissionDenied) => ResponseCode::PERMISSION_DENIED.0,
+            _ if root_cause.is::<PermissionDenied>() => ResponseCode::PERMISSION_DENIED.0,
+            _ if root_cause.is::<UnknownNamespace>() => ResponseCode::PERMISSION_DENIED.0,
+            _ if root_cause.is::<TenantError>() => ResponseCode::PERMISSION_DENIED.0,
+            _ if root_cause.is::<KeyPolicyDenied>() => ResponseCode::PERMISSION_DENIED.0,
+            _ if root_cause.is::<DenialSuppressed>() => ResponseCode::PERMISSION_DENIED.0,
+            _ if root_cause.is::<QuotaExceeded>() => KeystoreErrorCode::QUOTA_EXCEEDED.0,
+            _ if root_cause.is::<NamespaceFrozen>() => KeystoreErrorCode::NAMESPACE_FROZEN.0,
             _ => ResponseCode::SYSTEM_ERROR.0,
         },
     }
 }
 
+/// Returns true if the given error is a binder transport failure, i.e., the remote object
+/// died or the transaction could not be delivered. In these cases the callee never got to
+/// report a result, so it is safe to reconnect and retry idempotent calls.
+pub fn is_transport_error(e: &Error) -> bool {
+    matches!(
+        e,
+        Error::BinderTransaction(StatusCode::DEAD_OBJECT)
+            | Error::BinderTransaction(StatusCode::FAILED_TRANSACTION)
+            | Error::Binder(ExceptionCode::TRANSACTION_FAILED, _)
+    )
+}
+
+/// Like `is_transport_error` but inspects the root cause of the given error.
+pub fn is_binder_transport_error(e: &anyhow::Error) -> bool {
+    e.root_cause().downcast_ref::<Error>().map_or(false, is_transport_error)
+}
+
 #[cfg(test)]
 pub mod tests {
 
@@ -363,6 +428,21 @@ pub mod tests {
                 .map_err(|s| ResponseCode(s.service_specific_error()))
         );
 
+        // PermissionDenied needs to be mapped to ResponseCode::PERMISSION_DENIED as well.
+        let denied = PermissionDenied {
+            class: "keystore2_key",
+            perm: "use",
+            source_ctx: "u:r:shell:s0".to_string(),
+            target_ctx: None,
+        };
+        assert_eq!(
+            Result::<(), ResponseCode>::Err(ResponseCode::PERMISSION_DENIED),
+            map_or_log_err(Err::<(), _>(anyhow!(denied)).context("In test."), |_| {
+                Err(BinderStatus::ok())
+            })
+            .map_err(|s| ResponseCode(s.service_specific_error()))
+        );
+
         // All other errors get mapped on System Error.
         assert_eq!(
             Result::<(), ResponseCode>::Err(ResponseCode::SYSTEM_ERROR),
@@ -380,6 +460,28 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn binder_transport_errors() {
+        assert!(is_binder_transport_error(
+            &anyhow::Error::new(Error::BinderTransaction(StatusCode::DEAD_OBJECT)).context("dead")
+        ));
+        assert!(is_binder_transport_error(&anyhow::Error::new(Error::BinderTransaction(
+            StatusCode::FAILED_TRANSACTION
+        ))));
+        assert!(is_binder_transport_error(
+            &anyhow::Error::new(Error::Binder(ExceptionCode::TRANSACTION_FAILED, 0))
+                .context("transaction failed")
+        ));
+        assert!(!is_binder_transport_error(&anyhow::Error::new(Error::Km(
+            ErrorCode::HARDWARE_TYPE_UNAVAILABLE
+        ))));
+        assert!(!is_binder_transport_error(&anyhow::Error::new(Error::Binder(
+            ExceptionCode::SERVICE_SPECIFIC,
+            1
+        ))));
+        assert!(!is_binder_transport_error(&anyhow!("not a keystore error")));
+    }
+
     //Helper function to test whether error cases are handled as expected.
     pub fn check_result_contains_error_string<T>(
         result: anyhow::Result<T>,
@@ -0,0 +1,221 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the IKeystoreErrorDetails AIDL interface. Errors are reported to
+//! clients as service specific exceptions, which only carry an error code and a message. The
+//! machine-readable details of each reported error, i.e., the subsystem that caused it, whether
+//! it may be retried, the error code of the HAL, and the trace id of the request, are retained
+//! for the most recent errors of each uid, so that clients can retrieve them in typed form.
+//! Each recorded error gets an id, which is reported in the message of the service specific
+//! exception as `error_id=<id>`, so that clients can correlate the exception with its details.
+
+use crate::error::{
+    get_error_code, is_binder_transport_error, map_or_log_err, Error, ErrorCode, ResponseCode,
+    RetryAfter,
+};
+use crate::namespace_freeze::NamespaceFrozen;
+use crate::permission::{PermissionDenied, UnknownNamespace};
+use crate::tenants::TenantError;
+use crate::trace;
+use crate::utils::watchdog as wd;
+use android_security_errors::aidl::android::security::errors::{
+    ErrorDetails::ErrorDetails,
+    IKeystoreErrorDetails::{BnKeystoreErrorDetails, IKeystoreErrorDetails},
+};
+use android_security_errors::binder::{
+    BinderFeatures, Interface, Result as BinderResult, Strong, ThreadState,
+};
+use anyhow::Result;
+use keystore2_selinux as selinux;
+use lazy_static::lazy_static;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// The number of errors retained per uid.
+const MAX_ERRORS_PER_UID: usize = 8;
+
+/// The number of uids for which errors are retained. If more uids encounter errors, the
+/// errors of an arbitrary other uid are dropped.
+const MAX_UIDS: usize = 128;
+
+/// Bounded record of the most recent errors per uid.
+#[derive(Default)]
+struct ErrorLog {
+    errors: HashMap<u32, VecDeque<ErrorDetails>>,
+    last_error_id: i64,
+}
+
+impl ErrorLog {
+    /// Records the details of an error of `uid` and returns the id assigned to it.
+    fn insert(&mut self, uid: u32, mut details: ErrorDetails) -> i64 {
+        self.last_error_id += 1;
+        details.errorId = self.last_error_id;
+        if !self.errors.contains_key(&uid) && self.errors.len() >= MAX_UIDS {
+            if let Some(victim) = self.errors.keys().next().copied() {
+                self.errors.remove(&victim);
+            }
+        }
+        let errors = self.errors.entry(uid).or_default();
+        if errors.len() >= MAX_ERRORS_PER_UID {
+            errors.pop_back();
+        }
+        errors.push_front(details);
+        self.last_error_id
+    }
+
+    fn recent(&self, uid: u32) -> Vec<ErrorDetails> {
+        self.errors.get(&uid).map(|errors| errors.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    fn find(&self, uid: u32, error_id: i64) -> Option<ErrorDetails> {
+        self.errors.get(&uid)?.iter().find(|details| details.errorId == error_id).cloned()
+    }
+}
+
+lazy_static! {
+    static ref ERROR_LOG: Mutex<ErrorLog> = Default::default();
+}
+
+/// Computes the details of the given error as it is reported to the client.
+pub fn details_of(e: &anyhow::Error) -> ErrorDetails {
+    let root_cause = e.root_cause();
+    let (subsystem, hal_error_code) = match root_cause.downcast_ref::<Error>() {
+        Some(Error::Km(ec)) => ("keymint", ec.0),
+        Some(Error::Rc(_)) => ("keystore", 0),
+        Some(Error::Rp(_)) => ("remote_provisioning", 0),
+        Some(Error::Binder(_, se)) => ("binder", *se),
+        Some(Error::BinderTransaction(_)) => ("binder", 0),
+        None if root_cause.is::<selinux::Error>()
+            || root_cause.is::<PermissionDenied>()
+            || root_cause.is::<UnknownNamespace>()
+            || root_cause.is::<TenantError>()
+            || root_cause.is::<NamespaceFrozen>() =>
+        {
+            ("access_control", 0)
+        }
+        None => ("keystore", 0),
+    };
+    let retry_after = e.downcast_ref::<RetryAfter>().map(|RetryAfter(d)| d.as_millis() as i64);
+    let retryable = retry_after.is_some()
+        || is_binder_transport_error(e)
+        || matches!(
+            root_cause.downcast_ref::<Error>(),
+            Some(Error::Rc(ResponseCode::BACKEND_BUSY))
+                | Some(Error::Rc(ResponseCode::OPERATION_BUSY))
+                | Some(Error::Km(ErrorCode::TOO_MANY_OPERATIONS))
+        );
+    ErrorDetails {
+        code: get_error_code(e),
+        subsystem: subsystem.to_string(),
+        retryable,
+        retryAfterMillis: retry_after.unwrap_or(-1),
+        halErrorCode: hal_error_code,
+        traceId: trace::current().map(|id| id.to_string()),
+        errorId: 0,
+    }
+}
+
+/// Records the details of an error that is about to be reported to the calling uid. Returns the
+/// id of the error, which is reported along with it.
+pub fn record(e: &anyhow::Error) -> i64 {
+    let uid = ThreadState::get_calling_uid();
+    ERROR_LOG.lock().unwrap().insert(uid, details_of(e))
+}
+
+/// Records the details of an error of the given uid that was not reported to it as a service
+/// specific exception, e.g., `KeystoreErrorCode::OPERATION_LOST`.
+pub fn record_for_uid(uid: u32, details: ErrorDetails) {
+    ERROR_LOG.lock().unwrap().insert(uid, details);
+}
+
+/// Implementation of the IKeystoreErrorDetails AIDL interface.
+pub struct ErrorDetailsService;
+
+impl ErrorDetailsService {
+    /// Creates a new instance of the error details service wrapped in a
+    /// BnKeystoreErrorDetails proxy object.
+    pub fn new_native_binder() -> Result<Strong<dyn IKeystoreErrorDetails>> {
+        Ok(BnKeystoreErrorDetails::new_binder(Self, BinderFeatures::default()))
+    }
+}
+
+impl Interface for ErrorDetailsService {}
+
+impl IKeystoreErrorDetails for ErrorDetailsService {
+    fn getRecentErrors(&self) -> BinderResult<Vec<ErrorDetails>> {
+        let _wp = wd::watch_millis("IKeystoreErrorDetails::getRecentErrors", 500);
+        let uid = ThreadState::get_calling_uid();
+        map_or_log_err(Ok(ERROR_LOG.lock().unwrap().recent(uid)), Ok)
+    }
+
+    fn getErrorDetails(&self, error_id: i64) -> BinderResult<Option<ErrorDetails>> {
+        let _wp = wd::watch_millis("IKeystoreErrorDetails::getErrorDetails", 500);
+        let uid = ThreadState::get_calling_uid();
+        map_or_log_err(Ok(ERROR_LOG.lock().unwrap().find(uid, error_id)), Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+    use std::time::Duration;
+
+    #[test]
+    fn details_of_errors() {
+        let e = anyhow!(Error::Km(ErrorCode::TOO_MANY_OPERATIONS)).context("In test.");
+        let details = details_of(&e);
+        assert_eq!(details.code, ErrorCode::TOO_MANY_OPERATIONS.0);
+        assert_eq!(details.subsystem, "keymint");
+        assert!(details.retryable);
+        assert_eq!(details.retryAfterMillis, -1);
+        assert_eq!(details.halErrorCode, ErrorCode::TOO_MANY_OPERATIONS.0);
+
+        let e = anyhow!(Error::Rc(ResponseCode::BACKEND_BUSY))
+            .context(RetryAfter(Duration::from_millis(250)))
+            .context("In test.");
+        let details = details_of(&e);
+        assert_eq!(details.subsystem, "keystore");
+        assert!(details.retryable);
+        assert_eq!(details.retryAfterMillis, 250);
+
+        let details = details_of(&anyhow!(selinux::Error::perm()));
+        assert_eq!(details.code, ResponseCode::PERMISSION_DENIED.0);
+        assert_eq!(details.subsystem, "access_control");
+        assert!(!details.retryable);
+    }
+
+    #[test]
+    fn error_log_is_bounded() {
+        let mut log = ErrorLog::default();
+        for code in 0..(MAX_ERRORS_PER_UID as i32 + 2) {
+            log.insert(1, ErrorDetails { code, ..Default::default() });
+        }
+        let recent = log.recent(1);
+        assert_eq!(recent.len(), MAX_ERRORS_PER_UID);
+        assert_eq!(recent[0].code, MAX_ERRORS_PER_UID as i32 + 1);
+        assert!(log.recent(2).is_empty());
+
+        // Errors can be looked up by id, but only by their own uid.
+        let error_id = log.insert(1, ErrorDetails { code: 42, ..Default::default() });
+        assert_eq!(log.find(1, error_id).map(|details| details.code), Some(42));
+        assert_eq!(log.find(2, error_id), None);
+        assert_ne!(log.insert(1, ErrorDetails::default()), error_id);
+
+        for uid in 2..(MAX_UIDS as u32 + 2) {
+            log.insert(uid, ErrorDetails::default());
+        }
+        assert_eq!(log.errors.len(), MAX_UIDS);
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the optional key escrow. Some regulated deployments require that
+//! the key material of designated keys can be recovered by an escrow agent. The escrow is
+//! only compiled in with the `key_escrow` feature and must additionally be enabled on the
+//! device with the `ro.keystore.escrow.enabled` property.
+//!
+//! Keys are designated for escrow by their owner with `ESCROW_FLAG` at creation. Only keys
+//! that are not backed by secure hardware can be escrowed. The KeyMint blob of such a key is
+//! encrypted to the escrow public key with `ECDHPrivateKey::encrypt_message` and the resulting
+//! `EscrowRecord` is stored in the key's metadata, from where it can be retrieved by holders
+//! of the `escrow_retrieve` permission. Both events are written to the audit log.
+
+use crate::audit_log::log_key_escrowed;
+use crate::caller_identity::CallerIdentity;
+use crate::ec_crypto::ECDHPrivateKey;
+use crate::error::Error;
+use crate::key_parameter::KeyParameter;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use android_security_keyparameters::aidl::android::security::keyparameters::KeystoreKeyFlag::KeystoreKeyFlag;
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor, ResponseCode::ResponseCode,
+};
+use anyhow::{Context, Result};
+use keystore2_system_property::PropertyWatcher;
+use std::convert::TryInto;
+
+/// Key flag with which the owner designates a key for escrow at creation.
+pub const ESCROW_FLAG: i32 = KeystoreKeyFlag::ESCROW.0;
+
+/// Property that enables the escrow on devices with the `key_escrow` feature.
+const ESCROW_ENABLED_PROPERTY: &str = "ro.keystore.escrow.enabled";
+
+/// File holding the SEC1 encoded P-256 escrow public key.
+const ESCROW_PUBLIC_KEY_PATH: &str = "/vendor/etc/security/keystore2/escrow_public_key";
+
+/// The key material of one key encrypted to the escrow public key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EscrowRecord {
+    /// The ephemeral public key of the sender.
+    pub sender_public_key: Vec<u8>,
+    /// The HKDF salt.
+    pub salt: Vec<u8>,
+    /// The AES-GCM initialization vector.
+    pub iv: Vec<u8>,
+    /// The encrypted KeyMint blob.
+    pub ciphertext: Vec<u8>,
+    /// The AES-GCM tag.
+    pub tag: Vec<u8>,
+}
+
+impl EscrowRecord {
+    const VERSION: u8 = 1;
+
+    /// Serializes the record as a version byte followed by all fields in declaration order,
+    /// each prefixed with its length as big endian u32.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let fields = [&self.sender_public_key, &self.salt, &self.iv, &self.ciphertext, &self.tag];
+        let mut result = vec![Self::VERSION];
+        for field in fields.iter() {
+            result.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            result.extend_from_slice(field);
+        }
+        result
+    }
+
+    /// Parses a record serialized by `to_bytes`.
+    pub fn from_bytes(mut buf: &[u8]) -> Result<Self> {
+        if buf.first() != Some(&Self::VERSION) {
+            return Err(Error::sys()).context("In EscrowRecord::from_bytes: Unknown version.");
+        }
+        buf = &buf[1..];
+        let mut next_field = || -> Result<Vec<u8>> {
+            if buf.len() < 4 {
+                return Err(Error::sys()).context("In EscrowRecord::from_bytes: Short length.");
+            }
+            let len = u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize;
+            if buf.len() < 4 + len {
+                return Err(Error::sys()).context("In EscrowRecord::from_bytes: Short field.");
+            }
+            let field = buf[4..4 + len].to_vec();
+            buf = &buf[4 + len..];
+            Ok(field)
+        };
+        Ok(Self {
+            sender_public_key: next_field()?,
+            salt: next_field()?,
+            iv: next_field()?,
+            ciphertext: next_field()?,
+            tag: next_field()?,
+        })
+    }
+
+    fn encrypt(escrow_public_key: &[u8], key_blob: &[u8]) -> Result<Self> {
+        let (sender_public_key, salt, iv, ciphertext, tag) =
+            ECDHPrivateKey::encrypt_message(escrow_public_key, key_blob)
+                .context("In EscrowRecord::encrypt.")?;
+        Ok(Self { sender_public_key, salt, iv, ciphertext, tag })
+    }
+}
+
+/// Returns true if the escrow is enabled on this device.
+pub fn enabled() -> bool {
+    PropertyWatcher::new(ESCROW_ENABLED_PROPERTY)
+        .ok()
+        .and_then(|mut w| w.read(|_n, v| Ok(v == "1")).ok())
+        .unwrap_or(false)
+}
+
+/// Called when a new key is created. If the key was designated for escrow with
+/// `ESCROW_FLAG`, the KeyMint blob is encrypted to the escrow public key and the
+/// serialized `EscrowRecord` is returned for storage in the key metadata.
+pub fn wrap_on_creation(
+    key: &KeyDescriptor,
+    key_parameters: &[KeyParameter],
+    flags: Option<i32>,
+    key_blob: &[u8],
+    caller: &CallerIdentity,
+) -> Result<Option<Vec<u8>>> {
+    if !flags.map_or(false, |f| f & ESCROW_FLAG != 0) {
+        return Ok(None);
+    }
+    let result = (|| {
+        if !enabled() {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In wrap_on_creation: Key escrow is not enabled.");
+        }
+        if !matches!(key.domain, Domain::APP | Domain::SELINUX) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In wrap_on_creation: Only keys stored by Keystore can be escrowed.");
+        }
+        // Key material of keys backed by secure hardware never leaves the hardware.
+        if key_parameters.iter().any(|p| {
+            matches!(
+                *p.security_level(),
+                SecurityLevel::TRUSTED_ENVIRONMENT | SecurityLevel::STRONGBOX
+            )
+        }) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In wrap_on_creation: Key is backed by secure hardware.");
+        }
+        let escrow_public_key = std::fs::read(ESCROW_PUBLIC_KEY_PATH)
+            .context("In wrap_on_creation: Failed to read escrow public key.")?;
+        EscrowRecord::encrypt(&escrow_public_key, key_blob)
+            .map(|record| Some(record.to_bytes()))
+            .context("In wrap_on_creation: Failed to encrypt key blob.")
+    })();
+
+    log_key_escrowed(key, caller, result.is_ok());
+    if result.is_ok() {
+        log::warn!("In wrap_on_creation: Escrowed key {:?} of uid {}.", key, caller.uid());
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escrow_record_roundtrip() -> Result<()> {
+        let escrow_key = ECDHPrivateKey::generate()?;
+        let record = EscrowRecord::encrypt(&escrow_key.public_key()?, b"key blob")?;
+        let parsed = EscrowRecord::from_bytes(&record.to_bytes())?;
+        assert_eq!(parsed, record);
+
+        let decrypted = escrow_key.decrypt_message(
+            &parsed.sender_public_key,
+            &parsed.salt,
+            &parsed.iv,
+            &parsed.ciphertext,
+            &parsed.tag,
+        )?;
+        assert_eq!(&decrypted[..], b"key blob");
+        assert!(EscrowRecord::from_bytes(&record.to_bytes()[..10]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the optional expired key sweeper. It periodically looks for keys whose
+//! usage expiration date has passed, marks them expired, informs the listener registered for the
+//! owning uid, and deletes the keys whose owner asked for deletion on expiry at creation time.
+//! Expired keys cannot be used anyway, so the sweeper merely keeps the database from
+//! accumulating dead credentials.
+
+use crate::database::{DateTime, ExpiredKey, KeystoreDB};
+use crate::globals::{ASYNC_TASK, DB, TASK_EXECUTOR};
+use crate::task_executor::Priority;
+use android_security_keyparameters::aidl::android::security::keyparameters::KeystoreKeyFlag::KeystoreKeyFlag;
+use android_security_maintenance::aidl::android::security::maintenance::IKeyExpiryListener::IKeyExpiryListener;
+use android_security_maintenance::binder::Strong;
+use android_system_keystore2::aidl::android::system::keystore2::Domain::Domain;
+use anyhow::{Context, Result};
+use keystore2_system_property::PropertyWatcher;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// System property holding the interval of the expired key sweeper in seconds. If absent or
+/// zero, the sweeper does not run.
+const SWEEP_INTERVAL_PROPERTY: &str = "keystore.expired_key_sweep_interval_seconds";
+
+/// Key flag that asks for the key to be deleted by the sweeper once it has expired, when passed
+/// to generateKey or importKey.
+pub const DELETE_ON_EXPIRY_FLAG: i32 = KeystoreKeyFlag::DELETE_ON_EXPIRY.0;
+
+lazy_static! {
+    /// Listeners informed about expired keys, indexed by the uid owning the keys.
+    static ref EXPIRY_LISTENERS: Mutex<HashMap<u32, Strong<dyn IKeyExpiryListener>>> =
+        Default::default();
+}
+
+/// Registers the listener for the keys owned by `uid`, replacing any previously registered
+/// listener. `None` unregisters the listener.
+pub fn set_listener(uid: u32, listener: Option<&Strong<dyn IKeyExpiryListener>>) {
+    let mut listeners = EXPIRY_LISTENERS.lock().unwrap();
+    match listener {
+        Some(listener) => {
+            listeners.insert(uid, listener.clone());
+        }
+        None => {
+            listeners.remove(&uid);
+        }
+    }
+}
+
+/// Sweeps the database once and informs the listeners about the keys found expired.
+pub fn sweep(db: &mut KeystoreDB) -> Result<Vec<ExpiredKey>> {
+    let now = DateTime::now().context("In sweep: Failed to get the current time.")?;
+    let expired_keys = db.sweep_expired_keys(now).context("In sweep.")?;
+    let listeners = EXPIRY_LISTENERS.lock().unwrap();
+    for ExpiredKey { key, deleted } in &expired_keys {
+        // Only keys in the app domain have an owning uid.
+        if key.domain != Domain::APP {
+            continue;
+        }
+        if let Some(listener) = listeners.get(&(key.nspace as u32)) {
+            if let Err(e) = listener.onKeyExpired(key, *deleted) {
+                log::warn!("In sweep: Failed to notify listener: {:?}", e);
+            }
+        }
+    }
+    Ok(expired_keys)
+}
+
+fn sweep_interval() -> Option<Duration> {
+    let seconds = PropertyWatcher::new(SWEEP_INTERVAL_PROPERTY).ok().and_then(|mut w| {
+        w.read(|_n, v| v.parse::<u64>().map_err(std::convert::Into::into)).ok()
+    })?;
+    if seconds == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(seconds))
+    }
+}
+
+/// Starts the sweeper if it is enabled by the system property
+/// `keystore.expired_key_sweep_interval_seconds`. The sweep itself runs on the low priority
+/// queue of the async task.
+pub fn start() {
+    let interval = match sweep_interval() {
+        Some(interval) => interval,
+        None => return,
+    };
+    log::info!("Starting the expired key sweeper with an interval of {:?}.", interval);
+    let spawned = TASK_EXECUTOR.spawn_periodic(
+        "keystore2_expiry_sweeper",
+        Priority::Background,
+        interval,
+        || {
+            ASYNC_TASK.queue_lo(|_| match DB.with(|db| sweep(&mut db.borrow_mut())) {
+                Ok(expired_keys) if !expired_keys.is_empty() => {
+                    log::info!("Expired key sweeper found {} expired keys.", expired_keys.len())
+                }
+                Ok(_) => {}
+                Err(e) => log::error!("Expired key sweeper failed: {:?}", e),
+            })
+        },
+    );
+    if let Err(e) = spawned {
+        log::error!("Failed to start the expired key sweeper: {:?}", e);
+    }
+}
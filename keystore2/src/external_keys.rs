@@ -0,0 +1,600 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the IKeystoreExternalKeys AIDL interface, which lets a bridge to a
+//! secure element, e.g., a SIM or eSE applet, register as a key provider. The keys of a
+//! provider are stored as regular key entries in a SELinux namespace reserved for the
+//! provider. Their key blob is the handle of the key on the secure element, and their KeyMint
+//! UUID identifies the provider, see `provider_uuid`. The service routes these keys to
+//! `ExternalSecurityLevel`, which applies the usual permission checks and authorizations and
+//! then proxies the operation to the provider through the `ExternalKeyProvider` trait.
+//!
+//! The keys are owned by the secure element. Deleting a key from Keystore only removes its
+//! entry, and the garbage collector does not call into the provider.
+
+use crate::caller_identity::CallerIdentity;
+use crate::database::{
+    BlobMetaData, CertificateInfo, DateTime, GrantRebindPolicy, KeyEntry, KeyEntryLoadBits,
+    KeyIdGuard, KeyMetaData, KeyMetaEntry, KeyOrigin, KeyType, Uuid,
+};
+use crate::enforcements::{key_use_approval_token, KEY_USE_APPROVAL_TAG};
+use crate::error::{map_km_error, map_or_log_err, Error, ErrorCode, ResponseCode};
+use crate::globals::{DB, ENFORCEMENTS};
+use crate::key_parameter::KeyParameter as KsKeyParam;
+use crate::namespace_freeze;
+use crate::operation::{KeystoreOperation, LoggingInfo, OperationDb};
+use crate::operation_intents;
+use crate::permission::{KeyPerm, KeystorePerm};
+use crate::security_level::note_key_use;
+use crate::utils::{check_client_context, check_key_permission, check_keystore_permission};
+use crate::utils::{watchdog as wd, Asp};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    BeginResult::BeginResult, HardwareAuthToken::HardwareAuthToken, KeyParameter::KeyParameter,
+    KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
+    Tag::Tag,
+};
+use android_security_externalkeys::aidl::android::security::externalkeys::{
+    ExternalKey::ExternalKey,
+    IExternalKeyProvider::IExternalKeyProvider,
+    IKeystoreExternalKeys::{
+        BnKeystoreExternalKeys, IKeystoreExternalKeys,
+        EXTERNAL_SECURITY_LEVEL as EXTERNAL_SECURITY_LEVEL_VALUE,
+    },
+};
+use android_security_externalkeys::binder::{
+    BinderFeatures, Interface, Result as BinderResult, Strong,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    AuthenticatorSpec::AuthenticatorSpec, CreateOperationResponse::CreateOperationResponse,
+    Domain::Domain, EphemeralStorageKeyResponse::EphemeralStorageKeyResponse,
+    IKeystoreOperation::IKeystoreOperation, IKeystoreSecurityLevel::BnKeystoreSecurityLevel,
+    IKeystoreSecurityLevel::IKeystoreSecurityLevel, KeyDescriptor::KeyDescriptor,
+    KeyMetadata::KeyMetadata, KeyParameters::KeyParameters,
+};
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+/// The security level reported for the keys of external providers.
+pub const EXTERNAL_SECURITY_LEVEL: SecurityLevel = SecurityLevel(EXTERNAL_SECURITY_LEVEL_VALUE);
+
+/// The first half of the KeyMint UUID of every external provider. The second half is the
+/// namespace of the provider.
+const PROVIDER_UUID_PREFIX: [u8; 8] = [0x65, 0x78, 0x74, 0x6b, 0x65, 0x79, 0x73, 0x00];
+
+/// A source of keys that live outside of KeyMint. Keystore checks permissions and key
+/// authorizations before it calls `begin`.
+pub trait ExternalKeyProvider: Send + Sync {
+    /// Returns all keys the provider exposes.
+    fn list_keys(&self) -> Result<Vec<ExternalKey>>;
+
+    /// Begins an operation with the key identified by `handle`. The returned operation is
+    /// driven like a KeyMint operation.
+    fn begin(
+        &self,
+        purpose: KeyPurpose,
+        handle: &[u8],
+        params: &[KeyParameter],
+        auth_token: Option<&HardwareAuthToken>,
+    ) -> Result<BeginResult>;
+}
+
+/// Adapts a provider that registered through Binder.
+struct BinderProvider(Asp);
+
+impl BinderProvider {
+    fn get(&self) -> Result<Strong<dyn IExternalKeyProvider>> {
+        self.0.get_interface().context("In BinderProvider::get.")
+    }
+}
+
+impl ExternalKeyProvider for BinderProvider {
+    fn list_keys(&self) -> Result<Vec<ExternalKey>> {
+        let provider = self.get()?;
+        let _wp = wd::watch_millis("In BinderProvider::list_keys: calling listKeys", 2000);
+        map_km_error(provider.listKeys()).context("In BinderProvider::list_keys.")
+    }
+
+    fn begin(
+        &self,
+        purpose: KeyPurpose,
+        handle: &[u8],
+        params: &[KeyParameter],
+        auth_token: Option<&HardwareAuthToken>,
+    ) -> Result<BeginResult> {
+        let provider = self.get()?;
+        let _wp = wd::watch_millis("In BinderProvider::begin: calling begin", 500);
+        map_km_error(provider.begin(purpose, handle, params, auth_token))
+            .context("In BinderProvider::begin.")
+    }
+}
+
+/// Returns the KeyMint UUID under which the keys of the provider of `namespace` are stored.
+pub fn provider_uuid(namespace: i64) -> Uuid {
+    let mut uuid = [0u8; 16];
+    uuid[..8].copy_from_slice(&PROVIDER_UUID_PREFIX);
+    uuid[8..].copy_from_slice(&namespace.to_be_bytes());
+    Uuid::from(uuid)
+}
+
+/// Returns true if `uuid` belongs to an external provider, registered or not.
+pub fn is_provider_uuid(uuid: &Uuid) -> bool {
+    uuid[..8] == PROVIDER_UUID_PREFIX
+}
+
+struct Registration {
+    name: String,
+    provider: Arc<dyn ExternalKeyProvider>,
+}
+
+/// The registered providers, indexed by their namespace.
+#[derive(Default)]
+struct Registry {
+    providers: HashMap<i64, Registration>,
+}
+
+impl Registry {
+    /// Fails if the namespace is used by another provider or if the provider is registered
+    /// with another namespace.
+    fn check(&self, name: &str, namespace: i64) -> Result<()> {
+        let conflict = self.providers.iter().any(|(ns, r)| (*ns == namespace) != (r.name == name));
+        if conflict {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(format!(
+                "In Registry::check: Provider {} or namespace {} is already registered.",
+                name, namespace
+            ));
+        }
+        Ok(())
+    }
+
+    fn insert(
+        &mut self,
+        name: &str,
+        namespace: i64,
+        provider: Arc<dyn ExternalKeyProvider>,
+    ) -> Result<()> {
+        self.check(name, namespace).context("In Registry::insert.")?;
+        self.providers.insert(namespace, Registration { name: name.to_string(), provider });
+        Ok(())
+    }
+
+    fn remove(&mut self, name: &str) -> Option<i64> {
+        let namespace = self.providers.iter().find(|(_, r)| r.name == name).map(|(ns, _)| *ns)?;
+        self.providers.remove(&namespace);
+        Some(namespace)
+    }
+
+    fn get(&self, uuid: &Uuid) -> Option<Arc<dyn ExternalKeyProvider>> {
+        self.providers
+            .iter()
+            .find(|(ns, _)| provider_uuid(**ns) == *uuid)
+            .map(|(_, r)| r.provider.clone())
+    }
+}
+
+lazy_static! {
+    /// The registered providers.
+    static ref PROVIDERS: RwLock<Registry> = Default::default();
+    /// The security level binder handed out for external keys. It is shared by all providers.
+    static ref SECURITY_LEVEL: Asp =
+        Asp::new(ExternalSecurityLevel::new_native_binder().as_binder());
+    /// The operations on external keys. This lives outside of the security level, so that
+    /// they can be aborted without going through binder.
+    static ref OPERATION_DB: OperationDb = OperationDb::new();
+}
+
+/// Returns the security level that serves the keys of external providers.
+pub fn security_level() -> Result<Strong<dyn IKeystoreSecurityLevel>> {
+    SECURITY_LEVEL.get_interface().context("In external_keys::security_level.")
+}
+
+/// Aborts the operations with the given external keys. See `OperationDb::abort_keys`.
+pub fn abort_key_operations(key_ids: &HashSet<i64>) -> usize {
+    OPERATION_DB.abort_keys(key_ids)
+}
+
+/// Replaces the keys in the namespace with the keys of the provider and publishes the
+/// provider. Returns the number of keys of the provider. The registry stays locked from the
+/// conflict check until the provider is published, so that concurrent registrations cannot
+/// replace each other's keys.
+pub fn register_provider(
+    name: &str,
+    namespace: i64,
+    provider: Arc<dyn ExternalKeyProvider>,
+) -> Result<usize> {
+    let keys = provider.list_keys().context("In register_provider: Failed to list keys.")?;
+    let km_uuid = provider_uuid(namespace);
+    let creation_date = DateTime::now().context("In register_provider: Trying to get time.")?;
+    let mut providers = PROVIDERS.write().unwrap();
+    providers.check(name, namespace).context("In register_provider.")?;
+    DB.with::<_, Result<()>>(|db| {
+        let mut db = db.borrow_mut();
+        let replaced = db.unbind_keys_for_namespace(Domain::SELINUX, namespace)?;
+        log::info!("Replacing {} keys of external provider {}.", replaced, name);
+        for key in &keys {
+            let descriptor = KeyDescriptor {
+                domain: Domain::SELINUX,
+                nspace: namespace,
+                alias: Some(key.alias.clone()),
+                blob: None,
+            };
+            let params: Vec<KsKeyParam> = key
+                .characteristics
+                .iter()
+                .map(|p| KsKeyParam::new(p.clone().into(), EXTERNAL_SECURITY_LEVEL))
+                .collect();
+            let mut metadata = KeyMetaData::new();
+            metadata.add(KeyMetaEntry::CreationDate(creation_date));
+            metadata.add(KeyMetaEntry::Origin(KeyOrigin::Imported));
+            if key.userMediated {
+                metadata.add(KeyMetaEntry::UserMediated(true));
+            }
+            db.store_new_key(
+                &descriptor,
+                KeyType::Client,
+                &params,
+                &(&key.handle, &BlobMetaData::new()),
+                &CertificateInfo::new(key.certificate.clone(), key.certificateChain.clone()),
+                &metadata,
+                &km_uuid,
+                GrantRebindPolicy::Keep,
+            )
+            .with_context(|| format!("Failed to store key {}.", key.alias))?;
+        }
+        Ok(())
+    })
+    .context("In register_provider.")?;
+    providers.insert(name, namespace, provider).context("In register_provider.")?;
+    Ok(keys.len())
+}
+
+/// Unregisters the provider. Its keys remain in the database.
+pub fn unregister_provider(name: &str) {
+    if let Some(namespace) = PROVIDERS.write().unwrap().remove(name) {
+        log::info!("Unregistered external provider {} of namespace {}.", name, namespace);
+    }
+}
+
+/// This struct is defined to implement the IKeystoreExternalKeys AIDL interface.
+pub struct ExternalKeys;
+
+impl ExternalKeys {
+    /// Creates a new instance of the external keys service wrapped in a BnKeystoreExternalKeys
+    /// proxy object.
+    pub fn new_native_binder() -> Result<Strong<dyn IKeystoreExternalKeys>> {
+        Ok(BnKeystoreExternalKeys::new_binder(
+            Self,
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        ))
+    }
+
+    fn register_provider(
+        name: &str,
+        namespace: i64,
+        provider: &Strong<dyn IExternalKeyProvider>,
+    ) -> Result<i32> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::external_keys())
+            .context("In register_provider: Checking permission.")?;
+        // Registering replaces all keys of the namespace, so the caller must be allowed to
+        // rebind aliases in it.
+        let target =
+            KeyDescriptor { domain: Domain::SELINUX, nspace: namespace, ..Default::default() };
+        check_key_permission(KeyPerm::rebind(), &target, &None)
+            .context("In register_provider: Checking permission on the namespace.")?;
+        let provider = Arc::new(BinderProvider(Asp::new(provider.as_binder())));
+        register_provider(name, namespace, provider).map(|n| n as i32)
+    }
+
+    fn unregister_provider(name: &str) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::external_keys())
+            .context("In unregister_provider: Checking permission.")?;
+        unregister_provider(name);
+        Ok(())
+    }
+}
+
+impl Interface for ExternalKeys {}
+
+impl IKeystoreExternalKeys for ExternalKeys {
+    fn registerProvider(
+        &self,
+        name: &str,
+        nspace: i64,
+        provider: &Strong<dyn IExternalKeyProvider>,
+    ) -> BinderResult<i32> {
+        let _wp = wd::watch_millis("IKeystoreExternalKeys::registerProvider", 5000);
+        map_or_log_err(Self::register_provider(name, nspace, provider), Ok)
+    }
+
+    fn unregisterProvider(&self, name: &str) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreExternalKeys::unregisterProvider", 500);
+        map_or_log_err(Self::unregister_provider(name), Ok)
+    }
+}
+
+/// Implements IKeystoreSecurityLevel for the keys of all external providers.
+pub struct ExternalSecurityLevel;
+
+impl ExternalSecurityLevel {
+    fn new_native_binder() -> Strong<dyn IKeystoreSecurityLevel> {
+        BnKeystoreSecurityLevel::new_binder(
+            Self,
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        )
+    }
+
+    fn create_operation(
+        &self,
+        key: &KeyDescriptor,
+        operation_parameters: &[KeyParameter],
+        forced: bool,
+        caller: &CallerIdentity,
+    ) -> Result<CreateOperationResponse> {
+        if key.domain == Domain::BLOB {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In create_operation: External keys cannot be used as blobs.");
+        }
+        let key_namespace = Cell::new((key.domain, key.nspace));
+        let (key_id_guard, mut key_entry) = DB
+            .with::<_, Result<(KeyIdGuard, KeyEntry)>>(|db| {
+                db.borrow_mut().load_key_entry(
+                    key,
+                    KeyType::Client,
+                    KeyEntryLoadBits::KM,
+                    caller.uid(),
+                    |k, av| {
+                        check_key_permission(KeyPerm::use_(), k, &av)?;
+                        if forced {
+                            check_key_permission(KeyPerm::req_forced_op(), k, &av)?;
+                        }
+                        key_namespace.set((k.domain, k.nspace));
+                        Ok(())
+                    },
+                )
+            })
+            .context("In create_operation: Failed to load key.")?;
+        check_client_context(key_entry.metadata()).context("In create_operation.")?;
+        let (domain, namespace) = key_namespace.get();
+        DB.with(|db| {
+            namespace_freeze::check_key_not_frozen(
+                &mut db.borrow_mut(),
+                domain,
+                namespace,
+                key_id_guard.id(),
+            )
+        })
+        .context("In create_operation.")?;
+        let last_used = key_entry.metadata().last_used().copied();
+        let user_mediated = key_entry.metadata().user_mediated() == Some(&true);
+
+        let provider = PROVIDERS
+            .read()
+            .unwrap()
+            .get(key_entry.km_uuid())
+            .ok_or(Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE))
+            .context("In create_operation: The provider of the key is not registered.")?;
+        let (handle, _) = key_entry
+            .take_key_blob_info()
+            .ok_or_else(Error::sys)
+            .context("In create_operation: Loaded key entry without a handle.")?;
+
+        let purpose = operation_parameters.iter().find(|p| p.tag == Tag::PURPOSE).map_or(
+            Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+                .context("In create_operation: No operation purpose specified."),
+            |kp| match kp.value {
+                KeyParameterValue::KeyPurpose(p) => Ok(p),
+                _ => Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+                    .context("In create_operation: Malformed KeyParameter."),
+            },
+        )?;
+        let approval_token = key_use_approval_token(operation_parameters);
+        let op_params: Vec<KeyParameter> = operation_parameters
+            .iter()
+            .filter(|p| p.tag != Tag::PURPOSE && p.tag != KEY_USE_APPROVAL_TAG)
+            .cloned()
+            .collect();
+
+        let key_id = key_id_guard.id();
+        let key_properties = (key_id, key_entry.into_key_parameters());
+        // See `KeystoreSecurityLevel::create_operation` for why the approval is consumed first.
+        if user_mediated {
+            ENFORCEMENTS
+                .consume_key_use_approval(key_id, caller.uid(), approval_token)
+                .context("In create_operation.")?;
+        }
+        let (immediate_hat, mut auth_info) = ENFORCEMENTS
+            .authorize_create(purpose, Some(&key_properties), &op_params, false)
+            .context("In create_operation.")?;
+        // The key id guard must not be held while the provider runs the operation.
+        drop(key_id_guard);
+
+        let begin_result = loop {
+            match provider.begin(purpose, &handle, &op_params, immediate_hat.as_ref()) {
+                Err(e)
+                    if matches!(
+                        e.root_cause().downcast_ref::<Error>(),
+                        Some(Error::Km(ErrorCode::TOO_MANY_OPERATIONS))
+                    ) =>
+                {
+                    OPERATION_DB.prune(caller.uid(), forced)?;
+                }
+                result => break result,
+            }
+        }
+        .context("In create_operation: Failed to begin operation.")?;
+
+        let operation_challenge = auth_info.finalize_create_authorization(begin_result.challenge);
+        let km_op = begin_result
+            .operation
+            .ok_or_else(Error::sys)
+            .context("In create_operation: The provider did not return an operation.")?;
+        let intent = operation_intents::record(
+            caller.uid(),
+            Some(key_id),
+            purpose,
+            &op_params,
+            EXTERNAL_SECURITY_LEVEL,
+            begin_result.challenge,
+        );
+        let operation = OPERATION_DB.create_operation(
+            km_op,
+            caller.uid(),
+            caller.pid(),
+            auth_info,
+            forced,
+            LoggingInfo::new(
+                EXTERNAL_SECURITY_LEVEL,
+                purpose,
+                op_params,
+                false,
+                key_namespace.get(),
+                Some(key_id),
+                None,
+            ),
+        );
+        if let Some(intent) = intent {
+            operation.attach_intent(intent);
+        }
+        let op_binder: Strong<dyn IKeystoreOperation> =
+            KeystoreOperation::new_native_binder(operation)
+                .as_binder()
+                .into_interface()
+                .context("In create_operation: Failed to create IKeystoreOperation.")?;
+        note_key_use(key_id, last_used);
+
+        Ok(CreateOperationResponse {
+            iOperation: Some(op_binder),
+            operationChallenge: operation_challenge,
+            parameters: match begin_result.params.len() {
+                0 => None,
+                _ => Some(KeyParameters { keyParameter: begin_result.params }),
+            },
+            upgradedBlob: None,
+        })
+    }
+
+    fn unsupported<T>(what: &str) -> Result<T> {
+        Err(Error::Km(ErrorCode::UNIMPLEMENTED))
+            .context(format!("In ExternalSecurityLevel: {} is not supported.", what))
+    }
+}
+
+impl Interface for ExternalSecurityLevel {}
+
+impl IKeystoreSecurityLevel for ExternalSecurityLevel {
+    fn createOperation(
+        &self,
+        key: &KeyDescriptor,
+        operation_parameters: &[KeyParameter],
+        forced: bool,
+    ) -> BinderResult<CreateOperationResponse> {
+        let _wp = wd::watch_millis("ExternalSecurityLevel::createOperation", 500);
+        let caller = CallerIdentity::current();
+        map_or_log_err(self.create_operation(key, operation_parameters, forced, &caller), Ok)
+    }
+    fn generateKey(
+        &self,
+        _key: &KeyDescriptor,
+        _attestation_key: Option<&KeyDescriptor>,
+        _params: &[KeyParameter],
+        _flags: i32,
+        _entropy: &[u8],
+    ) -> BinderResult<KeyMetadata> {
+        map_or_log_err(Self::unsupported("generateKey"), Ok)
+    }
+    fn importKey(
+        &self,
+        _key: &KeyDescriptor,
+        _attestation_key: Option<&KeyDescriptor>,
+        _params: &[KeyParameter],
+        _flags: i32,
+        _key_data: &[u8],
+    ) -> BinderResult<KeyMetadata> {
+        map_or_log_err(Self::unsupported("importKey"), Ok)
+    }
+    fn importWrappedKey(
+        &self,
+        _key: &KeyDescriptor,
+        _wrapping_key: &KeyDescriptor,
+        _masking_key: Option<&[u8]>,
+        _params: &[KeyParameter],
+        _authenticators: &[AuthenticatorSpec],
+    ) -> BinderResult<KeyMetadata> {
+        map_or_log_err(Self::unsupported("importWrappedKey"), Ok)
+    }
+    fn convertStorageKeyToEphemeral(
+        &self,
+        _storage_key: &KeyDescriptor,
+    ) -> BinderResult<EphemeralStorageKeyResponse> {
+        map_or_log_err(Self::unsupported("convertStorageKeyToEphemeral"), Ok)
+    }
+    fn deleteKey(&self, _key: &KeyDescriptor) -> BinderResult<()> {
+        map_or_log_err(Self::unsupported("deleteKey"), Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoKeys;
+
+    impl ExternalKeyProvider for NoKeys {
+        fn list_keys(&self) -> Result<Vec<ExternalKey>> {
+            Ok(vec![])
+        }
+
+        fn begin(
+            &self,
+            _purpose: KeyPurpose,
+            _handle: &[u8],
+            _params: &[KeyParameter],
+            _auth_token: Option<&HardwareAuthToken>,
+        ) -> Result<BeginResult> {
+            Err(Error::Km(ErrorCode::UNIMPLEMENTED).into())
+        }
+    }
+
+    #[test]
+    fn provider_uuids_are_distinct() {
+        assert_ne!(provider_uuid(1), provider_uuid(2));
+        assert!(is_provider_uuid(&provider_uuid(-1)));
+        assert!(!is_provider_uuid(&Uuid::from(SecurityLevel::STRONGBOX)));
+        assert!(!is_provider_uuid(&Uuid::from(EXTERNAL_SECURITY_LEVEL)));
+        assert!(!is_provider_uuid(&crate::database::KEYSTORE_UUID));
+    }
+
+    #[test]
+    fn registry_reserves_names_and_namespaces() -> Result<()> {
+        let mut registry = Registry::default();
+        registry.insert("sim", 100, Arc::new(NoKeys))?;
+        // Registering again under the same name and namespace replaces the provider.
+        registry.insert("sim", 100, Arc::new(NoKeys))?;
+        assert!(registry.insert("ese", 100, Arc::new(NoKeys)).is_err());
+        assert!(registry.insert("sim", 101, Arc::new(NoKeys)).is_err());
+        registry.insert("ese", 101, Arc::new(NoKeys))?;
+
+        assert!(registry.get(&provider_uuid(100)).is_some());
+        assert!(registry.get(&provider_uuid(102)).is_none());
+        assert_eq!(registry.remove("sim"), Some(100));
+        assert_eq!(registry.remove("sim"), None);
+        assert!(registry.get(&provider_uuid(100)).is_none());
+        assert!(registry.get(&provider_uuid(101)).is_some());
+        Ok(())
+    }
+}
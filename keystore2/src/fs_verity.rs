@@ -0,0 +1,146 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the IKeystoreFsVerity AIDL interface. It holds the certificates that
+//! fsverity_init loads into the kernel's .fs-verity keyring, so that they no longer need to be
+//! passed to fsverity_init through its standard input. Provisioning, loading, and removal are
+//! subject to Keystore's permission checks, and changes are recorded in the audit log.
+//! Certificates are stored in the Keystore database and survive reboots; since the keyring does
+//! not, `fsverity_init --load-keystore-keys` loads them again on every boot.
+
+use crate::audit_log::{log_fs_verity_cert_provisioned, log_fs_verity_cert_removed};
+use crate::caller_identity::CallerIdentity;
+use crate::error::{map_or_log_err, Error, ResponseCode};
+use crate::globals::DB;
+use crate::permission::KeystorePerm;
+use crate::utils::{check_keystore_permission, watchdog as wd};
+use android_security_fsverity::aidl::android::security::fsverity::{
+    FsVerityCertificate::FsVerityCertificate,
+    IKeystoreFsVerity::{BnKeystoreFsVerity, IKeystoreFsVerity},
+};
+use android_security_fsverity::binder::{
+    BinderFeatures, Interface, Result as BinderResult, Strong,
+};
+use anyhow::{Context, Result};
+
+/// The maximal number of certificates that can be provisioned at the same time.
+const MAX_CERTIFICATES: usize = 16;
+/// The maximal length of a certificate name.
+const MAX_NAME_LENGTH: usize = 32;
+
+/// Certificate names become key descriptions in the .fs-verity keyring, so they are limited to
+/// short identifiers.
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.len() > MAX_NAME_LENGTH
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(format!("In check_name: Invalid name \"{}\".", name));
+    }
+    Ok(())
+}
+
+/// Implementation of the IKeystoreFsVerity interface.
+pub struct FsVerityService;
+
+impl FsVerityService {
+    /// Creates a new instance of the fs-verity service wrapped in a BnKeystoreFsVerity proxy
+    /// object.
+    pub fn new_native_binder() -> Result<Strong<dyn IKeystoreFsVerity>> {
+        Ok(BnKeystoreFsVerity::new_binder(Self, BinderFeatures::default()))
+    }
+
+    fn provision_certificate(name: &str, certificate: &[u8]) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::fs_verity_provision())
+            .context("In provision_certificate: Checking permission.")?;
+        let result = keystore2_crypto::parse_subject_from_certificate(certificate)
+            .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context("Failed to parse certificate.")
+            .and_then(|_| check_name(name))
+            .and_then(|_| {
+                DB.with(|db| {
+                    db.borrow_mut().put_fs_verity_certificate(name, certificate, MAX_CERTIFICATES)
+                })
+            });
+        log_fs_verity_cert_provisioned(name, &CallerIdentity::current(), result.is_ok());
+        result.context("In provision_certificate.")
+    }
+
+    fn get_certificates() -> Result<Vec<FsVerityCertificate>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::fs_verity_load())
+            .context("In get_certificates: Checking permission.")?;
+        let certificates = DB
+            .with(|db| db.borrow_mut().list_fs_verity_certificates())
+            .context("In get_certificates.")?;
+        Ok(certificates
+            .into_iter()
+            .map(|(name, certificate)| FsVerityCertificate { name, certificate })
+            .collect())
+    }
+
+    fn remove_certificate(name: &str) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::fs_verity_provision())
+            .context("In remove_certificate: Checking permission.")?;
+        let result =
+            DB.with(|db| db.borrow_mut().delete_fs_verity_certificate(name)).and_then(|deleted| {
+                if deleted {
+                    Ok(())
+                } else {
+                    Err(Error::Rc(ResponseCode::KEY_NOT_FOUND))
+                        .context(format!("No certificate \"{}\".", name))
+                }
+            });
+        log_fs_verity_cert_removed(name, &CallerIdentity::current(), result.is_ok());
+        result.context("In remove_certificate.")
+    }
+}
+
+impl Interface for FsVerityService {}
+
+impl IKeystoreFsVerity for FsVerityService {
+    fn provisionCertificate(&self, name: &str, certificate: &[u8]) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreFsVerity::provisionCertificate", 500);
+        map_or_log_err(Self::provision_certificate(name, certificate), Ok)
+    }
+
+    fn getCertificates(&self) -> BinderResult<Vec<FsVerityCertificate>> {
+        let _wp = wd::watch_millis("IKeystoreFsVerity::getCertificates", 500);
+        map_or_log_err(Self::get_certificates(), Ok)
+    }
+
+    fn removeCertificate(&self, name: &str) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreFsVerity::removeCertificate", 500);
+        map_or_log_err(Self::remove_certificate(name), Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::get_error_code;
+
+    #[test]
+    fn test_check_name() {
+        check_name("fsv_ods").unwrap();
+        check_name(&"a".repeat(MAX_NAME_LENGTH)).unwrap();
+        for name in &["", "fsv ods", "fsv/ods", "fsv-ods", &"a".repeat(MAX_NAME_LENGTH + 1)] {
+            let e = check_name(name).unwrap_err();
+            assert_eq!(get_error_code(&e), ResponseCode::INVALID_ARGUMENT.0);
+        }
+    }
+}
@@ -20,6 +20,7 @@
 
 use crate::{
     async_task,
+    database::io_stats::{self, Subsystem},
     database::{BlobMetaData, KeystoreDB, Uuid},
     super_key::SuperKeyManager,
 };
@@ -29,6 +30,7 @@ use std::sync::{
     atomic::{AtomicU8, Ordering},
     Arc,
 };
+use std::time::Instant;
 
 pub struct Gc {
     async_task: Arc<AsyncTask>,
@@ -61,6 +63,7 @@ impl Gc {
             shelf.get_or_put_with(|| GcInternal {
                 deleted_blob_ids: vec![],
                 superseded_blobs: vec![],
+                more_pending: false,
                 invalidate_key,
                 db,
                 async_task: weak_at,
@@ -79,11 +82,25 @@ impl Gc {
             self.async_task.queue_lo(|shelf| shelf.get_downcast_mut::<GcInternal>().unwrap().step())
         }
     }
+
+    /// Invalidates the blobs of the batch that is in progress until `deadline` and removes the
+    /// invalidated blobs from the database, so that the next instance does not invalidate them
+    /// again. Blobs that were not processed yet stay in the database and are picked up by the
+    /// next instance. This is used before Keystore shuts down.
+    pub fn flush(&self, deadline: Instant) {
+        self.async_task.queue_hi(move |shelf| {
+            if let Some(gc) = shelf.get_downcast_mut::<GcInternal>() {
+                gc.flush(deadline);
+            }
+        })
+    }
 }
 
 struct GcInternal {
     deleted_blob_ids: Vec<i64>,
     superseded_blobs: Vec<(i64, Vec<u8>, BlobMetaData)>,
+    // True if the database has more to clean up than fit into the last batch.
+    more_pending: bool,
     invalidate_key: Box<dyn Fn(&Uuid, &[u8]) -> Result<()> + Send + 'static>,
     db: KeystoreDB,
     async_task: std::sync::Weak<AsyncTask>,
@@ -97,15 +114,21 @@ impl GcInternal {
     /// may involve calling into the KeyMint backend and we don't want to hog neither the backend
     /// nor the database for extended periods of time.
     /// To limit the number of database transactions, which are also expensive and competing
-    /// with threads on the critical path, deleted blobs are loaded in batches.
+    /// with threads on the critical path, deleted blobs are loaded in batches. The clean up of
+    /// the database is batched as well, so that a large deletion, e.g., of a namespace, does
+    /// not hold the database lock for long.
     fn process_one_key(&mut self) -> Result<()> {
+        let _subsystem = io_stats::enter(Subsystem::Gc);
         if self.superseded_blobs.is_empty() {
-            let blobs = self
+            // Stop rescheduling if the database fails.
+            self.more_pending = false;
+            let (blobs, more_pending) = self
                 .db
                 .handle_next_superseded_blobs(&self.deleted_blob_ids, 20)
                 .context("In process_one_key: Trying to handle superseded blob.")?;
             self.deleted_blob_ids = vec![];
             self.superseded_blobs = blobs;
+            self.more_pending = more_pending;
         }
 
         if let Some((blob_id, blob, blob_metadata)) = self.superseded_blobs.pop() {
@@ -130,6 +153,25 @@ impl GcInternal {
         Ok(())
     }
 
+    fn flush(&mut self, deadline: Instant) {
+        while !self.superseded_blobs.is_empty() && Instant::now() < deadline {
+            if let Err(e) = self.process_one_key() {
+                log::error!("In flush: Error trying to delete blob entry. {:?}", e);
+            }
+        }
+        if self.deleted_blob_ids.is_empty() {
+            return;
+        }
+        let _subsystem = io_stats::enter(Subsystem::Gc);
+        match self.db.handle_next_superseded_blobs(&self.deleted_blob_ids, 0) {
+            Ok((_, more_pending)) => {
+                self.deleted_blob_ids = vec![];
+                self.more_pending = more_pending;
+            }
+            Err(e) => log::error!("In flush: Failed to delete invalidated blobs: {:?}", e),
+        }
+    }
+
     /// Processes one key and then schedules another attempt until it runs out of blobs to delete.
     fn step(&mut self) {
         self.notified.store(0, Ordering::Relaxed);
@@ -137,7 +179,7 @@ impl GcInternal {
             log::error!("Error trying to delete blob entry. {:?}", e);
         }
         // Schedule the next step. This gives high priority requests a chance to interleave.
-        if !self.deleted_blob_ids.is_empty() {
+        if !self.deleted_blob_ids.is_empty() || self.more_pending {
             if let Some(at) = self.async_task.upgrade() {
                 if let Ok(0) =
                     self.notified.compare_exchange(0, 1, Ordering::Relaxed, Ordering::Relaxed)
@@ -15,11 +15,23 @@
 //! This module holds global state of Keystore such as the thread local
 //! database connections and connections to services that Keystore needs
 //! to talk to.
-
+//!
+//! All of this state is initialized lazily. Tests can tear it down with `teardown`, after which
+//! it is initialized again on next use. The global locks of this module are `OrderedMutex`es.
+//! They must be acquired in the order of their `LockRank`, which is checked in builds with
+//! debug assertions.
+
+use crate::capability_matrix::{self, Feature, Support};
+use crate::external_keys;
 use crate::gc::Gc;
 use crate::legacy_blob::LegacyBlobLoader;
 use crate::legacy_migrator::LegacyMigrator;
+use crate::lock_order::{LockRank, OrderedMutex};
+use crate::metrics_store::log_hal_transport_error_stats;
+use crate::software_fallback;
+use crate::storage_key;
 use crate::super_key::SuperKeyManager;
+use crate::task_executor::TaskExecutor;
 use crate::utils::watchdog as wd;
 use crate::utils::Asp;
 use crate::{async_task::AsyncTask, database::MonotonicRawTime};
@@ -28,7 +40,10 @@ use crate::{
     database::Uuid,
     error::{map_binder_status, map_binder_status_code, Error, ErrorCode},
 };
-use crate::{enforcements::Enforcements, error::map_km_error};
+use crate::{
+    enforcements::Enforcements,
+    error::{is_binder_transport_error, map_km_error},
+};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     IKeyMintDevice::IKeyMintDevice, IRemotelyProvisionedComponent::IRemotelyProvisionedComponent,
     KeyMintHardwareInfo::KeyMintHardwareInfo, SecurityLevel::SecurityLevel,
@@ -39,11 +54,15 @@ use anyhow::{Context, Result};
 use binder::FromIBinder;
 use keystore2_vintf::get_aidl_instances;
 use lazy_static::lazy_static;
-use std::sync::{Arc, Mutex, RwLock};
-use std::{cell::RefCell, sync::Once};
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Once, RwLock};
+use std::time::Instant;
 use std::{collections::HashMap, path::Path, path::PathBuf};
 
-static DB_INIT: Once = Once::new();
+/// Incremented by `teardown`. Thread local database connections that were opened in an earlier
+/// generation are reopened on next use.
+static DB_GENERATION: AtomicU64 = AtomicU64::new(0);
 
 /// Open a connection to the Keystore 2.0 database. This is called during the initialization of
 /// the thread local DB field. It should never be called directly. The first time this is called
@@ -54,11 +73,11 @@ static DB_INIT: Once = Once::new();
 /// is run only once, as long as the ASYNC_TASK instance is the same. So only one additional
 /// database connection is created for the garbage collector worker.
 pub fn create_thread_local_db() -> KeystoreDB {
-    let db_path = DB_PATH.read().expect("Could not get the database directory.");
+    let mut db = open_db(Some(gc())).expect("Failed to open database.");
 
-    let mut db = KeystoreDB::new(&db_path, Some(GC.clone())).expect("Failed to open database.");
-
-    DB_INIT.call_once(|| {
+    // Other threads that open their connection meanwhile wait until the cleanup has finished.
+    let db_init = DB_INIT.read().unwrap().clone();
+    db_init.call_once(|| {
         log::info!("Touching Keystore 2.0 database for this first time since boot.");
         db.insert_last_off_body(MonotonicRawTime::now());
         log::info!("Calling cleanup leftovers.");
@@ -76,13 +95,63 @@ pub fn create_thread_local_db() -> KeystoreDB {
     db
 }
 
+/// Opens a connection to the persistent database in `DB_PATH`, or to the in-memory database
+/// if the embedded mode selected one, see `IN_MEMORY_DB`.
+fn open_db(gc: Option<Arc<Gc>>) -> Result<KeystoreDB> {
+    #[cfg(feature = "embedded")]
+    if let Some(name) = IN_MEMORY_DB.read().unwrap().as_deref() {
+        return KeystoreDB::new_in_memory(name, gc);
+    }
+    KeystoreDB::new(&DB_PATH.read().expect("Could not get the database directory."), gc)
+}
+
+/// Returns true if the database was opened and cleaned up since boot or since the last
+/// `teardown`.
+pub fn is_db_initialized() -> bool {
+    DB_INIT.read().unwrap().is_completed()
+}
+
+/// The thread local database connection. It is opened on first use and reopened on the next
+/// use after a `teardown`.
+pub struct ThreadLocalDb {
+    generation: Cell<u64>,
+    db: RefCell<Option<KeystoreDB>>,
+}
+
+impl ThreadLocalDb {
+    fn new() -> Self {
+        Self { generation: Cell::new(0), db: RefCell::new(None) }
+    }
+
+    fn refresh(&self) {
+        let generation = DB_GENERATION.load(Ordering::SeqCst);
+        if self.db.borrow().is_none() || self.generation.get() != generation {
+            // Drop the stale connection before opening the new one.
+            *self.db.borrow_mut() = None;
+            *self.db.borrow_mut() = Some(create_thread_local_db());
+            self.generation.set(generation);
+        }
+    }
+
+    /// Mutably borrows the database connection of the current thread.
+    pub fn borrow_mut(&self) -> RefMut<KeystoreDB> {
+        self.refresh();
+        RefMut::map(self.db.borrow_mut(), |db| db.as_mut().unwrap())
+    }
+
+    /// Immutably borrows the database connection of the current thread.
+    pub fn borrow(&self) -> Ref<KeystoreDB> {
+        self.refresh();
+        Ref::map(self.db.borrow(), |db| db.as_ref().unwrap())
+    }
+}
+
 thread_local! {
     /// Database connections are not thread safe, but connecting to the
     /// same database multiple times is safe as long as each connection is
     /// used by only one thread. So we store one database connection per
     /// thread in this thread local key.
-    pub static DB: RefCell<KeystoreDB> =
-            RefCell::new(create_thread_local_db());
+    pub static DB: ThreadLocalDb = ThreadLocalDb::new();
 }
 
 #[derive(Default)]
@@ -119,6 +188,24 @@ impl DevicesMap {
         self.devices_by_uuid.insert(uuid, (dev, hw_info));
         self.uuid_by_sec_level.insert(sec_level, uuid);
     }
+
+    /// Removes the cached connection for the given security level, so that the next lookup
+    /// reconnects to the HAL.
+    fn remove(&mut self, sec_level: &SecurityLevel) {
+        if let Some(uuid) = self.uuid_by_sec_level.remove(sec_level) {
+            self.devices_by_uuid.remove(&uuid);
+        }
+    }
+
+    fn sec_levels(&self) -> Vec<SecurityLevel> {
+        self.uuid_by_sec_level.keys().cloned().collect()
+    }
+
+    fn sec_level_by_uuid(&self, uuid: &Uuid) -> Option<SecurityLevel> {
+        self.uuid_by_sec_level
+            .iter()
+            .find_map(|(sec_level, u)| if u == uuid { Some(*sec_level) } else { None })
+    }
 }
 
 #[derive(Default)]
@@ -134,23 +221,39 @@ impl RemotelyProvisionedDevicesMap {
     fn insert(&mut self, sec_level: SecurityLevel, dev: Asp) {
         self.devices_by_sec_level.insert(sec_level, dev);
     }
+
+    fn remove(&mut self, sec_level: &SecurityLevel) {
+        self.devices_by_sec_level.remove(sec_level);
+    }
 }
 
 lazy_static! {
     /// The path where keystore stores all its keys.
     pub static ref DB_PATH: RwLock<PathBuf> = RwLock::new(
         Path::new("/data/misc/keystore").to_path_buf());
+    /// If set, the persistent database is the shared in-memory database of this name instead
+    /// of a file in `DB_PATH`. Only the embedded mode sets it, see `embedded`.
+    #[cfg(feature = "embedded")]
+    pub static ref IN_MEMORY_DB: RwLock<Option<String>> = RwLock::new(None);
+    /// Runs the first-use cleanup of the database once since boot. `teardown` replaces it, so
+    /// that the cleanup runs again.
+    static ref DB_INIT: RwLock<Arc<Once>> = RwLock::new(Arc::new(Once::new()));
     /// Runtime database of unwrapped super keys.
     pub static ref SUPER_KEY: Arc<SuperKeyManager> = Default::default();
     /// Map of KeyMint devices.
-    static ref KEY_MINT_DEVICES: Mutex<DevicesMap> = Default::default();
+    static ref KEY_MINT_DEVICES: OrderedMutex<DevicesMap> =
+        OrderedMutex::with_rank(LockRank::KeyMintDevices);
     /// Timestamp service.
-    static ref TIME_STAMP_DEVICE: Mutex<Option<Asp>> = Default::default();
+    static ref TIME_STAMP_DEVICE: OrderedMutex<Option<Asp>> =
+        OrderedMutex::with_rank(LockRank::TimeStampDevice);
     /// RemotelyProvisionedComponent HAL devices.
-    static ref REMOTELY_PROVISIONED_COMPONENT_DEVICES: Mutex<RemotelyProvisionedDevicesMap> = Default::default();
+    static ref REMOTELY_PROVISIONED_COMPONENT_DEVICES: OrderedMutex<RemotelyProvisionedDevicesMap> =
+        OrderedMutex::with_rank(LockRank::RemotelyProvisionedDevices);
     /// A single on-demand worker thread that handles deferred tasks with two different
     /// priorities.
     pub static ref ASYNC_TASK: Arc<AsyncTask> = Default::default();
+    /// Runs the named background tasks on bounded worker pools.
+    pub static ref TASK_EXECUTOR: TaskExecutor = Default::default();
     /// Singleton for enforcements.
     pub static ref ENFORCEMENTS: Enforcements = Default::default();
     /// LegacyBlobLoader is initialized and exists globally.
@@ -162,21 +265,71 @@ lazy_static! {
         Arc::new(LegacyMigrator::new(Arc::new(Default::default())));
     /// Background thread which handles logging via statsd and logd
     pub static ref LOGS_HANDLER: Arc<AsyncTask> = Default::default();
+    /// The blob garbage collector. It is created on first use and dropped by `teardown`.
+    static ref GC: OrderedMutex<Option<Arc<Gc>>> = OrderedMutex::with_rank(LockRank::Gc);
+}
 
-    static ref GC: Arc<Gc> = Arc::new(Gc::new_init_with(ASYNC_TASK.clone(), || {
-        (
-            Box::new(|uuid, blob| {
-                let km_dev: Strong<dyn IKeyMintDevice> =
-                    get_keymint_dev_by_uuid(uuid).map(|(dev, _)| dev)?.get_interface()?;
-                let _wp = wd::watch_millis("In invalidate key closure: calling deleteKey", 500);
-                map_km_error(km_dev.deleteKey(&*blob))
-                    .context("In invalidate key closure: Trying to invalidate key blob.")
-            }),
-            KeystoreDB::new(&DB_PATH.read().expect("Could not get the database directory."), None)
-                .expect("Failed to open database."),
-            SUPER_KEY.clone(),
-        )
-    }));
+/// Returns the blob garbage collector, creating it if necessary.
+fn gc() -> Arc<Gc> {
+    GC.lock()
+        .get_or_insert_with(|| {
+            Arc::new(Gc::new_init_with(ASYNC_TASK.clone(), || {
+                (
+                    Box::new(|uuid, blob| {
+                        // Keys of external providers are owned by their secure element.
+                        if external_keys::is_provider_uuid(uuid) {
+                            return Ok(());
+                        }
+                        call_keymint_with_retry_by_uuid(
+                            uuid,
+                            Idempotent("Deleting a deleted key blob has no effect."),
+                            |km_dev| {
+                                let _wp = wd::watch_millis(
+                                    "In invalidate key closure: calling deleteKey",
+                                    500,
+                                );
+                                map_km_error(km_dev.deleteKey(&*blob)).context(
+                                    "In invalidate key closure: Trying to invalidate key blob.",
+                                )
+                            },
+                        )
+                    }),
+                    open_db(None).expect("Failed to open database."),
+                    SUPER_KEY.clone(),
+                )
+            }))
+        })
+        .clone()
+}
+
+/// Makes the garbage collector finish the deletions in progress until `deadline` and persist
+/// them, see `Gc::flush`. Nothing happens if the garbage collector was not created yet.
+pub fn flush_gc(deadline: Instant) {
+    if let Some(gc) = GC.lock().as_ref() {
+        gc.flush(deadline);
+    }
+}
+
+/// Tears down the lazily initialized global state: The deferred tasks are run to completion and
+/// the garbage collector with its database connection is dropped, all cached HAL connections
+/// are dropped, all super keys are forgotten, and thread local database connections are
+/// reopened on their next use, which also repeats the first-use cleanup of the database.
+/// Everything is initialized again on next use, possibly with a different `DB_PATH`.
+///
+/// This must not be called from a deferred task, and not while the calling thread borrows its
+/// database connection. Binder objects created before the teardown keep their connections.
+/// Teardown is only meant to isolate tests. Keystore itself never tears down its state.
+#[cfg(test)]
+pub fn teardown() {
+    ASYNC_TASK.shutdown();
+    LOGS_HANDLER.shutdown();
+    *GC.lock() = None;
+    *KEY_MINT_DEVICES.lock() = Default::default();
+    *TIME_STAMP_DEVICE.lock() = None;
+    *REMOTELY_PROVISIONED_COMPONENT_DEVICES.lock() = Default::default();
+    SUPER_KEY.forget_all();
+    *DB_INIT.write().unwrap() = Arc::new(Once::new());
+    DB_GENERATION.fetch_add(1, Ordering::SeqCst);
 }
 
 static KEYMINT_SERVICE_NAME: &str = "android.hardware.security.keymint.IKeyMintDevice";
@@ -203,6 +356,13 @@ fn connect_keymint(security_level: &SecurityLevel) -> Result<(Asp, KeyMintHardwa
                 None
             }
         }
+        // The embedded mode has no KeyMint HAL. It uses the software implementation in process,
+        // which also serves the software fallback, see `software_fallback`.
+        SecurityLevel::SOFTWARE
+            if cfg!(feature = "embedded") || software_fallback::is_enabled() =>
+        {
+            return connect_software_keymint()
+        }
         _ => {
             return Err(Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE))
                 .context("In connect_keymint.")
@@ -250,17 +410,61 @@ fn connect_keymint(security_level: &SecurityLevel) -> Result<(Asp, KeyMintHardwa
     if let Some(hal_version) = hal_version {
         hw_info.versionNumber = hal_version;
     }
+    capability_matrix::record_instance(*security_level, hw_info.versionNumber);
 
     Ok((Asp::new(keymint.as_binder()), hw_info))
 }
 
+/// Returns the software implementation of KeyMint, which runs in the Keystore process.
+fn connect_software_keymint() -> Result<(Asp, KeyMintHardwareInfo)> {
+    let keymint = keystore2_km_compat::get_software_keymint_device()
+        .ok_or(Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE))
+        .context("In connect_software_keymint: Failed to create software KeyMint.")?;
+    let mut hw_info = map_km_error(keymint.getHardwareInfo())
+        .context("In connect_software_keymint: Failed to get hardware info.")?;
+    // Like for KeyMint services, the version number is set to the HAL version, see
+    // `connect_keymint`, i.e., <AIDL version> * 100.
+    let interface_version = map_km_error(keymint.getInterfaceVersion())
+        .context("In connect_software_keymint: Failed to get interface version.")?;
+    hw_info.versionNumber = interface_version * 100;
+    capability_matrix::record_instance(SecurityLevel::SOFTWARE, hw_info.versionNumber);
+    Ok((Asp::new(keymint.as_binder()), hw_info))
+}
+
+/// Aborts an operation that a previous instance of Keystore began on the Keymaster 4.x device
+/// of the given security level. The operation handle is the challenge returned by `begin`.
+/// This is a no-op for KeyMint devices, which end the operations of Keystore when it dies.
+pub fn abort_orphaned_operation(
+    security_level: &SecurityLevel,
+    operation_handle: i64,
+) -> Result<()> {
+    let (_, hw_info, _) =
+        get_keymint_device(security_level).context("In abort_orphaned_operation.")?;
+    if capability_matrix::support(hw_info.versionNumber, Feature::OrphanedOperationCleanup)
+        == Support::PassThrough
+    {
+        return Ok(());
+    }
+    let keystore_compat_service: Strong<dyn IKeystoreCompatService> =
+        map_binder_status_code(binder::get_interface("android.security.compat"))
+            .context("In abort_orphaned_operation: Trying to connect to compat service.")?;
+    let _wp = wd::watch_millis("In abort_orphaned_operation: calling abortOrphanedOperation", 500);
+    match map_binder_status(
+        keystore_compat_service.abortOrphanedOperation(*security_level, operation_handle),
+    ) {
+        // The operation ended already, e.g., because the Keymaster device restarted as well.
+        Ok(()) | Err(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE)) => Ok(()),
+        Err(e) => Err(e).context("In abort_orphaned_operation."),
+    }
+}
+
 /// Get a keymint device for the given security level either from our cache or
 /// by making a new connection. Returns the device, the hardware info and the uuid.
 /// TODO the latter can be removed when the uuid is part of the hardware info.
 pub fn get_keymint_device(
     security_level: &SecurityLevel,
 ) -> Result<(Asp, KeyMintHardwareInfo, Uuid)> {
-    let mut devices_map = KEY_MINT_DEVICES.lock().unwrap();
+    let mut devices_map = KEY_MINT_DEVICES.lock();
     if let Some((dev, hw_info, uuid)) = devices_map.dev_by_sec_level(&security_level) {
         Ok((dev, hw_info, uuid))
     } else {
@@ -276,7 +480,7 @@ pub fn get_keymint_device(
 /// when this is called. This is a fair assumption, because service.rs iterates through all
 /// security levels when it gets instantiated.
 pub fn get_keymint_dev_by_uuid(uuid: &Uuid) -> Result<(Asp, KeyMintHardwareInfo)> {
-    let devices_map = KEY_MINT_DEVICES.lock().unwrap();
+    let devices_map = KEY_MINT_DEVICES.lock();
     if let Some((dev, hw_info, _)) = devices_map.dev_by_uuid(uuid) {
         Ok((dev, hw_info))
     } else {
@@ -284,9 +488,132 @@ pub fn get_keymint_dev_by_uuid(uuid: &Uuid) -> Result<(Asp, KeyMintHardwareInfo)
     }
 }
 
+/// Returns the security levels of all KeyMint devices that are currently bound.
+pub fn get_bound_security_levels() -> Vec<SecurityLevel> {
+    KEY_MINT_DEVICES.lock().sec_levels()
+}
+
+/// Drops the cached connection to the KeyMint device of the given security level. The next
+/// call to `get_keymint_device` establishes a new connection. This is used to recover from
+/// a HAL that died and was restarted by its service manager.
+pub fn reset_keymint_device(security_level: &SecurityLevel) {
+    KEY_MINT_DEVICES.lock().remove(security_level);
+    storage_key::forget(*security_level);
+}
+
+/// States that a HAL call is idempotent, i.e., that delivering it twice has the same effect as
+/// delivering it once, along with the reason why. `call_keymint_with_retry` and
+/// `call_rkp_with_retry` require one, so that every call site opts into the retry explicitly.
+/// The reason is logged when the call is retried.
+#[derive(Clone, Copy, Debug)]
+pub struct Idempotent(pub &'static str);
+
+/// Drops the cached connection to the KeyMint device of the given security level after a call
+/// that must not be retried, e.g., an operation call, failed with a binder transport error.
+/// The next call reconnects. The error is recorded in the metrics store as not recovered.
+pub fn note_keymint_transport_error(security_level: &SecurityLevel) {
+    log::warn!("Transport error on {:?}, dropping the connection.", security_level);
+    reset_keymint_device(security_level);
+    log_hal_transport_error_stats(*security_level, false);
+}
+
+/// Calls `op` on the device returned by `connect`. If the call fails with a binder transport
+/// error (see `is_binder_transport_error`), the cached connection is dropped with `reset`, the
+/// device is reconnected, and `op` is called exactly one more time.
+fn call_with_retry<D, T, F>(
+    security_level: &SecurityLevel,
+    idempotent: Idempotent,
+    connect: impl Fn() -> Result<Asp>,
+    reset: impl Fn(),
+    op: F,
+) -> Result<T>
+where
+    D: FromIBinder + ?Sized,
+    F: Fn(Strong<D>) -> Result<T>,
+{
+    let call = || -> Result<T> { op(connect()?.get_interface()?) };
+    match call() {
+        Err(e) if is_binder_transport_error(&e) => {
+            log::warn!(
+                "In call_with_retry: Transport error on {:?}, reconnecting ({}): {:?}",
+                security_level,
+                idempotent.0,
+                e
+            );
+            reset();
+            let result = call();
+            log_hal_transport_error_stats(*security_level, result.is_ok());
+            result.context("In call_with_retry: Retry after reconnecting failed.")
+        }
+        result => result,
+    }
+}
+
+/// Calls `op` on the KeyMint device of the given security level and retries it once on a
+/// reconnected device if it fails with a binder transport error. Every transport error is
+/// recorded in the metrics store along with whether the retry recovered.
+///
+/// Only idempotent calls may be made through this function, because a transaction that failed
+/// in transit may or may not have reached the HAL. Callers state this with `idempotent`.
+pub fn call_keymint_with_retry<T, F>(
+    security_level: &SecurityLevel,
+    idempotent: Idempotent,
+    op: F,
+) -> Result<T>
+where
+    F: Fn(Strong<dyn IKeyMintDevice>) -> Result<T>,
+{
+    call_with_retry(
+        security_level,
+        idempotent,
+        || get_keymint_device(security_level).map(|(dev, _, _)| dev),
+        || reset_keymint_device(security_level),
+        op,
+    )
+    .context("In call_keymint_with_retry.")
+}
+
+/// Like `call_keymint_with_retry` but selects the KeyMint device by uuid. This fails if
+/// no device with the given uuid was ever connected.
+pub fn call_keymint_with_retry_by_uuid<T, F>(
+    uuid: &Uuid,
+    idempotent: Idempotent,
+    op: F,
+) -> Result<T>
+where
+    F: Fn(Strong<dyn IKeyMintDevice>) -> Result<T>,
+{
+    let sec_level = KEY_MINT_DEVICES
+        .lock()
+        .sec_level_by_uuid(uuid)
+        .ok_or_else(Error::sys)
+        .context("In call_keymint_with_retry_by_uuid: No KeyMint instance found.")?;
+    call_keymint_with_retry(&sec_level, idempotent, op)
+}
+
+/// Like `call_keymint_with_retry` but for the IRemotelyProvisionedComponent of the given
+/// security level.
+pub fn call_rkp_with_retry<T, F>(
+    security_level: &SecurityLevel,
+    idempotent: Idempotent,
+    op: F,
+) -> Result<T>
+where
+    F: Fn(Strong<dyn IRemotelyProvisionedComponent>) -> Result<T>,
+{
+    call_with_retry(
+        security_level,
+        idempotent,
+        || get_remotely_provisioned_component(security_level),
+        || REMOTELY_PROVISIONED_COMPONENT_DEVICES.lock().remove(security_level),
+        op,
+    )
+    .context("In call_rkp_with_retry.")
+}
+
 /// Return all known keymint devices.
 pub fn get_keymint_devices() -> Vec<Strong<dyn IKeyMintDevice>> {
-    KEY_MINT_DEVICES.lock().unwrap().devices()
+    KEY_MINT_DEVICES.lock().devices()
 }
 
 static TIME_STAMP_SERVICE_NAME: &str = "android.hardware.security.secureclock.ISecureClock";
@@ -331,7 +658,7 @@ fn connect_secureclock() -> Result<Asp> {
 /// Get the timestamp service that verifies auth token timeliness towards security levels with
 /// different clocks.
 pub fn get_timestamp_service() -> Result<Asp> {
-    let mut ts_device = TIME_STAMP_DEVICE.lock().unwrap();
+    let mut ts_device = TIME_STAMP_DEVICE.lock();
     if let Some(dev) = &*ts_device {
         Ok(dev.clone())
     } else {
@@ -381,7 +708,13 @@ fn connect_remotely_provisioned_component(security_level: &SecurityLevel) -> Res
 /// Get a remote provisiong component device for the given security level either from the cache or
 /// by making a new connection. Returns the device.
 pub fn get_remotely_provisioned_component(security_level: &SecurityLevel) -> Result<Asp> {
-    let mut devices_map = REMOTELY_PROVISIONED_COMPONENT_DEVICES.lock().unwrap();
+    // The embedded mode does not support remote provisioning.
+    if cfg!(feature = "embedded") {
+        return Err(Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE))
+            .context("In get_remotely_provisioned_component: Not supported in embedded mode.");
+    }
+
+    let mut devices_map = REMOTELY_PROVISIONED_COMPONENT_DEVICES.lock();
     if let Some(dev) = devices_map.dev_by_sec_level(&security_level) {
         Ok(dev)
     } else {
@@ -0,0 +1,221 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the reconciliation of the grant table with the rules for grants.
+//! Grants are checked against the grantor's permissions when they are issued, and they can
+//! never carry `grant` or `ungrant`. Grants stored by earlier releases, or restored from a
+//! backup, may violate these rules. The optional reconciliation pass flags such grants, and
+//! revokes them if the system property `keystore.grant_reconciliation_revoke` is set to true.
+//!
+//! The grantee's own keystore2_key permissions are not consulted. Apps do not hold
+//! keystore2_key permissions on the namespaces of other apps, so a grant is legitimate exactly
+//! because it confers permissions the grantee does not hold otherwise.
+
+use crate::database::{GrantRecord, KeystoreDB};
+use crate::globals::{ASYNC_TASK, DB, TASK_EXECUTOR};
+use crate::permission::{self, KeyPermSet};
+use crate::task_executor::Priority;
+use anyhow::{Context, Result};
+use keystore2_system_property::PropertyWatcher;
+use lazy_static::lazy_static;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// System property holding the interval of the reconciliation pass in seconds. If absent or
+/// zero, the pass does not run.
+const RECONCILIATION_INTERVAL_PROPERTY: &str = "keystore.grant_reconciliation_interval_seconds";
+
+/// System property that, if true, makes the reconciliation pass revoke the grants it flags.
+const REVOKE_PROPERTY: &str = "keystore.grant_reconciliation_revoke";
+
+lazy_static! {
+    /// The outcome of the most recent reconciliation pass.
+    static ref LAST_RECONCILIATION: Mutex<Option<Reconciliation>> = Default::default();
+}
+
+/// A grant that violates the rules for grants.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlaggedGrant {
+    /// The grant.
+    pub grant: GrantRecord,
+    /// The permissions of the grant that no grant may hold.
+    pub invalid: KeyPermSet,
+}
+
+/// The outcome of a reconciliation pass.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Reconciliation {
+    /// The number of checked grants.
+    pub checked: usize,
+    /// The grants that violate the rules for grants.
+    pub flagged: Vec<FlaggedGrant>,
+    /// The number of flagged grants that were revoked.
+    pub revoked: usize,
+}
+
+/// Returns the grants for which `invalid_permissions` reports permissions, and the number of
+/// grants checked.
+fn find_inconsistent_grants<F>(
+    grants: Vec<GrantRecord>,
+    invalid_permissions: F,
+) -> (usize, Vec<FlaggedGrant>)
+where
+    F: Fn(&GrantRecord) -> KeyPermSet,
+{
+    let checked = grants.len();
+    let flagged = grants
+        .into_iter()
+        .filter_map(|grant| {
+            let invalid = invalid_permissions(&grant);
+            if invalid == KeyPermSet(0) {
+                None
+            } else {
+                Some(FlaggedGrant { grant, invalid })
+            }
+        })
+        .collect();
+    (checked, flagged)
+}
+
+/// Runs one reconciliation pass over the grant table. If `revoke` is true, the flagged grants
+/// are revoked.
+pub fn reconcile(db: &mut KeystoreDB, revoke: bool) -> Result<Reconciliation> {
+    let grants = db.list_all_grants().context("In reconcile: Failed to list grants.")?;
+    let (checked, flagged) = find_inconsistent_grants(grants, |grant| {
+        permission::ungrantable_permissions(grant.access_vector)
+    });
+    for FlaggedGrant { grant, invalid } in &flagged {
+        log::warn!(
+            "Grant {} of {:?} to uid {} holds permissions that cannot be granted: {}.",
+            grant.grant_id,
+            grant.key,
+            grant.grantee,
+            invalid
+        );
+    }
+    let revoked = if revoke && !flagged.is_empty() {
+        let grant_ids: Vec<i64> = flagged.iter().map(|f| f.grant.grant_id).collect();
+        db.revoke_grants(&grant_ids).context("In reconcile: Failed to revoke grants.")?
+    } else {
+        0
+    };
+    let reconciliation = Reconciliation { checked, flagged, revoked };
+    *LAST_RECONCILIATION.lock().unwrap() = Some(reconciliation.clone());
+    Ok(reconciliation)
+}
+
+fn reconciliation_interval() -> Option<Duration> {
+    let seconds =
+        PropertyWatcher::new(RECONCILIATION_INTERVAL_PROPERTY).ok().and_then(|mut w| {
+            w.read(|_n, v| v.parse::<u64>().map_err(std::convert::Into::into)).ok()
+        })?;
+    if seconds == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(seconds))
+    }
+}
+
+fn revoke_enabled() -> bool {
+    PropertyWatcher::new(REVOKE_PROPERTY)
+        .ok()
+        .and_then(|mut w| w.read(|_n, v| v.parse::<bool>().map_err(std::convert::Into::into)).ok())
+        .unwrap_or(false)
+}
+
+/// Starts the periodic reconciliation pass if it is enabled by the system property
+/// `keystore.grant_reconciliation_interval_seconds`. The pass runs on the low priority queue
+/// of the async task.
+pub fn start() {
+    let interval = match reconciliation_interval() {
+        Some(interval) => interval,
+        None => return,
+    };
+    log::info!("Starting grant reconciliation with an interval of {:?}.", interval);
+    let spawned = TASK_EXECUTOR.spawn_periodic(
+        "keystore2_grant_reconciliation",
+        Priority::Background,
+        interval,
+        || {
+            ASYNC_TASK.queue_lo(|_| {
+                if let Err(e) = DB.with(|db| reconcile(&mut db.borrow_mut(), revoke_enabled())) {
+                    log::error!("Grant reconciliation failed: {:?}", e);
+                }
+            })
+        },
+    );
+    if let Err(e) = spawned {
+        log::error!("Failed to start grant reconciliation: {:?}", e);
+    }
+}
+
+/// Writes the outcome of the most recent reconciliation pass to `f`.
+pub fn dump(f: &mut dyn Write) -> std::io::Result<()> {
+    match LAST_RECONCILIATION.lock().unwrap().as_ref() {
+        None => writeln!(f, "Grant reconciliation: not run"),
+        Some(Reconciliation { checked, flagged, revoked }) => writeln!(
+            f,
+            "Grant reconciliation: checked: {} flagged: {} revoked: {}",
+            checked,
+            flagged.len(),
+            revoked
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_perm_set;
+    use crate::permission::KeyPerm;
+    use android_system_keystore2::aidl::android::system::keystore2::{
+        Domain::Domain, KeyDescriptor::KeyDescriptor,
+    };
+
+    fn grant(grant_id: i64, grantee: u32, access_vector: KeyPermSet) -> GrantRecord {
+        GrantRecord {
+            grant_id,
+            grantee,
+            key: KeyDescriptor { domain: Domain::SELINUX, nspace: 100, ..Default::default() },
+            access_vector,
+        }
+    }
+
+    #[test]
+    fn test_find_inconsistent_grants() {
+        let grants = vec![
+            grant(1, 10, key_perm_set![KeyPerm::use_(), KeyPerm::grant()]),
+            grant(2, 11, key_perm_set![KeyPerm::use_(), KeyPerm::get_info()]),
+            grant(3, 12, KeyPermSet(1 << 30)),
+        ];
+        let (checked, flagged) = find_inconsistent_grants(grants, |grant| {
+            permission::ungrantable_permissions(grant.access_vector)
+        });
+        assert_eq!(checked, 3);
+        assert_eq!(
+            flagged,
+            vec![
+                FlaggedGrant {
+                    grant: grant(1, 10, key_perm_set![KeyPerm::use_(), KeyPerm::grant()]),
+                    invalid: key_perm_set![KeyPerm::grant()],
+                },
+                FlaggedGrant {
+                    grant: grant(3, 12, KeyPermSet(1 << 30)),
+                    invalid: KeyPermSet(1 << 30)
+                },
+            ]
+        );
+    }
+}
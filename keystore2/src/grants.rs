@@ -0,0 +1,206 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the IKeystoreGrants AIDL interface, which lets grantors enumerate
+//! the outstanding grants of their keys. Listing the grants of a key requires the same
+//! `grant` permission that is required to issue them. It also lets grantors grant all keys
+//! of a `Domain::SELINUX` namespace at once, revoke some permissions of a grant while
+//! keeping the others, and bind a key to callers whose SELinux context matches a pattern.
+
+use crate::caller_identity::CallerIdentity;
+use crate::database::GrantRecord;
+use crate::error::{map_or_log_err, Error, ResponseCode};
+use crate::globals::{DB, LEGACY_MIGRATOR};
+use crate::key_perm_set;
+use crate::permission::{KeyPerm, KeyPermSet};
+use crate::utils::{check_grant_permission, check_key_permission, watchdog as wd};
+use android_security_grants::aidl::android::security::grants::{
+    GrantInfo::GrantInfo,
+    IKeystoreGrants::{BnKeystoreGrants, IKeystoreGrants},
+};
+use android_security_grants::binder::{BinderFeatures, Interface, Result as BinderResult, Strong};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{Context, Result};
+
+impl From<GrantRecord> for GrantInfo {
+    fn from(grant: GrantRecord) -> Self {
+        GrantInfo {
+            granteeUid: grant.grantee as i32,
+            grantId: grant.grant_id,
+            accessVector: grant.access_vector.into(),
+        }
+    }
+}
+
+/// Implementation of the IKeystoreGrants AIDL interface.
+pub struct KeystoreGrants;
+
+impl KeystoreGrants {
+    /// Creates a new instance of the grants service wrapped in a BnKeystoreGrants proxy object.
+    /// It also enables `BinderFeatures::set_requesting_sid` on the new interface, because the
+    /// permission check requires the caller's context.
+    pub fn new_native_binder() -> Result<Strong<dyn IKeystoreGrants>> {
+        Ok(BnKeystoreGrants::new_binder(
+            Self,
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        ))
+    }
+
+    fn list_grants(key: &KeyDescriptor, caller: &CallerIdentity) -> Result<Vec<GrantInfo>> {
+        if !matches!(key.domain, Domain::APP | Domain::SELINUX | Domain::KEY_ID) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(format!("In list_grants: Cannot list grants of {:?}.", key.domain));
+        }
+        let caller_uid = caller.uid();
+        let grants = DB
+            .with(|db| {
+                LEGACY_MIGRATOR.with_try_migrate(key, caller_uid, || {
+                    db.borrow_mut().list_grants_of_key(key, caller_uid, |k| {
+                        check_grant_permission(key_perm_set![], k)
+                    })
+                })
+            })
+            .context("In list_grants.")?;
+        Ok(grants.into_iter().map(GrantInfo::from).collect())
+    }
+
+    fn grant_namespace(namespace: i64, grantee_uid: i32, access_vector: KeyPermSet) -> Result<()> {
+        DB.with(|db| {
+            db.borrow_mut().grant_namespace(
+                namespace,
+                grantee_uid as u32,
+                access_vector,
+                |k, av| check_grant_permission(*av, k).context("During grant_namespace."),
+            )
+        })
+        .context("In grant_namespace.")
+    }
+
+    fn ungrant_namespace(namespace: i64, grantee_uid: i32) -> Result<()> {
+        DB.with(|db| {
+            db.borrow_mut().ungrant_namespace(namespace, grantee_uid as u32, |k, _| {
+                check_key_permission(KeyPerm::ungrant(), k, &None)
+                    .context("During ungrant_namespace.")
+            })
+        })
+        .context("In ungrant_namespace.")
+    }
+
+    fn revoke_grant_permissions(
+        key: &KeyDescriptor,
+        grantee_uid: i32,
+        access_vector: KeyPermSet,
+        caller: &CallerIdentity,
+    ) -> Result<()> {
+        if access_vector.0 == 0 {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In revoke_grant_permissions: No permissions to revoke.");
+        }
+        DB.with(|db| {
+            db.borrow_mut().ungrant(
+                key,
+                caller.uid(),
+                grantee_uid as u32,
+                Some(access_vector),
+                |k, _| {
+                    check_key_permission(KeyPerm::ungrant(), k, &None)
+                        .context("During revoke_grant_permissions.")
+                },
+            )
+        })
+        .context("In revoke_grant_permissions.")
+    }
+
+    fn set_client_context_pattern(
+        key: &KeyDescriptor,
+        pattern: Option<&str>,
+        caller: &CallerIdentity,
+    ) -> Result<()> {
+        if !matches!(key.domain, Domain::APP | Domain::SELINUX | Domain::KEY_ID) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(format!(
+                "In set_client_context_pattern: Cannot bind {:?} keys.",
+                key.domain
+            ));
+        }
+        if pattern.map_or(false, str::is_empty) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In set_client_context_pattern: Empty pattern.");
+        }
+        let caller_uid = caller.uid();
+        DB.with(|db| {
+            LEGACY_MIGRATOR.with_try_migrate(key, caller_uid, || {
+                db.borrow_mut().set_client_context_pattern(key, caller_uid, pattern, |k| {
+                    check_grant_permission(key_perm_set![], k)
+                })
+            })
+        })
+        .context("In set_client_context_pattern.")
+    }
+}
+
+impl Interface for KeystoreGrants {}
+
+impl IKeystoreGrants for KeystoreGrants {
+    fn listGrants(&self, key: &KeyDescriptor) -> BinderResult<Vec<GrantInfo>> {
+        let _wp = wd::watch_millis("IKeystoreGrants::listGrants", 500);
+        map_or_log_err(Self::list_grants(key, &CallerIdentity::current()), Ok)
+    }
+
+    fn grantNamespace(
+        &self,
+        nspace: i64,
+        grantee_uid: i32,
+        access_vector: i32,
+    ) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreGrants::grantNamespace", 500);
+        map_or_log_err(Self::grant_namespace(nspace, grantee_uid, access_vector.into()), Ok)
+    }
+
+    fn ungrantNamespace(&self, nspace: i64, grantee_uid: i32) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreGrants::ungrantNamespace", 500);
+        map_or_log_err(Self::ungrant_namespace(nspace, grantee_uid), Ok)
+    }
+
+    fn revokeGrantPermissions(
+        &self,
+        key: &KeyDescriptor,
+        grantee_uid: i32,
+        access_vector: i32,
+    ) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreGrants::revokeGrantPermissions", 500);
+        map_or_log_err(
+            Self::revoke_grant_permissions(
+                key,
+                grantee_uid,
+                access_vector.into(),
+                &CallerIdentity::current(),
+            ),
+            Ok,
+        )
+    }
+
+    fn setClientContextPattern(
+        &self,
+        key: &KeyDescriptor,
+        pattern: Option<&str>,
+    ) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreGrants::setClientContextPattern", 500);
+        map_or_log_err(
+            Self::set_client_context_pattern(key, pattern, &CallerIdentity::current()),
+            Ok,
+        )
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module tracks KeyMint and IRemotelyProvisionedComponent instances that were declared
+//! in the VINTF manifest but could not be reached when Keystore started, e.g., a StrongBox
+//! implemented by a SIM based secure element that initializes late. A watcher thread retries
+//! the connection with an increasing interval until the instance appears. Once connected,
+//! the security level binding is published here, and the services consult this module for
+//! security levels they do not know about.
+//!
+//! Legacy keys of a late security level are not migrated before Keystore restarts, because
+//! the legacy migrator is initialized with the security levels available at startup.
+
+use crate::database::Uuid;
+use crate::globals::TASK_EXECUTOR;
+use crate::id_rotation::IdRotationState;
+use crate::remote_provisioning::get_supported_eek_curve;
+use crate::security_level::KeystoreSecurityLevel;
+use crate::task_executor::Priority;
+use crate::utils::Asp;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use anyhow::{Context, Result};
+use keystore2_vintf::get_aidl_instances;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// Interval before the first connection retry. It doubles with every failed attempt.
+const INITIAL_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Upper bound for the retry interval.
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+lazy_static! {
+    /// Security level bindings created after startup, indexed by security level.
+    static ref LATE_SECURITY_LEVELS: RwLock<HashMap<SecurityLevel, (Asp, Uuid)>> =
+        Default::default();
+    /// The supported EEK curves of the remote provisioning components connected after startup,
+    /// indexed by security level. The connections themselves are cached in `globals`.
+    static ref LATE_RPC_DEVICES: RwLock<HashMap<SecurityLevel, i32>> = Default::default();
+}
+
+fn instance_name(security_level: SecurityLevel) -> Option<&'static str> {
+    match security_level {
+        SecurityLevel::TRUSTED_ENVIRONMENT => Some("default"),
+        SecurityLevel::STRONGBOX => Some("strongbox"),
+        _ => None,
+    }
+}
+
+/// Returns true if an instance of the given interface is declared for the security level.
+/// Only declared instances can appear later, so there is no point in watching for others.
+fn is_declared(interface_name: &str, security_level: SecurityLevel) -> bool {
+    let instance = match instance_name(security_level) {
+        Some(instance) => instance,
+        None => return false,
+    };
+    get_aidl_instances("android.hardware.security.keymint", 1, interface_name)
+        .as_vec()
+        .map(|instances| instances.iter().any(|i| *i == instance))
+        .unwrap_or(false)
+}
+
+fn next_retry_interval(interval: Duration) -> Duration {
+    (interval * 2).min(MAX_RETRY_INTERVAL)
+}
+
+/// Spawns a task that calls `try_connect` until it succeeds or the task is cancelled.
+fn watch<F>(description: String, mut try_connect: F)
+where
+    F: FnMut() -> Result<()> + Send + 'static,
+{
+    TASK_EXECUTOR
+        .spawn_dedicated("keystore2_hal_hotplug", Priority::Background, move |context| {
+            let mut interval = INITIAL_RETRY_INTERVAL;
+            while context.sleep(interval) {
+                match try_connect() {
+                    Ok(()) => {
+                        log::info!("Late {} is now available.", description);
+                        return;
+                    }
+                    Err(e) => {
+                        log::debug!("Late {} still unavailable: {:?}", description, e);
+                        interval = next_retry_interval(interval);
+                    }
+                }
+            }
+        })
+        .map(|_| ())
+        .unwrap_or_else(|e| log::error!("Failed to spawn hot plug watcher: {:?}", e));
+}
+
+/// Watches for the KeyMint instance of the given security level if it is declared but was
+/// not available at startup. Once it appears, a security level binding is created for it.
+pub fn watch_keymint(security_level: SecurityLevel, id_rotation_state: IdRotationState) {
+    if !is_declared("IKeyMintDevice", security_level) {
+        return;
+    }
+    watch(format!("KeyMint instance {:?}", security_level), move || {
+        let (dev, uuid) =
+            KeystoreSecurityLevel::new_native_binder(security_level, id_rotation_state.clone())
+                .context("In watch_keymint: Trying to create security level binding.")?;
+        LATE_SECURITY_LEVELS
+            .write()
+            .unwrap()
+            .insert(security_level, (Asp::new(dev.as_binder()), uuid));
+        Ok(())
+    });
+}
+
+/// Watches for the IRemotelyProvisionedComponent instance of the given security level if it
+/// is declared but was not available at startup.
+pub fn watch_remotely_provisioned_component(security_level: SecurityLevel) {
+    if !is_declared("IRemotelyProvisionedComponent", security_level) {
+        return;
+    }
+    watch(format!("IRemotelyProvisionedComponent instance {:?}", security_level), move || {
+        let curve = get_supported_eek_curve(&security_level)
+            .context("In watch_remotely_provisioned_component: Trying to connect.")?;
+        LATE_RPC_DEVICES.write().unwrap().insert(security_level, curve);
+        Ok(())
+    });
+}
+
+/// Returns the late security level binding and its KeyMint uuid for the given security level.
+pub fn security_level(security_level: SecurityLevel) -> Option<(Asp, Uuid)> {
+    LATE_SECURITY_LEVELS.read().unwrap().get(&security_level).cloned()
+}
+
+/// Returns the late security level binding with the given KeyMint uuid.
+pub fn security_level_by_uuid(uuid: &Uuid) -> Option<(SecurityLevel, Asp)> {
+    LATE_SECURITY_LEVELS
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(_, (_, u))| u == uuid)
+        .map(|(sec_level, (dev, _))| (*sec_level, dev.clone()))
+}
+
+/// Returns true if a late remote provisioning component was connected for the given security
+/// level.
+pub fn has_remotely_provisioned_component(security_level: SecurityLevel) -> bool {
+    LATE_RPC_DEVICES.read().unwrap().contains_key(&security_level)
+}
+
+/// Returns the security levels and supported EEK curves of all late remote provisioning
+/// components.
+pub fn remotely_provisioned_component_curves() -> Vec<(SecurityLevel, i32)> {
+    LATE_RPC_DEVICES.read().unwrap().iter().map(|(sec_level, curve)| (*sec_level, *curve)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_interval_is_bounded() {
+        let mut interval = INITIAL_RETRY_INTERVAL;
+        for _ in 0..20 {
+            interval = next_retry_interval(interval);
+        }
+        assert_eq!(interval, MAX_RETRY_INTERVAL);
+    }
+}
@@ -0,0 +1,79 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements IKeystoreHealth AIDL interface. It allows early-boot consumers, such
+//! as on-device signing, to learn whether Keystore is ready to serve key operations instead of
+//! retrying key operations until they succeed.
+
+use crate::error::map_or_log_err;
+use crate::globals::{get_bound_security_levels, is_db_initialized, DB};
+use crate::maintenance::Maintenance;
+use crate::permission::KeystorePerm;
+use crate::utils::{check_keystore_permission, watchdog as wd};
+use android_security_health::aidl::android::security::health::{
+    IKeystoreHealth::{BnKeystoreHealth, IKeystoreHealth},
+    KeystoreHealth::KeystoreHealth,
+};
+use android_security_health::binder::{BinderFeatures, Interface, Result as BinderResult, Strong};
+use android_security_maintenance::aidl::android::security::maintenance::UserState::UserState as AidlUserState;
+use anyhow::{Context, Result};
+
+/// This struct is defined to implement the aforementioned AIDL interface.
+/// As of now, it is an empty struct.
+pub struct Health;
+
+impl Health {
+    /// Create a new instance of Keystore Health service.
+    pub fn new_native_binder() -> Result<Strong<dyn IKeystoreHealth>> {
+        Ok(BnKeystoreHealth::new_binder(
+            Self,
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        ))
+    }
+
+    fn get_health() -> Result<KeystoreHealth> {
+        check_keystore_permission(KeystorePerm::get_state()).context("In get_health.")?;
+
+        // Opening the database on first use runs the start-up cleanup, so probing the
+        // connection of this thread also makes sure that this happened before we report on it.
+        let database_usable = match DB.with(|db| db.borrow_mut().probe()) {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("In get_health: The database is not usable: {:?}", e);
+                false
+            }
+        };
+
+        let mut bound_security_levels = get_bound_security_levels();
+        bound_security_levels.sort();
+        Ok(KeystoreHealth {
+            databaseOpen: database_usable && is_db_initialized(),
+            boundSecurityLevels: bound_security_levels,
+        })
+    }
+}
+
+impl Interface for Health {}
+
+impl IKeystoreHealth for Health {
+    fn getHealth(&self) -> BinderResult<KeystoreHealth> {
+        let _wp = wd::watch_millis("IKeystoreHealth::getHealth", 500);
+        map_or_log_err(Self::get_health(), Ok)
+    }
+
+    fn getUserState(&self, user_id: i32) -> BinderResult<AidlUserState> {
+        let _wp = wd::watch_millis("IKeystoreHealth::getUserState", 500);
+        map_or_log_err(Maintenance::get_state(user_id), Ok)
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the device key policy, a set of deny rules that device builders can
+//! layer on top of the SELinux policy without changing SEPolicy. The key policy is evaluated
+//! after SELinux has allowed a `keystore2_key` permission, so it can only take permissions
+//! away.
+//!
+//! The policy file holds one rule per line of the form
+//! `deny <permission>[,<permission>...] [domain=app|selinux] [namespace=<first>[-<last>]]
+//! [uid=<first>[-<last>]]`, e.g., `deny grant domain=selinux namespace=102` or
+//! `deny use_dev_id uid=10000-19999`. Permissions are named like in the SELinux policy. A rule
+//! applies to a request if all of its selectors match, omitted selectors match everything.
+//! Empty lines and lines starting with `#` are ignored.
+//!
+//! The rules are compiled into a `DecisionTable` once on first use. The system property
+//! `keystore.key_policy.mode` selects how the table is applied, see `ShadowMode`. It is read
+//! once on first use as well. It defaults to enforcement, "shadow" only logs and counts the
+//! requests that the policy would deny. If the policy file is malformed, every permission that
+//! passed SELinux is denied, because the policy can only have taken permissions away.
+//!
+//! Keys accessed by grant are judged by their owner's domain and namespace, see
+//! `restrict_grant`.
+
+use crate::config_file::{self, parse_range};
+use crate::permission::{KeyPerm, KeyPermSet};
+use crate::shadow_permission::{ShadowMode, ShadowRule};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{anyhow, Context, Result};
+use keystore2_system_property::PropertyWatcher;
+use lazy_static::lazy_static;
+use std::ops::RangeInclusive;
+
+/// Location of the key policy.
+const KEY_POLICY_CONFIG: &str = "/vendor/etc/security/keystore2_key_policy.conf";
+
+/// System property selecting the mode of the key policy.
+const KEY_POLICY_MODE_PROPERTY: &str = "keystore.key_policy.mode";
+
+/// The key policy as it appears in logs and metrics.
+const KEY_POLICY_RULE: ShadowRule = ShadowRule { name: "key_policy" };
+
+lazy_static! {
+    /// The key policy of this device, loaded once on first use.
+    static ref KEY_POLICY: Result<DecisionTable> =
+        config_file::load(KEY_POLICY_CONFIG, DecisionTable::parse);
+    /// The mode of the key policy, read once on first use.
+    static ref MODE: ShadowMode = mode();
+}
+
+/// Reason for denying a request on behalf of the key policy. It is reported as
+/// `ResponseCode::PERMISSION_DENIED`.
+#[derive(Debug, thiserror::Error, PartialEq)]
+#[error("The key policy denies {perm} to caller {caller_uid}.")]
+pub struct KeyPolicyDenied {
+    /// The denied permission.
+    pub perm: &'static str,
+    /// The calling uid.
+    pub caller_uid: u32,
+}
+
+/// A row of the decision table, i.e., one compiled deny rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Row {
+    domain: Option<Domain>,
+    namespaces: Option<RangeInclusive<i64>>,
+    uids: Option<RangeInclusive<u32>>,
+    denied: KeyPermSet,
+}
+
+impl Row {
+    fn matches(&self, caller_uid: u32, key: &KeyDescriptor) -> bool {
+        self.domain.map_or(true, |d| d == key.domain)
+            && self.namespaces.as_ref().map_or(true, |n| {
+                matches!(key.domain, Domain::APP | Domain::SELINUX) && n.contains(&key.nspace)
+            })
+            && self.uids.as_ref().map_or(true, |u| u.contains(&caller_uid))
+    }
+}
+
+fn parse_rule(line: &str) -> Result<Row> {
+    let mut fields = line.split_whitespace();
+    if fields.next() != Some("deny") {
+        return Err(anyhow!("Rules must start with \"deny\"."));
+    }
+    let denied: KeyPermSet =
+        fields.next().ok_or_else(|| anyhow!("Missing permissions."))?.parse()?;
+    let mut row = Row { domain: None, namespaces: None, uids: None, denied };
+    for selector in fields {
+        match selector.split_once('=') {
+            Some(("domain", "app")) => row.domain = Some(Domain::APP),
+            Some(("domain", "selinux")) => row.domain = Some(Domain::SELINUX),
+            Some(("namespace", range)) => row.namespaces = Some(parse_range(range)?),
+            Some(("uid", range)) => row.uids = Some(parse_range(range)?),
+            _ => return Err(anyhow!("Bad selector \"{}\".", selector)),
+        }
+    }
+    Ok(row)
+}
+
+/// The compiled key policy. Each row holds the permissions that a rule denies along with the
+/// selectors of the requests it applies to.
+#[derive(Debug, Default)]
+pub struct DecisionTable {
+    rows: Vec<Row>,
+}
+
+impl DecisionTable {
+    /// Parses and compiles the key policy. See the module documentation for the format.
+    pub fn parse(config: &str) -> Result<Self> {
+        let mut rows = Vec::new();
+        for (n, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            rows.push(parse_rule(line).with_context(|| format!("In line {}.", n + 1))?);
+        }
+        Ok(Self { rows })
+    }
+
+    /// Returns the permissions that the policy denies to `caller_uid` on `key`.
+    pub fn denied(&self, caller_uid: u32, key: &KeyDescriptor) -> KeyPermSet {
+        self.rows
+            .iter()
+            .filter(|row| row.matches(caller_uid, key))
+            .fold(KeyPermSet(0), |denied, row| KeyPermSet(denied.0 | row.denied.0))
+    }
+}
+
+fn mode() -> ShadowMode {
+    let configured = PropertyWatcher::new(KEY_POLICY_MODE_PROPERTY)
+        .ok()
+        .and_then(|mut w| w.read(|_n, v| Ok(v.to_string())).ok());
+    match configured.as_deref() {
+        Some("off") => ShadowMode::Off,
+        Some("shadow") => ShadowMode::Shadow,
+        _ => ShadowMode::Enforce,
+    }
+}
+
+/// Returns the subset of `perms` that the key policy denies to `caller_uid` on `key` in the
+/// configured mode. In shadow mode the denials are only logged, and nothing is denied. If the
+/// policy is malformed, all of `perms` are denied.
+pub fn enforced_denials(caller_uid: u32, perms: KeyPermSet, key: &KeyDescriptor) -> KeyPermSet {
+    let table = match &*KEY_POLICY {
+        Ok(table) => table,
+        Err(_) => return perms,
+    };
+    let denied = KeyPermSet(table.denied(caller_uid, key).0 & perms.0);
+    let perm: KeyPerm = match denied.into_iter().next() {
+        Some(perm) => perm,
+        None => return denied,
+    };
+    let decision = KEY_POLICY_RULE.check_with_mode(*MODE, || {
+        Err(anyhow!(KeyPolicyDenied { perm: perm.to_selinux(), caller_uid }))
+            .context(format!("In key_policy::check: Denied {} on {:?}.", denied, key))
+    });
+    match decision {
+        Ok(()) => KeyPermSet(0),
+        Err(_) => denied,
+    }
+}
+
+/// Checks `perms` of `caller_uid` on `key` against the key policy. It must only be called once
+/// SELinux has allowed the request.
+pub fn check(caller_uid: u32, perms: KeyPermSet, key: &KeyDescriptor) -> Result<()> {
+    let denied = enforced_denials(caller_uid, perms, key);
+    match denied.into_iter().next() {
+        None => Ok(()),
+        Some(perm) => Err(anyhow!(KeyPolicyDenied { perm: perm.to_selinux(), caller_uid }))
+            .context(format!("In key_policy::check: Denied {} on {:?}.", denied, key)),
+    }
+}
+
+/// Returns true if the key policy may deny anything, i.e., if `restrict_grant` can change an
+/// access vector.
+pub fn is_active() -> bool {
+    *MODE != ShadowMode::Off && KEY_POLICY.as_ref().map_or(true, |table| !table.rows.is_empty())
+}
+
+/// Removes the permissions that the key policy denies to `caller_uid` on the key of `owner`
+/// from the `access_vector` of a grant. The descriptor a grantee uses does not reveal the
+/// domain and namespace of the key, so the policy is applied to the grant when it is resolved.
+pub fn restrict_grant(
+    caller_uid: u32,
+    access_vector: KeyPermSet,
+    owner: &KeyDescriptor,
+) -> KeyPermSet {
+    let denied = enforced_denials(caller_uid, access_vector, owner);
+    KeyPermSet(access_vector.0 & !denied.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_perm_set;
+
+    fn key(domain: Domain, nspace: i64) -> KeyDescriptor {
+        KeyDescriptor { domain, nspace, alias: Some("key".to_string()), blob: None }
+    }
+
+    #[test]
+    fn test_decision_table() -> Result<()> {
+        let table = DecisionTable::parse(
+            "# Keys of the vendor namespace must not be shared.
+             deny grant domain=selinux namespace=102
+
+             deny use_dev_id,gen_unique_id uid=10000-19999",
+        )?;
+        assert_eq!(table.denied(1000, &key(Domain::SELINUX, 102)), key_perm_set![KeyPerm::grant()]);
+        assert_eq!(table.denied(1000, &key(Domain::SELINUX, 103)), key_perm_set![]);
+        assert_eq!(table.denied(1000, &key(Domain::APP, 102)), key_perm_set![]);
+        assert_eq!(
+            table.denied(10123, &key(Domain::SELINUX, 102)),
+            key_perm_set![KeyPerm::grant(), KeyPerm::use_dev_id(), KeyPerm::gen_unique_id()]
+        );
+        assert_eq!(table.denied(20000, &key(Domain::APP, 20000)), key_perm_set![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_policy() {
+        assert!(DecisionTable::parse("allow grant").is_err());
+        assert!(DecisionTable::parse("deny").is_err());
+        assert!(DecisionTable::parse("deny frobnicate").is_err());
+        assert!(DecisionTable::parse("deny grant domain=blob").is_err());
+        assert!(DecisionTable::parse("deny grant namespace=9-1").is_err());
+        assert!(DecisionTable::parse("deny grant uid=-1").is_err());
+        assert!(DecisionTable::parse("deny grant owner=1").is_err());
+    }
+}
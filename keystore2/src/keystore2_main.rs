@@ -14,13 +14,37 @@
 
 //! This crate implements the Keystore 2.0 service entry point.
 
+use binder::SpIBinder;
+use keystore2::async_keygen::AsyncKeyGeneration;
+use keystore2::auth_token_coalescer;
+use keystore2::boot_state;
+use keystore2::bulk_import::BulkImport;
+use keystore2::cache_accounting;
+use keystore2::csprng;
+use keystore2::db_integrity;
 use keystore2::entropy;
-use keystore2::globals::ENFORCEMENTS;
+use keystore2::error_details::ErrorDetailsService;
+use keystore2::expiry_sweeper;
+use keystore2::external_keys::ExternalKeys;
+use keystore2::fs_verity::FsVerityService;
+use keystore2::globals::{DB, ENFORCEMENTS};
+use keystore2::grant_reconciliation;
+use keystore2::grants::KeystoreGrants;
+use keystore2::health::Health;
+use keystore2::labeled_operations::LabeledOperations;
+use keystore2::listing::KeystoreListing;
 use keystore2::maintenance::Maintenance;
-use keystore2::metrics::Metrics;
+use keystore2::metrics::{self, Metrics};
 use keystore2::metrics_store;
+use keystore2::namespace_freeze;
+use keystore2::odsign_key::OdsignKeyService;
+use keystore2::operation_intents;
+use keystore2::perboot_recovery;
 use keystore2::remote_provisioning::RemoteProvisioningService;
+use keystore2::secure_import::SecureImport;
 use keystore2::service::KeystoreService;
+use keystore2::shutdown;
+use keystore2::stale_op_reaper;
 use keystore2::{apc::ApcManager, shared_secret_negotiation};
 use keystore2::{authorization::AuthorizationManager, id_rotation::IdRotationState};
 use legacykeystore::LegacyKeystore;
@@ -34,6 +58,26 @@ static METRICS_SERVICE_NAME: &str = "android.security.metrics";
 static REMOTE_PROVISIONING_SERVICE_NAME: &str = "android.security.remoteprovisioning";
 static USER_MANAGER_SERVICE_NAME: &str = "android.security.maintenance";
 static LEGACY_KEYSTORE_SERVICE_NAME: &str = "android.security.legacykeystore";
+static ASYNC_KEYGEN_SERVICE_NAME: &str = "android.security.keygen";
+static HEALTH_SERVICE_NAME: &str = "android.security.health";
+static ERROR_DETAILS_SERVICE_NAME: &str = "android.security.errors";
+static FS_VERITY_SERVICE_NAME: &str = "android.security.fsverity";
+static LABELED_OPERATIONS_SERVICE_NAME: &str = "android.security.operations";
+static ODSIGN_KEY_SERVICE_NAME: &str = "android.security.odsign";
+static BULK_IMPORT_SERVICE_NAME: &str = "android.security.bulkimport";
+static GRANTS_SERVICE_NAME: &str = "android.security.grants";
+static LISTING_SERVICE_NAME: &str = "android.security.listing";
+static SECURE_IMPORT_SERVICE_NAME: &str = "android.security.secureimport";
+static EXTERNAL_KEYS_SERVICE_NAME: &str = "android.security.externalkeys";
+
+/// Registers a service that the platform policy does not declare on every device. Without a
+/// service_contexts entry, the service manager refuses the registration. This is logged, and
+/// Keystore keeps serving without the service instead of aborting on every start.
+fn add_optional_service(name: &str, service: SpIBinder) {
+    if let Err(e) = binder::add_service(name, service) {
+        error!("Failed to register optional service {} because of {:?}.", name, e);
+    }
+}
 
 /// Keystore 2.0 takes one argument which is a path indicating its designated working directory.
 fn main() {
@@ -49,11 +93,21 @@ fn main() {
     // Saying hi.
     info!("Keystore2 is starting.");
 
+    // This must happen before any other thread is started, see `shutdown::handle_sigterm`.
+    shutdown::handle_sigterm();
+
+    // A failed test is logged, and all requests for random data fail from now on.
+    if let Err(e) = csprng::self_test() {
+        error!("Random number generator self test failed: {:?}", e);
+    }
+
     let mut args = std::env::args();
     args.next().expect("That's odd. How is there not even a first argument?");
 
     // Write/update keystore.crash_count system property.
-    metrics_store::update_keystore_crash_sysprop();
+    let restarted = metrics_store::update_keystore_crash_sysprop();
+    // A restart during this boot lost the auth tokens of the previous instance.
+    perboot_recovery::check_on_startup(restarted);
 
     // Keystore 2.0 cannot change to the database directory (typically /data/misc/keystore) on
     // startup as Keystore 1.0 did because Keystore 2.0 is intended to run much earlier than
@@ -68,6 +122,20 @@ fn main() {
         panic!("Must specify a database directory.");
     };
 
+    db_integrity::check_on_startup();
+    boot_state::check_on_startup();
+    // Operations that were in progress when the previous instance ended are lost.
+    operation_intents::check_on_startup(restarted);
+    // Frozen namespaces must be known before any key is used. If they cannot be loaded, only
+    // system namespaces are usable until a later attempt succeeds. Panicking here instead would
+    // put the device into a boot loop.
+    DB.with(|db| namespace_freeze::load(&mut db.borrow_mut())).unwrap_or_else(|e| {
+        error!("Failed to load frozen namespaces because of {:?}.", e);
+    });
+
+    // The timers of deferred LSKF removals did not survive the previous instance.
+    Maintenance::check_lskf_removals_on_startup();
+
     let (confirmation_token_sender, confirmation_token_receiver) = channel();
 
     ENFORCEMENTS.install_confirmation_token_receiver(confirmation_token_receiver);
@@ -121,6 +189,80 @@ fn main() {
         panic!("Failed to register service {} because of {:?}.", METRICS_SERVICE_NAME, e);
     });
 
+    match AsyncKeyGeneration::new_native_binder() {
+        Ok(service) => add_optional_service(ASYNC_KEYGEN_SERVICE_NAME, service.as_binder()),
+        Err(e) => {
+            error!("Failed to create service {} because of {:?}.", ASYNC_KEYGEN_SERVICE_NAME, e)
+        }
+    }
+
+    match LabeledOperations::new_native_binder() {
+        Ok(service) => add_optional_service(LABELED_OPERATIONS_SERVICE_NAME, service.as_binder()),
+        Err(e) => error!(
+            "Failed to create service {} because of {:?}.",
+            LABELED_OPERATIONS_SERVICE_NAME, e
+        ),
+    }
+
+    let error_details_service = ErrorDetailsService::new_native_binder().unwrap_or_else(|e| {
+        panic!("Failed to create service {} because of {:?}.", ERROR_DETAILS_SERVICE_NAME, e);
+    });
+    binder::add_service(ERROR_DETAILS_SERVICE_NAME, error_details_service.as_binder())
+        .unwrap_or_else(|e| {
+            panic!("Failed to register service {} because of {:?}.", ERROR_DETAILS_SERVICE_NAME, e);
+        });
+
+    match FsVerityService::new_native_binder() {
+        Ok(service) => add_optional_service(FS_VERITY_SERVICE_NAME, service.as_binder()),
+        Err(e) => error!("Failed to create service {} because of {:?}.", FS_VERITY_SERVICE_NAME, e),
+    }
+
+    match OdsignKeyService::new_native_binder() {
+        Ok(service) => add_optional_service(ODSIGN_KEY_SERVICE_NAME, service.as_binder()),
+        Err(e) => {
+            error!("Failed to create service {} because of {:?}.", ODSIGN_KEY_SERVICE_NAME, e)
+        }
+    }
+
+    match BulkImport::new_native_binder() {
+        Ok(service) => add_optional_service(BULK_IMPORT_SERVICE_NAME, service.as_binder()),
+        Err(e) => {
+            error!("Failed to create service {} because of {:?}.", BULK_IMPORT_SERVICE_NAME, e)
+        }
+    }
+
+    match KeystoreGrants::new_native_binder() {
+        Ok(service) => add_optional_service(GRANTS_SERVICE_NAME, service.as_binder()),
+        Err(e) => error!("Failed to create service {} because of {:?}.", GRANTS_SERVICE_NAME, e),
+    }
+
+    match KeystoreListing::new_native_binder() {
+        Ok(service) => add_optional_service(LISTING_SERVICE_NAME, service.as_binder()),
+        Err(e) => error!("Failed to create service {} because of {:?}.", LISTING_SERVICE_NAME, e),
+    }
+
+    match SecureImport::new_native_binder() {
+        Ok(service) => add_optional_service(SECURE_IMPORT_SERVICE_NAME, service.as_binder()),
+        Err(e) => {
+            error!("Failed to create service {} because of {:?}.", SECURE_IMPORT_SERVICE_NAME, e)
+        }
+    }
+
+    let external_keys_service = ExternalKeys::new_native_binder().unwrap_or_else(|e| {
+        panic!("Failed to create service {} because of {:?}.", EXTERNAL_KEYS_SERVICE_NAME, e);
+    });
+    binder::add_service(EXTERNAL_KEYS_SERVICE_NAME, external_keys_service.as_binder())
+        .unwrap_or_else(|e| {
+            panic!("Failed to register service {} because of {:?}.", EXTERNAL_KEYS_SERVICE_NAME, e);
+        });
+
+    let health_service = Health::new_native_binder().unwrap_or_else(|e| {
+        panic!("Failed to create service {} because of {:?}.", HEALTH_SERVICE_NAME, e);
+    });
+    binder::add_service(HEALTH_SERVICE_NAME, health_service.as_binder()).unwrap_or_else(|e| {
+        panic!("Failed to register service {} because of {:?}.", HEALTH_SERVICE_NAME, e);
+    });
+
     // Devices with KS2 and KM 1.0 may not have any IRemotelyProvisionedComponent HALs at all. Do
     // not panic if new_native_binder returns failure because it could not find the TEE HAL.
     if let Ok(remote_provisioning_service) = RemoteProvisioningService::new_native_binder() {
@@ -147,6 +289,13 @@ fn main() {
 
     info!("Successfully registered Keystore 2.0 service.");
 
+    expiry_sweeper::start();
+    stale_op_reaper::start();
+    auth_token_coalescer::start();
+    cache_accounting::start();
+    grant_reconciliation::start();
+    metrics::start_namespace_usage_reporting();
+
     info!("Joining thread pool now.");
     binder::ProcessState::join_thread_pool();
 }
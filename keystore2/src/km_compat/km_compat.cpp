@@ -1332,6 +1332,16 @@ void KeyMintDevice::setNumFreeSlots(uint8_t numFreeSlots) {
     mOperationSlots.setNumFreeSlots(numFreeSlots);
 }
 
+ScopedAStatus KeyMintDevice::abortOrphanedOperation(uint64_t operationHandle) {
+    // The operation does not hold a slot of this instance, so no slot is freed.
+    auto result = mDevice->abort(operationHandle);
+    if (!result.isOk()) {
+        LOG(ERROR) << __func__ << " transaction failed. " << result.description();
+        return convertErrorCode(KMV1::ErrorCode::UNKNOWN_ERROR);
+    }
+    return convertErrorCode(result);
+}
+
 // Constructors and helpers.
 
 KeyMintDevice::KeyMintDevice(sp<Keymaster> device, KeyMintSecurityLevel securityLevel)
@@ -1430,3 +1440,15 @@ ScopedAStatus KeystoreCompatService::getSecureClock(std::shared_ptr<ISecureClock
     *_aidl_return = mSecureClock;
     return ScopedAStatus::ok();
 }
+
+ScopedAStatus KeystoreCompatService::abortOrphanedOperation(KeyMintSecurityLevel in_securityLevel,
+                                                            int64_t in_operationHandle) {
+    std::shared_ptr<IKeyMintDevice> device;
+    auto status = getKeyMintDevice(in_securityLevel, &device);
+    if (!status.isOk()) {
+        return status;
+    }
+    // The device cache only holds instances of KeyMintDevice.
+    return std::static_pointer_cast<KeyMintDevice>(device)->abortOrphanedOperation(
+        static_cast<uint64_t>(in_operationHandle));
+}
@@ -130,6 +130,9 @@ class KeyMintDevice : public aidl::android::hardware::security::keymint::BnKeyMi
 
     void setNumFreeSlots(uint8_t numFreeSlots);
 
+    // Aborts an operation that was begun by a previous instance of Keystore 2.0.
+    ScopedAStatus abortOrphanedOperation(uint64_t operationHandle);
+
   private:
     std::optional<KMV1_ErrorCode> signCertificate(const std::vector<KeyParameter>& keyParams,
                                                   const std::vector<uint8_t>& keyBlob, X509* cert);
@@ -207,4 +210,6 @@ class KeystoreCompatService : public BnKeystoreCompatService {
     ScopedAStatus getSharedSecret(KeyMintSecurityLevel in_securityLevel,
                                   std::shared_ptr<ISharedSecret>* _aidl_return) override;
     ScopedAStatus getSecureClock(std::shared_ptr<ISecureClock>* _aidl_return) override;
+    ScopedAStatus abortOrphanedOperation(KeyMintSecurityLevel in_securityLevel,
+                                         int64_t in_operationHandle) override;
 };
@@ -15,6 +15,7 @@
  */
 
 #include "km_compat.h"
+#include <AndroidKeyMintDevice.h>
 #include <android/binder_manager.h>
 
 #include <mutex>
@@ -37,4 +38,22 @@ int32_t addKeyMintDeviceService() {
     }
     return status;
 }
+
+// Create the software KeyMint device on first use and return a new strong reference to its
+// binder. The caller owns the reference. Returns nullptr if the device cannot be created.
+AIBinder* createSoftwareKeyMintDevice() {
+    static std::mutex mutex;
+    std::lock_guard<std::mutex> lock(mutex);
+    static std::shared_ptr<IKeyMintDevice> device;
+    if (!device) {
+        device.reset(::aidl::android::hardware::security::keymint::CreateKeyMintDevice(
+            KeyMintSecurityLevel::SOFTWARE));
+    }
+    if (!device) {
+        return nullptr;
+    }
+    ndk::SpAIBinder binder = device->asBinder();
+    AIBinder_incStrong(binder.get());
+    return binder.get();
+}
 }
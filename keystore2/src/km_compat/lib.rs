@@ -12,11 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! Export into Rust a function to create a KeyMintDevice and add it as a service.
+//! Export into Rust a function to create a KeyMintDevice and add it as a service, and a function
+//! to get the software KeyMint device.
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::IKeyMintDevice::IKeyMintDevice;
+use binder::unstable_api::{new_spibinder, AIBinder};
+use binder::{FromIBinder, Strong};
 
 #[allow(missing_docs)] // TODO remove this
 extern "C" {
     fn addKeyMintDeviceService() -> i32;
+    fn createSoftwareKeyMintDevice() -> *mut AIBinder;
 }
 
 #[allow(missing_docs)] // TODO remove this
@@ -24,6 +30,15 @@ pub fn add_keymint_device_service() -> i32 {
     unsafe { addKeyMintDeviceService() }
 }
 
+/// Returns the software implementation of KeyMint, which runs in the calling process. It is
+/// created on first use and shared by all callers. Returns None if it cannot be created.
+pub fn get_software_keymint_device() -> Option<Strong<dyn IKeyMintDevice>> {
+    // Safety: createSoftwareKeyMintDevice returns either null or a binder with a strong
+    // reference that is owned by the caller, and new_spibinder takes over that reference.
+    let binder = unsafe { new_spibinder(createSoftwareKeyMintDevice()) }?;
+    FromIBinder::try_from(binder).ok()
+}
+
 #[cfg(test)]
 mod tests {
 
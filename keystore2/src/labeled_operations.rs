@@ -0,0 +1,145 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the IKeystoreLabeledOperations AIDL interface. It begins operations
+//! like `IKeystoreSecurityLevel::createOperation`, but attaches a caller supplied label to them.
+//! The label shows up in the maintenance dump, in watchdog reports, and in the log line that is
+//! written when the operation is pruned.
+
+use crate::async_keygen::get_security_level;
+use crate::caller_identity::CallerIdentity;
+use crate::error::map_or_log_err;
+use crate::utils::watchdog as wd;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyParameter::KeyParameter, SecurityLevel::SecurityLevel,
+};
+use android_security_operations::aidl::android::security::operations::IKeystoreLabeledOperations::{
+    BnKeystoreLabeledOperations, IKeystoreLabeledOperations, MAX_LABEL_LENGTH,
+};
+use android_security_operations::binder::{
+    BinderFeatures, Interface, Result as BinderResult, Strong,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    CreateOperationResponse::CreateOperationResponse, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{Context, Result};
+use std::io::Write;
+
+/// Character that replaces control characters in labels, so that a label cannot break up the
+/// lines of a log or dump.
+const REPLACEMENT_CHARACTER: char = '?';
+
+/// Truncates the label to `MAX_LABEL_LENGTH` bytes at a character boundary and replaces all
+/// control characters.
+pub fn sanitize_label(label: &str) -> String {
+    let mut sanitized = String::new();
+    for c in label.chars() {
+        if sanitized.len() + c.len_utf8() > MAX_LABEL_LENGTH as usize {
+            break;
+        }
+        sanitized.push(if c.is_control() { REPLACEMENT_CHARACTER } else { c });
+    }
+    sanitized
+}
+
+/// Writes the live operations of all security levels to `f`.
+pub fn dump(f: &mut dyn Write) -> std::io::Result<()> {
+    writeln!(f, "Live operations:")?;
+    for security_level in
+        &[SecurityLevel::SOFTWARE, SecurityLevel::TRUSTED_ENVIRONMENT, SecurityLevel::STRONGBOX]
+    {
+        if let Ok(sec_level) = get_security_level(*security_level) {
+            writeln!(f, " {:?}:", security_level)?;
+            sec_level.dump_operations(f)?;
+        }
+    }
+    Ok(())
+}
+
+/// Implementation of the IKeystoreLabeledOperations AIDL interface.
+pub struct LabeledOperations;
+
+impl LabeledOperations {
+    /// Creates a new instance of the labeled operations service wrapped in a
+    /// BnKeystoreLabeledOperations proxy object. It also enables
+    /// `BinderFeatures::set_requesting_sid` on the new interface, because the permission checks
+    /// of createOperation require it.
+    pub fn new_native_binder() -> Result<Strong<dyn IKeystoreLabeledOperations>> {
+        Ok(BnKeystoreLabeledOperations::new_binder(
+            Self,
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        ))
+    }
+
+    fn create_labeled_operation(
+        security_level: SecurityLevel,
+        key: &KeyDescriptor,
+        operation_parameters: &[KeyParameter],
+        forced: bool,
+        label: &str,
+    ) -> Result<CreateOperationResponse> {
+        let sec_level =
+            get_security_level(security_level).context("In create_labeled_operation.")?;
+        let caller = CallerIdentity::current();
+        sec_level
+            .create_operation(
+                key,
+                operation_parameters,
+                forced,
+                Some(sanitize_label(label)),
+                &caller,
+            )
+            .context("In create_labeled_operation.")
+    }
+}
+
+impl Interface for LabeledOperations {}
+
+impl IKeystoreLabeledOperations for LabeledOperations {
+    fn createLabeledOperation(
+        &self,
+        security_level: SecurityLevel,
+        key: &KeyDescriptor,
+        operation_parameters: &[KeyParameter],
+        forced: bool,
+        label: &str,
+    ) -> BinderResult<CreateOperationResponse> {
+        let _wp = wd::watch_millis("IKeystoreLabeledOperations::createLabeledOperation", 500);
+        map_or_log_err(
+            Self::create_labeled_operation(
+                security_level,
+                key,
+                operation_parameters,
+                forced,
+                label,
+            ),
+            Ok,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_label_truncates_and_replaces() {
+        assert_eq!(sanitize_label("upload\nsigner"), "upload?signer");
+        let long = "a".repeat(MAX_LABEL_LENGTH as usize + 10);
+        assert_eq!(sanitize_label(&long).len(), MAX_LABEL_LENGTH as usize);
+        // A multi byte character that does not fit entirely is dropped.
+        let label = format!("{}é", "a".repeat(MAX_LABEL_LENGTH as usize - 1));
+        assert_eq!(sanitize_label(&label), "a".repeat(MAX_LABEL_LENGTH as usize - 1));
+    }
+}
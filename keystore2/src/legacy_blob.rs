@@ -17,6 +17,7 @@
 use crate::{
     error::{Error as KsError, ResponseCode},
     key_parameter::{KeyParameter, KeyParameterValue},
+    metrics_store::log_legacy_blob_quarantine_stats,
     super_key::SuperKeyManager,
     utils::uid_to_android_user,
 };
@@ -24,7 +25,7 @@ use android_hardware_security_keymint::aidl::android::hardware::security::keymin
     SecurityLevel::SecurityLevel, Tag::Tag, TagType::TagType,
 };
 use anyhow::{Context, Result};
-use keystore2_crypto::{aes_gcm_decrypt, Password, ZVec};
+use keystore2_crypto::{aes_gcm_decrypt, Error as CryptoError, Password, ZVec};
 use std::collections::{HashMap, HashSet};
 use std::{convert::TryInto, fs::File, path::Path, path::PathBuf};
 use std::{
@@ -87,6 +88,10 @@ pub enum Error {
     /// an invalid alias filename encoding.
     #[error("Invalid alias filename encoding.")]
     BadEncoding,
+    /// This error code is attached as context to errors that indicate that a legacy blob file
+    /// failed the integrity check. Such files are moved to quarantine.
+    #[error("Legacy blob file is corrupted.")]
+    Corrupted,
 }
 
 /// The blob payload, optionally with all information required to decrypt it.
@@ -214,8 +219,10 @@ impl LegacyBlobLoader {
     // flags (1 Byte)
     // info (1 Byte)
     // initialization_vector (16 Bytes)
-    // integrity (MD5 digest or gcm tag) (16 Bytes)
+    // integrity (gcm tag) (16 Bytes)
     // length (4 Bytes)
+    // Only encrypted blobs carry integrity data. Version 3 dropped the MD5 digest of earlier
+    // versions, so the gcm tag, which is verified on decryption, is all there is to check.
     const COMMON_HEADER_SIZE: usize = 4 + Self::IV_SIZE + Self::GCM_TAG_LENGTH + 4;
 
     const VERSION_OFFSET: usize = 0;
@@ -225,7 +232,9 @@ impl LegacyBlobLoader {
     const LENGTH_OFFSET: usize = 4 + Self::IV_SIZE + Self::GCM_TAG_LENGTH;
     const IV_OFFSET: usize = 4;
     const AEAD_TAG_OFFSET: usize = Self::IV_OFFSET + Self::IV_SIZE;
-    const _DIGEST_OFFSET: usize = Self::IV_OFFSET + Self::IV_SIZE;
+
+    // Corrupted files are moved to this directory, relative to the legacy blob database.
+    const QUARANTINE_DIR: &'static str = "legacy_quarantine";
 
     /// Construct a new LegacyBlobLoader with a root path of `path` relative to which it will
     /// expect legacy key blob files.
@@ -565,10 +574,12 @@ impl LegacyBlobLoader {
             BlobValue::Characteristics(data) => &data[..],
             BlobValue::CharacteristicsCache(data) => &data[..],
             _ => {
-                return Err(KsError::Rc(ResponseCode::VALUE_CORRUPTED)).context(concat!(
-                    "In read_characteristics_file: ",
-                    "Characteristics file does not hold key characteristics."
-                ))
+                return Err(KsError::Rc(ResponseCode::VALUE_CORRUPTED))
+                    .context(Error::Corrupted)
+                    .context(concat!(
+                        "In read_characteristics_file: ",
+                        "Characteristics file does not hold key characteristics."
+                    ))
             }
         };
 
@@ -577,6 +588,7 @@ impl LegacyBlobLoader {
             // the hardware enforced list.
             BlobValue::CharacteristicsCache(_) => Some(
                 Self::read_key_parameters(&mut stream)
+                    .context(Error::Corrupted)
                     .context("In read_characteristics_file.")?
                     .into_iter()
                     .map(|value| KeyParameter::new(value, hw_sec_level)),
@@ -585,6 +597,7 @@ impl LegacyBlobLoader {
         };
 
         let sw_list = Self::read_key_parameters(&mut stream)
+            .context(Error::Corrupted)
             .context("In read_characteristics_file.")?
             .into_iter()
             .map(|value| KeyParameter::new(value, SecurityLevel::KEYSTORE));
@@ -636,7 +649,14 @@ impl LegacyBlobLoader {
             },
         };
 
-        Ok(Some(Self::new_from_stream(&mut file).context("In read_generic_blob.")?))
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer).context("In read_generic_blob.")?;
+
+        Ok(Some(
+            Self::new_from_stream(&mut &buffer[..])
+                .context(Error::Corrupted)
+                .context("In read_generic_blob.")?,
+        ))
     }
 
     /// Read a legacy keystore entry blob.
@@ -789,6 +809,12 @@ impl LegacyBlobLoader {
         path
     }
 
+    fn make_quarantine_dir_name(&self) -> PathBuf {
+        let mut path = self.path.clone();
+        path.push(Self::QUARANTINE_DIR);
+        path
+    }
+
     fn make_super_key_filename(&self, user_id: u32) -> PathBuf {
         let mut path = self.make_user_path_name(user_id);
         path.push(".masterkey");
@@ -999,16 +1025,54 @@ impl LegacyBlobLoader {
         Ok(())
     }
 
-    /// Load a legacy key blob entry by uid and alias.
+    /// Load a legacy key blob entry by uid and alias. Files of the entry that fail the integrity
+    /// check are moved to quarantine and the entry is loaded as if they did not exist.
     pub fn load_by_uid_alias(
         &self,
         uid: u32,
         alias: &str,
         key_manager: Option<&SuperKeyManager>,
     ) -> Result<(Option<(Blob, Vec<KeyParameter>)>, Option<Vec<u8>>, Option<Vec<u8>>)> {
-        let km_blob = self.read_km_blob_file(uid, alias).context("In load_by_uid_alias.")?;
+        let km_blob = match self.load_km_blob(uid, alias, key_manager) {
+            Err(e) if Self::is_corrupted(&e) => {
+                log::warn!("In load_by_uid_alias: Quarantining corrupted key blob: {:?}", e);
+                // The key characteristics belong to the key blob, so we quarantine them
+                // together. A key must never be migrated without its characteristics.
+                let paths: Vec<PathBuf> = ["USRPKEY", "USRSKEY"]
+                    .iter()
+                    .flat_map(|prefix| {
+                        vec![
+                            self.make_blob_filename(uid, alias, prefix),
+                            self.make_chr_filename(uid, alias, prefix),
+                        ]
+                    })
+                    .collect();
+                self.quarantine(&paths);
+                None
+            }
+            result => result.context("In load_by_uid_alias.")?,
+        };
 
-        let km_blob = match km_blob {
+        let user_cert = self
+            .load_cert_file(uid, alias, "USRCERT")
+            .context("In load_by_uid_alias: While loading user cert.")?;
+
+        let ca_cert = self
+            .load_cert_file(uid, alias, "CACERT")
+            .context("In load_by_uid_alias: While loading ca cert.")?;
+
+        Ok((km_blob, user_cert, ca_cert))
+    }
+
+    fn load_km_blob(
+        &self,
+        uid: u32,
+        alias: &str,
+        key_manager: Option<&SuperKeyManager>,
+    ) -> Result<Option<(Blob, Vec<KeyParameter>)>> {
+        let km_blob = self.read_km_blob_file(uid, alias).context("In load_km_blob.")?;
+
+        Ok(match km_blob {
             Some((km_blob, prefix)) => {
                 let km_blob = match km_blob {
                     Blob { flags: _, value: BlobValue::Decrypted(_) } => km_blob,
@@ -1018,13 +1082,27 @@ impl LegacyBlobLoader {
                             let decrypted = match key_manager
                                 .get_per_boot_key_by_user_id(uid_to_android_user(uid))
                             {
-                                Some(key) => key.aes_gcm_decrypt(data, iv, tag).context(
-                                    "In load_by_uid_alias: while trying to decrypt legacy blob.",
-                                )?,
+                                Some(key) => key
+                                    .aes_gcm_decrypt(data, iv, tag)
+                                    .map_err(|e| {
+                                        // The user's key is the right one, so a tag mismatch
+                                        // means that the blob was altered.
+                                        if matches!(
+                                            e.root_cause().downcast_ref::<CryptoError>(),
+                                            Some(CryptoError::DecryptionFailed)
+                                        ) {
+                                            e.context(Error::Corrupted)
+                                        } else {
+                                            e
+                                        }
+                                    })
+                                    .context(
+                                        "In load_km_blob: while trying to decrypt legacy blob.",
+                                    )?,
                                 None => {
                                     return Err(KsError::Rc(ResponseCode::LOCKED)).context(format!(
                                         concat!(
-                                            "In load_by_uid_alias: ",
+                                            "In load_km_blob: ",
                                             "User {} has not unlocked the keystore yet.",
                                         ),
                                         uid_to_android_user(uid)
@@ -1037,9 +1115,11 @@ impl LegacyBlobLoader {
                         }
                     }
                     _ => {
-                        return Err(KsError::Rc(ResponseCode::VALUE_CORRUPTED)).context(
-                            "In load_by_uid_alias: Found wrong blob type in legacy key blob file.",
-                        )
+                        return Err(KsError::Rc(ResponseCode::VALUE_CORRUPTED))
+                            .context(Error::Corrupted)
+                            .context(
+                                "In load_km_blob: Found wrong blob type in legacy key blob file.",
+                            )
                     }
                 };
 
@@ -1049,37 +1129,82 @@ impl LegacyBlobLoader {
                 };
                 let key_parameters = self
                     .read_characteristics_file(uid, &prefix, alias, hw_sec_level)
-                    .context("In load_by_uid_alias.")?;
+                    .context("In load_km_blob.")?;
                 Some((km_blob, key_parameters))
             }
             None => None,
+        })
+    }
+
+    fn load_cert_file(&self, uid: u32, alias: &str, prefix: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.make_blob_filename(uid, alias, prefix);
+        let result = match Self::read_generic_blob(&path) {
+            Ok(Some(Blob { value: BlobValue::Generic(data), .. })) => Ok(Some(data)),
+            Ok(None) => Ok(None),
+            Ok(Some(_)) => {
+                Err(KsError::Rc(ResponseCode::VALUE_CORRUPTED)).context(Error::Corrupted).context(
+                    format!("In load_cert_file: Found unexpected blob type in {} file", prefix),
+                )
+            }
+            Err(e) => Err(e),
         };
 
-        let user_cert =
-            match Self::read_generic_blob(&self.make_blob_filename(uid, alias, "USRCERT"))
-                .context("In load_by_uid_alias: While loading user cert.")?
-            {
-                Some(Blob { value: BlobValue::Generic(data), .. }) => Some(data),
-                None => None,
-                _ => {
-                    return Err(KsError::Rc(ResponseCode::VALUE_CORRUPTED)).context(
-                        "In load_by_uid_alias: Found unexpected blob type in USRCERT file",
-                    )
-                }
-            };
+        match result {
+            Err(e) if Self::is_corrupted(&e) => {
+                log::warn!("In load_cert_file: Quarantining corrupted certificate: {:?}", e);
+                self.quarantine(&[path]);
+                Ok(None)
+            }
+            result => result.context("In load_cert_file."),
+        }
+    }
 
-        let ca_cert = match Self::read_generic_blob(&self.make_blob_filename(uid, alias, "CACERT"))
-            .context("In load_by_uid_alias: While loading ca cert.")?
-        {
-            Some(Blob { value: BlobValue::Generic(data), .. }) => Some(data),
-            None => None,
-            _ => {
-                return Err(KsError::Rc(ResponseCode::VALUE_CORRUPTED))
-                    .context("In load_by_uid_alias: Found unexpected blob type in CACERT file")
+    /// Returns true if the error indicates that a legacy blob file failed the integrity check.
+    fn is_corrupted(e: &anyhow::Error) -> bool {
+        e.downcast_ref::<Error>() == Some(&Error::Corrupted)
+    }
+
+    /// Moves the given files to the quarantine directory. Files that do not exist are ignored.
+    /// Failing to move a file is logged but not fatal, because there is nothing the caller
+    /// could do about it.
+    fn quarantine(&self, paths: &[PathBuf]) {
+        let quarantine_dir = self.make_quarantine_dir_name();
+        if let Err(e) = Self::with_retry_interrupted(|| fs::create_dir_all(&quarantine_dir)) {
+            log::error!("In quarantine: Failed to create quarantine directory: {:?}", e);
+            return;
+        }
+
+        let mut quarantined_files = 0;
+        for path in paths.iter().filter(|path| path.is_file()) {
+            // The file name is prefixed with the name of the user directory, because the
+            // quarantine directory is shared by all users.
+            let file_name = match (
+                path.parent().and_then(|p| p.file_name()).and_then(|n| n.to_str()),
+                path.file_name().and_then(|n| n.to_str()),
+            ) {
+                (Some(user_dir), Some(file_name)) => format!("{}_{}", user_dir, file_name),
+                _ => continue,
+            };
+            let mut target = quarantine_dir.clone();
+            target.push(file_name);
+            match Self::with_retry_interrupted(|| fs::rename(path, &target)) {
+                Ok(()) => quarantined_files += 1,
+                Err(e) => log::error!("In quarantine: Failed to move {:?}: {:?}", path, e),
             }
-        };
+        }
 
-        Ok((km_blob, user_cert, ca_cert))
+        if quarantined_files != 0 {
+            log_legacy_blob_quarantine_stats(quarantined_files);
+        }
+    }
+
+    /// Returns the number of legacy blob files that were moved to quarantine.
+    pub fn count_quarantined_files(&self) -> Result<usize> {
+        match Self::with_retry_interrupted(|| fs::read_dir(self.make_quarantine_dir_name())) {
+            Ok(dir) => Ok(dir.count()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e).context("In count_quarantined_files: Failed to open directory."),
+        }
     }
 
     /// Returns true if the given user has a super key.
@@ -1406,6 +1531,71 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_quarantine_corrupted_blobs() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("quarantine_corrupted_blobs")?;
+        std::fs::create_dir(&*temp_dir.build().push("user_0"))?;
+
+        std::fs::write(
+            &*temp_dir.build().push("user_0").push("10223_USRPKEY_non_authbound"),
+            &USRPKEY_NON_AUTHBOUND[0..20],
+        )?;
+        std::fs::write(
+            &*temp_dir.build().push("user_0").push(".10223_chr_USRPKEY_non_authbound"),
+            USRPKEY_NON_AUTHBOUND_CHR,
+        )?;
+        std::fs::write(
+            &*temp_dir.build().push("user_0").push("10223_USRCERT_non_authbound"),
+            USRCERT_NON_AUTHBOUND,
+        )?;
+
+        let legacy_blob_loader = LegacyBlobLoader::new(temp_dir.path());
+        assert_eq!(legacy_blob_loader.count_quarantined_files()?, 0);
+
+        let (km_blob, user_cert, ca_cert) =
+            legacy_blob_loader.load_by_uid_alias(10223, "non_authbound", None)?;
+        assert!(km_blob.is_none());
+        assert_eq!(user_cert.as_deref(), Some(LOADED_CERT_NON_AUTHBOUND));
+        assert!(ca_cert.is_none());
+
+        // The key blob was quarantined together with its characteristics.
+        assert_eq!(legacy_blob_loader.count_quarantined_files()?, 2);
+        assert_eq!(
+            legacy_blob_loader.list_keystore_entries_for_uid(10223)?,
+            vec!["non_authbound".to_string()]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quarantine_blob_with_bad_tag() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("quarantine_blob_with_bad_tag")?;
+        std::fs::create_dir(&*temp_dir.build().push("user_0"))?;
+        std::fs::write(&*temp_dir.build().push("user_0").push(".masterkey"), SUPERKEY)?;
+
+        let mut blob = USRPKEY_AUTHBOUND.to_vec();
+        blob[LegacyBlobLoader::AEAD_TAG_OFFSET] ^= 1;
+        std::fs::write(&*temp_dir.build().push("user_0").push("10223_USRPKEY_authbound"), blob)?;
+        std::fs::write(
+            &*temp_dir.build().push("user_0").push(".10223_chr_USRPKEY_authbound"),
+            USRPKEY_AUTHBOUND_CHR,
+        )?;
+
+        let key_manager: SuperKeyManager = Default::default();
+        let mut db = crate::database::KeystoreDB::new(temp_dir.path(), None)?;
+        let legacy_blob_loader = LegacyBlobLoader::new(temp_dir.path());
+        key_manager.unlock_user_key(&mut db, 0, &(PASSWORD.into()), &legacy_blob_loader)?;
+
+        assert_eq!(
+            legacy_blob_loader.load_by_uid_alias(10223, "authbound", Some(&key_manager))?,
+            (None, None, None)
+        );
+        assert_eq!(legacy_blob_loader.count_quarantined_files()?, 2);
+
+        Ok(())
+    }
+
     #[test]
     fn list_non_existing_user() -> Result<()> {
         let temp_dir = TempDir::new("list_non_existing_user")?;
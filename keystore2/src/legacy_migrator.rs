@@ -18,14 +18,15 @@ use crate::key_parameter::KeyParameterValue;
 use crate::legacy_blob::BlobValue;
 use crate::utils::{uid_to_android_user, watchdog as wd};
 use crate::{async_task::AsyncTask, legacy_blob::LegacyBlobLoader};
-use crate::{database::KeyType, error::Error};
 use crate::{
+    database::io_stats::{self, Subsystem},
     database::{
-        BlobMetaData, BlobMetaEntry, CertificateInfo, DateTime, EncryptedBy, KeyMetaData,
-        KeyMetaEntry, KeystoreDB, Uuid, KEYSTORE_UUID,
+        BlobMetaData, BlobMetaEntry, CertificateInfo, DateTime, EncryptedBy, GrantRebindPolicy,
+        KeyMetaData, KeyMetaEntry, KeyOrigin, KeystoreDB, Uuid, KEYSTORE_UUID,
     },
     super_key::USER_SUPER_KEY,
 };
+use crate::{database::KeyType, error::Error};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
 use android_system_keystore2::aidl::android::system::keystore2::{
     Domain::Domain, KeyDescriptor::KeyDescriptor, ResponseCode::ResponseCode,
@@ -217,6 +218,30 @@ impl LegacyMigrator {
         )
     }
 
+    /// List all aliases of the legacy keys of the apps of the given user as Domain::APP key
+    /// descriptors. These are the keys that `bulk_delete_user` deletes.
+    pub fn list_user(&self, user_id: u32) -> Result<Vec<KeyDescriptor>> {
+        let _wp = wd::watch_millis("LegacyMigrator::list_user", 500);
+
+        self.do_serialized(move |state| state.list_user(user_id))
+            .unwrap_or_else(|| Ok(HashMap::new()))
+            .map(|entries| {
+                let mut descriptors: Vec<KeyDescriptor> = entries
+                    .into_iter()
+                    .flat_map(|(uid, aliases)| {
+                        aliases.into_iter().map(move |alias| KeyDescriptor {
+                            domain: Domain::APP,
+                            nspace: uid as i64,
+                            alias: Some(alias),
+                            blob: None,
+                        })
+                    })
+                    .collect();
+                descriptors.sort_unstable();
+                descriptors
+            })
+    }
+
     /// Sends the given closure to the migrator thread for execution after calling check_state.
     /// Returns None if the database was empty and the request was not executed.
     /// Otherwise returns Some with the result produced by the migration request.
@@ -245,6 +270,7 @@ impl LegacyMigrator {
             let (new_state, result) = if let Some(legacy_migrator_state) =
                 shelf.get_downcast_mut::<LegacyMigratorState>()
             {
+                let _subsystem = io_stats::enter(Subsystem::Migration);
                 let result = f(legacy_migrator_state);
                 (legacy_migrator_state.check_empty(), Some(result))
             } else {
@@ -430,6 +456,12 @@ impl LegacyMigratorState {
             .context("In list_uid: Trying to list legacy entries.")
     }
 
+    fn list_user(&mut self, user_id: u32) -> Result<HashMap<u32, HashSet<String>>> {
+        self.legacy_loader
+            .list_keystore_entries_for_user(user_id)
+            .context("In list_user: Trying to list legacy entries.")
+    }
+
     /// This is a key migration request that must run in the migrator thread. This must
     /// be passed to do_serialized.
     fn check_and_migrate(&mut self, uid: u32, mut key: KeyDescriptor) -> Result<()> {
@@ -518,6 +550,7 @@ impl LegacyMigratorState {
                 let creation_date = DateTime::now()
                     .context("In check_and_migrate: Trying to make creation time.")?;
                 metadata.add(KeyMetaEntry::CreationDate(creation_date));
+                metadata.add(KeyMetaEntry::Origin(KeyOrigin::Migrated));
 
                 // Store legacy key in the database.
                 self.db
@@ -529,6 +562,7 @@ impl LegacyMigratorState {
                         &CertificateInfo::new(user_cert, ca_cert),
                         &metadata,
                         &km_uuid,
+                        GrantRebindPolicy::Keep,
                     )
                     .context("In check_and_migrate.")?;
                 Ok(())
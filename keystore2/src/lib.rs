@@ -15,37 +15,88 @@
 //! This crate implements the Android Keystore 2.0 service.
 #![recursion_limit = "256"]
 
+pub mod access_control;
+pub mod access_group;
 pub mod apc;
+pub mod async_keygen;
 pub mod async_task;
+pub mod attestation_ids;
+pub mod auth_token_coalescer;
 pub mod authorization;
+pub mod blob_verification;
 pub mod boot_level_keys;
+pub mod boot_state;
+pub mod buffer_pool;
+pub mod bulk_import;
+pub mod cache_accounting;
+pub mod caller_identity;
+pub mod capability_matrix;
+pub mod concurrency_limit;
+pub mod csprng;
 pub mod database;
+pub mod db_integrity;
+pub mod denial_limiter;
 pub mod ec_crypto;
+#[cfg(feature = "embedded")]
+pub mod embedded;
 pub mod enforcements;
 pub mod entropy;
 pub mod error;
+pub mod error_details;
+#[cfg(feature = "key_escrow")]
+pub mod escrow;
+pub mod expiry_sweeper;
+pub mod external_keys;
+pub mod fs_verity;
 pub mod globals;
+pub mod grant_reconciliation;
+pub mod grants;
+pub mod hal_hotplug;
+pub mod health;
 pub mod id_rotation;
 /// Internal Representation of Key Parameter and convenience functions.
 pub mod key_parameter;
+pub mod key_policy;
+pub mod labeled_operations;
 pub mod legacy_blob;
 pub mod legacy_migrator;
+pub mod listing;
 pub mod maintenance;
+pub mod metadata_snapshot;
 pub mod metrics;
 pub mod metrics_store;
+pub mod namespace_freeze;
+pub mod namespace_params;
+pub mod odsign_key;
 pub mod operation;
+pub mod operation_intents;
+pub mod perboot_recovery;
 pub mod permission;
+pub mod quota;
 pub mod raw_device;
 pub mod remote_provisioning;
+pub mod secure_import;
 pub mod security_level;
 pub mod service;
+pub mod shadow_permission;
 pub mod shared_secret_negotiation;
+pub mod shutdown;
+pub mod software_fallback;
+pub mod stale_op_reaper;
+pub mod storage_key;
+pub mod strict_mode;
+pub mod task_executor;
+pub mod tenants;
+pub mod trace;
 pub mod try_insert;
 pub mod utils;
 
 mod attestation_key_utils;
 mod audit_log;
+mod blob_format;
+mod config_file;
 mod gc;
+mod lock_order;
 mod super_key;
 
 #[cfg(feature = "watchdog")]
@@ -0,0 +1,208 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the IKeystoreListing AIDL interface, which lets clients page
+//! through namespaces with more keys than fit into the response of a single
+//! `IKeystoreService::listEntries` call. Batches are cut at a caller supplied count and at
+//! `RESPONSE_SIZE_LIMIT`, whichever comes first. The permissions are those of `listEntries`.
+
+use crate::caller_identity::CallerIdentity;
+use crate::database::KeyType;
+use crate::error::{map_or_log_err, Error, ResponseCode};
+use crate::globals::{DB, LEGACY_MIGRATOR};
+use crate::service::resolve_list_namespace;
+use crate::utils::watchdog as wd;
+use android_security_listing::aidl::android::security::listing::IKeystoreListing::{
+    BnKeystoreListing, IKeystoreListing,
+};
+use android_security_listing::binder::{BinderFeatures, Interface, Result as BinderResult, Strong};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{Context, Result};
+
+/// The estimated parcel size of a batch is kept below this limit. The binder transaction
+/// buffer of 1MB is shared by all transactions in flight in the process, so a single response
+/// must use only a fraction of it.
+const RESPONSE_SIZE_LIMIT: usize = 350_000;
+
+/// The parcel size of a KeyDescriptor without its alias: the parcelable header, domain,
+/// nspace, the string length, its terminator, and the null marker of the blob.
+const KEY_DESCRIPTOR_OVERHEAD: usize = 4 + 4 + 8 + 4 + 4 + 4;
+
+/// Returns an upper bound of the parcel size of `key`. Aliases are written as UTF-16, which
+/// takes at most two bytes per byte of UTF-8.
+fn estimated_parcel_size(key: &KeyDescriptor) -> usize {
+    KEY_DESCRIPTOR_OVERHEAD + key.alias.as_ref().map_or(0, |alias| alias.len() * 2)
+}
+
+/// Truncates `keys` to the longest prefix whose estimated parcel size is within `limit`. The
+/// first key is kept even if it exceeds the limit on its own, so that a listing always makes
+/// progress. An empty batch would tell the caller that the listing is complete.
+fn truncate_to_size_limit(keys: &mut Vec<KeyDescriptor>, limit: usize) {
+    let mut size = 0;
+    let fits = keys
+        .iter()
+        .take_while(|k| {
+            size += estimated_parcel_size(k);
+            size <= limit
+        })
+        .count();
+    keys.truncate(fits.max(1));
+}
+
+/// Implementation of the IKeystoreListing AIDL interface.
+pub struct KeystoreListing;
+
+impl KeystoreListing {
+    /// Creates a new instance of the listing service wrapped in a BnKeystoreListing proxy
+    /// object. It also enables `BinderFeatures::set_requesting_sid` on the new interface,
+    /// because the permission check requires the caller's context.
+    pub fn new_native_binder() -> Result<Strong<dyn IKeystoreListing>> {
+        Ok(BnKeystoreListing::new_binder(
+            Self,
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        ))
+    }
+
+    fn list_entries_batched(
+        domain: Domain,
+        namespace: i64,
+        start_past_alias: Option<&str>,
+        count: i32,
+        caller: &CallerIdentity,
+    ) -> Result<Vec<KeyDescriptor>> {
+        if count <= 0 {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(format!("In list_entries_batched: Invalid count {}.", count));
+        }
+        let count = count as usize;
+        let k = resolve_list_namespace(domain, namespace, caller)
+            .context("In list_entries_batched.")?;
+
+        // Legacy keys are not ordered, but there are few of them, so they are filtered here.
+        let mut result: Vec<KeyDescriptor> = LEGACY_MIGRATOR
+            .list_uid(k.domain, k.nspace)
+            .context("In list_entries_batched: Trying to list legacy keys.")?
+            .into_iter()
+            .filter(|d| match (&d.alias, start_past_alias) {
+                (Some(alias), Some(start)) => alias.as_str() > start,
+                _ => true,
+            })
+            .collect();
+
+        result.append(
+            &mut DB
+                .with(|db| {
+                    db.borrow_mut().list_past_alias(
+                        k.domain,
+                        k.nspace,
+                        KeyType::Client,
+                        start_past_alias,
+                        Some(count),
+                    )
+                })
+                .context("In list_entries_batched: Trying to list keystore database.")?,
+        );
+
+        // Same order as `IKeystoreService::listEntries`. Rust compares strings by their bytes,
+        // which is the binary collation order of the database.
+        result.sort_unstable();
+        result.dedup();
+        result.truncate(count);
+        truncate_to_size_limit(&mut result, RESPONSE_SIZE_LIMIT);
+        Ok(result)
+    }
+
+    fn count_entries(domain: Domain, namespace: i64, caller: &CallerIdentity) -> Result<i32> {
+        let k = resolve_list_namespace(domain, namespace, caller).context("In count_entries.")?;
+
+        let legacy = LEGACY_MIGRATOR
+            .list_uid(k.domain, k.nspace)
+            .context("In count_entries: Trying to list legacy keys.")?;
+        let count = DB
+            .with(|db| {
+                let mut db = db.borrow_mut();
+                if legacy.is_empty() {
+                    return db.count_keys(k.domain, k.nspace, KeyType::Client);
+                }
+                // A key may be in both places while it is being migrated, so it must be
+                // counted once.
+                let mut keys = db.list(k.domain, k.nspace, KeyType::Client)?;
+                keys.extend(legacy);
+                keys.sort_unstable();
+                keys.dedup();
+                Ok(keys.len())
+            })
+            .context("In count_entries: Trying to count keystore database.")?;
+        Ok(count.min(i32::MAX as usize) as i32)
+    }
+}
+
+impl Interface for KeystoreListing {}
+
+impl IKeystoreListing for KeystoreListing {
+    fn listEntriesBatched(
+        &self,
+        domain: Domain,
+        nspace: i64,
+        start_past_alias: Option<&str>,
+        count: i32,
+    ) -> BinderResult<Vec<KeyDescriptor>> {
+        let _wp = wd::watch_millis("IKeystoreListing::listEntriesBatched", 500);
+        map_or_log_err(
+            Self::list_entries_batched(
+                domain,
+                nspace,
+                start_past_alias,
+                count,
+                &CallerIdentity::current(),
+            ),
+            Ok,
+        )
+    }
+
+    fn countEntries(&self, domain: Domain, nspace: i64) -> BinderResult<i32> {
+        let _wp = wd::watch_millis("IKeystoreListing::countEntries", 500);
+        map_or_log_err(Self::count_entries(domain, nspace, &CallerIdentity::current()), Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(alias: &str) -> KeyDescriptor {
+        KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 10001,
+            alias: Some(alias.to_string()),
+            blob: None,
+        }
+    }
+
+    #[test]
+    fn test_truncate_to_size_limit() {
+        let mut keys = vec![key("a"), key("bb"), key("ccc")];
+        let limit = estimated_parcel_size(&keys[0]) + estimated_parcel_size(&keys[1]);
+        truncate_to_size_limit(&mut keys, limit);
+        assert_eq!(keys, vec![key("a"), key("bb")]);
+        // A key that exceeds the limit on its own is still returned.
+        truncate_to_size_limit(&mut keys, 0);
+        assert_eq!(keys, vec![key("a")]);
+        let mut keys: Vec<KeyDescriptor> = vec![];
+        truncate_to_size_limit(&mut keys, 0);
+        assert!(keys.is_empty());
+    }
+}
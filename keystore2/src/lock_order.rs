@@ -0,0 +1,150 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements `OrderedMutex`, a mutex that belongs to a fixed rank in the global
+//! lock order of Keystore. A thread that holds a ranked lock may only acquire locks of a
+//! strictly higher rank. Builds with debug assertions check this order on every acquisition and
+//! panic on a violation, which turns a potential deadlock into a deterministic failure. Release
+//! builds do not track the held locks.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard};
+
+#[cfg(debug_assertions)]
+use std::cell::RefCell;
+
+/// The ranks of the global locks in the order in which they may be acquired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockRank {
+    /// The garbage collector handle, `globals::GC`.
+    Gc = 1,
+    /// The cache of KeyMint connections, `globals::KEY_MINT_DEVICES`.
+    KeyMintDevices = 2,
+    /// The cache of the secure clock connection, `globals::TIME_STAMP_DEVICE`.
+    TimeStampDevice = 3,
+    /// The cache of remote provisioning connections,
+    /// `globals::REMOTELY_PROVISIONED_COMPONENT_DEVICES`.
+    RemotelyProvisionedDevices = 4,
+}
+
+#[cfg(debug_assertions)]
+thread_local! {
+    /// The ranks of the ordered locks that are held by the current thread.
+    static HELD_RANKS: RefCell<Vec<LockRank>> = RefCell::new(Vec::new());
+}
+
+/// A mutex with a rank in the global lock order.
+pub struct OrderedMutex<T> {
+    rank: LockRank,
+    inner: Mutex<T>,
+}
+
+/// The guard of an `OrderedMutex`. The rank is released when the guard is dropped.
+pub struct OrderedMutexGuard<'a, T> {
+    rank: LockRank,
+    guard: MutexGuard<'a, T>,
+}
+
+impl<T> OrderedMutex<T> {
+    /// Acquires the mutex. Panics if the lock is poisoned or, in builds with debug assertions,
+    /// if the current thread holds a lock of the same or a higher rank.
+    pub fn lock(&self) -> OrderedMutexGuard<T> {
+        acquire_rank(self.rank);
+        OrderedMutexGuard { rank: self.rank, guard: self.inner.lock().unwrap() }
+    }
+}
+
+impl<T: Default> OrderedMutex<T> {
+    /// Creates a new mutex of the given rank holding the default value.
+    pub fn with_rank(rank: LockRank) -> Self {
+        Self { rank, inner: Mutex::new(Default::default()) }
+    }
+}
+
+impl<T> Deref for OrderedMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for OrderedMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for OrderedMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        release_rank(self.rank);
+    }
+}
+
+#[cfg(debug_assertions)]
+fn acquire_rank(rank: LockRank) {
+    HELD_RANKS.with(|held| {
+        let mut held = held.borrow_mut();
+        if let Some(highest) = held.iter().max() {
+            if *highest >= rank {
+                panic!("Lock order violation: Acquiring {:?} while holding {:?}.", rank, *held);
+            }
+        }
+        held.push(rank);
+    })
+}
+
+#[cfg(not(debug_assertions))]
+fn acquire_rank(_rank: LockRank) {}
+
+#[cfg(debug_assertions)]
+fn release_rank(rank: LockRank) {
+    HELD_RANKS.with(|held| {
+        let mut held = held.borrow_mut();
+        if let Some(pos) = held.iter().rposition(|r| *r == rank) {
+            held.remove(pos);
+        }
+    })
+}
+
+#[cfg(not(debug_assertions))]
+fn release_rank(_rank: LockRank) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks_in_order() {
+        let low: OrderedMutex<u8> = OrderedMutex::with_rank(LockRank::KeyMintDevices);
+        let high: OrderedMutex<u8> = OrderedMutex::with_rank(LockRank::TimeStampDevice);
+        {
+            let _low = low.lock();
+            *high.lock() += 1;
+        }
+        // Once released, a lock may be taken again in any order.
+        let guard = high.lock();
+        assert_eq!(*guard, 1);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "Lock order violation")]
+    fn detects_order_violation() {
+        let low: OrderedMutex<u8> = OrderedMutex::with_rank(LockRank::KeyMintDevices);
+        let high: OrderedMutex<u8> = OrderedMutex::with_rank(LockRank::TimeStampDevice);
+        let _high = high.lock();
+        let _low = low.lock();
+    }
+}
@@ -14,28 +14,92 @@
 
 //! This module implements IKeystoreMaintenance AIDL interface.
 
-use crate::database::{KeyEntryLoadBits, KeyType, MonotonicRawTime};
+use crate::async_keygen::get_security_level;
+use crate::audit_log::{
+    self, log_key_escrow_record_retrieved, log_key_material_exported, log_keystore_reset,
+    log_namespace_cleared, log_namespace_frozen, log_user_keys_reset,
+};
+use crate::boot_state;
+use crate::buffer_pool;
+use crate::cache_accounting::{self, MemoryPressure};
+use crate::caller_identity::{self, CallerIdentity};
+use crate::capability_matrix;
+use crate::database::io_stats;
+use crate::database::{
+    DateTime, IntegrityCheck, KeyChangeKind as DbKeyChangeKind, KeyEntryLoadBits,
+    KeyFingerprintKind, KeyOrigin as DbKeyOrigin, KeyType, MonotonicRawTime,
+};
+use crate::denial_limiter;
 use crate::error::map_km_error;
 use crate::error::map_or_log_err;
 use crate::error::Error;
-use crate::globals::get_keymint_device;
-use crate::globals::{DB, LEGACY_MIGRATOR, SUPER_KEY};
-use crate::permission::{KeyPerm, KeystorePerm};
-use crate::super_key::UserState;
+use crate::expiry_sweeper;
+use crate::external_keys;
+use crate::globals::{call_keymint_with_retry, Idempotent};
+use crate::globals::{
+    ASYNC_TASK, DB, LEGACY_BLOB_LOADER, LEGACY_MIGRATOR, SUPER_KEY, TASK_EXECUTOR,
+};
+use crate::grant_reconciliation;
+use crate::labeled_operations;
+use crate::metadata_snapshot;
+use crate::namespace_freeze;
+use crate::permission::{self, KeyPerm, KeystorePerm};
+use crate::quota;
+use crate::shutdown;
+use crate::software_fallback;
+use crate::storage_key;
+use crate::super_key::{UserState, USER_SUPER_KEY};
+use crate::task_executor::Priority;
+use crate::tenants;
 use crate::utils::{check_key_permission, check_keystore_permission, watchdog as wd};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::IKeyMintDevice::IKeyMintDevice;
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
 use android_security_maintenance::aidl::android::security::maintenance::{
+    IBootStateListener::IBootStateListener,
+    IKeyExpiryListener::IKeyExpiryListener,
     IKeystoreMaintenance::{BnKeystoreMaintenance, IKeystoreMaintenance},
+    ILskfRemovalListener::ILskfRemovalListener,
+    IShutdownListener::IShutdownListener,
+    KeyChange::KeyChange,
+    KeyChangeKind::KeyChangeKind,
+    KeyChanges::KeyChanges,
+    KeyFingerprintType::KeyFingerprintType,
+    KeyOrigin::KeyOrigin,
+    KeyProvenance::KeyProvenance,
+    RebuildReport::RebuildReport,
     UserState::UserState as AidlUserState,
 };
 use android_security_maintenance::binder::{
-    BinderFeatures, Interface, Result as BinderResult, Strong, ThreadState,
+    BinderFeatures, Interface, Result as BinderResult, StatusCode, Strong,
 };
 use android_system_keystore2::aidl::android::system::keystore2::KeyDescriptor::KeyDescriptor;
 use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
 use anyhow::{Context, Result};
 use keystore2_crypto::Password;
+use keystore2_system_property::PropertyWatcher;
+use lazy_static::lazy_static;
+use std::collections::HashSet;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// System property holding the number of seconds by which the deletion of LSKF bound keys is
+/// deferred after a user removed their LSKF. If absent or zero, the keys are deleted immediately.
+const LSKF_REMOVAL_GRACE_PERIOD_PROPERTY: &str = "keystore.lskf_removal_grace_period_seconds";
+
+/// Persistent system property that enables the export of software key material for debugging
+/// when set to "1". It has no effect unless the build is debuggable.
+const DEBUG_KEY_EXPORT_PROPERTY: &str = "persist.keystore.debug_key_export";
+
+lazy_static! {
+    /// Listener that is informed about deferred deletions of LSKF bound keys.
+    static ref LSKF_REMOVAL_LISTENER: Mutex<Option<Strong<dyn ILskfRemovalListener>>> =
+        Default::default();
+    /// Users whose LSKF bound keys were deleted on startup, before a listener was registered.
+    static ref LSKF_REMOVALS_ON_STARTUP: Mutex<Vec<u32>> = Default::default();
+}
 
 /// Reexport Domain for the benefit of DeleteListener
 pub use android_system_keystore2::aidl::android::system::keystore2::Domain::Domain;
@@ -59,18 +123,178 @@ impl Maintenance {
     pub fn new_native_binder(
         delete_listener: Box<dyn DeleteListener + Send + Sync + 'static>,
     ) -> Result<Strong<dyn IKeystoreMaintenance>> {
+        // The device owner uid is set through this service, so it is restored along with it.
+        audit_log::load_device_owner_uid_on_startup();
         Ok(BnKeystoreMaintenance::new_binder(
             Self { delete_listener },
             BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
         ))
     }
 
+    fn lskf_removal_grace_period() -> Option<Duration> {
+        let mut prop_reader = PropertyWatcher::new(LSKF_REMOVAL_GRACE_PERIOD_PROPERTY).ok()?;
+        match prop_reader.read(|_n, v| v.parse::<u64>().map_err(std::convert::Into::into)) {
+            Ok(0) | Err(_) => None,
+            Ok(seconds) => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    fn notify_lskf_removal_listener<F>(f: F)
+    where
+        F: FnOnce(&Strong<dyn ILskfRemovalListener>) -> BinderResult<()>,
+    {
+        if let Some(listener) = LSKF_REMOVAL_LISTENER.lock().unwrap().as_ref() {
+            if let Err(e) = f(listener) {
+                log::warn!("In notify_lskf_removal_listener: Failed to notify listener: {:?}", e);
+            }
+        }
+    }
+
+    /// Checks back after the grace period and deletes the LSKF bound keys of the user unless
+    /// a new LSKF was set in the meantime.
+    fn schedule_lskf_removal(user_id: u32, grace_period: Duration) {
+        let spawned = TASK_EXECUTOR.spawn_delayed(
+            "keystore2_lskf_removal",
+            Priority::Normal,
+            grace_period,
+            move || {
+                ASYNC_TASK.queue_hi(move |_| {
+                    match DB.with(|db| {
+                        SUPER_KEY.complete_lskf_removal_if_due(
+                            &mut db.borrow_mut(),
+                            &LEGACY_MIGRATOR,
+                            user_id,
+                        )
+                    }) {
+                        Ok(true) => Self::notify_lskf_removal_listener(|l| {
+                            l.onLskfBoundKeysDeleted(user_id as i32)
+                        }),
+                        Ok(false) => {}
+                        Err(e) => log::error!(
+                            "In schedule_lskf_removal: Failed to complete LSKF removal: {:?}",
+                            e
+                        ),
+                    }
+                });
+            },
+        );
+        if let Err(e) = spawned {
+            log::error!("In schedule_lskf_removal: Failed to schedule LSKF removal: {:?}", e);
+        }
+    }
+
+    /// Deferred LSKF removals are only timed in memory. This must be called on startup to
+    /// complete the removals whose deadline passed or whose super key was lost with the previous
+    /// instance, and to re-arm the timers of all others. Errors are logged.
+    pub fn check_lskf_removals_on_startup() {
+        let user_ids = match DB.with(|db| db.borrow_mut().list_users_with_lskf_removal_deadline()) {
+            Ok(user_ids) => user_ids,
+            Err(e) => {
+                log::error!("In check_lskf_removals_on_startup: Failed to list users: {:?}", e);
+                return;
+            }
+        };
+        for user_id in user_ids {
+            // Returns whether the removal was completed and, if not, the pending deadline.
+            let result: Result<(bool, Option<DateTime>)> = DB.with(|db| {
+                let mut db = db.borrow_mut();
+                if SUPER_KEY.complete_lskf_removal_if_due(&mut db, &LEGACY_MIGRATOR, user_id)? {
+                    return Ok((true, None));
+                }
+                let deadline = db
+                    .load_super_key(&USER_SUPER_KEY, user_id)?
+                    .and_then(|(_, entry)| entry.metadata().lskf_removal_deadline().cloned());
+                Ok((false, deadline))
+            });
+            match result {
+                Ok((true, _)) => {
+                    log::info!("Completed deferred LSKF removal for user {} on startup.", user_id);
+                    LSKF_REMOVALS_ON_STARTUP.lock().unwrap().push(user_id);
+                }
+                Ok((false, None)) => {}
+                Ok((false, Some(deadline))) => {
+                    let remaining = DateTime::now().map_or(0, |now| {
+                        (deadline.to_millis_epoch() - now.to_millis_epoch()).max(0)
+                    });
+                    Self::schedule_lskf_removal(user_id, Duration::from_millis(remaining as u64));
+                }
+                Err(e) => log::error!(
+                    "In check_lskf_removals_on_startup: Failed for user {}: {:?}",
+                    user_id,
+                    e
+                ),
+            }
+        }
+    }
+
     fn on_user_password_changed(user_id: i32, password: Option<Password>) -> Result<()> {
         //Check permission. Function should return if this failed. Therefore having '?' at the end
         //is very important.
         check_keystore_permission(KeystorePerm::change_password())
             .context("In on_user_password_changed.")?;
 
+        // A deferred LSKF removal that is past its deadline or that was interrupted by a reboot
+        // must be completed before the new password can take effect.
+        if DB
+            .with(|db| {
+                SUPER_KEY.complete_lskf_removal_if_due(
+                    &mut db.borrow_mut(),
+                    &LEGACY_MIGRATOR,
+                    user_id as u32,
+                )
+            })
+            .context("In on_user_password_changed.")?
+        {
+            Self::notify_lskf_removal_listener(|l| l.onLskfBoundKeysDeleted(user_id));
+        }
+
+        match password.as_ref() {
+            Some(pw) => {
+                if DB
+                    .with(|db| {
+                        SUPER_KEY.cancel_lskf_removal(&mut db.borrow_mut(), user_id as u32, pw)
+                    })
+                    .context("In on_user_password_changed.")?
+                {
+                    log::info!("Cancelled deferred LSKF removal for user {}.", user_id);
+                    Self::notify_lskf_removal_listener(|l| {
+                        l.onLskfBoundKeysDeletionCancelled(user_id)
+                    });
+                }
+            }
+            None => {
+                if let Some(grace_period) = Self::lskf_removal_grace_period() {
+                    let deadline = DateTime::from_millis_epoch(
+                        DateTime::now()
+                            .context("In on_user_password_changed: Failed to get current time.")?
+                            .to_millis_epoch()
+                            + grace_period.as_millis() as i64,
+                    );
+                    if DB
+                        .with(|db| {
+                            SUPER_KEY.defer_lskf_removal(
+                                &mut db.borrow_mut(),
+                                user_id as u32,
+                                deadline,
+                            )
+                        })
+                        .context("In on_user_password_changed.")?
+                    {
+                        log::info!(
+                            "Deferred LSKF removal for user {} by {:?}.",
+                            user_id,
+                            grace_period
+                        );
+                        Self::schedule_lskf_removal(user_id as u32, grace_period);
+                        Self::notify_lskf_removal_listener(|l| {
+                            l.onLskfBoundKeysDeletionScheduled(user_id, deadline.to_millis_epoch())
+                        });
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
         if let Some(pw) = password.as_ref() {
             DB.with(|db| {
                 SUPER_KEY.unlock_screen_lock_bound_key(&mut db.borrow_mut(), user_id as u32, pw)
@@ -102,6 +326,32 @@ impl Maintenance {
         }
     }
 
+    fn set_lskf_removal_listener(
+        listener: Option<&Strong<dyn ILskfRemovalListener>>,
+    ) -> Result<()> {
+        // Permission check. Must return on error. Do not touch the '?'.
+        check_keystore_permission(KeystorePerm::change_password())
+            .context("In set_lskf_removal_listener.")?;
+        *LSKF_REMOVAL_LISTENER.lock().unwrap() = listener.cloned();
+        if listener.is_some() {
+            for user_id in LSKF_REMOVALS_ON_STARTUP.lock().unwrap().drain(..) {
+                Self::notify_lskf_removal_listener(|l| l.onLskfBoundKeysDeleted(user_id as i32));
+            }
+        }
+        Ok(())
+    }
+
+    fn set_key_expiry_listener(
+        uid: i32,
+        listener: Option<&Strong<dyn IKeyExpiryListener>>,
+    ) -> Result<()> {
+        // Permission check. Must return on error. Do not touch the '?'.
+        check_keystore_permission(KeystorePerm::clear_uid())
+            .context("In set_key_expiry_listener.")?;
+        expiry_sweeper::set_listener(uid as u32, listener);
+        Ok(())
+    }
+
     fn add_or_remove_user(&self, user_id: i32) -> Result<()> {
         // Check permission. Function should return if this failed. Therefore having '?' at the end
         // is very important.
@@ -116,26 +366,75 @@ impl Maintenance {
             )
         })
         .context("In add_or_remove_user: Trying to delete keys from db.")?;
+        storage_key::clear_cache();
         self.delete_listener
             .delete_user(user_id as u32)
             .context("In add_or_remove_user: While invoking the delete listener.")
     }
 
+    fn remove_user_dry_run(user_id: i32) -> Result<Vec<KeyDescriptor>> {
+        // Same permission as for `add_or_remove_user`. Must return on error.
+        check_keystore_permission(KeystorePerm::change_user())
+            .context("In remove_user_dry_run.")?;
+        let mut result = LEGACY_MIGRATOR
+            .list_user(user_id as u32)
+            .context("In remove_user_dry_run: Trying to list legacy keys.")?;
+        result.append(
+            &mut DB
+                .with(|db| db.borrow_mut().list_keys_for_user(user_id as u32))
+                .context("In remove_user_dry_run: Trying to list keys.")?,
+        );
+        result.sort_unstable();
+        result.dedup();
+        Ok(result)
+    }
+
+    fn clear_namespace_dry_run(domain: Domain, nspace: i64) -> Result<Vec<KeyDescriptor>> {
+        // Same permission as for `clear_namespace`. Must return on error.
+        check_keystore_permission(KeystorePerm::clear_uid())
+            .context("In clear_namespace_dry_run.")?;
+        if !(domain == Domain::APP || domain == Domain::SELINUX) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In clear_namespace_dry_run.");
+        }
+        let mut result = LEGACY_MIGRATOR
+            .list_uid(domain, nspace)
+            .context("In clear_namespace_dry_run: Trying to list legacy keys.")?;
+        result.append(
+            &mut DB
+                .with(|db| db.borrow_mut().list(domain, nspace, KeyType::Client))
+                .context("In clear_namespace_dry_run: Trying to list keys.")?,
+        );
+        result.sort_unstable();
+        result.dedup();
+        Ok(result)
+    }
+
     fn clear_namespace(&self, domain: Domain, nspace: i64) -> Result<()> {
         // Permission check. Must return on error. Do not touch the '?'.
         check_keystore_permission(KeystorePerm::clear_uid()).context("In clear_namespace.")?;
-
+        // The keys of a frozen namespace are quarantined by the database rather than deleted.
+        // Legacy keys cannot be quarantined, they are deleted, so that they cannot be migrated
+        // into the namespace after it is unfrozen.
         LEGACY_MIGRATOR
             .bulk_delete_uid(domain, nspace)
             .context("In clear_namespace: Trying to delete legacy keys.")?;
-        DB.with(|db| db.borrow_mut().unbind_keys_for_namespace(domain, nspace))
+        let deleted = DB
+            .with(|db| db.borrow_mut().unbind_keys_for_namespace(domain, nspace))
             .context("In clear_namespace: Trying to delete keys from db.")?;
+        log::info!("Deleted {} keys of namespace {} of domain {:?}.", deleted, nspace, domain);
+        // The uid of an uninstalled app may be reused by another package.
+        if domain == Domain::APP {
+            caller_identity::forget_package_name(nspace as u32);
+        }
         self.delete_listener
             .delete_namespace(domain, nspace)
             .context("In clear_namespace: While invoking the delete listener.")
     }
 
-    fn get_state(user_id: i32) -> Result<AidlUserState> {
+    /// Returns the super key state of the given user. This is also used by the
+    /// IKeystoreHealth service.
+    pub fn get_state(user_id: i32) -> Result<AidlUserState> {
         // Check permission. Function should return if this failed. Therefore having '?' at the end
         // is very important.
         check_keystore_permission(KeystorePerm::get_state()).context("In get_state.")?;
@@ -152,24 +451,29 @@ impl Maintenance {
         }
     }
 
-    fn call_with_watchdog<F>(sec_level: SecurityLevel, name: &'static str, op: &F) -> Result<()>
+    fn call_with_watchdog<F>(
+        sec_level: SecurityLevel,
+        name: &'static str,
+        idempotent: Idempotent,
+        op: &F,
+    ) -> Result<()>
     where
         F: Fn(Strong<dyn IKeyMintDevice>) -> binder::public_api::Result<()>,
     {
-        let (dev, _, _) = get_keymint_device(&sec_level)
-            .context("In call_with_watchdog: getting keymint device")?;
-        let km_dev: Strong<dyn IKeyMintDevice> = dev
-            .get_interface()
-            .context("In call_with_watchdog: getting keymint device interface")?;
-
-        let _wp = wd::watch_millis_with("In call_with_watchdog", 500, move || {
-            format!("Seclevel: {:?} Op: {}", sec_level, name)
-        });
-        map_km_error(op(km_dev)).with_context(|| format!("In keymint device: calling {}", name))?;
-        Ok(())
+        call_keymint_with_retry(&sec_level, idempotent, |km_dev| {
+            let _wp = wd::watch_millis_with("In call_with_watchdog", 500, move || {
+                format!("Seclevel: {:?} Op: {}", sec_level, name)
+            });
+            map_km_error(op(km_dev)).with_context(|| format!("In keymint device: calling {}", name))
+        })
+        .context("In call_with_watchdog.")
     }
 
-    fn call_on_all_security_levels<F>(name: &'static str, op: F) -> Result<()>
+    fn call_on_all_security_levels<F>(
+        name: &'static str,
+        idempotent: Idempotent,
+        op: F,
+    ) -> Result<()>
     where
         F: Fn(Strong<dyn IKeyMintDevice>) -> binder::public_api::Result<()>,
     {
@@ -178,7 +482,7 @@ impl Maintenance {
             (SecurityLevel::STRONGBOX, "STRONGBOX"),
         ];
         sec_levels.iter().fold(Ok(()), move |result, (sec_level, sec_level_string)| {
-            let curr_result = Maintenance::call_with_watchdog(*sec_level, name, &op);
+            let curr_result = Maintenance::call_with_watchdog(*sec_level, name, idempotent, &op);
             match curr_result {
                 Ok(()) => log::info!(
                     "Call to {} succeeded for security level {}.",
@@ -204,7 +508,11 @@ impl Maintenance {
         if let Err(e) = DB.with(|db| SUPER_KEY.set_up_boot_level_cache(&mut db.borrow_mut())) {
             log::error!("SUPER_KEY.set_up_boot_level_cache failed:\n{:?}\n:(", e);
         }
-        Maintenance::call_on_all_security_levels("earlyBootEnded", |dev| dev.earlyBootEnded())
+        Maintenance::call_on_all_security_levels(
+            "earlyBootEnded",
+            Idempotent("Ending the early boot stage again has no effect."),
+            |dev| dev.earlyBootEnded(),
+        )
     }
 
     fn on_device_off_body() -> Result<()> {
@@ -217,7 +525,7 @@ impl Maintenance {
     }
 
     fn migrate_key_namespace(source: &KeyDescriptor, destination: &KeyDescriptor) -> Result<()> {
-        let caller_uid = ThreadState::get_calling_uid();
+        let caller_uid = CallerIdentity::current().uid();
 
         DB.with(|db| {
             let key_id_guard = match source.domain {
@@ -253,17 +561,466 @@ impl Maintenance {
         })
     }
 
+    fn read_property(name: &str) -> Option<String> {
+        PropertyWatcher::new(name).ok().and_then(|mut w| w.read(|_n, v| Ok(v.to_string())).ok())
+    }
+
+    fn debug_key_export_enabled() -> bool {
+        Self::read_property("ro.debuggable").as_deref() == Some("1")
+            && Self::read_property(DEBUG_KEY_EXPORT_PROPERTY).as_deref() == Some("1")
+    }
+
+    fn export_debug_key_material(key: &KeyDescriptor) -> Result<Vec<u8>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::debug_key_export())
+            .context("In export_debug_key_material: Checking permission.")?;
+        if !Self::debug_key_export_enabled() {
+            return Err(Error::Rc(ResponseCode::PERMISSION_DENIED))
+                .context("In export_debug_key_material: Debug key export is not enabled.");
+        }
+        let caller = CallerIdentity::current();
+        let caller_uid = caller.uid();
+        log::warn!(
+            "In export_debug_key_material: uid {} requests the key material of {:?}.",
+            caller_uid,
+            key
+        );
+
+        let result = DB
+            .with(|db| {
+                LEGACY_MIGRATOR.with_try_migrate(&key, caller_uid, || {
+                    db.borrow_mut().load_key_entry(
+                        &key,
+                        KeyType::Client,
+                        KeyEntryLoadBits::KM,
+                        caller_uid,
+                        |k, av| check_key_permission(KeyPerm::use_(), k, &av),
+                    )
+                })
+            })
+            .context("In export_debug_key_material: Failed to load key.")
+            .and_then(|(_, mut key_entry)| {
+                // Key material of keys backed by secure hardware never leaves the hardware.
+                if key_entry.key_parameters().iter().any(|p| {
+                    matches!(
+                        *p.security_level(),
+                        SecurityLevel::TRUSTED_ENVIRONMENT | SecurityLevel::STRONGBOX
+                    )
+                }) {
+                    return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(
+                        "In export_debug_key_material: Key is backed by secure hardware.",
+                    );
+                }
+                let (blob, blob_metadata) =
+                    key_entry.take_key_blob_info().ok_or_else(Error::sys).context(concat!(
+                        "In export_debug_key_material: Successfully loaded key entry, ",
+                        "but KM blob was missing."
+                    ))?;
+                let key_blob = SUPER_KEY
+                    .unwrap_key_if_required(&blob_metadata, &blob)
+                    .context("In export_debug_key_material: Failed to handle super encryption.")?;
+                Ok(key_blob.to_vec())
+            });
+
+        log_key_material_exported(key, &caller, result.is_ok());
+        if result.is_ok() {
+            log::warn!("In export_debug_key_material: Exported key material of {:?}.", key);
+        }
+        result
+    }
+
+    fn find_keys_by_fingerprint(
+        fingerprint_type: KeyFingerprintType,
+        digest: &[u8],
+    ) -> Result<Vec<KeyDescriptor>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::find_by_fingerprint())
+            .context("In find_keys_by_fingerprint: Checking permission.")?;
+        let kind = match fingerprint_type {
+            KeyFingerprintType::CERTIFICATE => KeyFingerprintKind::Certificate,
+            KeyFingerprintType::SUBJECT_PUBLIC_KEY_INFO => KeyFingerprintKind::SubjectPublicKeyInfo,
+            _ => {
+                return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context("In find_keys_by_fingerprint: Unknown fingerprint type.")
+            }
+        };
+        if digest.len() != keystore2_crypto::SHA256_DIGEST_LENGTH {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In find_keys_by_fingerprint: Digest must be a SHA-256 digest.");
+        }
+        let keys = DB
+            .with(|db| db.borrow_mut().find_keys_by_fingerprint(kind, digest))
+            .context("In find_keys_by_fingerprint.")?;
+        // Only the keys the caller may inspect are revealed.
+        let mut visible = Vec::new();
+        for key in keys {
+            match check_key_permission(KeyPerm::get_info(), &key, &None) {
+                Ok(()) => visible.push(key),
+                Err(e) if permission::is_permission_denied(&e) => {}
+                Err(e) => return Err(e).context("In find_keys_by_fingerprint."),
+            }
+        }
+        Ok(visible)
+    }
+
+    fn get_key_provenance(key: &KeyDescriptor) -> Result<KeyProvenance> {
+        let caller_uid = CallerIdentity::current().uid();
+        let (_, key_entry) = DB
+            .with(|db| {
+                LEGACY_MIGRATOR.with_try_migrate(key, caller_uid, || {
+                    db.borrow_mut().load_key_entry(
+                        key,
+                        KeyType::Client,
+                        KeyEntryLoadBits::NONE,
+                        caller_uid,
+                        |k, av| check_key_permission(KeyPerm::get_info(), k, &av),
+                    )
+                })
+            })
+            .context("In get_key_provenance: Failed to load key.")?;
+        let metadata = key_entry.metadata();
+        Ok(KeyProvenance {
+            origin: match metadata.origin() {
+                None => KeyOrigin::UNKNOWN,
+                Some(DbKeyOrigin::Generated) => KeyOrigin::GENERATED,
+                Some(DbKeyOrigin::Imported) => KeyOrigin::IMPORTED,
+                Some(DbKeyOrigin::Migrated) => KeyOrigin::MIGRATED,
+            },
+            creatorUid: metadata.creator_uid().map_or(-1, |uid| *uid as i32),
+            kmVersion: metadata.km_version().copied().unwrap_or(-1),
+            superKeyVersion: metadata.super_key_version().copied().unwrap_or(-1),
+            creationDateMs: metadata.creation_date().map_or(-1, |d| d.to_millis_epoch()),
+            lastUseDateMs: metadata.last_used().map_or(-1, |d| d.to_millis_epoch()),
+        })
+    }
+
+    fn get_key_escrow_record(key: &KeyDescriptor) -> Result<Vec<u8>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::escrow_retrieve())
+            .context("In get_key_escrow_record: Checking permission.")?;
+        if !Self::key_escrow_available() {
+            return Err(Error::Rc(ResponseCode::PERMISSION_DENIED))
+                .context("In get_key_escrow_record: Key escrow is not available.");
+        }
+        if !matches!(key.domain, Domain::KEY_ID | Domain::SELINUX) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In get_key_escrow_record: Key must be addressed by key id or SELinux.");
+        }
+        let caller = CallerIdentity::current();
+        let caller_uid = caller.uid();
+
+        let result = DB
+            .with(|db| {
+                // The escrow_retrieve permission was checked above and grants access to the
+                // escrow records of all keys.
+                db.borrow_mut().load_key_entry(
+                    &key,
+                    KeyType::Client,
+                    KeyEntryLoadBits::NONE,
+                    caller_uid,
+                    |_, _| Ok(()),
+                )
+            })
+            .context("In get_key_escrow_record: Failed to load key.")
+            .and_then(|(_, key_entry)| {
+                key_entry
+                    .metadata()
+                    .escrow_record()
+                    .cloned()
+                    .ok_or(Error::Rc(ResponseCode::KEY_NOT_FOUND))
+                    .context("In get_key_escrow_record: Key was not escrowed.")
+            });
+
+        log_key_escrow_record_retrieved(key, &caller, result.is_ok());
+        if result.is_ok() {
+            log::warn!(
+                "In get_key_escrow_record: uid {} retrieved the escrow record of {:?}.",
+                caller_uid,
+                key
+            );
+        }
+        result
+    }
+
+    #[cfg(feature = "key_escrow")]
+    fn key_escrow_available() -> bool {
+        crate::escrow::enabled()
+    }
+
+    #[cfg(not(feature = "key_escrow"))]
+    fn key_escrow_available() -> bool {
+        false
+    }
+
     fn delete_all_keys() -> Result<()> {
         // Security critical permission check. This statement must return on fail.
         check_keystore_permission(KeystorePerm::delete_all_keys())
             .context("In delete_all_keys. Checking permission")?;
         log::info!("In delete_all_keys.");
 
-        Maintenance::call_on_all_security_levels("deleteAllKeys", |dev| dev.deleteAllKeys())
+        Maintenance::call_on_all_security_levels(
+            "deleteAllKeys",
+            Idempotent("Deleting all keys again has no effect."),
+            |dev| dev.deleteAllKeys(),
+        )
+    }
+
+    fn delete_all_keys_dry_run() -> Result<Vec<KeyDescriptor>> {
+        // Same permission as for `delete_all_keys`. Must return on error.
+        check_keystore_permission(KeystorePerm::delete_all_keys())
+            .context("In delete_all_keys_dry_run. Checking permission")?;
+        DB.with(|db| db.borrow_mut().list_keys_with_km_blob())
+            .context("In delete_all_keys_dry_run: Trying to list keys.")
+    }
+
+    /// The maximal number of changes returned by a single call to `getKeyChanges`.
+    const MAX_KEY_CHANGES_PER_CALL: usize = 500;
+
+    fn get_key_changes(domain: Domain, nspace: i64, since: i64) -> Result<KeyChanges> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::list())
+            .context("In get_key_changes: Checking permission.")?;
+        if !(domain == Domain::APP || domain == Domain::SELINUX) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In get_key_changes: Domain must be APP or SELINUX.");
+        }
+        let changes = DB
+            .with(|db| {
+                db.borrow_mut().list_key_changes(
+                    domain,
+                    nspace,
+                    since,
+                    Self::MAX_KEY_CHANGES_PER_CALL,
+                )
+            })
+            .context("In get_key_changes.")?;
+        Ok(KeyChanges {
+            changes: changes
+                .changes
+                .into_iter()
+                .map(|change| KeyChange {
+                    sequence: change.sequence,
+                    alias: change.alias,
+                    kind: match change.kind {
+                        DbKeyChangeKind::Created => KeyChangeKind::CREATED,
+                        DbKeyChangeKind::Updated => KeyChangeKind::UPDATED,
+                        DbKeyChangeKind::Deleted => KeyChangeKind::DELETED,
+                    },
+                })
+                .collect(),
+            nextSequence: changes.next_sequence,
+            truncated: changes.truncated,
+        })
+    }
+
+    fn on_trim_memory(level: i32) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::trim_memory())
+            .context("In on_trim_memory: Checking permission.")?;
+        cache_accounting::trim(MemoryPressure::from_trim_level(level));
+        Ok(())
+    }
+
+    fn prepare_for_shutdown() -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::shutdown())
+            .context("In prepare_for_shutdown: Checking permission.")?;
+        shutdown::prepare_for_shutdown();
+        Ok(())
+    }
+
+    fn add_shutdown_listener(listener: &Strong<dyn IShutdownListener>) -> Result<()> {
+        // Permission check. Must return on error. Do not touch the '?'.
+        check_keystore_permission(KeystorePerm::shutdown()).context("In add_shutdown_listener.")?;
+        shutdown::add_listener(listener);
+        Ok(())
+    }
+
+    fn remove_shutdown_listener(listener: &Strong<dyn IShutdownListener>) -> Result<()> {
+        // Permission check. Must return on error. Do not touch the '?'.
+        check_keystore_permission(KeystorePerm::shutdown())
+            .context("In remove_shutdown_listener.")?;
+        shutdown::remove_listener(listener);
+        Ok(())
+    }
+
+    fn add_boot_state_listener(listener: &Strong<dyn IBootStateListener>) -> Result<()> {
+        // Permission check. Must return on error. Do not touch the '?'.
+        check_keystore_permission(KeystorePerm::boot_state())
+            .context("In add_boot_state_listener.")?;
+        boot_state::add_listener(listener);
+        Ok(())
+    }
+
+    fn remove_boot_state_listener(listener: &Strong<dyn IBootStateListener>) -> Result<()> {
+        // Permission check. Must return on error. Do not touch the '?'.
+        check_keystore_permission(KeystorePerm::boot_state())
+            .context("In remove_boot_state_listener.")?;
+        boot_state::remove_listener(listener);
+        Ok(())
+    }
+
+    fn rebuild_derived_state() -> Result<RebuildReport> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::rebuild_derived_state())
+            .context("In rebuild_derived_state: Checking permission.")?;
+        let report = DB
+            .with(|db| {
+                let mut db = db.borrow_mut();
+                let mut integrity = db.check_integrity(IntegrityCheck::Full)?;
+                if !integrity.problems.is_empty() {
+                    // Broken indices are derived state as well. Only refuse if rebuilding them
+                    // does not repair the database.
+                    log::warn!("Rebuilding indices of corrupt database: {:?}", integrity.problems);
+                    db.rebuild_indices()?;
+                    integrity = db.check_integrity(IntegrityCheck::Full)?;
+                }
+                if !integrity.problems.is_empty() {
+                    return Err(Error::sys())
+                        .context(format!("The database is corrupt: {:?}", integrity.problems));
+                }
+                db.rebuild_derived_state()
+            })
+            .context("In rebuild_derived_state.")?;
+        log::info!("Rebuilt derived state: {:?}", report);
+        Ok(RebuildReport {
+            fingerprintedKeys: report.fingerprinted_keys as i32,
+            correctedRefcounts: report.corrected_refcounts as i32,
+            releasedCertificates: report.released_certificates as i32,
+        })
+    }
+
+    fn reset_user_super_keys(user_id: i32) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::reset())
+            .context("In reset_user_super_keys: Checking permission.")?;
+        DB.with(|db| {
+            UserState::reset_user(
+                &mut db.borrow_mut(),
+                &SUPER_KEY,
+                &LEGACY_MIGRATOR,
+                user_id as u32,
+                true,
+            )
+        })
+        .context("In reset_user_super_keys: Trying to delete super encrypted keys.")?;
+        log::info!("Reset the super keys of user {}.", user_id);
+        Self::notify_lskf_removal_listener(|l| l.onLskfBoundKeysDeleted(user_id));
+        Ok(())
+    }
+
+    fn set_device_owner_uid(uid: i32) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::set_device_owner())
+            .context("In set_device_owner_uid: Checking permission.")?;
+        let uid = if uid < 0 { None } else { Some(uid as u32) };
+        audit_log::set_device_owner_uid(uid).context("In set_device_owner_uid.")?;
+        log::info!("Device owner uid set to {:?}.", uid);
+        Ok(())
+    }
+
+    fn set_namespace_frozen(domain: Domain, nspace: i64, frozen: bool) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::freeze_namespace())
+            .context("In set_namespace_frozen: Checking permission.")?;
+        if !(domain == Domain::APP || domain == Domain::SELINUX) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In set_namespace_frozen: Unsupported domain.");
+        }
+        let changed = DB
+            .with(|db| namespace_freeze::set_frozen(&mut db.borrow_mut(), domain, nspace, frozen))
+            .context("In set_namespace_frozen.")?;
+        if !changed {
+            return Ok(());
+        }
+        if frozen {
+            // Operations that began before the namespace was frozen must not continue. They are
+            // matched by key id, so that the operations of grantees are aborted as well.
+            let key_ids: HashSet<i64> = DB
+                .with(|db| db.borrow_mut().list_key_ids_of_namespace(domain, nspace))
+                .context("In set_namespace_frozen: Trying to list keys.")?
+                .into_iter()
+                .collect();
+            let aborted: usize = [
+                SecurityLevel::SOFTWARE,
+                SecurityLevel::TRUSTED_ENVIRONMENT,
+                SecurityLevel::STRONGBOX,
+            ]
+            .iter()
+            .filter_map(|sec_level| get_security_level(*sec_level).ok())
+            .map(|sec_level| sec_level.abort_key_operations(&key_ids))
+            .sum::<usize>()
+                + external_keys::abort_key_operations(&key_ids);
+            log::warn!(
+                "Froze namespace {} of domain {:?}. Aborted {} operations.",
+                nspace,
+                domain,
+                aborted
+            );
+        } else {
+            log::warn!("Unfroze namespace {} of domain {:?}.", nspace, domain);
+        }
+        Ok(())
+    }
+
+    fn dump_state(f: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(f, "Database I/O per subsystem since start:")?;
+        for (subsystem, io) in io_stats::snapshot() {
+            writeln!(
+                f,
+                "  {}: transactions: {} pages read: {} pages written: {}",
+                subsystem, io.transactions, io.pages_read, io.pages_written
+            )?;
+        }
+        writeln!(f, "Recently seen SELinux namespaces without keystore2_key context:")?;
+        for namespace in permission::recent_unknown_namespaces() {
+            writeln!(f, "  {}", namespace)?;
+        }
+        capability_matrix::dump(f)?;
+        software_fallback::dump(f)?;
+        TASK_EXECUTOR.dump(f)?;
+        grant_reconciliation::dump(f)?;
+        labeled_operations::dump(f)?;
+        storage_key::dump(f)?;
+        cache_accounting::dump(f)?;
+        buffer_pool::dump(f)?;
+        denial_limiter::dump(f)?;
+        match LEGACY_BLOB_LOADER.count_quarantined_files() {
+            Ok(count) => writeln!(f, "Quarantined legacy blob files: {}", count)?,
+            Err(e) => writeln!(f, "Quarantined legacy blob files: unknown ({:?})", e)?,
+        }
+        metadata_snapshot::dump(f)?;
+        let snapshot = metadata_snapshot::get();
+        tenants::dump(f, snapshot.as_ref().map(|s| &s.tenant_key_counts))?;
+        quota::dump(f)?;
+        match namespace_freeze::frozen_namespaces() {
+            Some(frozen_namespaces) => {
+                writeln!(f, "Frozen namespaces:")?;
+                for (domain, nspace) in frozen_namespaces {
+                    writeln!(f, "  {:?} {}", domain, nspace)?;
+                }
+            }
+            None => writeln!(f, "Frozen namespaces: unknown, only system namespaces are usable")?,
+        }
+        Ok(())
     }
 }
 
-impl Interface for Maintenance {}
+impl Interface for Maintenance {
+    fn dump(&self, file: &File, _args: &[&CStr]) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::dump", 500);
+        // Security critical permission check. This statement must return on fail.
+        if let Err(e) = check_keystore_permission(KeystorePerm::dump_state()) {
+            log::warn!("In dump: Checking permission: {:?}", e);
+            return Err(StatusCode::PERMISSION_DENIED.into());
+        }
+        let mut file = file;
+        Self::dump_state(&mut file).map_err(|e| {
+            log::error!("In dump: Failed to write state: {:?}", e);
+            StatusCode::UNKNOWN_ERROR.into()
+        })
+    }
+}
 
 impl IKeystoreMaintenance for Maintenance {
     fn onUserPasswordChanged(&self, user_id: i32, password: Option<&[u8]>) -> BinderResult<()> {
@@ -271,19 +1028,44 @@ impl IKeystoreMaintenance for Maintenance {
         map_or_log_err(Self::on_user_password_changed(user_id, password.map(|pw| pw.into())), Ok)
     }
 
+    fn setLskfRemovalListener(
+        &self,
+        listener: Option<&Strong<dyn ILskfRemovalListener>>,
+    ) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::setLskfRemovalListener", 500);
+        map_or_log_err(Self::set_lskf_removal_listener(listener), Ok)
+    }
+
+    fn setKeyExpiryListener(
+        &self,
+        uid: i32,
+        listener: Option<&Strong<dyn IKeyExpiryListener>>,
+    ) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::setKeyExpiryListener", 500);
+        map_or_log_err(Self::set_key_expiry_listener(uid, listener), Ok)
+    }
+
     fn onUserAdded(&self, user_id: i32) -> BinderResult<()> {
-        let _wp = wd::watch_millis("IKeystoreMaintenance::onUserAdded", 500);
-        map_or_log_err(self.add_or_remove_user(user_id), Ok)
+        // Deleting the keys of a user can take a while.
+        let _wp = wd::watch_millis("IKeystoreMaintenance::onUserAdded", 5000);
+        let result = self.add_or_remove_user(user_id);
+        log_user_keys_reset(user_id as u32, &CallerIdentity::current(), result.is_ok());
+        map_or_log_err(result, Ok)
     }
 
     fn onUserRemoved(&self, user_id: i32) -> BinderResult<()> {
-        let _wp = wd::watch_millis("IKeystoreMaintenance::onUserRemoved", 500);
-        map_or_log_err(self.add_or_remove_user(user_id), Ok)
+        // Deleting the keys of a user can take a while.
+        let _wp = wd::watch_millis("IKeystoreMaintenance::onUserRemoved", 5000);
+        let result = self.add_or_remove_user(user_id);
+        log_user_keys_reset(user_id as u32, &CallerIdentity::current(), result.is_ok());
+        map_or_log_err(result, Ok)
     }
 
     fn clearNamespace(&self, domain: Domain, nspace: i64) -> BinderResult<()> {
         let _wp = wd::watch_millis("IKeystoreMaintenance::clearNamespace", 500);
-        map_or_log_err(self.clear_namespace(domain, nspace), Ok)
+        let result = self.clear_namespace(domain, nspace);
+        log_namespace_cleared(domain, nspace, &CallerIdentity::current(), result.is_ok());
+        map_or_log_err(result, Ok)
     }
 
     fn getState(&self, user_id: i32) -> BinderResult<AidlUserState> {
@@ -312,6 +1094,124 @@ impl IKeystoreMaintenance for Maintenance {
 
     fn deleteAllKeys(&self) -> BinderResult<()> {
         let _wp = wd::watch_millis("IKeystoreMaintenance::deleteAllKeys", 500);
-        map_or_log_err(Self::delete_all_keys(), Ok)
+        let result = Self::delete_all_keys();
+        log_keystore_reset(&CallerIdentity::current(), result.is_ok());
+        map_or_log_err(result, Ok)
+    }
+
+    fn exportDebugKeyMaterial(&self, key: &KeyDescriptor) -> BinderResult<Vec<u8>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::exportDebugKeyMaterial", 500);
+        map_or_log_err(Self::export_debug_key_material(key), Ok)
+    }
+
+    fn getKeyEscrowRecord(&self, key: &KeyDescriptor) -> BinderResult<Vec<u8>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getKeyEscrowRecord", 500);
+        map_or_log_err(Self::get_key_escrow_record(key), Ok)
+    }
+
+    fn findKeysByFingerprint(
+        &self,
+        fingerprint_type: KeyFingerprintType,
+        digest: &[u8],
+    ) -> BinderResult<Vec<KeyDescriptor>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::findKeysByFingerprint", 500);
+        map_or_log_err(Self::find_keys_by_fingerprint(fingerprint_type, digest), Ok)
+    }
+
+    fn getKeyProvenance(&self, key: &KeyDescriptor) -> BinderResult<KeyProvenance> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getKeyProvenance", 500);
+        map_or_log_err(Self::get_key_provenance(key), Ok)
+    }
+
+    fn clearNamespaceDryRun(
+        &self,
+        domain: Domain,
+        nspace: i64,
+    ) -> BinderResult<Vec<KeyDescriptor>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::clearNamespaceDryRun", 500);
+        map_or_log_err(Self::clear_namespace_dry_run(domain, nspace), Ok)
+    }
+
+    fn onUserRemovedDryRun(&self, user_id: i32) -> BinderResult<Vec<KeyDescriptor>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::onUserRemovedDryRun", 500);
+        map_or_log_err(Self::remove_user_dry_run(user_id), Ok)
+    }
+
+    fn deleteAllKeysDryRun(&self) -> BinderResult<Vec<KeyDescriptor>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::deleteAllKeysDryRun", 500);
+        map_or_log_err(Self::delete_all_keys_dry_run(), Ok)
+    }
+
+    fn getKeyChanges(
+        &self,
+        domain: Domain,
+        nspace: i64,
+        since_sequence: i64,
+    ) -> BinderResult<KeyChanges> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getKeyChanges", 500);
+        map_or_log_err(Self::get_key_changes(domain, nspace, since_sequence), Ok)
+    }
+
+    fn onTrimMemory(&self, level: i32) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::onTrimMemory", 500);
+        map_or_log_err(Self::on_trim_memory(level), Ok)
+    }
+
+    fn prepareForShutdown(&self) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::prepareForShutdown", 5000);
+        map_or_log_err(Self::prepare_for_shutdown(), Ok)
+    }
+
+    fn addShutdownListener(&self, listener: &Strong<dyn IShutdownListener>) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::addShutdownListener", 500);
+        map_or_log_err(Self::add_shutdown_listener(listener), Ok)
+    }
+
+    fn removeShutdownListener(&self, listener: &Strong<dyn IShutdownListener>) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::removeShutdownListener", 500);
+        map_or_log_err(Self::remove_shutdown_listener(listener), Ok)
+    }
+
+    fn addBootStateListener(&self, listener: &Strong<dyn IBootStateListener>) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::addBootStateListener", 500);
+        map_or_log_err(Self::add_boot_state_listener(listener), Ok)
+    }
+
+    fn removeBootStateListener(
+        &self,
+        listener: &Strong<dyn IBootStateListener>,
+    ) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::removeBootStateListener", 500);
+        map_or_log_err(Self::remove_boot_state_listener(listener), Ok)
+    }
+
+    fn rebuildDerivedState(&self) -> BinderResult<RebuildReport> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::rebuildDerivedState", 10000);
+        map_or_log_err(Self::rebuild_derived_state(), Ok)
+    }
+
+    fn resetUserSuperKeys(&self, user_id: i32) -> BinderResult<()> {
+        // Deleting the keys of a user can take a while.
+        let _wp = wd::watch_millis("IKeystoreMaintenance::resetUserSuperKeys", 5000);
+        map_or_log_err(Self::reset_user_super_keys(user_id), Ok)
+    }
+
+    fn freezeNamespace(&self, domain: Domain, nspace: i64) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::freezeNamespace", 500);
+        let result = Self::set_namespace_frozen(domain, nspace, true);
+        log_namespace_frozen(domain, nspace, true, &CallerIdentity::current(), result.is_ok());
+        map_or_log_err(result, Ok)
+    }
+
+    fn unfreezeNamespace(&self, domain: Domain, nspace: i64) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::unfreezeNamespace", 500);
+        let result = Self::set_namespace_frozen(domain, nspace, false);
+        log_namespace_frozen(domain, nspace, false, &CallerIdentity::current(), result.is_ok());
+        map_or_log_err(result, Ok)
+    }
+
+    fn setDeviceOwnerUid(&self, uid: i32) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::setDeviceOwnerUid", 500);
+        map_or_log_err(Self::set_device_owner_uid(uid), Ok)
     }
 }
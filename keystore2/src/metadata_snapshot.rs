@@ -0,0 +1,141 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module maintains an in-memory snapshot of database metadata, i.e., the storage
+//! statistics and per tenant key counts. Dumps and pulled metrics are served from the
+//! snapshot, so that collecting diagnostics, e.g., during a bugreport, never contends with key
+//! operations for the database. Determining the storage statistics scans the whole database,
+//! so the snapshot is not refreshed periodically. It is refreshed on demand, when a dump or a
+//! metrics pull finds it missing or older than `MAX_AGE`. The refresh runs on the low priority
+//! queue of the async task, and the request is served from the previous snapshot meanwhile.
+
+use crate::database::KeystoreDB;
+use crate::globals::{ASYNC_TASK, DB};
+use crate::tenants;
+use android_security_metrics::aidl::android::security::metrics::{
+    Storage::Storage as MetricsStorage, StorageStats::StorageStats,
+};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Age from which a requested snapshot is refreshed.
+const MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// The storage types whose statistics are captured in the snapshot.
+const STORAGE_TYPES: &[MetricsStorage] = &[
+    MetricsStorage::DATABASE,
+    MetricsStorage::KEY_ENTRY,
+    MetricsStorage::KEY_ENTRY_ID_INDEX,
+    MetricsStorage::KEY_ENTRY_DOMAIN_NAMESPACE_INDEX,
+    MetricsStorage::BLOB_ENTRY,
+    MetricsStorage::BLOB_ENTRY_KEY_ENTRY_ID_INDEX,
+    MetricsStorage::KEY_PARAMETER,
+    MetricsStorage::KEY_PARAMETER_KEY_ENTRY_ID_INDEX,
+    MetricsStorage::KEY_METADATA,
+    MetricsStorage::KEY_METADATA_KEY_ENTRY_ID_INDEX,
+    MetricsStorage::GRANT,
+    MetricsStorage::AUTH_TOKEN,
+    MetricsStorage::BLOB_METADATA,
+    MetricsStorage::BLOB_METADATA_BLOB_ENTRY_ID_INDEX,
+];
+
+/// A point in time copy of the database metadata used for diagnostics.
+#[derive(Debug, Clone)]
+pub struct MetadataSnapshot {
+    /// The time at which the snapshot was taken.
+    pub taken_at: Instant,
+    /// The storage statistics that could be determined.
+    pub storage_stats: Vec<StorageStats>,
+    /// The number of keys held by each tenant, indexed by the tenant name.
+    pub tenant_key_counts: HashMap<String, usize>,
+}
+
+impl MetadataSnapshot {
+    /// Takes a snapshot of the metadata in `db`. Statistics that cannot be determined are
+    /// logged and left out.
+    pub fn take(db: &mut KeystoreDB) -> Self {
+        let storage_stats = STORAGE_TYPES
+            .iter()
+            .filter_map(|storage_type| match db.get_storage_stat(*storage_type) {
+                Ok(stat) => Some(stat),
+                Err(e) => {
+                    log::error!("In MetadataSnapshot::take: Error getting storage stat: {:?}", e);
+                    None
+                }
+            })
+            .collect();
+        Self { taken_at: Instant::now(), storage_stats, tenant_key_counts: tenants::count_keys(db) }
+    }
+}
+
+lazy_static! {
+    static ref SNAPSHOT: RwLock<Option<Arc<MetadataSnapshot>>> = Default::default();
+}
+
+/// True while a refresh is queued or running.
+static REFRESH_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Returns the most recent snapshot, or None if no snapshot was taken yet. If there is none or
+/// it is older than `MAX_AGE`, a refresh is scheduled.
+pub fn get() -> Option<Arc<MetadataSnapshot>> {
+    let snapshot = SNAPSHOT.read().unwrap().clone();
+    if snapshot.as_ref().map_or(true, |snapshot| snapshot.taken_at.elapsed() >= MAX_AGE) {
+        refresh();
+    }
+    snapshot
+}
+
+/// Schedules a refresh of the snapshot on the low priority queue of the async task, unless one
+/// is pending already.
+fn refresh() {
+    if REFRESH_PENDING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    ASYNC_TASK.queue_lo(|_| {
+        let snapshot = DB.with(|db| MetadataSnapshot::take(&mut db.borrow_mut()));
+        *SNAPSHOT.write().unwrap() = Some(Arc::new(snapshot));
+        REFRESH_PENDING.store(false, Ordering::SeqCst);
+    });
+}
+
+/// Writes the age of the current snapshot to `f`.
+pub fn dump(f: &mut dyn Write) -> std::io::Result<()> {
+    match get() {
+        Some(snapshot) => {
+            writeln!(f, "Metadata snapshot age: {}s", snapshot.taken_at.elapsed().as_secs())
+        }
+        None => writeln!(f, "Metadata snapshot: not yet taken, refresh scheduled"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keystore2_test_utils::TempDir;
+
+    #[test]
+    fn take_covers_all_storage_types() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("metadata_snapshot_test")?;
+        let mut db = KeystoreDB::new(temp_dir.path(), None)?;
+        let snapshot = MetadataSnapshot::take(&mut db);
+        let storage_types: Vec<MetricsStorage> =
+            snapshot.storage_stats.iter().map(|stat| stat.storage_type).collect();
+        assert_eq!(storage_types, STORAGE_TYPES);
+        Ok(())
+    }
+}
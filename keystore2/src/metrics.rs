@@ -15,8 +15,11 @@
 //! This module implements the IKeystoreMetrics AIDL interface, which exposes the API method for the
 //! proxy in the system server to pull the aggregated metrics in keystore.
 use crate::error::map_or_log_err;
-use crate::metrics_store::METRICS_STORE;
+use crate::globals::TASK_EXECUTOR;
+use crate::metrics_store::{log_namespace_usage_stats, METRICS_STORE};
+use crate::operation::take_namespace_operation_counts;
 use crate::permission::KeystorePerm;
+use crate::task_executor::Priority;
 use crate::utils::{check_keystore_permission, watchdog as wd};
 use android_security_metrics::aidl::android::security::metrics::{
     AtomID::AtomID,
@@ -25,6 +28,10 @@ use android_security_metrics::aidl::android::security::metrics::{
 };
 use android_security_metrics::binder::{BinderFeatures, Interface, Result as BinderResult, Strong};
 use anyhow::{Context, Result};
+use std::time::Duration;
+
+/// Interval of the namespace usage heatmap. Operation counts are reported in daily buckets.
+const NAMESPACE_USAGE_REPORT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
 
 /// This struct is defined to implement IKeystoreMetrics AIDL interface.
 pub struct Metrics;
@@ -44,6 +51,33 @@ impl Metrics {
         check_keystore_permission(KeystorePerm::pull_metrics()).context("In pull_metrics.")?;
         METRICS_STORE.get_atoms(atom_id)
     }
+
+    fn set_detailed_metrics_enabled(&self, enabled: bool) -> Result<()> {
+        check_keystore_permission(KeystorePerm::pull_metrics())
+            .context("In set_detailed_metrics_enabled.")?;
+        METRICS_STORE.set_detailed_metrics_enabled(enabled);
+        Ok(())
+    }
+}
+
+/// Moves the operation counts per key namespace collected by the operation module into the
+/// metrics store as namespace usage atoms, and resets the counts.
+pub fn report_namespace_usage() {
+    for ((domain, namespace), count) in take_namespace_operation_counts() {
+        log_namespace_usage_stats(domain, namespace, count);
+    }
+}
+
+/// Starts the task that reports the namespace usage heatmap once a day.
+pub fn start_namespace_usage_reporting() {
+    if let Err(e) = TASK_EXECUTOR.spawn_periodic(
+        "keystore2_namespace_usage",
+        Priority::Background,
+        NAMESPACE_USAGE_REPORT_INTERVAL,
+        report_namespace_usage,
+    ) {
+        log::error!("Failed to start namespace usage reporting: {:?}", e);
+    }
 }
 
 impl Interface for Metrics {}
@@ -53,4 +87,9 @@ impl IKeystoreMetrics for Metrics {
         let _wp = wd::watch_millis("IKeystoreMetrics::pullMetrics", 500);
         map_or_log_err(self.pull_metrics(atom_id), Ok)
     }
+
+    fn setDetailedMetricsEnabled(&self, enabled: bool) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMetrics::setDetailedMetricsEnabled", 500);
+        map_or_log_err(self.set_detailed_metrics_enabled(enabled), Ok)
+    }
 }
@@ -17,9 +17,11 @@
 //!    stores them in an in-memory store.
 //! 2. Returns the collected metrics when requested by the statsd proxy.
 
+use crate::buffer_pool::{self, PoolKind};
+use crate::cache_accounting::{self, CacheKind};
 use crate::error::get_error_code;
-use crate::globals::DB;
 use crate::key_parameter::KeyParameterValue as KsKeyParamValue;
+use crate::metadata_snapshot;
 use crate::operation::Outcome;
 use crate::remote_provisioning::get_pool_status;
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
@@ -29,32 +31,49 @@ use android_hardware_security_keymint::aidl::android::hardware::security::keymin
     SecurityLevel::SecurityLevel,
 };
 use android_security_metrics::aidl::android::security::metrics::{
-    Algorithm::Algorithm as MetricsAlgorithm, AtomID::AtomID, CrashStats::CrashStats,
-    EcCurve::EcCurve as MetricsEcCurve,
+    Algorithm::Algorithm as MetricsAlgorithm, AtomID::AtomID,
+    AuthTokenCoalescingStats::AuthTokenCoalescingStats,
+    BlobVerificationStats::BlobVerificationStats, BufferPoolStats::BufferPoolStats,
+    BufferPoolType::BufferPoolType, CacheMemoryStats::CacheMemoryStats, CacheType::CacheType,
+    ClockSkewToleranceStats::ClockSkewToleranceStats, CrashStats::CrashStats,
+    DatabaseIntegrityStats::DatabaseIntegrityStats, DeprecatedParameter::DeprecatedParameter,
+    DeprecatedParameterStats::DeprecatedParameterStats, EcCurve::EcCurve as MetricsEcCurve,
+    HalTransportErrorStats::HalTransportErrorStats,
     HardwareAuthenticatorType::HardwareAuthenticatorType as MetricsHardwareAuthenticatorType,
     KeyCreationWithAuthInfo::KeyCreationWithAuthInfo,
     KeyCreationWithGeneralInfo::KeyCreationWithGeneralInfo,
     KeyCreationWithPurposeAndModesInfo::KeyCreationWithPurposeAndModesInfo,
     KeyOperationWithGeneralInfo::KeyOperationWithGeneralInfo,
     KeyOperationWithPurposeAndModesInfo::KeyOperationWithPurposeAndModesInfo,
-    KeyOrigin::KeyOrigin as MetricsKeyOrigin, Keystore2AtomWithOverflow::Keystore2AtomWithOverflow,
-    KeystoreAtom::KeystoreAtom, KeystoreAtomPayload::KeystoreAtomPayload,
-    Outcome::Outcome as MetricsOutcome, Purpose::Purpose as MetricsPurpose,
-    RkpError::RkpError as MetricsRkpError, RkpErrorStats::RkpErrorStats,
-    RkpPoolStats::RkpPoolStats, SecurityLevel::SecurityLevel as MetricsSecurityLevel,
-    Storage::Storage as MetricsStorage,
+    KeyOrigin::KeyOrigin as MetricsKeyOrigin, KeyUseThrottledStats::KeyUseThrottledStats,
+    Keystore2AtomWithOverflow::Keystore2AtomWithOverflow, KeystoreAtom::KeystoreAtom,
+    KeystoreAtomPayload::KeystoreAtomPayload, LegacyBlobQuarantineStats::LegacyBlobQuarantineStats,
+    NamespaceUsageStats::NamespaceUsageStats, Outcome::Outcome as MetricsOutcome,
+    PerbootRecoveryStats::PerbootRecoveryStats, PermissionCacheStats::PermissionCacheStats,
+    PermissionShadowMismatchStats::PermissionShadowMismatchStats,
+    Purpose::Purpose as MetricsPurpose, RkpError::RkpError as MetricsRkpError,
+    RkpErrorStats::RkpErrorStats, RkpPoolStats::RkpPoolStats,
+    SecurityLevel::SecurityLevel as MetricsSecurityLevel,
+    UnknownKeyNamespaceStats::UnknownKeyNamespaceStats,
 };
+use android_system_keystore2::aidl::android::system::keystore2::Domain::Domain;
 use anyhow::{Context, Result};
+use keystore2_selinux as selinux;
 use keystore2_system_property::{write, PropertyWatcher, PropertyWatcherError};
 use lazy_static::lazy_static;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // Note: Crash events are recorded at keystore restarts, based on the assumption that keystore only
-// gets restarted after a crash, during a boot cycle.
+// gets restarted after a crash, during a boot cycle. Controlled restarts set the clean shutdown
+// marker below, so that the next start is not counted.
 const KEYSTORE_CRASH_COUNT_PROPERTY: &str = "keystore.crash_count";
 
+// Set to "1" by `mark_clean_shutdown` and reset to "0" by the next start.
+const KEYSTORE_CLEAN_SHUTDOWN_PROPERTY: &str = "keystore.clean_shutdown";
+
 lazy_static! {
     /// Singleton for MetricsStore.
     pub static ref METRICS_STORE: MetricsStore = Default::default();
@@ -70,9 +89,20 @@ lazy_static! {
 /// objects are queried by the atom id, the corresponding atom objects are retrieved, cloned, and
 /// the count field of the cloned objects is set to the corresponding value field in the inner hash
 /// map before the query result is returned.
-#[derive(Default)]
+///
+/// Unless detailed metrics are enabled, which they are by default, only the atoms listed in
+/// `MetricsStore::ESSENTIAL_ATOMS` are recorded and reported. This reflects the user's
+/// diagnostics and usage reporting preference, and it is enforced here, so that the sites that
+/// log atoms need not be aware of it.
 pub struct MetricsStore {
     metrics_store: Mutex<HashMap<AtomID, HashMap<KeystoreAtomPayload, i32>>>,
+    detailed_metrics_enabled: AtomicBool,
+}
+
+impl Default for MetricsStore {
+    fn default() -> Self {
+        Self { metrics_store: Default::default(), detailed_metrics_enabled: AtomicBool::new(true) }
+    }
 }
 
 impl MetricsStore {
@@ -83,10 +113,39 @@ impl MetricsStore {
     /// such atoms.
     const SINGLE_ATOM_STORE_MAX_SIZE: usize = 250;
 
+    /// Atoms that are needed to monitor the health of keystore. These are recorded and reported
+    /// even if the user has opted out of detailed diagnostics reporting.
+    const ESSENTIAL_ATOMS: &'static [AtomID] =
+        &[AtomID::CRASH_STATS, AtomID::HAL_TRANSPORT_ERROR_STATS];
+
+    /// Enables or disables the recording and reporting of atoms that are not essential. Atoms
+    /// recorded so far that are not essential are discarded when disabling detailed metrics.
+    pub fn set_detailed_metrics_enabled(&self, enabled: bool) {
+        self.detailed_metrics_enabled.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            // It is safe to call unwrap here since the lock can not be poisoned based on its
+            // usage in this module and the lock is not acquired in the same thread before.
+            self.metrics_store
+                .lock()
+                .unwrap()
+                .retain(|atom_id, _| Self::ESSENTIAL_ATOMS.contains(atom_id));
+        }
+    }
+
+    /// Returns true if the given atom may be recorded and reported.
+    fn is_atom_allowed(&self, atom_id: AtomID) -> bool {
+        self.detailed_metrics_enabled.load(Ordering::Relaxed)
+            || Self::ESSENTIAL_ATOMS.contains(&atom_id)
+    }
+
     /// Return a vector of atom objects with the given atom ID, if one exists in the metrics_store.
     /// If any atom object does not exist in the metrics_store for the given atom ID, return an
     /// empty vector.
     pub fn get_atoms(&self, atom_id: AtomID) -> Result<Vec<KeystoreAtom>> {
+        if !self.is_atom_allowed(atom_id) {
+            return Ok(vec![]);
+        }
+
         // StorageStats is an original pulled atom (i.e. not a pushed atom converted to a
         // pulledd atom). Therefore, it is handled separately.
         if AtomID::STORAGE_STATS == atom_id {
@@ -108,6 +167,62 @@ impl MetricsStore {
             }]);
         }
 
+        // The permission cache counters are read at pull time.
+        if AtomID::PERMISSION_CACHE_STATS == atom_id {
+            let stats = selinux::access_cache_stats();
+            return Ok(vec![KeystoreAtom {
+                payload: KeystoreAtomPayload::PermissionCacheStats(PermissionCacheStats {
+                    hits: stats.hits as i64,
+                    misses: stats.misses as i64,
+                    flushes: stats.flushes as i64,
+                }),
+                ..Default::default()
+            }]);
+        }
+
+        // The cache sizes and eviction counts are read at pull time.
+        if AtomID::CACHE_MEMORY_STATS == atom_id {
+            return Ok(cache_accounting::usage()
+                .into_iter()
+                .map(|usage| KeystoreAtom {
+                    payload: KeystoreAtomPayload::CacheMemoryStats(CacheMemoryStats {
+                        cacheType: match usage.kind {
+                            CacheKind::AuthTokens => CacheType::AUTH_TOKENS,
+                            CacheKind::PackageNames => CacheType::PACKAGE_NAMES,
+                            CacheKind::AccessDecisions => CacheType::ACCESS_DECISIONS,
+                            CacheKind::Grants => CacheType::GRANTS,
+                            CacheKind::EphemeralStorageKeys => CacheType::EPHEMERAL_STORAGE_KEYS,
+                            CacheKind::BufferPools => CacheType::BUFFER_POOLS,
+                            CacheKind::Sqlite => CacheType::SQLITE,
+                            CacheKind::SelinuxContexts => CacheType::SELINUX_CONTEXTS,
+                        },
+                        sizeBytes: usage.size_bytes as i64,
+                        evictions: usage.evictions as i64,
+                    }),
+                    ..Default::default()
+                })
+                .collect());
+        }
+
+        // The buffer pool counters are read at pull time.
+        if AtomID::BUFFER_POOL_STATS == atom_id {
+            return Ok(buffer_pool::stats()
+                .into_iter()
+                .map(|stats| KeystoreAtom {
+                    payload: KeystoreAtomPayload::BufferPoolStats(BufferPoolStats {
+                        poolType: match stats.kind {
+                            PoolKind::UpdatePayloads => BufferPoolType::UPDATE_PAYLOADS,
+                            PoolKind::KeyParameters => BufferPoolType::KEY_PARAMETERS,
+                        },
+                        reused: stats.reused as i64,
+                        allocated: stats.allocated as i64,
+                        discarded: stats.discarded as i64,
+                    }),
+                    ..Default::default()
+                })
+                .collect());
+        }
+
         // It is safe to call unwrap here since the lock can not be poisoned based on its usage
         // in this module and the lock is not acquired in the same thread before.
         let metrics_store_guard = self.metrics_store.lock().unwrap();
@@ -121,6 +236,10 @@ impl MetricsStore {
 
     /// Insert an atom object to the metrics_store indexed by the atom ID.
     fn insert_atom(&self, atom_id: AtomID, atom: KeystoreAtomPayload) {
+        if !self.is_atom_allowed(atom_id) {
+            return;
+        }
+
         // It is ok to unwrap here since the mutex cannot be poisoned according to the way it is
         // used in this module. And the lock is not acquired by this thread before.
         let mut metrics_store_guard = self.metrics_store.lock().unwrap();
@@ -361,16 +480,7 @@ fn process_key_operation_event_stats(
 
     key_operation_with_general_info.key_upgraded = key_upgraded;
 
-    key_operation_with_purpose_and_modes_info.purpose = match key_purpose {
-        KeyPurpose::ENCRYPT => MetricsPurpose::ENCRYPT,
-        KeyPurpose::DECRYPT => MetricsPurpose::DECRYPT,
-        KeyPurpose::SIGN => MetricsPurpose::SIGN,
-        KeyPurpose::VERIFY => MetricsPurpose::VERIFY,
-        KeyPurpose::WRAP_KEY => MetricsPurpose::WRAP_KEY,
-        KeyPurpose::AGREE_KEY => MetricsPurpose::AGREE_KEY,
-        KeyPurpose::ATTEST_KEY => MetricsPurpose::ATTEST_KEY,
-        _ => MetricsPurpose::KEY_PURPOSE_UNSPECIFIED,
-    };
+    key_operation_with_purpose_and_modes_info.purpose = process_purpose(key_purpose);
 
     key_operation_with_general_info.outcome = match op_outcome {
         Outcome::Unknown | Outcome::Dropped => MetricsOutcome::DROPPED,
@@ -415,6 +525,19 @@ fn process_key_operation_event_stats(
     )
 }
 
+fn process_purpose(key_purpose: KeyPurpose) -> MetricsPurpose {
+    match key_purpose {
+        KeyPurpose::ENCRYPT => MetricsPurpose::ENCRYPT,
+        KeyPurpose::DECRYPT => MetricsPurpose::DECRYPT,
+        KeyPurpose::SIGN => MetricsPurpose::SIGN,
+        KeyPurpose::VERIFY => MetricsPurpose::VERIFY,
+        KeyPurpose::WRAP_KEY => MetricsPurpose::WRAP_KEY,
+        KeyPurpose::AGREE_KEY => MetricsPurpose::AGREE_KEY,
+        KeyPurpose::ATTEST_KEY => MetricsPurpose::ATTEST_KEY,
+        _ => MetricsPurpose::KEY_PURPOSE_UNSPECIFIED,
+    }
+}
+
 fn process_security_level(sec_level: SecurityLevel) -> MetricsSecurityLevel {
     match sec_level {
         SecurityLevel::SOFTWARE => MetricsSecurityLevel::SECURITY_LEVEL_SOFTWARE,
@@ -525,36 +648,25 @@ fn compute_purpose_bitmap(purpose_bitmap: &mut i32, purpose: KeyPurpose) {
 }
 
 fn pull_storage_stats() -> Result<Vec<KeystoreAtom>> {
-    let mut atom_vec: Vec<KeystoreAtom> = Vec::new();
-    let mut append = |stat| {
-        match stat {
-            Ok(s) => atom_vec.push(KeystoreAtom {
-                payload: KeystoreAtomPayload::StorageStats(s),
-                ..Default::default()
-            }),
-            Err(error) => {
-                log::error!("pull_metrics_callback: Error getting storage stat: {}", error)
-            }
-        };
+    // Storage stats are served from the metadata snapshot, so that pulling metrics does not
+    // contend with key operations for the database.
+    let snapshot = match metadata_snapshot::get() {
+        Some(snapshot) => snapshot,
+        None => {
+            log::warn!(
+                "In pull_storage_stats: No metadata snapshot available yet, refresh scheduled."
+            );
+            return Ok(Vec::new());
+        }
     };
-    DB.with(|db| {
-        let mut db = db.borrow_mut();
-        append(db.get_storage_stat(MetricsStorage::DATABASE));
-        append(db.get_storage_stat(MetricsStorage::KEY_ENTRY));
-        append(db.get_storage_stat(MetricsStorage::KEY_ENTRY_ID_INDEX));
-        append(db.get_storage_stat(MetricsStorage::KEY_ENTRY_DOMAIN_NAMESPACE_INDEX));
-        append(db.get_storage_stat(MetricsStorage::BLOB_ENTRY));
-        append(db.get_storage_stat(MetricsStorage::BLOB_ENTRY_KEY_ENTRY_ID_INDEX));
-        append(db.get_storage_stat(MetricsStorage::KEY_PARAMETER));
-        append(db.get_storage_stat(MetricsStorage::KEY_PARAMETER_KEY_ENTRY_ID_INDEX));
-        append(db.get_storage_stat(MetricsStorage::KEY_METADATA));
-        append(db.get_storage_stat(MetricsStorage::KEY_METADATA_KEY_ENTRY_ID_INDEX));
-        append(db.get_storage_stat(MetricsStorage::GRANT));
-        append(db.get_storage_stat(MetricsStorage::AUTH_TOKEN));
-        append(db.get_storage_stat(MetricsStorage::BLOB_METADATA));
-        append(db.get_storage_stat(MetricsStorage::BLOB_METADATA_BLOB_ENTRY_ID_INDEX));
-    });
-    Ok(atom_vec)
+    Ok(snapshot
+        .storage_stats
+        .iter()
+        .map(|s| KeystoreAtom {
+            payload: KeystoreAtomPayload::StorageStats(s.clone()),
+            ..Default::default()
+        })
+        .collect())
 }
 
 fn pull_attestation_pool_stats() -> Result<Vec<KeystoreAtom>> {
@@ -598,12 +710,156 @@ pub fn log_rkp_error_stats(rkp_error: MetricsRkpError) {
     METRICS_STORE.insert_atom(AtomID::RKP_ERROR_STATS, rkp_error_stats);
 }
 
+/// Log a binder transport failure observed while calling into the HAL of the given security
+/// level. `recovered` indicates whether the retry after reconnecting to the HAL succeeded.
+pub fn log_hal_transport_error_stats(sec_level: SecurityLevel, recovered: bool) {
+    let hal_transport_error_stats =
+        KeystoreAtomPayload::HalTransportErrorStats(HalTransportErrorStats {
+            security_level: process_security_level(sec_level),
+            recovered,
+        });
+    METRICS_STORE.insert_atom(AtomID::HAL_TRANSPORT_ERROR_STATS, hal_transport_error_stats);
+}
+
+/// Log an operation that was refused, because a key was used again before the minimum interval
+/// between uses had elapsed.
+pub fn log_key_use_throttled_stats(key_purpose: KeyPurpose) {
+    let key_use_throttled_stats = KeystoreAtomPayload::KeyUseThrottledStats(KeyUseThrottledStats {
+        purpose: process_purpose(key_purpose),
+    });
+    METRICS_STORE.insert_atom(AtomID::KEY_USE_THROTTLED_STATS, key_use_throttled_stats);
+}
+
+/// Log a permission check that passed under the current policy, but would have been denied by
+/// the proposed rule of the given name.
+pub fn log_permission_shadow_mismatch_stats(rule: &str) {
+    let permission_shadow_mismatch_stats =
+        KeystoreAtomPayload::PermissionShadowMismatchStats(PermissionShadowMismatchStats {
+            rule: rule.to_string(),
+        });
+    METRICS_STORE
+        .insert_atom(AtomID::PERMISSION_SHADOW_MISMATCH_STATS, permission_shadow_mismatch_stats);
+}
+
+/// Log a request that was denied, because the given SELinux namespace has no keystore2_key
+/// context.
+pub fn log_unknown_key_namespace_stats(namespace: i64) {
+    let unknown_key_namespace_stats =
+        KeystoreAtomPayload::UnknownKeyNamespaceStats(UnknownKeyNamespaceStats {
+            keyNamespace: namespace,
+        });
+    METRICS_STORE.insert_atom(AtomID::UNKNOWN_KEY_NAMESPACE_STATS, unknown_key_namespace_stats);
+}
+
+/// Log the number of tracked auth tokens before and after a coalescing pass.
+pub fn log_auth_token_coalescing_stats(
+    tokens_before: usize,
+    tokens_after: usize,
+    at_startup: bool,
+) {
+    let auth_token_coalescing_stats =
+        KeystoreAtomPayload::AuthTokenCoalescingStats(AuthTokenCoalescingStats {
+            tokensBefore: tokens_before as i32,
+            tokensAfter: tokens_after as i32,
+            atStartup: at_startup,
+        });
+    METRICS_STORE.insert_atom(AtomID::AUTH_TOKEN_COALESCING_STATS, auth_token_coalescing_stats);
+}
+
+/// Log a legacy Keystore entry that was moved to quarantine during migration.
+pub fn log_legacy_blob_quarantine_stats(quarantined_files: usize) {
+    let legacy_blob_quarantine_stats =
+        KeystoreAtomPayload::LegacyBlobQuarantineStats(LegacyBlobQuarantineStats {
+            quarantinedFiles: quarantined_files as i32,
+        });
+    METRICS_STORE.insert_atom(AtomID::LEGACY_BLOB_QUARANTINE_STATS, legacy_blob_quarantine_stats);
+}
+
+/// Log the loss of the per-boot database, or, if `listener_informed` is true, that the auth
+/// token recovery listener was asked to supply fresh auth tokens.
+pub fn log_perboot_recovery_stats(listener_informed: bool) {
+    let perboot_recovery_stats = KeystoreAtomPayload::PerbootRecoveryStats(PerbootRecoveryStats {
+        listenerInformed: listener_informed,
+    });
+    METRICS_STORE.insert_atom(AtomID::PERBOOT_RECOVERY_STATS, perboot_recovery_stats);
+}
+
+/// Log a problem found by the integrity check of the persistent database.
+pub fn log_database_integrity_stats(quarantined: bool, orphaned_rows: usize) {
+    let database_integrity_stats =
+        KeystoreAtomPayload::DatabaseIntegrityStats(DatabaseIntegrityStats {
+            quarantined,
+            orphanedRows: orphaned_rows as i32,
+        });
+    METRICS_STORE.insert_atom(AtomID::DATABASE_INTEGRITY_STATS, database_integrity_stats);
+}
+
+/// Log the outcome of a verification of super encrypted key blobs.
+pub fn log_blob_verification_stats(verified_blobs: usize, failed_blobs: usize) {
+    let blob_verification_stats =
+        KeystoreAtomPayload::BlobVerificationStats(BlobVerificationStats {
+            verifiedBlobs: verified_blobs as i32,
+            failedBlobs: failed_blobs as i32,
+        });
+    METRICS_STORE.insert_atom(AtomID::BLOB_VERIFICATION_STATS, blob_verification_stats);
+}
+
+/// Log that an operation was allowed because of the clock skew tolerance. `expired` tells
+/// whether the key had expired or was not yet valid.
+pub fn log_clock_skew_tolerance_stats(expired: bool, skew_millis: i64) {
+    let clock_skew_tolerance_stats =
+        KeystoreAtomPayload::ClockSkewToleranceStats(ClockSkewToleranceStats {
+            expired,
+            skewMillis: skew_millis,
+        });
+    METRICS_STORE.insert_atom(AtomID::CLOCK_SKEW_TOLERANCE_STATS, clock_skew_tolerance_stats);
+}
+
+/// Log that an operation used a deprecated parameter and whether it was rejected.
+pub fn log_deprecated_parameter_stats(parameter: DeprecatedParameter, rejected: bool) {
+    let deprecated_parameter_stats =
+        KeystoreAtomPayload::DeprecatedParameterStats(DeprecatedParameterStats {
+            parameter,
+            rejected,
+        });
+    METRICS_STORE.insert_atom(AtomID::DEPRECATED_PARAMETER_STATS, deprecated_parameter_stats);
+}
+
+/// Log the number of operations started in a key namespace during one day. The count is
+/// reported as a coarse bucket only, see `compute_operation_count_bucket`.
+pub fn log_namespace_usage_stats(domain: Domain, namespace: i64, operation_count: u64) {
+    if operation_count == 0 {
+        return;
+    }
+    let namespace_usage_stats = KeystoreAtomPayload::NamespaceUsageStats(NamespaceUsageStats {
+        domain: domain.0,
+        keyNamespace: namespace,
+        operationCountBucket: compute_operation_count_bucket(operation_count),
+    });
+    METRICS_STORE.insert_atom(AtomID::NAMESPACE_USAGE_STATS, namespace_usage_stats);
+}
+
+/// Returns the decimal order of magnitude of a non zero operation count, i.e., bucket n holds
+/// the counts from 10^n to 10^(n+1) - 1.
+fn compute_operation_count_bucket(operation_count: u64) -> i32 {
+    let mut bucket = 0;
+    let mut count = operation_count;
+    while count >= 10 {
+        count /= 10;
+        bucket += 1;
+    }
+    bucket
+}
+
 /// This function tries to read and update the system property: keystore.crash_count.
 /// If the property is absent, it sets the property with value 0. If the property is present, it
-/// increments the value. This helps tracking keystore crashes internally.
-pub fn update_keystore_crash_sysprop() {
+/// increments the value, unless the previous instance shut down cleanly, see
+/// `mark_clean_shutdown`. This helps tracking keystore crashes internally.
+/// Returns true if Keystore was started before during this boot, crashed or not.
+pub fn update_keystore_crash_sysprop() -> bool {
     let crash_count = read_keystore_crash_count();
     let new_count = match crash_count {
+        Ok(_) if take_clean_shutdown_marker() => return true,
         Ok(count) => count + 1,
         Err(error) => {
             // If the property is absent, this is the first start up during the boot.
@@ -620,11 +876,12 @@ pub fn update_keystore_crash_sysprop() {
                     ),
                     error
                 );
-                return;
+                return false;
             }
             0
         }
     };
+    let restarted = new_count > 0;
 
     if let Err(e) = write(KEYSTORE_CRASH_COUNT_PROPERTY, &new_count.to_string()) {
         log::error!(
@@ -635,6 +892,29 @@ pub fn update_keystore_crash_sysprop() {
             e
         );
     }
+    restarted
+}
+
+/// Sets the system property keystore.clean_shutdown, so that the next start of Keystore during
+/// this boot is not counted as a crash.
+pub fn mark_clean_shutdown() {
+    if let Err(e) = write(KEYSTORE_CLEAN_SHUTDOWN_PROPERTY, "1") {
+        log::error!("In mark_clean_shutdown: Failed to write the system property: {:?}", e);
+    }
+}
+
+/// Returns true if the previous instance marked a clean shutdown and resets the marker.
+fn take_clean_shutdown_marker() -> bool {
+    let marked = PropertyWatcher::new(KEYSTORE_CLEAN_SHUTDOWN_PROPERTY)
+        .ok()
+        .and_then(|mut w| w.read(|_n, v| Ok(v == "1")).ok())
+        .unwrap_or(false);
+    if marked {
+        if let Err(e) = write(KEYSTORE_CLEAN_SHUTDOWN_PROPERTY, "0") {
+            log::error!("In take_clean_shutdown_marker: Failed to reset the marker: {:?}", e);
+        }
+    }
+    marked
 }
 
 /// Read the system property: keystore.crash_count.
@@ -722,3 +1002,45 @@ enum KeyPurposeBitPosition {
     ///Bit position in the KeyPurpose bitmap for Attest Key.
     ATTEST_KEY_BIT_POS = 7,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detailed_metrics_opt_out() -> Result<()> {
+        let store = MetricsStore::default();
+        let rkp_error = KeystoreAtomPayload::RkpErrorStats(RkpErrorStats {
+            rkpError: MetricsRkpError::OUT_OF_KEYS,
+        });
+        let transport_error = KeystoreAtomPayload::HalTransportErrorStats(HalTransportErrorStats {
+            security_level: MetricsSecurityLevel::SECURITY_LEVEL_TRUSTED_ENVIRONMENT,
+            recovered: true,
+        });
+        store.insert_atom(AtomID::RKP_ERROR_STATS, rkp_error.clone());
+        store.insert_atom(AtomID::HAL_TRANSPORT_ERROR_STATS, transport_error.clone());
+        assert_eq!(store.get_atoms(AtomID::RKP_ERROR_STATS)?.len(), 1);
+
+        // Opting out discards detailed atoms and stops recording them.
+        store.set_detailed_metrics_enabled(false);
+        store.insert_atom(AtomID::RKP_ERROR_STATS, rkp_error.clone());
+        store.insert_atom(AtomID::HAL_TRANSPORT_ERROR_STATS, transport_error);
+        assert!(store.get_atoms(AtomID::RKP_ERROR_STATS)?.is_empty());
+        assert_eq!(store.get_atoms(AtomID::HAL_TRANSPORT_ERROR_STATS)?[0].count, 2);
+
+        store.set_detailed_metrics_enabled(true);
+        store.insert_atom(AtomID::RKP_ERROR_STATS, rkp_error);
+        assert_eq!(store.get_atoms(AtomID::RKP_ERROR_STATS)?[0].count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compute_operation_count_bucket() {
+        assert_eq!(compute_operation_count_bucket(1), 0);
+        assert_eq!(compute_operation_count_bucket(9), 0);
+        assert_eq!(compute_operation_count_bucket(10), 1);
+        assert_eq!(compute_operation_count_bucket(999), 2);
+        assert_eq!(compute_operation_count_bucket(1000), 3);
+        assert_eq!(compute_operation_count_bucket(u64::MAX), 19);
+    }
+}
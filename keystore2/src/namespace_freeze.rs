@@ -0,0 +1,214 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the freezing of namespaces for incident response. The keys of a
+//! frozen namespace remain in place, but Keystore refuses to use them, to create, import, or
+//! delete keys in the namespace, and to update their certificates. This lets the platform
+//! contain a compromised app without destroying the keys as evidence. Reading metadata, e.g.,
+//! with `getKeyEntry` or by listing the namespace, still succeeds. Refused requests are
+//! reported as `KeystoreErrorCode::NAMESPACE_FROZEN`.
+//!
+//! Clearing a frozen namespace, e.g., because the app was uninstalled, or removing its user
+//! succeeds. The keys are quarantined: they are hidden from the namespace but kept in the
+//! database until the namespace is unfrozen, which deletes them. So an app that later reuses
+//! the uid never sees them.
+//!
+//! Frozen namespaces are stored in the database and survive restarts and reboots. They are
+//! mirrored in memory, so that the checks never touch the database. Until the mirror was
+//! loaded successfully, it is not known which namespaces are frozen. Keystore then fails
+//! closed: all namespaces except SELinux namespaces and those of system uids are treated as
+//! frozen, so that the system can still boot, and loading is retried whenever a check has
+//! access to the database.
+
+use crate::database::{DateTime, KeystoreDB};
+use crate::permission::AID_APP_START;
+use crate::utils::AID_USER_OFFSET;
+use android_system_keystore2::aidl::android::system::keystore2::Domain::Domain;
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+lazy_static! {
+    /// The frozen namespaces, mirrored from the database. None if they could not be loaded yet.
+    static ref FROZEN_NAMESPACES: RwLock<Option<HashSet<(Domain, i64)>>> = Default::default();
+}
+
+/// The namespace of a key is frozen. This is reported as `KeystoreErrorCode::NAMESPACE_FROZEN`.
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("Namespace {namespace} of domain {domain} is frozen.")]
+pub struct NamespaceFrozen {
+    /// The domain of the namespace.
+    pub domain: i32,
+    /// The namespace.
+    pub namespace: i64,
+}
+
+/// Loads the frozen namespaces from the database. Must be called on startup before any key is
+/// used. If this fails, namespaces are treated as frozen as described in the module
+/// documentation until a later attempt succeeds.
+pub fn load(db: &mut KeystoreDB) -> Result<()> {
+    let mut frozen_namespaces = FROZEN_NAMESPACES.write().unwrap();
+    load_locked(db, &mut frozen_namespaces).context("In load.")
+}
+
+fn load_locked(
+    db: &mut KeystoreDB,
+    frozen_namespaces: &mut Option<HashSet<(Domain, i64)>>,
+) -> Result<()> {
+    let frozen = db.list_frozen_namespaces().context("In load_locked.")?;
+    if !frozen.is_empty() {
+        log::warn!("{} namespaces are frozen.", frozen.len());
+    }
+    *frozen_namespaces = Some(frozen.into_iter().collect());
+    Ok(())
+}
+
+/// Retries loading the frozen namespaces if this failed before. Errors are logged, because
+/// the checks fail closed anyway.
+fn reload_if_unknown(db: &mut KeystoreDB) {
+    if FROZEN_NAMESPACES.read().unwrap().is_some() {
+        return;
+    }
+    let mut frozen_namespaces = FROZEN_NAMESPACES.write().unwrap();
+    if frozen_namespaces.is_none() {
+        match load_locked(db, &mut frozen_namespaces) {
+            Ok(()) => log::info!("Loaded frozen namespaces after an earlier failure."),
+            Err(e) => log::error!("Still failed to load frozen namespaces: {:?}", e),
+        }
+    }
+}
+
+/// Returns true if the namespace may be used while it is unknown which namespaces are frozen.
+fn is_system_namespace(domain: Domain, namespace: i64) -> bool {
+    match domain {
+        Domain::SELINUX => true,
+        Domain::APP => (namespace as u32 % AID_USER_OFFSET) < AID_APP_START,
+        _ => false,
+    }
+}
+
+/// Returns `NamespaceFrozen` if the given namespace is frozen, or if it is unknown whether it
+/// is and it is not a system namespace. This does not access the database, so it may be called
+/// while the database is in use.
+pub fn check_not_frozen(domain: Domain, namespace: i64) -> Result<()> {
+    let frozen = match &*FROZEN_NAMESPACES.read().unwrap() {
+        Some(frozen_namespaces) => frozen_namespaces.contains(&(domain, namespace)),
+        None => !is_system_namespace(domain, namespace),
+    };
+    if frozen {
+        return Err(NamespaceFrozen { domain: domain.0, namespace })
+            .context("In check_not_frozen.");
+    }
+    Ok(())
+}
+
+/// Like `check_not_frozen`, but resolves keys that are accessed by grant to the namespace of
+/// their owner, so that grantees cannot use the keys of a frozen namespace either. `domain` and
+/// `namespace` are those of the descriptor the key with `key_id` was loaded with. Must not be
+/// called while the database is in use.
+pub fn check_key_not_frozen(
+    db: &mut KeystoreDB,
+    domain: Domain,
+    namespace: i64,
+    key_id: i64,
+) -> Result<()> {
+    reload_if_unknown(db);
+    if domain != Domain::GRANT {
+        return check_not_frozen(domain, namespace);
+    }
+    if FROZEN_NAMESPACES.read().unwrap().as_ref().map_or(false, |f| f.is_empty()) {
+        return Ok(());
+    }
+    match db.load_key_descriptor(key_id).context("In check_key_not_frozen.")? {
+        Some(owner) => check_not_frozen(owner.domain, owner.nspace),
+        // The key is gone. Using it fails elsewhere.
+        None => Ok(()),
+    }
+}
+
+/// Freezes or unfreezes the given namespace. Returns false if it was in the requested state
+/// already.
+pub fn set_frozen(
+    db: &mut KeystoreDB,
+    domain: Domain,
+    namespace: i64,
+    frozen: bool,
+) -> Result<bool> {
+    // The lock is held across the database update, so that concurrent calls cannot leave the
+    // mirror out of sync with the database.
+    let mut frozen_namespaces = FROZEN_NAMESPACES.write().unwrap();
+    if frozen_namespaces.is_none() {
+        load_locked(db, &mut frozen_namespaces).context("In set_frozen.")?;
+    }
+    let frozen_namespaces = frozen_namespaces.as_mut().unwrap();
+    let changed = if frozen {
+        let now = DateTime::now().context("In set_frozen: Failed to get current time.")?;
+        db.freeze_namespace(domain, namespace, now).context("In set_frozen.")?
+    } else {
+        db.unfreeze_namespace(domain, namespace).context("In set_frozen.")?
+    };
+    if frozen {
+        frozen_namespaces.insert((domain, namespace));
+    } else {
+        frozen_namespaces.remove(&(domain, namespace));
+    }
+    Ok(changed)
+}
+
+/// Returns all frozen namespaces, or None if they could not be loaded yet.
+pub fn frozen_namespaces() -> Option<Vec<(Domain, i64)>> {
+    let mut result: Vec<(Domain, i64)> =
+        FROZEN_NAMESPACES.read().unwrap().as_ref()?.iter().copied().collect();
+    result.sort();
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keystore2_test_utils::TempDir;
+
+    // A namespace that no other test uses, because the mirror is global.
+    const TEST_NAMESPACE: i64 = 0x7f7f_0001;
+
+    #[test]
+    fn frozen_namespaces_are_refused() -> Result<()> {
+        let temp_dir = TempDir::new("namespace_freeze_test")?;
+        let mut db = KeystoreDB::new(temp_dir.path(), None)?;
+
+        assert!(set_frozen(&mut db, Domain::SELINUX, TEST_NAMESPACE, true)?);
+        assert!(!set_frozen(&mut db, Domain::SELINUX, TEST_NAMESPACE, true)?);
+        let e = check_not_frozen(Domain::SELINUX, TEST_NAMESPACE).unwrap_err();
+        assert_eq!(
+            e.root_cause().downcast_ref::<NamespaceFrozen>(),
+            Some(&NamespaceFrozen { domain: Domain::SELINUX.0, namespace: TEST_NAMESPACE })
+        );
+        assert!(check_not_frozen(Domain::APP, TEST_NAMESPACE).is_ok());
+
+        assert!(set_frozen(&mut db, Domain::SELINUX, TEST_NAMESPACE, false)?);
+        assert!(check_not_frozen(Domain::SELINUX, TEST_NAMESPACE).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn system_namespaces() {
+        assert!(is_system_namespace(Domain::SELINUX, TEST_NAMESPACE));
+        assert!(is_system_namespace(Domain::APP, 1000));
+        assert!(is_system_namespace(Domain::APP, (10 * AID_USER_OFFSET + 1000) as i64));
+        assert!(!is_system_namespace(Domain::APP, 10001));
+        assert!(!is_system_namespace(Domain::APP, (10 * AID_USER_OFFSET + 10001) as i64));
+        assert!(!is_system_namespace(Domain::BLOB, 1000));
+    }
+}
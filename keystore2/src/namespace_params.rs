@@ -0,0 +1,385 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements mandatory key parameters for Domain::SELINUX namespaces. Platform
+//! security requirements, e.g., that all keys of the wifi namespace are usable without user
+//! authentication and backed by the TEE, are enforced when a key is created instead of relying
+//! on every client to request the right parameters.
+//!
+//! The configuration file holds one line per rule of the form
+//! `namespace=<first>[-<last>] <requirement> [<requirement>...]`, e.g.,
+//! `namespace=102 no_auth_required security_level=tee`. The requirements are
+//! * `no_auth_required`: Keys must be usable without user authentication. `NO_AUTH_REQUIRED`
+//!   is added if missing, user authentication parameters are rejected.
+//! * `auth_required`: Keys must be bound to user authentication. Requests without
+//!   `USER_SECURE_ID` or with `NO_AUTH_REQUIRED` are rejected.
+//! * `unlocked_device_required`: `UNLOCKED_DEVICE_REQUIRED` is added if missing.
+//! * `rollback_resistance`: `ROLLBACK_RESISTANCE` is added if missing.
+//! * `security_level=tee|strongbox`: Keys must be created on this security level or a higher
+//!   one. Such keys never fall back to software, see `software_fallback`.
+//!
+//! The requirements of all rules that cover a namespace apply. Empty lines and lines starting
+//! with `#` are ignored. If the configuration is malformed, no keys can be created in
+//! Domain::SELINUX namespaces, because their requirements are unknown.
+//!
+//! The parameters of wrapped keys are only known after KeyMint unwrapped them, so imported
+//! wrapped keys are validated against the requirements by their key characteristics instead.
+
+use crate::config_file;
+use crate::error::{Error, ErrorCode};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyCharacteristics::KeyCharacteristics, KeyParameter::KeyParameter,
+    KeyParameterValue::KeyParameterValue, SecurityLevel::SecurityLevel, Tag::Tag,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use std::ops::RangeInclusive;
+
+/// Location of the namespace parameter configuration.
+const NAMESPACE_PARAMS_CONFIG: &str = "/vendor/etc/security/keystore2_namespace_params.conf";
+
+lazy_static! {
+    /// The namespace parameters of this device, loaded once on first use.
+    static ref NAMESPACE_PARAMS: Result<NamespaceParams> =
+        config_file::load(NAMESPACE_PARAMS_CONFIG, NamespaceParams::parse);
+}
+
+/// The requirements on the keys of a namespace.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Requirements {
+    /// Keys must be usable without user authentication.
+    pub no_auth_required: bool,
+    /// Keys must be bound to user authentication.
+    pub auth_required: bool,
+    /// Keys must only be usable while the device is unlocked.
+    pub unlocked_device_required: bool,
+    /// Keys must be rollback resistant.
+    pub rollback_resistance: bool,
+    /// The lowest security level keys may be created on.
+    pub min_security_level: Option<SecurityLevel>,
+}
+
+impl Requirements {
+    fn merge(&mut self, other: &Requirements) {
+        self.no_auth_required |= other.no_auth_required;
+        self.auth_required |= other.auth_required;
+        self.unlocked_device_required |= other.unlocked_device_required;
+        self.rollback_resistance |= other.rollback_resistance;
+        self.min_security_level = match (self.min_security_level, other.min_security_level) {
+            (Some(a), Some(b)) => Some(if a.0 >= b.0 { a } else { b }),
+            (a, b) => a.or(b),
+        };
+    }
+
+    /// Returns an error if keys may not be created on `security_level`.
+    pub fn check_security_level(&self, security_level: SecurityLevel) -> Result<()> {
+        match self.min_security_level {
+            Some(min) if security_level.0 < min.0 => {
+                Err(Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE)).context(format!(
+                    "In check_security_level: {:?} is required, but the key is created on {:?}.",
+                    min, security_level
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Validates `params` against the requirements and adds the mandatory parameters that are
+    /// missing. Returns the resulting key parameters.
+    pub fn apply(
+        &self,
+        security_level: SecurityLevel,
+        params: &[KeyParameter],
+    ) -> Result<Vec<KeyParameter>> {
+        self.check_security_level(security_level).context("In apply.")?;
+        let has = |tag| params.iter().any(|p| p.tag == tag);
+        let invalid = |requirement: &str| -> Result<Vec<KeyParameter>> {
+            Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+                .context(format!("In apply: The namespace requires {}.", requirement))
+        };
+        if self.no_auth_required
+            && (has(Tag::USER_SECURE_ID) || has(Tag::USER_AUTH_TYPE) || has(Tag::AUTH_TIMEOUT))
+        {
+            return invalid("no_auth_required");
+        }
+        if self.auth_required && (has(Tag::NO_AUTH_REQUIRED) || !has(Tag::USER_SECURE_ID)) {
+            return invalid("auth_required");
+        }
+        let mut result = params.to_vec();
+        for (required, tag) in &[
+            (self.no_auth_required, Tag::NO_AUTH_REQUIRED),
+            (self.unlocked_device_required, Tag::UNLOCKED_DEVICE_REQUIRED),
+            (self.rollback_resistance, Tag::ROLLBACK_RESISTANCE),
+        ] {
+            if *required && !has(*tag) {
+                result.push(KeyParameter { tag: *tag, value: KeyParameterValue::BoolValue(true) });
+            }
+        }
+        Ok(result)
+    }
+
+    /// Validates the characteristics of a key that KeyMint created on `security_level`
+    /// against the requirements.
+    pub fn check_characteristics(
+        &self,
+        security_level: SecurityLevel,
+        characteristics: &[KeyCharacteristics],
+    ) -> Result<()> {
+        self.check_security_level(security_level).context("In check_characteristics.")?;
+        let has = |tag| {
+            characteristics.iter().flat_map(|c| c.authorizations.iter()).any(|p| p.tag == tag)
+        };
+        let violated = |requirement: &str| -> Result<()> {
+            Err(Error::Km(ErrorCode::INVALID_ARGUMENT)).context(format!(
+                "In check_characteristics: The namespace requires {}.",
+                requirement
+            ))
+        };
+        if self.no_auth_required && (!has(Tag::NO_AUTH_REQUIRED) || has(Tag::USER_SECURE_ID)) {
+            return violated("no_auth_required");
+        }
+        if self.auth_required && (has(Tag::NO_AUTH_REQUIRED) || !has(Tag::USER_SECURE_ID)) {
+            return violated("auth_required");
+        }
+        if self.unlocked_device_required && !has(Tag::UNLOCKED_DEVICE_REQUIRED) {
+            return violated("unlocked_device_required");
+        }
+        if self.rollback_resistance && !has(Tag::ROLLBACK_RESISTANCE) {
+            return violated("rollback_resistance");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    namespaces: RangeInclusive<i64>,
+    requirements: Requirements,
+}
+
+fn parse_rule(line: &str) -> Result<Rule> {
+    let mut fields = line.split_whitespace();
+    let namespaces = match fields.next().and_then(|f| f.strip_prefix("namespace=")) {
+        Some(range) => config_file::parse_range(range)?,
+        None => return Err(anyhow!("Rules must start with \"namespace=\".")),
+    };
+    let mut requirements: Requirements = Default::default();
+    for requirement in fields {
+        match requirement {
+            "no_auth_required" => requirements.no_auth_required = true,
+            "auth_required" => requirements.auth_required = true,
+            "unlocked_device_required" => requirements.unlocked_device_required = true,
+            "rollback_resistance" => requirements.rollback_resistance = true,
+            "security_level=tee" => {
+                requirements.min_security_level = Some(SecurityLevel::TRUSTED_ENVIRONMENT)
+            }
+            "security_level=strongbox" => {
+                requirements.min_security_level = Some(SecurityLevel::STRONGBOX)
+            }
+            _ => return Err(anyhow!("Bad requirement \"{}\".", requirement)),
+        }
+    }
+    if requirements == Default::default() {
+        return Err(anyhow!("Missing requirements."));
+    }
+    if requirements.no_auth_required && requirements.auth_required {
+        return Err(anyhow!("Conflicting requirements."));
+    }
+    Ok(Rule { namespaces, requirements })
+}
+
+/// The compiled namespace parameter configuration.
+#[derive(Debug, Default)]
+pub struct NamespaceParams {
+    rules: Vec<Rule>,
+}
+
+impl NamespaceParams {
+    /// Parses the namespace parameter configuration. See the module documentation for the
+    /// format.
+    pub fn parse(config: &str) -> Result<Self> {
+        let mut rules = Vec::new();
+        for (n, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            rules.push(parse_rule(line).with_context(|| format!("In line {}.", n + 1))?);
+        }
+        Ok(Self { rules })
+    }
+
+    /// Returns the requirements on the keys of `key`'s namespace. Only Domain::SELINUX
+    /// namespaces have requirements.
+    pub fn requirements(&self, key: &KeyDescriptor) -> Requirements {
+        let mut requirements: Requirements = Default::default();
+        if key.domain == Domain::SELINUX {
+            for rule in self.rules.iter().filter(|r| r.namespaces.contains(&key.nspace)) {
+                requirements.merge(&rule.requirements);
+            }
+        }
+        requirements
+    }
+}
+
+/// Returns the requirements on the keys of `key`'s namespace. Fails for Domain::SELINUX keys if
+/// the configuration is malformed.
+fn requirements(key: &KeyDescriptor) -> Result<Requirements> {
+    match &*NAMESPACE_PARAMS {
+        Ok(params) => Ok(params.requirements(key)),
+        Err(_) if key.domain != Domain::SELINUX => Ok(Default::default()),
+        Err(e) => Err(Error::sys()).context(format!(
+            "In namespace_params::requirements: Malformed {}: {:?}",
+            NAMESPACE_PARAMS_CONFIG, e
+        )),
+    }
+}
+
+/// Validates the parameters of a new key `key` on `security_level` against the requirements
+/// of its namespace and adds the missing mandatory parameters.
+pub fn apply(
+    security_level: SecurityLevel,
+    key: &KeyDescriptor,
+    params: &[KeyParameter],
+) -> Result<Vec<KeyParameter>> {
+    requirements(key)
+        .and_then(|r| r.apply(security_level, params))
+        .with_context(|| format!("In namespace_params::apply: For {:?}.", key))
+}
+
+/// Returns an error if the namespace of `key` does not allow keys on `security_level`.
+pub fn check_security_level(security_level: SecurityLevel, key: &KeyDescriptor) -> Result<()> {
+    requirements(key)
+        .and_then(|r| r.check_security_level(security_level))
+        .with_context(|| format!("In namespace_params::check_security_level: For {:?}.", key))
+}
+
+/// Validates the characteristics of the new key `key`, which KeyMint created on
+/// `security_level`, against the requirements of its namespace. This is used for keys whose
+/// parameters are not known before they are created, i.e., wrapped keys.
+pub fn check_characteristics(
+    security_level: SecurityLevel,
+    key: &KeyDescriptor,
+    characteristics: &[KeyCharacteristics],
+) -> Result<()> {
+    requirements(key)
+        .and_then(|r| r.check_characteristics(security_level, characteristics))
+        .with_context(|| format!("In namespace_params::check_characteristics: For {:?}.", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(domain: Domain, nspace: i64) -> KeyDescriptor {
+        KeyDescriptor { domain, nspace, alias: Some("key".to_string()), blob: None }
+    }
+
+    fn bool_param(tag: Tag) -> KeyParameter {
+        KeyParameter { tag, value: KeyParameterValue::BoolValue(true) }
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let params = NamespaceParams::parse(
+            "# Wifi keys must be usable without authentication.
+             namespace=102 no_auth_required security_level=tee
+
+             namespace=100-199 rollback_resistance",
+        )?;
+        assert_eq!(
+            params.requirements(&key(Domain::SELINUX, 102)),
+            Requirements {
+                no_auth_required: true,
+                rollback_resistance: true,
+                min_security_level: Some(SecurityLevel::TRUSTED_ENVIRONMENT),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            params.requirements(&key(Domain::SELINUX, 150)),
+            Requirements { rollback_resistance: true, ..Default::default() }
+        );
+        assert_eq!(params.requirements(&key(Domain::APP, 102)), Default::default());
+        assert_eq!(params.requirements(&key(Domain::SELINUX, 200)), Default::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_config() {
+        assert!(NamespaceParams::parse("102 no_auth_required").is_err());
+        assert!(NamespaceParams::parse("namespace=102").is_err());
+        assert!(NamespaceParams::parse("namespace=9-1 no_auth_required").is_err());
+        assert!(NamespaceParams::parse("namespace=102 security_level=software").is_err());
+        assert!(NamespaceParams::parse("namespace=102 no_auth_required auth_required").is_err());
+    }
+
+    #[test]
+    fn test_apply() -> Result<()> {
+        let requirements = Requirements {
+            no_auth_required: true,
+            min_security_level: Some(SecurityLevel::TRUSTED_ENVIRONMENT),
+            ..Default::default()
+        };
+        let params = requirements.apply(SecurityLevel::STRONGBOX, &[])?;
+        assert_eq!(params, vec![bool_param(Tag::NO_AUTH_REQUIRED)]);
+        // Present parameters are not duplicated.
+        let params = requirements.apply(SecurityLevel::TRUSTED_ENVIRONMENT, &params)?;
+        assert_eq!(params, vec![bool_param(Tag::NO_AUTH_REQUIRED)]);
+        let auth_bound =
+            [KeyParameter { tag: Tag::USER_SECURE_ID, value: KeyParameterValue::LongInteger(1) }];
+        assert!(requirements.apply(SecurityLevel::TRUSTED_ENVIRONMENT, &auth_bound).is_err());
+        assert!(requirements.apply(SecurityLevel::SOFTWARE, &[]).is_err());
+
+        let requirements = Requirements { auth_required: true, ..Default::default() };
+        assert_eq!(requirements.apply(SecurityLevel::SOFTWARE, &auth_bound)?, auth_bound.to_vec());
+        assert!(requirements.apply(SecurityLevel::SOFTWARE, &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_characteristics() {
+        let characteristics = |params: Vec<KeyParameter>| {
+            vec![KeyCharacteristics {
+                securityLevel: SecurityLevel::TRUSTED_ENVIRONMENT,
+                authorizations: params,
+            }]
+        };
+        let auth_bound = characteristics(vec![KeyParameter {
+            tag: Tag::USER_SECURE_ID,
+            value: KeyParameterValue::LongInteger(1),
+        }]);
+        let unbound = characteristics(vec![bool_param(Tag::NO_AUTH_REQUIRED)]);
+        let tee = SecurityLevel::TRUSTED_ENVIRONMENT;
+
+        let requirements = Requirements { no_auth_required: true, ..Default::default() };
+        assert!(requirements.check_characteristics(tee, &unbound).is_ok());
+        assert!(requirements.check_characteristics(tee, &auth_bound).is_err());
+
+        let requirements = Requirements { auth_required: true, ..Default::default() };
+        assert!(requirements.check_characteristics(tee, &auth_bound).is_ok());
+        assert!(requirements.check_characteristics(tee, &unbound).is_err());
+
+        let requirements = Requirements { rollback_resistance: true, ..Default::default() };
+        assert!(requirements.check_characteristics(tee, &unbound).is_err());
+        let requirements = Requirements {
+            min_security_level: Some(SecurityLevel::STRONGBOX),
+            ..Default::default()
+        };
+        assert!(requirements.check_characteristics(tee, &unbound).is_err());
+    }
+}
@@ -0,0 +1,305 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the IKeystoreOdsignKey AIDL interface. It gives the on-device signing
+//! daemon (odsign) dedicated calls to create, inspect, rotate, and use its boot level bound
+//! signing key, instead of assembling the key parameters and validity checks from generic
+//! Keystore calls. The service runs all requests with the identity of the caller, so the
+//! keystore2_key permissions of the odsign_key namespace apply as they would to generic calls.
+
+use crate::async_keygen;
+use crate::caller_identity::CallerIdentity;
+use crate::database::{KeyEntry, KeyEntryLoadBits, KeyIdGuard, KeyType, Uuid};
+use crate::error::{map_binder_status, map_or_log_err, Error, ErrorCode, ResponseCode};
+use crate::globals::{DB, LEGACY_MIGRATOR, SUPER_KEY};
+use crate::key_parameter::{KeyParameter, KeyParameterValue};
+use crate::permission::KeyPerm;
+use crate::utils::{check_key_permission, key_parameters_to_authorizations, watchdog as wd};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, Digest::Digest, KeyParameter::KeyParameter as KmKeyParameter,
+    KeyPurpose::KeyPurpose, PaddingMode::PaddingMode, SecurityLevel::SecurityLevel,
+};
+use android_security_odsign::aidl::android::security::odsign::{
+    IKeystoreOdsignKey::{BnKeystoreOdsignKey, IKeystoreOdsignKey},
+    OdsignKeyUsability::OdsignKeyUsability,
+};
+use android_security_odsign::binder::{BinderFeatures, Interface, Result as BinderResult, Strong};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor, KeyMetadata::KeyMetadata,
+};
+use anyhow::{Context, Result};
+
+/// The SELinux namespace of the signing key, odsign_key.
+const ODSIGN_KEY_NAMESPACE: i64 = 101;
+/// The alias of the signing key.
+const ODSIGN_KEY_ALIAS: &str = "ondevice-signing";
+/// The size of the RSA signing key in bits.
+const ODSIGN_KEY_SIZE: i32 = 2048;
+/// The public exponent of the RSA signing key.
+const ODSIGN_KEY_EXPONENT: i64 = 65537;
+
+fn key_descriptor() -> KeyDescriptor {
+    KeyDescriptor {
+        domain: Domain::SELINUX,
+        nspace: ODSIGN_KEY_NAMESPACE,
+        alias: Some(ODSIGN_KEY_ALIAS.to_string()),
+        blob: None,
+    }
+}
+
+fn key_parameters(max_boot_level: i32, attestation_challenge: &[u8]) -> Vec<KmKeyParameter> {
+    let mut params = vec![
+        KeyParameterValue::Algorithm(Algorithm::RSA).into(),
+        KeyParameterValue::KeySize(ODSIGN_KEY_SIZE).into(),
+        KeyParameterValue::RSAPublicExponent(ODSIGN_KEY_EXPONENT).into(),
+        KeyParameterValue::Digest(Digest::SHA_2_256).into(),
+        KeyParameterValue::PaddingMode(PaddingMode::RSA_PKCS1_1_5_SIGN).into(),
+        KeyParameterValue::KeyPurpose(KeyPurpose::SIGN).into(),
+        KeyParameterValue::NoAuthRequired.into(),
+        KeyParameterValue::MaxBootLevel(max_boot_level).into(),
+    ];
+    if !attestation_challenge.is_empty() {
+        params.push(KeyParameterValue::AttestationChallenge(attestation_challenge.to_vec()).into());
+    }
+    params
+}
+
+fn sign_parameters() -> Vec<KmKeyParameter> {
+    vec![
+        KeyParameterValue::Algorithm(Algorithm::RSA).into(),
+        KeyParameterValue::Digest(Digest::SHA_2_256).into(),
+        KeyParameterValue::PaddingMode(PaddingMode::RSA_PKCS1_1_5_SIGN).into(),
+        KeyParameterValue::KeyPurpose(KeyPurpose::SIGN).into(),
+    ]
+}
+
+/// Determines the usability of the signing key given the KeyMint instance `key_uuid` that backs
+/// it and its parameters. `tee_uuid` identifies the Trusted Environment.
+fn usability(
+    key: Option<(&Uuid, &[KeyParameter])>,
+    tee_uuid: &Uuid,
+    max_boot_level: i32,
+    level_accessible: impl Fn(i32) -> bool,
+) -> OdsignKeyUsability {
+    let (key_uuid, params) = match key {
+        Some(key) => key,
+        None => return OdsignKeyUsability::MISSING,
+    };
+    if key_uuid != tee_uuid {
+        return OdsignKeyUsability::WRONG_SECURITY_LEVEL;
+    }
+    let bound_to_level = params
+        .iter()
+        .any(|p| *p.key_parameter_value() == KeyParameterValue::MaxBootLevel(max_boot_level));
+    if !bound_to_level {
+        OdsignKeyUsability::WRONG_BOOT_LEVEL
+    } else if !level_accessible(max_boot_level) {
+        OdsignKeyUsability::BOOT_LEVEL_EXCEEDED
+    } else {
+        OdsignKeyUsability::USABLE
+    }
+}
+
+/// Implementation of the IKeystoreOdsignKey interface.
+pub struct OdsignKeyService;
+
+impl OdsignKeyService {
+    /// Creates a new instance of the odsign key service wrapped in a BnKeystoreOdsignKey proxy
+    /// object.
+    pub fn new_native_binder() -> Result<Strong<dyn IKeystoreOdsignKey>> {
+        Ok(BnKeystoreOdsignKey::new_binder(Self, BinderFeatures::default()))
+    }
+
+    fn load_key(caller: &CallerIdentity) -> Result<Option<(KeyIdGuard, KeyEntry)>> {
+        let key = key_descriptor();
+        let caller_uid = caller.uid();
+        DB.with(|db| {
+            LEGACY_MIGRATOR.with_try_migrate(&key, caller_uid, || {
+                db.borrow_mut().load_key_entry(
+                    &key,
+                    KeyType::Client,
+                    KeyEntryLoadBits::PUBLIC,
+                    caller_uid,
+                    |k, av| check_key_permission(KeyPerm::get_info(), k, &av),
+                )
+            })
+        })
+        .map(Some)
+        .or_else(|e| match e.root_cause().downcast_ref::<Error>() {
+            Some(Error::Rc(ResponseCode::KEY_NOT_FOUND)) => Ok(None),
+            _ => Err(e),
+        })
+        .context("In load_key.")
+    }
+
+    fn key_usability(entry: Option<&KeyEntry>, max_boot_level: i32) -> Result<OdsignKeyUsability> {
+        let tee = async_keygen::get_security_level(SecurityLevel::TRUSTED_ENVIRONMENT)
+            .context("In key_usability.")?;
+        Ok(usability(
+            entry.map(|e| (e.km_uuid(), &e.key_parameters()[..])),
+            tee.km_uuid(),
+            max_boot_level,
+            |level| SUPER_KEY.level_accessible(level),
+        ))
+    }
+
+    fn get_key_usability(max_boot_level: i32) -> Result<OdsignKeyUsability> {
+        let entry = Self::load_key(&CallerIdentity::current()).context("In get_key_usability.")?;
+        Self::key_usability(entry.as_ref().map(|(_, e)| e), max_boot_level)
+            .context("In get_key_usability.")
+    }
+
+    fn generate_key(max_boot_level: i32, attestation_challenge: &[u8]) -> Result<KeyMetadata> {
+        if !SUPER_KEY.level_accessible(max_boot_level) {
+            return Err(Error::Km(ErrorCode::BOOT_LEVEL_EXCEEDED))
+                .context("In generate_key: Boot level is too late.");
+        }
+        let tee = async_keygen::get_security_level(SecurityLevel::TRUSTED_ENVIRONMENT)
+            .context("In generate_key.")?;
+        let params = key_parameters(max_boot_level, attestation_challenge);
+        let request = tee
+            .prepare_generate_key(
+                &key_descriptor(),
+                None,
+                &params,
+                0,
+                &[],
+                &CallerIdentity::current(),
+            )
+            .context("In generate_key.")?;
+        tee.complete_generate_key(request).context("In generate_key.")
+    }
+
+    fn get_or_create_key(max_boot_level: i32, attestation_challenge: &[u8]) -> Result<KeyMetadata> {
+        let entry = Self::load_key(&CallerIdentity::current()).context("In get_or_create_key.")?;
+        let usability = Self::key_usability(entry.as_ref().map(|(_, e)| e), max_boot_level)
+            .context("In get_or_create_key.")?;
+        match (usability, entry) {
+            (OdsignKeyUsability::USABLE, Some((key_id_guard, mut entry))) => Ok(KeyMetadata {
+                key: KeyDescriptor {
+                    domain: Domain::KEY_ID,
+                    nspace: key_id_guard.id(),
+                    ..Default::default()
+                },
+                keySecurityLevel: SecurityLevel::TRUSTED_ENVIRONMENT,
+                certificate: entry.take_cert(),
+                certificateChain: entry.take_cert_chain(),
+                modificationTimeMs: entry
+                    .metadata()
+                    .creation_date()
+                    .map(|d| d.to_millis_epoch())
+                    .ok_or(Error::Rc(ResponseCode::VALUE_CORRUPTED))
+                    .context("In get_or_create_key: Trying to get creation date.")?,
+                authorizations: key_parameters_to_authorizations(entry.into_key_parameters()),
+            }),
+            (usability, _) => {
+                log::info!("In get_or_create_key: Replacing key with usability {:?}.", usability);
+                Self::generate_key(max_boot_level, attestation_challenge)
+                    .context("In get_or_create_key.")
+            }
+        }
+    }
+
+    fn sign(message: &[u8]) -> Result<Vec<u8>> {
+        let tee = async_keygen::get_security_level(SecurityLevel::TRUSTED_ENVIRONMENT)
+            .context("In sign.")?;
+        let response = tee
+            .create_operation(
+                &key_descriptor(),
+                &sign_parameters(),
+                false,
+                None,
+                &CallerIdentity::current(),
+            )
+            .context("In sign: Failed to create operation.")?;
+        let operation = response
+            .iOperation
+            .ok_or_else(Error::sys)
+            .context("In sign: No operation returned.")?;
+        map_binder_status(operation.update(message)).context("In sign: Update failed.")?;
+        map_binder_status(operation.finish(None, None))
+            .context("In sign: Finish failed.")?
+            .ok_or_else(Error::sys)
+            .context("In sign: No signature returned.")
+    }
+}
+
+impl Interface for OdsignKeyService {}
+
+impl IKeystoreOdsignKey for OdsignKeyService {
+    fn getOrCreateKey(
+        &self,
+        max_boot_level: i32,
+        attestation_challenge: &[u8],
+    ) -> BinderResult<KeyMetadata> {
+        let _wp = wd::watch_millis("IKeystoreOdsignKey::getOrCreateKey", 5000);
+        map_or_log_err(Self::get_or_create_key(max_boot_level, attestation_challenge), Ok)
+    }
+
+    fn getKeyUsability(&self, max_boot_level: i32) -> BinderResult<OdsignKeyUsability> {
+        let _wp = wd::watch_millis("IKeystoreOdsignKey::getKeyUsability", 500);
+        map_or_log_err(Self::get_key_usability(max_boot_level), Ok)
+    }
+
+    fn rotateKey(
+        &self,
+        max_boot_level: i32,
+        attestation_challenge: &[u8],
+    ) -> BinderResult<KeyMetadata> {
+        let _wp = wd::watch_millis("IKeystoreOdsignKey::rotateKey", 5000);
+        map_or_log_err(Self::generate_key(max_boot_level, attestation_challenge), Ok)
+    }
+
+    fn sign(&self, message: &[u8]) -> BinderResult<Vec<u8>> {
+        let _wp = wd::watch_millis("IKeystoreOdsignKey::sign", 500);
+        map_or_log_err(Self::sign(message), Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usability() {
+        let tee_uuid = Uuid::from(SecurityLevel::TRUSTED_ENVIRONMENT);
+        let sb_uuid = Uuid::from(SecurityLevel::STRONGBOX);
+        let params = vec![
+            KeyParameter::new(
+                KeyParameterValue::Algorithm(Algorithm::RSA),
+                SecurityLevel::TRUSTED_ENVIRONMENT,
+            ),
+            KeyParameter::new(KeyParameterValue::MaxBootLevel(30), SecurityLevel::KEYSTORE),
+        ];
+        let accessible = |level| level >= 30;
+
+        assert_eq!(usability(None, &tee_uuid, 30, accessible), OdsignKeyUsability::MISSING);
+        assert_eq!(
+            usability(Some((&sb_uuid, &params[..])), &tee_uuid, 30, accessible),
+            OdsignKeyUsability::WRONG_SECURITY_LEVEL
+        );
+        assert_eq!(
+            usability(Some((&tee_uuid, &params[..])), &tee_uuid, 40, accessible),
+            OdsignKeyUsability::WRONG_BOOT_LEVEL
+        );
+        assert_eq!(
+            usability(Some((&tee_uuid, &params[..])), &tee_uuid, 30, |level| level >= 31),
+            OdsignKeyUsability::BOOT_LEVEL_EXCEEDED
+        );
+        assert_eq!(
+            usability(Some((&tee_uuid, &params[..])), &tee_uuid, 30, accessible),
+            OdsignKeyUsability::USABLE
+        );
+    }
+}
@@ -126,8 +126,13 @@
 //! Either way, we have to revaluate the pruning scores.
 
 use crate::enforcements::AuthInfo;
-use crate::error::{map_err_with, map_km_error, map_or_log_err, Error, ErrorCode, ResponseCode};
+use crate::error::{
+    is_transport_error, map_err_with, map_km_error, map_or_log_err, Error, ErrorCode, ResponseCode,
+};
+use crate::globals::note_keymint_transport_error;
 use crate::metrics_store::log_key_operation_event_stats;
+use crate::operation_intents::IntentRecord;
+use crate::trace;
 use crate::utils::{watchdog as wd, Asp};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     IKeyMintOperation::IKeyMintOperation, KeyParameter::KeyParameter, KeyPurpose::KeyPurpose,
@@ -135,16 +140,42 @@ use android_hardware_security_keymint::aidl::android::hardware::security::keymin
 };
 use android_hardware_security_keymint::binder::BinderFeatures;
 use android_system_keystore2::aidl::android::system::keystore2::{
-    IKeystoreOperation::BnKeystoreOperation, IKeystoreOperation::IKeystoreOperation,
+    Domain::Domain, IKeystoreOperation::BnKeystoreOperation, IKeystoreOperation::IKeystoreOperation,
 };
 use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    io::Write,
     sync::{Arc, Mutex, MutexGuard, Weak},
     time::Duration,
     time::Instant,
 };
 
+/// Namespace under which the operations on keys outside of `Domain::SELINUX` are counted.
+/// App namespaces are uids, which must not be reported individually.
+pub const AGGREGATED_NAMESPACE: i64 = -1;
+
+/// The retry hint given to clients if `OperationDb::prune` found no operation to prune.
+pub const PRUNE_RETRY_AFTER: Duration = Duration::from_millis(500);
+
+lazy_static! {
+    /// Number of operations created per key domain and namespace since the counts were last
+    /// taken. Feeds the namespace usage heatmap, see `metrics::report_namespace_usage`.
+    static ref NAMESPACE_OPERATION_COUNTS: Mutex<HashMap<(Domain, i64), u64>> = Default::default();
+}
+
+/// Returns the number of operations created per key domain and namespace since the last call,
+/// and resets the counts.
+pub fn take_namespace_operation_counts() -> HashMap<(Domain, i64), u64> {
+    std::mem::take(&mut *NAMESPACE_OPERATION_COUNTS.lock().unwrap())
+}
+
+fn count_operation_in_namespace(domain: Domain, namespace: i64) {
+    let namespace = if domain == Domain::SELINUX { namespace } else { AGGREGATED_NAMESPACE };
+    *NAMESPACE_OPERATION_COUNTS.lock().unwrap().entry((domain, namespace)).or_insert(0) += 1;
+}
+
 /// Operations have `Outcome::Unknown` as long as they are active. They transition
 /// to one of the other variants exactly once. The distinction in outcome is mainly
 /// for the statistic.
@@ -173,10 +204,13 @@ pub struct Operation {
     km_op: Asp,
     last_usage: Mutex<Instant>,
     outcome: Mutex<Outcome>,
-    owner: u32, // Uid of the operation's owner.
+    owner: u32,     // Uid of the operation's owner.
+    owner_pid: i32, // Pid of the process that created the operation.
     auth_info: Mutex<AuthInfo>,
     forced: bool,
     logging_info: LoggingInfo,
+    // The record of the operation, see `operation_intents`.
+    intent: Mutex<Option<IntentRecord>>,
 }
 
 /// Keeps track of the information required for logging operations.
@@ -186,6 +220,12 @@ pub struct LoggingInfo {
     purpose: KeyPurpose,
     op_params: Vec<KeyParameter>,
     key_upgraded: bool,
+    // Domain and namespace of the key, used for the namespace usage heatmap.
+    key_namespace: (Domain, i64),
+    // Id of the key, resolved from grants, used to abort the operations of a key.
+    key_id: Option<i64>,
+    // Caller supplied label, see `labeled_operations`.
+    label: Option<String>,
 }
 
 impl LoggingInfo {
@@ -195,8 +235,11 @@ impl LoggingInfo {
         purpose: KeyPurpose,
         op_params: Vec<KeyParameter>,
         key_upgraded: bool,
+        key_namespace: (Domain, i64),
+        key_id: Option<i64>,
+        label: Option<String>,
     ) -> LoggingInfo {
-        Self { sec_level, purpose, op_params, key_upgraded }
+        Self { sec_level, purpose, op_params, key_upgraded, key_namespace, key_id, label }
     }
 }
 
@@ -216,6 +259,7 @@ impl Operation {
         index: usize,
         km_op: binder::Strong<dyn IKeyMintOperation>,
         owner: u32,
+        owner_pid: i32,
         auth_info: AuthInfo,
         forced: bool,
         logging_info: LoggingInfo,
@@ -226,9 +270,41 @@ impl Operation {
             last_usage: Mutex::new(Instant::now()),
             outcome: Mutex::new(Outcome::Unknown),
             owner,
+            owner_pid,
             auth_info: Mutex::new(auth_info),
             forced,
             logging_info,
+            intent: Mutex::new(None),
+        }
+    }
+
+    /// Attaches the record of the operation in the database. It is deleted when the operation
+    /// ends.
+    pub fn attach_intent(&self, intent: IntentRecord) {
+        *self.intent.lock().expect("In attach_intent.") = Some(intent);
+    }
+
+    // Deletes the record of the operation in the database. Called when the operation ends
+    // while the Operation object lives on, i.e., when it is aborted or pruned.
+    fn release_intent(&self) {
+        self.intent.lock().expect("In release_intent.").take();
+    }
+
+    fn describe(&self) -> String {
+        match &self.logging_info.label {
+            Some(label) => format!("operation {} of uid {} ({})", self.index, self.owner, label),
+            None => format!("operation {} of uid {}", self.index, self.owner),
+        }
+    }
+
+    /// Sets a watch point for a KeyMint call. Reports about it include the operation's label.
+    fn watch(&self, id: &'static str) -> Option<wd::WatchPoint> {
+        match &self.logging_info.label {
+            Some(label) => {
+                let label = label.clone();
+                wd::watch_millis_with(id, 500, move || format!("label: {}", label))
+            }
+            None => wd::watch_millis(id, 500),
         }
     }
 
@@ -281,6 +357,7 @@ impl Operation {
             return Err(Error::Rc(ResponseCode::OPERATION_BUSY));
         }
         *locked_outcome = Outcome::Pruned;
+        self.release_intent();
 
         let km_op: binder::public_api::Strong<dyn IKeyMintOperation> =
             match self.km_op.get_interface() {
@@ -291,10 +368,14 @@ impl Operation {
                 }
             };
 
-        let _wp = wd::watch_millis("In Operation::prune: calling abort()", 500);
+        log::info!("In prune: Pruning {}.", self.describe());
+        let _wp = self.watch("In Operation::prune: calling abort()");
 
         // We abort the operation. If there was an error we log it but ignore it.
         if let Err(e) = map_km_error(km_op.abort()) {
+            if is_transport_error(&e) {
+                note_keymint_transport_error(&self.logging_info.sec_level);
+            }
             log::error!("In prune: KeyMint::abort failed with {:?}.", e);
         }
 
@@ -307,6 +388,9 @@ impl Operation {
     // The precondition to this call must be *locked_outcome == Outcome::Unknown.
     // Ideally the `locked_outcome` came from a successful call to `check_active`
     // see below.
+    // Operation calls are never retried, because the operation lives in the KeyMint instance
+    // that failed. On a transport error the connection is dropped, so that the next operation
+    // starts on a new connection.
     fn update_outcome<T>(
         &self,
         locked_outcome: &mut Outcome,
@@ -314,7 +398,12 @@ impl Operation {
     ) -> Result<T, Error> {
         match &err {
             Err(Error::Km(e)) => *locked_outcome = Outcome::ErrorCode(*e),
-            Err(_) => *locked_outcome = Outcome::ErrorCode(ErrorCode::UNKNOWN_ERROR),
+            Err(e) => {
+                if is_transport_error(e) {
+                    note_keymint_transport_error(&self.logging_info.sec_level);
+                }
+                *locked_outcome = Outcome::ErrorCode(ErrorCode::UNKNOWN_ERROR)
+            }
             Ok(_) => (),
         }
         err
@@ -373,7 +462,7 @@ impl Operation {
             .context("In update_aad: Trying to get auth tokens.")?;
 
         self.update_outcome(&mut *outcome, {
-            let _wp = wd::watch_millis("Operation::update_aad: calling updateAad", 500);
+            let _wp = self.watch("Operation::update_aad: calling updateAad");
             map_km_error(km_op.updateAad(aad_input, hat.as_ref(), tst.as_ref()))
         })
         .context("In update_aad: KeyMint::update failed.")?;
@@ -400,11 +489,13 @@ impl Operation {
 
         let output = self
             .update_outcome(&mut *outcome, {
-                let _wp = wd::watch_millis("Operation::update: calling update", 500);
+                let _wp = self.watch("Operation::update: calling update");
                 map_km_error(km_op.update(input, hat.as_ref(), tst.as_ref()))
             })
             .context("In update: KeyMint::update failed.")?;
 
+        self.auth_info.lock().unwrap().record_input(input);
+
         if output.is_empty() {
             Ok(None)
         } else {
@@ -424,16 +515,17 @@ impl Operation {
         let km_op: binder::public_api::Strong<dyn IKeyMintOperation> =
             self.km_op.get_interface().context("In finish: Failed to get KeyMintOperation.")?;
 
-        let (hat, tst, confirmation_token) = self
-            .auth_info
-            .lock()
-            .unwrap()
-            .before_finish()
-            .context("In finish: Trying to get auth tokens.")?;
+        let (hat, tst, confirmation_token) = {
+            let mut auth_info = self.auth_info.lock().unwrap();
+            if let Some(input) = input {
+                auth_info.record_input(input);
+            }
+            auth_info.before_finish(self.owner).context("In finish: Trying to get auth tokens.")?
+        };
 
         let output = self
             .update_outcome(&mut *outcome, {
-                let _wp = wd::watch_millis("Operation::finish: calling finish", 500);
+                let _wp = self.watch("Operation::finish: calling finish");
                 map_km_error(km_op.finish(
                     input,
                     signature,
@@ -462,12 +554,20 @@ impl Operation {
     fn abort(&self, outcome: Outcome) -> Result<()> {
         let mut locked_outcome = self.check_active().context("In abort")?;
         *locked_outcome = outcome;
+        self.release_intent();
         let km_op: binder::public_api::Strong<dyn IKeyMintOperation> =
             self.km_op.get_interface().context("In abort: Failed to get KeyMintOperation.")?;
 
         {
-            let _wp = wd::watch_millis("Operation::abort: calling abort", 500);
-            map_km_error(km_op.abort()).context("In abort: KeyMint::abort failed.")
+            let _wp = self.watch("Operation::abort: calling abort");
+            map_km_error(km_op.abort())
+                .map_err(|e| {
+                    if is_transport_error(&e) {
+                        note_keymint_transport_error(&self.logging_info.sec_level);
+                    }
+                    e
+                })
+                .context("In abort: KeyMint::abort failed.")
         }
     }
 }
@@ -509,16 +609,20 @@ impl OperationDb {
     }
 
     /// Creates a new operation.
-    /// This function takes a KeyMint operation and an associated
-    /// owner uid and returns a new Operation wrapped in a `std::sync::Arc`.
+    /// This function takes a KeyMint operation and the associated
+    /// owner uid and pid and returns a new Operation wrapped in a `std::sync::Arc`.
     pub fn create_operation(
         &self,
         km_op: binder::public_api::Strong<dyn IKeyMintOperation>,
         owner: u32,
+        owner_pid: i32,
         auth_info: AuthInfo,
         forced: bool,
         logging_info: LoggingInfo,
     ) -> Arc<Operation> {
+        let (domain, namespace) = logging_info.key_namespace;
+        count_operation_in_namespace(domain, namespace);
+
         // We use unwrap because we don't allow code that can panic while locked.
         let mut operations = self.operations.lock().expect("In create_operation.");
 
@@ -534,6 +638,7 @@ impl OperationDb {
                     index - 1,
                     km_op,
                     owner,
+                    owner_pid,
                     auth_info,
                     forced,
                     logging_info,
@@ -546,6 +651,7 @@ impl OperationDb {
                     operations.len(),
                     km_op,
                     owner,
+                    owner_pid,
                     auth_info,
                     forced,
                     logging_info,
@@ -556,6 +662,104 @@ impl OperationDb {
         }
     }
 
+    /// Writes a line for each live operation to `f`. Used by `IKeystoreMaintenance::dump`.
+    pub fn dump(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        let operations: Vec<Arc<Operation>> = self
+            .operations
+            .lock()
+            .expect("In OperationDb::dump.")
+            .iter()
+            .filter_map(|op| op.upgrade())
+            .collect();
+        for op in operations {
+            // Expect safety: See `Operation::get_pruning_info`.
+            let idle = op.last_usage.lock().expect("In OperationDb::dump.").elapsed();
+            writeln!(
+                f,
+                "  {}: purpose: {:?} forced: {} idle: {}s",
+                op.describe(),
+                op.logging_info.purpose,
+                op.forced,
+                idle.as_secs()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Prunes the operations that were idle for at least `min_idle` and whose owning process
+    /// is gone according to `is_alive`, which is called with the pid and uid of the owner.
+    /// This frees the KeyMint operation slots held by crashed clients without waiting for
+    /// pruning pressure. Returns the number of reaped operations.
+    pub fn reap_orphaned<F>(&self, min_idle: Duration, is_alive: F) -> usize
+    where
+        F: Fn(i32, u32) -> bool,
+    {
+        let operations: Vec<Arc<Operation>> = self
+            .operations
+            .lock()
+            .expect("In OperationDb::reap_orphaned.")
+            .iter()
+            .filter_map(|op| op.upgrade())
+            .collect();
+        let mut reaped = 0;
+        for op in operations {
+            let last_usage = match op.get_pruning_info() {
+                Some(PruningInfo { last_usage, .. }) => last_usage,
+                None => continue,
+            };
+            if last_usage.elapsed() < min_idle || is_alive(op.owner_pid, op.owner) {
+                continue;
+            }
+            log::info!("In reap_orphaned: The owner of {} is gone.", op.describe());
+            // If the operation was used or finalized in the meantime, prune fails and the
+            // operation is left alone.
+            if op.prune(last_usage).is_ok() {
+                reaped += 1;
+            }
+        }
+        reaped
+    }
+
+    /// Aborts all active operations, e.g., before Keystore shuts down, so that no KeyMint
+    /// operation slots are left behind. Clients get `ErrorCode::INVALID_OPERATION_HANDLE` on
+    /// their next call. Returns the number of aborted operations.
+    pub fn abort_all(&self) -> usize {
+        self.abort_where(|_| true)
+    }
+
+    /// Aborts the active operations with the given keys, e.g., when their namespace is frozen.
+    /// This includes the operations of grantees, because keys are matched by their id. Returns
+    /// the number of aborted operations.
+    pub fn abort_keys(&self, key_ids: &HashSet<i64>) -> usize {
+        self.abort_where(|op| op.logging_info.key_id.map_or(false, |id| key_ids.contains(&id)))
+    }
+
+    fn abort_where<F>(&self, filter: F) -> usize
+    where
+        F: Fn(&Operation) -> bool,
+    {
+        let operations: Vec<Arc<Operation>> = self
+            .operations
+            .lock()
+            .expect("In OperationDb::abort_where.")
+            .iter()
+            .filter_map(|op| op.upgrade())
+            .filter(|op| filter(op))
+            .collect();
+        let mut aborted = 0;
+        for op in operations {
+            // Finalized operations cannot be aborted, which is fine.
+            match op.abort(Outcome::Abort) {
+                Ok(()) => aborted += 1,
+                Err(e) => match e.root_cause().downcast_ref::<Error>() {
+                    Some(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE)) => {}
+                    _ => log::warn!("In abort_where: Failed to abort {}: {:?}", op.describe(), e),
+                },
+            }
+        }
+        aborted
+    }
+
     fn get(&self, index: usize) -> Option<Arc<Operation>> {
         self.operations.lock().expect("In OperationDb::get.").get(index).and_then(|op| op.upgrade())
     }
@@ -842,6 +1046,7 @@ impl binder::Interface for KeystoreOperation {}
 
 impl IKeystoreOperation for KeystoreOperation {
     fn updateAad(&self, aad_input: &[u8]) -> binder::public_api::Result<()> {
+        let _trace = trace::begin();
         let _wp = wd::watch_millis("IKeystoreOperation::updateAad", 500);
         map_or_log_err(
             self.with_locked_operation(
@@ -853,6 +1058,7 @@ impl IKeystoreOperation for KeystoreOperation {
     }
 
     fn update(&self, input: &[u8]) -> binder::public_api::Result<Option<Vec<u8>>> {
+        let _trace = trace::begin();
         let _wp = wd::watch_millis("IKeystoreOperation::update", 500);
         map_or_log_err(
             self.with_locked_operation(
@@ -867,6 +1073,7 @@ impl IKeystoreOperation for KeystoreOperation {
         input: Option<&[u8]>,
         signature: Option<&[u8]>,
     ) -> binder::public_api::Result<Option<Vec<u8>>> {
+        let _trace = trace::begin();
         let _wp = wd::watch_millis("IKeystoreOperation::finish", 500);
         map_or_log_err(
             self.with_locked_operation(
@@ -878,6 +1085,7 @@ impl IKeystoreOperation for KeystoreOperation {
     }
 
     fn abort(&self) -> binder::public_api::Result<()> {
+        let _trace = trace::begin();
         let _wp = wd::watch_millis("IKeystoreOperation::abort", 500);
         map_err_with(
             self.with_locked_operation(
@@ -890,7 +1098,10 @@ impl IKeystoreOperation for KeystoreOperation {
                     // There is no reason to clutter the log with it. It is never the cause
                     // for a true problem.
                     Some(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE)) => {}
-                    _ => log::error!("{:?}", e),
+                    _ => match trace::current() {
+                        Some(trace_id) => log::error!("trace: {} {:?}", trace_id, e),
+                        None => log::error!("{:?}", e),
+                    },
                 };
                 e
             },
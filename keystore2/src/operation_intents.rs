@@ -0,0 +1,367 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module records the operations that are in progress, so that their loss can be handled
+//! if Keystore restarts during a boot. A record holds the caller, the key, a digest of the
+//! operation parameters, and the challenge returned by `begin`. It is written when the operation
+//! begins and deleted when the operation ends.
+//!
+//! The records only need to survive a crash of Keystore, not a reboot. They are kept in a
+//! database of their own next to the persistent database, which is written with
+//! `synchronous = OFF`: a commit is complete once it reached the page cache of the kernel, so
+//! beginning and ending operations costs no flash sync and never waits for the lock of the
+//! persistent database.
+//!
+//! The clients of a crashed instance only observe dead operation binders. On startup, the
+//! records left behind are reported to their callers as `KeystoreErrorCode::OPERATION_LOST`
+//! through `IKeystoreErrorDetails`. Keymaster 4.x devices keep the operations of the crashed
+//! instance in their slots, because the legacy wrapper ended with Keystore. These operations
+//! are aborted by their operation handle, which is the challenge. When a caller begins the same
+//! operation again, the lost operation is considered retried.
+
+use crate::error::KeystoreErrorCode;
+use crate::error_details;
+use crate::external_keys::EXTERNAL_SECURITY_LEVEL;
+#[cfg(feature = "embedded")]
+use crate::globals::IN_MEMORY_DB;
+use crate::globals::{abort_orphaned_operation, ASYNC_TASK, DB_PATH};
+use crate::utils::watchdog as wd;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyParameter::KeyParameter, KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
+};
+use android_security_errors::aidl::android::security::errors::ErrorDetails::ErrorDetails;
+use anyhow::{Context, Result};
+use keystore2_crypto::sha256;
+use lazy_static::lazy_static;
+use rusqlite::{params, Connection, NO_PARAMS};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Name of the file in `DB_PATH` that holds the records.
+const INTENT_DB_FILENAME: &str = "operationintents.sqlite";
+
+lazy_static! {
+    /// Operations lost with the previous instance of Keystore that were not retried yet.
+    static ref LOST_OPERATIONS: Mutex<Vec<OperationIntent>> = Default::default();
+    /// The store of the records. It is opened on first use.
+    static ref STORE: Mutex<Option<IntentStore>> = Default::default();
+}
+
+/// An operation that began and did not end yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperationIntent {
+    /// The uid of the caller that began the operation.
+    pub uid: u32,
+    /// The key entry id of the key, or None for `Domain::BLOB` keys.
+    pub key_id: Option<i64>,
+    /// Digest of the operation parameters, see `params_digest`.
+    pub params_digest: Vec<u8>,
+    /// The security level of the KeyMint device that performs the operation.
+    pub sec_level: SecurityLevel,
+    /// The challenge returned by `begin`. For Keymaster 4.x devices it is the operation handle.
+    pub challenge: i64,
+}
+
+/// The database of the records. See the module documentation for why it is not synced.
+struct IntentStore {
+    conn: Connection,
+}
+
+impl IntentStore {
+    fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path).context("In IntentStore::open: Failed to open.")?;
+        Self::init(conn)
+    }
+
+    #[cfg(feature = "embedded")]
+    fn open_in_memory() -> Result<Self> {
+        let conn = Connection::open_in_memory()
+            .context("In IntentStore::open_in_memory: Failed to open.")?;
+        Self::init(conn)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        // Write-ahead logging keeps the database intact if Keystore dies during a commit, even
+        // though nothing is synced.
+        conn.query_row("PRAGMA journal_mode = WAL;", NO_PARAMS, |_| Ok(()))
+            .context("In IntentStore::init: Failed to enable write-ahead logging.")?;
+        conn.execute_batch(
+            "PRAGMA synchronous = OFF;
+             CREATE TABLE IF NOT EXISTS operationintent (
+                 id INTEGER PRIMARY KEY,
+                 uid INTEGER,
+                 keyentryid INTEGER,
+                 params_digest BLOB,
+                 security_level INTEGER,
+                 challenge INTEGER);",
+        )
+        .context("In IntentStore::init: Failed to initialize.")?;
+        Ok(Self { conn })
+    }
+
+    fn insert(&mut self, intent: &OperationIntent) -> Result<i64> {
+        let _wp = wd::watch_millis("IntentStore::insert", 500);
+
+        self.conn
+            .execute(
+                "INSERT INTO operationintent
+                    (uid, keyentryid, params_digest, security_level, challenge)
+                    VALUES (?, ?, ?, ?, ?);",
+                params![
+                    intent.uid,
+                    intent.key_id,
+                    intent.params_digest,
+                    intent.sec_level.0,
+                    intent.challenge
+                ],
+            )
+            .context("In IntentStore::insert.")?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    fn delete(&mut self, id: i64) -> Result<()> {
+        let _wp = wd::watch_millis("IntentStore::delete", 500);
+
+        self.conn
+            .execute("DELETE FROM operationintent WHERE id = ?;", params![id])
+            .context("In IntentStore::delete.")?;
+        Ok(())
+    }
+
+    /// Returns all records and deletes them.
+    fn take_all(&mut self) -> Result<Vec<OperationIntent>> {
+        let _wp = wd::watch_millis("IntentStore::take_all", 500);
+
+        let tx = self.conn.transaction().context("In IntentStore::take_all.")?;
+        let intents = tx
+            .prepare(
+                "SELECT uid, keyentryid, params_digest, security_level, challenge
+                 FROM operationintent ORDER BY id;",
+            )
+            .context("In IntentStore::take_all: Failed to prepare.")?
+            .query_map(NO_PARAMS, |row| {
+                Ok(OperationIntent {
+                    uid: row.get(0)?,
+                    key_id: row.get(1)?,
+                    params_digest: row.get(2)?,
+                    sec_level: SecurityLevel(row.get(3)?),
+                    challenge: row.get(4)?,
+                })
+            })
+            .context("In IntentStore::take_all: Failed to query.")?
+            .collect::<rusqlite::Result<Vec<OperationIntent>>>()
+            .context("In IntentStore::take_all: Failed to read records.")?;
+        tx.execute("DELETE FROM operationintent;", NO_PARAMS)
+            .context("In IntentStore::take_all: Failed to delete records.")?;
+        tx.commit().context("In IntentStore::take_all: Failed to commit.")?;
+        Ok(intents)
+    }
+}
+
+fn open_store() -> Result<IntentStore> {
+    // The embedded mode runs in the process of its client, so records cannot outlive it.
+    #[cfg(feature = "embedded")]
+    if IN_MEMORY_DB.read().unwrap().is_some() {
+        return IntentStore::open_in_memory();
+    }
+    IntentStore::open(&DB_PATH.read().unwrap().join(INTENT_DB_FILENAME))
+}
+
+fn with_store<T>(f: impl FnOnce(&mut IntentStore) -> Result<T>) -> Result<T> {
+    let mut store = STORE.lock().unwrap();
+    if store.is_none() {
+        *store = Some(open_store().context("In with_store: Failed to open the store.")?);
+    }
+    f(store.as_mut().unwrap())
+}
+
+/// Deletes the record of an operation when dropped. It is owned by the `Operation`, which
+/// releases it when the operation ends.
+#[derive(Debug)]
+pub struct IntentRecord(i64);
+
+impl Drop for IntentRecord {
+    fn drop(&mut self) {
+        if let Err(e) = with_store(|store| store.delete(self.0)) {
+            log::error!("In IntentRecord::drop: Failed to delete operation intent: {:?}", e);
+        }
+    }
+}
+
+/// Computes the digest of the purpose and the parameters of an operation. It only needs to be
+/// stable across restarts of Keystore within a boot, so their debug representation is hashed.
+pub fn params_digest(purpose: KeyPurpose, params: &[KeyParameter]) -> Result<Vec<u8>> {
+    sha256(format!("{:?} {:?}", purpose, params).as_bytes()).context("In params_digest.")
+}
+
+/// Records an operation that began. Failures are logged and yield `None`, because they must
+/// not fail the operation. If the caller lost the same operation when Keystore restarted, the
+/// lost operation is considered retried.
+pub fn record(
+    uid: u32,
+    key_id: Option<i64>,
+    purpose: KeyPurpose,
+    params: &[KeyParameter],
+    sec_level: SecurityLevel,
+    challenge: i64,
+) -> Option<IntentRecord> {
+    let intent = match params_digest(purpose, params) {
+        Ok(params_digest) => OperationIntent { uid, key_id, params_digest, sec_level, challenge },
+        Err(e) => {
+            log::error!("In record: {:?}", e);
+            return None;
+        }
+    };
+    note_retry(&intent);
+    match with_store(|store| store.insert(&intent)) {
+        Ok(id) => Some(IntentRecord(id)),
+        Err(e) => {
+            log::error!("In record: Failed to insert operation intent: {:?}", e);
+            None
+        }
+    }
+}
+
+fn note_retry(intent: &OperationIntent) {
+    let mut lost = LOST_OPERATIONS.lock().unwrap();
+    if let Some(pos) = lost.iter().position(|l| is_same_operation(l, intent)) {
+        lost.swap_remove(pos);
+        log::info!("Uid {} retried an operation that was lost in a restart.", intent.uid);
+    }
+}
+
+fn is_same_operation(a: &OperationIntent, b: &OperationIntent) -> bool {
+    a.uid == b.uid
+        && a.key_id == b.key_id
+        && a.params_digest == b.params_digest
+        && a.sec_level == b.sec_level
+}
+
+fn operation_lost() -> ErrorDetails {
+    ErrorDetails {
+        code: KeystoreErrorCode::OPERATION_LOST.0,
+        subsystem: "keystore".to_string(),
+        retryable: true,
+        retryAfterMillis: -1,
+        halErrorCode: 0,
+        traceId: None,
+        errorId: 0,
+    }
+}
+
+/// Handles the records that the previous instance of Keystore left behind. `restarted` is the
+/// result of `metrics_store::update_keystore_crash_sysprop`. If Keystore restarted during this
+/// boot, the recorded operations were lost with the previous instance. Otherwise, the records
+/// stem from a previous boot and are discarded.
+pub fn check_on_startup(restarted: bool) {
+    let intents = match with_store(|store| store.take_all()) {
+        Ok(intents) => intents,
+        Err(e) => {
+            log::error!("In check_on_startup: Failed to load operation intents: {:?}", e);
+            return;
+        }
+    };
+    if !restarted || intents.is_empty() {
+        return;
+    }
+    log::warn!("{} operations were lost when Keystore restarted.", intents.len());
+    for intent in &intents {
+        error_details::record_for_uid(intent.uid, operation_lost());
+    }
+    // The operations of external key providers are not held by the legacy wrapper.
+    let orphans: Vec<(SecurityLevel, i64)> = intents
+        .iter()
+        .filter(|intent| intent.sec_level != EXTERNAL_SECURITY_LEVEL)
+        .map(|intent| (intent.sec_level, intent.challenge))
+        .collect();
+    *LOST_OPERATIONS.lock().unwrap() = intents;
+
+    // Connecting to the KeyMint devices may take a while, so it is not done on startup.
+    ASYNC_TASK.queue_lo(move |_| {
+        for (sec_level, challenge) in orphans {
+            if let Err(e) = abort_orphaned_operation(&sec_level, challenge) {
+                log::warn!("Failed to abort orphaned operation on {:?}: {:?}", sec_level, e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+        Digest::Digest, KeyParameterValue::KeyParameterValue, Tag::Tag,
+    };
+    use keystore2_test_utils::TempDir;
+
+    fn digest(digest: Digest) -> KeyParameter {
+        KeyParameter { tag: Tag::DIGEST, value: KeyParameterValue::Digest(digest) }
+    }
+
+    #[test]
+    fn params_digest_distinguishes_operations() -> Result<()> {
+        let sha256 = [digest(Digest::SHA_2_256)];
+        let sha512 = [digest(Digest::SHA_2_512)];
+        let sign = KeyPurpose::SIGN;
+        assert_eq!(params_digest(sign, &sha256)?, params_digest(sign, &sha256)?);
+        assert_ne!(params_digest(sign, &sha256)?, params_digest(sign, &sha512)?);
+        assert_ne!(params_digest(sign, &sha256)?, params_digest(sign, &[])?);
+        assert_ne!(params_digest(sign, &sha256)?, params_digest(KeyPurpose::VERIFY, &sha256)?);
+        Ok(())
+    }
+
+    #[test]
+    fn store_returns_open_records() -> Result<()> {
+        let temp_dir = TempDir::new("operation_intents_test")?;
+        let mut store = IntentStore::open(&temp_dir.path().join(INTENT_DB_FILENAME))?;
+        let first = OperationIntent {
+            uid: 10001,
+            key_id: Some(17),
+            params_digest: vec![1, 2, 3],
+            sec_level: SecurityLevel::TRUSTED_ENVIRONMENT,
+            challenge: -4711,
+        };
+        let second = OperationIntent {
+            uid: 10002,
+            key_id: None,
+            params_digest: vec![4, 5, 6],
+            sec_level: SecurityLevel::STRONGBOX,
+            challenge: 0,
+        };
+        let third = OperationIntent { uid: 10003, ..second.clone() };
+        store.insert(&first)?;
+        let second_id = store.insert(&second)?;
+        store.insert(&third)?;
+        store.delete(second_id)?;
+
+        assert_eq!(store.take_all()?, vec![first, third]);
+        assert!(store.take_all()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn same_operation_ignores_challenge() {
+        let a = OperationIntent {
+            uid: 10001,
+            key_id: Some(1),
+            params_digest: vec![1],
+            sec_level: SecurityLevel::TRUSTED_ENVIRONMENT,
+            challenge: 1,
+        };
+        assert!(is_same_operation(&a, &OperationIntent { challenge: 2, ..a.clone() }));
+        assert!(!is_same_operation(&a, &OperationIntent { uid: 10002, ..a.clone() }));
+        assert!(!is_same_operation(&a, &OperationIntent { key_id: None, ..a.clone() }));
+        assert!(!is_same_operation(&a, &OperationIntent { params_digest: vec![2], ..a.clone() }));
+    }
+}
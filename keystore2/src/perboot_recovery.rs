@@ -0,0 +1,160 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the recovery from the loss of the per-boot database. The per-boot
+//! database holds the auth tokens Keystore received since boot. It lives in memory, so it is
+//! lost if Keystore restarts during a boot, e.g., after being killed on a low memory device.
+//! Keystore then starts over with an empty per-boot database, and auth bound keys cannot be
+//! used until fresh auth tokens are added. Instead of waiting for the user to authenticate
+//! again, Keystore informs the registered `IAuthTokenRecoveryListener`, i.e., system_server,
+//! which can request fresh auth tokens from Gatekeeper and the biometric authenticators.
+//! A pending loss is reported as soon as a listener is registered.
+
+use crate::metrics_store::log_perboot_recovery_stats;
+use android_security_authorization::aidl::android::security::authorization::IAuthTokenRecoveryListener::IAuthTokenRecoveryListener;
+use android_security_authorization::binder::Strong;
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+#[derive(Default)]
+struct RecoveryState {
+    listener: Option<Strong<dyn IAuthTokenRecoveryListener>>,
+    // True if the per-boot database was lost and the listener was not informed yet.
+    loss_pending: bool,
+}
+
+lazy_static! {
+    static ref RECOVERY_STATE: Mutex<RecoveryState> = Default::default();
+}
+
+impl RecoveryState {
+    fn inform_listener(&mut self) {
+        if !self.loss_pending {
+            return;
+        }
+        if let Some(listener) = &self.listener {
+            match listener.onAuthTokensLost() {
+                Ok(()) => {
+                    self.loss_pending = false;
+                    log_perboot_recovery_stats(true);
+                }
+                Err(e) => log::warn!("In inform_listener: Failed to inform listener: {:?}", e),
+            }
+        }
+    }
+}
+
+/// Records the loss of the per-boot database and informs the listener, if registered.
+pub fn note_perboot_loss() {
+    log::warn!("The per-boot database was lost. Auth bound keys need fresh auth tokens.");
+    log_perboot_recovery_stats(false);
+    let mut state = RECOVERY_STATE.lock().unwrap();
+    state.loss_pending = true;
+    state.inform_listener();
+}
+
+/// Checks on startup whether Keystore restarted during this boot, which means that the
+/// per-boot database of the previous instance was lost. Clean restarts lose it as well.
+/// `restarted` is the result of `metrics_store::update_keystore_crash_sysprop`.
+pub fn check_on_startup(restarted: bool) {
+    if restarted {
+        note_perboot_loss();
+    }
+}
+
+/// Registers the listener, replacing any previously registered listener. `None` unregisters
+/// the listener. A pending loss is reported to the new listener right away.
+pub fn set_listener(listener: Option<&Strong<dyn IAuthTokenRecoveryListener>>) {
+    let mut state = RECOVERY_STATE.lock().unwrap();
+    state.listener = listener.cloned();
+    state.inform_listener();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_security_authorization::aidl::android::security::authorization::IAuthTokenRecoveryListener::BnAuthTokenRecoveryListener;
+    use android_security_authorization::binder::{
+        BinderFeatures, Interface, Result as BinderResult, Status as BinderStatus,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct TestListener {
+        calls: Arc<AtomicUsize>,
+        fail: bool,
+    }
+
+    impl Interface for TestListener {}
+
+    impl IAuthTokenRecoveryListener for TestListener {
+        fn onAuthTokensLost(&self) -> BinderResult<()> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            if self.fail {
+                Err(BinderStatus::new_service_specific_error(1, None))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn new_listener(fail: bool) -> (Strong<dyn IAuthTokenRecoveryListener>, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let listener = BnAuthTokenRecoveryListener::new_binder(
+            TestListener { calls: calls.clone(), fail },
+            BinderFeatures::default(),
+        );
+        (listener, calls)
+    }
+
+    #[test]
+    fn test_no_loss_is_not_reported() {
+        let (listener, calls) = new_listener(false);
+        let mut state = RecoveryState { listener: Some(listener), loss_pending: false };
+        state.inform_listener();
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_pending_loss_is_reported_once_listener_registers() {
+        let mut state = RecoveryState { listener: None, loss_pending: true };
+        state.inform_listener();
+        assert!(state.loss_pending);
+
+        let (listener, calls) = new_listener(false);
+        state.listener = Some(listener);
+        state.inform_listener();
+        assert!(!state.loss_pending);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // The loss was reported, so it is not reported again.
+        state.inform_listener();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_loss_stays_pending_if_listener_fails() {
+        let (listener, calls) = new_listener(true);
+        let mut state = RecoveryState { listener: Some(listener), loss_pending: true };
+        state.inform_listener();
+        assert!(state.loss_pending);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        let (listener, calls) = new_listener(false);
+        state.listener = Some(listener);
+        state.inform_listener();
+        assert!(!state.loss_pending);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+}
@@ -23,33 +23,227 @@ use android_system_keystore2::aidl::android::system::keystore2::{
 };
 
 use std::cmp::PartialEq;
-use std::convert::From;
+use std::collections::VecDeque;
+use std::convert::{From, TryFrom};
 use std::ffi::CStr;
+use std::fmt;
+use std::sync::Mutex;
 
+use crate::access_control;
+use crate::access_group;
+use crate::denial_limiter::DenialSuppressed;
 use crate::error::Error as KsError;
+use crate::key_policy;
+use crate::metrics_store::log_unknown_key_namespace_stats;
+use crate::tenants;
+use crate::utils::AID_USER_OFFSET;
 use keystore2_selinux as selinux;
+use keystore2_system_property::PropertyWatcher;
 
-use anyhow::Context as AnyhowContext;
-
-use selinux::Backend;
+use anyhow::{anyhow, Context as AnyhowContext};
 
 use lazy_static::lazy_static;
 
-// Replace getcon with a mock in the test situation
-#[cfg(not(test))]
-use selinux::getcon;
-#[cfg(test)]
-use tests::test_getcon as getcon;
-
 lazy_static! {
-    // Panicking here is allowed because keystore cannot function without this backend
-    // and it would happen early and indicate a gross misconfiguration of the device.
-    static ref KEYSTORE2_KEY_LABEL_BACKEND: selinux::KeystoreKeyBackend =
-            selinux::KeystoreKeyBackend::new().unwrap();
+    /// The most recently seen namespaces without keystore2_key context, most recent last.
+    static ref UNKNOWN_NAMESPACES: Mutex<VecDeque<i64>> = Default::default();
+    /// The configured derivation of the level of `Domain::APP` target contexts.
+    static ref APP_KEY_LEVEL_FROM: LevelFrom = LevelFrom::configured();
+}
+
+/// System property selecting how the MLS level of the target context of `Domain::APP` keys is
+/// derived from the uid of the key owner. It takes the values of the `levelFrom` selector of
+/// seapp_contexts, i.e., "none", "user", "app", or "all". If absent, Keystore's own context
+/// is used as is.
+const APP_KEY_LEVEL_FROM_PROPERTY: &str = "ro.keystore.app_key_level_from";
+
+/// The first application uid within a user. Uids below are system uids.
+pub(crate) const AID_APP_START: u32 = 10000;
+
+/// Number of distinct unknown namespaces that are remembered for dumpsys.
+const UNKNOWN_NAMESPACE_HISTORY: usize = 16;
+
+/// Indicates that a request was denied, because the given SELinux namespace has no entry in
+/// keystore2_key_contexts. Such requests fail closed, i.e., they are reported as
+/// `ResponseCode::PERMISSION_DENIED`.
+#[derive(Debug, thiserror::Error, PartialEq)]
+#[error("SELinux namespace {0} has no keystore2_key context.")]
+pub struct UnknownNamespace(pub i64);
+
+/// Indicates that a permission check failed. Unlike a bare `selinux::Error::PermissionDenied`
+/// this names the denied permission, so that the service layer and audit logging do not need
+/// to parse the error context. It is reported as `ResponseCode::PERMISSION_DENIED`.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Permission {class}:{perm} denied for {source_ctx:?} on {target_ctx:?}.")]
+pub struct PermissionDenied {
+    /// The security class of the denied permission, e.g., "keystore2_key".
+    pub class: &'static str,
+    /// The denied permission, e.g., "use".
+    pub perm: &'static str,
+    /// The SELinux context of the caller.
+    pub source_ctx: String,
+    /// The SELinux context of the target. This is None if the permission was not denied by the
+    /// SELinux policy but by Keystore itself, e.g., because the caller does not own the key or
+    /// because a grant does not cover the permission.
+    pub target_ctx: Option<String>,
+}
+
+impl PermissionDenied {
+    fn new(class: &'static str, perm: &'static str, source: &CStr, target: Option<&CStr>) -> Self {
+        Self {
+            class,
+            perm,
+            source_ctx: source.to_string_lossy().into_owned(),
+            target_ctx: target.map(|t| t.to_string_lossy().into_owned()),
+        }
+    }
+}
+
+/// Returns true if the root cause of `e` is a permission denial, i.e., a `PermissionDenied`,
+/// a `selinux::Error::PermissionDenied`, or a denial suppressed by `denial_limiter`.
+pub fn is_permission_denied(e: &anyhow::Error) -> bool {
+    let root_cause = e.root_cause();
+    root_cause.is::<PermissionDenied>()
+        || root_cause.is::<DenialSuppressed>()
+        || matches!(
+            root_cause.downcast_ref::<selinux::Error>(),
+            Some(selinux::Error::PermissionDenied)
+        )
+}
+
+/// Checks the access with the installed `AccessControl` backend and reports denials as
+/// `PermissionDenied`.
+fn check_access(
+    source: &CStr,
+    target: &CStr,
+    class: &'static str,
+    perm: &'static str,
+) -> anyhow::Result<()> {
+    access_control::get().check_access(source, target, class, perm).map_err(|e| {
+        if is_permission_denied(&e) {
+            anyhow!(PermissionDenied::new(class, perm, source, Some(target)))
+        } else {
+            e
+        }
+    })
+}
+
+/// Returns the context of Keystore as reported by the installed `AccessControl` backend.
+fn getcon() -> anyhow::Result<selinux::Context> {
+    access_control::get().keystore_context()
+}
+
+/// Selects the categories of the MLS level derived from a uid like the `levelFrom` selector of
+/// seapp_contexts, so that the target context of app keys carries the same categories as the
+/// app itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LevelFrom {
+    /// Keystore's own level is used for all app keys.
+    None,
+    /// The level has the categories of the Android user of the key owner.
+    User,
+    /// The level has the categories of the app id of the key owner.
+    App,
+    /// The level has the categories of both the app id and the Android user of the key owner.
+    All,
+}
+
+impl LevelFrom {
+    /// Parses a `levelFrom` value of seapp_contexts.
+    pub fn from_selector(selector: &str) -> Option<Self> {
+        match selector {
+            "none" => Some(Self::None),
+            "user" => Some(Self::User),
+            "app" => Some(Self::App),
+            "all" => Some(Self::All),
+            _ => None,
+        }
+    }
+
+    fn configured() -> Self {
+        let selector = PropertyWatcher::new(APP_KEY_LEVEL_FROM_PROPERTY)
+            .ok()
+            .and_then(|mut w| w.read(|_n, v| Ok(v.to_string())).ok());
+        match selector {
+            None => Self::None,
+            Some(selector) => Self::from_selector(&selector).unwrap_or_else(|| {
+                log::error!(
+                    "Invalid value \"{}\" of {}. Using Keystore's level for app keys.",
+                    selector,
+                    APP_KEY_LEVEL_FROM_PROPERTY
+                );
+                Self::None
+            }),
+        }
+    }
+
+    /// Returns the MLS level for the given uid as computed by libselinux for seapp_contexts
+    /// entries with this selector, e.g., "s0:c512,c768" for `LevelFrom::User` and user 0.
+    /// Returns None if no level is derived for this uid, e.g., because it is a system uid.
+    pub fn level(self, uid: u32) -> Option<String> {
+        let user_id = uid / AID_USER_OFFSET;
+        let app_id = match uid % AID_USER_OFFSET {
+            app_id if app_id >= AID_APP_START => app_id - AID_APP_START,
+            _ => return None,
+        };
+        let app_categories = format!("c{},c{}", app_id & 0xff, 256 + ((app_id >> 8) & 0xff));
+        let user_categories =
+            format!("c{},c{}", 512 + (user_id & 0xff), 768 + ((user_id >> 8) & 0xff));
+        match self {
+            Self::None => None,
+            Self::User => Some(format!("s0:{}", user_categories)),
+            Self::App => Some(format!("s0:{}", app_categories)),
+            Self::All => Some(format!("s0:{},{}", app_categories, user_categories)),
+        }
+    }
+}
+
+/// Replaces the level of `context` with `level`.
+fn with_level(context: &CStr, level: &str) -> anyhow::Result<selinux::Context> {
+    let context = context.to_str().context("In with_level: Context is not UTF-8.")?;
+    let fields: Vec<&str> = context.splitn(4, ':').collect();
+    if fields.len() < 3 {
+        return Err(anyhow!("In with_level: Malformed context \"{}\".", context));
+    }
+    selinux::Context::new(&format!("{}:{}:{}:{}", fields[0], fields[1], fields[2], level))
+        .context("In with_level.")
+}
+
+/// Returns the target context of the keys in `Domain::APP` owned by `owner`. This is Keystore's
+/// context, unless `APP_KEY_LEVEL_FROM_PROPERTY` selects a level derived from the owner's uid.
+fn app_key_context(owner: i64) -> anyhow::Result<selinux::Context> {
+    let context = getcon().context("In app_key_context: getcon failed.")?;
+    match u32::try_from(owner).ok().and_then(|uid| APP_KEY_LEVEL_FROM.level(uid)) {
+        Some(level) => with_level(&context, &level).context("In app_key_context."),
+        None => Ok(context),
+    }
 }
 
 fn lookup_keystore2_key_context(namespace: i64) -> anyhow::Result<selinux::Context> {
-    KEYSTORE2_KEY_LABEL_BACKEND.lookup(&namespace.to_string())
+    match access_control::get().key_context(namespace)? {
+        Some(context) => Ok(context),
+        None => {
+            note_unknown_namespace(namespace);
+            Err(anyhow!(UnknownNamespace(namespace)))
+        }
+    }
+}
+
+fn note_unknown_namespace(namespace: i64) {
+    log::warn!("SELinux namespace {} has no keystore2_key context.", namespace);
+    log_unknown_key_namespace_stats(namespace);
+    let mut recent = UNKNOWN_NAMESPACES.lock().unwrap();
+    recent.retain(|n| *n != namespace);
+    if recent.len() >= UNKNOWN_NAMESPACE_HISTORY {
+        recent.pop_front();
+    }
+    recent.push_back(namespace);
+}
+
+/// Returns the most recently seen SELinux namespaces without keystore2_key context, most
+/// recent last.
+pub fn recent_unknown_namespaces() -> Vec<i64> {
+    UNKNOWN_NAMESPACES.lock().unwrap().iter().copied().collect()
 }
 
 /// ## Background
@@ -71,12 +265,19 @@ fn lookup_keystore2_key_context(namespace: i64) -> anyhow::Result<selinux::Conte
 ///    representation.
 ///  * `MyPerm.to_selinux(&self)` returns the SELinux string representation of the
 ///    represented permission.
+///  * `MyPerm::SELINUX_NAMES` lists the SELinux names of all variants but the default.
 ///
 /// ## Special behavior
 /// If the keyword `use` appears as an selinux name `use_` is used as identifier for the
 /// constructor function (e.g. `MePerm::use_()`) but the string returned by `to_selinux` will
 /// still be `"use"`.
 ///
+/// ## Extension permissions
+/// An element of the form `NAME = <value>, selinux name: <selinux_name>;` defines a
+/// permission that the AIDL enum does not have, e.g., because the enum is frozen. It is
+/// represented by the AIDL value `<value>`, which must not collide with a variant of the enum,
+/// and is otherwise treated like a variant.
+///
 /// ## Example
 /// ```
 ///
@@ -86,6 +287,7 @@ fn lookup_keystore2_key_context(namespace: i64) -> anyhow::Result<selinux::Conte
 ///     MyPerm from EnumName with default (None, none) {}
 ///         Variant1,    selinux name: variant1;
 ///         Variant2,    selinux name: variant1;
+///         EXTENSION = 0x100, selinux name: extension;
 ///     }
 /// );
 /// ```
@@ -99,9 +301,11 @@ macro_rules! implement_permission_aidl {
             $($element)*);
     };
 
-    // The following three rules recurse through the elements of the form
-    // `<enum variant>, selinux name: <selinux_name>;`
-    // preprocessing the input.
+    // The following four rules recurse through the elements of the form
+    // `<enum variant>, selinux name: <selinux_name>;` or
+    // `<extension name> = <value>, selinux name: <selinux_name>;`
+    // preprocessing the input. Each element is turned into an AIDL value in parentheses, which
+    // serves as expression and as pattern in the final rule.
 
     // The first rule terminates the recursion and passes the processed arguments to the final
     // rule that spills out the implementation.
@@ -110,28 +314,38 @@ macro_rules! implement_permission_aidl {
     };
 
     // The second rule is triggered if the selinux name of an element is literally `use`.
-    // It produces the tuple `<enum variant>, use_, use;`
+    // It produces the tuple `(<aidl_name>::<enum variant>), use_, use;`
     // and appends it to the out list.
     (@replace_use $($m:meta)*, $name:ident, $aidl_name:ident, ($($def:tt)*), [$($out:tt)*],
         $e_name:ident, selinux name: use; $($element:tt)*)
     => {
         implement_permission_aidl!(@replace_use $($m)*, $name, $aidl_name, ($($def)*),
-                              [$($out)* $e_name, use_, use;], $($element)*);
+                              [$($out)* ($aidl_name::$e_name), use_, use;], $($element)*);
     };
 
     // The third rule is the default rule which replaces every input tuple with
-    // `<enum variant>, <selinux_name>, <selinux_name>;`
+    // `(<aidl_name>::<enum variant>), <selinux_name>, <selinux_name>;`
     // and appends the result to the out list.
     (@replace_use $($m:meta)*, $name:ident, $aidl_name:ident, ($($def:tt)*), [$($out:tt)*],
         $e_name:ident, selinux name: $e_str:ident; $($element:tt)*)
     => {
         implement_permission_aidl!(@replace_use $($m)*, $name, $aidl_name, ($($def)*),
-                              [$($out)* $e_name, $e_str, $e_str;], $($element)*);
+                              [$($out)* ($aidl_name::$e_name), $e_str, $e_str;], $($element)*);
+    };
+
+    // The fourth rule handles extension permissions. It replaces the input tuple with
+    // `(<aidl_name>(<value>)), <selinux_name>, <selinux_name>;`
+    // and appends the result to the out list.
+    (@replace_use $($m:meta)*, $name:ident, $aidl_name:ident, ($($def:tt)*), [$($out:tt)*],
+        $e_name:ident = $e_val:literal, selinux name: $e_str:ident; $($element:tt)*)
+    => {
+        implement_permission_aidl!(@replace_use $($m)*, $name, $aidl_name, ($($def)*),
+                              [$($out)* ($aidl_name($e_val)), $e_str, $e_str;], $($element)*);
     };
 
     (@end $($m:meta)*, $name:ident, $aidl_name:ident,
         ($def_name:ident, $def_selinux_name:ident) {
-            $($element_name:ident, $element_identifier:ident,
+            $(($($element_value:tt)*), $element_identifier:ident,
                 $selinux_name:ident;)*
         })
     =>
@@ -143,7 +357,7 @@ macro_rules! implement_permission_aidl {
             fn from (p: $aidl_name) -> Self {
                 match p {
                     $aidl_name::$def_name => Self($aidl_name::$def_name),
-                    $($aidl_name::$element_name => Self($aidl_name::$element_name),)*
+                    $($($element_value)* => Self($($element_value)*),)*
                     _ => Self($aidl_name::$def_name),
                 }
             }
@@ -156,12 +370,15 @@ macro_rules! implement_permission_aidl {
         }
 
         impl $name {
+            /// The SELinux names of all permissions but the default.
+            pub const SELINUX_NAMES: &'static [&'static str] = &[$(stringify!($selinux_name),)*];
+
             /// Returns a string representation of the permission as required by
             /// `selinux::check_access`.
             pub fn to_selinux(&self) -> &'static str {
                 match self {
                     Self($aidl_name::$def_name) => stringify!($def_selinux_name),
-                    $(Self($aidl_name::$element_name) => stringify!($selinux_name),)*
+                    $(Self($($element_value)*) => stringify!($selinux_name),)*
                     _ => stringify!($def_selinux_name),
                 }
             }
@@ -170,7 +387,7 @@ macro_rules! implement_permission_aidl {
             pub const fn $def_selinux_name() -> Self { Self($aidl_name::$def_name) }
             $(
                 /// Creates an instance representing a permission with the same name.
-                pub const fn $element_identifier() -> Self { Self($aidl_name::$element_name) }
+                pub const fn $element_identifier() -> Self { Self($($element_value)*) }
             )*
         }
     };
@@ -204,9 +421,34 @@ implement_permission_aidl!(
         UPDATE,         selinux name: update;
         USE,            selinux name: use;
         USE_DEV_ID,     selinux name: use_dev_id;
+        // Extensions. The KeyPermission AIDL enum is frozen, so these use bits far above its
+        // variants. The first two are implied by get_info, ungrant is implied by grant, see
+        // `KeyPerm::implied_by`. They must be added to the keystore2_key class in the
+        // access_vectors of system/sepolicy. Until the policy defines them, only the implying
+        // permission grants them.
+        GET_CHARACTERISTICS = 0x1000000, selinux name: get_characteristics;
+        GET_CERTIFICATES = 0x2000000, selinux name: get_certificates;
+        UNGRANT = 0x4000000, selinux name: ungrant;
     }
 );
 
+impl KeyPerm {
+    /// Returns the permission that implies this one, if any. `get_characteristics` and
+    /// `get_certificates` split `get_info`, so that, e.g., a certificate distribution daemon
+    /// can fetch certificates without seeing authorization lists. Callers and grants that hold
+    /// `get_info` keep both. Likewise, `ungrant` lets a caller revoke grants without being able
+    /// to issue them, and callers that hold `grant` may revoke the grants they issued.
+    pub fn implied_by(&self) -> Option<KeyPerm> {
+        if *self == Self::get_characteristics() || *self == Self::get_certificates() {
+            Some(Self::get_info())
+        } else if *self == Self::ungrant() {
+            Some(Self::grant())
+        } else {
+            None
+        }
+    }
+}
+
 /// This macro implements an enum with values mapped to SELinux permission names.
 /// The below example wraps the enum MyPermission in the tuple struct `MyPerm` and implements
 ///  * From<i32> and Into<i32> are implemented. Where the implementation of From maps
@@ -215,6 +457,7 @@ implement_permission_aidl!(
 ///    representation.
 ///  * `MyPerm.to_selinux(&self)` returns the SELinux string representation of the
 ///    represented permission.
+///  * `MyPerm::SELINUX_NAMES` lists the SELinux names of all variants but the default.
 ///
 /// ## Example
 /// ```
@@ -264,6 +507,9 @@ macro_rules! implement_permission {
         }
 
         impl $name {
+            /// The SELinux names of all permissions but the default.
+            pub const SELINUX_NAMES: &'static [&'static str] = &[$(stringify!($selinux_name),)*];
+
             /// Returns a string representation of the permission as required by
             /// `selinux::check_access`.
             pub fn to_selinux(&self) -> &'static str {
@@ -319,6 +565,35 @@ implement_permission!(
         PullMetrics = 0x2000, selinux name: pull_metrics;
         /// Checked when IKeystoreMaintenance::deleteAllKeys is called.
         DeleteAllKeys = 0x4000, selinux name: delete_all_keys;
+        /// Checked when IKeystoreMaintenance::exportDebugKeyMaterial is called.
+        DebugKeyExport = 0x8000, selinux name: debug_key_export;
+        /// Checked when the state of Keystore 2.0 is dumped.
+        DumpState = 0x10000, selinux name: dump_state;
+        /// Checked when IKeystoreMaintenance::getKeyEscrowRecord is called.
+        EscrowRetrieve = 0x20000, selinux name: escrow_retrieve;
+        /// Checked when IKeystoreMaintenance::findKeysByFingerprint is called.
+        FindByFingerprint = 0x40000, selinux name: find_by_fingerprint;
+        /// Checked when certificates for the fs-verity keyring are provisioned or removed.
+        FsVerityProvision = 0x80000, selinux name: fs_verity_provision;
+        /// Checked when IKeystoreFsVerity::getCertificates is called.
+        FsVerityLoad = 0x100000, selinux name: fs_verity_load;
+        /// Checked when IKeystoreMaintenance::onTrimMemory is called.
+        TrimMemory = 0x200000, selinux name: trim_memory;
+        /// Checked when IKeystoreMaintenance::prepareForShutdown is called or a shutdown
+        /// listener is registered.
+        Shutdown = 0x400000, selinux name: shutdown;
+        /// Checked when a boot state listener is registered or unregistered.
+        BootState = 0x800000, selinux name: boot_state;
+        /// Checked when IKeystoreMaintenance::rebuildDerivedState is called.
+        RebuildDerivedState = 0x1000000, selinux name: rebuild_derived_state;
+        /// Checked when an external key provider is registered or unregistered.
+        ExternalKeys = 0x2000000, selinux name: external_keys;
+        /// Checked when the user approved the use of a user mediated key.
+        ApproveKeyUse = 0x4000000, selinux name: approve_key_use;
+        /// Checked when a namespace is frozen or unfrozen.
+        FreezeNamespace = 0x8000000, selinux name: freeze_namespace;
+        /// Checked when IKeystoreMaintenance::setDeviceOwnerUid is called.
+        SetDeviceOwner = 0x10000000, selinux name: set_device_owner;
     }
 );
 
@@ -326,9 +601,12 @@ implement_permission!(
 /// `IntoIterator` is implemented for this struct allowing the iteration through all the
 /// permissions in the set.
 /// It also implements a function `includes(self, other)` that checks if the permissions
-/// in `other` are included in `self`.
+/// in `other` are included in `self`, and the set operations `union`, `intersection`, and
+/// `difference`.
 ///
-/// KeyPermSet can be created with the macro `key_perm_set![]`.
+/// KeyPermSet can be created with the macro `key_perm_set![]` or collected from an iterator
+/// of `KeyPerm`. It displays as the SELinux names of its permissions separated by `|`, e.g.,
+/// "delete|use|get_info".
 ///
 /// ## Example
 /// ```
@@ -345,7 +623,7 @@ implement_permission!(
 /// assert_eq(Some(KeyPerm::use_()), i.next());
 /// assert_eq(None, i.next());
 /// ```
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct KeyPermSet(pub i32);
 
 mod perm {
@@ -405,6 +683,96 @@ impl KeyPermSet {
         let o: KeyPermSet = other.into();
         (self.0 & o.0) == o.0
     }
+
+    /// Returns true iff `perm` is in this permission set.
+    pub fn contains(&self, perm: KeyPerm) -> bool {
+        self.includes(perm)
+    }
+
+    /// Returns true iff `perm` is in this permission set or implied by a permission in it, see
+    /// `KeyPerm::implied_by`.
+    pub fn permits(&self, perm: KeyPerm) -> bool {
+        self.includes(perm) || perm.implied_by().map_or(false, |p| self.includes(p))
+    }
+
+    /// Returns true iff this permission set has no permissions.
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns the permissions that are in this set or in `other`.
+    pub fn union<T: Into<KeyPermSet>>(self, other: T) -> Self {
+        Self(self.0 | other.into().0)
+    }
+
+    /// Returns the permissions that are in this set and in `other`.
+    pub fn intersection<T: Into<KeyPermSet>>(self, other: T) -> Self {
+        Self(self.0 & other.into().0)
+    }
+
+    /// Returns the permissions that are in this set but not in `other`.
+    pub fn difference<T: Into<KeyPermSet>>(self, other: T) -> Self {
+        Self(self.0 & !other.into().0)
+    }
+}
+
+impl std::iter::FromIterator<KeyPerm> for KeyPermSet {
+    fn from_iter<I: IntoIterator<Item = KeyPerm>>(iter: I) -> Self {
+        iter.into_iter().fold(KeyPermSet(0), KeyPermSet::union)
+    }
+}
+
+impl fmt::Display for KeyPermSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for bit in (0..32).map(|pos| 1i32 << pos).filter(|bit| self.0 & bit != 0) {
+            if !first {
+                f.write_str("|")?;
+            }
+            first = false;
+            let perm = KeyPerm::from(KeyPermission(bit));
+            // Bits without a defined permission are shown numerically.
+            if KeyPermSet::from(perm).0 == bit {
+                f.write_str(perm.to_selinux())?;
+            } else {
+                write!(f, "{:#x}", bit)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Error returned when parsing a `KeyPermSet` from a string fails.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Unknown key permission \"{0}\".")]
+pub struct ParseKeyPermSetError(pub String);
+
+/// Parses a list of selinux permission names separated by "," or "|", e.g.,
+/// "delete,use,get_info". Whitespace around names is ignored and the empty string yields the
+/// empty set. The output of `Display` can be parsed unless it contains undefined bits.
+impl std::str::FromStr for KeyPermSet {
+    type Err = ParseKeyPermSetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().is_empty() {
+            return Ok(KeyPermSet(0));
+        }
+        s.split(|c| c == ',' || c == '|')
+            .map(str::trim)
+            .map(|name| {
+                (0..32)
+                    .map(|pos| KeyPerm::from(KeyPermission(1i32 << pos)))
+                    .find(|perm| KeyPermSet::from(*perm).0 != 0 && perm.to_selinux() == name)
+                    .ok_or_else(|| ParseKeyPermSetError(name.to_string()))
+            })
+            .collect()
+    }
+}
+
+impl fmt::Debug for KeyPermSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KeyPermSet({})", self)
+    }
 }
 
 /// This macro can be used to create a `KeyPermSet` from a list of `KeyPerm` values.
@@ -430,66 +798,156 @@ impl IntoIterator for KeyPermSet {
     }
 }
 
-/// Uses `selinux::check_access` to check if the given caller context `caller_cxt` may access
-/// the given permision `perm` of the `keystore2` security class.
+/// The SELinux classes and permissions that Keystore 2.0 checks.
+pub const PERMISSION_MANIFEST: &[(&str, &[&str])] =
+    &[("keystore2", KeystorePerm::SELINUX_NAMES), ("keystore2_key", KeyPerm::SELINUX_NAMES)];
+
+/// Returns `PERMISSION_MANIFEST` in the syntax of the SEPolicy access_vectors file.
+pub fn permission_manifest() -> String {
+    let mut manifest = String::new();
+    for (class, perms) in PERMISSION_MANIFEST {
+        manifest.push_str(&format!("class {}\n{{\n", class));
+        for perm in perms.iter() {
+            manifest.push_str(&format!("\t{}\n", perm));
+        }
+        manifest.push_str("}\n");
+    }
+    manifest
+}
+
+/// Extracts the permissions of the given class from a policy in the syntax of the SEPolicy
+/// access_vectors file or of a policy.conf. Returns None if the policy does not define the
+/// permissions of the class.
+fn policy_class_permissions(policy: &str, class: &str) -> Option<Vec<String>> {
+    let policy = policy
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .replace('{', " { ")
+        .replace('}', " } ");
+    let mut tokens = policy.split_whitespace().peekable();
+    while let Some(token) = tokens.next() {
+        if token != "class" || tokens.peek() != Some(&class) {
+            continue;
+        }
+        tokens.next();
+        // Permissions inherited from a common definition are not considered. Keystore 2.0
+        // classes do not inherit any.
+        if tokens.peek() == Some(&"inherits") {
+            tokens.next();
+            tokens.next();
+        }
+        // A class without a permission block is a mere declaration, as in security_classes.
+        if tokens.peek() == Some(&"{") {
+            tokens.next();
+            return Some(tokens.take_while(|t| *t != "}").map(str::to_string).collect());
+        }
+    }
+    None
+}
+
+/// Compares `PERMISSION_MANIFEST` with the given policy in the syntax of the SEPolicy
+/// access_vectors file or of a policy.conf. Returns a description of every class or permission
+/// that Keystore 2.0 checks but the policy does not define and of every permission that the
+/// policy defines but Keystore 2.0 does not check.
+pub fn check_permission_manifest(policy: &str) -> Vec<String> {
+    let mut skew = Vec::new();
+    for (class, perms) in PERMISSION_MANIFEST {
+        let policy_perms = match policy_class_permissions(policy, class) {
+            Some(policy_perms) => policy_perms,
+            None => {
+                skew.push(format!("Class {} is not defined by the policy.", class));
+                continue;
+            }
+        };
+        for perm in perms.iter().filter(|p| !policy_perms.iter().any(|pp| pp == *p)) {
+            skew.push(format!("Permission {}:{} is not defined by the policy.", class, perm));
+        }
+        for perm in policy_perms.iter().filter(|pp| !perms.contains(&pp.as_str())) {
+            skew.push(format!("Permission {}:{} is not checked by Keystore.", class, perm));
+        }
+    }
+    skew
+}
+
+/// Uses `selinux::check_access_cached` to check if the given caller context `caller_cxt` may
+/// access the given permision `perm` of the `keystore2` security class.
 pub fn check_keystore_permission(caller_ctx: &CStr, perm: KeystorePerm) -> anyhow::Result<()> {
     let target_context = getcon().context("check_keystore_permission: getcon failed.")?;
-    selinux::check_access(caller_ctx, &target_context, "keystore2", perm.to_selinux())
+    check_access(caller_ctx, &target_context, "keystore2", perm.to_selinux())
 }
 
-/// Uses `selinux::check_access` to check if the given caller context `caller_cxt` has
+/// Uses `selinux::check_access_cached` to check if the given caller context `caller_cxt` has
 /// all the permissions indicated in `access_vec` for the target domain indicated by the key
 /// descriptor `key` in the security class `keystore2_key`.
 ///
 /// Also checks if the caller has the grant permission for the given target domain.
 ///
-/// Attempts to grant the grant permission are always denied.
+/// Attempts to grant the grant or ungrant permission are always denied.
 ///
 /// The only viable target domains are
-///  * `Domain::APP` in which case u:r:keystore:s0 is used as target context and
+///  * `Domain::APP` in which case u:r:keystore:s0 is used as target context, see
+///                   `app_key_context`, and
 ///  * `Domain::SELINUX` in which case the `key.nspace` parameter is looked up in
 ///                      SELinux keystore key backend, and the result is used
-///                      as target context.
+///                      as target context. Grants of the entire namespace are checked
+///                      the same way with a `key` that has no alias, see
+///                      `KeystoreDB::grant_namespace`.
 pub fn check_grant_permission(
     caller_ctx: &CStr,
     access_vec: KeyPermSet,
     key: &KeyDescriptor,
 ) -> anyhow::Result<()> {
     let target_context = match key.domain {
-        Domain::APP => getcon().context("check_grant_permission: getcon failed.")?,
+        Domain::APP => app_key_context(key.nspace).context("check_grant_permission.")?,
         Domain::SELINUX => lookup_keystore2_key_context(key.nspace)
             .context("check_grant_permission: Domain::SELINUX: Failed to lookup namespace.")?,
         _ => return Err(KsError::sys()).context(format!("Cannot grant {:?}.", key.domain)),
     };
 
-    selinux::check_access(caller_ctx, &target_context, "keystore2_key", "grant")
+    check_access(caller_ctx, &target_context, "keystore2_key", "grant")
         .context("Grant permission is required when granting.")?;
 
     if access_vec.includes(KeyPerm::grant()) {
-        return Err(selinux::Error::perm()).context("Grant permission cannot be granted.");
+        return Err(anyhow!(PermissionDenied::new("keystore2_key", "grant", caller_ctx, None)))
+            .context("Grant permission cannot be granted.");
     }
 
-    for p in access_vec.into_iter() {
-        selinux::check_access(caller_ctx, &target_context, "keystore2_key", p.to_selinux())
-            .context(format!(
-                concat!(
-                    "check_grant_permission: check_access failed. ",
-                    "The caller may have tried to grant a permission that they don't possess. {:?}"
-                ),
-                p
-            ))?
+    if access_vec.includes(KeyPerm::ungrant()) {
+        return Err(anyhow!(PermissionDenied::new("keystore2_key", "ungrant", caller_ctx, None)))
+            .context("Ungrant permission cannot be granted.");
+    }
+
+    let denied = denied_permissions(caller_ctx, &target_context, access_vec)
+        .context("check_grant_permission: check_access failed.")?;
+    if let Some(perm) = denied.into_iter().next() {
+        let e = PermissionDenied::new(
+            "keystore2_key",
+            perm.to_selinux(),
+            caller_ctx,
+            Some(&*target_context),
+        );
+        return Err(anyhow!(e)).context(format!(
+            concat!(
+                "check_grant_permission: ",
+                "The caller tried to grant permissions that they don't possess: {}"
+            ),
+            denied
+        ));
     }
     Ok(())
 }
 
-/// Uses `selinux::check_access` to check if the given caller context `caller_cxt`
+/// Uses `selinux::check_access_cached` to check if the given caller context `caller_cxt`
 /// has the permissions indicated by `perm` for the target domain indicated by the key
 /// descriptor `key` in the security class `keystore2_key`.
 ///
 /// The behavior differs slightly depending on the selected target domain:
-///  * `Domain::APP` u:r:keystore:s0 is used as target context.
+///  * `Domain::APP` u:r:keystore:s0 is used as target context, see `app_key_context`.
 ///  * `Domain::SELINUX` `key.nspace` parameter is looked up in the SELinux keystore key
-///                      backend, and the result is used as target context.
+///                      backend, and the result is used as target context. If the caller
+///                      holds a namespace grant, its `access_vector` is consulted first.
 ///  * `Domain::BLOB` Same as SELinux but the "manage_blob" permission is always checked additionally
 ///                   to the one supplied in `perm`.
 ///  * `Domain::GRANT` Does not use selinux::check_access. Instead the `access_vector`
@@ -497,7 +955,13 @@ pub fn check_grant_permission(
 ///
 /// ## Return values.
 ///  * Ok(()) If the requested permissions were granted.
-///  * Err(selinux::Error::perm()) If the requested permissions were denied.
+///  * Err(PermissionDenied) If the requested permissions were denied.
+///  * Err(key_policy::KeyPolicyDenied) If SELinux allowed the request, but the device key
+///                      policy denies it.
+///  * Err(tenants::TenantError) If `Domain::SELINUX` or `Domain::BLOB` was selected and the
+///                      namespace belongs to a tenant other than the caller's, or if
+///                      `Domain::APP` was selected and the caller or the namespace owner is a
+///                      tenant proxy.
 ///  * Err(KsError::sys()) This error is produced if `Domain::GRANT` is selected but no `access_vec`
 ///                      was supplied. It is also produced if `Domain::KEY_ID` was selected, and
 ///                      on various unexpected backend failures.
@@ -508,34 +972,52 @@ pub fn check_key_permission(
     key: &KeyDescriptor,
     access_vector: &Option<KeyPermSet>,
 ) -> anyhow::Result<()> {
-    // If an access vector was supplied, the key is either accessed by GRANT or by KEY_ID.
-    // In the former case, key.domain was set to GRANT and we check the failure cases
+    // If an access vector was supplied, the key is either accessed by GRANT or by KEY_ID, or
+    // it is accessed by SELINUX and the caller holds a grant of the key's namespace.
+    // In the first case, key.domain was set to GRANT and we check the failure cases
     // further below. If the access is requested by KEY_ID, key.domain would have been
     // resolved to APP or SELINUX depending on where the key actually resides.
     // Either way we can return here immediately if the access vector covers the requested
     // permission. If it does not, we can still check if the caller has access by means of
     // ownership.
     if let Some(access_vector) = access_vector {
-        if access_vector.includes(perm) {
-            return Ok(());
+        if access_vector.permits(perm) {
+            // The key policy restricted the access vector of a grant when it was resolved.
+            if key.domain == Domain::GRANT {
+                return Ok(());
+            }
+            return key_policy::check(caller_uid, perm.into(), key);
         }
     }
 
     let target_context = match key.domain {
-        // apps get the default keystore context
+        // apps get the default keystore context, possibly with the level of the key owner
         Domain::APP => {
-            if caller_uid as i64 != key.nspace {
-                return Err(selinux::Error::perm())
-                    .context("Trying to access key without ownership.");
+            tenants::check_app_access(caller_uid, key.nspace)
+                .context("check_key_permission: Domain::APP: Tenant isolation.")?;
+            // Members of an access group may access the owner's keys within their permission
+            // mask.
+            if caller_uid as i64 != key.nspace
+                && !access_group::member_permissions(caller_uid, key.nspace).permits(perm)
+            {
+                let e = PermissionDenied::new("keystore2_key", perm.to_selinux(), caller_ctx, None);
+                return Err(anyhow!(e)).context("Trying to access key without ownership.");
             }
-            getcon().context("check_key_permission: getcon failed.")?
+            app_key_context(key.nspace).context("check_key_permission.")?
+        }
+        Domain::SELINUX => {
+            // Tenants are isolated regardless of the SELinux policy.
+            tenants::check_access(caller_uid, key.nspace)
+                .context("check_key_permission: Domain::SELINUX: Tenant isolation.")?;
+            lookup_keystore2_key_context(key.nspace)
+                .context("check_key_permission: Domain::SELINUX: Failed to lookup namespace.")?
         }
-        Domain::SELINUX => lookup_keystore2_key_context(key.nspace)
-            .context("check_key_permission: Domain::SELINUX: Failed to lookup namespace.")?,
         Domain::GRANT => {
             match access_vector {
                 Some(_) => {
-                    return Err(selinux::Error::perm())
+                    let e =
+                        PermissionDenied::new("keystore2_key", perm.to_selinux(), caller_ctx, None);
+                    return Err(anyhow!(e))
                         .context(format!("\"{}\" not granted", perm.to_selinux()));
                 }
                 None => {
@@ -553,34 +1035,348 @@ pub fn check_key_permission(
             return Err(KsError::sys()).context("Cannot check permission for Domain::KEY_ID.");
         }
         Domain::BLOB => {
+            tenants::check_access(caller_uid, key.nspace)
+                .context("Domain::BLOB: Tenant isolation.")?;
             let tctx = lookup_keystore2_key_context(key.nspace)
                 .context("Domain::BLOB: Failed to lookup namespace.")?;
             // If DOMAIN_KEY_BLOB was specified, we check for the "manage_blob"
             // permission in addition to the requested permission.
-            selinux::check_access(
+            check_access(caller_ctx, &tctx, "keystore2_key", KeyPerm::manage_blob().to_selinux())?;
+
+            tctx
+        }
+        _ => {
+            return Err(KsError::sys())
+                .context(format!("Unknown domain value: \"{:?}\".", key.domain))
+        }
+    };
+
+    check_key_access(caller_ctx, &target_context, perm)?;
+    key_policy::check(caller_uid, perm.into(), key)
+}
+
+/// Like `check_access` for the class `keystore2_key`, but a permission is also granted if the
+/// caller holds the permission that implies it, see `KeyPerm::implied_by`. The implying
+/// permission is checked without auditing, so that callers holding only the implied permission
+/// do not cause denial logs. As long as the policy does not define an implied permission, only
+/// the implying permission grants it.
+fn check_key_access(caller_ctx: &CStr, target_context: &CStr, perm: KeyPerm) -> anyhow::Result<()> {
+    if let Some(implying) = perm.implied_by() {
+        let access_control = access_control::get();
+        if access_control.compute_access(
+            caller_ctx,
+            target_context,
+            "keystore2_key",
+            implying.to_selinux(),
+        )? {
+            return Ok(());
+        }
+        if !access_control.is_permission_defined("keystore2_key", perm.to_selinux()) {
+            let e = PermissionDenied::new("keystore2_key", perm.to_selinux(), caller_ctx, None);
+            return Err(anyhow!(e))
+                .context(format!("\"{}\" is not defined by the policy.", perm.to_selinux()));
+        }
+    }
+    check_access(caller_ctx, target_context, "keystore2_key", perm.to_selinux())
+}
+
+/// Returns the permissions in `access_vec` that no grant may hold: `grant` and `ungrant`, which
+/// are never granted, see `check_grant_permission`, and bits that do not denote a permission.
+/// This is used to reconcile existing grants with the current rules, see
+/// `grant_reconciliation`.
+pub fn ungrantable_permissions(access_vec: KeyPermSet) -> KeyPermSet {
+    (0..32)
+        .map(|bit| KeyPermSet(access_vec.0 & (1 << bit)))
+        .filter(|p| p.0 != 0)
+        .filter(|p| {
+            let perm = KeyPerm::from(KeyPermission(p.0));
+            perm == KeyPerm::none() || perm == KeyPerm::grant() || perm == KeyPerm::ungrant()
+        })
+        .fold(KeyPermSet(0), |acc, p| KeyPermSet(acc.0 | p.0))
+}
+
+/// Checks each permission in `perms` of `caller_ctx` on `target_context` and returns the ones
+/// that the SELinux policy denies. Errors other than denials are returned as is.
+fn denied_permissions(
+    caller_ctx: &CStr,
+    target_context: &CStr,
+    perms: KeyPermSet,
+) -> anyhow::Result<KeyPermSet> {
+    let mut denied = KeyPermSet(0);
+    for p in perms.into_iter() {
+        match check_key_access(caller_ctx, target_context, p) {
+            Ok(()) => {}
+            Err(e) if is_permission_denied(&e) => denied.0 |= KeyPermSet::from(p).0,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(denied)
+}
+
+/// The outcome of `check_key_permissions`: the requested permissions split into the ones that
+/// were granted and the ones that were denied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPermCheck {
+    /// The requested permissions that the caller holds.
+    pub granted: KeyPermSet,
+    /// The requested permissions that the caller does not hold.
+    pub denied: KeyPermSet,
+}
+
+impl KeyPermCheck {
+    /// Returns true iff all requested permissions were granted.
+    pub fn all_granted(&self) -> bool {
+        self.denied.0 == 0
+    }
+}
+
+/// Like `check_key_permission` but checks all permissions in `perms` in a single pass. Instead
+/// of failing on the first denied permission, it reports which of the requested permissions
+/// were denied. The target context of the key is resolved only once.
+///
+/// ## Return values.
+///  * Ok(KeyPermCheck) The granted and denied subsets of `perms`.
+///  * Err(tenants::TenantError) If `Domain::SELINUX` or `Domain::BLOB` was selected and the
+///                      namespace belongs to a tenant other than the caller's, or if
+///                      `Domain::APP` was selected and the caller or the namespace owner is a
+///                      tenant proxy.
+///  * Err(KsError::sys()) On the same conditions as `check_key_permission`.
+pub fn check_key_permissions(
+    caller_uid: u32,
+    caller_ctx: &CStr,
+    perms: KeyPermSet,
+    key: &KeyDescriptor,
+    access_vector: &Option<KeyPermSet>,
+) -> anyhow::Result<KeyPermCheck> {
+    evaluate_key_permissions(caller_uid, caller_ctx, perms, key, access_vector, denied_permissions)
+}
+
+/// The outcome of `check_key_permission_audit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditDecision {
+    /// The policy allows all requested permissions.
+    Allowed,
+    /// The policy denies the permissions in `missing`.
+    Denied {
+        /// The requested permissions that the policy denies.
+        missing: KeyPermSet,
+    },
+}
+
+/// Evaluates the permissions in `perms` like `check_key_permissions`, but only computes the
+/// SELinux decision without enforcing or auditing it. Denials are reported even if the caller
+/// or the device is permissive, so that would-be denials can be logged while a permission
+/// change is rolled out in permissive mode.
+///
+/// ## Return values.
+///  * Ok(AuditDecision) The decision of the policy.
+///  * Err(...) On the same conditions as `check_key_permissions`.
+pub fn check_key_permission_audit(
+    caller_uid: u32,
+    caller_ctx: &CStr,
+    perms: KeyPermSet,
+    key: &KeyDescriptor,
+    access_vector: &Option<KeyPermSet>,
+) -> anyhow::Result<AuditDecision> {
+    let check = evaluate_key_permissions(
+        caller_uid,
+        caller_ctx,
+        perms,
+        key,
+        access_vector,
+        audit_denied_permissions,
+    )
+    .context("check_key_permission_audit.")?;
+    Ok(if check.all_granted() {
+        AuditDecision::Allowed
+    } else {
+        AuditDecision::Denied { missing: check.denied }
+    })
+}
+
+/// Like `denied_permissions` but uses the audit-only access decision of the policy.
+fn audit_denied_permissions(
+    caller_ctx: &CStr,
+    target_context: &CStr,
+    perms: KeyPermSet,
+) -> anyhow::Result<KeyPermSet> {
+    let mut denied = KeyPermSet(0);
+    for p in perms.into_iter() {
+        let allowed = |q: KeyPerm| {
+            access_control::get().compute_access(
                 caller_ctx,
-                &tctx,
+                target_context,
                 "keystore2_key",
-                KeyPerm::manage_blob().to_selinux(),
-            )?;
+                q.to_selinux(),
+            )
+        };
+        let implied = match p.implied_by() {
+            Some(q) => allowed(q)?,
+            None => false,
+        };
+        let defined = access_control::get().is_permission_defined("keystore2_key", p.to_selinux());
+        if !implied && !(defined && allowed(p)?) {
+            denied.0 |= KeyPermSet::from(p).0
+        }
+    }
+    Ok(denied)
+}
+
+/// Splits `perms` into granted and denied permissions. The SELinux decisions are made by
+/// `denied_fn`, which returns the denied subset of the permissions it is given. The device key
+/// policy is applied on top like in `check_key_permission`.
+fn evaluate_key_permissions(
+    caller_uid: u32,
+    caller_ctx: &CStr,
+    perms: KeyPermSet,
+    key: &KeyDescriptor,
+    access_vector: &Option<KeyPermSet>,
+    denied_fn: fn(&CStr, &CStr, KeyPermSet) -> anyhow::Result<KeyPermSet>,
+) -> anyhow::Result<KeyPermCheck> {
+    let check = evaluate_key_access(caller_uid, caller_ctx, perms, key, access_vector, denied_fn)?;
+    // The key policy restricted the access vector of a grant when it was resolved.
+    if key.domain == Domain::GRANT {
+        return Ok(check);
+    }
+    let policy_denied = key_policy::enforced_denials(caller_uid, check.granted, key);
+    Ok(KeyPermCheck {
+        granted: check.granted.difference(policy_denied),
+        denied: KeyPermSet(check.denied.0 | policy_denied.0),
+    })
+}
+
+/// Like `evaluate_key_permissions`, but without the key policy.
+fn evaluate_key_access(
+    caller_uid: u32,
+    caller_ctx: &CStr,
+    perms: KeyPermSet,
+    key: &KeyDescriptor,
+    access_vector: &Option<KeyPermSet>,
+    denied_fn: fn(&CStr, &CStr, KeyPermSet) -> anyhow::Result<KeyPermSet>,
+) -> anyhow::Result<KeyPermCheck> {
+    let mut granted = KeyPermSet(0);
+    let mut pending = perms;
+    if let Some(access_vector) = access_vector {
+        granted = perms.into_iter().filter(|p| access_vector.permits(*p)).collect();
+        pending = perms.difference(granted);
+    }
+    if pending.0 == 0 {
+        return Ok(KeyPermCheck { granted, denied: pending });
+    }
 
+    let mut denied = KeyPermSet(0);
+    let target_context = match key.domain {
+        Domain::APP => {
+            tenants::check_app_access(caller_uid, key.nspace)
+                .context("evaluate_key_permissions: Domain::APP: Tenant isolation.")?;
+            if caller_uid as i64 != key.nspace {
+                let member_perms = access_group::member_permissions(caller_uid, key.nspace);
+                denied.0 = pending.0 & !member_perms.0;
+                pending.0 &= member_perms.0;
+            }
+            app_key_context(key.nspace).context("evaluate_key_permissions.")?
+        }
+        Domain::SELINUX => {
+            tenants::check_access(caller_uid, key.nspace)
+                .context("evaluate_key_permissions: Domain::SELINUX: Tenant isolation.")?;
+            lookup_keystore2_key_context(key.nspace)
+                .context("evaluate_key_permissions: Domain::SELINUX: Failed to lookup namespace.")?
+        }
+        Domain::GRANT => {
+            if access_vector.is_none() {
+                return Err(KsError::sys())
+                    .context("Cannot check permissions for Domain::GRANT without access vector.");
+            }
+            return Ok(KeyPermCheck { granted, denied: pending });
+        }
+        Domain::BLOB => {
+            tenants::check_access(caller_uid, key.nspace)
+                .context("Domain::BLOB: Tenant isolation.")?;
+            let tctx = lookup_keystore2_key_context(key.nspace)
+                .context("Domain::BLOB: Failed to lookup namespace.")?;
+            // Without the "manage_blob" permission none of the requested permissions can be
+            // exercised on the blob.
+            let blob_denied = denied_fn(caller_ctx, &tctx, KeyPerm::manage_blob().into())
+                .context("evaluate_key_permissions: Domain::BLOB.")?;
+            if blob_denied.0 != 0 {
+                denied.0 |= pending.0;
+                return Ok(KeyPermCheck { granted, denied });
+            }
             tctx
         }
+        Domain::KEY_ID => {
+            return Err(KsError::sys()).context("Cannot check permissions for Domain::KEY_ID.");
+        }
         _ => {
             return Err(KsError::sys())
                 .context(format!("Unknown domain value: \"{:?}\".", key.domain))
         }
     };
 
-    selinux::check_access(caller_ctx, &target_context, "keystore2_key", perm.to_selinux())
+    let selinux_denied = denied_fn(caller_ctx, &target_context, pending)
+        .context("evaluate_key_permissions: check_access failed.")?;
+    denied.0 |= selinux_denied.0;
+    granted.0 |= pending.0 & !selinux_denied.0;
+    Ok(KeyPermCheck { granted, denied })
+}
+
+/// Checks the client context binding of a key in addition to `check_key_permission`.
+/// The owner of a key may bind it to callers whose SELinux context matches
+/// `client_context_pattern`, e.g., "u:r:vold:s0", see `IKeystoreGrants::setClientContextPattern`.
+/// The pattern may contain `*` wildcards, which match any sequence of characters. Keys without a
+/// pattern are not restricted.
+///
+/// ## Return values.
+///  * Ok(()) If the key is not bound or if the caller context matches the pattern.
+///  * Err(selinux::Error::perm()) If the caller context does not match the pattern.
+pub fn check_client_context(
+    caller_ctx: &CStr,
+    client_context_pattern: Option<&str>,
+) -> anyhow::Result<()> {
+    let pattern = match client_context_pattern {
+        Some(pattern) => pattern,
+        None => return Ok(()),
+    };
+    let caller_ctx =
+        caller_ctx.to_str().context("check_client_context: Invalid caller context.")?;
+    if context_matches(pattern, caller_ctx) {
+        Ok(())
+    } else {
+        Err(selinux::Error::perm())
+            .context(format!("Key is bound to client context \"{}\".", pattern))
+    }
+}
+
+fn context_matches(pattern: &str, ctx: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // The first part must be a prefix. If there is no wildcard, it must match exactly.
+    let first = parts.next().unwrap_or("");
+    let mut rest = match ctx.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::access_control::{set_access_control, AccessControl, SelinuxAccessControl};
     use anyhow::anyhow;
     use anyhow::Result;
     use keystore2_selinux::*;
+    use std::sync::{Arc, Once};
 
     const ALL_PERMS: KeyPermSet = key_perm_set![
         KeyPerm::manage_blob(),
@@ -635,13 +1431,54 @@ mod tests {
     /// SePolicy (system/sepolicy).
     const SHELL_KEY_NAMESPACE: i32 = 1;
 
-    pub fn test_getcon() -> Result<Context> {
-        Context::new("u:object_r:keystore:s0")
+    /// Evaluates the checks against the SELinux policy of the device, but pretends that
+    /// Keystore runs as u:object_r:keystore:s0 rather than in the context of the test.
+    struct TestAccessControl(SelinuxAccessControl);
+
+    impl AccessControl for TestAccessControl {
+        fn keystore_context(&self) -> Result<Context> {
+            Context::new("u:object_r:keystore:s0")
+        }
+
+        fn key_context(&self, namespace: i64) -> Result<Option<Context>> {
+            self.0.key_context(namespace)
+        }
+
+        fn check_access(
+            &self,
+            source: &CStr,
+            target: &CStr,
+            tclass: &str,
+            perm: &str,
+        ) -> Result<()> {
+            self.0.check_access(source, target, tclass, perm)
+        }
+
+        fn compute_access(
+            &self,
+            source: &CStr,
+            target: &CStr,
+            tclass: &str,
+            perm: &str,
+        ) -> Result<bool> {
+            self.0.compute_access(source, target, tclass, perm)
+        }
+
+        fn is_permission_defined(&self, tclass: &str, perm: &str) -> bool {
+            self.0.is_permission_defined(tclass, perm)
+        }
+    }
+
+    fn install_test_access_control() {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            set_access_control(Arc::new(TestAccessControl(SelinuxAccessControl::new().unwrap())))
+        });
     }
 
     // This macro evaluates the given expression and checks that
     // a) evaluated to Result::Err() and that
-    // b) the wrapped error is selinux::Error::perm() (permission denied).
+    // b) the wrapped error is a permission denial (see `is_permission_denied`).
     // We use a macro here because a function would mask which invocation caused the failure.
     //
     // TODO b/164121720 Replace this macro with a function when `track_caller` is available.
@@ -649,10 +1486,8 @@ mod tests {
         ($test_function:expr) => {
             let result = $test_function;
             assert!(result.is_err(), "Permission check should have failed.");
-            assert_eq!(
-                Some(&selinux::Error::perm()),
-                result.err().unwrap().root_cause().downcast_ref::<selinux::Error>()
-            );
+            let e = result.err().unwrap();
+            assert!(is_permission_denied(&e), "Expected permission denial, got {:?}", e);
         };
     }
 
@@ -671,6 +1506,7 @@ mod tests {
 
     #[test]
     fn check_keystore_permission_test() -> Result<()> {
+        install_test_access_control();
         let system_server_ctx = Context::new("u:r:system_server:s0")?;
         assert!(check_keystore_permission(&system_server_ctx, KeystorePerm::add_auth()).is_ok());
         assert!(check_keystore_permission(&system_server_ctx, KeystorePerm::clear_ns()).is_ok());
@@ -694,11 +1530,16 @@ mod tests {
         assert_perm_failed!(check_keystore_permission(&shell_ctx, KeystorePerm::change_user()));
         assert_perm_failed!(check_keystore_permission(&shell_ctx, KeystorePerm::change_password()));
         assert_perm_failed!(check_keystore_permission(&shell_ctx, KeystorePerm::clear_uid()));
+        assert_perm_failed!(check_keystore_permission(
+            &shell_ctx,
+            KeystorePerm::early_boot_ended()
+        ));
         Ok(())
     }
 
     #[test]
     fn check_grant_permission_app() -> Result<()> {
+        install_test_access_control();
         let system_server_ctx = Context::new("u:r:system_server:s0")?;
         let shell_ctx = Context::new("u:r:shell:s0")?;
         let key = KeyDescriptor { domain: Domain::APP, nspace: 0, alias: None, blob: None };
@@ -718,6 +1559,7 @@ mod tests {
 
     #[test]
     fn check_grant_permission_selinux() -> Result<()> {
+        install_test_access_control();
         let (sctx, namespace, is_su) = check_context()?;
         let key = KeyDescriptor {
             domain: Domain::SELINUX,
@@ -738,6 +1580,7 @@ mod tests {
 
     #[test]
     fn check_key_permission_domain_grant() -> Result<()> {
+        install_test_access_control();
         let key = KeyDescriptor { domain: Domain::GRANT, nspace: 0, alias: None, blob: None };
 
         assert_perm_failed!(check_key_permission(
@@ -757,8 +1600,98 @@ mod tests {
         )
     }
 
+    #[test]
+    fn ungrantable_permissions_of_grants() {
+        assert_eq!(ungrantable_permissions(key_perm_set![KeyPerm::use_()]), KeyPermSet(0));
+        assert_eq!(
+            ungrantable_permissions(key_perm_set![
+                KeyPerm::use_(),
+                KeyPerm::grant(),
+                KeyPerm::ungrant()
+            ]),
+            key_perm_set![KeyPerm::grant(), KeyPerm::ungrant()]
+        );
+        assert_eq!(ungrantable_permissions(KeyPermSet(1 << 30)), KeyPermSet(1 << 30));
+    }
+
+    #[test]
+    fn key_perm_extensions() {
+        assert_eq!(KeyPerm::get_certificates().to_selinux(), "get_certificates");
+        assert_eq!(KeyPerm::from(KeyPermission(0x1000000)), KeyPerm::get_characteristics());
+        assert!(KeyPerm::SELINUX_NAMES.contains(&"get_characteristics"));
+        assert_eq!(KeyPerm::get_certificates().implied_by(), Some(KeyPerm::get_info()));
+        assert_eq!(KeyPerm::get_info().implied_by(), None);
+        assert_eq!(KeyPerm::ungrant().implied_by(), Some(KeyPerm::grant()));
+        assert_eq!(
+            "get_info,get_certificates".parse::<KeyPermSet>().unwrap(),
+            key_perm_set![KeyPerm::get_info(), KeyPerm::get_certificates()]
+        );
+    }
+
+    #[test]
+    fn check_key_permission_implied_by_grant() -> Result<()> {
+        install_test_access_control();
+        let key = KeyDescriptor { domain: Domain::GRANT, nspace: 0, alias: None, blob: None };
+        let ctx = selinux::Context::new("ignored").unwrap();
+        let get_info = Some(key_perm_set![KeyPerm::get_info()]);
+        let get_certificates = Some(key_perm_set![KeyPerm::get_certificates()]);
+
+        check_key_permission(0, &ctx, KeyPerm::get_characteristics(), &key, &get_info)?;
+        check_key_permission(0, &ctx, KeyPerm::get_certificates(), &key, &get_certificates)?;
+        assert_perm_failed!(check_key_permission(
+            0,
+            &ctx,
+            KeyPerm::get_characteristics(),
+            &key,
+            &get_certificates
+        ));
+        assert_perm_failed!(check_key_permission(
+            0,
+            &ctx,
+            KeyPerm::get_info(),
+            &key,
+            &get_certificates
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn check_key_permission_split_get_info() -> Result<()> {
+        install_test_access_control();
+        let shell_ctx = Context::new("u:r:shell:s0")?;
+        let untrusted_app = Context::new("u:r:untrusted_app:s0")?;
+        let app_key = KeyDescriptor { domain: Domain::APP, nspace: 0, alias: None, blob: None };
+        let su_key = KeyDescriptor {
+            domain: Domain::SELINUX,
+            nspace: SU_KEY_NAMESPACE as i64,
+            alias: None,
+            blob: None,
+        };
+
+        // get_info implies the split permissions, whether or not the policy defines them.
+        check_key_permission(0, &shell_ctx, KeyPerm::get_characteristics(), &app_key, &None)?;
+        check_key_permission(0, &shell_ctx, KeyPerm::get_certificates(), &app_key, &None)?;
+        // Without get_info, they are denied, and an undefined permission is not an error.
+        assert_perm_failed!(check_key_permission(
+            0,
+            &untrusted_app,
+            KeyPerm::get_characteristics(),
+            &su_key,
+            &None
+        ));
+        let perms = key_perm_set![KeyPerm::get_characteristics(), KeyPerm::get_certificates()];
+        let check = check_key_permissions(0, &untrusted_app, perms, &su_key, &None)?;
+        assert_eq!(check.denied, perms);
+        assert_eq!(
+            check_key_permission_audit(0, &untrusted_app, perms, &su_key, &None)?,
+            AuditDecision::Denied { missing: perms }
+        );
+        Ok(())
+    }
+
     #[test]
     fn check_key_permission_domain_app() -> Result<()> {
+        install_test_access_control();
         let system_server_ctx = Context::new("u:r:system_server:s0")?;
         let shell_ctx = Context::new("u:r:shell:s0")?;
         let gmscore_app = Context::new("u:r:gmscore_app:s0")?;
@@ -785,7 +1718,34 @@ mod tests {
         assert!(check_key_permission(0, &shell_ctx, KeyPerm::get_info(), &key, &None).is_ok());
         assert!(check_key_permission(0, &shell_ctx, KeyPerm::rebind(), &key, &None).is_ok());
         assert!(check_key_permission(0, &shell_ctx, KeyPerm::update(), &key, &None).is_ok());
+        assert!(
+            check_key_permission(0, &system_server_ctx, KeyPerm::ungrant(), &key, &None).is_ok()
+        );
         assert_perm_failed!(check_key_permission(0, &shell_ctx, KeyPerm::grant(), &key, &None));
+        assert_perm_failed!(check_key_permission(0, &shell_ctx, KeyPerm::ungrant(), &key, &None));
+        // Grants of the keys of other apps cannot be revoked.
+        assert_perm_failed!(check_key_permission(
+            1,
+            &system_server_ctx,
+            KeyPerm::ungrant(),
+            &key,
+            &None
+        ));
+        let e = check_key_permission(0, &shell_ctx, KeyPerm::grant(), &key, &None).unwrap_err();
+        assert_eq!(
+            e.root_cause().downcast_ref::<PermissionDenied>(),
+            Some(&PermissionDenied {
+                class: "keystore2_key",
+                perm: "grant",
+                source_ctx: "u:r:shell:s0".to_string(),
+                target_ctx: Some("u:object_r:keystore:s0".to_string()),
+            })
+        );
+        let e = check_key_permission(1, &shell_ctx, KeyPerm::use_(), &key, &None).unwrap_err();
+        assert_eq!(
+            e.root_cause().downcast_ref::<PermissionDenied>().map(|e| (e.perm, &e.target_ctx)),
+            Some(("use", &None))
+        );
         assert_perm_failed!(check_key_permission(
             0,
             &shell_ctx,
@@ -814,6 +1774,13 @@ mod tests {
             &key,
             &None
         ));
+        assert_perm_failed!(check_key_permission(
+            0,
+            &shell_ctx,
+            KeyPerm::convert_storage_key_to_ephemeral(),
+            &key,
+            &None
+        ));
 
         // Also make sure that the permission fails if the caller is not the owner.
         assert_perm_failed!(check_key_permission(
@@ -844,8 +1811,131 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn check_key_permissions_domain_app() -> Result<()> {
+        install_test_access_control();
+        let shell_ctx = Context::new("u:r:shell:s0")?;
+        let key = KeyDescriptor { domain: Domain::APP, nspace: 0, alias: None, blob: None };
+
+        let check = check_key_permissions(
+            0,
+            &shell_ctx,
+            key_perm_set![KeyPerm::use_(), KeyPerm::grant(), KeyPerm::use_dev_id()],
+            &key,
+            &None,
+        )?;
+        assert_eq!(check.granted, key_perm_set![KeyPerm::use_()]);
+        assert_eq!(check.denied, key_perm_set![KeyPerm::grant(), KeyPerm::use_dev_id()]);
+        assert!(!check.all_granted());
+
+        // A caller that does not own the key only holds what was granted.
+        let check = check_key_permissions(
+            1, // the owner is 0
+            &shell_ctx,
+            key_perm_set![KeyPerm::use_(), KeyPerm::get_info()],
+            &key,
+            &Some(key_perm_set![KeyPerm::get_info()]),
+        )?;
+        assert_eq!(check.granted, key_perm_set![KeyPerm::get_info()]);
+        assert_eq!(check.denied, key_perm_set![KeyPerm::use_()]);
+        Ok(())
+    }
+
+    #[test]
+    fn key_perm_set_algebra() {
+        let a = key_perm_set![KeyPerm::delete(), KeyPerm::use_()];
+        let b = key_perm_set![KeyPerm::use_(), KeyPerm::get_info()];
+        assert_eq!(
+            a.union(b),
+            key_perm_set![KeyPerm::delete(), KeyPerm::use_(), KeyPerm::get_info()]
+        );
+        assert_eq!(a.intersection(b), key_perm_set![KeyPerm::use_()]);
+        assert_eq!(a.difference(b), key_perm_set![KeyPerm::delete()]);
+        assert!(a.contains(KeyPerm::delete()));
+        assert!(!a.contains(KeyPerm::get_info()));
+        assert!(a.difference(a).is_empty());
+        assert!(!a.is_empty());
+        assert_eq!(a.into_iter().collect::<KeyPermSet>(), a);
+        assert_eq!(vec![KeyPerm::delete(), KeyPerm::use_()].into_iter().collect::<KeyPermSet>(), a);
+    }
+
+    #[test]
+    fn key_perm_set_display() {
+        let perms = key_perm_set![KeyPerm::use_(), KeyPerm::delete(), KeyPerm::get_info()];
+        assert_eq!(perms.to_string(), "delete|get_info|use");
+        assert_eq!(format!("{:?}", perms), "KeyPermSet(delete|get_info|use)");
+        assert_eq!(KeyPermSet(0).to_string(), "");
+        assert_eq!(KeyPermSet(1 << 30).to_string(), "0x40000000");
+    }
+
+    #[test]
+    fn key_perm_set_from_str() {
+        let perms = key_perm_set![KeyPerm::use_(), KeyPerm::delete(), KeyPerm::get_info()];
+        assert_eq!("delete,use,get_info".parse::<KeyPermSet>(), Ok(perms));
+        assert_eq!(" delete , use|get_info ".parse::<KeyPermSet>(), Ok(perms));
+        assert_eq!(perms.to_string().parse::<KeyPermSet>(), Ok(perms));
+        assert_eq!("".parse::<KeyPermSet>(), Ok(KeyPermSet(0)));
+        assert_eq!(
+            "delete,frobnicate".parse::<KeyPermSet>(),
+            Err(ParseKeyPermSetError("frobnicate".to_string()))
+        );
+        assert_eq!("delete,,use".parse::<KeyPermSet>(), Err(ParseKeyPermSetError("".to_string())));
+        assert_eq!(
+            "0x40000000".parse::<KeyPermSet>(),
+            Err(ParseKeyPermSetError("0x40000000".to_string()))
+        );
+    }
+
+    #[test]
+    fn check_key_permission_audit_domain_app() -> Result<()> {
+        install_test_access_control();
+        let shell_ctx = Context::new("u:r:shell:s0")?;
+        let key = KeyDescriptor { domain: Domain::APP, nspace: 0, alias: None, blob: None };
+
+        assert_eq!(
+            check_key_permission_audit(0, &shell_ctx, KeyPerm::use_().into(), &key, &None)?,
+            AuditDecision::Allowed
+        );
+        assert_eq!(
+            check_key_permission_audit(
+                0,
+                &shell_ctx,
+                key_perm_set![KeyPerm::use_(), KeyPerm::grant()],
+                &key,
+                &None
+            )?,
+            AuditDecision::Denied { missing: KeyPerm::grant().into() }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_key_permissions_domain_grant() -> Result<()> {
+        install_test_access_control();
+        let key = KeyDescriptor { domain: Domain::GRANT, nspace: 0, alias: None, blob: None };
+        let ctx = selinux::Context::new("ignored").unwrap();
+
+        let check = check_key_permissions(
+            0,
+            &ctx,
+            key_perm_set![KeyPerm::use_(), KeyPerm::delete()],
+            &key,
+            &Some(key_perm_set![KeyPerm::use_()]),
+        )?;
+        assert_eq!(
+            check,
+            KeyPermCheck {
+                granted: key_perm_set![KeyPerm::use_()],
+                denied: key_perm_set![KeyPerm::delete()]
+            }
+        );
+        assert!(check_key_permissions(0, &ctx, UNPRIV_PERMS, &key, &None).is_err());
+        Ok(())
+    }
+
     #[test]
     fn check_key_permission_domain_selinux() -> Result<()> {
+        install_test_access_control();
         let (sctx, namespace, is_su) = check_context()?;
         let key = KeyDescriptor {
             domain: Domain::SELINUX,
@@ -865,6 +1955,14 @@ mod tests {
             assert!(check_key_permission(0, &sctx, KeyPerm::use_dev_id(), &key, &None).is_ok());
             assert!(check_key_permission(0, &sctx, KeyPerm::gen_unique_id(), &key, &None).is_ok());
             assert!(check_key_permission(0, &sctx, KeyPerm::req_forced_op(), &key, &None).is_ok());
+            assert!(check_key_permission(
+                0,
+                &sctx,
+                KeyPerm::convert_storage_key_to_ephemeral(),
+                &key,
+                &None
+            )
+            .is_ok());
         } else {
             assert!(check_key_permission(0, &sctx, KeyPerm::use_(), &key, &None).is_ok());
             assert!(check_key_permission(0, &sctx, KeyPerm::delete(), &key, &None).is_ok());
@@ -894,12 +1992,38 @@ mod tests {
                 &key,
                 &None
             ));
+            assert_perm_failed!(check_key_permission(
+                0,
+                &sctx,
+                KeyPerm::convert_storage_key_to_ephemeral(),
+                &key,
+                &None
+            ));
         }
         Ok(())
     }
 
+    #[test]
+    fn check_key_permission_unknown_namespace() -> Result<()> {
+        install_test_access_control();
+        let (sctx, _, _) = check_context()?;
+        // No sane policy assigns a keystore2_key context to this namespace.
+        let namespace = i64::MAX - 1;
+        let key =
+            KeyDescriptor { domain: Domain::SELINUX, nspace: namespace, ..Default::default() };
+
+        let result = check_key_permission(0, &sctx, KeyPerm::use_(), &key, &None);
+        assert_eq!(
+            Some(&UnknownNamespace(namespace)),
+            result.unwrap_err().root_cause().downcast_ref::<UnknownNamespace>()
+        );
+        assert_eq!(Some(&namespace), recent_unknown_namespaces().last());
+        Ok(())
+    }
+
     #[test]
     fn check_key_permission_domain_blob() -> Result<()> {
+        install_test_access_control();
         let (sctx, namespace, is_su) = check_context()?;
         let key = KeyDescriptor {
             domain: Domain::BLOB,
@@ -918,6 +2042,7 @@ mod tests {
 
     #[test]
     fn check_key_permission_domain_key_id() -> Result<()> {
+        install_test_access_control();
         let key = KeyDescriptor { domain: Domain::KEY_ID, nspace: 0, alias: None, blob: None };
 
         assert_eq!(
@@ -1051,6 +2176,20 @@ mod tests {
         assert!(!v1.includes(v2));
         assert!(!v2.includes(v1));
     }
+    #[test]
+    fn check_client_context_test() -> Result<()> {
+        let vold = Context::new("u:r:vold:s0")?;
+        let system_server = Context::new("u:r:system_server:s0")?;
+        assert!(check_client_context(&system_server, None).is_ok());
+        assert!(check_client_context(&vold, Some("u:r:vold:s0")).is_ok());
+        assert!(check_client_context(&vold, Some("u:r:vold:*")).is_ok());
+        assert!(check_client_context(&vold, Some("*:vold:*")).is_ok());
+        assert_perm_failed!(check_client_context(&system_server, Some("u:r:vold:s0")));
+        assert_perm_failed!(check_client_context(&system_server, Some("u:r:vold*")));
+        assert_perm_failed!(check_client_context(&vold, Some("u:r:vold")));
+        Ok(())
+    }
+
     #[test]
     fn key_perm_set_include_no_overlap_test() {
         let v1 = key_perm_set![KeyPerm::manage_blob(), KeyPerm::delete(), KeyPerm::grant(),];
@@ -1063,4 +2202,92 @@ mod tests {
         assert!(!v1.includes(v2));
         assert!(!v2.includes(v1));
     }
+
+    #[test]
+    fn permission_manifest_test() {
+        let manifest = permission_manifest();
+        assert!(manifest.starts_with("class keystore2\n{\n\tadd_auth\n\tclear_ns\n"));
+        assert!(manifest.contains("class keystore2_key\n{\n\tconvert_storage_key_to_ephemeral\n"));
+        assert!(!manifest.contains("\tnone\n"));
+        assert_eq!(check_permission_manifest(&manifest), Vec::<String>::new());
+    }
+
+    #[test]
+    fn check_permission_manifest_test() {
+        let keystore2 = KeystorePerm::SELINUX_NAMES.join(" ");
+        let key_perms = KeyPerm::SELINUX_NAMES
+            .iter()
+            .filter(|p| **p != "use_dev_id")
+            .cloned()
+            .collect::<Vec<_>>()
+            .join("\n    ");
+        let policy = format!(
+            concat!(
+                "# class keystore2_key {{ bogus }}\n",
+                "class keystore2\n",
+                "class keystore2_key\n",
+                "common file {{ ioctl read }}\n",
+                "class keystore2 {{ {} }}\n",
+                "class keystore2_key\n",
+                "{{\n",
+                "    {}\n",
+                "    frobnicate # Not checked.\n",
+                "}}\n",
+            ),
+            keystore2, key_perms
+        );
+        assert_eq!(
+            check_permission_manifest(&policy),
+            vec![
+                "Permission keystore2_key:use_dev_id is not defined by the policy.".to_string(),
+                "Permission keystore2_key:frobnicate is not checked by Keystore.".to_string(),
+            ]
+        );
+        let skew = check_permission_manifest("class keystore2 { add_auth }");
+        assert_eq!(skew.len(), KeystorePerm::SELINUX_NAMES.len());
+        assert!(
+            skew.contains(&"Permission keystore2:clear_ns is not defined by the policy.".into())
+        );
+        assert!(skew.contains(&"Class keystore2_key is not defined by the policy.".into()));
+    }
+
+    /// Compares the permission manifest with the platform policy, which the test configuration
+    /// installs next to the test binary. A missing policy fails the test rather than skipping
+    /// it, so that a broken test configuration cannot hide a skew.
+    #[test]
+    fn permission_manifest_matches_platform_policy() -> Result<()> {
+        let path = std::env::current_exe()?.with_file_name("plat_sepolicy.conf");
+        let policy = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read the test data {:?}: {:?}", path, e));
+        assert_eq!(check_permission_manifest(&policy), Vec::<String>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn app_key_level_from_uid() -> Result<()> {
+        assert_eq!(LevelFrom::from_selector("user"), Some(LevelFrom::User));
+        assert_eq!(LevelFrom::from_selector("mls"), None);
+
+        // App 10456 of user 10, i.e., app id 456 = 0x1c8.
+        let uid = 10 * AID_USER_OFFSET + 10456;
+        assert_eq!(LevelFrom::None.level(uid), None);
+        assert_eq!(LevelFrom::User.level(uid), Some("s0:c522,c768".to_string()));
+        assert_eq!(LevelFrom::App.level(uid), Some("s0:c200,c257".to_string()));
+        assert_eq!(LevelFrom::All.level(uid), Some("s0:c200,c257,c522,c768".to_string()));
+        // System uids keep Keystore's level.
+        assert_eq!(LevelFrom::All.level(1000), None);
+
+        let keystore = selinux::Context::new("u:r:keystore:s0")?;
+        assert_eq!(
+            with_level(&keystore, "s0:c200,c257")?,
+            selinux::Context::new("u:r:keystore:s0:c200,c257")?
+        );
+        let with_categories = selinux::Context::new("u:r:keystore:s0:c1,c2")?;
+        assert_eq!(
+            with_level(&with_categories, "s0:c522,c768")?,
+            selinux::Context::new("u:r:keystore:s0:c522,c768")?
+        );
+        assert!(with_level(&selinux::Context::new("keystore")?, "s0").is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,244 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements storage quotas for the namespaces of apps and of Domain::SELINUX.
+//! Without them, a misbehaving app can create keys until /data/misc/keystore is full, which
+//! breaks Keystore for everyone else. Quotas are enforced by `KeystoreDB` when a key or
+//! certificate is stored, in the same transaction, and a violation is reported to the client
+//! as `KeystoreErrorCode::QUOTA_EXCEEDED`.
+//!
+//! The configuration file holds one line per rule of the form
+//! `<selector> [max_keys=<n>] [max_bytes=<n>]`, where the selector is either
+//! `uid=<first>[-<last>]`, which covers the Domain::APP namespaces of the given app ids in all
+//! users, or `namespace=<first>[-<last>]`, which covers Domain::SELINUX namespaces, e.g.,
+//! `uid=10000-19999 max_keys=2000 max_bytes=8388608`. `max_keys` limits the number of keys and
+//! `max_bytes` the combined size of their key blobs and certificates. The limits apply to each
+//! covered namespace on its own. If several rules cover a namespace, the lowest limits apply.
+//! Empty lines and lines starting with `#` are ignored. If the file is malformed, storing keys
+//! in any namespace fails, see `config_file`.
+
+use crate::config_file::{self, parse_range};
+use crate::utils::AID_USER_OFFSET;
+use android_system_keystore2::aidl::android::system::keystore2::Domain::Domain;
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use std::io::Write;
+use std::ops::RangeInclusive;
+
+/// Location of the quota configuration.
+const QUOTA_CONFIG: &str = "/vendor/etc/security/keystore2_quotas.conf";
+
+lazy_static! {
+    /// The quotas of this device, loaded once on first use.
+    static ref QUOTAS: Result<Quotas> = config_file::load(QUOTA_CONFIG, Quotas::parse);
+}
+
+/// Storing a key would exceed the quota of its namespace. This is reported as
+/// `KeystoreErrorCode::QUOTA_EXCEEDED`.
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("Namespace {namespace} of domain {domain} exceeded its storage quota.")]
+pub struct QuotaExceeded {
+    /// The domain of the namespace.
+    pub domain: i32,
+    /// The namespace.
+    pub namespace: i64,
+}
+
+/// The storage limits of a namespace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximal number of keys.
+    pub max_keys: Option<usize>,
+    /// The maximal combined size of the key blobs and certificates in bytes.
+    pub max_bytes: Option<usize>,
+}
+
+impl Limits {
+    /// Returns true if there is no limit.
+    pub fn is_unlimited(&self) -> bool {
+        self.max_keys.is_none() && self.max_bytes.is_none()
+    }
+
+    fn merge(&mut self, other: &Limits) {
+        let min = |a: Option<usize>, b: Option<usize>| match (a, b) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.max_keys = min(self.max_keys, other.max_keys);
+        self.max_bytes = min(self.max_bytes, other.max_bytes);
+    }
+
+    /// Returns an error if a namespace with `keys` keys holding `bytes` bytes exceeds the
+    /// limits.
+    pub fn check(&self, domain: Domain, namespace: i64, keys: usize, bytes: usize) -> Result<()> {
+        let exceeded = |what: String| {
+            Err(anyhow!(QuotaExceeded { domain: domain.0, namespace }))
+                .context(format!("In Limits::check: {}.", what))
+        };
+        match (self.max_keys, self.max_bytes) {
+            (Some(max_keys), _) if keys > max_keys => {
+                exceeded(format!("{} keys of {}", keys, max_keys))
+            }
+            (_, Some(max_bytes)) if bytes > max_bytes => {
+                exceeded(format!("{} bytes of {}", bytes, max_bytes))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    domain: Domain,
+    /// App ids for Domain::APP, namespaces for Domain::SELINUX.
+    range: RangeInclusive<i64>,
+    limits: Limits,
+}
+
+fn parse_rule(line: &str) -> Result<Rule> {
+    let mut fields = line.split_whitespace();
+    let selector = fields.next().unwrap_or("");
+    let (domain, range) = if let Some(range) = selector.strip_prefix("uid=") {
+        (Domain::APP, parse_range(range)?)
+    } else if let Some(range) = selector.strip_prefix("namespace=") {
+        (Domain::SELINUX, parse_range(range)?)
+    } else {
+        return Err(anyhow!("Rules must start with \"uid=\" or \"namespace=\"."));
+    };
+    let mut limits: Limits = Default::default();
+    for limit in fields {
+        let (name, value) =
+            limit.split_once('=').ok_or_else(|| anyhow!("Bad limit \"{}\".", limit))?;
+        let value: usize = value.parse().map_err(|_| anyhow!("Bad limit \"{}\".", limit))?;
+        match name {
+            "max_keys" => limits.max_keys = Some(value),
+            "max_bytes" => limits.max_bytes = Some(value),
+            _ => return Err(anyhow!("Bad limit \"{}\".", limit)),
+        }
+    }
+    if limits.is_unlimited() {
+        return Err(anyhow!("Missing limits."));
+    }
+    Ok(Rule { domain, range, limits })
+}
+
+/// The compiled quota configuration.
+#[derive(Debug, Default)]
+pub struct Quotas {
+    rules: Vec<Rule>,
+}
+
+impl Quotas {
+    /// Parses the quota configuration. See the module documentation for the format.
+    pub fn parse(config: &str) -> Result<Self> {
+        let mut rules = Vec::new();
+        for (n, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            rules.push(parse_rule(line).with_context(|| format!("In line {}.", n + 1))?);
+        }
+        Ok(Self { rules })
+    }
+
+    /// Returns the limits of the given namespace.
+    pub fn limits(&self, domain: Domain, namespace: i64) -> Limits {
+        let id = match domain {
+            Domain::APP => namespace % AID_USER_OFFSET as i64,
+            Domain::SELINUX => namespace,
+            _ => return Default::default(),
+        };
+        let mut limits: Limits = Default::default();
+        for rule in self.rules.iter().filter(|r| r.domain == domain && r.range.contains(&id)) {
+            limits.merge(&rule.limits);
+        }
+        limits
+    }
+}
+
+/// Returns the limits of the given namespace according to the configuration of this device.
+/// Fails if the configuration is malformed.
+pub fn limits(domain: Domain, namespace: i64) -> Result<Limits> {
+    match &*QUOTAS {
+        Ok(quotas) => Ok(quotas.limits(domain, namespace)),
+        Err(e) => Err(anyhow!("In limits: Quota configuration unavailable: {:?}", e)),
+    }
+}
+
+/// Writes all quota rules to `f`.
+pub fn dump(f: &mut dyn Write) -> std::io::Result<()> {
+    writeln!(f, "Storage quotas:")?;
+    match &*QUOTAS {
+        Ok(quotas) => {
+            for rule in &quotas.rules {
+                writeln!(f, "  {:?} {:?}: {:?}", rule.domain, rule.range, rule.limits)?;
+            }
+        }
+        Err(e) => writeln!(f, "  Malformed configuration: {:?}", e)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let quotas = Quotas::parse(
+            "# Apps.
+             uid=10000-19999 max_keys=100 max_bytes=65536
+
+             uid=10057 max_keys=10
+             namespace=102 max_bytes=4096",
+        )?;
+        assert_eq!(
+            quotas.limits(Domain::APP, 1_010_057),
+            Limits { max_keys: Some(10), max_bytes: Some(65536) }
+        );
+        assert_eq!(
+            quotas.limits(Domain::APP, 10100),
+            Limits { max_keys: Some(100), max_bytes: Some(65536) }
+        );
+        assert_eq!(
+            quotas.limits(Domain::SELINUX, 102),
+            Limits { max_bytes: Some(4096), ..Default::default() }
+        );
+        assert!(quotas.limits(Domain::APP, 102).is_unlimited());
+        assert!(quotas.limits(Domain::SELINUX, 10100).is_unlimited());
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_config() {
+        assert!(Quotas::parse("10000 max_keys=1").is_err());
+        assert!(Quotas::parse("uid=10000").is_err());
+        assert!(Quotas::parse("uid=9-1 max_keys=1").is_err());
+        assert!(Quotas::parse("namespace=102 max_keys=-1").is_err());
+        assert!(Quotas::parse("namespace=102 max_blobs=1").is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let limits = Limits { max_keys: Some(2), max_bytes: Some(100) };
+        assert!(limits.check(Domain::APP, 10001, 2, 100).is_ok());
+        let e = limits.check(Domain::APP, 10001, 3, 0).unwrap_err();
+        assert_eq!(
+            e.root_cause().downcast_ref::<QuotaExceeded>(),
+            Some(&QuotaExceeded { domain: Domain::APP.0, namespace: 10001 })
+        );
+        assert!(limits.check(Domain::APP, 10001, 1, 101).is_err());
+    }
+}
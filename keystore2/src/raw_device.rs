@@ -16,8 +16,9 @@
 
 use crate::{
     database::{
-        BlobMetaData, BlobMetaEntry, CertificateInfo, DateTime, KeyEntry, KeyEntryLoadBits,
-        KeyIdGuard, KeyMetaData, KeyMetaEntry, KeyType, KeystoreDB, SubComponentType, Uuid,
+        BlobMetaData, BlobMetaEntry, CertificateInfo, DateTime, GrantRebindPolicy, KeyEntry,
+        KeyEntryLoadBits, KeyIdGuard, KeyMetaData, KeyMetaEntry, KeyType, KeystoreDB,
+        SubComponentType, Uuid,
     },
     error::{map_km_error, Error, ErrorCode},
     globals::get_keymint_device,
@@ -127,6 +128,7 @@ impl KeyMintDevice {
             &CertificateInfo::new(None, None),
             &key_metadata,
             &self.km_uuid,
+            GrantRebindPolicy::Keep,
         )
         .context("In create_and_store_key: store_new_key failed")?;
         Ok(())
@@ -331,3 +333,165 @@ impl KeyMintDevice {
         .context("In use_key_in_one_step: Failed to finish operation.")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+        AttestationKey::AttestationKey, BeginResult::BeginResult,
+        IKeyMintDevice::BnKeyMintDevice, KeyFormat::KeyFormat,
+        KeyMintHardwareInfo::KeyMintHardwareInfo,
+    };
+    use android_hardware_security_keymint::binder::{
+        BinderFeatures, Interface, Result as BinderResult, Status as BinderStatus,
+    };
+    use android_hardware_security_secureclock::aidl::android::hardware::security::secureclock::TimeStampToken::TimeStampToken;
+    use keystore2_test_utils::TempDir;
+
+    const OLD_PREFIX: &[u8] = b"OLD";
+    const NEW_PREFIX: &[u8] = b"NEW";
+
+    /// A KeyMint device that only knows how to upgrade key blobs. Blobs starting with
+    /// `OLD_PREFIX` are upgraded by replacing the prefix with `NEW_PREFIX`.
+    struct UpgradingDevice;
+
+    impl Interface for UpgradingDevice {}
+
+    fn unimplemented<T>() -> BinderResult<T> {
+        Err(BinderStatus::new_service_specific_error(ErrorCode::UNIMPLEMENTED.0, None))
+    }
+
+    #[allow(non_snake_case)]
+    impl IKeyMintDevice for UpgradingDevice {
+        fn getHardwareInfo(&self) -> BinderResult<KeyMintHardwareInfo> {
+            unimplemented()
+        }
+        fn addRngEntropy(&self, _data: &[u8]) -> BinderResult<()> {
+            unimplemented()
+        }
+        fn generateKey(
+            &self,
+            _key_params: &[KeyParameter],
+            _attestation_key: Option<&AttestationKey>,
+        ) -> BinderResult<KeyCreationResult> {
+            unimplemented()
+        }
+        fn importKey(
+            &self,
+            _key_params: &[KeyParameter],
+            _key_format: KeyFormat,
+            _key_data: &[u8],
+            _attestation_key: Option<&AttestationKey>,
+        ) -> BinderResult<KeyCreationResult> {
+            unimplemented()
+        }
+        fn importWrappedKey(
+            &self,
+            _wrapped_key_data: &[u8],
+            _wrapping_key_blob: &[u8],
+            _masking_key: &[u8],
+            _unwrapping_params: &[KeyParameter],
+            _password_sid: i64,
+            _biometric_sid: i64,
+        ) -> BinderResult<KeyCreationResult> {
+            unimplemented()
+        }
+        fn upgradeKey(
+            &self,
+            key_blob_to_upgrade: &[u8],
+            _upgrade_params: &[KeyParameter],
+        ) -> BinderResult<Vec<u8>> {
+            match key_blob_to_upgrade.strip_prefix(OLD_PREFIX) {
+                Some(rest) => Ok([NEW_PREFIX, rest].concat()),
+                None => Err(BinderStatus::new_service_specific_error(
+                    ErrorCode::INVALID_KEY_BLOB.0,
+                    None,
+                )),
+            }
+        }
+        fn deleteKey(&self, _key_blob: &[u8]) -> BinderResult<()> {
+            unimplemented()
+        }
+        fn deleteAllKeys(&self) -> BinderResult<()> {
+            unimplemented()
+        }
+        fn destroyAttestationIds(&self) -> BinderResult<()> {
+            unimplemented()
+        }
+        fn begin(
+            &self,
+            _purpose: KeyPurpose,
+            _key_blob: &[u8],
+            _params: &[KeyParameter],
+            _auth_token: Option<&HardwareAuthToken>,
+        ) -> BinderResult<BeginResult> {
+            unimplemented()
+        }
+        fn deviceLocked(
+            &self,
+            _password_only: bool,
+            _timestamp_token: Option<&TimeStampToken>,
+        ) -> BinderResult<()> {
+            unimplemented()
+        }
+        fn earlyBootEnded(&self) -> BinderResult<()> {
+            unimplemented()
+        }
+        fn convertStorageKeyToEphemeral(&self, _storage_key_blob: &[u8]) -> BinderResult<Vec<u8>> {
+            unimplemented()
+        }
+        fn getKeyCharacteristics(
+            &self,
+            _key_blob: &[u8],
+            _app_id: &[u8],
+            _app_data: &[u8],
+        ) -> BinderResult<Vec<KeyCharacteristics>> {
+            unimplemented()
+        }
+    }
+
+    fn stored_blob(db: &mut KeystoreDB, key_desc: &KeyDescriptor) -> Result<Vec<u8>> {
+        let (_, mut key_entry) = KeyMintDevice::lookup_from_desc(db, key_desc, KeyType::Client)?;
+        Ok(key_entry.take_key_blob_info().expect("Key blob missing.").0)
+    }
+
+    #[test]
+    fn test_upgrade_keyblob_if_required_with() -> Result<()> {
+        let temp_dir = TempDir::new("raw_device_upgrade_test")?;
+        let mut db = KeystoreDB::new(temp_dir.path(), None)?;
+        let km_dev = KeyMintDevice {
+            km_dev: BnKeyMintDevice::new_binder(UpgradingDevice, BinderFeatures::default()),
+            km_uuid: Uuid::from(SecurityLevel::TRUSTED_ENVIRONMENT),
+            version: KeyMintDevice::KEY_MINT_V1,
+            security_level: SecurityLevel::TRUSTED_ENVIRONMENT,
+        };
+        let key_desc = KeyMintDevice::internal_descriptor("upgrade_test_key".to_string());
+        km_dev.create_and_store_key(&mut db, &key_desc, KeyType::Client, |_| {
+            Ok(KeyCreationResult { keyBlob: b"OLDblob".to_vec(), ..Default::default() })
+        })?;
+
+        let (key_id_guard, mut key_entry) =
+            KeyMintDevice::lookup_from_desc(&mut db, &key_desc, KeyType::Client)?;
+        let (old_blob, _) = key_entry.take_key_blob_info().expect("Key blob missing.");
+
+        // The closure behaves like a KeyMint call that rejects stale blobs.
+        let (used_blob, upgraded_blob) = km_dev.upgrade_keyblob_if_required_with(
+            &mut db,
+            &key_id_guard,
+            KeyBlob::NonSensitive(old_blob),
+            |blob| {
+                if blob.starts_with(OLD_PREFIX) {
+                    Err(Error::Km(ErrorCode::KEY_REQUIRES_UPGRADE))
+                } else {
+                    Ok(blob.to_vec())
+                }
+            },
+        )?;
+        drop(key_id_guard);
+
+        assert_eq!(used_blob, b"NEWblob".to_vec());
+        assert_eq!(&*upgraded_blob, b"NEWblob");
+        assert_eq!(stored_blob(&mut db, &key_desc)?, b"NEWblob".to_vec());
+        Ok(())
+    }
+}
@@ -23,8 +23,7 @@ use std::collections::HashMap;
 
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     Algorithm::Algorithm, AttestationKey::AttestationKey, Certificate::Certificate,
-    DeviceInfo::DeviceInfo, IRemotelyProvisionedComponent::IRemotelyProvisionedComponent,
-    KeyParameter::KeyParameter, KeyParameterValue::KeyParameterValue,
+    DeviceInfo::DeviceInfo, KeyParameter::KeyParameter, KeyParameterValue::KeyParameterValue,
     MacedPublicKey::MacedPublicKey, ProtectedData::ProtectedData, SecurityLevel::SecurityLevel,
     Tag::Tag,
 };
@@ -40,11 +39,15 @@ use anyhow::{Context, Result};
 use keystore2_crypto::parse_subject_from_certificate;
 use std::sync::atomic::{AtomicBool, Ordering};
 
+use crate::database::io_stats::{self, Subsystem};
 use crate::database::{CertificateChain, KeystoreDB, Uuid};
 use crate::error::{self, map_or_log_err, map_rem_prov_error, Error};
-use crate::globals::{get_keymint_device, get_remotely_provisioned_component, DB};
+use crate::globals::{
+    call_rkp_with_retry, get_keymint_device, get_remotely_provisioned_component, Idempotent, DB,
+};
+use crate::hal_hotplug;
 use crate::metrics_store::log_rkp_error_stats;
-use crate::utils::{watchdog as wd, Asp};
+use crate::utils::watchdog as wd;
 use android_security_metrics::aidl::android::security::metrics::RkpError::RkpError as MetricsRkpError;
 
 /// Contains helper functions to check if remote provisioning is enabled on the system and, if so,
@@ -93,6 +96,7 @@ impl RemProvState {
         caller_uid: u32,
         db: &mut KeystoreDB,
     ) -> Result<Option<CertificateChain>> {
+        let _subsystem = io_stats::enter(Subsystem::Rkp);
         match key.domain {
             Domain::APP => {
                 // Attempt to get an Attestation Key once. If it fails, then the app doesn't
@@ -215,23 +219,35 @@ impl RemProvState {
         }
     }
 }
+
+/// Returns the EEK curve supported by the IRemotelyProvisionedComponent of the given security
+/// level. This also connects to the component if it was not connected yet.
+pub fn get_supported_eek_curve(sec_level: &SecurityLevel) -> Result<i32> {
+    call_rkp_with_retry(sec_level, Idempotent("getHardwareInfo only reads."), |dev| {
+        let _wp = wd::watch_millis("In get_supported_eek_curve: calling getHardwareInfo.", 500);
+        map_rem_prov_error(dev.getHardwareInfo())
+            .context("In get_supported_eek_curve: Failed to get hardware info.")
+    })
+    .map(|hw_info| hw_info.supportedEekCurve)
+}
+
 /// Implementation of the IRemoteProvisioning service.
 #[derive(Default)]
 pub struct RemoteProvisioningService {
-    device_by_sec_level: HashMap<SecurityLevel, Asp>,
     curve_by_sec_level: HashMap<SecurityLevel, i32>,
 }
 
 impl RemoteProvisioningService {
-    fn get_dev_by_sec_level(
-        &self,
-        sec_level: &SecurityLevel,
-    ) -> Result<Strong<dyn IRemotelyProvisionedComponent>> {
-        if let Some(dev) = self.device_by_sec_level.get(sec_level) {
-            dev.get_interface().context("In get_dev_by_sec_level.")
+    /// Checks that an IRemotelyProvisionedComponent is available for the given security level.
+    /// The connection itself is managed by `globals`, see `call_rkp_with_retry`.
+    fn check_dev_available(&self, sec_level: &SecurityLevel) -> Result<()> {
+        if self.curve_by_sec_level.contains_key(sec_level)
+            || hal_hotplug::has_remotely_provisioned_component(*sec_level)
+        {
+            Ok(())
         } else {
             Err(error::Error::sys()).context(concat!(
-                "In get_dev_by_sec_level: Remote instance for requested security level",
+                "In check_dev_available: Remote instance for requested security level",
                 " not found."
             ))
         }
@@ -240,27 +256,19 @@ impl RemoteProvisioningService {
     /// Creates a new instance of the remote provisioning service
     pub fn new_native_binder() -> Result<Strong<dyn IRemoteProvisioning>> {
         let mut result: Self = Default::default();
-        let dev = get_remotely_provisioned_component(&SecurityLevel::TRUSTED_ENVIRONMENT)
-            .context("In new_native_binder: Failed to get TEE Remote Provisioner instance.")?;
-        let rkp_tee_dev: Strong<dyn IRemotelyProvisionedComponent> = dev.get_interface()?;
         result.curve_by_sec_level.insert(
             SecurityLevel::TRUSTED_ENVIRONMENT,
-            rkp_tee_dev
-                .getHardwareInfo()
-                .context("In new_native_binder: Failed to get hardware info for the TEE.")?
-                .supportedEekCurve,
+            get_supported_eek_curve(&SecurityLevel::TRUSTED_ENVIRONMENT)
+                .context("In new_native_binder: Failed to get TEE Remote Provisioner instance.")?,
         );
-        result.device_by_sec_level.insert(SecurityLevel::TRUSTED_ENVIRONMENT, dev);
-        if let Ok(dev) = get_remotely_provisioned_component(&SecurityLevel::STRONGBOX) {
-            let rkp_sb_dev: Strong<dyn IRemotelyProvisionedComponent> = dev.get_interface()?;
+        if get_remotely_provisioned_component(&SecurityLevel::STRONGBOX).is_ok() {
             result.curve_by_sec_level.insert(
                 SecurityLevel::STRONGBOX,
-                rkp_sb_dev
-                    .getHardwareInfo()
-                    .context("In new_native_binder: Failed to get hardware info for StrongBox.")?
-                    .supportedEekCurve,
+                get_supported_eek_curve(&SecurityLevel::STRONGBOX)
+                    .context("In new_native_binder: Failed to get hardware info for StrongBox.")?,
             );
-            result.device_by_sec_level.insert(SecurityLevel::STRONGBOX, dev);
+        } else {
+            hal_hotplug::watch_remotely_provisioned_component(SecurityLevel::STRONGBOX);
         }
         Ok(BnRemoteProvisioning::new_binder(result, BinderFeatures::default()))
     }
@@ -284,7 +292,7 @@ impl RemoteProvisioningService {
         protected_data: &mut ProtectedData,
         device_info: &mut DeviceInfo,
     ) -> Result<Vec<u8>> {
-        let dev = self.get_dev_by_sec_level(&sec_level)?;
+        self.check_dev_available(&sec_level).context("In generate_csr.")?;
         let (_, _, uuid) = get_keymint_device(&sec_level)?;
         let keys_to_sign = DB.with::<_, Result<Vec<MacedPublicKey>>>(|db| {
             let mut db = db.borrow_mut();
@@ -294,15 +302,27 @@ impl RemoteProvisioningService {
                 .map(|key| MacedPublicKey { macedKey: key.to_vec() })
                 .collect())
         })?;
-        let mut mac = map_rem_prov_error(dev.generateCertificateRequest(
-            test_mode,
-            &keys_to_sign,
-            eek,
-            challenge,
-            device_info,
-            protected_data,
-        ))
-        .context("In generate_csr: Failed to generate csr")?;
+        let (mut mac, new_device_info, new_protected_data) = call_rkp_with_retry(
+            &sec_level,
+            Idempotent("The component keeps no state between certificate requests."),
+            |dev| {
+                let mut device_info = DeviceInfo::default();
+                let mut protected_data = ProtectedData::default();
+                let mac = map_rem_prov_error(dev.generateCertificateRequest(
+                    test_mode,
+                    &keys_to_sign,
+                    eek,
+                    challenge,
+                    &mut device_info,
+                    &mut protected_data,
+                ))
+                .context("In generate_csr: Failed to generate csr")?;
+                Ok((mac, device_info, protected_data))
+            },
+        )
+        .context("In generate_csr.")?;
+        *device_info = new_device_info;
+        *protected_data = new_protected_data;
         // TODO(b/180392379): Replace this manual CBOR generation with the cbor-serde crate as well.
         //                    This generates an array consisting of the mac and the public key Maps.
         //                    Just generate the actual MacedPublicKeys structure when the crate is
@@ -361,11 +381,19 @@ impl RemoteProvisioningService {
     /// the key pair is then added to the database.
     pub fn generate_key_pair(&self, is_test_mode: bool, sec_level: SecurityLevel) -> Result<()> {
         let (_, _, uuid) = get_keymint_device(&sec_level)?;
-        let dev = self.get_dev_by_sec_level(&sec_level)?;
-        let mut maced_key = MacedPublicKey { macedKey: Vec::new() };
-        let priv_key =
-            map_rem_prov_error(dev.generateEcdsaP256KeyPair(is_test_mode, &mut maced_key))
-                .context("In generate_key_pair: Failed to generated ECDSA keypair.")?;
+        self.check_dev_available(&sec_level).context("In generate_key_pair.")?;
+        let (priv_key, maced_key) = call_rkp_with_retry(
+            &sec_level,
+            Idempotent("The component keeps no state about the key pairs it generates."),
+            |dev| {
+                let mut maced_key = MacedPublicKey { macedKey: Vec::new() };
+                let priv_key =
+                    map_rem_prov_error(dev.generateEcdsaP256KeyPair(is_test_mode, &mut maced_key))
+                        .context("In generate_key_pair: Failed to generated ECDSA keypair.")?;
+                Ok((priv_key, maced_key))
+            },
+        )
+        .context("In generate_key_pair.")?;
         // TODO(b/180392379): This is a brittle hack that relies on the consistent formatting of
         //                    the returned CBOR blob in order to extract the public key.
         let data = &maced_key.macedKey;
@@ -390,7 +418,9 @@ impl RemoteProvisioningService {
         Ok(self
             .curve_by_sec_level
             .iter()
-            .map(|(sec_level, curve)| ImplInfo { secLevel: *sec_level, supportedCurve: *curve })
+            .map(|(sec_level, curve)| (*sec_level, *curve))
+            .chain(hal_hotplug::remotely_provisioned_component_curves())
+            .map(|(sec_level, curve)| ImplInfo { secLevel: sec_level, supportedCurve: curve })
             .collect())
     }
 
@@ -430,6 +460,8 @@ impl IRemoteProvisioning for RemoteProvisioningService {
         expired_by: i64,
         sec_level: SecurityLevel,
     ) -> binder::public_api::Result<AttestationPoolStatus> {
+        let _subsystem = io_stats::enter(Subsystem::Rkp);
+
         let _wp = wd::watch_millis("IRemoteProvisioning::getPoolStatus", 500);
         map_or_log_err(get_pool_status(expired_by, sec_level), Ok)
     }
@@ -444,6 +476,8 @@ impl IRemoteProvisioning for RemoteProvisioningService {
         protected_data: &mut ProtectedData,
         device_info: &mut DeviceInfo,
     ) -> binder::public_api::Result<Vec<u8>> {
+        let _subsystem = io_stats::enter(Subsystem::Rkp);
+
         let _wp = wd::watch_millis("IRemoteProvisioning::generateCsr", 500);
         map_or_log_err(
             self.generate_csr(
@@ -467,6 +501,8 @@ impl IRemoteProvisioning for RemoteProvisioningService {
         expiration_date: i64,
         sec_level: SecurityLevel,
     ) -> binder::public_api::Result<()> {
+        let _subsystem = io_stats::enter(Subsystem::Rkp);
+
         let _wp = wd::watch_millis("IRemoteProvisioning::provisionCertChain", 500);
         map_or_log_err(
             self.provision_cert_chain(public_key, batch_cert, certs, expiration_date, sec_level),
@@ -479,16 +515,22 @@ impl IRemoteProvisioning for RemoteProvisioningService {
         is_test_mode: bool,
         sec_level: SecurityLevel,
     ) -> binder::public_api::Result<()> {
+        let _subsystem = io_stats::enter(Subsystem::Rkp);
+
         let _wp = wd::watch_millis("IRemoteProvisioning::generateKeyPair", 500);
         map_or_log_err(self.generate_key_pair(is_test_mode, sec_level), Ok)
     }
 
     fn getImplementationInfo(&self) -> binder::public_api::Result<Vec<ImplInfo>> {
+        let _subsystem = io_stats::enter(Subsystem::Rkp);
+
         let _wp = wd::watch_millis("IRemoteProvisioning::getSecurityLevels", 500);
         map_or_log_err(self.get_implementation_info(), Ok)
     }
 
     fn deleteAllKeys(&self) -> binder::public_api::Result<i64> {
+        let _subsystem = io_stats::enter(Subsystem::Rkp);
+
         let _wp = wd::watch_millis("IRemoteProvisioning::deleteAllKeys", 500);
         map_or_log_err(self.delete_all_keys(), Ok)
     }
@@ -0,0 +1,161 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the IKeystoreSecureImport AIDL interface, a session based variant of
+//! `IKeystoreSecurityLevel::importWrappedKey`. The sessions are managed by the security levels,
+//! see `KeystoreSecurityLevel::begin_import_session`.
+
+use crate::async_keygen::{get_security_level, get_security_level_for_new_keys};
+use crate::caller_identity::CallerIdentity;
+use crate::error::map_or_log_err;
+use crate::utils::watchdog as wd;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyParameter::KeyParameter, SecurityLevel::SecurityLevel,
+};
+use android_security_secureimport::aidl::android::security::secureimport::{
+    IKeystoreSecureImport::{BnKeystoreSecureImport, IKeystoreSecureImport},
+    ImportSessionInfo::ImportSessionInfo,
+};
+use android_security_secureimport::binder::{
+    BinderFeatures, Interface, Result as BinderResult, Strong,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    AuthenticatorSpec::AuthenticatorSpec, KeyDescriptor::KeyDescriptor, KeyMetadata::KeyMetadata,
+};
+use anyhow::{Context, Result};
+
+/// This struct is defined to implement the aforementioned AIDL interface.
+pub struct SecureImport;
+
+impl SecureImport {
+    /// Creates a new instance of the secure import service wrapped in a BnKeystoreSecureImport
+    /// proxy object. It also enables `BinderFeatures::set_requesting_sid` on the new interface,
+    /// because the permission checks of importWrappedKey require it.
+    pub fn new_native_binder() -> Result<Strong<dyn IKeystoreSecureImport>> {
+        Ok(BnKeystoreSecureImport::new_binder(
+            Self,
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        ))
+    }
+
+    fn begin_import_session(
+        security_level: SecurityLevel,
+        wrapping_key: &KeyDescriptor,
+    ) -> Result<ImportSessionInfo> {
+        let sec_level =
+            get_security_level_for_new_keys(security_level).context("In begin_import_session.")?;
+        let (session_id, wrapping_key_certificate) = sec_level
+            .begin_import_session(wrapping_key, &CallerIdentity::current())
+            .context("In begin_import_session.")?;
+        Ok(ImportSessionInfo {
+            sessionId: session_id,
+            wrappingKeyCertificate: wrapping_key_certificate,
+        })
+    }
+
+    fn update_import_session(
+        security_level: SecurityLevel,
+        session_id: i64,
+        wrapped_key_data: &[u8],
+        params: &[KeyParameter],
+        masking_key: Option<&[u8]>,
+    ) -> Result<()> {
+        get_security_level(security_level)
+            .context("In update_import_session.")?
+            .update_import_session(
+                session_id,
+                wrapped_key_data,
+                params,
+                masking_key,
+                &CallerIdentity::current(),
+            )
+            .context("In update_import_session.")
+    }
+
+    fn finish_import_session(
+        security_level: SecurityLevel,
+        session_id: i64,
+        key: &KeyDescriptor,
+        authenticators: &[AuthenticatorSpec],
+    ) -> Result<KeyMetadata> {
+        get_security_level(security_level)
+            .context("In finish_import_session.")?
+            .finish_import_session(session_id, key, authenticators, &CallerIdentity::current())
+            .context("In finish_import_session.")
+    }
+
+    fn abort_import_session(security_level: SecurityLevel, session_id: i64) -> Result<()> {
+        get_security_level(security_level)
+            .context("In abort_import_session.")?
+            .abort_import_session(session_id, &CallerIdentity::current());
+        Ok(())
+    }
+}
+
+impl Interface for SecureImport {}
+
+impl IKeystoreSecureImport for SecureImport {
+    fn beginImportSession(
+        &self,
+        security_level: SecurityLevel,
+        wrapping_key: &KeyDescriptor,
+    ) -> BinderResult<ImportSessionInfo> {
+        let _wp = wd::watch_millis("IKeystoreSecureImport::beginImportSession", 500);
+        map_or_log_err(Self::begin_import_session(security_level, wrapping_key), Ok)
+    }
+
+    fn updateImportSession(
+        &self,
+        security_level: SecurityLevel,
+        session_id: i64,
+        wrapped_key_data: &[u8],
+        params: &[KeyParameter],
+        masking_key: Option<&[u8]>,
+    ) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreSecureImport::updateImportSession", 500);
+        map_or_log_err(
+            Self::update_import_session(
+                security_level,
+                session_id,
+                wrapped_key_data,
+                params,
+                masking_key,
+            ),
+            Ok,
+        )
+    }
+
+    fn finishImportSession(
+        &self,
+        security_level: SecurityLevel,
+        session_id: i64,
+        key: &KeyDescriptor,
+        authenticators: &[AuthenticatorSpec],
+    ) -> BinderResult<KeyMetadata> {
+        let _wp = wd::watch_millis("IKeystoreSecureImport::finishImportSession", 500);
+        map_or_log_err(
+            Self::finish_import_session(security_level, session_id, key, authenticators),
+            Ok,
+        )
+    }
+
+    fn abortImportSession(
+        &self,
+        security_level: SecurityLevel,
+        session_id: i64,
+    ) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreSecureImport::abortImportSession", 500);
+        map_or_log_err(Self::abort_import_session(security_level, session_id), Ok)
+    }
+}
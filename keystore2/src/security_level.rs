@@ -14,30 +14,54 @@
 
 //! This crate implements the IKeystoreSecurityLevel interface.
 
+use crate::access_group;
+use crate::async_keygen;
+use crate::attestation_ids;
 use crate::attestation_key_utils::{get_attest_key_info, AttestationKeyInfo};
 use crate::audit_log::{
-    log_key_deleted, log_key_generated, log_key_imported, log_key_integrity_violation,
+    log_device_id_attestation, log_key_deleted, log_key_generated, log_key_imported,
+    log_key_integrity_violation,
+};
+use crate::buffer_pool::{PooledVec, KEY_PARAMETER_BUFFERS};
+use crate::caller_identity::CallerIdentity;
+use crate::capability_matrix::{self, Feature, Support};
+use crate::concurrency_limit::ConcurrencyLimit;
+use crate::database::{CertificateInfo, KeyIdGuard, KeystoreDB, NewKey};
+use crate::enforcements::{key_use_approval_token, KEY_USE_APPROVAL_TAG, USER_MEDIATED_FLAG};
+use crate::error::{
+    self, is_transport_error, map_km_error, map_or_log_err, Error, ErrorCode, ResponseCode,
+    RetryAfter,
+};
+use crate::expiry_sweeper::DELETE_ON_EXPIRY_FLAG;
+use crate::globals::{
+    call_keymint_with_retry, note_keymint_transport_error, Idempotent, DB, ENFORCEMENTS,
+    LEGACY_MIGRATOR, SUPER_KEY,
 };
-use crate::database::{CertificateInfo, KeyIdGuard};
-use crate::error::{self, map_km_error, map_or_log_err, Error, ErrorCode};
-use crate::globals::{DB, ENFORCEMENTS, LEGACY_MIGRATOR, SUPER_KEY};
 use crate::key_parameter::KeyParameter as KsKeyParam;
 use crate::key_parameter::KeyParameterValue as KsKeyParamValue;
 use crate::metrics_store::log_key_creation_event_stats;
+use crate::namespace_freeze;
+use crate::namespace_params;
+use crate::operation_intents;
 use crate::remote_provisioning::RemProvState;
+use crate::software_fallback;
+use crate::storage_key;
+use crate::strict_mode;
 use crate::super_key::{KeyBlob, SuperKeyManager};
+use crate::trace;
 use crate::utils::{
-    check_device_attestation_permissions, check_key_permission, is_device_id_attestation_tag,
-    key_characteristics_to_internal, uid_to_android_user, watchdog as wd, Asp,
+    check_client_context, check_device_attestation_permissions, check_key_permission,
+    is_device_id_attestation_tag, key_characteristics_to_internal, watchdog as wd,
 };
 use crate::{
     database::{
-        BlobMetaData, BlobMetaEntry, DateTime, KeyEntry, KeyEntryLoadBits, KeyMetaData,
-        KeyMetaEntry, KeyType, SubComponentType, Uuid,
+        BlobMetaData, BlobMetaEntry, DateTime, GrantRebindPolicy, KeyEntry, KeyEntryLoadBits,
+        KeyMetaData, KeyMetaEntry, KeyOrigin, KeyType, SubComponentType, Uuid,
     },
     operation::KeystoreOperation,
     operation::LoggingInfo,
     operation::OperationDb,
+    operation::PRUNE_RETRY_AFTER,
     permission::KeyPerm,
 };
 use crate::{globals::get_keymint_device, id_rotation::IdRotationState};
@@ -48,7 +72,7 @@ use android_hardware_security_keymint::aidl::android::hardware::security::keymin
     KeyMintHardwareInfo::KeyMintHardwareInfo, KeyParameter::KeyParameter,
     KeyParameterValue::KeyParameterValue, SecurityLevel::SecurityLevel, Tag::Tag,
 };
-use android_hardware_security_keymint::binder::{BinderFeatures, Strong, ThreadState};
+use android_hardware_security_keymint::binder::{BinderFeatures, Strong};
 use android_system_keystore2::aidl::android::system::keystore2::{
     AuthenticatorSpec::AuthenticatorSpec, CreateOperationResponse::CreateOperationResponse,
     Domain::Domain, EphemeralStorageKeyResponse::EphemeralStorageKeyResponse,
@@ -57,16 +81,191 @@ use android_system_keystore2::aidl::android::system::keystore2::{
     KeyMetadata::KeyMetadata, KeyParameters::KeyParameters,
 };
 use anyhow::{anyhow, Context, Result};
+use keystore2_crypto::ZVec;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 /// Implementation of the IKeystoreSecurityLevel Interface.
 pub struct KeystoreSecurityLevel {
     security_level: SecurityLevel,
-    keymint: Asp,
     hw_info: KeyMintHardwareInfo,
     km_uuid: Uuid,
     operation_db: OperationDb,
     rem_prov_state: RemProvState,
     id_rotation_state: IdRotationState,
+    generate_limit: ConcurrencyLimit,
+    import_sessions: Mutex<ImportSessions>,
+}
+
+/// The binder object of a security level. The security level itself is shared with the
+/// asynchronous key generation, which runs key generations on worker threads.
+struct SharedSecurityLevel(Arc<KeystoreSecurityLevel>);
+
+impl Deref for SharedSecurityLevel {
+    type Target = KeystoreSecurityLevel;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// A key generation request that passed all checks that depend on the identity of the
+/// caller. It can be completed on any thread with `KeystoreSecurityLevel::complete_generate_key`.
+pub struct KeyGenerationRequest {
+    key: KeyDescriptor,
+    caller: CallerIdentity,
+    params: PooledVec<KeyParameter>,
+    attestation_key_info: Option<AttestationKeyInfo>,
+    flags: i32,
+}
+
+impl KeyGenerationRequest {
+    /// The descriptor of the new key with the namespace resolved.
+    pub fn key(&self) -> &KeyDescriptor {
+        &self.key
+    }
+}
+
+/// A key that KeyMint created, with the parameters, certificates and metadata that Keystore
+/// stores along with it, see `KeystoreSecurityLevel::prepare_new_key`.
+struct CreatedKey {
+    key_blob: Vec<u8>,
+    key_parameters: Vec<KsKeyParam>,
+    cert_info: CertificateInfo,
+    key_metadata: KeyMetaData,
+    creation_date: DateTime,
+    user_id: u32,
+    flags: Option<i32>,
+    grant_policy: GrantRebindPolicy,
+}
+
+impl CreatedKey {
+    /// Returns the metadata that is reported to the client for the stored key `key`.
+    fn into_key_metadata(
+        mut self,
+        key: KeyDescriptor,
+        security_level: SecurityLevel,
+    ) -> KeyMetadata {
+        KeyMetadata {
+            key,
+            keySecurityLevel: security_level,
+            certificate: self.cert_info.take_cert(),
+            certificateChain: self.cert_info.take_cert_chain(),
+            authorizations: crate::utils::key_parameters_to_authorizations(self.key_parameters),
+            modificationTimeMs: self.creation_date.to_millis_epoch(),
+        }
+    }
+}
+
+/// A template of a bulk import whose key parameters were checked and completed once for all
+/// entries of the batch that refer to it, see `KeystoreSecurityLevel::prepare_import_template`.
+pub struct ImportTemplate {
+    params: PooledVec<KeyParameter>,
+    format: KeyFormat,
+    flags: i32,
+}
+
+/// Idle time after which a secure import session expires.
+const IMPORT_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximal number of secure import sessions that a single uid may have open per security level.
+const MAX_IMPORT_SESSIONS_PER_UID: usize = 4;
+
+/// Maximal size of the wrapped key data that a secure import session accumulates.
+const MAX_IMPORT_SESSION_DATA: usize = 64 * 1024;
+
+/// Maximal number of import parameters that a secure import session accumulates.
+const MAX_IMPORT_SESSION_PARAMS: usize = 64;
+
+/// The state of a secure import session, see `KeystoreSecurityLevel::begin_import_session`.
+struct ImportSession {
+    owner: u32,
+    // Refers to the wrapping key by its id, so that rebinding its alias during the session
+    // cannot substitute another wrapping key.
+    wrapping_key: KeyDescriptor,
+    wrapped_data: ZVec,
+    masking_key: Option<ZVec>,
+    params: Vec<KeyParameter>,
+    last_used: Instant,
+}
+
+/// The open secure import sessions of a security level. Session ids are never reused while
+/// keystore is running. Expired sessions are dropped whenever a session is opened.
+struct ImportSessions {
+    next_id: i64,
+    sessions: HashMap<i64, ImportSession>,
+}
+
+impl Default for ImportSessions {
+    fn default() -> Self {
+        Self { next_id: 1, sessions: Default::default() }
+    }
+}
+
+impl ImportSessions {
+    /// Drops all sessions that were idle for longer than `IMPORT_SESSION_TIMEOUT`. Returns the
+    /// number of dropped sessions.
+    fn expire(&mut self, now: Instant) -> usize {
+        let before = self.sessions.len();
+        self.sessions
+            .retain(|_, s| now.saturating_duration_since(s.last_used) < IMPORT_SESSION_TIMEOUT);
+        before - self.sessions.len()
+    }
+
+    /// Opens a new session for `owner`, or returns None if the owner already has
+    /// `MAX_IMPORT_SESSIONS_PER_UID` sessions open.
+    fn open(
+        &mut self,
+        owner: u32,
+        wrapping_key: KeyDescriptor,
+        now: Instant,
+    ) -> Result<Option<i64>> {
+        self.expire(now);
+        if self.sessions.values().filter(|s| s.owner == owner).count()
+            >= MAX_IMPORT_SESSIONS_PER_UID
+        {
+            return Ok(None);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.sessions.insert(
+            id,
+            ImportSession {
+                owner,
+                wrapping_key,
+                wrapped_data: ZVec::new(0).context("In ImportSessions::open.")?,
+                masking_key: None,
+                params: Vec::new(),
+                last_used: now,
+            },
+        );
+        Ok(Some(id))
+    }
+
+    /// Returns the live session `id` of `caller` and marks it as used. Returns None if the
+    /// session is unknown, owned by another uid, or expired.
+    fn get_mut(&mut self, id: i64, caller: u32, now: Instant) -> Option<&mut ImportSession> {
+        match self.sessions.get_mut(&id) {
+            Some(s)
+                if s.owner == caller
+                    && now.saturating_duration_since(s.last_used) < IMPORT_SESSION_TIMEOUT =>
+            {
+                s.last_used = now;
+                Some(s)
+            }
+            _ => None,
+        }
+    }
+
+    /// Removes and returns the live session `id` of `caller`, see `get_mut`.
+    fn take(&mut self, id: i64, caller: u32, now: Instant) -> Option<ImportSession> {
+        self.get_mut(id, caller, now)?;
+        self.sessions.remove(&id)
+    }
 }
 
 // Blob of 32 zeroes used as empty masking key.
@@ -76,27 +275,57 @@ static ZERO_BLOB_32: &[u8] = &[0; 32];
 // 999912312359559, which is 253402300799000 ms from Jan 1, 1970.
 const UNDEFINED_NOT_AFTER: i64 = 253402300799000i64;
 
+/// The date of the last use of a key is only updated if the recorded one is older than this,
+/// so that frequently used keys do not cost a database write per operation. The date is
+/// reported as `KeyProvenance::lastUseDateMs` only.
+pub const LAST_USE_GRANULARITY: Duration = Duration::from_secs(60 * 60);
+
+/// Records that an operation began with the key `key_id`, whose last use was recorded at
+/// `last_used`, see `LAST_USE_GRANULARITY`. Failures are only logged, because they must not
+/// fail the operation.
+pub fn note_key_use(key_id: i64, last_used: Option<DateTime>) {
+    let now = match DateTime::now() {
+        Ok(now) => now,
+        Err(e) => {
+            log::error!("In note_key_use: Failed to get the time: {:?}", e);
+            return;
+        }
+    };
+    let granularity = LAST_USE_GRANULARITY.as_millis() as i64;
+    if last_used.map_or(false, |last| now.to_millis_epoch() - last.to_millis_epoch() < granularity)
+    {
+        return;
+    }
+    if let Err(e) = DB.with(|db| db.borrow_mut().note_key_used(key_id, now)) {
+        log::error!("In note_key_use: Failed to record the use of key {}: {:?}", key_id, e);
+    }
+}
+
 impl KeystoreSecurityLevel {
     /// Creates a new security level instance wrapped in a
     /// BnKeystoreSecurityLevel proxy object. It also enables
     /// `BinderFeatures::set_requesting_sid` on the new interface, because
-    /// we need it for checking keystore permissions.
+    /// we need it for checking keystore permissions. The new instance is also registered
+    /// with the asynchronous key generation.
     pub fn new_native_binder(
         security_level: SecurityLevel,
         id_rotation_state: IdRotationState,
     ) -> Result<(Strong<dyn IKeystoreSecurityLevel>, Uuid)> {
-        let (dev, hw_info, km_uuid) = get_keymint_device(&security_level)
+        let (_, hw_info, km_uuid) = get_keymint_device(&security_level)
             .context("In KeystoreSecurityLevel::new_native_binder.")?;
+        let shared = Arc::new(Self {
+            security_level,
+            hw_info,
+            km_uuid,
+            operation_db: OperationDb::new(),
+            rem_prov_state: RemProvState::new(security_level, km_uuid),
+            id_rotation_state,
+            generate_limit: ConcurrencyLimit::for_generate_key(security_level),
+            import_sessions: Default::default(),
+        });
+        async_keygen::register_security_level(security_level, shared.clone());
         let result = BnKeystoreSecurityLevel::new_binder(
-            Self {
-                security_level,
-                keymint: dev,
-                hw_info,
-                km_uuid,
-                operation_db: OperationDb::new(),
-                rem_prov_state: RemProvState::new(security_level, km_uuid),
-                id_rotation_state,
-            },
+            SharedSecurityLevel(shared),
             BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
         );
         Ok((result, km_uuid))
@@ -107,20 +336,24 @@ impl KeystoreSecurityLevel {
         wd::watch_millis_with(id, millis, move || format!("SecurityLevel {:?}", sec_level))
     }
 
-    fn store_new_key(
+    /// Turns the result of a KeyMint key creation into the parameters, certificates and metadata
+    /// that Keystore stores along with the key blob.
+    fn prepare_new_key(
         &self,
-        key: KeyDescriptor,
+        key: &KeyDescriptor,
         creation_result: KeyCreationResult,
-        user_id: u32,
+        caller: &CallerIdentity,
         flags: Option<i32>,
-    ) -> Result<KeyMetadata> {
+        origin: KeyOrigin,
+    ) -> Result<CreatedKey> {
+        let user_id = caller.user_id();
         let KeyCreationResult {
             keyBlob: key_blob,
             keyCharacteristics: key_characteristics,
             certificateChain: mut certificate_chain,
         } = creation_result;
 
-        let mut cert_info: CertificateInfo = CertificateInfo::new(
+        let cert_info: CertificateInfo = CertificateInfo::new(
             match certificate_chain.len() {
                 0 => None,
                 _ => Some(certificate_chain.remove(0).encodedCertificate),
@@ -138,6 +371,11 @@ impl KeystoreSecurityLevel {
             },
         );
 
+        // Whatever the software implementation reports, its keys are not protected by hardware.
+        let key_characteristics = match self.security_level {
+            SecurityLevel::SOFTWARE => software_fallback::mark_software(key_characteristics),
+            _ => key_characteristics,
+        };
         let mut key_parameters = key_characteristics_to_internal(key_characteristics);
 
         key_parameters.push(KsKeyParam::new(
@@ -147,41 +385,100 @@ impl KeystoreSecurityLevel {
 
         let creation_date = DateTime::now().context("Trying to make creation time.")?;
 
+        #[cfg(feature = "key_escrow")]
+        let escrow_record =
+            crate::escrow::wrap_on_creation(key, &key_parameters, flags, &key_blob, caller)
+                .context("In prepare_new_key: Failed to escrow key.")?;
+
+        let mut key_metadata = KeyMetaData::new();
+        key_metadata.add(KeyMetaEntry::CreationDate(creation_date));
+        key_metadata.add(KeyMetaEntry::Origin(origin));
+        key_metadata.add(KeyMetaEntry::CreatorUid(caller.uid() as i64));
+        key_metadata.add(KeyMetaEntry::KmVersion(self.hw_info.versionNumber));
+        if flags.map_or(false, |f| f & DELETE_ON_EXPIRY_FLAG != 0) {
+            key_metadata.add(KeyMetaEntry::DeleteOnExpiry(true));
+        }
+        if flags.map_or(false, |f| f & USER_MEDIATED_FLAG != 0) {
+            key_metadata.add(KeyMetaEntry::UserMediated(true));
+        }
+        #[cfg(feature = "key_escrow")]
+        if let Some(record) = escrow_record {
+            key_metadata.add(KeyMetaEntry::EscrowRecord(record));
+        }
+
+        Ok(CreatedKey {
+            key_blob,
+            key_parameters,
+            cert_info,
+            key_metadata,
+            creation_date,
+            user_id,
+            flags,
+            grant_policy: GrantRebindPolicy::from_key_flags(flags)
+                .context("In prepare_new_key.")?,
+        })
+    }
+
+    /// Applies the super encryption that the new key requires to its key blob and returns the
+    /// blob to be stored together with its metadata.
+    fn encrypt_new_key(
+        &self,
+        db: &mut KeystoreDB,
+        key: &KeyDescriptor,
+        created: &mut CreatedKey,
+    ) -> Result<(Vec<u8>, BlobMetaData)> {
+        let (key_blob, mut blob_metadata) = SUPER_KEY
+            .handle_super_encryption_on_key_init(
+                db,
+                &LEGACY_MIGRATOR,
+                &(key.domain),
+                &created.key_parameters,
+                created.flags,
+                created.user_id,
+                &created.key_blob,
+            )
+            .context("In encrypt_new_key. Failed to handle super encryption.")?;
+        if let Some(version) = blob_metadata.format_version() {
+            created.key_metadata.add(KeyMetaEntry::SuperKeyVersion(*version));
+        }
+        blob_metadata.add(BlobMetaEntry::KmUuid(self.km_uuid));
+        Ok((key_blob, blob_metadata))
+    }
+
+    fn store_new_key(
+        &self,
+        key: KeyDescriptor,
+        creation_result: KeyCreationResult,
+        caller: &CallerIdentity,
+        flags: Option<i32>,
+        origin: KeyOrigin,
+    ) -> Result<KeyMetadata> {
+        let mut created = self
+            .prepare_new_key(&key, creation_result, caller, flags, origin)
+            .context("In store_new_key.")?;
+
         let key = match key.domain {
             Domain::BLOB => KeyDescriptor {
                 domain: Domain::BLOB,
-                blob: Some(key_blob.to_vec()),
+                blob: Some(created.key_blob.to_vec()),
                 ..Default::default()
             },
             _ => DB
                 .with::<_, Result<KeyDescriptor>>(|db| {
                     let mut db = db.borrow_mut();
-
-                    let (key_blob, mut blob_metadata) = SUPER_KEY
-                        .handle_super_encryption_on_key_init(
-                            &mut db,
-                            &LEGACY_MIGRATOR,
-                            &(key.domain),
-                            &key_parameters,
-                            flags,
-                            user_id,
-                            &key_blob,
-                        )
-                        .context("In store_new_key. Failed to handle super encryption.")?;
-
-                    let mut key_metadata = KeyMetaData::new();
-                    key_metadata.add(KeyMetaEntry::CreationDate(creation_date));
-                    blob_metadata.add(BlobMetaEntry::KmUuid(self.km_uuid));
-
+                    let (key_blob, blob_metadata) = self
+                        .encrypt_new_key(&mut db, &key, &mut created)
+                        .context("In store_new_key.")?;
                     let key_id = db
                         .store_new_key(
                             &key,
                             KeyType::Client,
-                            &key_parameters,
+                            &created.key_parameters,
                             &(&key_blob, &blob_metadata),
-                            &cert_info,
-                            &key_metadata,
+                            &created.cert_info,
+                            &created.key_metadata,
                             &self.km_uuid,
+                            created.grant_policy,
                         )
                         .context("In store_new_key.")?;
                     Ok(KeyDescriptor {
@@ -193,27 +490,97 @@ impl KeystoreSecurityLevel {
                 .context("In store_new_key.")?,
         };
 
-        Ok(KeyMetadata {
-            key,
-            keySecurityLevel: self.security_level,
-            certificate: cert_info.take_cert(),
-            certificateChain: cert_info.take_cert_chain(),
-            authorizations: crate::utils::key_parameters_to_authorizations(key_parameters),
-            modificationTimeMs: creation_date.to_millis_epoch(),
+        Ok(created.into_key_metadata(key, self.security_level))
+    }
+
+    /// Stores the imported keys `keys` with a single database transaction, see
+    /// `KeystoreDB::store_new_keys`. A key that fails to store does not affect the others.
+    /// The KeyMint blobs of keys that are not stored are deleted.
+    fn store_imported_keys(
+        &self,
+        keys: Vec<Result<(KeyDescriptor, CreatedKey)>>,
+    ) -> Result<Vec<Result<KeyMetadata>>> {
+        DB.with(|db| {
+            let mut db = db.borrow_mut();
+            let mut prepared = Vec::with_capacity(keys.len());
+            for key in keys {
+                prepared.push(key.and_then(|(key, mut created)| {
+                    match self.encrypt_new_key(&mut db, &key, &mut created) {
+                        Ok(blob) => Ok((key, created, blob)),
+                        Err(e) => {
+                            self.delete_unstored_key_blob(&created.key_blob);
+                            Err(e).context("In store_imported_keys.")
+                        }
+                    }
+                }));
+            }
+            let new_keys: Vec<NewKey> = prepared
+                .iter()
+                .filter_map(|entry| entry.as_ref().ok())
+                .map(|(key, created, (key_blob, blob_metadata))| NewKey {
+                    key,
+                    params: &created.key_parameters,
+                    blob_info: (key_blob, blob_metadata),
+                    cert_info: &created.cert_info,
+                    metadata: &created.key_metadata,
+                    grant_policy: created.grant_policy,
+                })
+                .collect();
+            let mut stored = match db.store_new_keys(&new_keys, &self.km_uuid) {
+                Ok(stored) => stored.into_iter(),
+                Err(e) => {
+                    for (_, created, _) in prepared.iter().flatten() {
+                        self.delete_unstored_key_blob(&created.key_blob);
+                    }
+                    return Err(e).context("In store_imported_keys.");
+                }
+            };
+            Ok(prepared
+                .into_iter()
+                .map(|entry| {
+                    let (_, created, _) = entry?;
+                    let stored_key = stored.next().unwrap_or_else(|| Err(anyhow!(Error::sys())));
+                    let key_id = match stored_key {
+                        Ok(key_id) => key_id,
+                        Err(e) => {
+                            self.delete_unstored_key_blob(&created.key_blob);
+                            return Err(e).context("In store_imported_keys.");
+                        }
+                    };
+                    Ok(created.into_key_metadata(
+                        KeyDescriptor {
+                            domain: Domain::KEY_ID,
+                            nspace: key_id.id(),
+                            ..Default::default()
+                        },
+                        self.security_level,
+                    ))
+                })
+                .collect())
         })
     }
 
-    fn create_operation(
+    /// Implementation of `IKeystoreSecurityLevel::createOperation`. The optional `label`
+    /// is attached to the new operation for diagnostics, see `labeled_operations`.
+    pub fn create_operation(
         &self,
         key: &KeyDescriptor,
         operation_parameters: &[KeyParameter],
         forced: bool,
+        label: Option<String>,
+        caller: &CallerIdentity,
     ) -> Result<CreateOperationResponse> {
-        let caller_uid = ThreadState::get_calling_uid();
+        let caller_uid = caller.uid();
         // We use `scoping_blob` to extend the life cycle of the blob loaded from the database,
         // so that we can use it by reference like the blob provided by the key descriptor.
         // Otherwise, we would have to clone the blob from the key descriptor.
         let scoping_blob: Vec<u8>;
+        // The recorded date of the last use of the key, if it was loaded from the database.
+        let mut last_used = None;
+        // True if each use of the key must be approved, see `USER_MEDIATED_FLAG`.
+        let mut user_mediated = false;
+        // The domain and namespace the key resides in, resolved while loading the key.
+        let key_namespace = Cell::new((key.domain, key.nspace));
         let (km_blob, key_properties, key_id_guard, blob_metadata) = match key.domain {
             Domain::BLOB => {
                 check_key_permission(KeyPerm::use_(), key, &None)
@@ -252,12 +619,16 @@ impl KeystoreSecurityLevel {
                                     if forced {
                                         check_key_permission(KeyPerm::req_forced_op(), k, &av)?;
                                     }
+                                    key_namespace.set((k.domain, k.nspace));
                                     Ok(())
                                 },
                             )
                         })
                     })
                     .context("In create_operation: Failed to load key blob.")?;
+                check_client_context(key_entry.metadata()).context("In create_operation.")?;
+                last_used = key_entry.metadata().last_used().copied();
+                user_mediated = key_entry.metadata().user_mediated() == Some(&true);
 
                 let (blob, blob_metadata) =
                     key_entry.take_key_blob_info().ok_or_else(Error::sys).context(concat!(
@@ -275,6 +646,19 @@ impl KeystoreSecurityLevel {
             }
         };
 
+        if let Some((key_id, _)) = &key_properties {
+            let (domain, namespace) = key_namespace.get();
+            DB.with(|db| {
+                namespace_freeze::check_key_not_frozen(
+                    &mut db.borrow_mut(),
+                    domain,
+                    namespace,
+                    *key_id,
+                )
+            })
+            .context("In create_operation.")?;
+        }
+
         let purpose = operation_parameters.iter().find(|p| p.tag == Tag::PURPOSE).map_or(
             Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
                 .context("In create_operation: No operation purpose specified."),
@@ -285,12 +669,36 @@ impl KeystoreSecurityLevel {
             },
         )?;
 
+        let algorithm = key_properties.as_ref().and_then(|(_, key_params)| {
+            key_params.iter().find_map(|p| match p.key_parameter_value() {
+                KsKeyParamValue::Algorithm(algorithm) => Some(*algorithm),
+                _ => None,
+            })
+        });
+        strict_mode::check_operation_parameters(caller, algorithm, purpose, operation_parameters)
+            .context("In create_operation.")?;
+
+        let approval_token = key_use_approval_token(operation_parameters);
+
         // Remove Tag::PURPOSE from the operation_parameters, since some keymaster devices return
-        // an error on begin() if Tag::PURPOSE is in the operation_parameters.
-        let op_params: Vec<KeyParameter> =
-            operation_parameters.iter().filter(|p| p.tag != Tag::PURPOSE).cloned().collect();
+        // an error on begin() if Tag::PURPOSE is in the operation_parameters. The Keystore
+        // private approval token is removed as well.
+        let op_params: Vec<KeyParameter> = operation_parameters
+            .iter()
+            .filter(|p| p.tag != Tag::PURPOSE && p.tag != KEY_USE_APPROVAL_TAG)
+            .cloned()
+            .collect();
         let operation_parameters = op_params.as_slice();
 
+        // The approval is consumed before the use of the key is authorized, because authorizing
+        // has side effects, e.g., recording the use of the key, that an unapproved request must
+        // not cause.
+        if let (true, Some((key_id, _))) = (user_mediated, &key_properties) {
+            ENFORCEMENTS
+                .consume_key_use_approval(*key_id, caller_uid, approval_token)
+                .context("In create_operation.")?;
+        }
+
         let (immediate_hat, mut auth_info) = ENFORCEMENTS
             .authorize_create(
                 purpose,
@@ -299,31 +707,28 @@ impl KeystoreSecurityLevel {
                 self.hw_info.timestampTokenRequired,
             )
             .context("In create_operation.")?;
+        let key_id = key_properties.as_ref().map(|(key_id, _)| *key_id);
 
         let km_blob = SUPER_KEY
             .unwrap_key_if_required(&blob_metadata, km_blob)
             .context("In create_operation. Failed to handle super encryption.")?;
 
-        let km_dev: Strong<dyn IKeyMintDevice> = self
-            .keymint
-            .get_interface()
-            .context("In create_operation: Failed to get KeyMint device")?;
+        let km_dev = self.keymint().context("In create_operation: Failed to get KeyMint device")?;
 
         let (begin_result, upgraded_blob) = self
             .upgrade_keyblob_if_required_with(
-                &*km_dev,
                 key_id_guard,
                 &km_blob,
                 &blob_metadata,
                 &operation_parameters,
                 |blob| loop {
-                    match map_km_error({
+                    match self.note_transport_error(map_km_error({
                         let _wp = self.watch_millis(
                             "In KeystoreSecurityLevel::create_operation: calling begin",
                             500,
                         );
                         km_dev.begin(purpose, blob, &operation_parameters, immediate_hat.as_ref())
-                    }) {
+                    })) {
                         Err(Error::Km(ErrorCode::TOO_MANY_OPERATIONS)) => {
                             self.operation_db.prune(caller_uid, forced)?;
                             continue;
@@ -344,6 +749,13 @@ impl KeystoreSecurityLevel {
                     }
                 },
             )
+            .map_err(|e| match e.root_cause().downcast_ref::<Error>() {
+                // No operation slot could be freed up.
+                Some(Error::Rc(ResponseCode::BACKEND_BUSY)) => {
+                    e.context(RetryAfter(PRUNE_RETRY_AFTER))
+                }
+                _ => e,
+            })
             .context("In create_operation: Failed to begin operation.")?;
 
         let operation_challenge = auth_info.finalize_create_authorization(begin_result.challenge);
@@ -354,9 +766,18 @@ impl KeystoreSecurityLevel {
             Some(km_op) => self.operation_db.create_operation(
                 km_op,
                 caller_uid,
+                caller.pid(),
                 auth_info,
                 forced,
-                LoggingInfo::new(self.security_level, purpose, op_params, upgraded_blob.is_some()),
+                LoggingInfo::new(
+                    self.security_level,
+                    purpose,
+                    op_params,
+                    upgraded_blob.is_some(),
+                    key_namespace.get(),
+                    key_id,
+                    label,
+                ),
             ),
             None => {
                 return Err(Error::sys()).context(concat!(
@@ -366,12 +787,27 @@ impl KeystoreSecurityLevel {
             }
         };
 
+        if let Some(intent) = operation_intents::record(
+            caller_uid,
+            key_properties.as_ref().map(|(key_id, _)| *key_id),
+            purpose,
+            operation_parameters,
+            self.security_level,
+            begin_result.challenge,
+        ) {
+            operation.attach_intent(intent);
+        }
+
         let op_binder: binder::public_api::Strong<dyn IKeystoreOperation> =
             KeystoreOperation::new_native_binder(operation)
                 .as_binder()
                 .into_interface()
                 .context("In create_operation: Failed to create IKeystoreOperation.")?;
 
+        if let Some((key_id, _)) = &key_properties {
+            note_key_use(*key_id, last_used);
+        }
+
         Ok(CreateOperationResponse {
             iOperation: Some(op_binder),
             operationChallenge: operation_challenge,
@@ -391,8 +827,10 @@ impl KeystoreSecurityLevel {
         uid: u32,
         params: &[KeyParameter],
         key: &KeyDescriptor,
-    ) -> Result<Vec<KeyParameter>> {
-        let mut result = params.to_vec();
+    ) -> Result<PooledVec<KeyParameter>> {
+        // A few parameters may be added below.
+        let mut result = KEY_PARAMETER_BUFFERS.take(params.len() + 4);
+        result.extend_from_slice(params);
         // If there is an attestation challenge we need to get an application id.
         if params.iter().any(|kp| kp.tag == Tag::ATTESTATION_CHALLENGE) {
             let aaid = {
@@ -438,6 +876,10 @@ impl KeystoreSecurityLevel {
             ))?;
         }
 
+        // Attestation IDs passed with an empty value are supplied by Keystore.
+        attestation_ids::fill_attestation_ids(&mut result)
+            .context("In add_certificate_parameters: Failed to fill in attestation IDs.")?;
+
         // If we are generating/importing an asymmetric key, we need to make sure
         // that NOT_BEFORE and NOT_AFTER are present.
         match params.iter().find(|kp| kp.tag == Tag::ALGORITHM) {
@@ -467,18 +909,72 @@ impl KeystoreSecurityLevel {
         attest_key_descriptor: Option<&KeyDescriptor>,
         params: &[KeyParameter],
         flags: i32,
-        _entropy: &[u8],
+        entropy: &[u8],
+        caller: &CallerIdentity,
     ) -> Result<KeyMetadata> {
+        let request = self
+            .prepare_generate_key(key, attest_key_descriptor, params, flags, entropy, caller)
+            .context("In generate_key.")?;
+        self.complete_generate_key(request).context("In generate_key.")
+    }
+
+    /// Estimates when a key generation request that was rejected because of congestion should be
+    /// retried.
+    pub fn generate_retry_after(&self) -> Duration {
+        self.generate_limit.retry_after()
+    }
+
+    /// The UUID of the KeyMint instance backing this security level.
+    pub fn km_uuid(&self) -> &Uuid {
+        &self.km_uuid
+    }
+
+    /// Writes the live operations of this security level to `f`.
+    pub fn dump_operations(&self, f: &mut dyn std::io::Write) -> std::io::Result<()> {
+        self.operation_db.dump(f)
+    }
+
+    /// Reaps the operations of this security level whose owning process is gone. See
+    /// `OperationDb::reap_orphaned`.
+    pub fn reap_orphaned_operations<F>(&self, min_idle: Duration, is_alive: F) -> usize
+    where
+        F: Fn(i32, u32) -> bool,
+    {
+        self.operation_db.reap_orphaned(min_idle, is_alive)
+    }
+
+    /// Aborts all operations of this security level. See `OperationDb::abort_all`.
+    pub fn abort_all_operations(&self) -> usize {
+        self.operation_db.abort_all()
+    }
+
+    /// Aborts the operations with the given keys. See `OperationDb::abort_keys`.
+    pub fn abort_key_operations(&self, key_ids: &HashSet<i64>) -> usize {
+        self.operation_db.abort_keys(key_ids)
+    }
+
+    /// Performs all checks of a key generation that depend on the identity of the caller,
+    /// including access control, and loads the attestation key. Must be called on the binder
+    /// thread that serves the request.
+    pub fn prepare_generate_key(
+        &self,
+        key: &KeyDescriptor,
+        attest_key_descriptor: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+        flags: i32,
+        _entropy: &[u8],
+        caller: &CallerIdentity,
+    ) -> Result<KeyGenerationRequest> {
         if key.domain != Domain::BLOB && key.alias.is_none() {
             return Err(error::Error::Km(ErrorCode::INVALID_ARGUMENT))
-                .context("In generate_key: Alias must be specified");
+                .context("In prepare_generate_key: Alias must be specified");
         }
-        let caller_uid = ThreadState::get_calling_uid();
+        let caller_uid = caller.uid();
 
         let key = match key.domain {
             Domain::APP => KeyDescriptor {
                 domain: key.domain,
-                nspace: caller_uid as i64,
+                nspace: access_group::resolve_app_namespace(caller_uid, key.nspace),
                 alias: key.alias.clone(),
                 blob: None,
             },
@@ -487,7 +983,13 @@ impl KeystoreSecurityLevel {
 
         // generate_key requires the rebind permission.
         // Must return on error for security reasons.
-        check_key_permission(KeyPerm::rebind(), &key, &None).context("In generate_key.")?;
+        check_key_permission(KeyPerm::rebind(), &key, &None).context("In prepare_generate_key.")?;
+        namespace_freeze::check_not_frozen(key.domain, key.nspace)
+            .context("In prepare_generate_key.")?;
+
+        let params = namespace_params::apply(self.security_level, &key, params)
+            .context("In prepare_generate_key.")?;
+        let params = &params[..];
 
         let attestation_key_info = match (key.domain, attest_key_descriptor) {
             (Domain::BLOB, _) => None,
@@ -502,14 +1004,68 @@ impl KeystoreSecurityLevel {
                         &mut db.borrow_mut(),
                     )
                 })
-                .context("In generate_key: Trying to get an attestation key")?,
+                .context("In prepare_generate_key: Trying to get an attestation key")?,
         };
         let params = self
             .add_certificate_parameters(caller_uid, params, &key)
-            .context("In generate_key: Trying to get aaid.")?;
+            .context("In prepare_generate_key: Trying to get aaid.")?;
+
+        Ok(KeyGenerationRequest {
+            key,
+            caller: caller.clone(),
+            params,
+            attestation_key_info,
+            flags,
+        })
+    }
+
+    fn generate_in_software(request: KeyGenerationRequest) -> Result<KeyMetadata> {
+        async_keygen::get_security_level(SecurityLevel::SOFTWARE)
+            .context("In generate_in_software.")?
+            .complete_generate_key(request)
+            .context("In generate_in_software.")
+    }
+
+    /// Generates and stores the key of a prepared key generation request. This may be called
+    /// on any thread. Requests that the HAL does not support may be served by the SOFTWARE
+    /// security level instead, see `software_fallback`.
+    pub fn complete_generate_key(&self, request: KeyGenerationRequest) -> Result<KeyMetadata> {
+        let fallback_request = if software_fallback::may_fall_back(
+            self.security_level,
+            &request.key,
+            &request.params,
+            request.flags,
+        ) {
+            if software_fallback::is_registered(self.security_level, &request.params) {
+                return Self::generate_in_software(request)
+                    .context("In complete_generate_key: Registered for software fallback.");
+            }
+            // Requests that fall back are not attested, so no attestation key is needed.
+            Some(KeyGenerationRequest {
+                key: request.key.clone(),
+                caller: request.caller.clone(),
+                params: request.params.clone_pooled(),
+                attestation_key_info: None,
+                flags: request.flags,
+            })
+        } else {
+            None
+        };
+
+        let KeyGenerationRequest { key, caller, params, attestation_key_info, flags } = request;
 
-        let km_dev: Strong<dyn IKeyMintDevice> = self.keymint.get_interface()?;
+        let km_dev = self.keymint().context("In complete_generate_key.")?;
 
+        // Only as many generate requests as the KeyMint instance can serve concurrently
+        // are allowed in flight, and requests wait for a slot only for a bounded time. The slot
+        // is released before the new key is stored.
+        let generate_slot = self
+            .generate_limit
+            .acquire_or_reject()
+            .map_err(|retry_after| {
+                anyhow!(Error::Rc(ResponseCode::BACKEND_BUSY)).context(RetryAfter(retry_after))
+            })
+            .context("In complete_generate_key: No generate slot available in time.")?;
         let creation_result = match attestation_key_info {
             Some(AttestationKeyInfo::UserGenerated {
                 key_id_guard,
@@ -518,7 +1074,6 @@ impl KeystoreSecurityLevel {
                 issuer_subject,
             }) => self
                 .upgrade_keyblob_if_required_with(
-                    &*km_dev,
                     Some(key_id_guard),
                     &KeyBlob::Ref(&blob),
                     &blob_metadata,
@@ -529,7 +1084,7 @@ impl KeystoreSecurityLevel {
                             attestKeyParams: vec![],
                             issuerSubjectName: issuer_subject.clone(),
                         });
-                        map_km_error({
+                        self.note_transport_error(map_km_error({
                             let _wp = self.watch_millis(
                                 concat!(
                                     "In KeystoreSecurityLevel::generate_key (UserGenerated): ",
@@ -538,13 +1093,13 @@ impl KeystoreSecurityLevel {
                                 5000, // Generate can take a little longer.
                             );
                             km_dev.generateKey(&params, attest_key.as_ref())
-                        })
+                        }))
                     },
                 )
-                .context("In generate_key: Using user generated attestation key.")
+                .context("In complete_generate_key: Using user generated attestation key.")
                 .map(|(result, _)| result),
             Some(AttestationKeyInfo::RemoteProvisioned { attestation_key, attestation_certs }) => {
-                map_km_error({
+                self.note_transport_error(map_km_error({
                     let _wp = self.watch_millis(
                         concat!(
                             "In KeystoreSecurityLevel::generate_key (RemoteProvisioned): ",
@@ -553,49 +1108,53 @@ impl KeystoreSecurityLevel {
                         5000, // Generate can take a little longer.
                     );
                     km_dev.generateKey(&params, Some(&attestation_key))
-                })
+                }))
                 .context("While generating Key with remote provisioned attestation key.")
                 .map(|mut creation_result| {
                     creation_result.certificateChain.push(attestation_certs);
                     creation_result
                 })
             }
-            None => map_km_error({
-                let _wp = self.watch_millis(
-                    concat!(
-                        "In KeystoreSecurityLevel::generate_key (No attestation): ",
-                        "calling generate_key.",
-                    ),
-                    5000, // Generate can take a little longer.
+            None => self
+                .note_transport_error(map_km_error({
+                    let _wp = self.watch_millis(
+                        concat!(
+                            "In KeystoreSecurityLevel::generate_key (No attestation): ",
+                            "calling generate_key.",
+                        ),
+                        5000, // Generate can take a little longer.
+                    );
+                    km_dev.generateKey(&params, None)
+                }))
+                .context("While generating Key without explicit attestation key."),
+        };
+        drop(generate_slot);
+        let creation_result = match (creation_result, fallback_request) {
+            (Err(e), Some(fallback_request))
+                if software_fallback::on_generate_error(self.security_level, &params, &e) =>
+            {
+                log::info!(
+                    "In complete_generate_key: Falling back to software after {:?}: {:?}",
+                    self.security_level,
+                    e
                 );
-                km_dev.generateKey(&params, None)
-            })
-            .context("While generating Key without explicit attestation key."),
-        }
-        .context("In generate_key.")?;
+                return Self::generate_in_software(fallback_request)
+                    .context("In complete_generate_key: Falling back to software.");
+            }
+            (result, _) => result.context("In complete_generate_key.")?,
+        };
 
-        let user_id = uid_to_android_user(caller_uid);
-        self.store_new_key(key, creation_result, user_id, Some(flags)).context("In generate_key.")
+        self.store_new_key(key, creation_result, &caller, Some(flags), KeyOrigin::Generated)
+            .context("In complete_generate_key.")
     }
 
-    fn import_key(
-        &self,
-        key: &KeyDescriptor,
-        _attestation_key: Option<&KeyDescriptor>,
-        params: &[KeyParameter],
-        flags: i32,
-        key_data: &[u8],
-    ) -> Result<KeyMetadata> {
-        if key.domain != Domain::BLOB && key.alias.is_none() {
-            return Err(error::Error::Km(ErrorCode::INVALID_ARGUMENT))
-                .context("In import_key: Alias must be specified");
-        }
-        let caller_uid = ThreadState::get_calling_uid();
-
+    /// Resolves the namespace of a key that is about to be imported and checks that the caller
+    /// may bind keys to it.
+    fn resolve_import_target(&self, key: &KeyDescriptor, caller_uid: u32) -> Result<KeyDescriptor> {
         let key = match key.domain {
             Domain::APP => KeyDescriptor {
                 domain: key.domain,
-                nspace: caller_uid as i64,
+                nspace: access_group::resolve_app_namespace(caller_uid, key.nspace),
                 alias: key.alias.clone(),
                 blob: None,
             },
@@ -603,11 +1162,28 @@ impl KeystoreSecurityLevel {
         };
 
         // import_key requires the rebind permission.
-        check_key_permission(KeyPerm::rebind(), &key, &None).context("In import_key.")?;
+        check_key_permission(KeyPerm::rebind(), &key, &None)
+            .context("In resolve_import_target.")?;
+        namespace_freeze::check_not_frozen(key.domain, key.nspace)
+            .context("In resolve_import_target.")?;
+        Ok(key)
+    }
+
+    /// Checks and completes the import parameters `params` of the key `key` and derives the
+    /// format of the key material from them.
+    fn prepare_import_params(
+        &self,
+        key: &KeyDescriptor,
+        params: &[KeyParameter],
+        flags: i32,
+        caller_uid: u32,
+    ) -> Result<ImportTemplate> {
+        let params = namespace_params::apply(self.security_level, key, params)
+            .context("In prepare_import_params.")?;
 
         let params = self
-            .add_certificate_parameters(caller_uid, params, &key)
-            .context("In import_key: Trying to get aaid.")?;
+            .add_certificate_parameters(caller_uid, &params, key)
+            .context("In prepare_import_params: Trying to get aaid.")?;
 
         let format = params
             .iter()
@@ -623,19 +1199,122 @@ impl KeystoreSecurityLevel {
                 v => Err(error::Error::Km(ErrorCode::INVALID_ARGUMENT))
                     .context(format!("Unknown Algorithm {:?}.", v)),
             })
+            .context("In prepare_import_params.")?;
+        Ok(ImportTemplate { params, format, flags })
+    }
+
+    fn import_key(
+        &self,
+        key: &KeyDescriptor,
+        _attestation_key: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+        flags: i32,
+        key_data: &[u8],
+        caller: &CallerIdentity,
+    ) -> Result<KeyMetadata> {
+        if key.domain != Domain::BLOB && key.alias.is_none() {
+            return Err(error::Error::Km(ErrorCode::INVALID_ARGUMENT))
+                .context("In import_key: Alias must be specified");
+        }
+        let caller_uid = caller.uid();
+        let key = self.resolve_import_target(key, caller_uid).context("In import_key.")?;
+        let template = self
+            .prepare_import_params(&key, params, flags, caller_uid)
             .context("In import_key.")?;
 
-        let km_dev: Strong<dyn IKeyMintDevice> =
-            self.keymint.get_interface().context("In import_key: Trying to get the KM device")?;
-        let creation_result = map_km_error({
-            let _wp =
-                self.watch_millis("In KeystoreSecurityLevel::import_key: calling importKey.", 500);
-            km_dev.importKey(&params, format, key_data, None /* attestKey */)
-        })
-        .context("In import_key: Trying to call importKey")?;
+        let km_dev = self.keymint().context("In import_key: Trying to get the KM device")?;
+        let creation_result = self
+            .note_transport_error(map_km_error({
+                let _wp = self
+                    .watch_millis("In KeystoreSecurityLevel::import_key: calling importKey.", 500);
+                km_dev.importKey(
+                    &template.params,
+                    template.format,
+                    key_data,
+                    None, /* attestKey */
+                )
+            }))
+            .context("In import_key: Trying to call importKey")?;
 
-        let user_id = uid_to_android_user(caller_uid);
-        self.store_new_key(key, creation_result, user_id, Some(flags)).context("In import_key.")
+        self.store_new_key(key, creation_result, caller, Some(flags), KeyOrigin::Imported)
+            .context("In import_key.")
+    }
+
+    /// Resolves the namespace of the bulk import target `target` and checks that the caller may
+    /// bind keys to it. All keys of a batch go into the same namespace, so this is done once per
+    /// batch. It must be called on the binder thread, because the permission checks depend on
+    /// the calling context.
+    pub fn resolve_bulk_import_target(
+        &self,
+        target: &KeyDescriptor,
+        caller: &CallerIdentity,
+    ) -> Result<KeyDescriptor> {
+        if !matches!(target.domain, Domain::APP | Domain::SELINUX) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(format!(
+                "In resolve_bulk_import_target: Unsupported domain {:?}.",
+                target.domain
+            ));
+        }
+        let target = KeyDescriptor { alias: None, blob: None, ..target.clone() };
+        self.resolve_import_target(&target, caller.uid()).context("In resolve_bulk_import_target.")
+    }
+
+    /// Checks and completes the parameters of a bulk import template for the resolved target
+    /// `target`, once for all entries that refer to it. Like `resolve_bulk_import_target`, it
+    /// must be called on the binder thread.
+    pub fn prepare_import_template(
+        &self,
+        target: &KeyDescriptor,
+        params: &[KeyParameter],
+        flags: i32,
+        caller: &CallerIdentity,
+    ) -> Result<ImportTemplate> {
+        self.prepare_import_params(target, params, flags, caller.uid())
+            .context("In prepare_import_template.")
+    }
+
+    /// Imports the entries of a bulk import into the resolved target `target`. Each entry is
+    /// the alias, the template and the key material of a key. KeyMint imports one key per
+    /// call, but the new keys are stored with a single database transaction. The outcome of
+    /// each entry is reported on its own.
+    pub fn import_batch(
+        &self,
+        target: &KeyDescriptor,
+        entries: &[(&str, &ImportTemplate, &[u8])],
+        caller: &CallerIdentity,
+    ) -> Result<Vec<Result<KeyMetadata>>> {
+        let km_dev = self.keymint().context("In import_batch: Trying to get the KM device")?;
+        let created = entries
+            .iter()
+            .map(|(alias, template, key_data)| {
+                let creation_result = self
+                    .note_transport_error(map_km_error({
+                        let _wp = self.watch_millis(
+                            "In KeystoreSecurityLevel::import_batch: calling importKey.",
+                            500,
+                        );
+                        km_dev.importKey(&template.params, template.format, key_data, None)
+                    }))
+                    .context("In import_batch: Trying to call importKey")?;
+                let key = KeyDescriptor { alias: Some(alias.to_string()), ..target.clone() };
+                let key_blob = creation_result.keyBlob.clone();
+                match self.prepare_new_key(
+                    &key,
+                    creation_result,
+                    caller,
+                    Some(template.flags),
+                    KeyOrigin::Imported,
+                ) {
+                    Ok(created) => Ok((key, created)),
+                    Err(e) => {
+                        // The key is never stored, so it must not linger in KeyMint either.
+                        self.delete_unstored_key_blob(&key_blob);
+                        Err(e).context("In import_batch.")
+                    }
+                }
+            })
+            .collect();
+        self.store_imported_keys(created).context("In import_batch.")
     }
 
     fn import_wrapped_key(
@@ -645,6 +1324,7 @@ impl KeystoreSecurityLevel {
         masking_key: Option<&[u8]>,
         params: &[KeyParameter],
         authenticators: &[AuthenticatorSpec],
+        caller: &CallerIdentity,
     ) -> Result<KeyMetadata> {
         let wrapped_data: &[u8] = match key {
             KeyDescriptor { domain: Domain::APP, blob: Some(ref blob), alias: Some(_), .. }
@@ -668,13 +1348,12 @@ impl KeystoreSecurityLevel {
             );
         }
 
-        let caller_uid = ThreadState::get_calling_uid();
-        let user_id = uid_to_android_user(caller_uid);
+        let caller_uid = caller.uid();
 
         let key = match key.domain {
             Domain::APP => KeyDescriptor {
                 domain: key.domain,
-                nspace: caller_uid as i64,
+                nspace: access_group::resolve_app_namespace(caller_uid, key.nspace),
                 alias: key.alias.clone(),
                 blob: None,
             },
@@ -689,6 +1368,13 @@ impl KeystoreSecurityLevel {
 
         // Import_wrapped_key requires the rebind permission for the new key.
         check_key_permission(KeyPerm::rebind(), &key, &None).context("In import_wrapped_key.")?;
+        namespace_freeze::check_not_frozen(key.domain, key.nspace)
+            .context("In import_wrapped_key.")?;
+        // The wrapped key's parameters are not known before unwrapping, so only the security
+        // level requirement of the namespace is checked here. The key characteristics are
+        // checked after unwrapping.
+        namespace_params::check_security_level(self.security_level, &key)
+            .context("In import_wrapped_key.")?;
 
         let (wrapping_key_id_guard, mut wrapping_key_entry) = DB
             .with(|db| {
@@ -703,6 +1389,8 @@ impl KeystoreSecurityLevel {
                 })
             })
             .context("Failed to load wrapping key.")?;
+        check_client_context(wrapping_key_entry.metadata())
+            .context("In import_wrapped_key: Checking client context of the wrapping key.")?;
 
         let (wrapping_key_blob, wrapping_blob_metadata) = wrapping_key_entry
             .take_key_blob_info()
@@ -736,10 +1424,9 @@ impl KeystoreSecurityLevel {
 
         let masking_key = masking_key.unwrap_or(ZERO_BLOB_32);
 
-        let km_dev: Strong<dyn IKeyMintDevice> = self.keymint.get_interface()?;
+        let km_dev = self.keymint().context("In import_wrapped_key.")?;
         let (creation_result, _) = self
             .upgrade_keyblob_if_required_with(
-                &*km_dev,
                 Some(wrapping_key_id_guard),
                 &wrapping_key_blob,
                 &wrapping_blob_metadata,
@@ -749,23 +1436,165 @@ impl KeystoreSecurityLevel {
                         "In KeystoreSecurityLevel::import_wrapped_key: calling importWrappedKey.",
                         500,
                     );
-                    let creation_result = map_km_error(km_dev.importWrappedKey(
-                        wrapped_data,
-                        wrapping_blob,
-                        masking_key,
-                        &params,
-                        pw_sid,
-                        fp_sid,
-                    ))?;
+                    let creation_result =
+                        self.note_transport_error(map_km_error(km_dev.importWrappedKey(
+                            wrapped_data,
+                            wrapping_blob,
+                            masking_key,
+                            &params,
+                            pw_sid,
+                            fp_sid,
+                        )))?;
                     Ok(creation_result)
                 },
             )
             .context("In import_wrapped_key.")?;
 
-        self.store_new_key(key, creation_result, user_id, None)
+        if let Err(e) = namespace_params::check_characteristics(
+            self.security_level,
+            &key,
+            &creation_result.keyCharacteristics,
+        ) {
+            // The key is never stored, so it must not linger in KeyMint either.
+            self.delete_unstored_key_blob(&creation_result.keyBlob);
+            return Err(e).context("In import_wrapped_key.");
+        }
+
+        self.store_new_key(key, creation_result, caller, None, KeyOrigin::Imported)
             .context("In import_wrapped_key: Trying to store the new key.")
     }
 
+    /// Opens a secure import session for `caller` that imports a key wrapped with
+    /// `wrapping_key`. Secure import servers and secure elements often deliver the wrapped key
+    /// in several steps, e.g., after they received the certificate of the wrapping key. The
+    /// session pins the wrapping key, collects the wrapped key data, its import parameters, and
+    /// the masking key across calls, and `finish_import_session` imports the key like
+    /// `IKeystoreSecurityLevel::importWrappedKey` in a single KeyMint call. The IV of the
+    /// wrapped key is part of the wrapped key data, so no nonce needs to be negotiated.
+    ///
+    /// The caller needs the `use` permission on the wrapping key, which is checked again when
+    /// the session is finished. Returns the id of the new session and the certificate of the
+    /// wrapping key, if it has one.
+    pub fn begin_import_session(
+        &self,
+        wrapping_key: &KeyDescriptor,
+        caller: &CallerIdentity,
+    ) -> Result<(i64, Option<Vec<u8>>)> {
+        if wrapping_key.domain == Domain::BLOB {
+            return Err(error::Error::Km(ErrorCode::INVALID_ARGUMENT)).context(
+                "In begin_import_session: Import wrapped key not supported for self managed blobs.",
+            );
+        }
+        let caller_uid = caller.uid();
+        let (wrapping_key_id_guard, mut wrapping_key_entry) = DB
+            .with(|db| {
+                LEGACY_MIGRATOR.with_try_migrate(wrapping_key, caller_uid, || {
+                    db.borrow_mut().load_key_entry(
+                        wrapping_key,
+                        KeyType::Client,
+                        KeyEntryLoadBits::PUBLIC,
+                        caller_uid,
+                        |k, av| check_key_permission(KeyPerm::use_(), k, &av),
+                    )
+                })
+            })
+            .context("In begin_import_session: Failed to load wrapping key.")?;
+        check_client_context(wrapping_key_entry.metadata())
+            .context("In begin_import_session: Checking client context of the wrapping key.")?;
+
+        let wrapping_key = KeyDescriptor {
+            domain: Domain::KEY_ID,
+            nspace: wrapping_key_id_guard.id(),
+            alias: None,
+            blob: None,
+        };
+        let id = self
+            .import_sessions
+            .lock()
+            .unwrap()
+            .open(caller_uid, wrapping_key, Instant::now())
+            .context("In begin_import_session.")?
+            .ok_or(Error::Rc(ResponseCode::BACKEND_BUSY))
+            .context("In begin_import_session: Too many open import sessions.")?;
+        Ok((id, wrapping_key_entry.take_cert()))
+    }
+
+    /// Adds to the secure import session `id` of `caller`. `wrapped_data` is appended to the
+    /// wrapped key data and `params` to the import parameters. A masking key replaces the
+    /// masking key given earlier.
+    pub fn update_import_session(
+        &self,
+        id: i64,
+        wrapped_data: &[u8],
+        params: &[KeyParameter],
+        masking_key: Option<&[u8]>,
+        caller: &CallerIdentity,
+    ) -> Result<()> {
+        let mut sessions = self.import_sessions.lock().unwrap();
+        let session = sessions
+            .get_mut(id, caller.uid(), Instant::now())
+            .ok_or(Error::Rc(ResponseCode::KEY_NOT_FOUND))
+            .context("In update_import_session: Unknown session.")?;
+        let data_len = session.wrapped_data.len() + wrapped_data.len();
+        if data_len > MAX_IMPORT_SESSION_DATA {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In update_import_session: Too much wrapped key data.");
+        }
+        if session.params.len() + params.len() > MAX_IMPORT_SESSION_PARAMS {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In update_import_session: Too many import parameters.");
+        }
+        // The wrapped key data and the masking key are only held in buffers that are zeroed
+        // when they are dropped.
+        let mut data = ZVec::new(data_len).context("In update_import_session.")?;
+        let (old, new) = data.split_at_mut(session.wrapped_data.len());
+        old.copy_from_slice(&session.wrapped_data);
+        new.copy_from_slice(wrapped_data);
+        session.wrapped_data = data;
+        session.params.extend_from_slice(params);
+        if let Some(masking_key) = masking_key {
+            session.masking_key =
+                Some(ZVec::try_from(masking_key).context("In update_import_session.")?);
+        }
+        Ok(())
+    }
+
+    /// Ends the secure import session `id` of `caller` and imports the wrapped key it
+    /// collected as `key`. The session is closed even if the import fails.
+    pub fn finish_import_session(
+        &self,
+        id: i64,
+        key: &KeyDescriptor,
+        authenticators: &[AuthenticatorSpec],
+        caller: &CallerIdentity,
+    ) -> Result<KeyMetadata> {
+        let session = self
+            .import_sessions
+            .lock()
+            .unwrap()
+            .take(id, caller.uid(), Instant::now())
+            .ok_or(Error::Rc(ResponseCode::KEY_NOT_FOUND))
+            .context("In finish_import_session: Unknown session.")?;
+        let key = KeyDescriptor { blob: Some(session.wrapped_data.to_vec()), ..key.clone() };
+        let result = self.import_wrapped_key(
+            &key,
+            &session.wrapping_key,
+            session.masking_key.as_deref(),
+            &session.params,
+            authenticators,
+            caller,
+        );
+        log_key_creation_event_stats(self.security_level, &session.params, &result);
+        log_key_imported(&key, caller, result.is_ok());
+        result.context("In finish_import_session.")
+    }
+
+    /// Closes the secure import session `id` of `caller` without importing a key. Closing an
+    /// unknown session is not an error.
+    pub fn abort_import_session(&self, id: i64, caller: &CallerIdentity) {
+        self.import_sessions.lock().unwrap().take(id, caller.uid(), Instant::now());
+    }
+
     fn store_upgraded_keyblob(
         key_id_guard: KeyIdGuard,
         km_uuid: Option<&Uuid>,
@@ -793,9 +1622,48 @@ impl KeystoreSecurityLevel {
         .context("In store_upgraded_keyblob: Failed to insert upgraded blob into the database.")
     }
 
+    /// Returns the KeyMint device of this security level. The connection is cached in
+    /// `globals`, so that a connection that was dropped after a transport error is
+    /// re-established here.
+    fn keymint(&self) -> Result<Strong<dyn IKeyMintDevice>> {
+        let (dev, _, _) = get_keymint_device(&self.security_level)
+            .context("In KeystoreSecurityLevel::keymint.")?;
+        dev.get_interface().context("In KeystoreSecurityLevel::keymint.")
+    }
+
+    /// Forwards the result of a KeyMint call that is not retried, because it is not
+    /// idempotent. If it failed with a binder transport error, the connection is dropped, so
+    /// that the next call reconnects.
+    fn note_transport_error<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        if let Err(e) = &result {
+            if is_transport_error(e) {
+                note_keymint_transport_error(&self.security_level);
+            }
+        }
+        result
+    }
+
+    /// Deletes a key blob that KeyMint created but that Keystore did not store, so that the
+    /// key does not linger in KeyMint. Failures are only logged, because the caller reports
+    /// the error that made it discard the key.
+    fn delete_unstored_key_blob(&self, key_blob: &[u8]) {
+        if let Err(e) = call_keymint_with_retry(
+            &self.security_level,
+            Idempotent("Deleting a deleted key blob has no effect."),
+            |km_dev| {
+                let _wp = self.watch_millis(
+                    "In KeystoreSecurityLevel::delete_unstored_key_blob: calling deleteKey",
+                    500,
+                );
+                map_km_error(km_dev.deleteKey(key_blob)).context("In delete_unstored_key_blob.")
+            },
+        ) {
+            log::warn!("In delete_unstored_key_blob: Failed to delete key blob: {:?}", e);
+        }
+    }
+
     fn upgrade_keyblob_if_required_with<T, F>(
         &self,
-        km_dev: &dyn IKeyMintDevice,
         key_id_guard: Option<KeyIdGuard>,
         key_blob: &KeyBlob,
         blob_metadata: &BlobMetaData,
@@ -807,16 +1675,21 @@ impl KeystoreSecurityLevel {
     {
         match f(key_blob) {
             Err(Error::Km(ErrorCode::KEY_REQUIRES_UPGRADE)) => {
-                let upgraded_blob = {
-                    let _wp = self.watch_millis(
-                        concat!(
-                            "In KeystoreSecurityLevel::upgrade_keyblob_if_required_with: ",
-                            "calling upgradeKey."
-                        ),
-                        500,
-                    );
-                    map_km_error(km_dev.upgradeKey(key_blob, params))
-                }
+                let upgraded_blob = call_keymint_with_retry(
+                    &self.security_level,
+                    Idempotent("Upgrading the same key blob again yields an equivalent blob."),
+                    |km_dev| {
+                        let _wp = self.watch_millis(
+                            concat!(
+                                "In KeystoreSecurityLevel::upgrade_keyblob_if_required_with: ",
+                                "calling upgradeKey."
+                            ),
+                            500,
+                        );
+                        map_km_error(km_dev.upgradeKey(key_blob, params))
+                            .context("In upgrade_keyblob_if_required_with: calling upgradeKey.")
+                    },
+                )
                 .context("In upgrade_keyblob_if_required_with: Upgrade failed.")?;
 
                 if let Some(kid) = key_id_guard {
@@ -883,51 +1756,20 @@ impl KeystoreSecurityLevel {
         check_key_permission(KeyPerm::convert_storage_key_to_ephemeral(), storage_key, &None)
             .context("In convert_storage_key_to_ephemeral: Check permission")?;
 
-        let km_dev: Strong<dyn IKeyMintDevice> = self.keymint.get_interface().context(concat!(
-            "In IKeystoreSecurityLevel convert_storage_key_to_ephemeral: ",
-            "Getting keymint device interface"
-        ))?;
-        match {
-            let _wp = self.watch_millis(
-                concat!(
-                    "In IKeystoreSecurityLevel::convert_storage_key_to_ephemeral: ",
-                    "calling convertStorageKeyToEphemeral (1)"
-                ),
-                500,
+        if capability_matrix::support(self.hw_info.versionNumber, Feature::StorageKeys)
+            == Support::Reject
+        {
+            return Err(Error::Km(ErrorCode::UNIMPLEMENTED)).context(
+                "In convert_storage_key_to_ephemeral: Storage keys are not supported by this HAL.",
             );
-            map_km_error(km_dev.convertStorageKeyToEphemeral(key_blob))
-        } {
-            Ok(result) => {
-                Ok(EphemeralStorageKeyResponse { ephemeralKey: result, upgradedBlob: None })
-            }
-            Err(error::Error::Km(ErrorCode::KEY_REQUIRES_UPGRADE)) => {
-                let upgraded_blob = {
-                    let _wp = self.watch_millis(
-                        "In convert_storage_key_to_ephemeral: calling upgradeKey",
-                        500,
-                    );
-                    map_km_error(km_dev.upgradeKey(key_blob, &[]))
-                }
-                .context("In convert_storage_key_to_ephemeral: Failed to upgrade key blob.")?;
-                let ephemeral_key = {
-                    let _wp = self.watch_millis(
-                        "In convert_storage_key_to_ephemeral: calling convertStorageKeyToEphemeral (2)",
-                        500,
-                    );
-                    map_km_error(km_dev.convertStorageKeyToEphemeral(&upgraded_blob))
-                }
-                    .context(concat!(
-                        "In convert_storage_key_to_ephemeral: ",
-                        "Failed to retrieve ephemeral key (after upgrade)."
-                    ))?;
-                Ok(EphemeralStorageKeyResponse {
-                    ephemeralKey: ephemeral_key,
-                    upgradedBlob: Some(upgraded_blob),
-                })
-            }
-            Err(e) => Err(e)
-                .context("In convert_storage_key_to_ephemeral: Failed to retrieve ephemeral key."),
         }
+
+        call_keymint_with_retry(
+            &self.security_level,
+            Idempotent("Converting a storage key has no effect on the HAL's state."),
+            |km_dev| storage_key::convert(&km_dev, self.security_level, key_blob),
+        )
+        .context("In convert_storage_key_to_ephemeral.")
     }
 
     fn delete_key(&self, key: &KeyDescriptor) -> Result<()> {
@@ -945,29 +1787,34 @@ impl KeystoreSecurityLevel {
         check_key_permission(KeyPerm::delete(), key, &None)
             .context("In IKeystoreSecurityLevel delete_key: Checking delete permissions")?;
 
-        let km_dev: Strong<dyn IKeyMintDevice> = self
-            .keymint
-            .get_interface()
-            .context("In IKeystoreSecurityLevel delete_key: Getting keymint device interface")?;
-        {
-            let _wp =
-                self.watch_millis("In KeystoreSecuritylevel::delete_key: calling deleteKey", 500);
-            map_km_error(km_dev.deleteKey(&key_blob)).context("In keymint device deleteKey")
-        }
+        storage_key::remove(self.security_level, key_blob);
+
+        call_keymint_with_retry(
+            &self.security_level,
+            Idempotent("Deleting a deleted key blob has no effect."),
+            |km_dev| {
+                let _wp = self
+                    .watch_millis("In KeystoreSecuritylevel::delete_key: calling deleteKey", 500);
+                map_km_error(km_dev.deleteKey(&key_blob)).context("In keymint device deleteKey")
+            },
+        )
+        .context("In IKeystoreSecurityLevel delete_key.")
     }
 }
 
-impl binder::Interface for KeystoreSecurityLevel {}
+impl binder::Interface for SharedSecurityLevel {}
 
-impl IKeystoreSecurityLevel for KeystoreSecurityLevel {
+impl IKeystoreSecurityLevel for SharedSecurityLevel {
     fn createOperation(
         &self,
         key: &KeyDescriptor,
         operation_parameters: &[KeyParameter],
         forced: bool,
     ) -> binder::public_api::Result<CreateOperationResponse> {
+        let _trace = trace::begin();
         let _wp = self.watch_millis("IKeystoreSecurityLevel::createOperation", 500);
-        map_or_log_err(self.create_operation(key, operation_parameters, forced), Ok)
+        let caller = CallerIdentity::current();
+        map_or_log_err(self.create_operation(key, operation_parameters, forced, None, &caller), Ok)
     }
     fn generateKey(
         &self,
@@ -977,12 +1824,17 @@ impl IKeystoreSecurityLevel for KeystoreSecurityLevel {
         flags: i32,
         entropy: &[u8],
     ) -> binder::public_api::Result<KeyMetadata> {
+        let _trace = trace::begin();
         // Duration is set to 5 seconds, because generateKey - especially for RSA keys, takes more
         // time than other operations
         let _wp = self.watch_millis("IKeystoreSecurityLevel::generateKey", 5000);
-        let result = self.generate_key(key, attestation_key, params, flags, entropy);
+        let caller = CallerIdentity::current();
+        let result = self.generate_key(key, attestation_key, params, flags, entropy, &caller);
         log_key_creation_event_stats(self.security_level, params, &result);
-        log_key_generated(key, ThreadState::get_calling_uid(), result.is_ok());
+        log_key_generated(key, &caller, result.is_ok());
+        if params.iter().any(|kp| is_device_id_attestation_tag(kp.tag)) {
+            log_device_id_attestation(key, &caller, result.is_ok());
+        }
         map_or_log_err(result, Ok)
     }
     fn importKey(
@@ -993,10 +1845,12 @@ impl IKeystoreSecurityLevel for KeystoreSecurityLevel {
         flags: i32,
         key_data: &[u8],
     ) -> binder::public_api::Result<KeyMetadata> {
+        let _trace = trace::begin();
         let _wp = self.watch_millis("IKeystoreSecurityLevel::importKey", 500);
-        let result = self.import_key(key, attestation_key, params, flags, key_data);
+        let caller = CallerIdentity::current();
+        let result = self.import_key(key, attestation_key, params, flags, key_data, &caller);
         log_key_creation_event_stats(self.security_level, params, &result);
-        log_key_imported(key, ThreadState::get_calling_uid(), result.is_ok());
+        log_key_imported(key, &caller, result.is_ok());
         map_or_log_err(result, Ok)
     }
     fn importWrappedKey(
@@ -1007,24 +1861,84 @@ impl IKeystoreSecurityLevel for KeystoreSecurityLevel {
         params: &[KeyParameter],
         authenticators: &[AuthenticatorSpec],
     ) -> binder::public_api::Result<KeyMetadata> {
+        let _trace = trace::begin();
         let _wp = self.watch_millis("IKeystoreSecurityLevel::importWrappedKey", 500);
-        let result =
-            self.import_wrapped_key(key, wrapping_key, masking_key, params, authenticators);
+        let caller = CallerIdentity::current();
+        let result = self.import_wrapped_key(
+            key,
+            wrapping_key,
+            masking_key,
+            params,
+            authenticators,
+            &caller,
+        );
         log_key_creation_event_stats(self.security_level, params, &result);
-        log_key_imported(key, ThreadState::get_calling_uid(), result.is_ok());
+        log_key_imported(key, &caller, result.is_ok());
         map_or_log_err(result, Ok)
     }
     fn convertStorageKeyToEphemeral(
         &self,
         storage_key: &KeyDescriptor,
     ) -> binder::public_api::Result<EphemeralStorageKeyResponse> {
+        let _trace = trace::begin();
         let _wp = self.watch_millis("IKeystoreSecurityLevel::convertStorageKeyToEphemeral", 500);
         map_or_log_err(self.convert_storage_key_to_ephemeral(storage_key), Ok)
     }
     fn deleteKey(&self, key: &KeyDescriptor) -> binder::public_api::Result<()> {
+        let _trace = trace::begin();
         let _wp = self.watch_millis("IKeystoreSecurityLevel::deleteKey", 500);
+        let caller = CallerIdentity::current();
         let result = self.delete_key(key);
-        log_key_deleted(key, ThreadState::get_calling_uid(), result.is_ok());
+        log_key_deleted(key, &caller, result.is_ok());
         map_or_log_err(result, Ok)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrapping_key() -> KeyDescriptor {
+        KeyDescriptor { domain: Domain::APP, nspace: 0, alias: Some("wrap".into()), blob: None }
+    }
+
+    #[test]
+    fn import_sessions_are_private_to_their_owner() {
+        let now = Instant::now();
+        let mut sessions = ImportSessions::default();
+        let id = sessions.open(10001, wrapping_key(), now).unwrap().unwrap();
+        assert!(sessions.get_mut(id, 10002, now).is_none());
+        assert!(sessions.take(id, 10002, now).is_none());
+        assert_eq!(sessions.take(id, 10001, now).unwrap().wrapping_key, wrapping_key());
+        assert!(sessions.take(id, 10001, now).is_none());
+    }
+
+    #[test]
+    fn import_sessions_expire() {
+        let now = Instant::now();
+        let mut sessions = ImportSessions::default();
+        let id = sessions.open(10001, wrapping_key(), now).unwrap().unwrap();
+        // Using a session keeps it alive.
+        let later = now + IMPORT_SESSION_TIMEOUT / 2;
+        assert!(sessions.get_mut(id, 10001, later).is_some());
+        assert!(sessions.get_mut(id, 10001, later + IMPORT_SESSION_TIMEOUT / 2).is_some());
+        let expired = later + IMPORT_SESSION_TIMEOUT * 2;
+        assert!(sessions.get_mut(id, 10001, expired).is_none());
+        assert_eq!(sessions.expire(expired), 1);
+    }
+
+    #[test]
+    fn import_sessions_are_limited_per_uid() {
+        let now = Instant::now();
+        let mut sessions = ImportSessions::default();
+        let ids: Vec<i64> = (0..MAX_IMPORT_SESSIONS_PER_UID)
+            .map(|_| sessions.open(10001, wrapping_key(), now).unwrap().unwrap())
+            .collect();
+        assert_eq!(sessions.open(10001, wrapping_key(), now).unwrap(), None);
+        assert!(sessions.open(10002, wrapping_key(), now).unwrap().is_some());
+        // Expired sessions do not count against the limit.
+        let expired = now + IMPORT_SESSION_TIMEOUT;
+        let id = sessions.open(10001, wrapping_key(), expired).unwrap().unwrap();
+        assert!(!ids.contains(&id));
+    }
+}
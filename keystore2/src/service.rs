@@ -15,14 +15,24 @@
 //! This crate implement the core Keystore 2.0 service API as defined by the Keystore 2.0
 //! AIDL spec.
 
+use std::cell::Cell;
 use std::collections::HashMap;
 
-use crate::audit_log::log_key_deleted;
-use crate::permission::{KeyPerm, KeystorePerm};
+use crate::access_group;
+use crate::audit_log::{log_key_deleted, log_key_granted};
+use crate::caller_identity::CallerIdentity;
+use crate::external_keys::{self, EXTERNAL_SECURITY_LEVEL};
+use crate::hal_hotplug;
+use crate::key_perm_set;
+use crate::namespace_freeze;
+use crate::permission::{KeyPerm, KeyPermSet, KeystorePerm};
 use crate::security_level::KeystoreSecurityLevel;
+use crate::shadow_permission::UPDATE_REQUIRES_REBIND;
+use crate::software_fallback;
+use crate::trace;
 use crate::utils::{
     check_grant_permission, check_key_permission, check_keystore_permission,
-    key_parameters_to_authorizations, watchdog as wd, Asp,
+    granted_key_permissions, key_parameters_to_authorizations, watchdog as wd, Asp,
 };
 use crate::{
     database::Uuid,
@@ -38,7 +48,7 @@ use crate::{
     id_rotation::IdRotationState,
 };
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
-use android_hardware_security_keymint::binder::{BinderFeatures, Strong, ThreadState};
+use android_hardware_security_keymint::binder::{BinderFeatures, Strong};
 use android_system_keystore2::aidl::android::system::keystore2::{
     Domain::Domain, IKeystoreSecurityLevel::IKeystoreSecurityLevel,
     IKeystoreService::BnKeystoreService, IKeystoreService::IKeystoreService,
@@ -46,7 +56,6 @@ use android_system_keystore2::aidl::android::system::keystore2::{
 };
 use anyhow::{Context, Result};
 use error::Error;
-use keystore2_selinux as selinux;
 
 /// Implementation of the IKeystoreService.
 #[derive(Default)]
@@ -74,25 +83,74 @@ impl KeystoreService {
         result.uuid_by_sec_level.insert(SecurityLevel::TRUSTED_ENVIRONMENT, uuid);
 
         // Strongbox is optional, so we ignore errors and turn the result into an Option.
-        if let Ok((dev, uuid)) =
-            KeystoreSecurityLevel::new_native_binder(SecurityLevel::STRONGBOX, id_rotation_state)
-                .map(|(dev, uuid)| (Asp::new(dev.as_binder()), uuid))
+        // If it is declared but not yet available, it is picked up once it appears.
+        match KeystoreSecurityLevel::new_native_binder(
+            SecurityLevel::STRONGBOX,
+            id_rotation_state.clone(),
+        )
+        .map(|(dev, uuid)| (Asp::new(dev.as_binder()), uuid))
         {
-            result.i_sec_level_by_uuid.insert(uuid, dev);
-            result.uuid_by_sec_level.insert(SecurityLevel::STRONGBOX, uuid);
+            Ok((dev, uuid)) => {
+                result.i_sec_level_by_uuid.insert(uuid, dev);
+                result.uuid_by_sec_level.insert(SecurityLevel::STRONGBOX, uuid);
+            }
+            Err(_) => {
+                hal_hotplug::watch_keymint(SecurityLevel::STRONGBOX, id_rotation_state.clone())
+            }
+        }
+
+        // The SOFTWARE security level only serves keys that fell back to software. It is found
+        // by the uuid of such keys, but clients cannot request it with `getSecurityLevel`.
+        if software_fallback::is_enabled() {
+            match KeystoreSecurityLevel::new_native_binder(
+                SecurityLevel::SOFTWARE,
+                id_rotation_state,
+            )
+            .map(|(dev, uuid)| (Asp::new(dev.as_binder()), uuid))
+            {
+                Ok((dev, uuid)) => {
+                    result.i_sec_level_by_uuid.insert(uuid, dev);
+                }
+                Err(e) => log::error!(
+                    "In KeystoreService::new_native_binder: No software fallback: {:?}",
+                    e
+                ),
+            }
         }
 
-        let uuid_by_sec_level = result.uuid_by_sec_level.clone();
+        result.into_binder().context("In KeystoreService::new_native_binder.")
+    }
+
+    /// Create a new instance of the Keystore 2.0 service for the embedded mode, see `embedded`.
+    /// The only security level is SOFTWARE, which is served by the software implementation of
+    /// KeyMint in process.
+    #[cfg(feature = "embedded")]
+    pub fn new_embedded_binder(
+        id_rotation_state: IdRotationState,
+    ) -> Result<Strong<dyn IKeystoreService>> {
+        let mut result: Self = Default::default();
+        let (dev, uuid) =
+            KeystoreSecurityLevel::new_native_binder(SecurityLevel::SOFTWARE, id_rotation_state)
+                .context(concat!(
+                    "In KeystoreService::new_embedded_binder: ",
+                    "Trying to construct security level SOFTWARE."
+                ))
+                .map(|(dev, uuid)| (Asp::new(dev.as_binder()), uuid))?;
+        result.i_sec_level_by_uuid.insert(uuid, dev);
+        result.uuid_by_sec_level.insert(SecurityLevel::SOFTWARE, uuid);
+        result.into_binder().context("In KeystoreService::new_embedded_binder.")
+    }
+
+    fn into_binder(self) -> Result<Strong<dyn IKeystoreService>> {
+        let uuid_by_sec_level = self.uuid_by_sec_level.clone();
         LEGACY_MIGRATOR
             .set_init(move || {
                 (create_thread_local_db(), uuid_by_sec_level, LEGACY_BLOB_LOADER.clone())
             })
-            .context(
-                "In KeystoreService::new_native_binder: Trying to initialize the legacy migrator.",
-            )?;
+            .context("In into_binder: Trying to initialize the legacy migrator.")?;
 
         Ok(BnKeystoreService::new_binder(
-            result,
+            self,
             BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
         ))
     }
@@ -102,12 +160,18 @@ impl KeystoreService {
             .iter()
             .find(|(_, v)| **v == *uuid)
             .map(|(s, _)| *s)
+            .or_else(|| hal_hotplug::security_level_by_uuid(uuid).map(|(s, _)| s))
+            .or_else(|| external_keys::is_provider_uuid(uuid).then(|| EXTERNAL_SECURITY_LEVEL))
             .unwrap_or(SecurityLevel::SOFTWARE)
     }
 
     fn get_i_sec_level_by_uuid(&self, uuid: &Uuid) -> Result<Strong<dyn IKeystoreSecurityLevel>> {
         if let Some(dev) = self.i_sec_level_by_uuid.get(uuid) {
             dev.get_interface().context("In get_i_sec_level_by_uuid.")
+        } else if let Some((_, dev)) = hal_hotplug::security_level_by_uuid(uuid) {
+            dev.get_interface().context("In get_i_sec_level_by_uuid: Late security level.")
+        } else if external_keys::is_provider_uuid(uuid) {
+            external_keys::security_level().context("In get_i_sec_level_by_uuid: External key.")
         } else {
             Err(error::Error::sys())
                 .context("In get_i_sec_level_by_uuid: KeyMint instance for key not found.")
@@ -124,14 +188,29 @@ impl KeystoreService {
             .and_then(|uuid| self.i_sec_level_by_uuid.get(uuid))
         {
             dev.get_interface().context("In get_security_level.")
+        } else if let Some((dev, _)) = hal_hotplug::security_level(sec_level) {
+            dev.get_interface().context("In get_security_level: Late security level.")
+        } else if sec_level == EXTERNAL_SECURITY_LEVEL {
+            external_keys::security_level().context("In get_security_level.")
         } else {
             Err(error::Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE))
                 .context("In get_security_level: No such security level.")
         }
     }
 
-    fn get_key_entry(&self, key: &KeyDescriptor) -> Result<KeyEntryResponse> {
-        let caller_uid = ThreadState::get_calling_uid();
+    fn get_key_entry(
+        &self,
+        key: &KeyDescriptor,
+        caller: &CallerIdentity,
+    ) -> Result<KeyEntryResponse> {
+        let caller_uid = caller.uid();
+        // The caller needs get_characteristics or get_certificates, and only gets the part of
+        // the entry that it may read. Both are implied by get_info. The result of the check is
+        // kept in a Cell, because the permission check may run more than once. The permissions
+        // are queried without auditing, so that a caller holding only one of them does not
+        // cause a denial log. Only if the caller holds neither, the audited check of get_info
+        // produces the denial.
+        let may_read = Cell::new((false, false));
         let (key_id_guard, mut key_entry) = DB
             .with(|db| {
                 LEGACY_MIGRATOR.with_try_migrate(&key, caller_uid, || {
@@ -140,11 +219,35 @@ impl KeystoreService {
                         KeyType::Client,
                         KeyEntryLoadBits::PUBLIC,
                         caller_uid,
-                        |k, av| check_key_permission(KeyPerm::get_info(), k, &av),
+                        |k, av| {
+                            let granted = granted_key_permissions(
+                                key_perm_set![
+                                    KeyPerm::get_characteristics(),
+                                    KeyPerm::get_certificates()
+                                ],
+                                k,
+                                &av,
+                            )?;
+                            let characteristics = granted.includes(KeyPerm::get_characteristics());
+                            let certificates = granted.includes(KeyPerm::get_certificates());
+                            if !characteristics && !certificates {
+                                // Permissive callers are let through by the audited check.
+                                check_key_permission(KeyPerm::get_info(), k, &av)?;
+                                may_read.set((true, true));
+                            } else {
+                                may_read.set((characteristics, certificates));
+                            }
+                            Ok(())
+                        },
                     )
                 })
             })
             .context("In get_key_entry, while trying to load key info.")?;
+        let (may_read_characteristics, may_read_certificates) = may_read.get();
+        if !may_read_certificates {
+            key_entry.take_cert();
+            key_entry.take_cert_chain();
+        }
 
         let i_sec_level = if !key_entry.pure_cert() {
             Some(
@@ -172,7 +275,11 @@ impl KeystoreService {
                     .map(|d| d.to_millis_epoch())
                     .ok_or(Error::Rc(ResponseCode::VALUE_CORRUPTED))
                     .context("In get_key_entry: Trying to get creation date.")?,
-                authorizations: key_parameters_to_authorizations(key_entry.into_key_parameters()),
+                authorizations: if may_read_characteristics {
+                    key_parameters_to_authorizations(key_entry.into_key_parameters())
+                } else {
+                    vec![]
+                },
             },
         })
     }
@@ -182,8 +289,9 @@ impl KeystoreService {
         key: &KeyDescriptor,
         public_cert: Option<&[u8]>,
         certificate_chain: Option<&[u8]>,
+        caller: &CallerIdentity,
     ) -> Result<()> {
-        let caller_uid = ThreadState::get_calling_uid();
+        let caller_uid = caller.uid();
         DB.with::<_, Result<()>>(|db| {
             let entry = match LEGACY_MIGRATOR.with_try_migrate(&key, caller_uid, || {
                 db.borrow_mut().load_key_entry(
@@ -193,6 +301,11 @@ impl KeystoreService {
                     caller_uid,
                     |k, av| {
                         check_key_permission(KeyPerm::update(), k, &av)
+                            .context("In update_subcomponent.")?;
+                        namespace_freeze::check_not_frozen(k.domain, k.nspace)
+                            .context("In update_subcomponent.")?;
+                        UPDATE_REQUIRES_REBIND
+                            .check(|| check_key_permission(KeyPerm::rebind(), k, &av))
                             .context("In update_subcomponent.")
                     },
                 )
@@ -226,7 +339,7 @@ impl KeystoreService {
             let key = match (key.domain, &key.alias) {
                 (Domain::APP, Some(ref alias)) => KeyDescriptor {
                     domain: Domain::APP,
-                    nspace: ThreadState::get_calling_uid() as i64,
+                    nspace: access_group::resolve_app_namespace(caller_uid, key.nspace),
                     alias: Some(alias.clone()),
                     blob: None,
                 },
@@ -240,6 +353,7 @@ impl KeystoreService {
             // Security critical: This must return on failure. Do not remove the `?`;
             check_key_permission(KeyPerm::rebind(), &key, &None)
                 .context("Caller does not have permission to insert this certificate.")?;
+            namespace_freeze::check_not_frozen(key.domain, key.nspace)?;
 
             db.store_new_certificate(
                 &key,
@@ -253,40 +367,31 @@ impl KeystoreService {
         .context("In update_subcomponent.")
     }
 
-    fn list_entries(&self, domain: Domain, namespace: i64) -> Result<Vec<KeyDescriptor>> {
-        let mut k = match domain {
-            Domain::APP => KeyDescriptor {
-                domain,
-                nspace: ThreadState::get_calling_uid() as u64 as i64,
-                ..Default::default()
-            },
-            Domain::SELINUX => KeyDescriptor{domain, nspace: namespace, ..Default::default()},
-            _ => return Err(Error::perm()).context(
-                "In list_entries: List entries is only supported for Domain::APP and Domain::SELINUX."
-            ),
-        };
+    /// Lists the keys granted to the caller as grant descriptors. Only grants whose access
+    /// vector includes `KeyPerm::get_info` are listed, mirroring the permission needed to list
+    /// the caller's own namespace.
+    fn list_granted_entries(&self, caller: &CallerIdentity) -> Result<Vec<KeyDescriptor>> {
+        let grants = DB
+            .with(|db| db.borrow_mut().list_grants_of_grantee(caller.uid()))
+            .context("In list_granted_entries: Trying to list grants.")?;
+        Ok(grants
+            .into_iter()
+            .filter(|(_, access_vector)| access_vector.includes(KeyPerm::get_info()))
+            .map(|(grant, _)| grant)
+            .collect())
+    }
 
-        // First we check if the caller has the info permission for the selected domain/namespace.
-        // By default we use the calling uid as namespace if domain is Domain::APP.
-        // If the first check fails we check if the caller has the list permission allowing to list
-        // any namespace. In that case we also adjust the queried namespace if a specific uid was
-        // selected.
-        match check_key_permission(KeyPerm::get_info(), &k, &None) {
-            Err(e) => {
-                if let Some(selinux::Error::PermissionDenied) =
-                    e.root_cause().downcast_ref::<selinux::Error>()
-                {
-                    check_keystore_permission(KeystorePerm::list())
-                        .context("In list_entries: While checking keystore permission.")?;
-                    if namespace != -1 {
-                        k.nspace = namespace;
-                    }
-                } else {
-                    return Err(e).context("In list_entries: While checking key permission.")?;
-                }
-            }
-            Ok(()) => {}
-        };
+    fn list_entries(
+        &self,
+        domain: Domain,
+        namespace: i64,
+        caller: &CallerIdentity,
+    ) -> Result<Vec<KeyDescriptor>> {
+        // Grants are always listed for the caller, so the namespace is ignored.
+        if domain == Domain::GRANT {
+            return self.list_granted_entries(caller);
+        }
+        let k = resolve_list_namespace(domain, namespace, caller).context("In list_entries.")?;
 
         let mut result = LEGACY_MIGRATOR
             .list_uid(k.domain, k.nspace)
@@ -301,17 +406,22 @@ impl KeystoreService {
                 .context("In list_entries: Trying to list keystore database.")?,
         );
 
+        // All descriptors share the domain and namespace, so this orders the entries by alias
+        // in binary collation order, like `KeystoreDB::list` does. Clients may rely on this.
         result.sort_unstable();
         result.dedup();
         Ok(result)
     }
 
-    fn delete_key(&self, key: &KeyDescriptor) -> Result<()> {
-        let caller_uid = ThreadState::get_calling_uid();
+    fn delete_key(&self, key: &KeyDescriptor, caller: &CallerIdentity) -> Result<()> {
+        let caller_uid = caller.uid();
         DB.with(|db| {
             LEGACY_MIGRATOR.with_try_migrate(&key, caller_uid, || {
                 db.borrow_mut().unbind_key(&key, KeyType::Client, caller_uid, |k, av| {
-                    check_key_permission(KeyPerm::delete(), k, &av).context("During delete_key.")
+                    check_key_permission(KeyPerm::delete(), k, &av)
+                        .context("During delete_key.")?;
+                    namespace_freeze::check_not_frozen(k.domain, k.nspace)
+                        .context("During delete_key.")
                 })
             })
         })
@@ -324,8 +434,9 @@ impl KeystoreService {
         key: &KeyDescriptor,
         grantee_uid: i32,
         access_vector: permission::KeyPermSet,
+        caller: &CallerIdentity,
     ) -> Result<KeyDescriptor> {
-        let caller_uid = ThreadState::get_calling_uid();
+        let caller_uid = caller.uid();
         DB.with(|db| {
             LEGACY_MIGRATOR.with_try_migrate(&key, caller_uid, || {
                 db.borrow_mut().grant(
@@ -340,16 +451,68 @@ impl KeystoreService {
         .context("In KeystoreService::grant.")
     }
 
-    fn ungrant(&self, key: &KeyDescriptor, grantee_uid: i32) -> Result<()> {
+    fn ungrant(
+        &self,
+        key: &KeyDescriptor,
+        grantee_uid: i32,
+        caller: &CallerIdentity,
+    ) -> Result<()> {
         DB.with(|db| {
-            db.borrow_mut().ungrant(&key, ThreadState::get_calling_uid(), grantee_uid as u32, |k| {
-                check_key_permission(KeyPerm::grant(), k, &None)
+            db.borrow_mut().ungrant(&key, caller.uid(), grantee_uid as u32, None, |k, _| {
+                check_key_permission(KeyPerm::ungrant(), k, &None)
             })
         })
         .context("In KeystoreService::ungrant.")
     }
 }
 
+/// Returns the descriptor of the namespace that a listing of `domain` and `namespace` by the
+/// caller covers, after checking that the caller may list it. Only the domain and nspace fields
+/// of the descriptor are set. Domain::GRANT must be handled by the caller.
+///
+/// First we check if the caller has the info permission for the selected domain/namespace.
+/// By default we use the calling uid as namespace if domain is Domain::APP.
+/// If the first check fails we check if the caller has the list permission allowing to list
+/// any namespace. In that case we also adjust the queried namespace if a specific uid was
+/// selected.
+pub(crate) fn resolve_list_namespace(
+    domain: Domain,
+    namespace: i64,
+    caller: &CallerIdentity,
+) -> Result<KeyDescriptor> {
+    let mut k = match domain {
+        Domain::APP => KeyDescriptor {
+            domain,
+            nspace: access_group::resolve_app_namespace(caller.uid(), namespace),
+            ..Default::default()
+        },
+        Domain::SELINUX => KeyDescriptor { domain, nspace: namespace, ..Default::default() },
+        _ => {
+            return Err(Error::perm()).context(concat!(
+                "In resolve_list_namespace: List entries is only supported for Domain::APP, ",
+                "Domain::SELINUX, and Domain::GRANT."
+            ))
+        }
+    };
+
+    match check_key_permission(KeyPerm::get_info(), &k, &None) {
+        Err(e) => {
+            if permission::is_permission_denied(&e) {
+                check_keystore_permission(KeystorePerm::list())
+                    .context("In resolve_list_namespace: While checking keystore permission.")?;
+                if namespace != -1 {
+                    k.nspace = namespace;
+                }
+            } else {
+                return Err(e)
+                    .context("In resolve_list_namespace: While checking key permission.")?;
+            }
+        }
+        Ok(()) => {}
+    };
+    Ok(k)
+}
+
 impl binder::Interface for KeystoreService {}
 
 // Implementation of IKeystoreService. See AIDL spec at
@@ -359,14 +522,16 @@ impl IKeystoreService for KeystoreService {
         &self,
         security_level: SecurityLevel,
     ) -> binder::public_api::Result<Strong<dyn IKeystoreSecurityLevel>> {
+        let _trace = trace::begin();
         let _wp = wd::watch_millis_with("IKeystoreService::getSecurityLevel", 500, move || {
             format!("security_level: {}", security_level.0)
         });
         map_or_log_err(self.get_security_level(security_level), Ok)
     }
     fn getKeyEntry(&self, key: &KeyDescriptor) -> binder::public_api::Result<KeyEntryResponse> {
+        let _trace = trace::begin();
         let _wp = wd::watch_millis("IKeystoreService::get_key_entry", 500);
-        map_or_log_err(self.get_key_entry(key), Ok)
+        map_or_log_err(self.get_key_entry(key, &CallerIdentity::current()), Ok)
     }
     fn updateSubcomponent(
         &self,
@@ -374,21 +539,26 @@ impl IKeystoreService for KeystoreService {
         public_cert: Option<&[u8]>,
         certificate_chain: Option<&[u8]>,
     ) -> binder::public_api::Result<()> {
+        let _trace = trace::begin();
         let _wp = wd::watch_millis("IKeystoreService::updateSubcomponent", 500);
-        map_or_log_err(self.update_subcomponent(key, public_cert, certificate_chain), Ok)
+        let caller = CallerIdentity::current();
+        map_or_log_err(self.update_subcomponent(key, public_cert, certificate_chain, &caller), Ok)
     }
     fn listEntries(
         &self,
         domain: Domain,
         namespace: i64,
     ) -> binder::public_api::Result<Vec<KeyDescriptor>> {
+        let _trace = trace::begin();
         let _wp = wd::watch_millis("IKeystoreService::listEntries", 500);
-        map_or_log_err(self.list_entries(domain, namespace), Ok)
+        map_or_log_err(self.list_entries(domain, namespace, &CallerIdentity::current()), Ok)
     }
     fn deleteKey(&self, key: &KeyDescriptor) -> binder::public_api::Result<()> {
+        let _trace = trace::begin();
         let _wp = wd::watch_millis("IKeystoreService::deleteKey", 500);
-        let result = self.delete_key(key);
-        log_key_deleted(key, ThreadState::get_calling_uid(), result.is_ok());
+        let caller = CallerIdentity::current();
+        let result = self.delete_key(key, &caller);
+        log_key_deleted(key, &caller, result.is_ok());
         map_or_log_err(result, Ok)
     }
     fn grant(
@@ -397,11 +567,16 @@ impl IKeystoreService for KeystoreService {
         grantee_uid: i32,
         access_vector: i32,
     ) -> binder::public_api::Result<KeyDescriptor> {
+        let _trace = trace::begin();
         let _wp = wd::watch_millis("IKeystoreService::grant", 500);
-        map_or_log_err(self.grant(key, grantee_uid, access_vector.into()), Ok)
+        let caller = CallerIdentity::current();
+        let result = self.grant(key, grantee_uid, access_vector.into(), &caller);
+        log_key_granted(key, grantee_uid as u32, &caller, result.is_ok());
+        map_or_log_err(result, Ok)
     }
     fn ungrant(&self, key: &KeyDescriptor, grantee_uid: i32) -> binder::public_api::Result<()> {
+        let _trace = trace::begin();
         let _wp = wd::watch_millis("IKeystoreService::ungrant", 500);
-        map_or_log_err(self.ungrant(key, grantee_uid), Ok)
+        map_or_log_err(self.ungrant(key, grantee_uid, &CallerIdentity::current()), Ok)
     }
 }
@@ -0,0 +1,146 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements shadow evaluation of proposed permission rules. Tightening the
+//! permission required for an operation can break callers that were allowed the operation
+//! before. To roll out such a change safely, the proposed rule is first evaluated in shadow:
+//! the legacy check alone decides the outcome, but every call that the proposed rule would
+//! have denied is logged and counted in metrics. Once the mismatches have been understood, the
+//! rule can be enforced with the system property `keystore.shadow_permission.<rule>`.
+
+use crate::error::Error;
+use crate::key_policy::KeyPolicyDenied;
+use crate::metrics_store::log_permission_shadow_mismatch_stats;
+use crate::permission;
+use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
+use anyhow::Result;
+use keystore2_system_property::PropertyWatcher;
+
+/// How a proposed rule is evaluated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadowMode {
+    /// The proposed rule is not evaluated.
+    Off,
+    /// The proposed rule is evaluated, but only mismatches are reported.
+    Shadow,
+    /// The proposed rule decides the outcome along with the legacy check.
+    Enforce,
+}
+
+/// A proposed stricter permission rule.
+pub struct ShadowRule {
+    /// Name of the rule as it appears in logs, metrics, and the system property.
+    pub name: &'static str,
+}
+
+/// Updating the certificates of an existing key changes what all clients see under its alias,
+/// so the caller is proposed to also hold the `rebind` permission in addition to `update`.
+pub const UPDATE_REQUIRES_REBIND: ShadowRule = ShadowRule { name: "update_requires_rebind" };
+
+impl ShadowRule {
+    /// Returns the mode configured with the system property `keystore.shadow_permission.<name>`,
+    /// which may be one of "off", "shadow", or "enforce". Defaults to `ShadowMode::Shadow`.
+    pub fn mode(&self) -> ShadowMode {
+        let property = format!("keystore.shadow_permission.{}", self.name);
+        let configured = PropertyWatcher::new(&property)
+            .ok()
+            .and_then(|mut w| w.read(|_n, v| Ok(v.to_string())).ok());
+        match configured.as_deref() {
+            Some("off") => ShadowMode::Off,
+            Some("enforce") => ShadowMode::Enforce,
+            _ => ShadowMode::Shadow,
+        }
+    }
+
+    /// Evaluates the proposed check after the legacy check has passed. It must only be called
+    /// once the legacy check has allowed the request.
+    pub fn check<F>(&self, proposed: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        self.check_with_mode(self.mode(), proposed)
+    }
+
+    /// Like `check`, but evaluates the proposed check in the given mode rather than in the
+    /// mode configured for this rule.
+    pub fn check_with_mode<F>(&self, mode: ShadowMode, proposed: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        match mode {
+            ShadowMode::Off => Ok(()),
+            ShadowMode::Enforce => proposed(),
+            ShadowMode::Shadow => {
+                match proposed() {
+                    Ok(()) => {}
+                    Err(e) if is_permission_denied(&e) => {
+                        log::warn!(
+                            "Permission check passed, but would be denied by rule \"{}\": {:?}",
+                            self.name,
+                            e
+                        );
+                        log_permission_shadow_mismatch_stats(self.name);
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            "Failed to evaluate rule \"{}\" in shadow mode: {:?}",
+                            self.name,
+                            e
+                        );
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn is_permission_denied(e: &anyhow::Error) -> bool {
+    permission::is_permission_denied(e)
+        || e.root_cause().is::<KeyPolicyDenied>()
+        || matches!(
+            e.root_cause().downcast_ref::<Error>(),
+            Some(Error::Rc(ResponseCode::PERMISSION_DENIED))
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use keystore2_selinux as selinux;
+    use std::cell::Cell;
+
+    const TEST_RULE: ShadowRule = ShadowRule { name: "test_rule" };
+
+    #[test]
+    fn test_shadow_modes() {
+        let evaluated = Cell::new(false);
+        let deny = || {
+            evaluated.set(true);
+            Err(anyhow!(selinux::Error::perm()))
+        };
+
+        assert!(TEST_RULE.check_with_mode(ShadowMode::Off, deny).is_ok());
+        assert!(!evaluated.get());
+
+        assert!(TEST_RULE.check_with_mode(ShadowMode::Shadow, deny).is_ok());
+        assert!(evaluated.get());
+
+        let e = TEST_RULE.check_with_mode(ShadowMode::Enforce, deny).unwrap_err();
+        assert!(is_permission_denied(&e));
+        assert!(is_permission_denied(&anyhow!(Error::perm())));
+        assert!(!is_permission_denied(&anyhow!(Error::sys())));
+    }
+}
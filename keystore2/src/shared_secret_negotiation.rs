@@ -15,6 +15,8 @@
 //! This module implements the shared secret negotiation.
 
 use crate::error::{map_binder_status, map_binder_status_code, Error};
+use crate::globals::TASK_EXECUTOR;
+use crate::task_executor::Priority;
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
 use android_hardware_security_keymint::binder::Strong;
 use android_hardware_security_sharedsecret::aidl::android::hardware::security::sharedsecret::{
@@ -26,10 +28,10 @@ use keystore2_vintf::{get_aidl_instances, get_hidl_instances};
 use std::fmt::{self, Display, Formatter};
 use std::time::Duration;
 
-/// This function initiates the shared secret negotiation. It starts a thread and then returns
-/// immediately. The thread consults the vintf manifest to enumerate expected negotiation
+/// This function initiates the shared secret negotiation. It starts a task and then returns
+/// immediately. The task consults the vintf manifest to enumerate expected negotiation
 /// participants. It then attempts to connect to all of these participants. If any connection
-/// fails the thread will retry once per second to connect to the failed instance(s) until all of
+/// fails the task will retry once per second to connect to the failed instance(s) until all of
 /// the instances are connected. It then performs the negotiation.
 ///
 /// During the first phase of the negotiation it will again try every second until
@@ -37,13 +39,15 @@ use std::time::Duration;
 /// are not fully functioning at this time due to hardware delays or boot order dependency issues.
 /// An error during the second phase or a checksum mismatch leads to a panic.
 pub fn perform_shared_secret_negotiation() {
-    std::thread::spawn(|| {
-        let participants = list_participants()
-            .expect("In perform_shared_secret_negotiation: Trying to list participants.");
-        let connected = connect_participants(participants);
-        negotiate_shared_secret(connected);
-        log::info!("Shared secret negotiation concluded successfully.");
-    });
+    TASK_EXECUTOR
+        .spawn_dedicated("keystore2_shared_secret", Priority::Normal, |_| {
+            let participants = list_participants()
+                .expect("In perform_shared_secret_negotiation: Trying to list participants.");
+            let connected = connect_participants(participants);
+            negotiate_shared_secret(connected);
+            log::info!("Shared secret negotiation concluded successfully.");
+        })
+        .expect("In perform_shared_secret_negotiation: Failed to start negotiation.");
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -0,0 +1,155 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the clean shutdown of Keystore. Without it, a controlled restart,
+//! e.g., for an update, looks like a crash: It is counted in `keystore.crash_count`, dependent
+//! services learn about it only when their next call fails, and the KeyMint operations that
+//! were running stay alive in the HAL until it prunes them.
+//!
+//! `prepare_for_shutdown` informs the registered `IShutdownListener`s, aborts all operations,
+//! lets the garbage collector finish and persist the blob deletions in progress, and flushes
+//! the database. It runs when `IKeystoreMaintenance::prepareForShutdown` is called, after which
+//! Keystore keeps serving requests. When Keystore receives SIGTERM, it prepares for shutdown,
+//! stops the background tasks, waits for the database transactions in progress and blocks
+//! later ones, and only then marks the shutdown as clean, so that the next start is not counted
+//! as a crash, and exits.
+
+use crate::async_keygen::get_security_level;
+use crate::database::KeystoreDB;
+use crate::globals::{flush_gc, ASYNC_TASK, DB, TASK_EXECUTOR};
+use crate::metrics_store::mark_clean_shutdown;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use android_security_maintenance::aidl::android::security::maintenance::IShutdownListener::IShutdownListener;
+use android_security_maintenance::binder::{Interface, Strong};
+use lazy_static::lazy_static;
+use std::sync::{mpsc::channel, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long the shutdown waits for the garbage collector to finish and persist the deletions
+/// in progress. A key deletion in progress has to finish first.
+const GC_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long the shutdown waits for the running background tasks to return.
+const TASK_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref SHUTDOWN_LISTENERS: Mutex<Vec<Strong<dyn IShutdownListener>>> = Default::default();
+}
+
+/// Registers a shutdown listener. Registering the same listener twice has no effect.
+pub fn add_listener(listener: &Strong<dyn IShutdownListener>) {
+    let mut listeners = SHUTDOWN_LISTENERS.lock().unwrap();
+    if !listeners.iter().any(|l| l.as_binder() == listener.as_binder()) {
+        listeners.push(listener.clone());
+    }
+}
+
+/// Unregisters a shutdown listener.
+pub fn remove_listener(listener: &Strong<dyn IShutdownListener>) {
+    SHUTDOWN_LISTENERS.lock().unwrap().retain(|l| l.as_binder() != listener.as_binder());
+}
+
+fn notify_listeners() {
+    for listener in SHUTDOWN_LISTENERS.lock().unwrap().iter() {
+        if let Err(e) = listener.onKeystoreShutdown() {
+            log::warn!("In notify_listeners: Failed to notify listener: {:?}", e);
+        }
+    }
+}
+
+/// Prepares Keystore for a controlled stop or restart. Every step is attempted even if an
+/// earlier one failed. Keystore keeps working afterwards, so calling this more than once is
+/// harmless.
+pub fn prepare_for_shutdown() {
+    log::info!("Preparing for shutdown.");
+    notify_listeners();
+
+    let aborted: usize =
+        [SecurityLevel::SOFTWARE, SecurityLevel::TRUSTED_ENVIRONMENT, SecurityLevel::STRONGBOX]
+            .iter()
+            .filter_map(|security_level| get_security_level(*security_level).ok())
+            .map(|sec_level| sec_level.abort_all_operations())
+            .sum();
+    log::info!("In prepare_for_shutdown: Aborted {} operations.", aborted);
+
+    // The flush is queued on the async task, so we wait for a job queued after it. The garbage
+    // collector stops deleting blobs a little before we stop waiting.
+    let start = Instant::now();
+    flush_gc(start + GC_FLUSH_TIMEOUT / 2);
+    let (sender, receiver) = channel();
+    ASYNC_TASK.queue_hi(move |_| {
+        let _ = sender.send(());
+    });
+    if receiver.recv_timeout(GC_FLUSH_TIMEOUT).is_err() {
+        log::warn!("In prepare_for_shutdown: Timed out waiting for the garbage collector.");
+    }
+
+    flush_database();
+}
+
+fn flush_database() {
+    if let Err(e) = DB.with(|db| db.borrow_mut().flush()) {
+        log::error!("In flush_database: Failed to flush the database: {:?}", e);
+    }
+}
+
+/// Prepares for shutdown and stops everything that could still change the persistent state, so
+/// that the process can exit. Keystore does not serve requests that need the database anymore
+/// afterwards.
+fn shut_down() {
+    prepare_for_shutdown();
+    let remaining = TASK_EXECUTOR.shutdown(TASK_SHUTDOWN_TIMEOUT);
+    if !remaining.is_empty() {
+        log::warn!("In shut_down: Exiting with running tasks: {:?}", remaining);
+    }
+    KeystoreDB::block_transactions();
+    // Transactions may have completed since the flush above.
+    flush_database();
+    mark_clean_shutdown();
+}
+
+/// Blocks SIGTERM and starts a thread that waits for it, prepares for shutdown, and exits.
+/// init sends SIGTERM when it stops Keystore, e.g., before a reboot. Threads inherit the
+/// signal mask, so this must be called before any other thread is started. Otherwise, SIGTERM
+/// may be delivered to a thread that does not block it, which ends the process right away.
+pub fn handle_sigterm() {
+    // Safety: `set` is a valid signal set. The calls only access `set`, and
+    // pthread_sigmask accepts a null pointer for the previous mask.
+    let mut set: libc::sigset_t = unsafe { std::mem::zeroed() };
+    let result = unsafe {
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut())
+    };
+    if result != 0 {
+        log::error!("In handle_sigterm: Failed to block SIGTERM: {}", result);
+        return;
+    }
+    let spawned =
+        std::thread::Builder::new().name("keystore2_shutdown".to_string()).spawn(move || {
+            let mut signal: libc::c_int = 0;
+            // Safety: sigwait only reads `set` and writes `signal`.
+            let result = unsafe { libc::sigwait(&set, &mut signal) };
+            if result != 0 {
+                log::error!("In handle_sigterm: sigwait failed: {}", result);
+                return;
+            }
+            log::info!("Received SIGTERM.");
+            shut_down();
+            std::process::exit(0);
+        });
+    if let Err(e) = spawned {
+        log::error!("In handle_sigterm: Failed to start the shutdown thread: {:?}", e);
+    }
+}
@@ -0,0 +1,359 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the registry of key generation requests that the KeyMint HAL of a
+//! security level does not support, and that are served by the software implementation of
+//! KeyMint at the SOFTWARE security level instead. Keys generated this way are stored for the
+//! SOFTWARE security level and report it in their metadata and characteristics, so that
+//! callers can tell them apart.
+//!
+//! Falling back is off by default. The device enables it per algorithm in the configuration
+//! file, which holds one algorithm name per line, i.e., `rsa`, `ec`, `aes`, `3des` or `hmac`.
+//! Empty lines and lines starting with `#` are ignored. If the configuration is malformed, no
+//! request falls back. In addition, each request must opt in with
+//! `KeystoreKeyFlag::ALLOW_SOFTWARE_FALLBACK`. Requests for keys whose namespace requires a
+//! security level, see `namespace_params`, and requests for attested keys never fall back.
+//!
+//! If a HAL rejects an (algorithm, purpose) combination outright, the combination is
+//! registered, and subsequent requests are sent to the software implementation directly. If
+//! it rejects a less fundamental parameter, e.g., a digest or key size, only the request at
+//! hand falls back, because other requests for the same combination may well be supported.
+
+use crate::config_file;
+use crate::error::{Error, ErrorCode};
+use crate::namespace_params;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, KeyCharacteristics::KeyCharacteristics, KeyParameter::KeyParameter,
+    KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
+    Tag::Tag,
+};
+use android_security_keyparameters::aidl::android::security::keyparameters::KeystoreKeyFlag::KeystoreKeyFlag;
+use android_system_keystore2::aidl::android::system::keystore2::KeyDescriptor::KeyDescriptor;
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::sync::Mutex;
+
+/// Location of the software fallback configuration.
+const SOFTWARE_FALLBACK_CONFIG: &str = "/vendor/etc/security/keystore2_software_fallback.conf";
+
+/// Key flag with which a key generation request opts in to the software fallback.
+pub const ALLOW_SOFTWARE_FALLBACK_FLAG: i32 = KeystoreKeyFlag::ALLOW_SOFTWARE_FALLBACK.0;
+
+/// Errors with which a HAL rejects an (algorithm, purpose) combination as a whole.
+const UNSUPPORTED_COMBINATION_ERRORS: &[ErrorCode] =
+    &[ErrorCode::UNSUPPORTED_ALGORITHM, ErrorCode::UNSUPPORTED_PURPOSE];
+
+/// Errors with which a HAL rejects individual parameters of a request.
+const UNSUPPORTED_PARAMETER_ERRORS: &[ErrorCode] = &[
+    ErrorCode::UNSUPPORTED_KEY_SIZE,
+    ErrorCode::UNSUPPORTED_BLOCK_MODE,
+    ErrorCode::UNSUPPORTED_DIGEST,
+    ErrorCode::UNSUPPORTED_PADDING_MODE,
+    ErrorCode::UNSUPPORTED_EC_CURVE,
+    ErrorCode::UNSUPPORTED_MGF_DIGEST,
+];
+
+fn parse_algorithm(name: &str) -> Option<Algorithm> {
+    match name {
+        "rsa" => Some(Algorithm::RSA),
+        "ec" => Some(Algorithm::EC),
+        "aes" => Some(Algorithm::AES),
+        "3des" => Some(Algorithm::TRIPLE_DES),
+        "hmac" => Some(Algorithm::HMAC),
+        _ => None,
+    }
+}
+
+/// The algorithms whose keys may fall back to software.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Policy(BTreeSet<i32>);
+
+impl Policy {
+    /// Parses the software fallback configuration.
+    pub fn parse(config: &str) -> Result<Self> {
+        let mut algorithms = BTreeSet::new();
+        for (n, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let algorithm = parse_algorithm(line)
+                .ok_or_else(|| anyhow!("Unknown algorithm \"{}\" in line {}.", line, n + 1))?;
+            algorithms.insert(algorithm.0);
+        }
+        Ok(Self(algorithms))
+    }
+
+    fn allows(&self, algorithm: Algorithm) -> bool {
+        self.0.contains(&algorithm.0)
+    }
+}
+
+fn algorithm_of(params: &[KeyParameter]) -> Option<Algorithm> {
+    params.iter().find_map(|kp| match kp.value {
+        KeyParameterValue::Algorithm(algorithm) => Some(algorithm),
+        _ => None,
+    })
+}
+
+fn purposes_of(params: &[KeyParameter]) -> impl Iterator<Item = KeyPurpose> + '_ {
+    params.iter().filter_map(|kp| match kp.value {
+        KeyParameterValue::KeyPurpose(purpose) => Some(purpose),
+        _ => None,
+    })
+}
+
+/// The (security level, algorithm, purpose) combinations that are known to be unsupported.
+#[derive(Default)]
+struct Registry(BTreeSet<(i32, i32, i32)>);
+
+impl Registry {
+    fn is_registered(&self, security_level: SecurityLevel, params: &[KeyParameter]) -> bool {
+        match algorithm_of(params) {
+            Some(algorithm) => purposes_of(params)
+                .any(|purpose| self.0.contains(&(security_level.0, algorithm.0, purpose.0))),
+            None => false,
+        }
+    }
+
+    fn register(&mut self, security_level: SecurityLevel, params: &[KeyParameter]) {
+        if let Some(algorithm) = algorithm_of(params) {
+            for purpose in purposes_of(params) {
+                self.0.insert((security_level.0, algorithm.0, purpose.0));
+            }
+        }
+    }
+
+    /// Decides whether a request that failed with `error` falls back to software, and
+    /// registers the request's combinations if they are unsupported as a whole.
+    fn on_error(
+        &mut self,
+        security_level: SecurityLevel,
+        params: &[KeyParameter],
+        error: &anyhow::Error,
+    ) -> bool {
+        let code = match error.root_cause().downcast_ref::<Error>() {
+            Some(Error::Km(code)) => *code,
+            _ => return false,
+        };
+        if UNSUPPORTED_COMBINATION_ERRORS.contains(&code) {
+            self.register(security_level, params);
+            true
+        } else {
+            UNSUPPORTED_PARAMETER_ERRORS.contains(&code)
+        }
+    }
+}
+
+lazy_static! {
+    /// The software fallback policy of this device, loaded once on first use. A malformed
+    /// configuration disables the fallback.
+    static ref POLICY: Policy =
+        config_file::load(SOFTWARE_FALLBACK_CONFIG, Policy::parse).unwrap_or_default();
+    static ref REGISTRY: Mutex<Registry> = Default::default();
+}
+
+/// Returns true if the device allows any algorithm to fall back to software. Only then is the
+/// SOFTWARE security level served.
+pub fn is_enabled() -> bool {
+    !POLICY.0.is_empty()
+}
+
+fn may_fall_back_with(
+    policy: &Policy,
+    security_level: SecurityLevel,
+    key: &KeyDescriptor,
+    params: &[KeyParameter],
+    flags: i32,
+) -> bool {
+    security_level != SecurityLevel::SOFTWARE
+        && flags & ALLOW_SOFTWARE_FALLBACK_FLAG != 0
+        && algorithm_of(params).map_or(false, |algorithm| policy.allows(algorithm))
+        && !params.iter().any(|kp| kp.tag == Tag::ATTESTATION_CHALLENGE)
+        && namespace_params::check_security_level(SecurityLevel::SOFTWARE, key).is_ok()
+}
+
+/// Returns true if a key generation request for the new key `key` with the given parameters
+/// and flags may fall back to software at all, see the module documentation.
+pub fn may_fall_back(
+    security_level: SecurityLevel,
+    key: &KeyDescriptor,
+    params: &[KeyParameter],
+    flags: i32,
+) -> bool {
+    may_fall_back_with(&POLICY, security_level, key, params, flags)
+}
+
+/// Returns true if the request should be sent to the software implementation right away,
+/// because the HAL of `security_level` is known not to support it.
+pub fn is_registered(security_level: SecurityLevel, params: &[KeyParameter]) -> bool {
+    REGISTRY.lock().unwrap().is_registered(security_level, params)
+}
+
+/// Called when the HAL of `security_level` failed a key generation request that may fall back
+/// to software. Returns true if the request should be retried in software.
+pub fn on_generate_error(
+    security_level: SecurityLevel,
+    params: &[KeyParameter],
+    error: &anyhow::Error,
+) -> bool {
+    REGISTRY.lock().unwrap().on_error(security_level, params, error)
+}
+
+/// Marks all characteristics that KeyMint enforces as enforced by the SOFTWARE security level.
+/// The characteristics that Keystore enforces are kept as they are.
+pub fn mark_software(characteristics: Vec<KeyCharacteristics>) -> Vec<KeyCharacteristics> {
+    characteristics
+        .into_iter()
+        .map(|kc| match kc.securityLevel {
+            SecurityLevel::KEYSTORE => kc,
+            _ => KeyCharacteristics { securityLevel: SecurityLevel::SOFTWARE, ..kc },
+        })
+        .collect()
+}
+
+/// Writes the policy and the registered combinations to `f`.
+pub fn dump(f: &mut dyn Write) -> std::io::Result<()> {
+    writeln!(f, "Software fallback algorithms:")?;
+    for algorithm in POLICY.0.iter() {
+        writeln!(f, "  {:?}", Algorithm(*algorithm))?;
+    }
+    writeln!(f, "Software fallback registry:")?;
+    for (security_level, algorithm, purpose) in REGISTRY.lock().unwrap().0.iter() {
+        writeln!(
+            f,
+            "  {:?}: {:?} for {:?}",
+            SecurityLevel(*security_level),
+            Algorithm(*algorithm),
+            KeyPurpose(*purpose)
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_system_keystore2::aidl::android::system::keystore2::Domain::Domain;
+
+    fn params(algorithm: Algorithm, purposes: &[KeyPurpose]) -> Vec<KeyParameter> {
+        let mut result = vec![KeyParameter {
+            tag: Tag::ALGORITHM,
+            value: KeyParameterValue::Algorithm(algorithm),
+        }];
+        result.extend(purposes.iter().map(|purpose| KeyParameter {
+            tag: Tag::PURPOSE,
+            value: KeyParameterValue::KeyPurpose(*purpose),
+        }));
+        result
+    }
+
+    #[test]
+    fn test_parse_policy() -> Result<()> {
+        assert_eq!(Policy::parse("")?, Policy::default());
+        let policy = Policy::parse("# Niche algorithms.\nrsa\n\n 3des \n")?;
+        assert!(policy.allows(Algorithm::RSA));
+        assert!(policy.allows(Algorithm::TRIPLE_DES));
+        assert!(!policy.allows(Algorithm::EC));
+        assert!(Policy::parse("rsa,ec").is_err());
+        assert!(Policy::parse("rsa-pss").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_may_fall_back() -> Result<()> {
+        let policy = Policy::parse("rsa")?;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 10001,
+            alias: Some("key".to_string()),
+            blob: None,
+        };
+        let sign = params(Algorithm::RSA, &[KeyPurpose::SIGN]);
+        let tee = SecurityLevel::TRUSTED_ENVIRONMENT;
+        let allow = ALLOW_SOFTWARE_FALLBACK_FLAG;
+
+        assert!(may_fall_back_with(&policy, tee, &key, &sign, allow));
+        // The request must opt in.
+        assert!(!may_fall_back_with(&policy, tee, &key, &sign, 0));
+        // The policy must allow the algorithm.
+        assert!(!may_fall_back_with(
+            &policy,
+            tee,
+            &key,
+            &params(Algorithm::EC, &[KeyPurpose::SIGN]),
+            allow
+        ));
+        assert!(!may_fall_back_with(&Policy::default(), tee, &key, &sign, allow));
+        // The software security level has nothing to fall back to.
+        assert!(!may_fall_back_with(&policy, SecurityLevel::SOFTWARE, &key, &sign, allow));
+        // Attested keys never fall back.
+        let mut attested = sign.clone();
+        attested.push(KeyParameter {
+            tag: Tag::ATTESTATION_CHALLENGE,
+            value: KeyParameterValue::Blob(vec![1, 2, 3]),
+        });
+        assert!(!may_fall_back_with(&policy, tee, &key, &attested, allow));
+        Ok(())
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = Registry::default();
+        let sign = params(Algorithm::RSA, &[KeyPurpose::SIGN]);
+        let encrypt = params(Algorithm::RSA, &[KeyPurpose::ENCRYPT]);
+        let tee = SecurityLevel::TRUSTED_ENVIRONMENT;
+        let strongbox = SecurityLevel::STRONGBOX;
+
+        // Errors unrelated to support never fall back.
+        assert!(!registry.on_error(
+            strongbox,
+            &sign,
+            &anyhow!(Error::Km(ErrorCode::UNKNOWN_ERROR))
+        ));
+        assert!(!registry.on_error(strongbox, &sign, &anyhow!(Error::sys())));
+
+        // An unsupported parameter falls back without registering the combination.
+        let e = anyhow!(Error::Km(ErrorCode::UNSUPPORTED_DIGEST)).context("In test.");
+        assert!(registry.on_error(strongbox, &sign, &e));
+        assert!(!registry.is_registered(strongbox, &sign));
+
+        // An unsupported combination is registered for the security level it was reported by.
+        let e = anyhow!(Error::Km(ErrorCode::UNSUPPORTED_PURPOSE)).context("In test.");
+        assert!(registry.on_error(strongbox, &sign, &e));
+        assert!(registry.is_registered(strongbox, &sign));
+        assert!(!registry.is_registered(strongbox, &encrypt));
+        assert!(!registry.is_registered(tee, &sign));
+        assert!(registry.is_registered(
+            strongbox,
+            &params(Algorithm::RSA, &[KeyPurpose::VERIFY, KeyPurpose::SIGN])
+        ));
+    }
+
+    #[test]
+    fn test_mark_software() {
+        let characteristics = vec![
+            KeyCharacteristics {
+                securityLevel: SecurityLevel::TRUSTED_ENVIRONMENT,
+                authorizations: params(Algorithm::RSA, &[KeyPurpose::SIGN]),
+            },
+            KeyCharacteristics { securityLevel: SecurityLevel::KEYSTORE, authorizations: vec![] },
+        ];
+        let levels: Vec<SecurityLevel> =
+            mark_software(characteristics).into_iter().map(|kc| kc.securityLevel).collect();
+        assert_eq!(levels, vec![SecurityLevel::SOFTWARE, SecurityLevel::KEYSTORE]);
+    }
+}
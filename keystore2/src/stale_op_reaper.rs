@@ -0,0 +1,118 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the optional stale operation reaper. It periodically checks whether
+//! the processes that own long idle operations are still alive and prunes the operations of
+//! processes that are gone. Without it, the KeyMint operation slots held by crashed clients are
+//! only reclaimed under pruning pressure or when the binder object is eventually dropped.
+
+use crate::async_keygen::get_security_level;
+use crate::globals::{ASYNC_TASK, TASK_EXECUTOR};
+use crate::task_executor::Priority;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use keystore2_system_property::PropertyWatcher;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::Duration;
+
+/// System property holding the interval of the stale operation reaper in seconds. If absent or
+/// zero, the reaper does not run.
+const REAP_INTERVAL_PROPERTY: &str = "keystore.stale_operation_reap_interval_seconds";
+
+/// Operations that were used more recently than this are never reaped. This keeps the reaper
+/// from racing a client that is still in the middle of setting up its operation.
+const MIN_IDLE: Duration = Duration::from_secs(30);
+
+/// Returns true if the process `pid` exists and belongs to `uid`. Checking the uid keeps a
+/// recycled pid from keeping the operations of a dead client alive.
+pub fn is_process_alive(pid: i32, uid: u32) -> bool {
+    is_process_alive_in(Path::new("/proc"), pid, uid)
+}
+
+fn is_process_alive_in(proc_root: &Path, pid: i32, uid: u32) -> bool {
+    match proc_root.join(pid.to_string()).metadata() {
+        Ok(metadata) => metadata.uid() == uid,
+        // If the process entry cannot be inspected for any other reason than its absence, we
+        // err on the side of keeping the operation.
+        Err(e) => e.kind() != std::io::ErrorKind::NotFound,
+    }
+}
+
+/// Reaps the orphaned operations of all security levels once. Returns the number of reaped
+/// operations.
+pub fn reap() -> usize {
+    [SecurityLevel::SOFTWARE, SecurityLevel::TRUSTED_ENVIRONMENT, SecurityLevel::STRONGBOX]
+        .iter()
+        .filter_map(|security_level| get_security_level(*security_level).ok())
+        .map(|sec_level| sec_level.reap_orphaned_operations(MIN_IDLE, is_process_alive))
+        .sum()
+}
+
+fn reap_interval() -> Option<Duration> {
+    let seconds = PropertyWatcher::new(REAP_INTERVAL_PROPERTY).ok().and_then(|mut w| {
+        w.read(|_n, v| v.parse::<u64>().map_err(std::convert::Into::into)).ok()
+    })?;
+    if seconds == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(seconds))
+    }
+}
+
+/// Starts the reaper if it is enabled by the system property
+/// `keystore.stale_operation_reap_interval_seconds`. The reaping itself runs on the low
+/// priority queue of the async task.
+pub fn start() {
+    let interval = match reap_interval() {
+        Some(interval) => interval,
+        None => return,
+    };
+    log::info!("Starting the stale operation reaper with an interval of {:?}.", interval);
+    let spawned = TASK_EXECUTOR.spawn_periodic(
+        "keystore2_stale_op_reaper",
+        Priority::Background,
+        interval,
+        || {
+            ASYNC_TASK.queue_lo(|_| {
+                let reaped = reap();
+                if reaped != 0 {
+                    log::info!("Stale operation reaper pruned {} operations.", reaped);
+                }
+            })
+        },
+    );
+    if let Err(e) = spawned {
+        log::error!("Failed to start the stale operation reaper: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keystore2_test_utils::TempDir;
+
+    #[test]
+    fn own_process_is_alive() {
+        let pid = std::process::id() as i32;
+        let uid = std::fs::metadata("/proc/self").unwrap().uid();
+        assert!(is_process_alive(pid, uid));
+        assert!(!is_process_alive(pid, uid.wrapping_add(1)));
+    }
+
+    #[test]
+    fn missing_process_is_dead() {
+        let proc_root = TempDir::new("stale_op_reaper_test").unwrap();
+        assert!(!is_process_alive_in(proc_root.path(), 1234, 0));
+    }
+}
@@ -0,0 +1,337 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the conversion of storage keys into ephemeral keys, which vold uses to
+//! set up file based encryption. Storage keys are wrapped by KeyMint and handed to Keystore as
+//! `Domain::BLOB` keys, so conversion is gated by the `convert_storage_key_to_ephemeral` key
+//! permission alone.
+//!
+//! Vold converts the same storage keys again and again, e.g., whenever a user is unlocked. The
+//! ephemeral keys stay valid until KeyMint reboots, so the converted keys are cached per
+//! security level in zeroizing memory. Because ephemeral keys are raw file encryption keys, the
+//! cache is dropped whenever a user is locked, added or removed, and when the connection to
+//! the KeyMint device is reset. It thus only serves the conversions of one unlock.
+
+use crate::error::{map_km_error, Error, ErrorCode};
+use crate::utils::watchdog as wd;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    IKeyMintDevice::IKeyMintDevice, SecurityLevel::SecurityLevel,
+};
+use android_hardware_security_keymint::binder::Strong;
+use android_system_keystore2::aidl::android::system::keystore2::EphemeralStorageKeyResponse::EphemeralStorageKeyResponse;
+use anyhow::{Context, Result};
+use keystore2_crypto::ZVec;
+use lazy_static::lazy_static;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io::Write;
+use std::sync::Mutex;
+
+/// Upper bound for the number of cached ephemeral keys. Vold holds only a handful of storage
+/// keys per user.
+const MAX_CACHED_KEYS: usize = 32;
+
+lazy_static! {
+    /// The ephemeral keys converted since boot.
+    static ref EPHEMERAL_KEYS: EphemeralKeyCache = Default::default();
+}
+
+/// The KeyMint operations needed to convert a storage key.
+pub trait StorageKeyDevice {
+    /// Converts the given storage key blob into an ephemeral key.
+    fn convert_storage_key(&self, storage_key_blob: &[u8]) -> Result<Vec<u8>, Error>;
+    /// Upgrades the given storage key blob.
+    fn upgrade_storage_key(&self, storage_key_blob: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+impl StorageKeyDevice for Strong<dyn IKeyMintDevice> {
+    fn convert_storage_key(&self, storage_key_blob: &[u8]) -> Result<Vec<u8>, Error> {
+        let _wp =
+            wd::watch_millis("In convert_storage_key: calling convertStorageKeyToEphemeral", 500);
+        map_km_error(self.convertStorageKeyToEphemeral(storage_key_blob))
+    }
+
+    fn upgrade_storage_key(&self, storage_key_blob: &[u8]) -> Result<Vec<u8>, Error> {
+        let _wp = wd::watch_millis("In upgrade_storage_key: calling upgradeKey", 500);
+        map_km_error(self.upgradeKey(storage_key_blob, &[]))
+    }
+}
+
+/// Ephemeral keys indexed by security level and storage key blob. The oldest entry is evicted
+/// when the cache is full.
+#[derive(Default)]
+pub struct EphemeralKeyCache {
+    entries: Mutex<VecDeque<(SecurityLevel, Vec<u8>, ZVec)>>,
+}
+
+impl EphemeralKeyCache {
+    fn get(&self, sec_level: SecurityLevel, storage_key_blob: &[u8]) -> Option<Vec<u8>> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(l, blob, _)| *l == sec_level && blob == storage_key_blob)
+            .map(|(_, _, ephemeral_key)| ephemeral_key.to_vec())
+    }
+
+    fn insert(&self, sec_level: SecurityLevel, storage_key_blob: &[u8], ephemeral_key: &[u8]) {
+        let ephemeral_key = match ZVec::try_from(ephemeral_key) {
+            Ok(ephemeral_key) => ephemeral_key,
+            Err(e) => {
+                log::warn!("In EphemeralKeyCache::insert: Not caching ephemeral key: {:?}", e);
+                return;
+            }
+        };
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|(l, blob, _)| !(*l == sec_level && blob == storage_key_blob));
+        if entries.len() >= MAX_CACHED_KEYS {
+            entries.pop_front();
+        }
+        entries.push_back((sec_level, storage_key_blob.to_vec(), ephemeral_key));
+    }
+
+    /// Removes the ephemeral key of the given storage key blob, if cached.
+    pub fn remove(&self, sec_level: SecurityLevel, storage_key_blob: &[u8]) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|(l, blob, _)| !(*l == sec_level && blob == storage_key_blob));
+    }
+
+    /// Removes all ephemeral keys of the given security level.
+    pub fn forget(&self, sec_level: SecurityLevel) {
+        self.entries.lock().unwrap().retain(|(l, _, _)| *l != sec_level);
+    }
+
+    /// Returns the number of cached ephemeral keys.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Returns true if no ephemeral keys are cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns an estimate of the memory held by the cached ephemeral keys in bytes.
+    pub fn size_bytes(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, blob, ephemeral_key)| {
+                std::mem::size_of::<(SecurityLevel, Vec<u8>, ZVec)>()
+                    + blob.len()
+                    + ephemeral_key.len()
+            })
+            .sum()
+    }
+
+    /// Removes all ephemeral keys. Returns the number of removed keys.
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let removed = entries.len();
+        entries.clear();
+        removed
+    }
+
+    /// Converts the given storage key blob using `dev`, unless the ephemeral key is cached. If
+    /// KeyMint requires the storage key to be upgraded, the upgraded blob is returned with the
+    /// ephemeral key and only the upgraded blob is cached. This way a caller that failed to
+    /// store the upgraded blob is handed the upgraded blob again.
+    pub fn convert(
+        &self,
+        dev: &dyn StorageKeyDevice,
+        sec_level: SecurityLevel,
+        storage_key_blob: &[u8],
+    ) -> Result<EphemeralStorageKeyResponse> {
+        if let Some(ephemeral_key) = self.get(sec_level, storage_key_blob) {
+            return Ok(EphemeralStorageKeyResponse {
+                ephemeralKey: ephemeral_key,
+                upgradedBlob: None,
+            });
+        }
+        match dev.convert_storage_key(storage_key_blob) {
+            Ok(ephemeral_key) => {
+                self.insert(sec_level, storage_key_blob, &ephemeral_key);
+                Ok(EphemeralStorageKeyResponse { ephemeralKey: ephemeral_key, upgradedBlob: None })
+            }
+            Err(Error::Km(ErrorCode::KEY_REQUIRES_UPGRADE)) => {
+                let upgraded_blob = dev
+                    .upgrade_storage_key(storage_key_blob)
+                    .context("In EphemeralKeyCache::convert: Failed to upgrade key blob.")?;
+                let ephemeral_key = dev.convert_storage_key(&upgraded_blob).context(concat!(
+                    "In EphemeralKeyCache::convert: ",
+                    "Failed to retrieve ephemeral key (after upgrade)."
+                ))?;
+                self.insert(sec_level, &upgraded_blob, &ephemeral_key);
+                Ok(EphemeralStorageKeyResponse {
+                    ephemeralKey: ephemeral_key,
+                    upgradedBlob: Some(upgraded_blob),
+                })
+            }
+            Err(e) => {
+                Err(e).context("In EphemeralKeyCache::convert: Failed to retrieve ephemeral key.")
+            }
+        }
+    }
+}
+
+/// Converts the given storage key blob into an ephemeral key using the cache of this boot.
+pub fn convert(
+    dev: &dyn StorageKeyDevice,
+    sec_level: SecurityLevel,
+    storage_key_blob: &[u8],
+) -> Result<EphemeralStorageKeyResponse> {
+    EPHEMERAL_KEYS.convert(dev, sec_level, storage_key_blob)
+}
+
+/// Removes the cached ephemeral key of the given storage key blob. This is called when the
+/// storage key is deleted.
+pub fn remove(sec_level: SecurityLevel, storage_key_blob: &[u8]) {
+    EPHEMERAL_KEYS.remove(sec_level, storage_key_blob)
+}
+
+/// Drops the cached ephemeral keys of the given security level. This is called when the
+/// connection to the KeyMint device is reset, because a restarted KeyMint may no longer accept
+/// the ephemeral keys.
+pub fn forget(sec_level: SecurityLevel) {
+    EPHEMERAL_KEYS.forget(sec_level)
+}
+
+/// Returns an estimate of the memory held by the ephemeral key cache in bytes.
+pub fn cache_size_bytes() -> usize {
+    EPHEMERAL_KEYS.size_bytes()
+}
+
+/// Drops all cached ephemeral keys. Vold's next conversions go to KeyMint again. Returns the
+/// number of dropped keys.
+pub fn clear_cache() -> usize {
+    EPHEMERAL_KEYS.clear()
+}
+
+/// Writes the number of cached ephemeral keys to `f`.
+pub fn dump(f: &mut dyn Write) -> std::io::Result<()> {
+    writeln!(f, "Cached ephemeral storage keys: {}", EPHEMERAL_KEYS.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+
+    /// Stands in for a KeyMint device. Storage key blobs prefixed with `OLD` require an
+    /// upgrade, which replaces the prefix with `NEW`. The ephemeral key is the blob reversed.
+    #[derive(Default)]
+    struct MockDevice {
+        conversions: Cell<usize>,
+        upgrades: Cell<usize>,
+        error: RefCell<Option<ErrorCode>>,
+    }
+
+    const OLD: &[u8] = b"OLD";
+    const NEW: &[u8] = b"NEW";
+
+    impl StorageKeyDevice for MockDevice {
+        fn convert_storage_key(&self, storage_key_blob: &[u8]) -> Result<Vec<u8>, Error> {
+            self.conversions.set(self.conversions.get() + 1);
+            if let Some(ec) = *self.error.borrow() {
+                return Err(Error::Km(ec));
+            }
+            if storage_key_blob.starts_with(OLD) {
+                return Err(Error::Km(ErrorCode::KEY_REQUIRES_UPGRADE));
+            }
+            Ok(storage_key_blob.iter().rev().cloned().collect())
+        }
+
+        fn upgrade_storage_key(&self, storage_key_blob: &[u8]) -> Result<Vec<u8>, Error> {
+            self.upgrades.set(self.upgrades.get() + 1);
+            Ok([NEW, &storage_key_blob[OLD.len()..]].concat())
+        }
+    }
+
+    #[test]
+    fn convert_caches_ephemeral_keys() -> Result<()> {
+        let cache = EphemeralKeyCache::default();
+        let dev = MockDevice::default();
+        let tee = SecurityLevel::TRUSTED_ENVIRONMENT;
+
+        let response = cache.convert(&dev, tee, b"key")?;
+        assert_eq!(response.ephemeralKey, b"yek".to_vec());
+        assert_eq!(response.upgradedBlob, None);
+        assert_eq!(cache.convert(&dev, tee, b"key")?.ephemeralKey, b"yek".to_vec());
+        assert_eq!(dev.conversions.get(), 1);
+
+        // The cache is per security level.
+        cache.convert(&dev, SecurityLevel::STRONGBOX, b"key")?;
+        assert_eq!(dev.conversions.get(), 2);
+
+        cache.remove(tee, b"key");
+        cache.convert(&dev, tee, b"key")?;
+        assert_eq!(dev.conversions.get(), 3);
+
+        cache.forget(tee);
+        assert_eq!(cache.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn convert_upgrades_storage_keys() -> Result<()> {
+        let cache = EphemeralKeyCache::default();
+        let dev = MockDevice::default();
+        let tee = SecurityLevel::TRUSTED_ENVIRONMENT;
+
+        let response = cache.convert(&dev, tee, b"OLDkey")?;
+        assert_eq!(response.upgradedBlob, Some(b"NEWkey".to_vec()));
+        assert_eq!(response.ephemeralKey, b"yekWEN".to_vec());
+        assert_eq!(dev.upgrades.get(), 1);
+
+        // Only the upgraded blob is cached, so the upgrade is handed out again.
+        assert_eq!(cache.convert(&dev, tee, b"OLDkey")?.upgradedBlob, Some(b"NEWkey".to_vec()));
+        assert_eq!(dev.upgrades.get(), 2);
+        let conversions = dev.conversions.get();
+        assert_eq!(cache.convert(&dev, tee, b"NEWkey")?.ephemeralKey, b"yekWEN".to_vec());
+        assert_eq!(dev.conversions.get(), conversions);
+        Ok(())
+    }
+
+    #[test]
+    fn convert_does_not_cache_failures() {
+        let cache = EphemeralKeyCache::default();
+        let dev = MockDevice::default();
+        *dev.error.borrow_mut() = Some(ErrorCode::INVALID_KEY_BLOB);
+
+        let result = cache.convert(&dev, SecurityLevel::TRUSTED_ENVIRONMENT, b"key");
+        assert_eq!(
+            result.unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::INVALID_KEY_BLOB))
+        );
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn cache_evicts_oldest_entry() -> Result<()> {
+        let cache = EphemeralKeyCache::default();
+        let dev = MockDevice::default();
+        let tee = SecurityLevel::TRUSTED_ENVIRONMENT;
+
+        for i in 0..=MAX_CACHED_KEYS {
+            cache.convert(&dev, tee, &i.to_be_bytes())?;
+        }
+        assert_eq!(cache.len(), MAX_CACHED_KEYS);
+        cache.convert(&dev, tee, &0usize.to_be_bytes())?;
+        assert_eq!(dev.conversions.get(), MAX_CACHED_KEYS + 2);
+        Ok(())
+    }
+}
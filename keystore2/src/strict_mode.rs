@@ -0,0 +1,183 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements Keystore's strict mode for deprecated operation parameters, i.e.,
+//! SHA-1 signatures, PKCS#1 v1.5 encryption, and the ECB block mode. Apps that target
+//! `STRICT_MODE_MIN_TARGET_SDK` or newer cannot sign or encrypt with these parameters. Verifying
+//! and decrypting remain allowed, so that existing data stays readable. For all other callers
+//! and purposes the use is logged and reported as metric, but the operation proceeds.
+//!
+//! The target SDK is only looked up if an operation actually uses a deprecated parameter, so
+//! that the package manager is not queried on the critical path of ordinary operations. If it
+//! cannot be looked up, the caller is held to strict mode.
+
+use crate::caller_identity::CallerIdentity;
+use crate::error::{Error, ErrorCode};
+use crate::metrics_store::log_deprecated_parameter_stats;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, BlockMode::BlockMode, Digest::Digest, KeyParameter::KeyParameter,
+    KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose, PaddingMode::PaddingMode,
+};
+use android_security_metrics::aidl::android::security::metrics::DeprecatedParameter::DeprecatedParameter;
+use anyhow::{Context, Result};
+
+/// Apps targeting this SDK version or newer get their operations rejected if they use a
+/// deprecated parameter.
+pub const STRICT_MODE_MIN_TARGET_SDK: i32 = 36;
+
+/// Returns the deprecated parameters among `op_params` of an operation with the given purpose
+/// on a key with the given algorithm. The algorithm is unknown for `Domain::BLOB` keys, in
+/// which case SHA-1 digests are not reported, because they are fine for HMAC.
+pub fn find_deprecated_parameters(
+    algorithm: Option<Algorithm>,
+    purpose: KeyPurpose,
+    op_params: &[KeyParameter],
+) -> Vec<DeprecatedParameter> {
+    let asymmetric = matches!(algorithm, Some(Algorithm::RSA) | Some(Algorithm::EC));
+    let mut deprecated: Vec<DeprecatedParameter> = op_params
+        .iter()
+        .filter_map(|p| match p.value {
+            KeyParameterValue::Digest(Digest::SHA1)
+                if asymmetric && purpose == KeyPurpose::SIGN =>
+            {
+                Some(DeprecatedParameter::SHA1_SIGNATURE)
+            }
+            KeyParameterValue::PaddingMode(PaddingMode::RSA_PKCS1_1_5_ENCRYPT) => {
+                Some(DeprecatedParameter::PKCS1_V1_5_ENCRYPTION)
+            }
+            KeyParameterValue::BlockMode(BlockMode::ECB) => {
+                Some(DeprecatedParameter::ECB_BLOCK_MODE)
+            }
+            _ => None,
+        })
+        .collect();
+    deprecated.sort();
+    deprecated.dedup();
+    deprecated
+}
+
+fn rejection(parameter: DeprecatedParameter) -> Error {
+    match parameter {
+        DeprecatedParameter::SHA1_SIGNATURE => Error::Km(ErrorCode::INCOMPATIBLE_DIGEST),
+        DeprecatedParameter::PKCS1_V1_5_ENCRYPTION => {
+            Error::Km(ErrorCode::INCOMPATIBLE_PADDING_MODE)
+        }
+        DeprecatedParameter::ECB_BLOCK_MODE => Error::Km(ErrorCode::INCOMPATIBLE_BLOCK_MODE),
+        _ => Error::Km(ErrorCode::INVALID_ARGUMENT),
+    }
+}
+
+/// Strict mode rejects deprecated parameters only for purposes that produce new data.
+fn is_rejected_purpose(purpose: KeyPurpose) -> bool {
+    matches!(purpose, KeyPurpose::SIGN | KeyPurpose::ENCRYPT)
+}
+
+/// Returns true if a caller with the given target SDK lookup result is held to strict mode.
+fn is_strict(target_sdk: Result<Option<i32>>) -> bool {
+    match target_sdk {
+        Ok(target_sdk) => target_sdk.map_or(false, |v| v >= STRICT_MODE_MIN_TARGET_SDK),
+        Err(e) => {
+            log::warn!("In is_strict: Assuming strict mode: {:?}", e);
+            true
+        }
+    }
+}
+
+/// Applies the strict mode policy of the caller to the parameters of a new operation.
+/// Returns an error if the caller is subject to strict mode and uses a deprecated parameter.
+pub fn check_operation_parameters(
+    caller: &CallerIdentity,
+    algorithm: Option<Algorithm>,
+    purpose: KeyPurpose,
+    op_params: &[KeyParameter],
+) -> Result<()> {
+    let deprecated = find_deprecated_parameters(algorithm, purpose, op_params);
+    let first = match deprecated.first() {
+        Some(first) => *first,
+        None => return Ok(()),
+    };
+    // The target SDK is not looked up for purposes that are never rejected.
+    let strict = is_rejected_purpose(purpose) && is_strict(caller.target_sdk_version());
+    for parameter in &deprecated {
+        log_deprecated_parameter_stats(*parameter, strict);
+    }
+    if strict {
+        return Err(rejection(first)).with_context(|| {
+            format!("In check_operation_parameters: {:?} is not allowed in strict mode.", first)
+        });
+    }
+    log::warn!("uid {} began an operation with deprecated {:?}.", caller.uid(), deprecated);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::Tag::Tag;
+
+    fn param(tag: Tag, value: KeyParameterValue) -> KeyParameter {
+        KeyParameter { tag, value }
+    }
+
+    #[test]
+    fn find_deprecated_parameters_test() {
+        let sha1 = param(Tag::DIGEST, KeyParameterValue::Digest(Digest::SHA1));
+        let sha256 = param(Tag::DIGEST, KeyParameterValue::Digest(Digest::SHA_2_256));
+        let ecb = param(Tag::BLOCK_MODE, KeyParameterValue::BlockMode(BlockMode::ECB));
+        let pkcs1 =
+            param(Tag::PADDING, KeyParameterValue::PaddingMode(PaddingMode::RSA_PKCS1_1_5_ENCRYPT));
+
+        assert_eq!(
+            find_deprecated_parameters(Some(Algorithm::EC), KeyPurpose::SIGN, &[sha1.clone()]),
+            vec![DeprecatedParameter::SHA1_SIGNATURE]
+        );
+        assert!(
+            find_deprecated_parameters(Some(Algorithm::EC), KeyPurpose::SIGN, &[sha256]).is_empty()
+        );
+        // HMAC-SHA1 and keys of unknown algorithm are not reported.
+        assert!(find_deprecated_parameters(
+            Some(Algorithm::HMAC),
+            KeyPurpose::SIGN,
+            &[sha1.clone()]
+        )
+        .is_empty());
+        assert!(find_deprecated_parameters(None, KeyPurpose::SIGN, &[sha1]).is_empty());
+        assert_eq!(
+            find_deprecated_parameters(
+                Some(Algorithm::AES),
+                KeyPurpose::ENCRYPT,
+                &[ecb.clone(), ecb]
+            ),
+            vec![DeprecatedParameter::ECB_BLOCK_MODE]
+        );
+        assert_eq!(
+            find_deprecated_parameters(Some(Algorithm::RSA), KeyPurpose::DECRYPT, &[pkcs1]),
+            vec![DeprecatedParameter::PKCS1_V1_5_ENCRYPTION]
+        );
+    }
+
+    #[test]
+    fn strict_mode_policy_test() {
+        assert!(is_strict(Ok(Some(STRICT_MODE_MIN_TARGET_SDK))));
+        assert!(!is_strict(Ok(Some(STRICT_MODE_MIN_TARGET_SDK - 1))));
+        assert!(!is_strict(Ok(None)));
+        // A caller whose target SDK cannot be looked up is not exempted.
+        assert!(is_strict(Err(anyhow::anyhow!("The package manager is unavailable."))));
+
+        assert!(is_rejected_purpose(KeyPurpose::SIGN));
+        assert!(is_rejected_purpose(KeyPurpose::ENCRYPT));
+        assert!(!is_rejected_purpose(KeyPurpose::DECRYPT));
+        assert!(!is_rejected_purpose(KeyPurpose::VERIFY));
+    }
+}
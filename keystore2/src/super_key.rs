@@ -13,21 +13,27 @@
 // limitations under the License.
 
 use crate::{
+    blob_format::SuperEncryptionFormat,
     boot_level_keys::{get_level_zero_key, BootLevelKeyCache},
+    csprng::{generate_aes256_key, generate_salt},
     database::BlobMetaData,
     database::BlobMetaEntry,
+    database::DateTime,
     database::EncryptedBy,
     database::KeyEntry,
     database::KeyType,
+    database::SubComponentType,
     database::{KeyEntryLoadBits, KeyIdGuard, KeyMetaData, KeyMetaEntry, KeystoreDB},
     ec_crypto::ECDHPrivateKey,
     enforcements::Enforcements,
     error::Error,
     error::ResponseCode,
+    globals::TASK_EXECUTOR,
     key_parameter::{KeyParameter, KeyParameterValue},
     legacy_blob::LegacyBlobLoader,
     legacy_migrator::LegacyMigrator,
     raw_device::KeyMintDevice,
+    task_executor::{Priority, TaskContext},
     try_insert::TryInsert,
     utils::watchdog as wd,
     utils::AID_KEYSTORE,
@@ -42,10 +48,7 @@ use android_system_keystore2::aidl::android::system::keystore2::{
     Domain::Domain, KeyDescriptor::KeyDescriptor,
 };
 use anyhow::{Context, Result};
-use keystore2_crypto::{
-    aes_gcm_decrypt, aes_gcm_encrypt, generate_aes256_key, generate_salt, Password, ZVec,
-    AES_256_KEY_LENGTH,
-};
+use keystore2_crypto::{aes_gcm_decrypt, aes_gcm_encrypt, Password, ZVec, AES_256_KEY_LENGTH};
 use keystore2_system_property::PropertyWatcher;
 use std::{
     collections::HashMap,
@@ -292,17 +295,20 @@ impl SuperKeyManager {
         data.boot_level_key_cache = Some(BootLevelKeyCache::new(level_zero_key));
         log::info!("Starting boot level watcher.");
         let clone = self.clone();
-        std::thread::spawn(move || {
-            clone
-                .watch_boot_level()
-                .unwrap_or_else(|e| log::error!("watch_boot_level failed:\n{:?}", e));
-        });
+        TASK_EXECUTOR
+            .spawn_dedicated("keystore2_boot_level_watcher", Priority::Normal, move |context| {
+                clone
+                    .watch_boot_level(context)
+                    .unwrap_or_else(|e| log::error!("watch_boot_level failed:\n{:?}", e));
+            })
+            .context("In set_up_boot_level_cache: Failed to start boot level watcher.")?;
         Ok(())
     }
 
     /// Watch the `keystore.boot_level` system property, and keep boot level up to date.
-    /// Blocks waiting for system property changes, so must be run in its own thread.
-    fn watch_boot_level(&self) -> Result<()> {
+    /// Blocks waiting for system property changes, so must be run in its own task. A cancelled
+    /// task returns after the next property change.
+    fn watch_boot_level(&self, context: &TaskContext) -> Result<()> {
         let mut w = PropertyWatcher::new("keystore.boot_level")
             .context("In watch_boot_level: PropertyWatcher::new failed")?;
         loop {
@@ -330,6 +336,9 @@ impl SuperKeyManager {
                 break;
             }
             w.wait().context("In watch_boot_level: property wait failed")?;
+            if context.is_cancelled() {
+                break;
+            }
         }
         Ok(())
     }
@@ -348,6 +357,12 @@ impl SuperKeyManager {
         data.user_keys.remove(&user);
     }
 
+    /// Forgets all super keys of all users and the boot level key cache. This is used when
+    /// the global state of Keystore is torn down.
+    pub fn forget_all(&self) {
+        *self.data.lock().unwrap() = Default::default();
+    }
+
     fn install_per_boot_key_for_user(&self, user: UserId, super_key: Arc<SuperKey>) -> Result<()> {
         let mut data = self.data.lock().unwrap();
         data.add_key_to_key_index(&super_key)
@@ -454,6 +469,8 @@ impl SuperKeyManager {
 
     /// Unwraps an encrypted key blob given an encryption key.
     fn unwrap_key_with_key(blob: &[u8], metadata: &BlobMetaData, key: &SuperKey) -> Result<ZVec> {
+        SuperEncryptionFormat::from_metadata(metadata)
+            .context("In unwrap_key_with_key: Cannot read key blob.")?;
         match key.algorithm {
             SuperEncryptionAlgorithm::Aes256Gcm => match (metadata.iv(), metadata.aead_tag()) {
                 (Some(iv), Some(tag)) => key
@@ -615,35 +632,8 @@ impl SuperKeyManager {
         reencrypt_with: Option<Arc<SuperKey>>,
     ) -> Result<Arc<SuperKey>> {
         if let Some((blob, metadata)) = entry.key_blob_info() {
-            let key = match (
-                metadata.encrypted_by(),
-                metadata.salt(),
-                metadata.iv(),
-                metadata.aead_tag(),
-            ) {
-                (Some(&EncryptedBy::Password), Some(salt), Some(iv), Some(tag)) => {
-                    // Note that password encryption is AES no matter the value of algorithm
-                    let key = pw.derive_key(Some(salt), AES_256_KEY_LENGTH).context(
-                        "In extract_super_key_from_key_entry: Failed to generate key from password.",
-                    )?;
-
-                    aes_gcm_decrypt(blob, iv, tag, &key).context(
-                        "In extract_super_key_from_key_entry: Failed to decrypt key blob.",
-                    )?
-                }
-                (enc_by, salt, iv, tag) => {
-                    return Err(Error::Rc(ResponseCode::VALUE_CORRUPTED)).context(format!(
-                        concat!(
-                        "In extract_super_key_from_key_entry: Super key has incomplete metadata.",
-                        "encrypted_by: {:?}; Present: salt: {}, iv: {}, aead_tag: {}."
-                    ),
-                        enc_by,
-                        salt.is_some(),
-                        iv.is_some(),
-                        tag.is_some()
-                    ));
-                }
-            };
+            let key = Self::decrypt_with_password(blob, metadata, pw)
+                .context("In extract_super_key_from_key_entry.")?;
             Ok(Arc::new(SuperKey {
                 algorithm,
                 key,
@@ -656,6 +646,35 @@ impl SuperKeyManager {
         }
     }
 
+    /// Decrypts a super key blob that was encrypted with a key derived from the password.
+    fn decrypt_with_password(blob: &[u8], metadata: &BlobMetaData, pw: &Password) -> Result<ZVec> {
+        SuperEncryptionFormat::from_metadata(metadata)
+            .context("In decrypt_with_password: Cannot read super key.")?;
+        match (metadata.encrypted_by(), metadata.salt(), metadata.iv(), metadata.aead_tag()) {
+            (Some(&EncryptedBy::Password), Some(salt), Some(iv), Some(tag)) => {
+                // Note that password encryption is AES no matter the value of algorithm
+                let key = pw
+                    .derive_key(Some(salt), AES_256_KEY_LENGTH)
+                    .context("In decrypt_with_password: Failed to generate key from password.")?;
+
+                aes_gcm_decrypt(blob, iv, tag, &key)
+                    .context("In decrypt_with_password: Failed to decrypt key blob.")
+            }
+            (enc_by, salt, iv, tag) => {
+                Err(Error::Rc(ResponseCode::VALUE_CORRUPTED)).context(format!(
+                    concat!(
+                        "In decrypt_with_password: Super key has incomplete metadata.",
+                        "encrypted_by: {:?}; Present: salt: {}, iv: {}, aead_tag: {}."
+                    ),
+                    enc_by,
+                    salt.is_some(),
+                    iv.is_some(),
+                    tag.is_some()
+                ))
+            }
+        }
+    }
+
     /// Encrypts the super key from a key derived from the password, before storing in the database.
     pub fn encrypt_with_password(
         super_key: &[u8],
@@ -672,6 +691,7 @@ impl SuperKeyManager {
             .context("In encrypt_with_password: Failed to encrypt new super key.")?;
         metadata.add(BlobMetaEntry::Iv(iv));
         metadata.add(BlobMetaEntry::AeadTag(tag));
+        SuperEncryptionFormat::add_current_to_metadata(&mut metadata);
         Ok((encrypted_key, metadata))
     }
 
@@ -718,6 +738,7 @@ impl SuperKeyManager {
         metadata.add(BlobMetaEntry::Iv(iv));
         metadata.add(BlobMetaEntry::AeadTag(tag));
         super_key.id.add_to_metadata(&mut metadata);
+        SuperEncryptionFormat::add_current_to_metadata(&mut metadata);
         Ok((encrypted_key, metadata))
     }
 
@@ -775,6 +796,7 @@ impl SuperKeyManager {
                     metadata.add(BlobMetaEntry::AeadTag(aead_tag));
                     SuperKeyIdentifier::DatabaseId(key_id_guard.id())
                         .add_to_metadata(&mut metadata);
+                    SuperEncryptionFormat::add_current_to_metadata(&mut metadata);
                     Ok((encrypted_key, metadata))
                 }
             }
@@ -876,6 +898,90 @@ impl SuperKeyManager {
         }
     }
 
+    /// Defers the removal of the user's LSKF until `deadline`. The super key stays in memory and
+    /// on disk in the meantime, so that LSKF bound keys remain usable. This is only possible
+    /// while the user is unlocked. Returns false if the removal cannot be deferred, in which
+    /// case the caller must remove the LSKF immediately.
+    pub fn defer_lskf_removal(
+        &self,
+        db: &mut KeystoreDB,
+        user_id: UserId,
+        deadline: DateTime,
+    ) -> Result<bool> {
+        if self.get_per_boot_key_by_user_id(user_id).is_none() {
+            return Ok(false);
+        }
+        db.set_lskf_removal_deadline(user_id, Some(deadline))
+            .context("In defer_lskf_removal: Failed to set removal deadline.")
+    }
+
+    /// Cancels a deferred LSKF removal, because the user set a new LSKF before the deadline.
+    /// The super key is re-encrypted with the new password. Returns false if no removal was
+    /// pending for the user.
+    pub fn cancel_lskf_removal(
+        &self,
+        db: &mut KeystoreDB,
+        user_id: UserId,
+        pw: &Password,
+    ) -> Result<bool> {
+        let key_id_guard = match db
+            .load_super_key(&USER_SUPER_KEY, user_id)
+            .context("In cancel_lskf_removal: Failed to load super key.")?
+        {
+            Some((key_id_guard, entry)) if entry.metadata().lskf_removal_deadline().is_some() => {
+                key_id_guard
+            }
+            _ => return Ok(false),
+        };
+        let super_key = self
+            .get_per_boot_key_by_user_id(user_id)
+            .ok_or(Error::Rc(ResponseCode::LOCKED))
+            .context("In cancel_lskf_removal: Super key is not in memory.")?;
+        let (encrypted_super_key, blob_metadata) =
+            Self::encrypt_with_password(&super_key.key, pw).context("In cancel_lskf_removal.")?;
+        db.set_blob(
+            &key_id_guard,
+            SubComponentType::KEY_BLOB,
+            Some(&encrypted_super_key),
+            Some(&blob_metadata),
+        )
+        .context("In cancel_lskf_removal: Failed to store re-encrypted super key.")?;
+        db.set_lskf_removal_deadline(user_id, None)
+            .context("In cancel_lskf_removal: Failed to clear removal deadline.")
+    }
+
+    /// Completes a deferred LSKF removal if its deadline has passed, or if the super key is
+    /// no longer in memory. The latter happens if the device rebooted during the grace period,
+    /// because the LSKF bound keys can never be unlocked again. Returns true if the LSKF bound
+    /// keys were deleted.
+    pub fn complete_lskf_removal_if_due(
+        &self,
+        db: &mut KeystoreDB,
+        legacy_migrator: &LegacyMigrator,
+        user_id: UserId,
+    ) -> Result<bool> {
+        let deadline = db
+            .load_super_key(&USER_SUPER_KEY, user_id)
+            .context("In complete_lskf_removal_if_due: Failed to load super key.")?
+            .and_then(|(_, entry)| entry.metadata().lskf_removal_deadline().cloned());
+        let due = match deadline {
+            None => false,
+            Some(_) if self.get_per_boot_key_by_user_id(user_id).is_none() => true,
+            Some(deadline) => {
+                deadline
+                    <= DateTime::now().context(
+                        "In complete_lskf_removal_if_due: Failed to get the current time.",
+                    )?
+            }
+        };
+        if due {
+            log::info!("Completing deferred LSKF removal for user {}.", user_id);
+            UserState::reset_user(db, self, legacy_migrator, user_id, true)
+                .context("In complete_lskf_removal_if_due: Trying to delete keys from the db.")?;
+        }
+        Ok(due)
+    }
+
     /// Decrypt the screen-lock bound keys for this user using the password and store in memory.
     pub fn unlock_screen_lock_bound_key(
         &self,
@@ -1169,8 +1275,10 @@ impl UserState {
         legacy_migrator
             .bulk_delete_user(user_id, keep_non_super_encrypted_keys)
             .context("In reset_user: Trying to delete legacy keys.")?;
-        db.unbind_keys_for_user(user_id, keep_non_super_encrypted_keys)
+        let deleted = db
+            .unbind_keys_for_user(user_id, keep_non_super_encrypted_keys)
             .context("In reset user. Error in unbinding keys.")?;
+        log::info!("Deleted {} keys of user {}.", deleted, user_id);
 
         //delete super key in cache, if exists
         skm.forget_all_keys_for_user(user_id);
@@ -1221,3 +1329,124 @@ impl<'a> Deref for KeyBlob<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod blob_format_test_vectors;
+    use blob_format_test_vectors::*;
+
+    fn password_metadata(salt: &[u8], iv: &[u8], tag: &[u8]) -> BlobMetaData {
+        let mut metadata = BlobMetaData::new();
+        metadata.add(BlobMetaEntry::EncryptedBy(EncryptedBy::Password));
+        metadata.add(BlobMetaEntry::Salt(salt.to_vec()));
+        metadata.add(BlobMetaEntry::Iv(iv.to_vec()));
+        metadata.add(BlobMetaEntry::AeadTag(tag.to_vec()));
+        metadata
+    }
+
+    fn super_encrypted_metadata(iv: &[u8], tag: &[u8]) -> BlobMetaData {
+        let mut metadata = BlobMetaData::new();
+        metadata.add(BlobMetaEntry::Iv(iv.to_vec()));
+        metadata.add(BlobMetaEntry::AeadTag(tag.to_vec()));
+        metadata.add(BlobMetaEntry::EncryptedBy(EncryptedBy::KeyId(1)));
+        metadata
+    }
+
+    fn golden_super_key(key: ZVec) -> SuperKey {
+        SuperKey {
+            algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
+            key,
+            id: SuperKeyIdentifier::DatabaseId(1),
+            reencrypt_with: None,
+        }
+    }
+
+    #[test]
+    fn test_read_unversioned_golden_fixtures() -> Result<()> {
+        let pw: Password = PASSWORD.into();
+        let metadata = password_metadata(
+            UNVERSIONED_SUPER_KEY_SALT,
+            UNVERSIONED_SUPER_KEY_IV,
+            UNVERSIONED_SUPER_KEY_AEAD_TAG,
+        );
+        let super_key = SuperKeyManager::decrypt_with_password(
+            UNVERSIONED_ENCRYPTED_SUPER_KEY,
+            &metadata,
+            &pw,
+        )?;
+        assert_eq!(&super_key[..], SUPER_KEY);
+
+        let metadata =
+            super_encrypted_metadata(UNVERSIONED_KEY_BLOB_IV, UNVERSIONED_KEY_BLOB_AEAD_TAG);
+        let key_blob = SuperKeyManager::unwrap_key_with_key(
+            UNVERSIONED_ENCRYPTED_KEY_BLOB,
+            &metadata,
+            &golden_super_key(super_key),
+        )?;
+        assert_eq!(&key_blob[..], KEY_BLOB);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_v1_golden_fixtures() -> Result<()> {
+        let pw: Password = PASSWORD.into();
+        let mut metadata =
+            password_metadata(V1_SUPER_KEY_SALT, V1_SUPER_KEY_IV, V1_SUPER_KEY_AEAD_TAG);
+        metadata.add(BlobMetaEntry::FormatVersion(1));
+        let super_key =
+            SuperKeyManager::decrypt_with_password(V1_ENCRYPTED_SUPER_KEY, &metadata, &pw)?;
+        assert_eq!(&super_key[..], SUPER_KEY);
+
+        let mut metadata = super_encrypted_metadata(V1_KEY_BLOB_IV, V1_KEY_BLOB_AEAD_TAG);
+        metadata.add(BlobMetaEntry::FormatVersion(1));
+        let key_blob = SuperKeyManager::unwrap_key_with_key(
+            V1_ENCRYPTED_KEY_BLOB,
+            &metadata,
+            &golden_super_key(super_key),
+        )?;
+        assert_eq!(&key_blob[..], KEY_BLOB);
+        Ok(())
+    }
+
+    #[test]
+    fn test_refuse_blobs_from_newer_release() -> Result<()> {
+        // A V1 blob that claims to be written in a format that this release does not know
+        // must be refused even though it would decrypt.
+        let pw: Password = PASSWORD.into();
+        let mut metadata =
+            password_metadata(V1_SUPER_KEY_SALT, V1_SUPER_KEY_IV, V1_SUPER_KEY_AEAD_TAG);
+        metadata.add(BlobMetaEntry::FormatVersion(2));
+        assert_eq!(
+            Some(&Error::Rc(ResponseCode::SYSTEM_ERROR)),
+            SuperKeyManager::decrypt_with_password(V1_ENCRYPTED_SUPER_KEY, &metadata, &pw)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>()
+        );
+
+        let mut metadata = super_encrypted_metadata(V1_KEY_BLOB_IV, V1_KEY_BLOB_AEAD_TAG);
+        metadata.add(BlobMetaEntry::FormatVersion(2));
+        let super_key = golden_super_key(ZVec::try_from(SUPER_KEY)?);
+        assert_eq!(
+            Some(&Error::Rc(ResponseCode::SYSTEM_ERROR)),
+            SuperKeyManager::unwrap_key_with_key(V1_ENCRYPTED_KEY_BLOB, &metadata, &super_key)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_current_format() -> Result<()> {
+        let super_key = golden_super_key(ZVec::try_from(SUPER_KEY)?);
+        let (encrypted, metadata) =
+            SuperKeyManager::encrypt_with_aes_super_key(KEY_BLOB, &super_key)?;
+        assert_eq!(metadata.format_version(), SuperEncryptionFormat::CURRENT.version().as_ref());
+        let key_blob = SuperKeyManager::unwrap_key_with_key(&encrypted, &metadata, &super_key)?;
+        assert_eq!(&key_blob[..], KEY_BLOB);
+        Ok(())
+    }
+}
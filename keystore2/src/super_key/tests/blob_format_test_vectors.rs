@@ -0,0 +1,109 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Golden fixtures of super encrypted blobs as written by past releases of Keystore. These
+// must never be regenerated; a fixture that stops decrypting means that blobs on devices
+// upgrading from that release would become unreadable. Add a new set of fixtures whenever
+// a new `SuperEncryptionFormat` is introduced.
+//
+// UNVERSIONED_*: Written before the format version was recorded in the blob metadata.
+// V1_*: Written with `SuperEncryptionFormat::V1`.
+
+/// The LSKF that protects the super keys of all fixtures.
+pub static PASSWORD: &[u8] = &[
+    0x6b, 0x65, 0x79, 0x73, 0x74, 0x6f, 0x72, 0x65, 0x20, 0x67, 0x6f, 0x6c, 0x64, 0x65, 0x6e, 0x20,
+    0x66, 0x69, 0x78, 0x74, 0x75, 0x72, 0x65,
+];
+
+/// The plaintext AES-256 super key of all fixtures.
+pub static SUPER_KEY: &[u8] = &[
+    0x03, 0x0a, 0x11, 0x18, 0x1f, 0x26, 0x2d, 0x34, 0x3b, 0x42, 0x49, 0x50, 0x57, 0x5e, 0x65, 0x6c,
+    0x73, 0x7a, 0x81, 0x88, 0x8f, 0x96, 0x9d, 0xa4, 0xab, 0xb2, 0xb9, 0xc0, 0xc7, 0xce, 0xd5, 0xdc,
+];
+
+/// The plaintext KeyMint blob that is super encrypted in all fixtures.
+pub static KEY_BLOB: &[u8] = &[
+    0x67, 0x6f, 0x6c, 0x64, 0x65, 0x6e, 0x20, 0x4b, 0x65, 0x79, 0x4d, 0x69, 0x6e, 0x74, 0x20, 0x6b,
+    0x65, 0x79, 0x20, 0x62, 0x6c, 0x6f, 0x62,
+];
+
+/// Salt of the password wrapped super key.
+pub static UNVERSIONED_SUPER_KEY_SALT: &[u8] = &[
+    0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f,
+];
+
+/// IV of the password wrapped super key.
+pub static UNVERSIONED_SUPER_KEY_IV: &[u8] =
+    &[0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x2b];
+
+/// AEAD tag of the password wrapped super key.
+pub static UNVERSIONED_SUPER_KEY_AEAD_TAG: &[u8] = &[
+    0x57, 0x79, 0x3e, 0xbc, 0x68, 0x72, 0x4b, 0x04, 0x7b, 0x7b, 0x4d, 0xc2, 0x38, 0x08, 0x10, 0xe2,
+];
+
+/// The password wrapped super key.
+pub static UNVERSIONED_ENCRYPTED_SUPER_KEY: &[u8] = &[
+    0x64, 0x3c, 0x26, 0x26, 0x0e, 0xc9, 0x95, 0xab, 0x95, 0xfe, 0xde, 0xec, 0xa2, 0xc9, 0xde, 0xfe,
+    0xe9, 0x48, 0x06, 0x9c, 0xbf, 0x87, 0x7a, 0xec, 0xfd, 0xc9, 0x67, 0x77, 0x05, 0xe8, 0x96, 0xbd,
+];
+
+/// IV of the super encrypted key blob.
+pub static UNVERSIONED_KEY_BLOB_IV: &[u8] =
+    &[0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x3b];
+
+/// AEAD tag of the super encrypted key blob.
+pub static UNVERSIONED_KEY_BLOB_AEAD_TAG: &[u8] = &[
+    0x75, 0xa9, 0xbc, 0x23, 0x29, 0xe5, 0x8a, 0x8b, 0x3e, 0x5c, 0x04, 0xb2, 0x70, 0xb7, 0x8b, 0x32,
+];
+
+/// The super encrypted key blob.
+pub static UNVERSIONED_ENCRYPTED_KEY_BLOB: &[u8] = &[
+    0x50, 0xe3, 0xec, 0x88, 0x0f, 0x89, 0x59, 0x6f, 0x56, 0x4a, 0xa5, 0xd1, 0xee, 0x2c, 0x15, 0x84,
+    0x65, 0xe3, 0x3f, 0x49, 0x88, 0xf2, 0x56,
+];
+
+/// Salt of the password wrapped super key.
+pub static V1_SUPER_KEY_SALT: &[u8] = &[
+    0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x4b, 0x4c, 0x4d, 0x4e, 0x4f,
+];
+
+/// IV of the password wrapped super key.
+pub static V1_SUPER_KEY_IV: &[u8] =
+    &[0x50, 0x51, 0x52, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x5b];
+
+/// AEAD tag of the password wrapped super key.
+pub static V1_SUPER_KEY_AEAD_TAG: &[u8] = &[
+    0xc4, 0xbd, 0x97, 0xd0, 0x0d, 0x79, 0x65, 0x98, 0x2a, 0x8e, 0x30, 0x14, 0x4b, 0xe2, 0xf9, 0x85,
+];
+
+/// The password wrapped super key.
+pub static V1_ENCRYPTED_SUPER_KEY: &[u8] = &[
+    0x73, 0x64, 0x23, 0xb9, 0x16, 0xd2, 0x69, 0x91, 0x36, 0x07, 0xb1, 0x7d, 0x2f, 0xdf, 0x22, 0x3d,
+    0x70, 0x9d, 0xb4, 0xea, 0x42, 0x61, 0x97, 0x3f, 0x1c, 0x7a, 0x5b, 0xd0, 0x18, 0xce, 0x7d, 0x3f,
+];
+
+/// IV of the super encrypted key blob.
+pub static V1_KEY_BLOB_IV: &[u8] =
+    &[0x60, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x6b];
+
+/// AEAD tag of the super encrypted key blob.
+pub static V1_KEY_BLOB_AEAD_TAG: &[u8] = &[
+    0x34, 0x64, 0xf9, 0x66, 0x3c, 0x5c, 0xd1, 0x03, 0x50, 0x71, 0x60, 0x66, 0xe2, 0xf7, 0x1e, 0x7a,
+];
+
+/// The super encrypted key blob.
+pub static V1_ENCRYPTED_KEY_BLOB: &[u8] = &[
+    0x60, 0x71, 0x33, 0x4a, 0x1d, 0x13, 0x28, 0x94, 0xac, 0x60, 0x25, 0x56, 0x5d, 0x91, 0x4a, 0xa0,
+    0x28, 0xfb, 0x7f, 0x7c, 0x52, 0x33, 0xca,
+];
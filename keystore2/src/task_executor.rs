@@ -0,0 +1,644 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the task executor, which runs Keystore's background work, e.g.,
+//! periodic sweeps, delayed maintenance, and watchers for late HAL instances. Each task has a
+//! name. Short tasks run on a small pool of worker threads per priority, so that the number of
+//! threads does not grow with the number of tasks. Delayed and periodic tasks wait in a timer
+//! queue and do not occupy a worker while they wait. The queue of each pool is bounded, and
+//! spawning fails if it is full. Tasks that block for a long time, e.g., on a system property,
+//! must be spawned with `spawn_dedicated`, which runs them on a thread of their own that carries
+//! the name of the task.
+//!
+//! Tasks are cancelled cooperatively: A task learns about its cancellation from its
+//! `TaskContext`, whose `sleep` returns early and whose `is_cancelled` returns true once the
+//! task or the executor was shut down. A task that is cancelled before it started does not run.
+//! Background priority tasks run with a lower scheduling priority.
+//! The executor keeps timing statistics per task name, which are part of the maintenance
+//! dump. The busy time of a task excludes the time it spends sleeping in `TaskContext::sleep`.
+//!
+//! Note that work on the database should still be queued on the async task, so that it is
+//! serialized with the garbage collector.
+
+use anyhow::{anyhow, Context, Result};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::Write;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Niceness of background priority task threads.
+const BACKGROUND_NICENESS: libc::c_int = 10;
+
+/// Number of worker threads that run normal priority tasks.
+const NORMAL_WORKERS: usize = 4;
+
+/// Number of worker threads that run background priority tasks.
+const BACKGROUND_WORKERS: usize = 2;
+
+/// Maximal number of tasks that may wait in the queue of a pool, including delayed and
+/// periodic tasks that are not due yet.
+const MAX_QUEUED_TASKS: usize = 64;
+
+/// The scheduling priority of a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// The task runs with Keystore's priority.
+    Normal,
+    /// The task yields to Keystore's request handling.
+    Background,
+}
+
+/// Timing statistics of all tasks with the same name.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TaskStats {
+    /// Number of tasks started.
+    pub started: u64,
+    /// Number of tasks that returned, including cancelled tasks.
+    pub finished: u64,
+    /// Number of tasks that returned after they were cancelled.
+    pub cancelled: u64,
+    /// Accumulated busy time of all finished tasks.
+    pub busy: Duration,
+    /// Longest busy time of a single finished task.
+    pub longest_busy: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Cancellation {
+    cancelled: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl Cancellation {
+    fn cancel(&self) {
+        *self.cancelled.lock().unwrap() = true;
+        self.condvar.notify_all();
+    }
+
+    fn is_cancelled(&self) -> bool {
+        *self.cancelled.lock().unwrap()
+    }
+}
+
+/// Handed to each task. It tells the task about its cancellation.
+pub struct TaskContext {
+    cancellation: Arc<Cancellation>,
+    slept: Cell<Duration>,
+}
+
+impl TaskContext {
+    /// Returns true if the task was cancelled. Long running tasks should check this regularly
+    /// and return if it is set.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    /// Sleeps for `duration` unless the task is cancelled in the meantime. Returns false if the
+    /// task was cancelled, and true if it slept for the full duration.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let start = Instant::now();
+        let cancelled = self.cancellation.cancelled.lock().unwrap();
+        let (cancelled, _) = self
+            .cancellation
+            .condvar
+            .wait_timeout_while(cancelled, duration, |cancelled| !*cancelled)
+            .unwrap();
+        self.slept.set(self.slept.get() + start.elapsed());
+        !*cancelled
+    }
+}
+
+/// Allows cancelling a single task.
+#[derive(Clone)]
+pub struct TaskHandle {
+    id: u64,
+    cancellation: Arc<Cancellation>,
+    state: Arc<(Condvar, Mutex<ExecutorState>)>,
+}
+
+impl TaskHandle {
+    /// Cancels the task. A task that waits in a queue is removed right away. Otherwise, this
+    /// does not wait for the task to return.
+    pub fn cancel(&self) {
+        self.cancellation.cancel();
+        let (ref condvar, ref state) = *self.state;
+        let mut state = state.lock().unwrap();
+        let queued = match state.normal.take(self.id) {
+            Some(task) => Some(task),
+            None => state.background.take(self.id),
+        };
+        if let Some(task) = queued {
+            state.finish(task.id, task.name, true);
+            drop(state);
+            condvar.notify_all();
+        }
+    }
+}
+
+enum Work {
+    Once(Box<dyn FnOnce(&TaskContext) + Send>),
+    Periodic(Duration, Box<dyn FnMut() + Send>),
+}
+
+struct Task {
+    id: u64,
+    name: &'static str,
+    cancellation: Arc<Cancellation>,
+    work: Work,
+}
+
+/// The tasks of one priority and the worker threads that run them.
+#[derive(Default)]
+struct Pool {
+    ready: VecDeque<Task>,
+    // Tasks that are not due yet, ordered by their deadline and id.
+    timers: BTreeMap<(Instant, u64), Task>,
+    workers: usize,
+    idle: usize,
+}
+
+impl Pool {
+    fn queued(&self) -> usize {
+        self.ready.len() + self.timers.len()
+    }
+
+    /// Moves the due timers to the ready queue and returns the next ready task. If no task is
+    /// ready, returns the deadline of the next timer, if any.
+    fn next_task(&mut self, now: Instant) -> std::result::Result<Task, Option<Instant>> {
+        while let Some(&key) = self.timers.keys().next() {
+            if key.0 > now {
+                break;
+            }
+            if let Some(task) = self.timers.remove(&key) {
+                self.ready.push_back(task);
+            }
+        }
+        self.ready
+            .pop_front()
+            .ok_or_else(|| self.timers.keys().next().map(|(deadline, _)| *deadline))
+    }
+
+    fn take(&mut self, id: u64) -> Option<Task> {
+        if let Some(index) = self.ready.iter().position(|task| task.id == id) {
+            return self.ready.remove(index);
+        }
+        let key = self.timers.keys().find(|(_, task_id)| *task_id == id).copied()?;
+        self.timers.remove(&key)
+    }
+
+    fn drain(&mut self) -> Vec<Task> {
+        self.ready.drain(..).chain(std::mem::take(&mut self.timers).into_values()).collect()
+    }
+}
+
+#[derive(Default)]
+struct ExecutorState {
+    next_id: u64,
+    // All tasks that did not finish yet, including queued tasks.
+    running: HashMap<u64, (&'static str, Arc<Cancellation>)>,
+    stats: BTreeMap<&'static str, TaskStats>,
+    normal: Pool,
+    background: Pool,
+}
+
+impl ExecutorState {
+    fn pool_mut(&mut self, priority: Priority) -> &mut Pool {
+        match priority {
+            Priority::Normal => &mut self.normal,
+            Priority::Background => &mut self.background,
+        }
+    }
+
+    fn add(&mut self, name: &'static str) -> (u64, Arc<Cancellation>) {
+        let cancellation: Arc<Cancellation> = Default::default();
+        let id = self.next_id;
+        self.next_id += 1;
+        self.running.insert(id, (name, cancellation.clone()));
+        self.stats.entry(name).or_default().started += 1;
+        (id, cancellation)
+    }
+
+    fn account_busy(&mut self, name: &'static str, busy: Duration) {
+        let stats = self.stats.entry(name).or_default();
+        stats.busy += busy;
+        stats.longest_busy = stats.longest_busy.max(busy);
+    }
+
+    fn finish(&mut self, id: u64, name: &'static str, cancelled: bool) {
+        self.running.remove(&id);
+        let stats = self.stats.entry(name).or_default();
+        stats.finished += 1;
+        if cancelled {
+            stats.cancelled += 1;
+        }
+    }
+}
+
+/// Runs named background tasks. See the module documentation.
+#[derive(Default)]
+pub struct TaskExecutor {
+    state: Arc<(Condvar, Mutex<ExecutorState>)>,
+}
+
+impl TaskExecutor {
+    /// Queues the task `f` on the worker pool of the given priority. `f` must not block for a
+    /// long time, because it occupies one of the few workers. Fails if the queue is full.
+    pub fn spawn<F>(&self, name: &'static str, priority: Priority, f: F) -> Result<TaskHandle>
+    where
+        F: FnOnce(&TaskContext) + Send + 'static,
+    {
+        self.queue(name, priority, None, Work::Once(Box::new(f))).context("In TaskExecutor::spawn.")
+    }
+
+    /// Queues a task named `name` that calls `f` every `interval` until it is cancelled.
+    pub fn spawn_periodic<F>(
+        &self,
+        name: &'static str,
+        priority: Priority,
+        interval: Duration,
+        f: F,
+    ) -> Result<TaskHandle>
+    where
+        F: FnMut() + Send + 'static,
+    {
+        self.queue(name, priority, Some(interval), Work::Periodic(interval, Box::new(f)))
+            .context("In TaskExecutor::spawn_periodic.")
+    }
+
+    /// Queues a task named `name` that calls `f` after `delay` unless it was cancelled before.
+    pub fn spawn_delayed<F>(
+        &self,
+        name: &'static str,
+        priority: Priority,
+        delay: Duration,
+        f: F,
+    ) -> Result<TaskHandle>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.queue(name, priority, Some(delay), Work::Once(Box::new(move |_| f())))
+            .context("In TaskExecutor::spawn_delayed.")
+    }
+
+    /// Spawns the task `f` on a new thread named `name`. This is meant for the few tasks that
+    /// block for a long time or for the lifetime of Keystore.
+    pub fn spawn_dedicated<F>(
+        &self,
+        name: &'static str,
+        priority: Priority,
+        f: F,
+    ) -> Result<TaskHandle>
+    where
+        F: FnOnce(&TaskContext) + Send + 'static,
+    {
+        let (id, cancellation) = {
+            let (_, ref state) = *self.state;
+            state.lock().unwrap().add(name)
+        };
+
+        let context = TaskContext { cancellation: cancellation.clone(), slept: Default::default() };
+        let cloned_state = self.state.clone();
+        std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                if priority == Priority::Background {
+                    Self::lower_thread_priority(name);
+                }
+                let start = Instant::now();
+                // The bookkeeping below must also happen if the task panics, so that
+                // `shutdown` does not wait for it.
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(&context)));
+                let busy = start.elapsed().saturating_sub(context.slept.get());
+
+                let (ref condvar, ref state) = *cloned_state;
+                let mut state = state.lock().unwrap();
+                state.account_busy(name, busy);
+                state.finish(id, name, context.is_cancelled());
+                drop(state);
+                condvar.notify_all();
+                if let Err(panic) = result {
+                    std::panic::resume_unwind(panic);
+                }
+            })
+            .map_err(|e| {
+                let (_, ref state) = *self.state;
+                let mut state = state.lock().unwrap();
+                state.running.remove(&id);
+                state.stats.entry(name).or_default().started -= 1;
+                e
+            })
+            .with_context(|| {
+                format!("In TaskExecutor::spawn_dedicated: Failed to spawn {}.", name)
+            })?;
+        Ok(TaskHandle { id, cancellation, state: self.state.clone() })
+    }
+
+    fn queue(
+        &self,
+        name: &'static str,
+        priority: Priority,
+        delay: Option<Duration>,
+        work: Work,
+    ) -> Result<TaskHandle> {
+        let (ref condvar, ref state) = *self.state;
+        let mut state = state.lock().unwrap();
+        if state.pool_mut(priority).queued() >= MAX_QUEUED_TASKS {
+            return Err(anyhow!("Too many queued tasks. Not queueing {}.", name));
+        }
+        let (id, cancellation) = state.add(name);
+        let task = Task { id, name, cancellation: cancellation.clone(), work };
+        let pool = state.pool_mut(priority);
+        match delay {
+            Some(delay) => {
+                pool.timers.insert((Instant::now() + delay, id), task);
+            }
+            None => pool.ready.push_back(task),
+        }
+        let max_workers = match priority {
+            Priority::Normal => NORMAL_WORKERS,
+            Priority::Background => BACKGROUND_WORKERS,
+        };
+        if pool.idle == 0 && pool.workers < max_workers {
+            if let Err(e) = self.start_worker(priority) {
+                // The task still runs if there is a worker already.
+                if pool.workers == 0 {
+                    pool.take(id);
+                    state.running.remove(&id);
+                    state.stats.entry(name).or_default().started -= 1;
+                    return Err(e);
+                }
+                log::warn!("In TaskExecutor::queue: {:?}", e);
+            } else {
+                pool.workers += 1;
+            }
+        }
+        drop(state);
+        condvar.notify_all();
+        Ok(TaskHandle { id, cancellation, state: self.state.clone() })
+    }
+
+    fn start_worker(&self, priority: Priority) -> Result<()> {
+        let name = match priority {
+            Priority::Normal => "keystore2_tasks",
+            Priority::Background => "keystore2_bg_tasks",
+        };
+        let state = self.state.clone();
+        std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                if priority == Priority::Background {
+                    Self::lower_thread_priority(name);
+                }
+                Self::run_worker(&state, priority);
+            })
+            .map(|_| ())
+            .with_context(|| format!("Failed to start worker {}.", name))
+    }
+
+    fn run_worker(shared: &(Condvar, Mutex<ExecutorState>), priority: Priority) {
+        let (ref condvar, ref state) = *shared;
+        let mut state = state.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let pool = state.pool_mut(priority);
+            let task = match pool.next_task(now) {
+                Ok(task) => task,
+                Err(deadline) => {
+                    pool.idle += 1;
+                    state = match deadline {
+                        Some(deadline) => {
+                            condvar
+                                .wait_timeout(state, deadline.saturating_duration_since(now))
+                                .unwrap()
+                                .0
+                        }
+                        None => condvar.wait(state).unwrap(),
+                    };
+                    state.pool_mut(priority).idle -= 1;
+                    continue;
+                }
+            };
+            drop(state);
+
+            let Task { id, name, cancellation, work } = task;
+            let context = TaskContext { cancellation, slept: Default::default() };
+            let start = Instant::now();
+            // The bookkeeping below must also happen if the task panics, so that `shutdown`
+            // does not wait for it.
+            let (result, work) = match work {
+                Work::Once(f) => {
+                    (std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(&context))), None)
+                }
+                Work::Periodic(interval, mut f) => (
+                    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f())),
+                    Some((interval, f)),
+                ),
+            };
+            let busy = start.elapsed().saturating_sub(context.slept.get());
+
+            state = shared.1.lock().unwrap();
+            state.account_busy(name, busy);
+            match work {
+                Some((interval, f)) if result.is_ok() && !context.is_cancelled() => {
+                    let task = Task {
+                        id,
+                        name,
+                        cancellation: context.cancellation,
+                        work: Work::Periodic(interval, f),
+                    };
+                    state.pool_mut(priority).timers.insert((Instant::now() + interval, id), task);
+                }
+                _ => {
+                    state.finish(id, name, context.is_cancelled());
+                    condvar.notify_all();
+                }
+            }
+            if let Err(panic) = result {
+                state.pool_mut(priority).workers -= 1;
+                drop(state);
+                std::panic::resume_unwind(panic);
+            }
+        }
+    }
+
+    /// Cancels all tasks and waits up to `timeout` for the running ones to return. Queued tasks
+    /// are dropped without running. Returns the names of the tasks that are still running after
+    /// the timeout. Tasks spawned afterwards run normally.
+    pub fn shutdown(&self, timeout: Duration) -> Vec<&'static str> {
+        let (ref condvar, ref state) = *self.state;
+        let mut state = state.lock().unwrap();
+        for (_, cancellation) in state.running.values() {
+            cancellation.cancel();
+        }
+        let mut queued = state.normal.drain();
+        queued.extend(state.background.drain());
+        for task in queued {
+            state.finish(task.id, task.name, true);
+        }
+        let (state, _) =
+            condvar.wait_timeout_while(state, timeout, |state| !state.running.is_empty()).unwrap();
+        let mut remaining: Vec<&'static str> =
+            state.running.values().map(|(name, _)| *name).collect();
+        remaining.sort_unstable();
+        for name in &remaining {
+            log::warn!("In TaskExecutor::shutdown: Task {} did not return in time.", name);
+        }
+        remaining
+    }
+
+    /// Returns the timing statistics of all tasks that were ever spawned, indexed by name.
+    pub fn stats(&self) -> BTreeMap<&'static str, TaskStats> {
+        let (_, ref state) = *self.state;
+        state.lock().unwrap().stats.clone()
+    }
+
+    /// Writes the timing statistics of all tasks to `f`.
+    pub fn dump(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(f, "Background tasks:")?;
+        for (name, stats) in self.stats() {
+            writeln!(
+                f,
+                "  {}: started: {} finished: {} cancelled: {} busy: {:?} longest: {:?}",
+                name,
+                stats.started,
+                stats.finished,
+                stats.cancelled,
+                stats.busy,
+                stats.longest_busy
+            )?;
+        }
+        Ok(())
+    }
+
+    fn lower_thread_priority(name: &str) {
+        // Safety: setpriority has no memory safety requirements. On Linux, PRIO_PROCESS with
+        // who = 0 applies to the calling thread only.
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, BACKGROUND_NICENESS) } != 0 {
+            log::warn!(
+                "In TaskExecutor: Failed to lower priority of {}: {:?}",
+                name,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_spawn_and_stats() -> Result<()> {
+        let executor: TaskExecutor = Default::default();
+        let (sender, receiver) = channel();
+        executor.spawn("test_task", Priority::Background, move |_| sender.send(42).unwrap())?;
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(42));
+        assert!(executor.shutdown(Duration::from_secs(5)).is_empty());
+
+        let stats = executor.stats();
+        assert_eq!(stats["test_task"].started, 1);
+        assert_eq!(stats["test_task"].finished, 1);
+        assert_eq!(stats["test_task"].cancelled, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancellation() -> Result<()> {
+        let executor: TaskExecutor = Default::default();
+        let (sender, receiver) = channel();
+        let periodic_sender = sender.clone();
+        executor.spawn_periodic(
+            "test_periodic",
+            Priority::Normal,
+            Duration::from_millis(10),
+            move || periodic_sender.send("tick").unwrap_or(()),
+        )?;
+        let handle = executor.spawn_delayed(
+            "test_delayed",
+            Priority::Normal,
+            Duration::from_secs(3600),
+            move || sender.send("delayed").unwrap(),
+        )?;
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok("tick"));
+
+        // Cancelling a single task leaves the others running.
+        handle.cancel();
+        while receiver.recv_timeout(Duration::from_secs(5)) != Ok("tick") {}
+        assert!(executor.shutdown(Duration::from_secs(5)).is_empty());
+        assert!(receiver.try_iter().all(|message| message == "tick"));
+
+        let stats = executor.stats();
+        assert_eq!(stats["test_periodic"].cancelled, 1);
+        assert_eq!(stats["test_delayed"].cancelled, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pool_is_bounded() -> Result<()> {
+        let executor: TaskExecutor = Default::default();
+        let (sender, receiver) = channel();
+        for _ in 0..4 * NORMAL_WORKERS {
+            let sender = sender.clone();
+            executor.spawn("test_pooled", Priority::Normal, move |_| {
+                sender.send(std::thread::current().id()).unwrap()
+            })?;
+        }
+        let threads: HashSet<_> = (0..4 * NORMAL_WORKERS)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(5)))
+            .collect::<std::result::Result<_, _>>()?;
+        assert!(threads.len() <= NORMAL_WORKERS);
+
+        let handles = (0..MAX_QUEUED_TASKS)
+            .map(|_| {
+                executor.spawn_delayed(
+                    "test_queued",
+                    Priority::Background,
+                    Duration::from_secs(3600),
+                    || {},
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        assert!(executor.spawn("test_rejected", Priority::Background, |_| {}).is_err());
+        // A cancelled task leaves the queue right away.
+        handles[0].cancel();
+        executor.spawn("test_accepted", Priority::Background, |_| {})?;
+        assert!(executor.shutdown(Duration::from_secs(5)).is_empty());
+
+        let stats = executor.stats();
+        assert_eq!(stats["test_pooled"].finished, 4 * NORMAL_WORKERS as u64);
+        assert_eq!(stats["test_queued"].cancelled, MAX_QUEUED_TASKS as u64);
+        assert!(!stats.contains_key("test_rejected"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_spawn_dedicated() -> Result<()> {
+        let executor: TaskExecutor = Default::default();
+        let (sender, receiver) = channel();
+        executor.spawn_dedicated("test_dedicated", Priority::Normal, move |context| {
+            sender.send(std::thread::current().name().map(str::to_string)).unwrap();
+            while context.sleep(Duration::from_secs(3600)) {}
+        })?;
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)),
+            Ok(Some("test_dedicated".to_string()))
+        );
+        assert!(executor.shutdown(Duration::from_secs(5)).is_empty());
+        assert_eq!(executor.stats()["test_dedicated"].cancelled, 1);
+        Ok(())
+    }
+}
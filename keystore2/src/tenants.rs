@@ -0,0 +1,310 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements tenants for devices that host virtual machines or containers, which
+//! proxy the key requests of their guests into Keystore. Each tenant is given a range of
+//! Domain::SELINUX namespaces, the uids of its proxies, and a quota on the number of keys in
+//! its range. Tenants are isolated from each other and from the rest of the system: A tenant's
+//! proxy can only address namespaces in its own range, and no other caller can address
+//! namespaces in a tenant's range, regardless of what the SELinux policy allows. This keeps one
+//! tenant from exhausting or enumerating the key space of another.
+//!
+//! The configuration file holds one line per tenant of the form
+//! `<name> <first namespace>-<last namespace> <max keys> <uid>[,<uid>...]`,
+//! e.g., `vm1 200000-200999 500 1076,1077`. Ranges must not overlap and each uid can belong to
+//! only one tenant. Empty lines and lines starting with `#` are ignored.
+//!
+//! Tenant proxies cannot use Domain::APP, because keys in their own app namespaces would
+//! escape the tenant's quota and isolation. If the configuration is present but malformed,
+//! Keystore cannot tell which namespaces and uids belong to tenants, so all requests that
+//! are subject to tenant isolation fail, see `config_file`.
+
+use crate::config_file::{self, parse_range};
+use crate::database::KeystoreDB;
+use android_system_keystore2::aidl::android::system::keystore2::Domain::Domain;
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Write;
+use std::ops::RangeInclusive;
+
+/// Location of the tenant configuration.
+const TENANTS_CONFIG: &str = "/vendor/etc/security/keystore2_tenants.conf";
+
+lazy_static! {
+    /// The tenants of this device, loaded once on first use.
+    static ref TENANTS: Result<Tenants> = config_file::load(TENANTS_CONFIG, Tenants::parse);
+}
+
+/// Reasons for denying a request on behalf of tenant isolation. These are reported as
+/// `ResponseCode::PERMISSION_DENIED`.
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum TenantError {
+    /// The caller addressed a namespace outside of its tenant's range or inside the range of
+    /// a tenant it does not belong to.
+    #[error("Caller {caller_uid} may not access namespace {namespace} of another tenant.")]
+    Isolation {
+        /// The calling uid.
+        caller_uid: u32,
+        /// The addressed namespace.
+        namespace: i64,
+    },
+    /// The tenant owning the namespace has as many keys as its quota allows.
+    #[error("Tenant {0} exceeded its key quota.")]
+    QuotaExceeded(String),
+}
+
+/// A tenant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tenant {
+    /// Name of the tenant, used for logging.
+    pub name: String,
+    /// The Domain::SELINUX namespaces of the tenant.
+    pub namespaces: RangeInclusive<i64>,
+    /// The maximal number of keys in the tenant's namespaces.
+    pub max_keys: usize,
+    /// The uids that act on behalf of the tenant.
+    pub uids: Vec<u32>,
+}
+
+impl Tenant {
+    /// Checks that the tenant, currently holding `key_count` keys besides the one to be
+    /// stored, can store another key.
+    pub fn check_quota(&self, key_count: usize) -> Result<()> {
+        if key_count >= self.max_keys {
+            return Err(anyhow!(TenantError::QuotaExceeded(self.name.clone())))
+                .context(format!("In check_quota: {} keys of {}.", key_count, self.max_keys));
+        }
+        Ok(())
+    }
+}
+
+/// A set of tenants.
+#[derive(Debug, Default)]
+pub struct Tenants {
+    tenants: Vec<Tenant>,
+}
+
+impl Tenants {
+    /// Parses the tenant configuration. See the module documentation for the format.
+    pub fn parse(config: &str) -> Result<Self> {
+        let mut tenants: Vec<Tenant> = Vec::new();
+        for (n, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (name, range, max_keys, uids) = match fields[..] {
+                [name, range, max_keys, uids] => (name, range, max_keys, uids),
+                _ => return Err(anyhow!("Expected four fields in line {}.", n + 1)),
+            };
+            // Unlike other configuration files, tenants always specify both ends of the range.
+            if !range.contains('-') {
+                return Err(anyhow!("Bad namespace range in line {}.", n + 1));
+            }
+            let namespaces: RangeInclusive<i64> = parse_range(range)
+                .with_context(|| format!("Bad namespace range in line {}.", n + 1))?;
+            let max_keys: usize =
+                max_keys.parse().with_context(|| format!("Bad quota in line {}.", n + 1))?;
+            let uids = uids
+                .split(',')
+                .map(|uid| uid.parse::<u32>())
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(|| format!("Bad uid in line {}.", n + 1))?;
+            for other in &tenants {
+                if namespaces.start() <= other.namespaces.end()
+                    && other.namespaces.start() <= namespaces.end()
+                {
+                    return Err(anyhow!(
+                        "Namespace range overlaps {} in line {}.",
+                        other.name,
+                        n + 1
+                    ));
+                }
+                if uids.iter().any(|uid| other.uids.contains(uid)) {
+                    return Err(anyhow!(
+                        "Uid belongs to {} as well in line {}.",
+                        other.name,
+                        n + 1
+                    ));
+                }
+            }
+            tenants.push(Tenant { name: name.to_string(), namespaces, max_keys, uids });
+        }
+        Ok(Self { tenants })
+    }
+
+    fn tenant_of_namespace(&self, namespace: i64) -> Option<&Tenant> {
+        self.tenants.iter().find(|t| t.namespaces.contains(&namespace))
+    }
+
+    fn tenant_of_caller(&self, caller_uid: u32) -> Option<&Tenant> {
+        self.tenants.iter().find(|t| t.uids.contains(&caller_uid))
+    }
+
+    /// Checks that `caller_uid` may address the Domain::SELINUX namespace `namespace` as far
+    /// as tenant isolation is concerned. The SELinux policy must be checked in addition.
+    pub fn check_access(&self, caller_uid: u32, namespace: i64) -> Result<()> {
+        let caller_tenant = self.tenant_of_caller(caller_uid).map(|t| &t.name);
+        let namespace_tenant = self.tenant_of_namespace(namespace).map(|t| &t.name);
+        if caller_tenant == namespace_tenant {
+            Ok(())
+        } else {
+            Err(anyhow!(TenantError::Isolation { caller_uid, namespace }))
+        }
+    }
+
+    /// Checks that `caller_uid` may address the Domain::APP namespace `namespace` as far as
+    /// tenant isolation is concerned. Neither may tenant proxies use Domain::APP nor may
+    /// anyone, e.g., members of an access group, reach the app namespace of a tenant proxy.
+    pub fn check_app_access(&self, caller_uid: u32, namespace: i64) -> Result<()> {
+        let tenant = self
+            .tenant_of_caller(caller_uid)
+            .or_else(|| u32::try_from(namespace).ok().and_then(|uid| self.tenant_of_caller(uid)));
+        match tenant {
+            Some(_) => Err(anyhow!(TenantError::Isolation { caller_uid, namespace })),
+            None => Ok(()),
+        }
+    }
+}
+
+fn tenants() -> Result<&'static Tenants> {
+    TENANTS.as_ref().map_err(|e| anyhow!("Tenant configuration unavailable: {:?}", e))
+}
+
+/// Checks that `caller_uid` may address the Domain::SELINUX namespace `namespace` as far as
+/// tenant isolation is concerned.
+pub fn check_access(caller_uid: u32, namespace: i64) -> Result<()> {
+    tenants().context("In check_access.")?.check_access(caller_uid, namespace)
+}
+
+/// Checks that `caller_uid` may address the Domain::APP namespace `namespace` as far as tenant
+/// isolation is concerned.
+pub fn check_app_access(caller_uid: u32, namespace: i64) -> Result<()> {
+    tenants().context("In check_app_access.")?.check_app_access(caller_uid, namespace)
+}
+
+/// Returns the tenant owning the namespace `namespace` of `domain`, if any. Storing a key in
+/// this namespace must not exceed the tenant's quota, see `Tenant::check_quota`.
+pub fn tenant_of_namespace(domain: Domain, namespace: i64) -> Result<Option<Tenant>> {
+    if domain != Domain::SELINUX {
+        return Ok(None);
+    }
+    Ok(tenants().context("In tenant_of_namespace.")?.tenant_of_namespace(namespace).cloned())
+}
+
+/// Counts the keys held by each tenant. Tenants whose keys cannot be counted are logged and
+/// left out.
+pub fn count_keys(db: &mut KeystoreDB) -> HashMap<String, usize> {
+    let tenants = match tenants() {
+        Ok(tenants) => tenants,
+        Err(_) => return HashMap::new(),
+    };
+    tenants
+        .tenants
+        .iter()
+        .filter_map(|tenant| {
+            match db.count_keys_in_namespaces(Domain::SELINUX, tenant.namespaces.clone(), None) {
+                Ok(count) => Some((tenant.name.clone(), count)),
+                Err(e) => {
+                    log::error!("In count_keys: Failed to count keys of {}: {:?}", tenant.name, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Writes all tenants and the number of keys they hold according to `key_counts`, as
+/// returned by `count_keys`, to `f`.
+pub fn dump(f: &mut dyn Write, key_counts: Option<&HashMap<String, usize>>) -> std::io::Result<()> {
+    writeln!(f, "Tenants:")?;
+    let tenants = match tenants() {
+        Ok(tenants) => tenants,
+        Err(e) => return writeln!(f, "  {:?}", e),
+    };
+    for tenant in &tenants.tenants {
+        let count = key_counts
+            .and_then(|counts| counts.get(&tenant.name))
+            .map_or_else(|| "unknown".to_string(), |count| count.to_string());
+        writeln!(
+            f,
+            "  {}: namespaces {:?} uids {:?} keys {} of {}",
+            tenant.name, tenant.namespaces, tenant.uids, count, tenant.max_keys
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "
+        # Two virtual machines.
+        vm1 200000-200999 500 1076
+        vm2 201000-201999 100 1077,1078
+    ";
+
+    #[test]
+    fn test_check_access() -> Result<()> {
+        let tenants = Tenants::parse(CONFIG)?;
+        assert!(tenants.check_access(1076, 200500).is_ok());
+        assert!(tenants.check_access(1078, 201000).is_ok());
+        // Tenants cannot reach each other's namespaces or namespaces outside of their range.
+        assert!(tenants.check_access(1076, 201000).is_err());
+        assert!(tenants.check_access(1077, 102).is_err());
+        // Other callers cannot reach the namespaces of tenants.
+        assert!(tenants.check_access(1000, 200000).is_err());
+        assert!(tenants.check_access(1000, 102).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_app_access() -> Result<()> {
+        let tenants = Tenants::parse(CONFIG)?;
+        assert!(tenants.check_app_access(10001, 10001).is_ok());
+        assert!(tenants.check_app_access(10001, 10002).is_ok());
+        // Tenant proxies cannot use their own or any other app namespace.
+        assert!(tenants.check_app_access(1076, 1076).is_err());
+        assert!(tenants.check_app_access(1077, 10001).is_err());
+        // No one can reach the app namespace of a tenant proxy.
+        assert!(tenants.check_app_access(10001, 1078).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_quota() -> Result<()> {
+        let tenants = Tenants::parse(CONFIG)?;
+        let vm2 = tenants.tenant_of_namespace(201500).unwrap();
+        assert!(vm2.check_quota(99).is_ok());
+        let e = vm2.check_quota(100).unwrap_err();
+        assert_eq!(
+            e.root_cause().downcast_ref::<TenantError>(),
+            Some(&TenantError::QuotaExceeded("vm2".to_string()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_config() {
+        assert!(Tenants::parse("vm1 200000-200999 500").is_err());
+        assert!(Tenants::parse("vm1 200999-200000 500 1076").is_err());
+        assert!(Tenants::parse("vm1 200000 500 1076").is_err());
+        assert!(Tenants::parse("vm1 200000-200999 500 1076\nvm2 200999-201999 5 1077").is_err());
+        assert!(Tenants::parse("vm1 200000-200999 500 1076\nvm2 201000-201999 5 1076").is_err());
+    }
+}
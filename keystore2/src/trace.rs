@@ -0,0 +1,99 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements per request trace ids. A trace id is generated when a request
+//! enters Keystore at the service boundary and is kept in a thread local for the duration
+//! of the request. Error logs and watchdog records pick up the current trace id, so that
+//! the log lines and overdue HAL calls belonging to one request can be correlated in a
+//! bugreport. The KeyMint HAL interfaces have no field for caller annotations, so HAL calls
+//! are correlated through the watch points set around them.
+
+use lazy_static::lazy_static;
+use std::cell::Cell;
+use std::convert::TryInto;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Identifies a single request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceId(u64);
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+lazy_static! {
+    /// The next trace id. It starts at a random value, so that trace ids from before and
+    /// after a Keystore restart are unlikely to collide.
+    static ref NEXT_TRACE_ID: AtomicU64 = AtomicU64::new(
+        crate::csprng::random_data(8)
+            .ok()
+            .and_then(|r| r[..].try_into().ok())
+            .map(u64::from_ne_bytes)
+            .unwrap_or(0)
+    );
+}
+
+thread_local! {
+    static CURRENT_TRACE_ID: Cell<Option<TraceId>> = Cell::new(None);
+}
+
+/// Sets the trace id of the current thread while in scope and restores the previous one
+/// on drop.
+pub struct TraceScope {
+    previous: Option<TraceId>,
+    not_send: PhantomData<*mut ()>, // TraceScope must not be Send.
+}
+
+impl Drop for TraceScope {
+    fn drop(&mut self) {
+        CURRENT_TRACE_ID.with(|c| c.set(self.previous));
+    }
+}
+
+/// Generates a new trace id and makes it the current trace id of this thread until the
+/// returned scope is dropped. Must be called at the service boundary before any watch point
+/// is set.
+pub fn begin() -> TraceScope {
+    let id = TraceId(NEXT_TRACE_ID.fetch_add(1, Ordering::Relaxed));
+    let previous = CURRENT_TRACE_ID.with(|c| c.replace(Some(id)));
+    TraceScope { previous, not_send: PhantomData }
+}
+
+/// Returns the trace id of the request currently served by this thread, if any.
+pub fn current() -> Option<TraceId> {
+    CURRENT_TRACE_ID.with(|c| c.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_scopes() {
+        assert_eq!(current(), None);
+        let outer = begin();
+        let outer_id = current().unwrap();
+        {
+            let _inner = begin();
+            assert_ne!(current(), Some(outer_id));
+        }
+        assert_eq!(current(), Some(outer_id));
+        drop(outer);
+        assert_eq!(current(), None);
+    }
+}
@@ -15,7 +15,10 @@
 //! This module implements utility functions used by the Keystore 2.0 service
 //! implementation.
 
+use crate::database::KeyMetaData;
+use crate::denial_limiter;
 use crate::error::{map_binder_status, Error, ErrorCode};
+use crate::key_policy;
 use crate::permission;
 use crate::permission::{KeyPerm, KeyPermSet, KeystorePerm};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
@@ -36,15 +39,27 @@ use keystore2_apc_compat::{
     APC_COMPAT_ERROR_IGNORED, APC_COMPAT_ERROR_OK, APC_COMPAT_ERROR_OPERATION_PENDING,
     APC_COMPAT_ERROR_SYSTEM_ERROR,
 };
+use std::ffi::CStr;
 use std::sync::Mutex;
 
+/// Returns the SELinux context of the caller of the current binder call, given the calling sid
+/// reported by binder. Callers have no SELinux context in embedded mode, where
+/// `embedded::caller_context` stands in for it.
+fn calling_context(calling_sid: Option<&CStr>) -> Option<&CStr> {
+    #[cfg(feature = "embedded")]
+    if calling_sid.is_none() {
+        return Some(crate::embedded::caller_context());
+    }
+    calling_sid
+}
+
 /// This function uses its namesake in the permission module and in
 /// combination with with_calling_sid from the binder crate to check
 /// if the caller has the given keystore permission.
 pub fn check_keystore_permission(perm: KeystorePerm) -> anyhow::Result<()> {
     ThreadState::with_calling_sid(|calling_sid| {
         permission::check_keystore_permission(
-            &calling_sid.ok_or_else(Error::sys).context(
+            &calling_context(calling_sid).ok_or_else(Error::sys).context(
                 "In check_keystore_permission: Cannot check permission without calling_sid.",
             )?,
             perm,
@@ -54,37 +69,84 @@ pub fn check_keystore_permission(perm: KeystorePerm) -> anyhow::Result<()> {
 
 /// This function uses its namesake in the permission module and in
 /// combination with with_calling_sid from the binder crate to check
-/// if the caller has the given grant permission.
+/// if the caller has the given grant permission. The grant permission is also subject to
+/// the key policy.
 pub fn check_grant_permission(access_vec: KeyPermSet, key: &KeyDescriptor) -> anyhow::Result<()> {
     ThreadState::with_calling_sid(|calling_sid| {
         permission::check_grant_permission(
-            &calling_sid.ok_or_else(Error::sys).context(
+            &calling_context(calling_sid).ok_or_else(Error::sys).context(
                 "In check_grant_permission: Cannot check permission without calling_sid.",
             )?,
             access_vec,
             key,
         )
-    })
+    })?;
+    key_policy::check(ThreadState::get_calling_uid(), KeyPerm::grant().into(), key)
+        .context("In check_grant_permission.")
 }
 
 /// This function uses its namesake in the permission module and in
 /// combination with with_calling_sid from the binder crate to check
-/// if the caller has the given key permission.
+/// if the caller has the given key permission. Repeated denials are rate limited, see
+/// `denial_limiter`.
 pub fn check_key_permission(
     perm: KeyPerm,
     key: &KeyDescriptor,
     access_vector: &Option<KeyPermSet>,
 ) -> anyhow::Result<()> {
-    ThreadState::with_calling_sid(|calling_sid| {
-        permission::check_key_permission(
+    let caller_uid = ThreadState::get_calling_uid();
+    denial_limiter::check(caller_uid, perm, key, || {
+        ThreadState::with_calling_sid(|calling_sid| {
+            permission::check_key_permission(
+                caller_uid,
+                &calling_context(calling_sid).ok_or_else(Error::sys).context(
+                    "In check_key_permission: Cannot check permission without calling_sid.",
+                )?,
+                perm,
+                key,
+                access_vector,
+            )
+        })
+    })
+}
+
+/// Returns the subset of `perms` that the caller holds on `key`. Unlike
+/// `check_key_permission`, the SELinux policy is only queried and denials are not audited.
+/// This is meant for requests that work with a subset of the permissions, which must not log
+/// a denial for each permission that the caller lacks.
+pub fn granted_key_permissions(
+    perms: KeyPermSet,
+    key: &KeyDescriptor,
+    access_vector: &Option<KeyPermSet>,
+) -> anyhow::Result<KeyPermSet> {
+    let decision = ThreadState::with_calling_sid(|calling_sid| {
+        permission::check_key_permission_audit(
             ThreadState::get_calling_uid(),
-            &calling_sid
-                .ok_or_else(Error::sys)
-                .context("In check_key_permission: Cannot check permission without calling_sid.")?,
-            perm,
+            &calling_context(calling_sid).ok_or_else(Error::sys).context(
+                "In granted_key_permissions: Cannot check permission without calling_sid.",
+            )?,
+            perms,
             key,
             access_vector,
         )
+    })?;
+    Ok(match decision {
+        permission::AuditDecision::Allowed => perms,
+        permission::AuditDecision::Denied { missing } => perms.difference(missing),
+    })
+}
+
+/// This function uses its namesake in the permission module and in
+/// combination with with_calling_sid from the binder crate to check
+/// if the caller matches the client context binding of a loaded key.
+pub fn check_client_context(metadata: &KeyMetaData) -> anyhow::Result<()> {
+    ThreadState::with_calling_sid(|calling_sid| {
+        permission::check_client_context(
+            &calling_context(calling_sid)
+                .ok_or_else(Error::sys)
+                .context("In check_client_context: Cannot check permission without calling_sid.")?,
+            metadata.client_context_pattern().map(String::as_str),
+        )
     })
 }
 
@@ -237,6 +299,7 @@ pub fn uid_to_android_user(uid: u32) -> u32 {
 /// This module provides helpers for simplified use of the watchdog module.
 #[cfg(feature = "watchdog")]
 pub mod watchdog {
+    use crate::trace;
     pub use crate::watchdog::WatchPoint;
     use crate::watchdog::Watchdog;
     use lazy_static::lazy_static;
@@ -249,8 +312,16 @@ pub mod watchdog {
     }
 
     /// Sets a watch point with `id` and a timeout of `millis` milliseconds.
+    /// The record includes the trace id of the current request if any.
     pub fn watch_millis(id: &'static str, millis: u64) -> Option<WatchPoint> {
-        Watchdog::watch(&WD, id, Duration::from_millis(millis))
+        match trace::current() {
+            Some(trace_id) => {
+                Watchdog::watch_with(&WD, id, Duration::from_millis(millis), move || {
+                    format!("trace: {}", trace_id)
+                })
+            }
+            None => Watchdog::watch(&WD, id, Duration::from_millis(millis)),
+        }
     }
 
     /// Like `watch_millis` but with a callback that is called every time a report
@@ -260,7 +331,11 @@ pub mod watchdog {
         millis: u64,
         callback: impl Fn() -> String + Send + 'static,
     ) -> Option<WatchPoint> {
-        Watchdog::watch_with(&WD, id, Duration::from_millis(millis), callback)
+        let trace_id = trace::current();
+        Watchdog::watch_with(&WD, id, Duration::from_millis(millis), move || match trace_id {
+            Some(trace_id) => format!("trace: {} {}", trace_id, callback()),
+            None => callback(),
+        })
     }
 }
 
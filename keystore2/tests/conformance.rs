@@ -0,0 +1,244 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements a conformance harness that runs identical request sequences against
+//! the KeyMint HALs of the device and against the reference implementation that is bundled as
+//! the SOFTWARE security level. Each step of a sequence is reduced to an `Observation` that
+//! leaves out what legitimately differs between implementations, e.g., the security level of
+//! the authorizations or the creation time. Every step whose observation differs from the
+//! reference is reported as a `Deviation`.
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    SecurityLevel::SecurityLevel, Tag::Tag,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, IKeystoreSecurityLevel::IKeystoreSecurityLevel,
+    IKeystoreService::IKeystoreService, KeyDescriptor::KeyDescriptor, KeyMetadata::KeyMetadata,
+    ResponseCode::ResponseCode,
+};
+use binder::{Status, Strong};
+use std::fmt;
+
+/// The security level of the reference implementation.
+pub const REFERENCE: SecurityLevel = SecurityLevel::SOFTWARE;
+
+/// The security levels that are compared against the reference.
+pub const DEVICE_LEVELS: &[SecurityLevel] =
+    &[SecurityLevel::TRUSTED_ENVIRONMENT, SecurityLevel::STRONGBOX];
+
+/// Tags whose values differ between implementations by design and are left out of
+/// `Observation::Characteristics`.
+const VOLATILE_TAGS: &[Tag] =
+    &[Tag::CREATION_DATETIME, Tag::VENDOR_PATCHLEVEL, Tag::BOOT_PATCHLEVEL];
+
+/// The implementation independent outcome of one step of a request sequence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Observation {
+    /// The step succeeded without a result worth comparing.
+    Done,
+    /// The step failed with the given service specific error code.
+    Error(i32),
+    /// The step returned the given authorizations as sorted (tag, value) pairs.
+    Characteristics(Vec<(i32, String)>),
+    /// The step returned an attestation with or without leaf certificate and chain.
+    Attestation {
+        /// True if a leaf certificate was returned.
+        certificate: bool,
+        /// True if a non empty certificate chain was returned.
+        chain: bool,
+    },
+    /// The step cannot be compared, because it depends on provisioning, e.g., of attestation
+    /// keys, rather than on the implementation.
+    Unavailable,
+}
+
+impl Observation {
+    /// Observes the authorizations of a successful key creation or the error of a failed one.
+    pub fn characteristics(result: &Result<KeyMetadata, Status>) -> Self {
+        match result {
+            Ok(metadata) => {
+                let mut authorizations: Vec<(i32, String)> = metadata
+                    .authorizations
+                    .iter()
+                    .filter(|a| !VOLATILE_TAGS.contains(&a.keyParameter.tag))
+                    .map(|a| (a.keyParameter.tag.0, format!("{:?}", a.keyParameter.value)))
+                    .collect();
+                authorizations.sort();
+                Observation::Characteristics(authorizations)
+            }
+            Err(status) => Observation::Error(status.service_specific_error()),
+        }
+    }
+
+    /// Observes the attestation of a successful key creation or the error of a failed one.
+    pub fn attestation(result: &Result<KeyMetadata, Status>) -> Self {
+        match result {
+            Ok(metadata) => Observation::Attestation {
+                certificate: metadata.certificate.is_some(),
+                chain: metadata.certificateChain.as_ref().map_or(false, |c| !c.is_empty()),
+            },
+            Err(status) if status.service_specific_error() == ResponseCode::OUT_OF_KEYS.0 => {
+                Observation::Unavailable
+            }
+            Err(status) => Observation::Error(status.service_specific_error()),
+        }
+    }
+
+    /// Observes the outcome of a step without result.
+    pub fn outcome<T>(result: &Result<T, Status>) -> Self {
+        match result {
+            Ok(_) => Observation::Done,
+            Err(status) => Observation::Error(status.service_specific_error()),
+        }
+    }
+
+    fn conforms_to(&self, reference: &Observation) -> bool {
+        matches!((self, reference), (Observation::Unavailable, _) | (_, Observation::Unavailable))
+            || self == reference
+    }
+}
+
+/// Everything a sequence needs to run against one implementation.
+pub struct SequenceContext {
+    /// The security level under test.
+    pub sec_level: SecurityLevel,
+    /// The Keystore service.
+    pub service: Strong<dyn IKeystoreService>,
+    /// The Keystore binding of the security level under test.
+    pub level: Strong<dyn IKeystoreSecurityLevel>,
+    sequence: &'static str,
+}
+
+impl SequenceContext {
+    /// Returns a descriptor for a key of the calling app that is unique to the security level,
+    /// the sequence, and `suffix`.
+    pub fn key(&self, suffix: &str) -> KeyDescriptor {
+        KeyDescriptor {
+            domain: Domain::APP,
+            nspace: -1,
+            alias: Some(format!("conformance_{}_{}_{}", self.sec_level.0, self.sequence, suffix)),
+            blob: None,
+        }
+    }
+}
+
+/// A request sequence. It returns one named observation per step.
+pub type Sequence = fn(&SequenceContext) -> Vec<(&'static str, Observation)>;
+
+/// A step of a sequence whose observation on a device level differs from the reference.
+#[derive(Debug)]
+pub struct Deviation {
+    /// The deviating security level.
+    pub sec_level: SecurityLevel,
+    /// The name of the sequence.
+    pub sequence: &'static str,
+    /// The name of the step.
+    pub step: &'static str,
+    /// The observation on the reference implementation.
+    pub reference: Option<Observation>,
+    /// The observation on the device level.
+    pub device: Option<Observation>,
+}
+
+/// The deviations found by a run of the harness.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// The security levels that were compared against the reference.
+    pub compared: Vec<SecurityLevel>,
+    /// All deviations in the order they were found.
+    pub deviations: Vec<Deviation>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Compared {:?} against {:?}.", self.compared, REFERENCE)?;
+        for d in &self.deviations {
+            writeln!(
+                f,
+                "{:?} {}/{}: expected {:?}, got {:?}",
+                d.sec_level, d.sequence, d.step, d.reference, d.device
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares the observations of a sequence step by step. Steps are matched by name, so a
+/// sequence that ends early on one implementation reports the missing steps as well.
+fn compare(
+    sec_level: SecurityLevel,
+    sequence: &'static str,
+    reference: &[(&'static str, Observation)],
+    device: &[(&'static str, Observation)],
+    deviations: &mut Vec<Deviation>,
+) {
+    let find = |steps: &[(&'static str, Observation)], step| {
+        steps.iter().find(|(s, _)| *s == step).map(|(_, o)| o.clone())
+    };
+    let mut steps: Vec<&'static str> = reference.iter().map(|(s, _)| *s).collect();
+    steps.extend(device.iter().map(|(s, _)| *s).filter(|s| !reference.iter().any(|(r, _)| r == s)));
+    for step in steps {
+        let (r, d) = (find(reference, step), find(device, step));
+        let conforms = match (&r, &d) {
+            (Some(r), Some(d)) => d.conforms_to(r),
+            _ => false,
+        };
+        if !conforms {
+            deviations.push(Deviation { sec_level, sequence, step, reference: r, device: d });
+        }
+    }
+}
+
+/// A set of named request sequences.
+#[derive(Default)]
+pub struct Harness {
+    sequences: Vec<(&'static str, Sequence)>,
+}
+
+impl Harness {
+    /// Adds a sequence to the harness.
+    pub fn sequence(mut self, name: &'static str, sequence: Sequence) -> Self {
+        self.sequences.push((name, sequence));
+        self
+    }
+
+    /// Runs all sequences against the reference and every available device level. Returns
+    /// None if the reference implementation is not available.
+    pub fn run(&self, service: &Strong<dyn IKeystoreService>) -> Option<Report> {
+        let context = |sec_level: SecurityLevel, sequence| {
+            service.getSecurityLevel(sec_level).ok().map(|level| SequenceContext {
+                sec_level,
+                service: service.clone(),
+                level,
+                sequence,
+            })
+        };
+        let mut report: Report = Default::default();
+        let mut reference = Vec::new();
+        for (name, run) in &self.sequences {
+            reference.push(run(&context(REFERENCE, *name)?));
+        }
+        for sec_level in DEVICE_LEVELS {
+            if service.getSecurityLevel(*sec_level).is_err() {
+                continue;
+            }
+            report.compared.push(*sec_level);
+            for ((name, run), reference) in self.sequences.iter().zip(&reference) {
+                let device = context(*sec_level, *name).map(|ctx| run(&ctx)).unwrap_or_default();
+                compare(*sec_level, *name, reference, &device, &mut report.deviations);
+            }
+        }
+        Some(report)
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runs request sequences against the KeyMint HALs of the device and the bundled reference
+//! implementation and reports where the HALs deviate from the reference. See `conformance.rs`
+//! for what is compared.
+
+mod conformance;
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, BlockMode::BlockMode, Digest::Digest, EcCurve::EcCurve,
+    KeyParameter::KeyParameter, KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose,
+    PaddingMode::PaddingMode, Tag::Tag,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    IKeystoreService::IKeystoreService, KeyDescriptor::KeyDescriptor,
+};
+use conformance::{Harness, Observation, SequenceContext};
+
+const KEYSTORE_SERVICE_NAME: &str = "android.system.keystore2.IKeystoreService/default";
+
+fn param(tag: Tag, value: KeyParameterValue) -> KeyParameter {
+    KeyParameter { tag, value }
+}
+
+fn no_auth_required() -> KeyParameter {
+    param(Tag::NO_AUTH_REQUIRED, KeyParameterValue::BoolValue(true))
+}
+
+fn ec_sign_params() -> Vec<KeyParameter> {
+    vec![
+        param(Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::EC)),
+        param(Tag::EC_CURVE, KeyParameterValue::EcCurve(EcCurve::P_256)),
+        param(Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::SIGN)),
+        param(Tag::DIGEST, KeyParameterValue::Digest(Digest::SHA_2_256)),
+        no_auth_required(),
+    ]
+}
+
+fn aes_gcm_params() -> Vec<KeyParameter> {
+    vec![
+        param(Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::AES)),
+        param(Tag::KEY_SIZE, KeyParameterValue::Integer(128)),
+        param(Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::ENCRYPT)),
+        param(Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::DECRYPT)),
+        param(Tag::BLOCK_MODE, KeyParameterValue::BlockMode(BlockMode::GCM)),
+        param(Tag::PADDING, KeyParameterValue::PaddingMode(PaddingMode::NONE)),
+        no_auth_required(),
+    ]
+}
+
+fn cleanup(ctx: &SequenceContext, key: &KeyDescriptor) {
+    // The key may not exist if a step failed, so errors are ignored.
+    let _ = ctx.service.deleteKey(key);
+}
+
+/// Generates an EC signing key and signs with it.
+fn ec_sign_sequence(ctx: &SequenceContext) -> Vec<(&'static str, Observation)> {
+    let key = ctx.key("key");
+    let generated = ctx.level.generateKey(&key, None, &ec_sign_params(), 0, b"");
+    let mut steps = vec![("generate", Observation::characteristics(&generated))];
+    if generated.is_ok() {
+        let op_params = vec![
+            param(Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::SIGN)),
+            param(Tag::DIGEST, KeyParameterValue::Digest(Digest::SHA_2_256)),
+        ];
+        let response = ctx.level.createOperation(&key, &op_params, false);
+        steps.push(("begin", Observation::outcome(&response)));
+        if let Some(op) = response.ok().and_then(|r| r.iOperation) {
+            steps.push(("update", Observation::outcome(&op.update(b"conformance"))));
+            steps.push(("finish", Observation::outcome(&op.finish(None, None))));
+        }
+        // Signing with a digest that the key is not authorized for must fail.
+        let op_params = vec![
+            param(Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::SIGN)),
+            param(Tag::DIGEST, KeyParameterValue::Digest(Digest::SHA_2_512)),
+        ];
+        let response = ctx.level.createOperation(&key, &op_params, false);
+        steps.push(("begin_unauthorized_digest", Observation::outcome(&response)));
+    }
+    cleanup(ctx, &key);
+    steps
+}
+
+/// Generates AES-GCM keys with and without the required minimum MAC length.
+fn aes_gcm_sequence(ctx: &SequenceContext) -> Vec<(&'static str, Observation)> {
+    let key = ctx.key("key");
+    let generated = ctx.level.generateKey(&key, None, &aes_gcm_params(), 0, b"");
+    let mut steps = vec![("generate_without_min_mac_length", Observation::outcome(&generated))];
+    cleanup(ctx, &key);
+    let mut params = aes_gcm_params();
+    params.push(param(Tag::MIN_MAC_LENGTH, KeyParameterValue::Integer(128)));
+    let generated = ctx.level.generateKey(&key, None, &params, 0, b"");
+    steps.push(("generate", Observation::characteristics(&generated)));
+    cleanup(ctx, &key);
+    steps
+}
+
+/// Generates keys with sizes that KeyMint must reject.
+fn unsupported_key_size_sequence(ctx: &SequenceContext) -> Vec<(&'static str, Observation)> {
+    let key = ctx.key("key");
+    let hmac_params = vec![
+        param(Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::HMAC)),
+        // HMAC key sizes must be multiples of 8.
+        param(Tag::KEY_SIZE, KeyParameterValue::Integer(100)),
+        param(Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::SIGN)),
+        param(Tag::DIGEST, KeyParameterValue::Digest(Digest::SHA_2_256)),
+        param(Tag::MIN_MAC_LENGTH, KeyParameterValue::Integer(256)),
+        no_auth_required(),
+    ];
+    let hmac = ctx.level.generateKey(&key, None, &hmac_params, 0, b"");
+    cleanup(ctx, &key);
+    let mut aes_params = aes_gcm_params();
+    aes_params.retain(|p| p.tag != Tag::KEY_SIZE);
+    aes_params.push(param(Tag::KEY_SIZE, KeyParameterValue::Integer(100)));
+    aes_params.push(param(Tag::MIN_MAC_LENGTH, KeyParameterValue::Integer(128)));
+    let aes = ctx.level.generateKey(&key, None, &aes_params, 0, b"");
+    cleanup(ctx, &key);
+    vec![("hmac", Observation::outcome(&hmac)), ("aes", Observation::outcome(&aes))]
+}
+
+/// Generates an attested EC key. The step is not compared if the level has no attestation
+/// keys.
+fn attestation_sequence(ctx: &SequenceContext) -> Vec<(&'static str, Observation)> {
+    let key = ctx.key("key");
+    let mut params = ec_sign_params();
+    params.push(param(Tag::ATTESTATION_CHALLENGE, KeyParameterValue::Blob(b"conf".to_vec())));
+    let generated = ctx.level.generateKey(&key, None, &params, 0, b"");
+    cleanup(ctx, &key);
+    vec![("generate", Observation::attestation(&generated))]
+}
+
+/// The reference implementation is only served by Keystore builds that bundle it as the
+/// SOFTWARE security level, so the test is ignored by default. Run it explicitly with
+/// `--ignored` on such builds; it then fails if the reference or all KeyMint HALs are missing.
+#[test]
+#[ignore]
+fn keymint_conformance() {
+    android_logger::init_once(
+        android_logger::Config::default()
+            .with_tag("keystore2_conformance_test")
+            .with_min_level(log::Level::Debug),
+    );
+    let service: binder::Strong<dyn IKeystoreService> =
+        binder::get_interface(KEYSTORE_SERVICE_NAME).expect("Failed to connect to Keystore.");
+    let report = Harness::default()
+        .sequence("ec_sign", ec_sign_sequence)
+        .sequence("aes_gcm", aes_gcm_sequence)
+        .sequence("unsupported_key_size", unsupported_key_size_sequence)
+        .sequence("attestation", attestation_sequence)
+        .run(&service)
+        .expect("The reference implementation is not available.");
+    log::info!("{}", report);
+    assert!(!report.compared.is_empty(), "No KeyMint HAL is available for comparison.");
+    assert!(
+        report.deviations.is_empty(),
+        "{} deviation(s) from the reference:\n{}",
+        report.deviations.len(),
+        report
+    );
+}
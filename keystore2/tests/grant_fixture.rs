@@ -0,0 +1,185 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements a fixture that runs the steps of a test in separate processes as
+//! different callers of Keystore: two test apps, the grantor and the grantee, and a privileged
+//! helper that stands in for the system, e.g., when an app is uninstalled. The test binary must
+//! run as root. For each step it forks a child, which drops to the uid of its actor, connects
+//! to Keystore, runs the step, and reports the result back through a pipe. The parent never
+//! talks to Binder itself, so that each child starts with a fresh Binder state. Forking is only
+//! safe while no other thread runs, so the tests must run with `--test-threads=1`, as
+//! configured in `GrantLifecycleTest.xml`.
+//!
+//! A step returns an `i64`, e.g., the namespace of a grant descriptor, or the service specific
+//! error code of the call that failed.
+
+use android_security_grants::aidl::android::security::grants::IKeystoreGrants::IKeystoreGrants;
+use android_security_maintenance::aidl::android::security::maintenance::IKeystoreMaintenance::IKeystoreMaintenance;
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, IKeystoreService::IKeystoreService,
+};
+use binder::{Status, Strong};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{fork, pipe, setresgid, setresuid, ForkResult, Gid, Uid};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::panic::{self, AssertUnwindSafe};
+
+const KEYSTORE_SERVICE_NAME: &str = "android.system.keystore2.IKeystoreService/default";
+const GRANTS_SERVICE_NAME: &str = "android.security.grants";
+const MAINTENANCE_SERVICE_NAME: &str = "android.security.maintenance";
+
+/// The uid of the privileged helper.
+const HELPER_UID: u32 = 0;
+
+/// The result of a step: a value or the service specific error code of the failed call.
+pub type Outcome = Result<i64, i32>;
+
+/// Maps a Binder result to an `Outcome` error code, see `Outcome`.
+pub fn code<T>(result: Result<T, Status>) -> Result<T, i32> {
+    result.map_err(|status| status.service_specific_error())
+}
+
+/// The callers a step can run as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Actor {
+    /// The test app that owns the keys and grants them.
+    Grantor,
+    /// The test app the keys are granted to.
+    Grantee,
+    /// The privileged helper that stands in for the system.
+    Helper,
+}
+
+/// The Keystore services as seen by the actor of a step.
+pub struct Services {
+    /// The Keystore service.
+    pub keystore: Strong<dyn IKeystoreService>,
+    /// The grants service.
+    pub grants: Strong<dyn IKeystoreGrants>,
+    /// The maintenance service. Only the helper is allowed to use it.
+    pub maintenance: Strong<dyn IKeystoreMaintenance>,
+}
+
+impl Services {
+    fn connect() -> Self {
+        Self {
+            keystore: binder::get_interface(KEYSTORE_SERVICE_NAME).unwrap(),
+            grants: binder::get_interface(GRANTS_SERVICE_NAME).unwrap(),
+            maintenance: binder::get_interface(MAINTENANCE_SERVICE_NAME).unwrap(),
+        }
+    }
+}
+
+/// A pair of test apps. The apps must be distinct for tests that run concurrently. Their
+/// namespaces are cleared when the fixture is created and when it is dropped.
+pub struct Fixture {
+    grantor_uid: u32,
+    grantee_uid: u32,
+}
+
+impl Fixture {
+    /// Creates a fixture for the test apps with the uids `first_uid` and `first_uid + 1`.
+    pub fn new(first_uid: u32) -> Self {
+        let fixture = Self { grantor_uid: first_uid, grantee_uid: first_uid + 1 };
+        fixture.uninstall(Actor::Grantor).expect("Failed to clear the grantor.");
+        fixture.uninstall(Actor::Grantee).expect("Failed to clear the grantee.");
+        fixture
+    }
+
+    /// Returns the uid of the given actor.
+    pub fn uid(&self, actor: Actor) -> u32 {
+        match actor {
+            Actor::Grantor => self.grantor_uid,
+            Actor::Grantee => self.grantee_uid,
+            Actor::Helper => HELPER_UID,
+        }
+    }
+
+    /// Runs `step` in a child process as `actor` and returns its outcome. Panics if the child
+    /// does not report an outcome, e.g., because an assertion in `step` failed.
+    pub fn run_as<F>(&self, actor: Actor, step: F) -> Outcome
+    where
+        F: FnOnce(&Services) -> Outcome,
+    {
+        let (read_fd, write_fd) = pipe().expect("Failed to create pipe.");
+        // Safety: The child only runs the step and exits, it never returns to the caller.
+        match unsafe { fork() }.expect("Failed to fork.") {
+            ForkResult::Child => {
+                // Safety: The child owns its copy of the write end of the pipe.
+                let mut writer = unsafe { File::from_raw_fd(write_fd) };
+                let uid = self.uid(actor);
+                setresgid(Gid::from_raw(uid), Gid::from_raw(uid), Gid::from_raw(uid))
+                    .expect("Failed to set gid.");
+                setresuid(Uid::from_raw(uid), Uid::from_raw(uid), Uid::from_raw(uid))
+                    .expect("Failed to set uid.");
+                // A panicking step must not unwind into the test harness of the child.
+                let outcome =
+                    match panic::catch_unwind(AssertUnwindSafe(|| step(&Services::connect()))) {
+                        Ok(outcome) => outcome,
+                        Err(_) => std::process::exit(1),
+                    };
+                let mut message = [0u8; 9];
+                match outcome {
+                    Ok(value) => message[1..].copy_from_slice(&value.to_le_bytes()),
+                    Err(error) => {
+                        message[0] = 1;
+                        message[1..5].copy_from_slice(&error.to_le_bytes());
+                    }
+                }
+                writer.write_all(&message).expect("Failed to report outcome.");
+                std::process::exit(0);
+            }
+            ForkResult::Parent { child } => {
+                // Safety: The parent owns its copies of both ends of the pipe. The write end
+                // must be closed, so that reading ends if the child dies without reporting.
+                drop(unsafe { File::from_raw_fd(write_fd) });
+                let mut reader = unsafe { File::from_raw_fd(read_fd) };
+                let mut message = Vec::new();
+                reader.read_to_end(&mut message).expect("Failed to read outcome.");
+                let status = waitpid(child, None).expect("Failed to wait for child.");
+                assert_eq!(status, WaitStatus::Exited(child, 0), "Step as {:?} failed.", actor);
+                assert_eq!(message.len(), 9, "Step as {:?} did not report.", actor);
+                let mut value = [0u8; 8];
+                value.copy_from_slice(&message[1..]);
+                match message[0] {
+                    0 => Ok(i64::from_le_bytes(value)),
+                    _ => Err(i32::from_le_bytes([value[0], value[1], value[2], value[3]])),
+                }
+            }
+        }
+    }
+
+    /// Has the helper clear the namespace of the given test app, as the system does when the
+    /// app is uninstalled.
+    pub fn uninstall(&self, app: Actor) -> Outcome {
+        assert_ne!(app, Actor::Helper);
+        let uid = self.uid(app) as i64;
+        self.run_as(Actor::Helper, move |services| {
+            code(services.maintenance.clearNamespace(Domain::APP, uid)).map(|_| 0)
+        })
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        // After a failed test, the namespaces are left for the next run to clear, because a
+        // failing cleanup step would panic while panicking.
+        if !std::thread::panicking() {
+            let _ = self.uninstall(Actor::Grantor);
+            let _ = self.uninstall(Actor::Grantee);
+        }
+    }
+}
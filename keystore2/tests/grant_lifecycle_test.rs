@@ -0,0 +1,220 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Exercises the lifecycle of a grant across uids against the Keystore of the device: creation
+//! by the grantor, use by the grantee, enforcement of the granted permissions, expiry of the
+//! granted key, revocation, and cleanup when either app is uninstalled. See `grant_fixture.rs`
+//! for how the steps run as different callers. Grants themselves do not expire, so expiry is
+//! covered through the validity period of the granted key.
+
+mod grant_fixture;
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, Digest::Digest, EcCurve::EcCurve, ErrorCode::ErrorCode,
+    KeyParameter::KeyParameter, KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose,
+    SecurityLevel::SecurityLevel, Tag::Tag,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor, KeyPermission::KeyPermission,
+    ResponseCode::ResponseCode,
+};
+use grant_fixture::{code, Actor, Fixture, Outcome, Services};
+
+const ALIAS: &str = "grant_lifecycle_key";
+
+fn param(tag: Tag, value: KeyParameterValue) -> KeyParameter {
+    KeyParameter { tag, value }
+}
+
+fn ec_sign_params() -> Vec<KeyParameter> {
+    vec![
+        param(Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::EC)),
+        param(Tag::EC_CURVE, KeyParameterValue::EcCurve(EcCurve::P_256)),
+        param(Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::SIGN)),
+        param(Tag::DIGEST, KeyParameterValue::Digest(Digest::SHA_2_256)),
+        param(Tag::NO_AUTH_REQUIRED, KeyParameterValue::BoolValue(true)),
+    ]
+}
+
+fn own_key() -> KeyDescriptor {
+    KeyDescriptor { domain: Domain::APP, nspace: -1, alias: Some(ALIAS.to_string()), blob: None }
+}
+
+fn granted_key(grant_id: i64) -> KeyDescriptor {
+    KeyDescriptor { domain: Domain::GRANT, nspace: grant_id, alias: None, blob: None }
+}
+
+fn generate(services: &Services, params: &[KeyParameter]) -> Outcome {
+    let level = code(services.keystore.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT))?;
+    code(level.generateKey(&own_key(), None, params, 0, b"")).map(|_| 0)
+}
+
+fn sign(services: &Services, key: &KeyDescriptor) -> Outcome {
+    let level = code(services.keystore.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT))?;
+    let op_params = vec![
+        param(Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::SIGN)),
+        param(Tag::DIGEST, KeyParameterValue::Digest(Digest::SHA_2_256)),
+    ];
+    let response = code(level.createOperation(key, &op_params, false))?;
+    let op = response.iOperation.expect("createOperation returned no operation.");
+    code(op.finish(Some(b"grant lifecycle"), None)).map(|_| 0)
+}
+
+/// Has the grantor generate a key with `params` and grant `access_vector` on it to the grantee.
+/// Returns the grant id.
+fn generate_and_grant(fixture: &Fixture, params: Vec<KeyParameter>, access_vector: i32) -> i64 {
+    let grantee_uid = fixture.uid(Actor::Grantee) as i32;
+    fixture
+        .run_as(Actor::Grantor, move |services| {
+            generate(services, &params)?;
+            code(services.keystore.grant(&own_key(), grantee_uid, access_vector))
+                .map(|key| key.nspace)
+        })
+        .expect("Failed to generate and grant the key.")
+}
+
+#[test]
+fn grantee_uses_granted_key() {
+    let fixture = Fixture::new(10_900);
+    let grant_id = generate_and_grant(
+        &fixture,
+        ec_sign_params(),
+        KeyPermission::USE.0 | KeyPermission::GET_INFO.0,
+    );
+
+    let outcome = fixture.run_as(Actor::Grantee, move |services| {
+        let entry = code(services.keystore.getKeyEntry(&granted_key(grant_id)))?;
+        // The grantee sees the key under the grant, not under the namespace of the grantor.
+        assert_eq!(entry.metadata.key.domain, Domain::KEY_ID);
+        sign(services, &granted_key(grant_id))
+    });
+    assert_eq!(outcome, Ok(0));
+
+    // The grant is listed for the grantor.
+    let grantee_uid = fixture.uid(Actor::Grantee) as i32;
+    let outcome = fixture.run_as(Actor::Grantor, move |services| {
+        let grants = code(services.grants.listGrants(&own_key()))?;
+        assert_eq!(grants.len(), 1);
+        assert_eq!((grants[0].granteeUid, grants[0].grantId), (grantee_uid, grant_id));
+        Ok(0)
+    });
+    assert_eq!(outcome, Ok(0));
+}
+
+#[test]
+fn grant_enforces_granted_permissions() {
+    let fixture = Fixture::new(10_902);
+    let grant_id = generate_and_grant(&fixture, ec_sign_params(), KeyPermission::USE.0);
+    let permission_denied = Err(ResponseCode::PERMISSION_DENIED.0);
+
+    assert_eq!(
+        fixture.run_as(Actor::Grantee, move |services| sign(services, &granted_key(grant_id))),
+        Ok(0)
+    );
+    assert_eq!(
+        fixture.run_as(Actor::Grantee, move |services| {
+            code(services.keystore.getKeyEntry(&granted_key(grant_id))).map(|_| 0)
+        }),
+        permission_denied
+    );
+    assert_eq!(
+        fixture.run_as(Actor::Grantee, move |services| {
+            code(services.keystore.deleteKey(&granted_key(grant_id))).map(|_| 0)
+        }),
+        permission_denied
+    );
+    // The grantee cannot pass the key on.
+    let grantor_uid = fixture.uid(Actor::Grantor) as i32;
+    assert_eq!(
+        fixture.run_as(Actor::Grantee, move |services| {
+            code(services.keystore.grant(&granted_key(grant_id), grantor_uid, KeyPermission::USE.0))
+                .map(|_| 0)
+        }),
+        permission_denied
+    );
+    // Nor can the grantor grant the permission to grant.
+    let grantee_uid = fixture.uid(Actor::Grantee) as i32;
+    assert_eq!(
+        fixture.run_as(Actor::Grantor, move |services| {
+            code(services.keystore.grant(&own_key(), grantee_uid, KeyPermission::GRANT.0))
+                .map(|_| 0)
+        }),
+        permission_denied
+    );
+}
+
+#[test]
+fn grant_honors_key_expiry() {
+    let fixture = Fixture::new(10_904);
+    let mut params = ec_sign_params();
+    // One millisecond after the epoch, i.e., long expired.
+    params.push(param(Tag::ORIGINATION_EXPIRE_DATETIME, KeyParameterValue::DateTime(1)));
+    let grant_id = generate_and_grant(&fixture, params, KeyPermission::USE.0);
+
+    assert_eq!(
+        fixture.run_as(Actor::Grantee, move |services| sign(services, &granted_key(grant_id))),
+        Err(ErrorCode::KEY_EXPIRED.0)
+    );
+}
+
+#[test]
+fn ungrant_revokes_access() {
+    let fixture = Fixture::new(10_906);
+    let grant_id = generate_and_grant(&fixture, ec_sign_params(), KeyPermission::USE.0);
+    assert_eq!(
+        fixture.run_as(Actor::Grantee, move |services| sign(services, &granted_key(grant_id))),
+        Ok(0)
+    );
+
+    let grantee_uid = fixture.uid(Actor::Grantee) as i32;
+    assert_eq!(
+        fixture.run_as(Actor::Grantor, move |services| {
+            code(services.keystore.ungrant(&own_key(), grantee_uid)).map(|_| 0)
+        }),
+        Ok(0)
+    );
+    assert_eq!(
+        fixture.run_as(Actor::Grantee, move |services| sign(services, &granted_key(grant_id))),
+        Err(ResponseCode::KEY_NOT_FOUND.0)
+    );
+}
+
+#[test]
+fn uninstalling_grantor_revokes_grants() {
+    let fixture = Fixture::new(10_908);
+    let grant_id = generate_and_grant(&fixture, ec_sign_params(), KeyPermission::USE.0);
+
+    assert_eq!(fixture.uninstall(Actor::Grantor), Ok(0));
+    assert_eq!(
+        fixture.run_as(Actor::Grantee, move |services| sign(services, &granted_key(grant_id))),
+        Err(ResponseCode::KEY_NOT_FOUND.0)
+    );
+}
+
+#[test]
+fn uninstalling_grantee_revokes_grants() {
+    let fixture = Fixture::new(10_910);
+    let grant_id = generate_and_grant(&fixture, ec_sign_params(), KeyPermission::USE.0);
+
+    assert_eq!(fixture.uninstall(Actor::Grantee), Ok(0));
+    // An app installed later under the uid of the grantee does not inherit the grant.
+    assert_eq!(
+        fixture.run_as(Actor::Grantee, move |services| sign(services, &granted_key(grant_id))),
+        Err(ResponseCode::KEY_NOT_FOUND.0)
+    );
+    let outcome = fixture.run_as(Actor::Grantor, |services| {
+        code(services.grants.listGrants(&own_key())).map(|grants| grants.len() as i64)
+    });
+    assert_eq!(outcome, Ok(0));
+}
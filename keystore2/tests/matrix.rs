@@ -0,0 +1,175 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements a test matrix that runs the same behavioral test cases against each
+//! security level of the device. Levels without a KeyMint instance are skipped as a whole, and
+//! a case may skip itself if its level lacks an optional feature, e.g., provisioned attestation
+//! keys. The outcome of every case on every level is reported, so that differences between
+//! vendor HALs show up side by side in one suite.
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, IKeystoreSecurityLevel::IKeystoreSecurityLevel,
+    IKeystoreService::IKeystoreService, KeyDescriptor::KeyDescriptor,
+};
+use binder::{Status, Strong};
+use std::fmt;
+
+/// The security levels covered by the matrix.
+pub const SECURITY_LEVELS: &[SecurityLevel] =
+    &[SecurityLevel::SOFTWARE, SecurityLevel::TRUSTED_ENVIRONMENT, SecurityLevel::STRONGBOX];
+
+/// Why a case did not pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// The case does not apply to the security level.
+    Skip(String),
+    /// The case failed.
+    Fail(String),
+}
+
+/// The result of a case. `Ok(())` means the case passed.
+pub type CaseResult = Result<(), Verdict>;
+
+/// A test case. It is run once for each available security level.
+pub type TestCase = fn(&TestContext) -> CaseResult;
+
+/// Returns a failure verdict with the given message.
+pub fn fail<T>(message: impl Into<String>) -> Result<T, Verdict> {
+    Err(Verdict::Fail(message.into()))
+}
+
+/// Returns a skip verdict with the given reason.
+pub fn skip<T>(reason: impl Into<String>) -> Result<T, Verdict> {
+    Err(Verdict::Skip(reason.into()))
+}
+
+/// Converts binder results into case results.
+pub trait ExpectOk<T> {
+    /// Turns an error into a failure verdict that names the failed step `what`.
+    fn expect_ok(self, what: &str) -> Result<T, Verdict>;
+}
+
+impl<T> ExpectOk<T> for Result<T, Status> {
+    fn expect_ok(self, what: &str) -> Result<T, Verdict> {
+        self.or_else(|status| fail(format!("{} failed: {:?}", what, status)))
+    }
+}
+
+/// Returns the service specific error code of a failed binder call, if any.
+pub fn error_code<T>(result: &Result<T, Status>) -> Option<i32> {
+    result.as_ref().err().map(|status| status.service_specific_error())
+}
+
+/// Everything a case needs to run against one security level.
+pub struct TestContext {
+    /// The security level under test.
+    pub sec_level: SecurityLevel,
+    /// The Keystore service.
+    pub service: Strong<dyn IKeystoreService>,
+    /// The Keystore binding of the security level under test.
+    pub level: Strong<dyn IKeystoreSecurityLevel>,
+    case: &'static str,
+}
+
+impl TestContext {
+    /// Returns a descriptor for a key of the calling app that is unique to the security level,
+    /// the case, and `suffix`.
+    pub fn key(&self, suffix: &str) -> KeyDescriptor {
+        KeyDescriptor {
+            domain: Domain::APP,
+            nspace: -1,
+            alias: Some(format!("matrix_{}_{}_{}", self.sec_level.0, self.case, suffix)),
+            blob: None,
+        }
+    }
+}
+
+/// The outcome of one case on one security level.
+#[derive(Debug)]
+pub struct Entry {
+    /// The security level.
+    pub sec_level: SecurityLevel,
+    /// The name of the case.
+    pub case: &'static str,
+    /// The outcome.
+    pub result: CaseResult,
+}
+
+/// The outcomes of all cases on all security levels.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// All outcomes in the order they were run.
+    pub entries: Vec<Entry>,
+}
+
+impl Report {
+    /// Returns the entries of failed cases.
+    pub fn failures(&self) -> Vec<&Entry> {
+        self.entries.iter().filter(|e| matches!(e.result, Err(Verdict::Fail(_)))).collect()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for Entry { sec_level, case, result } in &self.entries {
+            match result {
+                Ok(()) => writeln!(f, "{:?} {}: PASS", sec_level, case)?,
+                Err(Verdict::Skip(reason)) => {
+                    writeln!(f, "{:?} {}: SKIP ({})", sec_level, case, reason)?
+                }
+                Err(Verdict::Fail(message)) => {
+                    writeln!(f, "{:?} {}: FAIL ({})", sec_level, case, message)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A set of named cases that are run against every security level.
+#[derive(Default)]
+pub struct Matrix {
+    cases: Vec<(&'static str, TestCase)>,
+}
+
+impl Matrix {
+    /// Adds a case to the matrix.
+    pub fn case(mut self, name: &'static str, case: TestCase) -> Self {
+        self.cases.push((name, case));
+        self
+    }
+
+    /// Runs all cases against all security levels. If a security level is not available, all
+    /// cases are reported as skipped for it.
+    pub fn run(&self, service: &Strong<dyn IKeystoreService>) -> Report {
+        let mut report: Report = Default::default();
+        for sec_level in SECURITY_LEVELS {
+            let level = service.getSecurityLevel(*sec_level);
+            for (case, run) in &self.cases {
+                let result = match &level {
+                    Ok(level) => run(&TestContext {
+                        sec_level: *sec_level,
+                        service: service.clone(),
+                        level: level.clone(),
+                        case,
+                    }),
+                    Err(e) => skip(format!("security level unavailable: {:?}", e)),
+                };
+                report.entries.push(Entry { sec_level: *sec_level, case, result });
+            }
+        }
+        report
+    }
+}
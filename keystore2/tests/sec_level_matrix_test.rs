@@ -0,0 +1,213 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runs the Keystore behavioral test suite against every security level of the device.
+//! See `matrix.rs` for how levels and cases are skipped and reported.
+
+mod matrix;
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, Digest::Digest, EcCurve::EcCurve, ErrorCode::ErrorCode,
+    HardwareAuthenticatorType::HardwareAuthenticatorType, KeyParameter::KeyParameter,
+    KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose, Tag::Tag,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, IKeystoreService::IKeystoreService, KeyDescriptor::KeyDescriptor,
+    KeyMetadata::KeyMetadata, ResponseCode::ResponseCode,
+};
+use matrix::{error_code, fail, skip, CaseResult, ExpectOk, Matrix, TestContext};
+
+const KEYSTORE_SERVICE_NAME: &str = "android.system.keystore2.IKeystoreService/default";
+
+fn ec_sign_params() -> Vec<KeyParameter> {
+    vec![
+        KeyParameter { tag: Tag::ALGORITHM, value: KeyParameterValue::Algorithm(Algorithm::EC) },
+        KeyParameter { tag: Tag::EC_CURVE, value: KeyParameterValue::EcCurve(EcCurve::P_256) },
+        KeyParameter { tag: Tag::PURPOSE, value: KeyParameterValue::KeyPurpose(KeyPurpose::SIGN) },
+        KeyParameter { tag: Tag::DIGEST, value: KeyParameterValue::Digest(Digest::SHA_2_256) },
+    ]
+}
+
+fn no_auth_required() -> KeyParameter {
+    KeyParameter { tag: Tag::NO_AUTH_REQUIRED, value: KeyParameterValue::BoolValue(true) }
+}
+
+fn sign_op_params() -> Vec<KeyParameter> {
+    vec![
+        KeyParameter { tag: Tag::PURPOSE, value: KeyParameterValue::KeyPurpose(KeyPurpose::SIGN) },
+        KeyParameter { tag: Tag::DIGEST, value: KeyParameterValue::Digest(Digest::SHA_2_256) },
+    ]
+}
+
+fn generate(ctx: &TestContext, key: &KeyDescriptor, params: &[KeyParameter]) -> CaseResult {
+    generate_with_metadata(ctx, key, params).map(|_| ())
+}
+
+fn generate_with_metadata(
+    ctx: &TestContext,
+    key: &KeyDescriptor,
+    params: &[KeyParameter],
+) -> Result<KeyMetadata, matrix::Verdict> {
+    let metadata = ctx.level.generateKey(key, None, params, 0, b"").expect_ok("generateKey")?;
+    if metadata.keySecurityLevel != ctx.sec_level {
+        return fail(format!(
+            "key was generated on {:?} instead of {:?}",
+            metadata.keySecurityLevel, ctx.sec_level
+        ));
+    }
+    Ok(metadata)
+}
+
+fn sign(ctx: &TestContext, key: &KeyDescriptor) -> CaseResult {
+    let response =
+        ctx.level.createOperation(key, &sign_op_params(), false).expect_ok("createOperation")?;
+    let op = match response.iOperation {
+        Some(op) => op,
+        None => return fail("createOperation returned no operation"),
+    };
+    op.update(b"security level matrix").expect_ok("update")?;
+    match op.finish(None, None).expect_ok("finish")? {
+        Some(signature) if !signature.is_empty() => Ok(()),
+        _ => fail("finish returned no signature"),
+    }
+}
+
+fn cleanup(ctx: &TestContext, key: &KeyDescriptor) {
+    // The key may not exist if the case failed early, so errors are ignored.
+    let _ = ctx.service.deleteKey(key);
+}
+
+/// Generates a key and checks that it is backed by the security level under test.
+fn generate_case(ctx: &TestContext) -> CaseResult {
+    let key = ctx.key("key");
+    let mut params = ec_sign_params();
+    params.push(no_auth_required());
+    let result = generate(ctx, &key, &params);
+    cleanup(ctx, &key);
+    result
+}
+
+/// Generates a key and signs with it.
+fn use_case(ctx: &TestContext) -> CaseResult {
+    let key = ctx.key("key");
+    let mut params = ec_sign_params();
+    params.push(no_auth_required());
+    let result = generate(ctx, &key, &params).and_then(|_| sign(ctx, &key));
+    cleanup(ctx, &key);
+    result
+}
+
+/// Generates an auth bound key and checks that it cannot be used without authentication.
+fn auth_bind_case(ctx: &TestContext) -> CaseResult {
+    let key = ctx.key("key");
+    let mut params = ec_sign_params();
+    params.extend(vec![
+        KeyParameter {
+            tag: Tag::USER_SECURE_ID,
+            // No authenticator will ever issue a token for this secure id.
+            value: KeyParameterValue::LongInteger(0x6d61_7472_6978),
+        },
+        KeyParameter {
+            tag: Tag::USER_AUTH_TYPE,
+            value: KeyParameterValue::HardwareAuthenticatorType(
+                HardwareAuthenticatorType::PASSWORD,
+            ),
+        },
+        KeyParameter { tag: Tag::AUTH_TIMEOUT, value: KeyParameterValue::Integer(60) },
+    ]);
+    let result = generate(ctx, &key, &params).and_then(|_| {
+        let result = ctx.level.createOperation(&key, &sign_op_params(), false);
+        match error_code(&result) {
+            Some(code) if code == ErrorCode::KEY_USER_NOT_AUTHENTICATED.0 => Ok(()),
+            Some(code) => fail(format!("createOperation failed with unexpected code {}", code)),
+            None => fail("createOperation succeeded without authentication"),
+        }
+    });
+    cleanup(ctx, &key);
+    result
+}
+
+/// Generates a key with an attestation challenge and checks that a certificate chain is
+/// returned. Skipped if the security level has no attestation keys.
+fn attest_case(ctx: &TestContext) -> CaseResult {
+    let key = ctx.key("key");
+    let mut params = ec_sign_params();
+    params.push(no_auth_required());
+    params.push(KeyParameter {
+        tag: Tag::ATTESTATION_CHALLENGE,
+        value: KeyParameterValue::Blob(b"matrix".to_vec()),
+    });
+    let result = ctx.level.generateKey(&key, None, &params, 0, b"");
+    let result = match error_code(&result) {
+        Some(code) if code == ResponseCode::OUT_OF_KEYS.0 => {
+            skip("no attestation keys are provisioned")
+        }
+        Some(code) if code == ErrorCode::UNIMPLEMENTED.0 => skip("attestation is not implemented"),
+        _ => result.expect_ok("generateKey").and_then(|metadata| {
+            match (metadata.certificate, metadata.certificateChain) {
+                (Some(_), Some(chain)) if !chain.is_empty() => Ok(()),
+                (Some(_), _) => fail("the attestation has no certificate chain"),
+                _ => fail("the attestation has no leaf certificate"),
+            }
+        }),
+    };
+    cleanup(ctx, &key);
+    result
+}
+
+/// Generates a key, loads it again by key id and signs with it. The key must keep its security
+/// level and remain usable when addressed by key id. Key blob upgrades cannot be triggered from
+/// a client; they are covered by the unit tests in `raw_device.rs`.
+fn reload_case(ctx: &TestContext) -> CaseResult {
+    let key = ctx.key("key");
+    let mut params = ec_sign_params();
+    params.push(no_auth_required());
+    let result = generate_with_metadata(ctx, &key, &params).and_then(|metadata| {
+        let by_id = KeyDescriptor {
+            domain: Domain::KEY_ID,
+            nspace: metadata.key.nspace,
+            alias: None,
+            blob: None,
+        };
+        sign(ctx, &by_id)?;
+        let entry = ctx.service.getKeyEntry(&by_id).expect_ok("getKeyEntry")?;
+        if entry.metadata.keySecurityLevel != ctx.sec_level {
+            return fail("the reloaded key changed its security level");
+        }
+        sign(ctx, &by_id)
+    });
+    cleanup(ctx, &key);
+    result
+}
+
+#[test]
+fn security_level_matrix() {
+    android_logger::init_once(
+        android_logger::Config::default()
+            .with_tag("keystore2_sec_level_matrix_test")
+            .with_min_level(log::Level::Debug),
+    );
+    let service: binder::Strong<dyn IKeystoreService> =
+        binder::get_interface(KEYSTORE_SERVICE_NAME).expect("Failed to connect to Keystore.");
+    let report = Matrix::default()
+        .case("generate", generate_case)
+        .case("use", use_case)
+        .case("auth_bind", auth_bind_case)
+        .case("attest", attest_case)
+        .case("reload", reload_case)
+        .run(&service);
+    log::info!("{}", report);
+    let failures = report.failures();
+    assert!(failures.is_empty(), "{} case(s) failed:\n{}", failures.len(), report);
+}
@@ -42,6 +42,8 @@ using android::hardware::security::keymint::PaddingMode;
 using android::hardware::security::keymint::SecurityLevel;
 using android::hardware::security::keymint::Tag;
 
+using android::security::odsign::OdsignKeyUsability;
+
 using android::system::keystore2::CreateOperationResponse;
 using android::system::keystore2::Domain;
 using android::system::keystore2::KeyDescriptor;
@@ -72,7 +74,9 @@ KeystoreKey::KeystoreKey() {
     mDescriptor = getKeyDescriptor();
 }
 
-Result<std::vector<uint8_t>> KeystoreKey::createKey() {
+// The parameters below are only used if Keystore does not provide the odsign key service,
+// which manages the key on our behalf otherwise.
+static std::vector<KeyParameter> getCreateKeyParameters() {
     std::vector<KeyParameter> params;
 
     KeyParameter algo;
@@ -116,8 +120,18 @@ Result<std::vector<uint8_t>> KeystoreKey::createKey() {
     boot_level.value = KeyParameterValue::make<KeyParameterValue::integer>(kOdsignBootLevel);
     params.push_back(boot_level);
 
+    return params;
+}
+
+Result<std::vector<uint8_t>> KeystoreKey::createKey() {
     KeyMetadata metadata;
-    auto status = mSecurityLevel->generateKey(mDescriptor, {}, params, 0, {}, &metadata);
+    android::binder::Status status;
+    if (mOdsignKeyService != nullptr) {
+        status = mOdsignKeyService->rotateKey(kOdsignBootLevel, {}, &metadata);
+    } else {
+        status =
+            mSecurityLevel->generateKey(mDescriptor, {}, getCreateKeyParameters(), 0, {}, &metadata);
+    }
     if (!status.isOk()) {
         return Error() << "Failed to create new key";
     }
@@ -158,6 +172,15 @@ bool KeystoreKey::initialize() {
         return false;
     }
 
+    // Keystore registers the odsign key service only if the platform policy declares it.
+    // Without it, we manage the key with the generic Keystore calls.
+    auto odsignKeyService = sm->checkService(String16("android.security.odsign"));
+    if (odsignKeyService != nullptr) {
+        mOdsignKeyService = interface_cast<IKeystoreOdsignKey>(odsignKeyService);
+    } else {
+        LOG(INFO) << "Keystore odsign key service not available, using generic calls.";
+    }
+
     auto status = mService->getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT, &mSecurityLevel);
     if (!status.isOk()) {
         return false;
@@ -181,37 +204,50 @@ bool KeystoreKey::initialize() {
 }
 
 Result<std::vector<uint8_t>> KeystoreKey::verifyExistingKey() {
-    // See if we can fetch an existing key
-    KeyEntryResponse keyEntryResponse;
+    // On some earlier builds, we created this key on the Strongbox security level;
+    // we now use TEE keys instead (mostly for speed). It shouldn't matter since
+    // verified boot is protected by the TEE anyway. Keys on the wrong security level
+    // or without the odsign boot level are replaced (this should happen just once).
     LOG(INFO) << "Trying to retrieve existing keystore key...";
-    auto status = mService->getKeyEntry(mDescriptor, &keyEntryResponse);
+    if (mOdsignKeyService != nullptr) {
+        OdsignKeyUsability usability;
+        auto status = mOdsignKeyService->getKeyUsability(kOdsignBootLevel, &usability);
+        if (!status.isOk()) {
+            return Error() << "Failed to query keystore key usability.";
+        }
+        if (usability != OdsignKeyUsability::USABLE) {
+            return Error() << "Found no usable keystore key: "
+                           << android::security::odsign::toString(usability);
+        }
+    }
 
+    KeyEntryResponse keyEntryResponse;
+    auto status = mService->getKeyEntry(mDescriptor, &keyEntryResponse);
     if (!status.isOk()) {
         return Error() << "Failed to find keystore key...";
     }
 
-    // On some earlier builds, we created this key on the Strongbox security level;
-    // we now use TEE keys instead (mostly for speed). It shouldn't matter since
-    // verified boot is protected by the TEE anyway. If the key happens to be on
-    // the wrong security level, delete it (this should happen just once).
-    if (keyEntryResponse.metadata.keySecurityLevel != SecurityLevel::TRUSTED_ENVIRONMENT) {
-        return Error() << "Found invalid keystore key with security level: "
-                       << android::hardware::security::keymint::toString(
-                              keyEntryResponse.metadata.keySecurityLevel);
-    }
+    if (mOdsignKeyService == nullptr) {
+        if (keyEntryResponse.metadata.keySecurityLevel != SecurityLevel::TRUSTED_ENVIRONMENT) {
+            return Error() << "Found invalid keystore key with security level: "
+                           << android::hardware::security::keymint::toString(
+                                  keyEntryResponse.metadata.keySecurityLevel);
+        }
 
-    // Make sure this is an early boot key
-    bool foundBootLevel = false;
-    for (const auto& auth : keyEntryResponse.metadata.authorizations) {
-        if (auth.keyParameter.tag == Tag::MAX_BOOT_LEVEL) {
-            if (auth.keyParameter.value.get<KeyParameterValue::integer>() == kOdsignBootLevel) {
-                foundBootLevel = true;
-                break;
+        // Make sure this is an early boot key
+        bool foundBootLevel = false;
+        for (const auto& auth : keyEntryResponse.metadata.authorizations) {
+            if (auth.keyParameter.tag == Tag::MAX_BOOT_LEVEL) {
+                if (auth.keyParameter.value.get<KeyParameterValue::integer>() ==
+                    kOdsignBootLevel) {
+                    foundBootLevel = true;
+                    break;
+                }
             }
         }
-    }
-    if (!foundBootLevel) {
-        return Error() << "Found invalid keystore key without MAX_BOOT_LEVEL tag";
+        if (!foundBootLevel) {
+            return Error() << "Found invalid keystore key without MAX_BOOT_LEVEL tag";
+        }
     }
 
     // If the key is still considered valid at this point, extract the public
@@ -291,7 +327,7 @@ static std::vector<KeyParameter> getSignOpParameters() {
     return opParameters;
 }
 
-Result<std::string> KeystoreKey::sign(const std::string& message) const {
+Result<std::string> KeystoreKey::signWithOperation(const std::string& message) const {
     static auto opParameters = getSignOpParameters();
     CreateOperationResponse opResponse;
 
@@ -321,6 +357,21 @@ Result<std::string> KeystoreKey::sign(const std::string& message) const {
     return std::string{signature.value().begin(), signature.value().end()};
 }
 
+Result<std::string> KeystoreKey::sign(const std::string& message) const {
+    if (mOdsignKeyService == nullptr) {
+        return signWithOperation(message);
+    }
+
+    std::vector<uint8_t> signature;
+    auto status = mOdsignKeyService->sign({message.begin(), message.end()}, &signature);
+    if (!status.isOk()) {
+        return Error() << "Failed to sign with keystore key: "
+                       << status.serviceSpecificErrorCode();
+    }
+
+    return std::string{signature.begin(), signature.end()};
+}
+
 Result<std::vector<uint8_t>> KeystoreKey::getPublicKey() const {
     return mPublicKey;
 }
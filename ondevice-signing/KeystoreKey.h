@@ -24,12 +24,14 @@
 
 #include <utils/StrongPointer.h>
 
+#include <android/security/odsign/IKeystoreOdsignKey.h>
 #include <android/system/keystore2/IKeystoreService.h>
 
 #include "KeystoreHmacKey.h"
 #include "SigningKey.h"
 
 class KeystoreKey : public SigningKey {
+    using IKeystoreOdsignKey = ::android::security::odsign::IKeystoreOdsignKey;
     using IKeystoreService = ::android::system::keystore2::IKeystoreService;
     using IKeystoreSecurityLevel = ::android::system::keystore2::IKeystoreSecurityLevel;
     using KeyDescriptor = ::android::system::keystore2::KeyDescriptor;
@@ -48,9 +50,11 @@ class KeystoreKey : public SigningKey {
     android::base::Result<std::vector<uint8_t>> verifyExistingKey();
     android::base::Result<std::vector<uint8_t>> createKey();
     android::base::Result<std::vector<uint8_t>> getOrCreateKey();
+    android::base::Result<std::string> signWithOperation(const std::string& message) const;
 
     KeyDescriptor mDescriptor;
     KeystoreHmacKey mHmacKey;
+    android::sp<IKeystoreOdsignKey> mOdsignKeyService;
     android::sp<IKeystoreService> mService;
     android::sp<IKeystoreSecurityLevel> mSecurityLevel;
     std::vector<uint8_t> mPublicKey;
@@ -25,8 +25,11 @@
 #include <sys/types.h>
 #include <sys/wait.h>
 
+#include <android-base/file.h>
 #include <android-base/logging.h>
 #include <android-base/unique_fd.h>
+#include <android/security/fsverity/IKeystoreFsVerity.h>
+#include <binder/IServiceManager.h>
 #include <libfsverity.h>
 #include <linux/fsverity.h>
 
@@ -40,7 +43,14 @@ using android::base::Error;
 using android::base::Result;
 using android::base::unique_fd;
 
+using android::defaultServiceManager;
+using android::interface_cast;
+using android::String16;
+using android::security::fsverity::IKeystoreFsVerity;
+
 static const char* kFsVerityInitPath = "/system/bin/fsverity_init";
+static const char* kKeystoreFsVerityService = "android.security.fsverity";
+static const char* kFsVerityCertName = "ods";
 
 #if __BYTE_ORDER__ == __ORDER_LITTLE_ENDIAN__
 #define cpu_to_le16(v) ((__force __le16)(uint16_t)(v))
@@ -255,13 +265,27 @@ Result<std::map<std::string, std::string>> verifyAllFilesInVerity(const std::str
 }
 
 Result<void> addCertToFsVerityKeyring(const std::string& path) {
-    const char* const argv[] = {kFsVerityInitPath, "--load-extra-key", "fsv_ods"};
+    const char* const argv[] = {kFsVerityInitPath, "--load-keystore-keys"};
+
+    std::string cert;
+    if (!android::base::ReadFileToString(path, &cert)) {
+        return ErrnoError() << "Failed to read " << path;
+    }
+    auto binder = defaultServiceManager()->getService(String16(kKeystoreFsVerityService));
+    auto service = interface_cast<IKeystoreFsVerity>(binder);
+    if (service == nullptr) {
+        return Error() << "Failed to get service " << kKeystoreFsVerityService;
+    }
+    // keystore persists the certificate; fsverity_init loads it into the keyring.
+    auto status =
+        service->provisionCertificate(String16(kFsVerityCertName),
+                                      std::vector<uint8_t>(cert.begin(), cert.end()));
+    if (!status.isOk()) {
+        return Error() << "Failed to provision certificate: " << status.exceptionMessage();
+    }
 
-    int fd = open(path.c_str(), O_RDONLY | O_CLOEXEC);
     pid_t pid = fork();
     if (pid == 0) {
-        dup2(fd, STDIN_FILENO);
-        close(fd);
         int argc = arraysize(argv);
         char* argv_child[argc + 1];
         memcpy(argv_child, argv, argc * sizeof(char*));
@@ -269,8 +293,6 @@ Result<void> addCertToFsVerityKeyring(const std::string& path) {
         execvp(argv_child[0], const_cast<char**>(argv_child));
         PLOG(ERROR) << "exec in ForkExecvp";
         _exit(EXIT_FAILURE);
-    } else {
-        close(fd);
     }
     if (pid == -1) {
         return ErrnoError() << "Failed to fork.";